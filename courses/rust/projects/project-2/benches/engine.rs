@@ -0,0 +1,108 @@
+//! Compares the kvs and sled engines on write-heavy, read-heavy and mixed workloads, with small
+//! and large values and with one or several threads sharing the engine.
+//!
+//! Run a single workload or engine with a filter, e.g. `cargo bench --bench engine -- mixed/kvs`.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use tempfile::TempDir;
+
+const KEYS: u64 = 1000;
+const VALUE_SIZES: &[usize] = &[16, 1024];
+const THREADS: &[u64] = &[1, 4];
+
+#[derive(Clone, Copy)]
+enum Workload {
+    Write,
+    Read,
+    Mixed,
+}
+
+impl Workload {
+    fn name(self) -> &'static str {
+        match self {
+            Workload::Write => "write",
+            Workload::Read => "read",
+            Workload::Mixed => "mixed",
+        }
+    }
+
+    // Whether operation `i` is a write: all of them, none, or one in five.
+    fn is_write(self, i: u64) -> bool {
+        match self {
+            Workload::Write => true,
+            Workload::Read => false,
+            Workload::Mixed => i.is_multiple_of(5),
+        }
+    }
+}
+
+// Run `iters` operations of the workload spread over `threads` threads, and time them.
+fn run<E: KvsEngine>(
+    engine: &E,
+    workload: Workload,
+    value: &str,
+    threads: u64,
+    iters: u64,
+) -> Duration {
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for t in 0..threads {
+            let engine = engine.clone();
+            scope.spawn(move || {
+                let ops = iters / threads + u64::from(t < iters % threads);
+                for i in 0..ops {
+                    // spread the keys so that the threads don't keep hitting the same ones
+                    let key = format!("key{}", (i * 7919 + t * 131) % KEYS);
+                    if workload.is_write(i) {
+                        engine.set(key, value.to_owned()).unwrap();
+                    } else {
+                        engine.get(key).unwrap();
+                    }
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn bench_engine<E: KvsEngine>(
+    c: &mut Criterion,
+    workload: Workload,
+    name: &str,
+    open: impl Fn(&TempDir) -> E,
+) {
+    let mut group = c.benchmark_group(workload.name());
+    group.throughput(Throughput::Elements(1));
+    for &value_size in VALUE_SIZES {
+        let value = "x".repeat(value_size);
+        for &threads in THREADS {
+            let temp_dir = TempDir::new().unwrap();
+            let engine = open(&temp_dir);
+            for key_id in 0..KEYS {
+                engine.set(format!("key{}", key_id), value.clone()).unwrap();
+            }
+            group.bench_with_input(
+                BenchmarkId::new(name, format!("{}B/{}threads", value_size, threads)),
+                &threads,
+                |b, &threads| b.iter_custom(|iters| run(&engine, workload, &value, threads, iters)),
+            );
+        }
+    }
+    group.finish();
+}
+
+fn engines(c: &mut Criterion) {
+    for &workload in &[Workload::Write, Workload::Read, Workload::Mixed] {
+        bench_engine(c, workload, "kvs", |dir| KvStore::open(dir.path()).unwrap());
+        bench_engine(c, workload, "sled", |dir| {
+            SledKvsEngine::open(dir.path()).unwrap()
+        });
+    }
+}
+
+criterion_group!(benches, engines);
+criterion_main!(benches);
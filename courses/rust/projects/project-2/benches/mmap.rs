@@ -0,0 +1,63 @@
+//! Compares reading large values with `get_bytes`, which copies each of them into a new buffer,
+//! and with `get_ref`, which borrows them from the memory-mapped segment.
+//!
+//! Run with `cargo bench --features mmap --bench mmap`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kvs::KvStore;
+use tempfile::TempDir;
+
+const KEYS: usize = 100;
+
+fn mmap(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mmap");
+    for &value_size in &[4 * 1024, 64 * 1024, 1024 * 1024] {
+        let temp_dir = TempDir::new().unwrap();
+        // values have to be stored as they are to be borrowed
+        let store = KvStore::options()
+            .cache_capacity(0)
+            .compression_threshold(None)
+            .open(temp_dir.path())
+            .unwrap();
+        let value = "x".repeat(value_size);
+        for key_id in 0..KEYS {
+            store.set(format!("key{}", key_id), value.clone()).unwrap();
+        }
+        group.throughput(Throughput::Bytes(value_size as u64));
+
+        let mut key_id = 0;
+        group.bench_with_input(
+            BenchmarkId::new("get_bytes", value_size),
+            &value_size,
+            |b, _| {
+                b.iter(|| {
+                    key_id = (key_id + 7) % KEYS;
+                    let value = store
+                        .get_bytes(format!("key{}", key_id).as_bytes())
+                        .unwrap()
+                        .unwrap();
+                    // touch the value, as a caller would
+                    value[value.len() - 1]
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("get_ref", value_size),
+            &value_size,
+            |b, _| {
+                b.iter(|| {
+                    key_id = (key_id + 7) % KEYS;
+                    let value = store
+                        .get_ref(format!("key{}", key_id).as_bytes())
+                        .unwrap()
+                        .unwrap();
+                    value[value.len() - 1]
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, mmap);
+criterion_main!(benches);
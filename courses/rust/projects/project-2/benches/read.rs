@@ -0,0 +1,59 @@
+//! Compares reading values through the long-lived segment handles of `KvStore` with opening the
+//! segment for every read, which is what `get` used to do.
+
+use std::fs::File;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::KvStore;
+use tempfile::TempDir;
+
+const KEYS: usize = 1000;
+
+fn read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read");
+    for &value_size in &[100, 4096] {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::options()
+            .cache_capacity(0)
+            .compression_threshold(None)
+            .open(temp_dir.path())
+            .unwrap();
+        let value = "x".repeat(value_size);
+        for key_id in 0..KEYS {
+            store.set(format!("key{}", key_id), value.clone()).unwrap();
+        }
+
+        let mut key_id = 0;
+        group.bench_with_input(
+            BenchmarkId::new("shared_handle", value_size),
+            &value_size,
+            |b, _| {
+                b.iter(|| {
+                    key_id = (key_id + 7919) % KEYS;
+                    store.get(format!("key{}", key_id)).unwrap()
+                })
+            },
+        );
+
+        // The same read, decoded and checked against its checksum by `get` all the same, with the
+        // segment opened and closed around it as `get` used to do.
+        let segment = temp_dir.path().join("1.log");
+        group.bench_with_input(
+            BenchmarkId::new("open_per_read", value_size),
+            &value_size,
+            |b, _| {
+                b.iter(|| {
+                    key_id = (key_id + 7919) % KEYS;
+                    let file = File::open(&segment).unwrap();
+                    let value = store.get(format!("key{}", key_id)).unwrap();
+                    drop(file);
+                    value
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, read);
+criterion_main!(benches);
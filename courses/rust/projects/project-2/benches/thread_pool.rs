@@ -0,0 +1,103 @@
+//! Compares the thread pools a `KvsServer` can handle its connections on, under read-heavy and
+//! write-heavy load from several clients at once.
+//!
+//! Run a single workload or pool with a filter, e.g.
+//! `cargo bench --bench thread_pool -- read/rayon`.
+
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kvs::thread_pool::{RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsServer};
+use tempfile::TempDir;
+
+const KEYS: u64 = 1000;
+const POOL_THREADS: &[u32] = &[2, 4, 8];
+const CLIENTS: u64 = 8;
+
+#[derive(Clone, Copy)]
+enum Workload {
+    ReadHeavy,
+    WriteHeavy,
+}
+
+impl Workload {
+    fn name(self) -> &'static str {
+        match self {
+            Workload::ReadHeavy => "read",
+            Workload::WriteHeavy => "write",
+        }
+    }
+
+    // Whether operation `i` is a write: one in ten, or nine in ten.
+    fn is_write(self, i: u64) -> bool {
+        match self {
+            Workload::ReadHeavy => i.is_multiple_of(10),
+            Workload::WriteHeavy => !i.is_multiple_of(10),
+        }
+    }
+}
+
+// Start a server on a free port with a pool of `threads` threads, filled with every key. It
+// runs until the bench exits.
+fn spawn_server<P: ThreadPool + Send + 'static>(temp_dir: &TempDir, threads: u32) -> SocketAddr {
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for key_id in 0..KEYS {
+        store.set(format!("key{}", key_id), "x".repeat(64)).unwrap();
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = KvsServer::new(store, P::new(threads).unwrap());
+    thread::spawn(move || server.serve(listener));
+    addr
+}
+
+// Run `iters` requests of the workload spread over the clients, each on its own connection, and
+// time them.
+fn run(addr: SocketAddr, workload: Workload, iters: u64) -> Duration {
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for c in 0..CLIENTS {
+            scope.spawn(move || {
+                let mut client = KvsClient::connect(addr).unwrap();
+                let requests = iters / CLIENTS + u64::from(c < iters % CLIENTS);
+                for i in 0..requests {
+                    let key = format!("key{}", (i * 7919 + c * 131) % KEYS);
+                    if workload.is_write(i) {
+                        client.set(key, "y".repeat(64)).unwrap();
+                    } else {
+                        client.get(key).unwrap();
+                    }
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn bench_pool<P: ThreadPool + Send + 'static>(c: &mut Criterion, workload: Workload, name: &str) {
+    let mut group = c.benchmark_group(workload.name());
+    group.throughput(Throughput::Elements(1));
+    for &threads in POOL_THREADS {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = spawn_server::<P>(&temp_dir, threads);
+        group.bench_with_input(
+            BenchmarkId::new(name, format!("{}threads", threads)),
+            &threads,
+            |b, _| b.iter_custom(|iters| run(addr, workload, iters)),
+        );
+    }
+    group.finish();
+}
+
+fn pools(c: &mut Criterion) {
+    for &workload in &[Workload::ReadHeavy, Workload::WriteHeavy] {
+        bench_pool::<SharedQueueThreadPool>(c, workload, "shared_queue");
+        bench_pool::<RayonThreadPool>(c, workload, "rayon");
+    }
+}
+
+criterion_group!(benches, pools);
+criterion_main!(benches);
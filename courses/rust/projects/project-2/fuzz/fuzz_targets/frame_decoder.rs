@@ -0,0 +1,11 @@
+//! Feeds arbitrary bytes to the decoders of what clients send to a server of the native
+//! protocol: its messages in each codec, and the chunks of a streamed value.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    kvs::fuzzing::native_messages(data);
+    kvs::fuzzing::chunks(data);
+});
@@ -0,0 +1,9 @@
+//! Feeds arbitrary bytes to the reader of the HTTP/2 frames of the gRPC front end.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    kvs::fuzzing::h2_frames(data);
+});
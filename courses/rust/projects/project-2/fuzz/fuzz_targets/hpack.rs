@@ -0,0 +1,9 @@
+//! Feeds arbitrary bytes to the HPACK decoder of the headers of the gRPC front end.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    kvs::fuzzing::hpack_blocks(data);
+});
@@ -0,0 +1,9 @@
+//! Feeds arbitrary bytes to the parser of the heads of the requests of the HTTP front end.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    kvs::fuzzing::http_heads(data);
+});
@@ -0,0 +1,12 @@
+//! Feeds arbitrary bytes to the parsers of the files of a store: as a segment, as the data of a
+//! single record, and as a hint file.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    kvs::fuzzing::record(data);
+    kvs::fuzzing::hint(data);
+    kvs::fuzzing::segment(data);
+});
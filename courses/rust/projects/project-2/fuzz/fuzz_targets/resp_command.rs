@@ -0,0 +1,9 @@
+//! Feeds arbitrary bytes to the reader of the commands of the Redis protocol.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    kvs::fuzzing::resp_commands(data);
+});
@@ -0,0 +1,154 @@
+//! The access log of a `KvsServer`: a line for each request it answers, whatever protocol the
+//! request came in, once turned on with `KvsServer::access_log`.
+//!
+//! The lines are logged at the info level with the `kvs::access` target, as `name=value` pairs:
+//!
+//! ```text
+//! peer=127.0.0.1:51234 protocol=native trace_id="checkout-7f3a" command=get key="user:42" outcome=ok latency_us=182
+//! ```
+//!
+//! * `protocol` is `native`, `resp`, `http` or `grpc`,
+//! * `trace_id` is the one the client sent the request with, in a `Traced` request of the native
+//!   protocol, an `X-Request-Id` header of the REST gateway or `x-request-id` metadata of gRPC,
+//!   or one the server made up otherwise,
+//! * `command` is the one the request is counted as in the metrics of the server,
+//! * `key` is the key the request is about, if it is about a single one, or the start of its
+//!   SHA-256 digest, e.g. `key=sha256:2c26b46b68ffc68f`, for logs that mustn't hold keys,
+//! * `outcome` is `ok`, `not_found`, or `error:` followed by the `ErrorCode` of the error, e.g.
+//!   `error:ReadOnly`, the prefix of the error of a RESP command, e.g. `error:NOAUTH`, or the
+//!   status of a gRPC call, e.g. `error:Unauthenticated`,
+//! * `latency_us` is the time from the moment the request started arriving to the moment it was
+//!   answered, in microseconds.
+//!
+//! Values a client chose, the keys and trace ids, are quoted and escaped, so that they can't
+//! break a line up.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use log::info;
+use sha2::{Digest, Sha256};
+
+use crate::common::Response;
+use crate::error::ErrorCode;
+
+/// What a `KvsServer` logs of the requests it answers, set with `KvsServer::access_log`. See
+/// the `access_log` module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccessLog {
+    /// Nothing, the default
+    #[default]
+    Off,
+    /// A line for each request, with its key as it is
+    Keys,
+    /// A line for each request, with the start of the SHA-256 digest of its key instead of the key
+    HashedKeys,
+}
+
+/// A request that was answered, to log.
+pub(crate) struct Access<'a> {
+    pub(crate) peer_addr: SocketAddr,
+    pub(crate) protocol: &'static str,
+    // the trace id the client sent, if it did
+    pub(crate) trace_id: Option<&'a str>,
+    pub(crate) command: &'a str,
+    pub(crate) key: Option<&'a [u8]>,
+    pub(crate) outcome: Outcome<'a>,
+    pub(crate) latency: Duration,
+}
+
+/// How a request went.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Outcome<'a> {
+    Ok,
+    NotFound,
+    Failed(ErrorCode),
+    // how a request of a protocol without error codes failed: the prefix of the error a RESP
+    // command was answered with, or the status of a gRPC call
+    FailedWith(&'a str),
+}
+
+impl AccessLog {
+    /// Whether requests are logged at all.
+    pub(crate) fn is_on(self) -> bool {
+        self != AccessLog::Off
+    }
+
+    /// Log a request that was answered, if requests are logged.
+    pub(crate) fn log(self, access: &Access<'_>) {
+        let key = match (self, access.key) {
+            (AccessLog::Off, _) => return,
+            (_, None) => String::new(),
+            (AccessLog::Keys, Some(key)) => format!(" key={:?}", String::from_utf8_lossy(key)),
+            (AccessLog::HashedKeys, Some(key)) => {
+                let digest = Sha256::digest(key);
+                let hex: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+                format!(" key=sha256:{}", hex)
+            }
+        };
+        let trace_id = access.trace_id.map_or_else(new_trace_id, str::to_owned);
+        info!(
+            target: "kvs::access",
+            "peer={} protocol={} trace_id={:?} command={}{} outcome={} latency_us={}",
+            access.peer_addr,
+            access.protocol,
+            trace_id,
+            access.command,
+            key,
+            access.outcome,
+            access.latency.as_micros()
+        );
+    }
+}
+
+impl Outcome<'_> {
+    /// How a request of the native protocol went, by its response.
+    pub(crate) fn of(response: &Response) -> Outcome<'static> {
+        match response {
+            Response::KeyNotFound => Outcome::NotFound,
+            Response::AuthRequired => Outcome::Failed(ErrorCode::AuthRequired),
+            Response::AuthFailed => Outcome::Failed(ErrorCode::AuthFailed),
+            Response::NotLeader(_) => Outcome::Failed(ErrorCode::NotLeader),
+            Response::Err(err) if err.code == ErrorCode::KeyNotFound => Outcome::NotFound,
+            Response::Err(err) => Outcome::Failed(err.code),
+            Response::Tagged { response, .. } | Response::Traced { response, .. } => {
+                Outcome::of(response)
+            }
+            _ => Outcome::Ok,
+        }
+    }
+
+    /// How a request that failed with an error of kind `code` went.
+    pub(crate) fn failed(code: ErrorCode) -> Outcome<'static> {
+        match code {
+            ErrorCode::KeyNotFound => Outcome::NotFound,
+            code => Outcome::Failed(code),
+        }
+    }
+}
+
+impl fmt::Display for Outcome<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Ok => f.write_str("ok"),
+            Outcome::NotFound => f.write_str("not_found"),
+            Outcome::Failed(code) => write!(f, "error:{:?}", code),
+            Outcome::FailedWith(status) => write!(f, "error:{}", status),
+        }
+    }
+}
+
+/// A trace id for a request the client didn't send with one, unique to the process, and most
+/// likely to the servers it is logged along with.
+pub(crate) fn new_trace_id() -> String {
+    static PROCESS: OnceLock<u32> = OnceLock::new();
+    static REQUESTS: AtomicU64 = AtomicU64::new(0);
+    let process = PROCESS.get_or_init(|| RandomState::new().build_hasher().finish() as u32);
+    let n = REQUESTS.fetch_add(1, Ordering::Relaxed);
+    format!("{:08x}-{:x}", process, n)
+}
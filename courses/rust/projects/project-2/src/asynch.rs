@@ -0,0 +1,159 @@
+//! An async interface to the store, for calling it from async code.
+//!
+//! The methods of `asynch::KvStore` run the blocking calls to the store on a pool of threads set
+//! aside for them, and return futures that complete once the calls are done, so they never
+//! block the executor they are awaited on. The futures don't depend on any runtime: they can be
+//! awaited from tokio, async-std or any other executor.
+//!
+//! ```no_run
+//! # async fn example() -> kvs::Result<()> {
+//! let store = kvs::asynch::KvStore::open("db").await?;
+//! store.set("key".to_owned(), "value".to_owned()).await?;
+//! assert_eq!(store.get("key".to_owned()).await?, Some("value".to_owned()));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use crate::error::Result;
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+
+/// The number of threads of the pool the calls run on, unless `KvStore::with_threads` says
+/// otherwise.
+pub const DEFAULT_THREADS: u32 = 4;
+
+/// A `kvs::KvStore` with async methods.
+///
+/// It is cheap to clone, and the clones share the store and the pool of threads.
+#[derive(Clone)]
+pub struct KvStore {
+    store: crate::KvStore,
+    pool: Arc<SharedQueueThreadPool>,
+}
+
+impl KvStore {
+    /// Open the store in a directory, as `kvs::KvStore::open` does, with a pool of
+    /// `DEFAULT_THREADS` threads.
+    pub async fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        let path = path.into();
+        let pool = Arc::new(SharedQueueThreadPool::new(DEFAULT_THREADS)?);
+        let store = spawn(&pool, move || crate::KvStore::open(path)).await?;
+        Ok(KvStore { store, pool })
+    }
+
+    /// Wrap a store that is already open, with a pool of `threads` threads.
+    pub fn with_threads(store: crate::KvStore, threads: u32) -> Result<KvStore> {
+        Ok(KvStore {
+            store,
+            pool: Arc::new(SharedQueueThreadPool::new(threads)?),
+        })
+    }
+
+    /// The blocking store behind this one, for the calls that have no async version.
+    pub fn store(&self) -> &crate::KvStore {
+        &self.store
+    }
+
+    /// Set the value of a key. See `kvs::KvStore::set`.
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.run(move |store| store.set(key, value)).await
+    }
+
+    /// Retrieve the value of a key. See `kvs::KvStore::get`.
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.run(move |store| store.get(key)).await
+    }
+
+    /// Remove a key. See `kvs::KvStore::remove`.
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.run(move |store| store.remove(key)).await
+    }
+
+    fn run<T, F>(&self, f: F) -> Blocking<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&crate::KvStore) -> Result<T> + Send + 'static,
+    {
+        let store = self.store.clone();
+        spawn(&self.pool, move || f(&store))
+    }
+}
+
+/// Run `f` on `pool`, and return a future of its result.
+fn spawn<T, F>(pool: &SharedQueueThreadPool, f: F) -> Blocking<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    let slot = Arc::new(Mutex::new(Slot {
+        result: None,
+        waker: None,
+    }));
+    let completion = Completion(Some(Arc::clone(&slot)));
+    pool.spawn(move || completion.complete(f()));
+    Blocking(slot)
+}
+
+struct Slot<T> {
+    result: Option<Result<T>>,
+    // the task waiting for the result
+    waker: Option<Waker>,
+}
+
+/// The future of a call running on the pool.
+struct Blocking<T>(Arc<Mutex<Slot<T>>>);
+
+impl<T> Future for Blocking<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T>> {
+        let mut slot = self.0.lock().expect("slot lock poisoned");
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Hands the result of a call to its future. If the call panics, the future gets an error
+/// instead of waiting forever.
+struct Completion<T>(Option<Arc<Mutex<Slot<T>>>>);
+
+impl<T> Completion<T> {
+    fn complete(mut self, result: Result<T>) {
+        if let Some(slot) = self.0.take() {
+            fill(&slot, result);
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.0.take() {
+            fill(
+                &slot,
+                Err(io::Error::other("the call to the store panicked").into()),
+            );
+        }
+    }
+}
+
+fn fill<T>(slot: &Mutex<Slot<T>>, result: Result<T>) {
+    let waker = {
+        let mut slot = slot.lock().expect("slot lock poisoned");
+        slot.result = Some(result);
+        slot.waker.take()
+    };
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{KvsError, Result};
+
+/// What a client authenticates with: a token shared by all clients, or a username and password.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Credentials {
+    username: Option<String>,
+    password: String,
+}
+
+impl Credentials {
+    /// A token shared by all clients.
+    pub fn token(token: impl Into<String>) -> Credentials {
+        Credentials {
+            username: None,
+            password: token.into(),
+        }
+    }
+
+    /// The password of a user.
+    pub fn user(username: impl Into<String>, password: impl Into<String>) -> Credentials {
+        Credentials {
+            username: Some(username.into()),
+            password: password.into(),
+        }
+    }
+
+    /// The name of the user, or `None` for a shared token.
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+}
+
+/// Parses a `username:password` pair, or a shared token if there is no `:`.
+impl FromStr for Credentials {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Credentials> {
+        match s.split_once(':') {
+            Some((username, password)) => Ok(Credentials::user(username, password)),
+            None if !s.is_empty() => Ok(Credentials::token(s)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "empty credentials").into()),
+        }
+    }
+}
+
+/// Keeps the password out of logs.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"..")
+            .finish()
+    }
+}
+
+/// The credentials a `KvsServer` accepts, set with `KvsServer::auth`, and the keys each user
+/// may access, if that is restricted with `AuthConfig::acl`.
+#[derive(Debug, Clone, Default)]
+pub struct AuthConfig {
+    accepted: Vec<Credentials>,
+    acl: Option<Acl>,
+}
+
+impl AuthConfig {
+    /// Accept nothing, until credentials are added.
+    pub fn new() -> AuthConfig {
+        AuthConfig::default()
+    }
+
+    /// Accept `credentials`.
+    pub fn add(&mut self, credentials: Credentials) -> &mut AuthConfig {
+        self.accepted.push(credentials);
+        self
+    }
+
+    /// Restrict the keys each user may read and write to those `acl` grants them.
+    pub fn acl(&mut self, acl: Acl) -> &mut AuthConfig {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// Read the credentials to accept from a file.
+    ///
+    /// Each line holds either a shared token or a `username:password` pair, so tokens can't
+    /// contain `:`. Blank lines and lines starting with `#` are skipped.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<AuthConfig> {
+        let mut config = AuthConfig::new();
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            config.add(line.parse()?);
+        }
+        if config.accepted.is_empty() {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidData, "no credentials in auth file").into(),
+            );
+        }
+        Ok(config)
+    }
+
+    /// Whether `credentials` are accepted.
+    pub(crate) fn check(&self, credentials: &Credentials) -> bool {
+        // Compare every password, so that how long this takes doesn't tell how close one was.
+        self.accepted.iter().fold(false, |found, accepted| {
+            let matches = accepted.username == credentials.username
+                && constant_time_eq(
+                    accepted.password.as_bytes(),
+                    credentials.password.as_bytes(),
+                );
+            found | matches
+        })
+    }
+
+    /// Fail with `Forbidden` unless `user` may access `key` as `access` says.
+    pub(crate) fn authorize(&self, user: Option<&str>, key: &str, access: KeyAccess) -> Result<()> {
+        if self.allows(user, key, access) {
+            Ok(())
+        } else {
+            Err(KvsError::Forbidden(key.to_owned()))
+        }
+    }
+
+    /// Whether `user`, or a shared token if it is `None`, may access `key` as `access` says.
+    /// Anyone who authenticated may access any key if there is no ACL.
+    pub(crate) fn allows(&self, user: Option<&str>, key: &str, access: KeyAccess) -> bool {
+        match self.acl {
+            Some(ref acl) => acl.allows(user, key, access),
+            None => true,
+        }
+    }
+}
+
+/// What a user may do with the keys an `Acl` grants them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyAccess {
+    /// Get them
+    Read,
+    /// Get, set and remove them
+    ReadWrite,
+}
+
+/// Which keys each user may read and write, set with `AuthConfig::acl`.
+///
+/// Users are granted read-only or read-write access to the keys that start with some prefixes,
+/// and may access no other key. A user granted the empty prefix may access every key, which
+/// replicating the store or taking part in its Raft cluster needs. Clients that authenticated
+/// with a shared token have no username to be granted anything, so they may access no key.
+///
+/// An ACL file grants access in TOML, with a table for each user:
+///
+/// ```toml
+/// [users.alice]
+/// read-write = ["team-a/"]
+/// read = ["shared/"]
+///
+/// [users.replica]
+/// read = [""]
+/// ```
+///
+/// Clones share their grants, so `Acl::reload` changes those of a running server.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    // the prefixes granted to each user, and what they may do with the keys starting with each
+    grants: Arc<RwLock<Grants>>,
+}
+
+type Grants = HashMap<String, Vec<(String, KeyAccess)>>;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AclFile {
+    #[serde(default)]
+    users: HashMap<String, UserGrants>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct UserGrants {
+    read: Vec<String>,
+    read_write: Vec<String>,
+}
+
+impl Acl {
+    /// Grant nothing, until access is granted.
+    pub fn new() -> Acl {
+        Acl::default()
+    }
+
+    /// Let `user` access the keys starting with `prefix` as `access` says.
+    pub fn grant(
+        &self,
+        user: impl Into<String>,
+        prefix: impl Into<String>,
+        access: KeyAccess,
+    ) -> &Acl {
+        self.write()
+            .entry(user.into())
+            .or_default()
+            .push((prefix.into(), access));
+        self
+    }
+
+    /// Read the grants from an ACL file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Acl> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// Replace the grants with those of an ACL file, for every clone of this ACL. The grants are
+    /// left as they are if the file can't be read.
+    pub fn reload(&self, path: impl AsRef<Path>) -> Result<()> {
+        let acl = Acl::from_file(path)?;
+        let grants = mem::take(&mut *acl.write());
+        *self.write() = grants;
+        Ok(())
+    }
+
+    fn allows(&self, user: Option<&str>, key: &str, access: KeyAccess) -> bool {
+        let grants = self.grants.read().expect("acl lock poisoned");
+        let granted = match user.and_then(|user| grants.get(user)) {
+            Some(granted) => granted,
+            None => return false,
+        };
+        granted
+            .iter()
+            .any(|(prefix, granted)| *granted >= access && key.starts_with(prefix.as_str()))
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Grants> {
+        self.grants.write().expect("acl lock poisoned")
+    }
+}
+
+impl FromStr for Acl {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Acl> {
+        let file: AclFile =
+            toml::from_str(s).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let acl = Acl::new();
+        for (user, grants) in file.users {
+            for prefix in grants.read {
+                acl.grant(user.as_str(), prefix, KeyAccess::Read);
+            }
+            for prefix in grants.read_write {
+                acl.grant(user.as_str(), prefix, KeyAccess::ReadWrite);
+            }
+        }
+        Ok(acl)
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
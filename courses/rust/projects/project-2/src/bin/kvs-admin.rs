@@ -0,0 +1,252 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use kvs::{KvStore, KvStoreOptions, Result, ShardedKvStore};
+use std::env::current_dir;
+use std::path::PathBuf;
+use std::process::exit;
+
+fn main() -> Result<()> {
+    env_logger::init();
+
+    let dir_arg = || {
+        Arg::with_name("DIR")
+            .help("The directory that holds the store [default: the current directory]")
+            .index(1)
+    };
+    let key_file_arg = || {
+        Arg::with_name("key-file")
+            .long("key-file")
+            .value_name("FILE")
+            .help("Reads the key of an encrypted store from a file")
+    };
+    let matches = App::new("kvs-admin")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Inspects and repairs the data files of a kvs store")
+        .setting(AppSettings::DisableHelpSubcommand)
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("List the records of every segment along with how much of it is live")
+                .arg(dir_arg())
+                .arg(key_file_arg())
+                .arg(
+                    Arg::with_name("summary")
+                        .long("summary")
+                        .help("Only print the summary of each segment"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check the checksums of every record, and exit with 1 if any is damaged")
+                .arg(dir_arg())
+                .arg(key_file_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("scrub")
+                .about("Open the store, drop the keys whose records are corrupt, and exit with 1 if any is")
+                .arg(dir_arg())
+                .arg(key_file_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("compact")
+                .about("Compact every segment that holds stale data")
+                .arg(dir_arg())
+                .arg(key_file_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("collect-values")
+                .about("Reclaim the overwritten and removed values of the value log")
+                .arg(dir_arg())
+                .arg(key_file_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .about("Cut damaged segments off after their last intact record")
+                .arg(dir_arg())
+                .arg(key_file_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("reshard")
+                .about("Move the keys of a sharded store to another number of shards, while it is stopped")
+                .arg(dir_arg())
+                .arg(key_file_arg())
+                .arg(
+                    Arg::with_name("shards")
+                        .long("shards")
+                        .value_name("N")
+                        .required(true)
+                        .help("Sets the number of shards"),
+                ),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        ("inspect", Some(matches)) => {
+            let store = options(matches)?.read_only(true).open(dir(matches)?)?;
+            let summary = matches.is_present("summary");
+            if !summary {
+                println!("segment\toffset\tlen\tlive\tkey\tvalue\texpires_at");
+            }
+            let segments = store.inspect(|record| {
+                if summary {
+                    return;
+                }
+                let value = match record.value {
+                    Some(value) if record.merge => format!("{} bytes merged", value.len()),
+                    Some(value) => format!("{} bytes", value.len()),
+                    None if record.prefix => "removed prefix".to_owned(),
+                    None => "removed".to_owned(),
+                };
+                let expires_at = match record.expires_at {
+                    Some(expires_at) => expires_at.to_string(),
+                    None => "-".to_owned(),
+                };
+                println!(
+                    "{}\t{}\t{}\t{}\t{:?}\t{}\t{}",
+                    record.segment,
+                    record.offset,
+                    record.len,
+                    record.live,
+                    String::from_utf8_lossy(&record.key),
+                    value,
+                    expires_at
+                );
+            })?;
+
+            if !summary {
+                println!();
+            }
+            println!("segment\tversion\tsize\trecords\tlive\tlive_bytes\tdead_bytes");
+            for segment in &segments {
+                let version = match segment.version {
+                    Some(version) => version.to_string(),
+                    None => "json".to_owned(),
+                };
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    segment.segment,
+                    version,
+                    segment.size,
+                    segment.records,
+                    segment.live_records,
+                    segment.live_bytes,
+                    segment.dead_bytes
+                );
+            }
+            let live_bytes: u64 = segments.iter().map(|segment| segment.live_bytes).sum();
+            let dead_bytes: u64 = segments.iter().map(|segment| segment.dead_bytes).sum();
+            println!(
+                "{} segments, {} live bytes, {} dead bytes",
+                segments.len(),
+                live_bytes,
+                dead_bytes
+            );
+        }
+        ("verify", Some(matches)) => {
+            let checks = options(matches)?.verify(dir(matches)?)?;
+            let mut damaged = false;
+            for check in &checks {
+                match check.error {
+                    None => println!(
+                        "{}.log: ok, {} records in {} bytes",
+                        check.segment, check.records, check.size
+                    ),
+                    Some(ref err) => {
+                        damaged = true;
+                        println!(
+                            "{}.log: damaged at byte {} of {} after {} records: {:?}",
+                            check.segment, check.valid_len, check.size, check.records, err
+                        );
+                    }
+                }
+            }
+            if damaged {
+                exit(1);
+            }
+        }
+        ("scrub", Some(matches)) => {
+            let store = options(matches)?.open(dir(matches)?)?;
+            let corrupt = store.scrub()?;
+            for record in &corrupt {
+                let keys: Vec<_> = record
+                    .keys
+                    .iter()
+                    .map(|key| String::from_utf8_lossy(key))
+                    .collect();
+                println!(
+                    "{}.log: corrupt record of {} bytes at byte {}, dropped keys: {:?}",
+                    record.segment, record.len, record.offset, keys
+                );
+            }
+            if !corrupt.is_empty() {
+                exit(1);
+            }
+        }
+        ("compact", Some(matches)) => {
+            let store = options(matches)?.open(dir(matches)?)?;
+            let before = store.stats()?;
+            store.compact()?;
+            let after = store.stats()?;
+            println!(
+                "{} dead bytes left in {} segments, down from {}",
+                after.dead_bytes, after.segments, before.dead_bytes
+            );
+        }
+        ("collect-values", Some(matches)) => {
+            let store = options(matches)?.open(dir(matches)?)?;
+            let reclaimed = store.collect_value_log()?;
+            println!(
+                "{} bytes reclaimed, {} bytes left in the value log",
+                reclaimed,
+                store.stats()?.value_log_bytes
+            );
+        }
+        ("repair", Some(matches)) => {
+            let checks = options(matches)?.repair(dir(matches)?)?;
+            let mut repaired = 0;
+            for check in checks.iter().filter(|check| !check.is_ok()) {
+                repaired += 1;
+                println!(
+                    "{}.log: cut off {} bytes after {} records",
+                    check.segment,
+                    check.size - check.valid_len,
+                    check.records
+                );
+            }
+            println!("Repaired {} of {} segments", repaired, checks.len());
+        }
+        ("reshard", Some(matches)) => {
+            let shards: usize = match matches
+                .value_of("shards")
+                .expect("shards argument missing")
+                .parse()
+            {
+                Ok(shards) => shards,
+                Err(err) => {
+                    eprintln!("Invalid number of shards: {}", err);
+                    exit(1);
+                }
+            };
+            let moved = ShardedKvStore::reshard(dir(matches)?, shards, &options(matches)?)?;
+            println!("Moved {} keys to {} shards", moved, shards);
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn dir(matches: &ArgMatches<'_>) -> Result<PathBuf> {
+    match matches.value_of("DIR") {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => Ok(current_dir()?),
+    }
+}
+
+fn options(matches: &ArgMatches<'_>) -> Result<KvStoreOptions> {
+    let mut options = KvStore::options();
+    if let Some(path) = matches.value_of("key-file") {
+        options.encryption_key_file(path)?;
+    }
+    Ok(options)
+}
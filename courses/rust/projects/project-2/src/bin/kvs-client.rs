@@ -0,0 +1,196 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use kvs::{repl, KvsClient, KvsError, Result};
+use std::process::exit;
+
+fn main() -> Result<()> {
+    let addr_arg = Arg::with_name("addr")
+        .long("addr")
+        .value_name("IP:PORT")
+        .help("Sets the server address")
+        .default_value("127.0.0.1:4000");
+    let tls_ca_arg = Arg::with_name("tls-ca")
+        .long("tls-ca")
+        .value_name("FILE")
+        .help("Talks to the server over TLS, trusting the certificate authorities in a PEM file");
+    let tls_server_name_arg = Arg::with_name("tls-server-name")
+        .long("tls-server-name")
+        .value_name("NAME")
+        .requires("tls-ca")
+        .help("Sets the name the server's certificate must be issued to [default: its IP]");
+
+    let auth_arg = Arg::with_name("auth")
+        .long("auth")
+        .value_name("CREDENTIALS")
+        .help("Authenticates with a shared token, or with USER:PASSWORD");
+    let codec_arg = Arg::with_name("codec")
+        .long("codec")
+        .value_name("CODEC")
+        .possible_values(&["json", "msgpack"])
+        .default_value("json")
+        .help("Sets what the messages are encoded with, if the server accepts it");
+
+    let matches = App::new("kvs-client")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .setting(AppSettings::DisableHelpSubcommand)
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("interactive")
+                .long("interactive")
+                .short("i")
+                .help("Starts an interactive shell on the server, see its help command"),
+        )
+        .arg(addr_arg.clone())
+        .arg(tls_ca_arg.clone())
+        .arg(tls_server_name_arg.clone())
+        .arg(auth_arg.clone())
+        .arg(codec_arg.clone())
+        .subcommand(
+            SubCommand::with_name("set")
+                .about("Set the value of a string key to a string")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(
+                    Arg::with_name("VALUE")
+                        .help("The string value of the key")
+                        .required(true),
+                )
+                .arg(addr_arg.clone())
+                .arg(tls_ca_arg.clone())
+                .arg(tls_server_name_arg.clone())
+                .arg(auth_arg.clone())
+                .arg(codec_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Get the string value of a given string key")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(addr_arg.clone())
+                .arg(tls_ca_arg.clone())
+                .arg(tls_server_name_arg.clone())
+                .arg(auth_arg.clone())
+                .arg(codec_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove a given key")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(addr_arg.clone())
+                .arg(tls_ca_arg.clone())
+                .arg(tls_server_name_arg.clone())
+                .arg(auth_arg.clone())
+                .arg(codec_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Print the statistics of the server's storage engine")
+                .arg(addr_arg.clone())
+                .arg(tls_ca_arg.clone())
+                .arg(tls_server_name_arg.clone())
+                .arg(auth_arg.clone())
+                .arg(codec_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("metrics")
+                .about("Print the statistics of the server's storage engine for Prometheus")
+                .arg(addr_arg.clone())
+                .arg(tls_ca_arg.clone())
+                .arg(tls_server_name_arg.clone())
+                .arg(auth_arg.clone())
+                .arg(codec_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("ping")
+                .about("Check that the server is up")
+                .arg(addr_arg)
+                .arg(tls_ca_arg)
+                .arg(tls_server_name_arg)
+                .arg(auth_arg)
+                .arg(codec_arg),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        ("set", Some(matches)) => {
+            let key = matches.value_of("KEY").expect("KEY argument missing");
+            let value = matches.value_of("VALUE").expect("VALUE argument missing");
+            let addr = matches.value_of("addr").expect("addr argument missing");
+
+            let mut client = connect(matches, addr)?;
+            client.set(key.to_string(), value.to_string())?;
+        }
+        ("get", Some(matches)) => {
+            let key = matches.value_of("KEY").expect("KEY argument missing");
+            let addr = matches.value_of("addr").expect("addr argument missing");
+
+            let mut client = connect(matches, addr)?;
+            if let Some(value) = client.get(key.to_string())? {
+                println!("{}", value);
+            } else {
+                println!("Key not found");
+            }
+        }
+        ("rm", Some(matches)) => {
+            let key = matches.value_of("KEY").expect("KEY argument missing");
+            let addr = matches.value_of("addr").expect("addr argument missing");
+
+            let mut client = connect(matches, addr)?;
+            match client.remove(key.to_string()) {
+                Ok(()) => {}
+                Err(KvsError::KeyNotFound) => {
+                    eprintln!("Key not found");
+                    exit(1);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        ("stats", Some(matches)) => {
+            let addr = matches.value_of("addr").expect("addr argument missing");
+
+            let mut client = connect(matches, addr)?;
+            println!("{}", client.stats()?);
+        }
+        ("metrics", Some(matches)) => {
+            let addr = matches.value_of("addr").expect("addr argument missing");
+
+            let mut client = connect(matches, addr)?;
+            print!("{}", client.stats()?.to_prometheus());
+        }
+        ("ping", Some(matches)) => {
+            let addr = matches.value_of("addr").expect("addr argument missing");
+
+            connect(matches, addr)?.ping()?;
+            println!("PONG");
+        }
+        ("", None) if matches.is_present("interactive") => {
+            let addr = matches.value_of("addr").expect("addr argument missing");
+
+            let mut client = connect(&matches, addr)?;
+            repl::run(&mut client, &format!("{}> ", addr))?;
+        }
+        ("", None) => {
+            eprintln!("{}", matches.usage());
+            exit(1);
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn connect(matches: &ArgMatches<'_>, addr: &str) -> Result<KvsClient> {
+    let mut options = KvsClient::options();
+    if let Some(path) = matches.value_of("tls-ca") {
+        options.tls_ca_file(path)?;
+    }
+    if let Some(name) = matches.value_of("tls-server-name") {
+        options.tls_server_name(name);
+    }
+    if let Some(credentials) = matches.value_of("auth") {
+        options.auth(credentials.parse()?);
+    }
+    if let Some(codec) = matches.value_of("codec") {
+        options.codec(codec.parse()?);
+    }
+    options.connect(addr)
+}
@@ -0,0 +1,236 @@
+// The messages of the FUSE kernel protocol, version 7, as `linux/fuse.h` lays them out: a
+// request read from `/dev/fuse` is a header followed by the arguments of its opcode, and a reply
+// written to it is a header followed by those of the answer, in native byte order.
+
+use std::convert::TryInto;
+
+pub const KERNEL_VERSION: u32 = 7;
+// The minor version of the protocol this speaks, which the kernel goes down to if it is older.
+pub const KERNEL_MINOR_VERSION: u32 = 31;
+// The minor version from which the reply to `INIT` has its longer layout.
+const INIT_OUT_LONG_MINOR: u32 = 23;
+
+pub const ROOT_ID: u64 = 1;
+
+pub const FUSE_LOOKUP: u32 = 1;
+pub const FUSE_FORGET: u32 = 2;
+pub const FUSE_GETATTR: u32 = 3;
+pub const FUSE_OPEN: u32 = 14;
+pub const FUSE_READ: u32 = 15;
+pub const FUSE_STATFS: u32 = 17;
+pub const FUSE_RELEASE: u32 = 18;
+pub const FUSE_FLUSH: u32 = 25;
+pub const FUSE_INIT: u32 = 26;
+pub const FUSE_OPENDIR: u32 = 27;
+pub const FUSE_READDIR: u32 = 28;
+pub const FUSE_RELEASEDIR: u32 = 29;
+pub const FUSE_INTERRUPT: u32 = 36;
+pub const FUSE_DESTROY: u32 = 38;
+pub const FUSE_BATCH_FORGET: u32 = 42;
+
+// `open_flags` of the reply to `OPEN`: the store doesn't change while it is mounted, so the pages
+// of a file read before stay valid.
+pub const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+pub const IN_HEADER_SIZE: usize = 40;
+const OUT_HEADER_SIZE: usize = 16;
+
+/// The header of a request.
+#[derive(Debug, Clone, Copy)]
+pub struct InHeader {
+    pub opcode: u32,
+    pub unique: u64,
+    pub nodeid: u64,
+}
+
+impl InHeader {
+    pub fn parse(request: &[u8]) -> Option<InHeader> {
+        if request.len() < IN_HEADER_SIZE {
+            return None;
+        }
+        Some(InHeader {
+            opcode: read_u32(request, 4)?,
+            unique: read_u64(request, 8)?,
+            nodeid: read_u64(request, 16)?,
+        })
+    }
+}
+
+pub fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    let bytes = bytes.get(at..at + 4)?;
+    Some(u32::from_ne_bytes(bytes.try_into().ok()?))
+}
+
+pub fn read_u64(bytes: &[u8], at: usize) -> Option<u64> {
+    let bytes = bytes.get(at..at + 8)?;
+    Some(u64::from_ne_bytes(bytes.try_into().ok()?))
+}
+
+/// The name a request is about, which ends with a nul byte.
+pub fn read_name(bytes: &[u8]) -> Option<&str> {
+    let end = bytes.iter().position(|&byte| byte == 0)?;
+    std::str::from_utf8(&bytes[..end]).ok()
+}
+
+/// A reply being written, starting with its header.
+pub struct Reply {
+    bytes: Vec<u8>,
+}
+
+impl Reply {
+    /// A reply to the request `unique`, with an error number of 0 for success.
+    pub fn new(unique: u64, error: i32) -> Reply {
+        let mut bytes = Vec::with_capacity(OUT_HEADER_SIZE);
+        // the length is filled in once it is known
+        bytes.extend_from_slice(&0u32.to_ne_bytes());
+        bytes.extend_from_slice(&(-error).to_ne_bytes());
+        bytes.extend_from_slice(&unique.to_ne_bytes());
+        Reply { bytes }
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Reply {
+        self.bytes.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Reply {
+        self.bytes.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Reply {
+        self.bytes.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Reply {
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    /// `struct fuse_attr`
+    pub fn attr(&mut self, attr: &Attr) -> &mut Reply {
+        self.u64(attr.ino)
+            .u64(attr.size)
+            .u64(attr.size.div_ceil(512))
+            // atime, mtime and ctime
+            .u64(attr.time)
+            .u64(attr.time)
+            .u64(attr.time)
+            .u32(0)
+            .u32(0)
+            .u32(0)
+            .u32(attr.mode)
+            .u32(attr.nlink)
+            .u32(attr.uid)
+            .u32(attr.gid)
+            // rdev, blksize, flags
+            .u32(0)
+            .u32(4096)
+            .u32(0)
+    }
+
+    /// `struct fuse_entry_out`, for `LOOKUP`
+    pub fn entry(&mut self, attr: &Attr, valid_secs: u64) -> &mut Reply {
+        self.u64(attr.ino)
+            // generation: inodes are never reused
+            .u64(0)
+            .u64(valid_secs)
+            .u64(valid_secs)
+            .u32(0)
+            .u32(0)
+            .attr(attr)
+    }
+
+    /// `struct fuse_attr_out`, for `GETATTR`
+    pub fn attr_out(&mut self, attr: &Attr, valid_secs: u64) -> &mut Reply {
+        self.u64(valid_secs).u32(0).u32(0).attr(attr)
+    }
+
+    /// `struct fuse_open_out`, for `OPEN` and `OPENDIR`
+    pub fn open(&mut self, fh: u64, open_flags: u32) -> &mut Reply {
+        self.u64(fh).u32(open_flags).u32(0)
+    }
+
+    /// `struct fuse_init_out`, accepting the `max_readahead` of the kernel and asking for none of
+    /// its optional features.
+    pub fn init(&mut self, minor: u32, max_readahead: u32, max_write: u32) -> &mut Reply {
+        self.u32(KERNEL_VERSION)
+            .u32(minor)
+            .u32(max_readahead)
+            .u32(0);
+        if minor < INIT_OUT_LONG_MINOR {
+            return self.u16(0).u16(0).u32(max_write);
+        }
+        // max_background, congestion_threshold, max_write, time_gran, max_pages, map_alignment,
+        // flags2, then unused
+        self.u16(16)
+            .u16(12)
+            .u32(max_write)
+            .u32(1)
+            .u16(0)
+            .u16(0)
+            .u32(0)
+            .bytes(&[0; 28])
+    }
+
+    /// `struct fuse_statfs_out`
+    pub fn statfs(&mut self, files: u64) -> &mut Reply {
+        // blocks, bfree, bavail, files, ffree
+        self.u64(0)
+            .u64(0)
+            .u64(0)
+            .u64(files)
+            .u64(0)
+            // bsize, namelen, frsize, padding
+            .u32(512)
+            .u32(255)
+            .u32(512)
+            .u32(0)
+            .bytes(&[0; 24])
+    }
+
+    /// The reply with its length filled in.
+    pub fn finish(&mut self) -> &[u8] {
+        let len = self.bytes.len() as u32;
+        self.bytes[..4].copy_from_slice(&len.to_ne_bytes());
+        &self.bytes
+    }
+}
+
+/// What `GETATTR` and `LOOKUP` tell of a file or directory.
+#[derive(Debug, Clone, Copy)]
+pub struct Attr {
+    pub ino: u64,
+    pub size: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    // seconds since the Unix epoch
+    pub time: u64,
+}
+
+/// Append a `struct fuse_dirent` to `buf`, padded to 8 bytes, unless it would take it past
+/// `max` bytes. Returns whether it was appended.
+pub fn push_dirent(
+    buf: &mut Vec<u8>,
+    max: usize,
+    ino: u64,
+    off: u64,
+    kind: u32,
+    name: &str,
+) -> bool {
+    let len = (24 + name.len()).next_multiple_of(8);
+    if buf.len() + len > max {
+        return false;
+    }
+    let start = buf.len();
+    buf.extend_from_slice(&ino.to_ne_bytes());
+    buf.extend_from_slice(&off.to_ne_bytes());
+    buf.extend_from_slice(&(name.len() as u32).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(name.as_bytes());
+    buf.resize(start + len, 0);
+    true
+}
@@ -0,0 +1,315 @@
+// The keys of a store as a tree of files: each key is split at its slashes, into the directories
+// that lead to it and the file that holds its value.
+//
+// A key that can't be a path, one with an empty component like `a//b` or `a/`, or with a `.` or
+// `..` component, is left out, and so is a key that is also a directory, like `a` with `a/b`.
+// The store is opened read-only, so the tree doesn't change while it is mounted.
+
+use std::collections::{BTreeMap, HashMap};
+
+use kvs::{KvStore, Result};
+
+use super::abi::{Attr, ROOT_ID};
+
+// The `d_type` of the entries of a directory.
+const DT_DIR: u32 = 4;
+const DT_REG: u32 = 8;
+
+/// A file or directory of the tree.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Node {
+    // the prefix of the keys in the directory, which ends with a slash but for the root
+    Dir(String),
+    // the key, and the length of its value once it is known
+    File(String, Option<u64>),
+}
+
+/// A file or directory listed by `READDIR`.
+pub struct Entry {
+    pub name: String,
+    pub ino: u64,
+    pub kind: u32,
+}
+
+/// The tree of a store, with an inode number for each file and directory looked up so far.
+pub struct KeyFs {
+    store: KvStore,
+    nodes: HashMap<u64, Node>,
+    inodes: HashMap<String, u64>,
+    next_ino: u64,
+    uid: u32,
+    gid: u32,
+    // when the store was mounted, given as the time of every file
+    time: u64,
+}
+
+impl KeyFs {
+    pub fn new(store: KvStore, uid: u32, gid: u32, time: u64) -> KeyFs {
+        let mut nodes = HashMap::new();
+        nodes.insert(ROOT_ID, Node::Dir(String::new()));
+        let mut inodes = HashMap::new();
+        inodes.insert(String::new(), ROOT_ID);
+        KeyFs {
+            store,
+            nodes,
+            inodes,
+            next_ino: ROOT_ID + 1,
+            uid,
+            gid,
+            time,
+        }
+    }
+
+    pub fn store(&self) -> &KvStore {
+        &self.store
+    }
+
+    /// The attributes of the inode `ino`, or `None` if it isn't known.
+    pub fn getattr(&mut self, ino: u64) -> Result<Option<Attr>> {
+        let node = match self.nodes.get(&ino) {
+            Some(node) => node.clone(),
+            None => return Ok(None),
+        };
+        let size = match node {
+            Node::Dir(_) => None,
+            Node::File(key, None) => {
+                let len = match self.store.value_len(key.clone())? {
+                    Some(len) => len,
+                    None => return Ok(None),
+                };
+                self.nodes.insert(ino, Node::File(key, Some(len)));
+                Some(len)
+            }
+            Node::File(_, Some(len)) => Some(len),
+        };
+        Ok(Some(self.attr(ino, size)))
+    }
+
+    /// The file or directory `name` in the directory `parent`, if there is one.
+    pub fn lookup(&mut self, parent: u64, name: &str) -> Result<Option<Attr>> {
+        let prefix = match self.nodes.get(&parent) {
+            Some(Node::Dir(prefix)) => prefix.clone(),
+            _ => return Ok(None),
+        };
+        if !is_component(name) {
+            return Ok(None);
+        }
+        let dir = format!("{}{}/", prefix, name);
+        let is_dir = self
+            .store
+            .scan_keys(&dir)
+            .any(|key| is_path(&key[dir.len()..]));
+        let ino = if is_dir {
+            self.inode(Node::Dir(dir))
+        } else {
+            let key = format!("{}{}", prefix, name);
+            if !self.store.contains_key(&key) {
+                return Ok(None);
+            }
+            self.inode(Node::File(key, None))
+        };
+        self.getattr(ino)
+    }
+
+    /// Whether the inode `ino` is a directory.
+    pub fn is_dir(&self, ino: u64) -> bool {
+        matches!(self.nodes.get(&ino), Some(Node::Dir(_)))
+    }
+
+    /// The files and directories in the directory `ino`, or `None` if it isn't one.
+    pub fn readdir(&mut self, ino: u64) -> Option<Vec<Entry>> {
+        let prefix = match self.nodes.get(&ino) {
+            Some(Node::Dir(prefix)) => prefix.clone(),
+            _ => return None,
+        };
+        // whether each name is a directory, which wins over a key of the same name
+        let mut children: BTreeMap<String, bool> = BTreeMap::new();
+        for key in self.store.scan_keys(&prefix) {
+            let rest = &key[prefix.len()..];
+            if !is_path(rest) {
+                continue;
+            }
+            match rest.split_once('/') {
+                Some((name, _)) => {
+                    children.insert(name.to_owned(), true);
+                }
+                None => {
+                    children.entry(rest.to_owned()).or_insert(false);
+                }
+            }
+        }
+        let mut entries = vec![
+            Entry {
+                name: ".".to_owned(),
+                ino,
+                kind: DT_DIR,
+            },
+            Entry {
+                name: "..".to_owned(),
+                ino: self.parent(&prefix),
+                kind: DT_DIR,
+            },
+        ];
+        for (name, is_dir) in children {
+            let (node, kind) = if is_dir {
+                (Node::Dir(format!("{}{}/", prefix, name)), DT_DIR)
+            } else {
+                (Node::File(format!("{}{}", prefix, name), None), DT_REG)
+            };
+            let ino = self.inode(node);
+            entries.push(Entry { name, ino, kind });
+        }
+        Some(entries)
+    }
+
+    /// The `size` bytes of the file `ino` from `offset` on, or as many as it holds, or `None` if
+    /// it isn't a file or its key was removed.
+    pub fn read(&self, ino: u64, offset: u64, size: u32) -> Result<Option<Vec<u8>>> {
+        let key = match self.nodes.get(&ino) {
+            Some(Node::File(key, _)) => key.clone(),
+            _ => return Ok(None),
+        };
+        let mut bytes = Vec::new();
+        if !self
+            .store
+            .get_range(key, offset, u64::from(size), &mut bytes)?
+        {
+            return Ok(None);
+        }
+        Ok(Some(bytes))
+    }
+
+    /// The inode of `node`, given one if it has none yet.
+    fn inode(&mut self, node: Node) -> u64 {
+        let path = match &node {
+            Node::Dir(prefix) => prefix,
+            Node::File(key, _) => key,
+        };
+        if let Some(&ino) = self.inodes.get(path) {
+            return ino;
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.inodes.insert(path.clone(), ino);
+        self.nodes.insert(ino, node);
+        ino
+    }
+
+    /// The inode of the directory holding the directory `prefix`.
+    fn parent(&mut self, prefix: &str) -> u64 {
+        let trimmed = prefix.strip_suffix('/').unwrap_or(prefix);
+        match trimmed.rfind('/') {
+            Some(end) => self.inode(Node::Dir(trimmed[..=end].to_owned())),
+            None => ROOT_ID,
+        }
+    }
+
+    fn attr(&self, ino: u64, size: Option<u64>) -> Attr {
+        let (mode, nlink) = match size {
+            None => (libc::S_IFDIR | 0o555, 2),
+            Some(_) => (libc::S_IFREG | 0o444, 1),
+        };
+        Attr {
+            ino,
+            size: size.unwrap_or(0),
+            mode,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            time: self.time,
+        }
+    }
+}
+
+/// Whether `name` can be a file or directory name.
+fn is_component(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".."
+}
+
+/// Whether a key, or what is left of it after a prefix, can be a path.
+fn is_path(key: &str) -> bool {
+    key.split('/').all(is_component)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // The tree of a store holding `keys`, each set to its own name.
+    fn key_fs(keys: &[&str]) -> (TempDir, KeyFs) {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        for key in keys {
+            store.set(key.to_string(), key.to_string()).unwrap();
+        }
+        drop(store);
+        let store = KvStore::options()
+            .read_only(true)
+            .open(temp_dir.path())
+            .unwrap();
+        (temp_dir, KeyFs::new(store, 0, 0, 0))
+    }
+
+    fn names(fs: &mut KeyFs, ino: u64) -> Vec<String> {
+        let entries = fs.readdir(ino).unwrap();
+        entries.into_iter().map(|entry| entry.name).collect()
+    }
+
+    #[test]
+    fn paths() {
+        assert!(is_component("a"));
+        assert!(is_component("..."));
+        assert!(!is_component(""));
+        assert!(!is_component("."));
+        assert!(!is_component(".."));
+
+        assert!(is_path("a"));
+        assert!(is_path("a/b/c"));
+        assert!(!is_path(""));
+        assert!(!is_path("/a"));
+        assert!(!is_path("a/"));
+        assert!(!is_path("a//b"));
+        assert!(!is_path("a/./b"));
+        assert!(!is_path("a/.."));
+    }
+
+    #[test]
+    fn keys_that_are_not_paths_are_left_out() {
+        let (_dir, mut fs) = key_fs(&["a", "/b", "c/", "d//e", "f/./g", "..", "h/..", "i/j"]);
+        assert_eq!(names(&mut fs, ROOT_ID), [".", "..", "a", "i"]);
+        for name in ["", ".", "..", "c", "d", "f", "h"] {
+            assert!(fs.lookup(ROOT_ID, name).unwrap().is_none(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn directory_shadows_key() {
+        let (_dir, mut fs) = key_fs(&["a", "a/b"]);
+        assert_eq!(names(&mut fs, ROOT_ID), [".", "..", "a"]);
+        let a = fs.lookup(ROOT_ID, "a").unwrap().unwrap();
+        assert!(fs.is_dir(a.ino));
+        assert_eq!(names(&mut fs, a.ino), [".", "..", "b"]);
+
+        let b = fs.lookup(a.ino, "b").unwrap().unwrap();
+        assert!(!fs.is_dir(b.ino));
+        assert_eq!(b.size, 3);
+        assert_eq!(fs.read(b.ino, 1, 10).unwrap(), Some(b"/b".to_vec()));
+        assert_eq!(fs.read(a.ino, 0, 10).unwrap(), None);
+    }
+
+    #[test]
+    fn parent_inodes() {
+        let (_dir, mut fs) = key_fs(&["a/b/c"]);
+        let entries = fs.readdir(ROOT_ID).unwrap();
+        assert_eq!((entries[0].ino, entries[1].ino), (ROOT_ID, ROOT_ID));
+
+        let a = fs.lookup(ROOT_ID, "a").unwrap().unwrap().ino;
+        let b = fs.lookup(a, "b").unwrap().unwrap().ino;
+        let entries = fs.readdir(b).unwrap();
+        assert_eq!((entries[0].ino, entries[1].ino), (b, a));
+        let entries = fs.readdir(a).unwrap();
+        assert_eq!((entries[0].ino, entries[1].ino), (a, ROOT_ID));
+        assert_eq!(entries[2].ino, b);
+    }
+}
@@ -0,0 +1,360 @@
+#[macro_use]
+extern crate log;
+
+use clap::{App, Arg};
+use kvs::Result;
+use std::env::current_dir;
+use std::path::PathBuf;
+
+#[cfg(target_os = "linux")]
+mod abi;
+#[cfg(target_os = "linux")]
+mod fs;
+#[cfg(target_os = "linux")]
+mod mount;
+
+fn main() -> Result<()> {
+    env_logger::init();
+
+    let matches = App::new("kvs-fuse")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about(
+            "Mounts the keys of a kvs store as a read-only filesystem, each key a file named by \
+             its path, until interrupted or unmounted",
+        )
+        .arg(
+            Arg::with_name("MOUNTPOINT")
+                .help("The directory to mount the store on")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("dir")
+                .long("dir")
+                .value_name("DIR")
+                .help("The directory that holds the store [default: the current directory]"),
+        )
+        .get_matches();
+
+    let mountpoint = PathBuf::from(
+        matches
+            .value_of("MOUNTPOINT")
+            .expect("MOUNTPOINT argument missing"),
+    );
+    let dir = match matches.value_of("dir") {
+        Some(dir) => dir.into(),
+        None => current_dir()?,
+    };
+    run(dir, mountpoint)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn run(_dir: PathBuf, _mountpoint: PathBuf) -> Result<()> {
+    error!("kvs-fuse only runs on Linux");
+    std::process::exit(1);
+}
+
+#[cfg(target_os = "linux")]
+fn run(dir: PathBuf, mountpoint: PathBuf) -> Result<()> {
+    use self::fs::KeyFs;
+    use kvs::KvStore;
+    use std::time::SystemTime;
+
+    // before the store starts its threads, for them to block the signals too
+    signals::block();
+    let store = KvStore::options().read_only(true).open(dir)?;
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    // SAFETY: `getuid` and `getgid` always succeed.
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let mut fs = KeyFs::new(store, uid, gid, time);
+
+    let (fuse, unmount) = mount::mount(&mountpoint)?;
+    info!("Mounted the store on {}", mountpoint.display());
+    signals::handle(unmount.clone())?;
+    let result = session::serve(&fuse, &mut fs);
+    if result.is_err() {
+        unmount.unmount();
+    }
+    info!("Unmounted the store from {}", mountpoint.display());
+    result
+}
+
+// Answering the requests the kernel reads from the filesystem.
+#[cfg(target_os = "linux")]
+mod session {
+    use super::abi::{self, InHeader, Reply, IN_HEADER_SIZE};
+    use super::fs::{Entry, KeyFs};
+    use kvs::Result;
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::{Read, Write};
+
+    // The most a `READ` asks for, and the room for it in the buffer a request is read into.
+    const MAX_READ: u32 = 128 * 1024;
+    const BUFFER_SIZE: usize = MAX_READ as usize + 4096;
+
+    // How long the kernel may keep the attributes and names it was told, which don't change.
+    const VALID_SECS: u64 = 60;
+
+    /// The state of a mount: the files and directories opened.
+    struct Session<'a> {
+        fs: &'a mut KeyFs,
+        // the inode of each file opened
+        files: HashMap<u64, u64>,
+        dirs: HashMap<u64, Vec<Entry>>,
+        next_fh: u64,
+    }
+
+    /// Answer the requests read from `fuse` until the filesystem is unmounted.
+    pub fn serve(fuse: &File, fs: &mut KeyFs) -> Result<()> {
+        let mut session = Session {
+            fs,
+            files: HashMap::new(),
+            dirs: HashMap::new(),
+            next_fh: 1,
+        };
+        let mut buf = vec![0; BUFFER_SIZE];
+        loop {
+            let len = match (&*fuse).read(&mut buf) {
+                Ok(len) => len,
+                Err(err) => match err.raw_os_error() {
+                    // the request was interrupted before it was read
+                    Some(libc::ENOENT) | Some(libc::EINTR) | Some(libc::EAGAIN) => continue,
+                    Some(libc::ENODEV) => return Ok(()),
+                    _ => return Err(err.into()),
+                },
+            };
+            let header = match InHeader::parse(&buf[..len]) {
+                Some(header) => header,
+                None => {
+                    warn!(
+                        "Dropping a request of {} bytes, too short for a header",
+                        len
+                    );
+                    continue;
+                }
+            };
+            if let Some(mut reply) = session.handle(header, &buf[IN_HEADER_SIZE..len]) {
+                if let Err(err) = (&*fuse).write_all(reply.finish()) {
+                    // a reply to an interrupted request is turned down
+                    if err.raw_os_error() != Some(libc::ENOENT) {
+                        return Err(err.into());
+                    }
+                }
+            }
+        }
+    }
+
+    impl<'a> Session<'a> {
+        /// The reply to a request, or `None` for those that get none.
+        fn handle(&mut self, header: InHeader, args: &[u8]) -> Option<Reply> {
+            let reply = match header.opcode {
+                abi::FUSE_FORGET | abi::FUSE_BATCH_FORGET | abi::FUSE_INTERRUPT => return None,
+                abi::FUSE_INIT => init(header.unique, args),
+                abi::FUSE_DESTROY | abi::FUSE_FLUSH => Ok(Reply::new(header.unique, 0)),
+                abi::FUSE_LOOKUP => self.lookup(header, args),
+                abi::FUSE_GETATTR => self.getattr(header),
+                abi::FUSE_OPEN => self.open(header),
+                abi::FUSE_READ => self.read(header, args),
+                abi::FUSE_RELEASE => {
+                    if let Some(fh) = abi::read_u64(args, 0) {
+                        self.files.remove(&fh);
+                    }
+                    Ok(Reply::new(header.unique, 0))
+                }
+                abi::FUSE_OPENDIR => self.opendir(header),
+                abi::FUSE_READDIR => self.readdir(header, args),
+                abi::FUSE_RELEASEDIR => {
+                    if let Some(fh) = abi::read_u64(args, 0) {
+                        self.dirs.remove(&fh);
+                    }
+                    Ok(Reply::new(header.unique, 0))
+                }
+                abi::FUSE_STATFS => {
+                    let mut reply = Reply::new(header.unique, 0);
+                    reply.statfs(self.fs.store().len() as u64);
+                    Ok(reply)
+                }
+                _ => Ok(Reply::new(header.unique, libc::ENOSYS)),
+            };
+            Some(reply.unwrap_or_else(|err| {
+                error!("Failed to answer request {}: {}", header.opcode, err);
+                Reply::new(header.unique, libc::EIO)
+            }))
+        }
+
+        fn lookup(&mut self, header: InHeader, args: &[u8]) -> Result<Reply> {
+            let attr = match abi::read_name(args) {
+                Some(name) => self.fs.lookup(header.nodeid, name)?,
+                None => None,
+            };
+            Ok(match attr {
+                Some(attr) => {
+                    let mut reply = Reply::new(header.unique, 0);
+                    reply.entry(&attr, VALID_SECS);
+                    reply
+                }
+                None => Reply::new(header.unique, libc::ENOENT),
+            })
+        }
+
+        fn getattr(&mut self, header: InHeader) -> Result<Reply> {
+            Ok(match self.fs.getattr(header.nodeid)? {
+                Some(attr) => {
+                    let mut reply = Reply::new(header.unique, 0);
+                    reply.attr_out(&attr, VALID_SECS);
+                    reply
+                }
+                None => Reply::new(header.unique, libc::ENOENT),
+            })
+        }
+
+        // Each read is served from the store, of only the bytes it asks for.
+        fn open(&mut self, header: InHeader) -> Result<Reply> {
+            if self.fs.is_dir(header.nodeid) {
+                return Ok(Reply::new(header.unique, libc::EISDIR));
+            }
+            if self.fs.getattr(header.nodeid)?.is_none() {
+                return Ok(Reply::new(header.unique, libc::ENOENT));
+            }
+            let fh = self.fh();
+            self.files.insert(fh, header.nodeid);
+            let mut reply = Reply::new(header.unique, 0);
+            reply.open(fh, abi::FOPEN_KEEP_CACHE);
+            Ok(reply)
+        }
+
+        fn read(&mut self, header: InHeader, args: &[u8]) -> Result<Reply> {
+            let (fh, offset, size) = match (
+                abi::read_u64(args, 0),
+                abi::read_u64(args, 8),
+                abi::read_u32(args, 16),
+            ) {
+                (Some(fh), Some(offset), Some(size)) => (fh, offset, size),
+                _ => return Ok(Reply::new(header.unique, libc::EINVAL)),
+            };
+            let ino = match self.files.get(&fh) {
+                Some(&ino) => ino,
+                None => return Ok(Reply::new(header.unique, libc::EBADF)),
+            };
+            let bytes = match self.fs.read(ino, offset, size)? {
+                Some(bytes) => bytes,
+                None => return Ok(Reply::new(header.unique, libc::ENOENT)),
+            };
+            let mut reply = Reply::new(header.unique, 0);
+            reply.bytes(&bytes);
+            Ok(reply)
+        }
+
+        // The entries are listed once the directory is opened, for the offsets of `READDIR` to
+        // stay valid until it is released.
+        fn opendir(&mut self, header: InHeader) -> Result<Reply> {
+            let entries = match self.fs.readdir(header.nodeid) {
+                Some(entries) => entries,
+                None => return Ok(Reply::new(header.unique, libc::ENOTDIR)),
+            };
+            let fh = self.fh();
+            self.dirs.insert(fh, entries);
+            let mut reply = Reply::new(header.unique, 0);
+            reply.open(fh, 0);
+            Ok(reply)
+        }
+
+        fn readdir(&mut self, header: InHeader, args: &[u8]) -> Result<Reply> {
+            let (fh, offset, size) = match (
+                abi::read_u64(args, 0),
+                abi::read_u64(args, 8),
+                abi::read_u32(args, 16),
+            ) {
+                (Some(fh), Some(offset), Some(size)) => (fh, offset, size),
+                _ => return Ok(Reply::new(header.unique, libc::EINVAL)),
+            };
+            let entries = match self.dirs.get(&fh) {
+                Some(entries) => entries,
+                None => return Ok(Reply::new(header.unique, libc::EBADF)),
+            };
+            // the offset of an entry is that of the next one, so that listing resumes after it
+            let mut buf = Vec::new();
+            for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
+                let next = i as u64 + 1;
+                if !abi::push_dirent(
+                    &mut buf,
+                    size as usize,
+                    entry.ino,
+                    next,
+                    entry.kind,
+                    &entry.name,
+                ) {
+                    break;
+                }
+            }
+            let mut reply = Reply::new(header.unique, 0);
+            reply.bytes(&buf);
+            Ok(reply)
+        }
+
+        fn fh(&mut self) -> u64 {
+            let fh = self.next_fh;
+            self.next_fh += 1;
+            fh
+        }
+    }
+
+    // Agree on the version of the protocol with the kernel.
+    fn init(unique: u64, args: &[u8]) -> Result<Reply> {
+        let (major, minor, max_readahead) = match (
+            abi::read_u32(args, 0),
+            abi::read_u32(args, 4),
+            abi::read_u32(args, 8),
+        ) {
+            (Some(major), Some(minor), Some(max_readahead)) => (major, minor, max_readahead),
+            _ => return Ok(Reply::new(unique, libc::EINVAL)),
+        };
+        if major < abi::KERNEL_VERSION {
+            error!("The kernel speaks FUSE {}.{}, older than 7", major, minor);
+            return Ok(Reply::new(unique, libc::EPROTO));
+        }
+        // a newer major version is answered with ours, for the kernel to go down to it
+        let minor = if major > abi::KERNEL_VERSION {
+            abi::KERNEL_MINOR_VERSION
+        } else {
+            minor.min(abi::KERNEL_MINOR_VERSION)
+        };
+        let mut reply = Reply::new(unique, 0);
+        reply.init(minor, max_readahead, MAX_READ);
+        Ok(reply)
+    }
+}
+
+// Unmounting the store on SIGINT and SIGTERM, which ends the session.
+#[cfg(target_os = "linux")]
+mod signals {
+    use super::mount::Unmount;
+    use kvs::{signals, Result};
+    use std::thread;
+
+    const SIGNALS: &[libc::c_int] = &[libc::SIGINT, libc::SIGTERM];
+
+    // Block SIGINT and SIGTERM in this thread and the threads it starts, so that they wait for
+    // `handle` instead of killing the process with the store mounted.
+    pub fn block() {
+        signals::block(SIGNALS);
+    }
+
+    // Unmount the store on SIGINT or SIGTERM.
+    pub fn handle(unmount: Unmount) -> Result<()> {
+        thread::Builder::new()
+            .name("kvs-signals".to_owned())
+            .spawn(move || {
+                if let Err(err) = signals::wait(SIGNALS) {
+                    error!("Failed to wait for signals: {}", err);
+                    return;
+                }
+                info!("Received a signal, unmounting");
+                unmount.unmount();
+            })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+// Mounting the filesystem: with mount(2) as root, and otherwise with the setuid `fusermount3`
+// (or `fusermount`) of libfuse, which mounts it for us and passes the opened `/dev/fuse` back
+// over a socket.
+
+use std::ffi::{CStr, CString};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr;
+
+use kvs::Result;
+
+// The options of the mount, those `fusermount` takes.
+const OPTIONS: &str = "ro,nosuid,nodev,default_permissions,fsname=kvs,subtype=kvs";
+
+/// Unmounts the filesystem, which ends the session reading from it.
+#[derive(Debug, Clone)]
+pub struct Unmount {
+    mountpoint: PathBuf,
+    fusermount: bool,
+}
+
+impl Unmount {
+    pub fn unmount(&self) {
+        let result = if self.fusermount {
+            fusermount(&["-u", "-z", "--"], &self.mountpoint, None).and_then(|status| {
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(io::Error::other(format!(
+                        "fusermount exited with {}",
+                        status
+                    )))
+                }
+            })
+        } else {
+            c_path(&self.mountpoint).and_then(|path| {
+                // SAFETY: the path is a valid C string.
+                match unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } {
+                    0 => Ok(()),
+                    _ => Err(io::Error::last_os_error()),
+                }
+            })
+        };
+        if let Err(err) = result {
+            error!("Failed to unmount {}: {}", self.mountpoint.display(), err);
+        }
+    }
+}
+
+/// Mount the filesystem on `mountpoint`, returning `/dev/fuse` opened for it.
+pub fn mount(mountpoint: &Path) -> Result<(File, Unmount)> {
+    let mountpoint = mountpoint.canonicalize()?;
+    // SAFETY: `geteuid` always succeeds.
+    let fusermount = unsafe { libc::geteuid() } != 0;
+    let fuse = if fusermount {
+        mount_with_fusermount(&mountpoint)?
+    } else {
+        mount_directly(&mountpoint)?
+    };
+    Ok((
+        fuse,
+        Unmount {
+            mountpoint,
+            fusermount,
+        },
+    ))
+}
+
+fn mount_directly(mountpoint: &Path) -> io::Result<File> {
+    let fuse = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")?;
+    // SAFETY: `getuid` and `getgid` always succeed.
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let data = CString::new(format!(
+        "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+        fuse.as_raw_fd(),
+        uid,
+        gid
+    ))?;
+    let path = c_path(mountpoint)?;
+    let source = CStr::from_bytes_with_nul(b"kvs\0").expect("nul-terminated");
+    let fstype = CStr::from_bytes_with_nul(b"fuse.kvs\0").expect("nul-terminated");
+    let flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_RDONLY;
+    // SAFETY: the strings are valid C strings, and the data is the options string FUSE expects.
+    let mounted = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            path.as_ptr(),
+            fstype.as_ptr(),
+            flags,
+            data.as_ptr().cast(),
+        )
+    };
+    if mounted != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fuse)
+}
+
+fn mount_with_fusermount(mountpoint: &Path) -> io::Result<File> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two sockets.
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the sockets were just created, and are owned by nothing else.
+    let (ours, theirs) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    let status = fusermount(&["-o", OPTIONS, "--"], mountpoint, Some(&theirs))?;
+    drop(theirs);
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "fusermount exited with {}",
+            status
+        )));
+    }
+    receive_fd(&ours)
+}
+
+/// Run `fusermount3`, or `fusermount` if there is none, handing it `socket` to send `/dev/fuse`
+/// over.
+fn fusermount(
+    args: &[&str],
+    mountpoint: &Path,
+    socket: Option<&OwnedFd>,
+) -> io::Result<std::process::ExitStatus> {
+    let run = |program: &str| {
+        let mut command = Command::new(program);
+        command.args(args).arg(mountpoint);
+        if let Some(socket) = socket {
+            command.env("_FUSE_COMMFD", socket.as_raw_fd().to_string());
+        }
+        command.status()
+    };
+    match run("fusermount3") {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => run("fusermount"),
+        result => result,
+    }
+}
+
+/// Receive the file descriptor `fusermount` sends over `socket`.
+fn receive_fd(socket: &OwnedFd) -> io::Result<File> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: byte.len(),
+    };
+    // aligned for a `cmsghdr`, with room for one file descriptor
+    let mut control = [0u64; 8];
+    // SAFETY: a zeroed `msghdr` is valid, and is pointed at live buffers before it is used.
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = mem::size_of_val(&control) as _;
+    // SAFETY: the message points at buffers that outlive the call.
+    if unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the message was filled in by `recvmsg`, and its control buffer is still alive.
+    unsafe {
+        let header = libc::CMSG_FIRSTHDR(&message);
+        if header.is_null()
+            || (*header).cmsg_level != libc::SOL_SOCKET
+            || (*header).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(io::Error::other("fusermount sent no file descriptor"));
+        }
+        let fd: libc::c_int = ptr::read_unaligned(libc::CMSG_DATA(header).cast());
+        Ok(File::from_raw_fd(fd))
+    }
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
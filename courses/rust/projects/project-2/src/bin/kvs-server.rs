@@ -0,0 +1,513 @@
+#[macro_use]
+extern crate log;
+
+use clap::{App, Arg, ArgMatches};
+use kvs::raft::RaftOptions;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    detect_engine, open_engine, AccessLog, Acl, AnyEngine, AuthConfig, ConfigFile, Engine, KvStore,
+    KvsClient, KvsEngine, KvsServer, Protocol, Replica, Result, ShardedKvStore,
+};
+use log::LevelFilter;
+use std::env::current_dir;
+use std::net::{SocketAddr, TcpListener};
+use std::process::exit;
+use std::thread;
+use std::time::Duration;
+
+// The directory the Raft log is kept in, under the directory of the store.
+const RAFT_DIR: &str = "raft";
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+// Reloads the tunable settings of the config file, on SIGHUP.
+type Reload = Box<dyn Fn() + Send>;
+
+fn main() -> Result<()> {
+    // before any thread is started, for every thread to block them too
+    #[cfg(unix)]
+    signals::block();
+    // The level is raised or lowered with `log::set_max_level` from then on.
+    env_logger::builder()
+        .filter_level(LevelFilter::Trace)
+        .init();
+    log::set_max_level(LevelFilter::Info);
+
+    let matches = App::new("kvs-server")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP:PORT")
+                .help("Sets the listening address [default: 127.0.0.1:4000]"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Reads settings from a TOML file, whose tunable ones are reloaded on SIGHUP"),
+        )
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .value_name("ENGINE-NAME")
+                .help("Sets the storage engine [default: the one of the store in the current directory, or kvs]")
+                .possible_values(&["kvs", "sled"]),
+        )
+        .arg(
+            Arg::with_name("protocol")
+                .long("protocol")
+                .value_name("PROTOCOL")
+                .help("Sets the protocol spoken to clients")
+                .possible_values(&["native", "resp"])
+                .default_value("native"),
+        )
+        .arg(
+            Arg::with_name("key-file")
+                .long("key-file")
+                .value_name("FILE")
+                .help("Encrypts the store with the key in a file, of 32 bytes or 64 hex digits"),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .value_name("FILE")
+                .requires("tls-key")
+                .help("Serves clients over TLS with the certificate chain in a PEM file"),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .value_name("FILE")
+                .requires("tls-cert")
+                .help("Sets the PEM file holding the private key of the TLS certificate"),
+        )
+        .arg(
+            Arg::with_name("auth-file")
+                .long("auth-file")
+                .value_name("FILE")
+                .help(
+                    "Requires clients to authenticate with a token or USER:PASSWORD line of a file",
+                ),
+        )
+        .arg(
+            Arg::with_name("acl-file")
+                .long("acl-file")
+                .value_name("FILE")
+                .requires("auth-file")
+                .help(
+                    "Restricts each user to the key prefixes a TOML file grants them read or \
+                     read-write access to, reloaded on SIGHUP",
+                ),
+        )
+        .arg(
+            Arg::with_name("replica-of")
+                .long("replica-of")
+                .value_name("IP:PORT")
+                .help("Serves reads from a copy of the store of another server, kept up to date"),
+        )
+        .arg(
+            Arg::with_name("leader-auth")
+                .long("leader-auth")
+                .value_name("CREDENTIALS")
+                .requires("replica-of")
+                .help("Authenticates with the leader with a shared token, or with USER:PASSWORD"),
+        )
+        .arg(
+            Arg::with_name("leader-tls-ca")
+                .long("leader-tls-ca")
+                .value_name("FILE")
+                .requires("replica-of")
+                .help(
+                    "Talks to the leader over TLS, trusting the certificate authorities in a file",
+                ),
+        )
+        .arg(
+            Arg::with_name("shards")
+                .long("shards")
+                .value_name("N")
+                .conflicts_with("replica-of")
+                .help("Spreads the keys of the kvs engine over N stores, to take more writes at once"),
+        )
+        .arg(
+            Arg::with_name("raft-cluster")
+                .long("raft-cluster")
+                .value_name("IP:PORT,...")
+                .use_delimiter(true)
+                .conflicts_with("replica-of")
+                .help("Replicates the store with Raft across the servers at these addresses, --addr among them"),
+        )
+        .arg(
+            Arg::with_name("raft-auth")
+                .long("raft-auth")
+                .value_name("CREDENTIALS")
+                .requires("raft-cluster")
+                .help("Authenticates with the other servers of the cluster with a shared token, or with USER:PASSWORD"),
+        )
+        .arg(
+            Arg::with_name("raft-tls-ca")
+                .long("raft-tls-ca")
+                .value_name("FILE")
+                .requires("raft-cluster")
+                .help("Talks to the other servers of the cluster over TLS, trusting the certificate authorities in a file"),
+        )
+        .arg(
+            Arg::with_name("max-connections")
+                .long("max-connections")
+                .value_name("N")
+                .help("Turns down connections once N are open [default: no limit]"),
+        )
+        .arg(
+            Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .value_name("SECONDS")
+                .help("Closes connections that send no request for this long [default: none]"),
+        )
+        .arg(
+            Arg::with_name("read-timeout")
+                .long("read-timeout")
+                .value_name("SECONDS")
+                .help("Closes connections that stall for this long while sending a request [default: none]"),
+        )
+        .arg(
+            Arg::with_name("metrics-addr")
+                .long("metrics-addr")
+                .value_name("IP:PORT")
+                .help("Serves metrics for Prometheus over HTTP at /metrics on this address"),
+        )
+        .arg(
+            Arg::with_name("grpc-addr")
+                .long("grpc-addr")
+                .value_name("IP:PORT")
+                .help("Serves the gRPC service of proto/kvs.proto on this address too"),
+        )
+        .arg(
+            Arg::with_name("http-addr")
+                .long("http-addr")
+                .value_name("IP:PORT")
+                .help("Serves a REST gateway at /keys on this address too"),
+        )
+        .arg(
+            Arg::with_name("write-timeout")
+                .long("write-timeout")
+                .value_name("SECONDS")
+                .help("Closes connections that don't take a response for this long [default: none]"),
+        )
+        .arg(
+            Arg::with_name("slow-log")
+                .long("slow-log")
+                .value_name("SECONDS")
+                .help("Logs the requests, and the reads and writes of the kvs engine, that take this long or longer, e.g. 0.1 [default: none]"),
+        )
+        .arg(
+            Arg::with_name("access-log")
+                .long("access-log")
+                .value_name("KEYS")
+                .possible_values(&["keys", "hashed-keys"])
+                .help("Logs every request with its client, trace id, key, outcome and latency, with its key as it is or hashed [default: off]"),
+        )
+        .get_matches();
+
+    let config = match matches.value_of("config") {
+        Some(path) => ConfigFile::from_file(path)?,
+        None => ConfigFile::default(),
+    };
+    log::set_max_level(config.log_level());
+    let addr = matches
+        .value_of("addr")
+        .or(config.addr.as_deref())
+        .unwrap_or(DEFAULT_ADDR)
+        .to_owned();
+    let engine: Engine = match (matches.value_of("engine"), config.engine) {
+        (Some(engine), _) => engine.parse()?,
+        (None, Some(engine)) => engine,
+        (None, None) => detect_engine(current_dir()?)?.unwrap_or(Engine::Kvs),
+    };
+    let protocol: Protocol = matches
+        .value_of("protocol")
+        .expect("protocol argument missing")
+        .parse()?;
+
+    info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+    info!("Storage engine: {:?}", engine);
+    info!("Protocol: {:?}", protocol);
+    info!("TLS: {}", matches.is_present("tls-cert"));
+    info!("Authentication: {}", matches.is_present("auth-file"));
+    if let Some(path) = matches.value_of("acl-file") {
+        info!("ACL: {}", path);
+    }
+    if let Some(shards) = matches.value_of("shards") {
+        info!("Shards: {}", shards);
+    }
+    if let Some(leader) = matches.value_of("replica-of") {
+        info!("Replica of {}", leader);
+    }
+    if let Some(peers) = matches.values_of("raft-cluster") {
+        info!("Raft cluster: {}", peers.collect::<Vec<_>>().join(", "));
+    }
+    info!("Listening on {}", addr);
+
+    let mut options = KvStore::options();
+    config.configure(&mut options);
+    options.slow_log_threshold(seconds(&matches, "slow-log"));
+    if let Some(path) = matches.value_of("key-file") {
+        if engine != Engine::Kvs {
+            error!("Encryption is only supported by the kvs engine");
+            exit(1);
+        }
+        options.encryption_key_file(path)?;
+    }
+
+    if let Some(shards) = matches.value_of("shards") {
+        let shards: usize = shards.parse().unwrap_or_else(|err| {
+            error!("Invalid number of shards: {}", err);
+            exit(1);
+        });
+        if engine != Engine::Kvs {
+            error!("Sharding is only supported by the kvs engine");
+            exit(1);
+        }
+        let engine = ShardedKvStore::open_with(current_dir()?, shards, &options)?;
+        let reload = reloader(&matches, config, engine.shards().to_vec());
+        return start(engine, &matches, protocol, &addr, reload);
+    }
+
+    let engine = match engine {
+        Engine::Kvs => AnyEngine::Kvs(options.open(current_dir()?)?),
+        Engine::Sled => open_engine(engine, current_dir()?)?,
+    };
+    // kept alive for as long as the server runs
+    let _replica = match matches.value_of("replica-of") {
+        None => None,
+        Some(leader) => {
+            let store = match engine {
+                AnyEngine::Kvs(ref store) => store.clone(),
+                AnyEngine::Sled(_) => {
+                    error!("Replication is only supported by the kvs engine");
+                    exit(1);
+                }
+            };
+            let mut options = KvsClient::options();
+            if let Some(credentials) = matches.value_of("leader-auth") {
+                options.auth(credentials.parse()?);
+            }
+            if let Some(path) = matches.value_of("leader-tls-ca") {
+                options.tls_ca_file(path)?;
+            }
+            Some(Replica::start(store, leader, &options)?)
+        }
+    };
+    let stores = engine.as_kv_store().cloned().into_iter().collect();
+    let reload = reloader(&matches, config, stores);
+    start(engine, &matches, protocol, &addr, reload)
+}
+
+// What to do on SIGHUP: apply the tunable settings of the config file to `stores`, if there is a
+// config file.
+fn reloader(matches: &ArgMatches<'_>, started: ConfigFile, stores: Vec<KvStore>) -> Reload {
+    let path = match matches.value_of("config") {
+        Some(path) => path.to_owned(),
+        None => return Box::new(|| info!("No config file to reload")),
+    };
+    Box::new(move || match ConfigFile::from_file(&path) {
+        Ok(config) => {
+            if config.addr != started.addr || config.engine != started.engine {
+                warn!("The address and the engine only change when the server restarts");
+            }
+            for store in &stores {
+                config.tune(store);
+            }
+            info!("Reloaded the configuration from {}", path);
+            log::set_max_level(config.log_level());
+        }
+        Err(err) => error!("Keeping the current configuration: {}", err),
+    })
+}
+
+// What to do on SIGHUP: `reload`, and read the grants of `acl` from its file again.
+fn reload_acl(reload: Reload, acl: Acl, path: String) -> Reload {
+    Box::new(move || {
+        reload();
+        match acl.reload(&path) {
+            Ok(()) => info!("Reloaded the ACL from {}", path),
+            Err(err) => error!("Keeping the current ACL: {}", err),
+        }
+    })
+}
+
+// Serve `engine`, replicated with Raft if the server is part of a cluster.
+fn start<E: KvsEngine>(
+    engine: E,
+    matches: &ArgMatches<'_>,
+    protocol: Protocol,
+    addr: &str,
+    reload: Reload,
+) -> Result<()> {
+    match matches.values_of("raft-cluster") {
+        None => serve(engine, matches, protocol, addr, 0, reload),
+        Some(peers) => {
+            let peers = peers
+                .map(|peer| peer.parse())
+                .collect::<std::result::Result<Vec<SocketAddr>, _>>()
+                .unwrap_or_else(|err| {
+                    error!("Invalid Raft cluster address: {}", err);
+                    exit(1);
+                });
+            let me = match addr
+                .parse()
+                .ok()
+                .and_then(|addr: SocketAddr| peers.iter().position(|&peer| peer == addr))
+            {
+                Some(me) => me,
+                None => {
+                    error!("--addr must be one of the addresses of --raft-cluster");
+                    exit(1);
+                }
+            };
+            let mut peer_options = KvsClient::options();
+            peer_options
+                .connect_timeout(Some(Duration::from_millis(200)))
+                .read_timeout(Some(Duration::from_secs(1)))
+                .write_timeout(Some(Duration::from_secs(1)));
+            if let Some(credentials) = matches.value_of("raft-auth") {
+                peer_options.auth(credentials.parse()?);
+            }
+            if let Some(path) = matches.value_of("raft-tls-ca") {
+                peer_options.tls_ca_file(path)?;
+            }
+            let others = peers.len() - 1;
+            let engine = RaftOptions::default().peer_options(peer_options).start(
+                engine,
+                current_dir()?.join(RAFT_DIR),
+                peers,
+                me,
+            )?;
+            serve(engine, matches, protocol, addr, others, reload)
+        }
+    }
+}
+
+fn serve<E: KvsEngine>(
+    engine: E,
+    matches: &ArgMatches<'_>,
+    protocol: Protocol,
+    addr: &str,
+    peers: usize,
+    mut reload: Reload,
+) -> Result<()> {
+    // on top of a thread for the connection of each other node of a Raft cluster
+    let threads = (thread::available_parallelism().map_or(4, |n| n.get()) + peers) as u32;
+    let pool = SharedQueueThreadPool::new(threads)?;
+    let max_connections = matches.value_of("max-connections").map(|max| {
+        max.parse().unwrap_or_else(|err| {
+            error!("Invalid maximum number of connections: {}", err);
+            exit(1);
+        })
+    });
+    let mut server = KvsServer::new(engine, pool)
+        .protocol(protocol)
+        .read_only(matches.is_present("replica-of"))
+        .max_connections(max_connections)
+        .idle_timeout(seconds(matches, "idle-timeout"))
+        .read_timeout(seconds(matches, "read-timeout"))
+        .write_timeout(seconds(matches, "write-timeout"))
+        .slow_log_threshold(seconds(matches, "slow-log"))
+        .access_log(match matches.value_of("access-log") {
+            Some("keys") => AccessLog::Keys,
+            Some(_) => AccessLog::HashedKeys,
+            None => AccessLog::Off,
+        });
+    if let (Some(cert), Some(key)) = (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+        server = server.tls_files(cert, key)?;
+    }
+    if let Some(path) = matches.value_of("auth-file") {
+        let mut auth = AuthConfig::from_file(path)?;
+        if let Some(path) = matches.value_of("acl-file") {
+            let acl = Acl::from_file(path)?;
+            auth.acl(acl.clone());
+            reload = reload_acl(reload, acl, path.to_owned());
+        }
+        server = server.auth(auth);
+    }
+    if let Some(addr) = matches.value_of("metrics-addr") {
+        server = server.metrics(TcpListener::bind(addr)?);
+    }
+    if let Some(addr) = matches.value_of("grpc-addr") {
+        server = server.grpc(TcpListener::bind(addr)?);
+    }
+    if let Some(addr) = matches.value_of("http-addr") {
+        server = server.http(TcpListener::bind(addr)?);
+    }
+    #[cfg(unix)]
+    signals::handle(server.shutdown_handle(), reload)?;
+    #[cfg(not(unix))]
+    drop(reload);
+    server.run(addr)
+}
+
+// The duration in seconds of an argument, if it is given.
+fn seconds(matches: &ArgMatches<'_>, name: &str) -> Option<Duration> {
+    let value = matches.value_of(name)?;
+    match value
+        .parse()
+        .ok()
+        .and_then(|s| Duration::try_from_secs_f64(s).ok())
+    {
+        Some(duration) if duration > Duration::ZERO => Some(duration),
+        _ => {
+            error!("Invalid --{}: {}", name, value);
+            exit(1);
+        }
+    }
+}
+
+// Shutting the server down on SIGINT and SIGTERM, and reloading its config file on SIGHUP.
+#[cfg(unix)]
+mod signals {
+    use super::Reload;
+    use kvs::{signals, Result, ShutdownHandle};
+    use std::process::exit;
+    use std::thread;
+
+    const SIGNALS: &[libc::c_int] = &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+    // Block SIGINT, SIGTERM and SIGHUP in this thread and the threads it starts, so that they wait
+    // for `handle` instead of killing the process.
+    pub fn block() {
+        signals::block(SIGNALS);
+    }
+
+    // Shut the server down on the first SIGINT or SIGTERM, and exit at once on the next. Reload
+    // the config file on SIGHUP.
+    pub fn handle(shutdown: ShutdownHandle, reload: Reload) -> Result<()> {
+        thread::Builder::new()
+            .name("kvs-signals".to_owned())
+            .spawn(move || loop {
+                let signal = match signals::wait(SIGNALS) {
+                    Ok(signal) => signal,
+                    Err(err) => {
+                        error!("Failed to wait for signals: {}", err);
+                        return;
+                    }
+                };
+                if signal == libc::SIGHUP {
+                    info!("Received SIGHUP, reloading the configuration");
+                    reload();
+                    continue;
+                }
+                let name = if signal == libc::SIGINT {
+                    "SIGINT"
+                } else {
+                    "SIGTERM"
+                };
+                if shutdown.is_shutdown() {
+                    warn!("Received {} again, exiting without shutting down", name);
+                    exit(128 + signal);
+                }
+                info!("Received {}, shutting down", name);
+                shutdown.shutdown();
+            })?;
+        Ok(())
+    }
+}
@@ -2,13 +2,26 @@
 extern crate log;
 
 use clap::{App, AppSettings, Arg, SubCommand};
-use kvs::{KvStore, KvsError, Result};
+use kvs::{repl, DumpFormat, FormatVersion, KvStore, KvsError, Result};
+use serde::Serialize;
 use std::env::current_dir;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
 use std::process::exit;
 
 fn main() -> Result<()> {
     env_logger::init();
 
+    let output_arg = Arg::with_name("output")
+        .long("output")
+        .value_name("FORMAT")
+        .possible_values(&["json", "raw", "tsv"])
+        .help(
+            "Prints the value as a line of JSON, as it is without a newline, or as KEY<tab>VALUE, \
+             exiting with 0 if the key is found, 1 if not and 2 on an error",
+        );
+
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -29,13 +42,105 @@ fn main() -> Result<()> {
         .subcommand(
             SubCommand::with_name("get")
                 .about("Get the string value of a given string key")
-                .arg(Arg::with_name("KEY").help("A string key").required(true)),
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(output_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("Print the keys that start with a prefix and their values, in sorted order")
+                .arg(Arg::with_name("PREFIX").help("The prefix [default: every key]"))
+                .arg(output_arg.default_value("tsv").help(
+                    "Prints each key and value as a line of JSON, each value as it is \
+                             on a line of its own but the last, or each as KEY<tab>VALUE, \
+                             exiting with 0 if a key is found, 1 if not and 2 on an error",
+                )),
         )
         .subcommand(
             SubCommand::with_name("rm")
                 .about("Remove a given key")
                 .arg(Arg::with_name("KEY").help("A string key").required(true)),
         )
+        .subcommand(
+            SubCommand::with_name("repl")
+                .about("Start an interactive shell on the store, see its help command"),
+        )
+        .subcommand(
+            SubCommand::with_name("exec")
+                .about("Run the commands of the shell in a file, one per line, printing JSON lines")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("The commands to run, or - for stdin")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Copy the store to a directory")
+                .arg(
+                    Arg::with_name("DIR")
+                        .help("The directory to write the backup to")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Replace the contents of the store with a backup")
+                .arg(
+                    Arg::with_name("DIR")
+                        .help("The directory that holds the backup")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write every key and its value to stdout")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Sets the format of the dump")
+                        .possible_values(&["jsonl", "csv"])
+                        .default_value("jsonl"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Set the keys of a dump written by export")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("The dump to import, or - for stdin")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help(
+                            "Sets the format of the dump [default: csv for .csv files, else jsonl]",
+                        )
+                        .possible_values(&["jsonl", "csv"]),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Rewrite the segments of a store in older formats in the current one")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("VERSION")
+                        .help("Sets the format the segments are expected in, e.g. json or v3"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("VERSION")
+                        .help("Sets the format to migrate to, which must be the current one"),
+                )
+                .arg(
+                    Arg::with_name("DIR")
+                        .help("The directory of the store [default: the current directory]"),
+                ),
+        )
         .get_matches();
 
     match matches.subcommand() {
@@ -43,9 +148,15 @@ fn main() -> Result<()> {
             let key = matches.value_of("KEY").expect("KEY argument missing");
             let value = matches.value_of("VALUE").expect("VALUE argument missing");
 
-            let mut store = KvStore::open(current_dir()?)?;
+            let store = KvStore::open(current_dir()?)?;
             store.set(key.to_string(), value.to_string())?;
         }
+        ("get", Some(matches)) if matches.is_present("output") => {
+            let key = matches.value_of("KEY").expect("KEY argument missing");
+            let output = Output::from_name(matches.value_of("output").expect("output missing"));
+
+            exit_found(get(key, output));
+        }
         ("get", Some(matches)) => {
             let key = matches.value_of("KEY").expect("KEY argument missing");
 
@@ -58,10 +169,16 @@ fn main() -> Result<()> {
                 println!("Key not found");
             }
         }
+        ("scan", Some(matches)) => {
+            let prefix = matches.value_of("PREFIX").unwrap_or("");
+            let output = Output::from_name(matches.value_of("output").expect("output missing"));
+
+            exit_found(scan(prefix, output));
+        }
         ("rm", Some(matches)) => {
             let key = matches.value_of("KEY").expect("KEY argument missing");
 
-            let mut store = KvStore::open(current_dir()?)?;
+            let store = KvStore::open(current_dir()?)?;
             match store.remove(key.to_string()) {
                 Ok(()) => {}
                 Err(KvsError::KeyNotFound) => {
@@ -71,7 +188,185 @@ fn main() -> Result<()> {
                 Err(e) => return Err(e),
             }
         }
+        ("repl", Some(_)) => {
+            let mut store = KvStore::open(current_dir()?)?;
+            repl::run(&mut store, "kvs> ")?;
+        }
+        ("exec", Some(matches)) => {
+            let file = matches.value_of("FILE").expect("FILE argument missing");
+
+            let mut store = KvStore::open(current_dir()?)?;
+            let stdout = io::stdout();
+            let output = BufWriter::new(stdout.lock());
+            let succeeded = if file == "-" {
+                repl::exec(&mut store, io::stdin().lock(), output)?
+            } else {
+                repl::exec(&mut store, BufReader::new(File::open(file)?), output)?
+            };
+            if !succeeded {
+                // close the store, which exiting would skip
+                drop(store);
+                exit(1);
+            }
+        }
+        ("backup", Some(matches)) => {
+            let dir = matches.value_of("DIR").expect("DIR argument missing");
+
+            let store = KvStore::open(current_dir()?)?;
+            store.backup(dir)?;
+        }
+        ("restore", Some(matches)) => {
+            let dir = matches.value_of("DIR").expect("DIR argument missing");
+
+            let store = KvStore::open(current_dir()?)?;
+            store.restore(dir)?;
+        }
+        ("export", Some(matches)) => {
+            let format: DumpFormat = matches
+                .value_of("format")
+                .expect("format argument missing")
+                .parse()?;
+
+            let store = KvStore::options().read_only(true).open(current_dir()?)?;
+            store.export(io::stdout().lock(), format)?;
+        }
+        ("import", Some(matches)) => {
+            let file = matches.value_of("FILE").expect("FILE argument missing");
+            let format: DumpFormat = match matches.value_of("format") {
+                Some(format) => format.parse()?,
+                None if Path::new(file).extension() == Some("csv".as_ref()) => DumpFormat::Csv,
+                None => DumpFormat::JsonLines,
+            };
+
+            let store = KvStore::open(current_dir()?)?;
+            let count = if file == "-" {
+                store.import(io::stdin().lock(), format)?
+            } else {
+                store.import(File::open(file)?, format)?
+            };
+            info!("Imported {} keys", count);
+        }
+        ("migrate", Some(matches)) => {
+            let from: Option<FormatVersion> =
+                matches.value_of("from").map(str::parse).transpose()?;
+            if let Some(to) = matches.value_of("to") {
+                let to: FormatVersion = to.parse()?;
+                if to != FormatVersion::current() {
+                    eprintln!(
+                        "Can only migrate to the current format, {}",
+                        FormatVersion::current()
+                    );
+                    exit(1);
+                }
+            }
+            let dir = match matches.value_of("DIR") {
+                Some(dir) => dir.into(),
+                None => current_dir()?,
+            };
+
+            let migrated = KvStore::migrate(dir, from)?;
+            println!(
+                "Migrated {} segments to {}",
+                migrated.len(),
+                FormatVersion::current()
+            );
+        }
         _ => unreachable!(),
     }
     Ok(())
 }
+
+// How `get` and `scan` print the keys they find, with `--output`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Json,
+    Raw,
+    Tsv,
+}
+
+impl Output {
+    fn from_name(name: &str) -> Output {
+        match name {
+            "json" => Output::Json,
+            "raw" => Output::Raw,
+            "tsv" => Output::Tsv,
+            _ => unreachable!(),
+        }
+    }
+
+    // Print a key and its value, after those printed before it if it isn't the `first`.
+    fn print(self, out: &mut impl Write, key: &str, value: &str, first: bool) -> Result<()> {
+        match self {
+            Output::Json => {
+                serde_json::to_writer(&mut *out, &Pair { key, value })?;
+                writeln!(out)?;
+            }
+            // a newline goes between the values rather than after each, for a lone value to be
+            // printed as it is
+            Output::Raw => {
+                if !first {
+                    writeln!(out)?;
+                }
+                write!(out, "{}", value)?;
+            }
+            Output::Tsv => writeln!(out, "{}\t{}", escape_tsv(key), escape_tsv(value))?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct Pair<'a> {
+    key: &'a str,
+    value: &'a str,
+}
+
+// Escape the backslashes, tabs and line endings of a field of TSV, as `\\`, `\t`, `\n` and `\r`.
+fn escape_tsv(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+// Print the value of `key`, returning whether it exists.
+fn get(key: &str, output: Output) -> Result<bool> {
+    let store = KvStore::open(current_dir()?)?;
+    let value = match store.get(key.to_owned())? {
+        Some(value) => value,
+        None => return Ok(false),
+    };
+    let mut out = io::stdout().lock();
+    output.print(&mut out, key, &value, true)?;
+    out.flush()?;
+    Ok(true)
+}
+
+// Print the keys that start with `prefix` and their values, returning whether there are any.
+fn scan(prefix: &str, output: Output) -> Result<bool> {
+    let store = KvStore::options().read_only(true).open(current_dir()?)?;
+    let mut out = BufWriter::new(io::stdout().lock());
+    let mut found = false;
+    for pair in store.scan_prefix(prefix) {
+        let (key, value) = pair?;
+        output.print(&mut out, &key, &value, !found)?;
+        found = true;
+    }
+    out.flush()?;
+    Ok(found)
+}
+
+// Exit with 0 if a key was found, 1 if not and 2 on an error, for `get` and `scan` to be checked
+// in scripts. A reader of the output that went away early, like `head`, isn't an error.
+fn exit_found(found: Result<bool>) -> ! {
+    match found {
+        Ok(true) => exit(0),
+        Ok(false) => exit(1),
+        Err(KvsError::IoError(err)) if err.kind() == io::ErrorKind::BrokenPipe => exit(0),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            exit(2);
+        }
+    }
+}
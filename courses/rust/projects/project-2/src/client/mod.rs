@@ -0,0 +1,563 @@
+use std::convert::TryFrom;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rustls::pki_types::ServerName;
+
+use self::retry::failure_kind;
+
+use crate::auth::Credentials;
+use crate::common::{
+    write_message, ChunkReader, ChunkWriter, Codec, ReplicationMessage, Request, RequestId,
+    Response, ServerInfo, CHUNK_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::error::KvsError::{
+    self, AuthFailed, AuthRequired, IoError, KeyNotFound, ServerError, UnexpectedEOF,
+};
+use crate::error::Result;
+use crate::raft::Message as RaftMessage;
+use crate::tls::Stream;
+use crate::Stats;
+
+pub use self::multiplexed::MultiplexedClient;
+pub use self::options::ClientOptions;
+pub use self::pipeline::Pipeline;
+pub use self::pool::{KvsClientPool, PooledClient};
+pub use self::retry::{RetryOn, RetryPolicy};
+pub use self::sharded::ShardedClient;
+
+mod multiplexed;
+mod options;
+mod pipeline;
+mod pool;
+mod retry;
+mod sharded;
+
+/// A client that talks to a `KvsServer`.
+///
+/// If the connection breaks, the client connects again for the next request. A request that
+/// fails because the connection turns out to have been closed, e.g. because the server restarted,
+/// or because the server didn't answer in time, is sent once more on a new connection, or as
+/// `ClientOptions::retry` says. Sets and removes carry an id with which the server applies them
+/// only once, so they are safe to send again, and a `remove` sent again doesn't report
+/// `KeyNotFound` for the key it removed the first time. Servers from before the ids ignore them.
+pub struct KvsClient {
+    addrs: Vec<SocketAddr>,
+    options: ClientOptions,
+    connection: Option<Connection>,
+    // tells the writes of the client apart from those of other clients
+    id: String,
+    // the number of the last write sent
+    last_request: u64,
+    // the trace id to send the next request with
+    trace_id: Option<String>,
+    // the trace id the server answered the last request with
+    last_trace_id: Option<String>,
+}
+
+struct Connection {
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+    // what the messages are encoded with, once the server agreed to it
+    codec: Codec,
+    // the codec to ask the server for
+    preferred: Codec,
+    // what the server said about itself, once it did
+    server: Option<ServerInfo>,
+}
+
+impl KvsClient {
+    /// Connect to the server listening on `addr`.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        KvsClient::options().connect(addr)
+    }
+
+    /// Options to connect with, starting from the defaults.
+    pub fn options() -> ClientOptions {
+        ClientOptions::default()
+    }
+
+    fn connect_with(addrs: Vec<SocketAddr>, options: ClientOptions) -> Result<KvsClient> {
+        let connection = Connection::open(&addrs, &options)?;
+        Ok(KvsClient {
+            addrs,
+            options,
+            connection: Some(connection),
+            id: client_id(),
+            last_request: 0,
+            trace_id: None,
+            last_trace_id: None,
+        })
+    }
+
+    /// Retrieve the value of a key from the server.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        into_value(self.request(Request::Get { key })?)
+    }
+
+    /// Retrieve the values of several keys from the server in a single request, in the same
+    /// order as `keys`.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self.request(Request::GetMany { keys })? {
+            Response::Values(values) => Ok(values),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Check whether a key exists on the server, which doesn't read its value if its engine can
+    /// tell without doing so.
+    ///
+    /// Fails with `UnsupportedProtocolVersion` if the server is from before version 2 of the
+    /// protocol, which added the request.
+    pub fn exists(&mut self, key: String) -> Result<bool> {
+        let version = self.server_info()?.version;
+        if version < 2 {
+            return Err(KvsError::UnsupportedProtocolVersion(version));
+        }
+        match self.request(Request::Exists { key })? {
+            Response::Exists(exists) => Ok(exists),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Set a key on the server.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_numbered(key, value).map(|_| ())
+    }
+
+    /// Set a key on the server, and return the sequence number its engine gave the write, if it
+    /// numbers its writes.
+    pub fn set_numbered(&mut self, key: String, value: String) -> Result<Option<u64>> {
+        let id = Some(self.next_request_id());
+        into_seq(self.request(Request::Set { key, value, id })?)
+    }
+
+    /// Remove a key on the server.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_numbered(key).map(|_| ())
+    }
+
+    /// Remove a key on the server, and return the sequence number its engine gave the write, if
+    /// it numbers its writes.
+    pub fn remove_numbered(&mut self, key: String) -> Result<Option<u64>> {
+        let id = Some(self.next_request_id());
+        into_seq(self.request(Request::Remove { key, id })?)
+    }
+
+    /// Set a key on the server to the `len` bytes read from `reader`, which are sent in chunks
+    /// rather than held in memory. Unlike the other requests, it isn't sent again on a new
+    /// connection if the server had closed this one, since `reader` was consumed.
+    pub fn set_reader(&mut self, key: String, reader: impl Read, len: u64) -> Result<()> {
+        let result = self.connection()?.set_reader(key, reader, len);
+        into_value(self.check(result)?).map(|_| ())
+    }
+
+    /// Write the value of a key on the server to `writer` as it is received in chunks, and
+    /// return whether the key exists.
+    pub fn get_writer(&mut self, key: String, writer: impl Write) -> Result<bool> {
+        let result = self.connection()?.get_writer(key, writer);
+        self.check(result)
+    }
+
+    /// Retrieve the statistics of the server's storage engine.
+    pub fn stats(&mut self) -> Result<Stats> {
+        match self.request(Request::Stats)? {
+            Response::Stats(stats) => Ok(stats),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Check that the server is up. This works without authenticating.
+    pub fn ping(&mut self) -> Result<()> {
+        into_value(self.request(Request::Ping)?).map(|_| ())
+    }
+
+    /// Authenticate the connection with `credentials`, which unlocks the other requests on a
+    /// server that requires it. Fails with `AuthFailed` if the server doesn't accept them.
+    ///
+    /// The credentials are kept to authenticate the connections opened after this one breaks.
+    pub fn auth(&mut self, credentials: Credentials) -> Result<()> {
+        into_value(self.request(Request::Auth(credentials.clone()))?)?;
+        self.options.auth = Some(credentials);
+        Ok(())
+    }
+
+    /// Send the next request with `trace_id`, which the server logs it with in its access log and
+    /// answers it with, to tie the logs of the server to the traces of the application. Only
+    /// `get`, `get_many`, `exists`, `set`, `remove`, `stats`, `ping` and `auth` are traced. Servers
+    /// from before version 4 of the protocol are sent the request without it.
+    ///
+    /// ```no_run
+    /// # use kvs::KvsClient;
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// let value = client.trace("checkout-7f3a").get("key".to_owned())?;
+    /// assert_eq!(client.last_trace_id(), Some("checkout-7f3a"));
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    pub fn trace(&mut self, trace_id: impl Into<String>) -> &mut KvsClient {
+        self.trace_id = Some(trace_id.into());
+        self
+    }
+
+    /// The trace id the server answered the last request with, if it was traced.
+    pub fn last_trace_id(&self) -> Option<&str> {
+        self.last_trace_id.as_deref()
+    }
+
+    /// What the server said about itself when the client connected: the version of the protocol
+    /// it speaks, the codec the connection uses and what the server can do.
+    pub fn server_info(&mut self) -> Result<ServerInfo> {
+        let result = self.connection()?.greet().cloned();
+        self.check(result)
+    }
+
+    /// Start a pipeline, to send several requests without waiting for each response.
+    ///
+    /// ```no_run
+    /// # use kvs::KvsClient;
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// let responses = client
+    ///     .pipeline()
+    ///     .set("key".to_owned(), "value".to_owned())
+    ///     .get("key".to_owned())
+    ///     .send()?;
+    /// assert_eq!(responses[1].as_ref().ok(), Some(&Some("value".to_owned())));
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline::new(self)
+    }
+
+    /// Turn the connection into the replication stream of the server's store. See
+    /// `Request::Replicate`.
+    pub(crate) fn replicate(
+        mut self,
+        id: Option<String>,
+        offset: u64,
+    ) -> Result<ReplicationStream> {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => Connection::open(&self.addrs, &self.options)?,
+        };
+        connection.greet()?;
+        let request = Request::Replicate { id, offset };
+        connection
+            .codec
+            .write_message(&mut connection.writer, &request)?;
+        connection.writer.flush()?;
+        Ok(ReplicationStream { connection })
+    }
+
+    /// Send a message to another node of a Raft cluster, and return its reply.
+    pub(crate) fn raft(&mut self, message: RaftMessage) -> Result<RaftMessage> {
+        match self.request(Request::Raft(message))? {
+            Response::Raft(reply) => Ok(reply),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    fn request(&mut self, request: Request) -> Result<Response> {
+        let request = match self.trace_id.take() {
+            Some(trace_id) => Request::Traced {
+                trace_id,
+                request: Box::new(request),
+            },
+            None => request,
+        };
+        self.last_trace_id = None;
+        let mut attempt = 1;
+        loop {
+            // whether the request failed before it was sent, for want of a connection
+            let (result, connecting) = match self.connection() {
+                Ok(connection) => {
+                    let result = connection.request(&request);
+                    let result = self.check(result);
+                    (result.and_then(|response| self.untrace(response)), false)
+                }
+                Err(err) => (Err(err), true),
+            };
+            let kind = match result {
+                Err(ref err) if self.options.reconnect => failure_kind(err, connecting),
+                _ => None,
+            };
+            match kind {
+                Some(kind) if self.options.retry.retries(&request, attempt, kind) => {
+                    thread::sleep(self.options.retry.wait(attempt));
+                    attempt += 1;
+                }
+                _ => return result,
+            }
+        }
+    }
+
+    // Take a response out of `Traced`, keeping its trace id.
+    fn untrace(&mut self, response: Response) -> Result<Response> {
+        match response {
+            Response::Traced { trace_id, response } => {
+                self.last_trace_id = Some(trace_id);
+                check_response(*response)
+            }
+            response => Ok(response),
+        }
+    }
+
+    fn next_request_id(&mut self) -> RequestId {
+        self.last_request += 1;
+        RequestId {
+            client: self.id.clone(),
+            request: self.last_request,
+        }
+    }
+
+    // Send requests without retrying them, since some may have been applied.
+    fn send_pipeline(&mut self, requests: &[Request]) -> Result<Vec<Result<Option<String>>>> {
+        let result = self.connection()?.pipeline(requests);
+        self.check(result)
+    }
+
+    fn connection(&mut self) -> Result<&mut Connection> {
+        match self.connection {
+            Some(ref mut connection) => Ok(connection),
+            None if self.options.reconnect => Ok(self
+                .connection
+                .get_or_insert(Connection::open(&self.addrs, &self.options)?)),
+            None => Err(io::Error::from(io::ErrorKind::NotConnected).into()),
+        }
+    }
+
+    // Drop the connection if `result` is an error that broke it.
+    fn check<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Err(IoError(_))
+        | Err(UnexpectedEOF)
+        | Err(KvsError::SerdeError(_))
+        | Err(KvsError::MessagePackError(_)) = result
+        {
+            // The connection may be in the middle of a message, so it can't be used again.
+            self.connection = None;
+        }
+        result
+    }
+}
+
+/// The messages of a replication stream, returned by `KvsClient::replicate`.
+pub(crate) struct ReplicationStream {
+    connection: Connection,
+}
+
+impl ReplicationStream {
+    /// Wait for the next message.
+    pub(crate) fn next(&mut self) -> Result<ReplicationMessage> {
+        match read_response(&mut self.connection.reader, self.connection.codec)? {
+            Response::Replication(message) => Ok(message),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Another handle to the socket, to shut the stream down from another thread.
+    pub(crate) fn socket(&self) -> io::Result<TcpStream> {
+        self.connection.reader.get_ref().socket().try_clone()
+    }
+}
+
+impl Connection {
+    // Connect to the first address that accepts the connection.
+    fn open(addrs: &[SocketAddr], options: &ClientOptions) -> Result<Connection> {
+        let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to");
+        for addr in addrs {
+            let stream = match options.connect_timeout {
+                Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+                None => TcpStream::connect(addr),
+            };
+            match stream {
+                Ok(stream) => {
+                    stream.set_read_timeout(options.read_timeout)?;
+                    stream.set_write_timeout(options.write_timeout)?;
+                    let server_name = match options.tls_server_name {
+                        Some(ref name) => ServerName::try_from(name.clone()).map_err(|err| {
+                            io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+                        })?,
+                        None => ServerName::IpAddress(addr.ip().into()),
+                    };
+                    let stream = Stream::connect(stream, options.tls.as_ref(), server_name)?;
+                    let mut connection = Connection {
+                        reader: BufReader::new(stream.try_clone()?),
+                        writer: BufWriter::new(stream),
+                        codec: Codec::Json,
+                        preferred: options.codec,
+                        server: None,
+                    };
+                    if let Some(ref credentials) = options.auth {
+                        into_value(connection.request(&Request::Auth(credentials.clone()))?)?;
+                    }
+                    return Ok(connection);
+                }
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err.into())
+    }
+
+    // Say hello to the server unless the connection already did, and return what the server
+    // said. It is done before the first request rather than when connecting, so that an error
+    // the server answers with right away, e.g. because it has too many connections, is returned
+    // by that request.
+    fn greet(&mut self) -> Result<&ServerInfo> {
+        if self.server.is_none() {
+            let codecs = if self.preferred == Codec::Json {
+                vec![Codec::Json]
+            } else {
+                vec![self.preferred, Codec::Json]
+            };
+            let hello = Request::Hello {
+                version: PROTOCOL_VERSION,
+                codecs,
+            };
+            write_message(&mut self.writer, &hello)?;
+            self.writer.flush()?;
+            match read_response(&mut self.reader, Codec::Json)? {
+                Response::Hello(server) if server.version < MIN_PROTOCOL_VERSION => {
+                    return Err(KvsError::UnsupportedProtocolVersion(server.version))
+                }
+                Response::Hello(server) => {
+                    self.codec = server.codec;
+                    self.server = Some(server);
+                }
+                response => return Err(unexpected(response)),
+            }
+        }
+        Ok(self.server.as_ref().expect("greeted the server"))
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Response> {
+        let version = self.greet()?.version;
+        let request = match request {
+            // servers from before the trace ids can't read them
+            Request::Traced { request, .. } if version < 4 => request,
+            request => request,
+        };
+        self.codec.write_message(&mut self.writer, request)?;
+        self.writer.flush()?;
+        read_response(&mut self.reader, self.codec)
+    }
+
+    fn set_reader(&mut self, key: String, reader: impl Read, len: u64) -> Result<Response> {
+        self.greet()?;
+        self.codec
+            .write_message(&mut self.writer, &Request::SetStream { key, len })?;
+        let mut chunks = BufWriter::with_capacity(CHUNK_SIZE, ChunkWriter(&mut self.writer));
+        io::copy(&mut reader.take(len), &mut chunks)?;
+        chunks
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .finish()?;
+        self.writer.flush()?;
+        read_response(&mut self.reader, self.codec)
+    }
+
+    fn get_writer(&mut self, key: String, mut writer: impl Write) -> Result<bool> {
+        match self.request(&Request::GetStream { key })? {
+            Response::Stream => {
+                io::copy(&mut ChunkReader::new(&mut self.reader), &mut writer)?;
+                writer.flush()?;
+                Ok(true)
+            }
+            Response::Ok(None) => Ok(false),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    // The requests are written on another thread while the responses are read, since the server
+    // stops reading requests while the responses it wrote aren't read.
+    fn pipeline(&mut self, requests: &[Request]) -> Result<Vec<Result<Option<String>>>> {
+        self.greet()?;
+        let writer = &mut self.writer;
+        let reader = &mut self.reader;
+        let codec = self.codec;
+        thread::scope(|scope| {
+            let sender = scope.spawn(move || -> Result<()> {
+                for request in requests {
+                    codec.write_message(writer, request)?;
+                }
+                writer.flush()?;
+                Ok(())
+            });
+            let mut responses = Vec::with_capacity(requests.len());
+            for _ in requests {
+                match read_response(reader, codec).and_then(into_value) {
+                    Err(err @ IoError(_))
+                    | Err(err @ UnexpectedEOF)
+                    | Err(err @ KvsError::SerdeError(_))
+                    | Err(err @ KvsError::MessagePackError(_)) => {
+                        // Unblock the sender if it is waiting for the server to read.
+                        let _ = reader.get_ref().socket().shutdown(Shutdown::Both);
+                        let _ = sender.join();
+                        return Err(err);
+                    }
+                    response => responses.push(response),
+                }
+            }
+            sender.join().expect("pipeline sender panicked")?;
+            Ok(responses)
+        })
+    }
+}
+
+// Read a response, turning the ones that report an error into that error.
+fn read_response(reader: &mut BufReader<Stream>, codec: Codec) -> Result<Response> {
+    match codec.read_message(reader)? {
+        Some(response) => check_response(response),
+        None => Err(UnexpectedEOF),
+    }
+}
+
+// Turn a response that reports an error into that error.
+fn check_response(response: Response) -> Result<Response> {
+    match response {
+        Response::KeyNotFound => Err(KeyNotFound),
+        Response::AuthRequired => Err(AuthRequired),
+        Response::AuthFailed => Err(AuthFailed),
+        Response::NotLeader(leader) => Err(KvsError::NotLeader(leader)),
+        Response::Err(err) => Err(err.into()),
+        response => Ok(response),
+    }
+}
+
+// The value of the response to a `get`, `set` or `remove`.
+fn into_value(response: Response) -> Result<Option<String>> {
+    match response {
+        Response::Ok(value) => Ok(value),
+        response => Err(unexpected(response)),
+    }
+}
+
+// The sequence number of the response to a `set` or `remove` with an id, which servers from
+// before the ids answer with `Ok`.
+fn into_seq(response: Response) -> Result<Option<u64>> {
+    match response {
+        Response::Written(seq) => Ok(seq),
+        Response::Ok(None) => Ok(None),
+        response => Err(unexpected(response)),
+    }
+}
+
+fn unexpected(response: Response) -> KvsError {
+    ServerError(format!("Unexpected response: {:?}", response))
+}
+
+// An id that tells a client apart from the others that a server sees.
+fn client_id() -> String {
+    static CLIENTS: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    let n = CLIENTS.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{:x}-{:x}", nanos, process::id(), n)
+}
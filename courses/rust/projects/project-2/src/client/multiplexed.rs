@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use super::retry::failure_kind;
+use super::{check_response, client_id, into_seq, into_value, unexpected};
+use super::{ClientOptions, Connection, KvsClient};
+use crate::common::{Codec, Request, RequestId, Response};
+use crate::error::{KvsError, Result};
+use crate::tls::Stream;
+use crate::Stats;
+
+// The first version of the protocol with tagged requests.
+const TAGGED_VERSION: u32 = 3;
+
+/// A client that sends the requests of many threads to a `KvsServer` over a single connection,
+/// each without waiting for the responses to the others.
+///
+/// Requests are tagged, which lets the server work on them at the same time and answer each as
+/// soon as it is done, and a thread of the client hands each response to the caller waiting for
+/// it. Unlike a `KvsClientPool`, a single connection and a single thread of the server serve any
+/// number of requests in flight, and a slow request doesn't hold up the others. Cloning the
+/// client is cheap and shares its connection.
+///
+/// The connection is opened again for the next request if it breaks, and requests are sent again
+/// as `ClientOptions::retry` says. Each caller waits for its response for as long as
+/// `ClientOptions::read_timeout` says. The server has to speak version 3 of the protocol, or
+/// connecting fails with `UnsupportedProtocolVersion`.
+///
+/// ```no_run
+/// # use std::thread;
+/// # use kvs::MultiplexedClient;
+/// let client = MultiplexedClient::connect("127.0.0.1:4000")?;
+/// let threads: Vec<_> = (0..8)
+///     .map(|i| {
+///         let client = client.clone();
+///         thread::spawn(move || client.set(format!("key{}", i), "value".to_owned()))
+///     })
+///     .collect();
+/// for thread in threads {
+///     thread.join().unwrap()?;
+/// }
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Clone)]
+pub struct MultiplexedClient {
+    inner: Arc<Shared>,
+}
+
+struct Shared {
+    addrs: Vec<SocketAddr>,
+    options: ClientOptions,
+    channel: Mutex<Option<Arc<Channel>>>,
+    next_tag: AtomicU64,
+    // The ids the writes are sent with, each with the number of its last write. The server
+    // expects a client to send its writes one at a time, so each write in flight has an id of
+    // its own.
+    write_ids: Mutex<Vec<RequestId>>,
+    // tells the write ids of the client apart from those of other clients
+    id: String,
+    write_id_count: AtomicU64,
+}
+
+// A connection, and the callers waiting for responses on it.
+struct Channel {
+    writer: Mutex<BufWriter<Stream>>,
+    codec: Codec,
+    // the callers waiting for a response, by the tag of their request, or `None` once the
+    // connection broke
+    waiting: Mutex<Option<HashMap<u64, Sender<Result<Response>>>>>,
+}
+
+impl MultiplexedClient {
+    /// Connect to the server listening on `addr`.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<MultiplexedClient> {
+        KvsClient::options().multiplexed(addr)
+    }
+
+    pub(super) fn connect_with(
+        addrs: Vec<SocketAddr>,
+        options: ClientOptions,
+    ) -> Result<MultiplexedClient> {
+        let channel = Channel::open(&addrs, &options)?;
+        Ok(MultiplexedClient {
+            inner: Arc::new(Shared {
+                addrs,
+                options,
+                channel: Mutex::new(Some(channel)),
+                next_tag: AtomicU64::new(0),
+                write_ids: Mutex::new(Vec::new()),
+                id: client_id(),
+                write_id_count: AtomicU64::new(0),
+            }),
+        })
+    }
+
+    /// Retrieve the value of a key from the server.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        into_value(self.request(Request::Get { key })?)
+    }
+
+    /// Retrieve the values of several keys from the server in a single request, in the same
+    /// order as `keys`.
+    pub fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self.request(Request::GetMany { keys })? {
+            Response::Values(values) => Ok(values),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Check whether a key exists on the server.
+    pub fn exists(&self, key: String) -> Result<bool> {
+        match self.request(Request::Exists { key })? {
+            Response::Exists(exists) => Ok(exists),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Set a key on the server.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_numbered(key, value).map(|_| ())
+    }
+
+    /// Set a key on the server, and return the sequence number its engine gave the write, if it
+    /// numbers its writes.
+    pub fn set_numbered(&self, key: String, value: String) -> Result<Option<u64>> {
+        into_seq(self.write(|id| Request::Set { key, value, id })?)
+    }
+
+    /// Remove a key on the server.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.remove_numbered(key).map(|_| ())
+    }
+
+    /// Remove a key on the server, and return the sequence number its engine gave the write, if
+    /// it numbers its writes.
+    pub fn remove_numbered(&self, key: String) -> Result<Option<u64>> {
+        into_seq(self.write(|id| Request::Remove { key, id })?)
+    }
+
+    /// Retrieve the statistics of the server's storage engine.
+    pub fn stats(&self) -> Result<Stats> {
+        match self.request(Request::Stats)? {
+            Response::Stats(stats) => Ok(stats),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Check that the server is up.
+    pub fn ping(&self) -> Result<()> {
+        into_value(self.request(Request::Ping)?).map(|_| ())
+    }
+
+    // Send a write with an id no other write in flight has.
+    fn write(&self, request: impl FnOnce(Option<RequestId>) -> Request) -> Result<Response> {
+        let free = self.inner.write_ids().pop();
+        let mut id = free.unwrap_or_else(|| {
+            let n = self.inner.write_id_count.fetch_add(1, Ordering::Relaxed);
+            RequestId {
+                client: format!("{}-{:x}", self.inner.id, n),
+                request: 0,
+            }
+        });
+        id.request += 1;
+        let result = self.request(request(Some(id.clone())));
+        self.inner.write_ids().push(id);
+        result
+    }
+
+    fn request(&self, request: Request) -> Result<Response> {
+        let options = &self.inner.options;
+        // the tag stays the same when the request is sent again, so that a late response to it
+        // is taken as well
+        let tag = self.inner.next_tag.fetch_add(1, Ordering::Relaxed);
+        let request = Request::Tagged {
+            tag,
+            request: Box::new(request),
+        };
+        let mut attempt = 1;
+        loop {
+            // whether the request failed before it was sent, for want of a connection
+            let (result, connecting) = match self.inner.channel() {
+                Ok(channel) => (channel.request(tag, &request, options.read_timeout), false),
+                Err(err) => (Err(err), true),
+            };
+            let kind = match result {
+                Err(ref err) if options.reconnect => failure_kind(err, connecting),
+                _ => None,
+            };
+            match kind {
+                Some(kind) if options.retry.retries(&request, attempt, kind) => {
+                    thread::sleep(options.retry.wait(attempt));
+                    attempt += 1;
+                }
+                _ => return result,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for MultiplexedClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MultiplexedClient")
+            .field("addrs", &self.inner.addrs)
+            .finish()
+    }
+}
+
+impl Shared {
+    // The connection, opened again if it broke.
+    fn channel(&self) -> Result<Arc<Channel>> {
+        let mut channel = self.channel.lock().expect("client channel lock poisoned");
+        match *channel {
+            Some(ref open) if open.is_open() => Ok(Arc::clone(open)),
+            _ if self.options.reconnect => {
+                let open = Channel::open(&self.addrs, &self.options)?;
+                *channel = Some(Arc::clone(&open));
+                Ok(open)
+            }
+            _ => Err(io::Error::from(io::ErrorKind::NotConnected).into()),
+        }
+    }
+
+    fn write_ids(&self) -> MutexGuard<'_, Vec<RequestId>> {
+        self.write_ids.lock().expect("write ids lock poisoned")
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if let Some(channel) = self.channel.get_mut().ok().and_then(Option::take) {
+            // which stops the thread reading the responses
+            channel.close();
+        }
+    }
+}
+
+impl Channel {
+    // Open a connection, and start the thread that hands its responses out.
+    fn open(addrs: &[SocketAddr], options: &ClientOptions) -> Result<Arc<Channel>> {
+        let mut connection = Connection::open(addrs, options)?;
+        let version = connection.greet()?.version;
+        if version < TAGGED_VERSION {
+            return Err(KvsError::UnsupportedProtocolVersion(version));
+        }
+        // each caller waits as long as the read timeout for its own response
+        connection
+            .reader
+            .get_ref()
+            .socket()
+            .set_read_timeout(None)?;
+        let Connection {
+            reader,
+            writer,
+            codec,
+            ..
+        } = connection;
+        let channel = Arc::new(Channel {
+            writer: Mutex::new(writer),
+            codec,
+            waiting: Mutex::new(Some(HashMap::new())),
+        });
+        let receiver = Arc::clone(&channel);
+        thread::Builder::new()
+            .name("kvs-multiplexed-client".to_owned())
+            .spawn(move || receiver.receive(reader))?;
+        Ok(channel)
+    }
+
+    fn is_open(&self) -> bool {
+        self.waiting().is_some()
+    }
+
+    // Send a request, and wait for the response with the same tag.
+    fn request(&self, tag: u64, request: &Request, timeout: Option<Duration>) -> Result<Response> {
+        let (sender, receiver) = mpsc::channel();
+        match *self.waiting() {
+            Some(ref mut waiting) => waiting.insert(tag, sender),
+            None => return Err(KvsError::UnexpectedEOF),
+        };
+        let sent = {
+            let mut writer = self.writer.lock().expect("client writer lock poisoned");
+            self.codec
+                .write_message(&mut *writer, request)
+                .and_then(|()| Ok(writer.flush()?))
+        };
+        if let Err(err) = sent {
+            self.stop_waiting(tag);
+            // the request may have been cut short, so nothing can be written after it
+            self.close();
+            return Err(err);
+        }
+        let response = match timeout {
+            Some(timeout) => receiver.recv_timeout(timeout),
+            None => receiver.recv().map_err(RecvTimeoutError::from),
+        };
+        match response {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                self.stop_waiting(tag);
+                Err(io::Error::from(io::ErrorKind::TimedOut).into())
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(KvsError::UnexpectedEOF),
+        }
+    }
+
+    // Hand the responses out to the callers waiting for them, until the connection breaks, and
+    // then fail the requests still waiting.
+    fn receive(&self, mut reader: BufReader<Stream>) {
+        let err = loop {
+            match self.codec.read_message(&mut reader) {
+                Ok(Some(Response::Tagged { tag, response })) => {
+                    let sender = self
+                        .waiting()
+                        .as_mut()
+                        .and_then(|waiting| waiting.remove(&tag));
+                    // unless the caller gave up waiting
+                    if let Some(sender) = sender {
+                        let _ = sender.send(check_response(*response));
+                    }
+                }
+                Ok(Some(response)) => break unexpected(response),
+                Ok(None) => break KvsError::UnexpectedEOF,
+                Err(err) => break err,
+            }
+        };
+        let waiting = self.waiting().take();
+        for (_, sender) in waiting.into_iter().flatten() {
+            let err = match err {
+                KvsError::IoError(ref err) => io::Error::new(err.kind(), err.to_string()).into(),
+                _ => KvsError::UnexpectedEOF,
+            };
+            let _ = sender.send(Err(err));
+        }
+        self.close();
+    }
+
+    fn stop_waiting(&self, tag: u64) {
+        if let Some(ref mut waiting) = *self.waiting() {
+            waiting.remove(&tag);
+        }
+    }
+
+    fn close(&self) {
+        let writer = self.writer.lock().expect("client writer lock poisoned");
+        let _ = writer.get_ref().socket().shutdown(Shutdown::Both);
+    }
+
+    fn waiting(&self) -> MutexGuard<'_, Option<HashMap<u64, Sender<Result<Response>>>>> {
+        self.waiting.lock().expect("client waiting lock poisoned")
+    }
+}
@@ -0,0 +1,159 @@
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::{ClientConfig, RootCertStore};
+
+use super::{KvsClient, KvsClientPool, MultiplexedClient, RetryPolicy};
+use crate::auth::Credentials;
+use crate::common::Codec;
+use crate::error::Result;
+use crate::tls::read_certs;
+
+/// Options for connecting to a `KvsServer`, created by `KvsClient::options`.
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use kvs::KvsClient;
+/// let mut client = KvsClient::options()
+///     .connect_timeout(Some(Duration::from_secs(1)))
+///     .read_timeout(Some(Duration::from_secs(5)))
+///     .connect("127.0.0.1:4000")?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    pub(super) connect_timeout: Option<Duration>,
+    pub(super) read_timeout: Option<Duration>,
+    pub(super) write_timeout: Option<Duration>,
+    pub(super) reconnect: bool,
+    pub(super) retry: RetryPolicy,
+    pub(super) max_idle: usize,
+    pub(super) tls: Option<Arc<ClientConfig>>,
+    pub(super) tls_server_name: Option<String>,
+    pub(super) auth: Option<Credentials>,
+    pub(super) codec: Codec,
+}
+
+impl Default for ClientOptions {
+    fn default() -> ClientOptions {
+        ClientOptions {
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            reconnect: true,
+            retry: RetryPolicy::default(),
+            max_idle: 16,
+            tls: None,
+            tls_server_name: None,
+            auth: None,
+            codec: Codec::Json,
+        }
+    }
+}
+
+impl ClientOptions {
+    /// Give up connecting after `timeout`, or wait as long as the operating system does if it is
+    /// `None`. Defaults to `None`.
+    pub fn connect_timeout(&mut self, timeout: Option<Duration>) -> &mut ClientOptions {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Fail a request if the server doesn't respond within `timeout`, or never if it is `None`.
+    /// It is sent once more on a new connection first, if the client connects again, or as
+    /// `retry` says. Defaults to `None`.
+    pub fn read_timeout(&mut self, timeout: Option<Duration>) -> &mut ClientOptions {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Fail a request if it can't be sent within `timeout`, or never if it is `None`. Defaults to
+    /// `None`.
+    pub fn write_timeout(&mut self, timeout: Option<Duration>) -> &mut ClientOptions {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// Connect again when the connection breaks. Defaults to true.
+    pub fn reconnect(&mut self, reconnect: bool) -> &mut ClientOptions {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// Send requests that failed in a way that may not last again as `policy` says, when the
+    /// client connects again. Defaults to `RetryPolicy::default()`, which sends them once more
+    /// right away after the connection was closed or timed out.
+    pub fn retry(&mut self, policy: &RetryPolicy) -> &mut ClientOptions {
+        self.retry = policy.clone();
+        self
+    }
+
+    /// Keep up to `count` unused connections open in a `KvsClientPool`. Defaults to 16.
+    pub fn max_idle(&mut self, count: usize) -> &mut ClientOptions {
+        self.max_idle = count;
+        self
+    }
+
+    /// Talk to the server over TLS, as configured by `config`.
+    pub fn tls(&mut self, config: Arc<ClientConfig>) -> &mut ClientOptions {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Talk to the server over TLS, trusting the certificates of the certificate authorities in
+    /// the PEM file at `path`, and those only.
+    pub fn tls_ca_file(&mut self, path: impl AsRef<Path>) -> Result<&mut ClientOptions> {
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(path.as_ref())? {
+            roots.add(cert)?;
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(self.tls(Arc::new(config)))
+    }
+
+    /// The name the certificate of the server must be issued to. Defaults to the IP address
+    /// connected to.
+    pub fn tls_server_name(&mut self, name: impl Into<String>) -> &mut ClientOptions {
+        self.tls_server_name = Some(name.into());
+        self
+    }
+
+    /// Authenticate every connection with `credentials` as soon as it is open. Defaults to not
+    /// authenticating.
+    pub fn auth(&mut self, credentials: Credentials) -> &mut ClientOptions {
+        self.auth = Some(credentials);
+        self
+    }
+
+    /// Encode the messages with `codec` if the server accepts it, and with JSON otherwise.
+    /// Defaults to JSON, which is easier to read when debugging.
+    pub fn codec(&mut self, codec: Codec) -> &mut ClientOptions {
+        self.codec = codec;
+        self
+    }
+
+    /// Connect to the server listening on `addr` with these options.
+    pub fn connect(&self, addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        KvsClient::connect_with(addr.to_socket_addrs()?.collect(), self.clone())
+    }
+
+    /// Create a pool of connections to the server listening on `addr` with these options.
+    ///
+    /// Connections are only opened when the pool has no idle one to hand out.
+    pub fn pool(&self, addr: impl ToSocketAddrs) -> Result<KvsClientPool> {
+        Ok(KvsClientPool::new_with(
+            addr.to_socket_addrs()?.collect(),
+            self.clone(),
+        ))
+    }
+
+    /// Connect to the server listening on `addr` with these options, with a client that many
+    /// threads can send requests over at the same time.
+    pub fn multiplexed(&self, addr: impl ToSocketAddrs) -> Result<MultiplexedClient> {
+        MultiplexedClient::connect_with(addr.to_socket_addrs()?.collect(), self.clone())
+    }
+}
@@ -0,0 +1,68 @@
+use super::KvsClient;
+use crate::common::Request;
+use crate::error::Result;
+
+/// Requests to send to the server at once, created by `KvsClient::pipeline`.
+///
+/// The requests are sent without waiting for each response, which saves a round trip per request.
+/// The server still handles them one after another, in order.
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<Request>,
+}
+
+impl<'a> Pipeline<'a> {
+    pub(super) fn new(client: &'a mut KvsClient) -> Pipeline<'a> {
+        Pipeline {
+            client,
+            requests: Vec::new(),
+        }
+    }
+
+    /// Retrieve the value of a key.
+    pub fn get(&mut self, key: String) -> &mut Pipeline<'a> {
+        self.requests.push(Request::Get { key });
+        self
+    }
+
+    /// Set a key.
+    pub fn set(&mut self, key: String, value: String) -> &mut Pipeline<'a> {
+        self.requests.push(Request::Set {
+            key,
+            value,
+            id: None,
+        });
+        self
+    }
+
+    /// Remove a key.
+    pub fn remove(&mut self, key: String) -> &mut Pipeline<'a> {
+        self.requests.push(Request::Remove { key, id: None });
+        self
+    }
+
+    /// The number of requests in the pipeline.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Whether the pipeline has no requests.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Send the requests and return their responses, in the same order. A `get` responds with the
+    /// value, and `set` and `remove` with `None`. A request that failed on the server, like a
+    /// `remove` of a missing key, doesn't stop the ones after it.
+    ///
+    /// An error is returned instead if the connection broke. Unlike a single request, the
+    /// pipeline isn't sent again on a new connection, since some of its requests may have been
+    /// applied. The pipeline is empty afterwards either way.
+    pub fn send(&mut self) -> Result<Vec<Result<Option<String>>>> {
+        let requests = std::mem::take(&mut self.requests);
+        if requests.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.client.send_pipeline(&requests)
+    }
+}
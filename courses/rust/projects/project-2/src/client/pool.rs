@@ -0,0 +1,115 @@
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+use super::{ClientOptions, KvsClient};
+use crate::error::Result;
+
+/// A pool of connections to a `KvsServer`, for applications that make requests from many threads.
+///
+/// Cloning the pool is cheap and shares its connections. `KvsServer` serves each connection on
+/// one of its threads for as long as it is open, idle or not, so the pool shouldn't hold more
+/// connections than the server has threads.
+///
+/// ```no_run
+/// # use kvs::KvsClientPool;
+/// let pool = KvsClientPool::new("127.0.0.1:4000")?;
+/// pool.get()?.set("key".to_owned(), "value".to_owned())?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Clone)]
+pub struct KvsClientPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    addrs: Vec<SocketAddr>,
+    options: ClientOptions,
+    idle: Mutex<Vec<KvsClient>>,
+}
+
+/// A client borrowed from a `KvsClientPool`, which goes back to the pool when dropped.
+pub struct PooledClient {
+    client: Option<KvsClient>,
+    pool: Arc<PoolInner>,
+}
+
+impl KvsClientPool {
+    /// Create a pool of connections to the server listening on `addr`, with the default options.
+    pub fn new(addr: impl ToSocketAddrs) -> Result<KvsClientPool> {
+        KvsClient::options().pool(addr)
+    }
+
+    pub(super) fn new_with(addrs: Vec<SocketAddr>, options: ClientOptions) -> KvsClientPool {
+        KvsClientPool {
+            inner: Arc::new(PoolInner {
+                addrs,
+                options,
+                idle: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Borrow an idle client, or connect a new one if there is none.
+    pub fn get(&self) -> Result<PooledClient> {
+        let idle = self.inner.idle().pop();
+        let client = match idle {
+            Some(client) => client,
+            None => KvsClient::connect_with(self.inner.addrs.clone(), self.inner.options.clone())?,
+        };
+        Ok(PooledClient {
+            client: Some(client),
+            pool: Arc::clone(&self.inner),
+        })
+    }
+
+    /// The number of idle connections in the pool.
+    pub fn idle_count(&self) -> usize {
+        self.inner.idle().len()
+    }
+}
+
+impl PoolInner {
+    fn idle(&self) -> std::sync::MutexGuard<'_, Vec<KvsClient>> {
+        self.idle.lock().expect("pool lock poisoned")
+    }
+}
+
+impl fmt::Debug for KvsClientPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KvsClientPool")
+            .field("addrs", &self.inner.addrs)
+            .field("idle", &self.idle_count())
+            .finish()
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().expect("client already returned")
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().expect("client already returned")
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            // A client whose connection broke would only have to connect again.
+            if !client.is_connected() {
+                return;
+            }
+            let mut idle = self.pool.idle();
+            if idle.len() < self.pool.options.max_idle {
+                idle.push(client);
+            }
+        }
+    }
+}
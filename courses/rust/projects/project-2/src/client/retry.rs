@@ -0,0 +1,170 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::time::Duration;
+
+use crate::common::Request;
+use crate::error::KvsError;
+
+/// The kinds of failures a client may send a request again after.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RetryOn {
+    /// The client couldn't connect to the server, so the request wasn't sent.
+    Connect,
+    /// The server closed the connection, e.g. because it restarted, before it answered.
+    Closed,
+    /// The server didn't answer within `ClientOptions::read_timeout`, and may still be working
+    /// on the request.
+    Timeout,
+    /// The server turned a write down with `KvsError::Backpressure`, so it wasn't applied.
+    Backpressure,
+}
+
+/// How a `KvsClient` or `MultiplexedClient` sends a request again when it fails in a way that may
+/// not last, set with `ClientOptions::retry`.
+///
+/// Only requests that are safe to send twice are sent again: reads, and the sets and removes that
+/// carry an id with which the server applies them only once. Requests sent with a `Pipeline` or
+/// `KvsClient::set_reader` never are. Nothing is sent again if the client doesn't reconnect.
+///
+/// The client waits between attempts, twice as long each time from the initial backoff up to
+/// the largest one, less a random part of it to keep clients that failed at the same time apart.
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use kvs::{KvsClient, RetryOn, RetryPolicy};
+/// let mut client = KvsClient::options()
+///     .retry(
+///         RetryPolicy::default()
+///             .max_attempts(5)
+///             .backoff(Duration::from_millis(50), Duration::from_secs(2))
+///             .retry_on(&[RetryOn::Connect, RetryOn::Closed, RetryOn::Timeout]),
+///     )
+///     .connect("127.0.0.1:4000")?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: f64,
+    retry_on: Vec<RetryOn>,
+}
+
+impl Default for RetryPolicy {
+    /// Send a request once more right away after the connection was closed or timed out.
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            jitter: 0.5,
+            retry_on: vec![RetryOn::Closed, RetryOn::Timeout],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that sends every request once only.
+    pub fn never() -> RetryPolicy {
+        let mut policy = RetryPolicy::default();
+        policy.max_attempts(1);
+        policy
+    }
+
+    /// Send a request up to `attempts` times in all, the first one included. Defaults to 2.
+    pub fn max_attempts(&mut self, attempts: u32) -> &mut RetryPolicy {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Wait `initial` before the second attempt, and twice as long before each one after it, but
+    /// never longer than `max`. Defaults to not waiting.
+    pub fn backoff(&mut self, initial: Duration, max: Duration) -> &mut RetryPolicy {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Take up to this fraction, between 0 and 1, off each wait at random. Defaults to 0.5.
+    pub fn jitter(&mut self, fraction: f64) -> &mut RetryPolicy {
+        self.jitter = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Send a request again after the failures of these kinds only. Defaults to `Closed` and
+    /// `Timeout`.
+    pub fn retry_on(&mut self, kinds: &[RetryOn]) -> &mut RetryPolicy {
+        self.retry_on = kinds.to_vec();
+        self
+    }
+
+    /// Whether to send `request` again after its attempt number `attempt`, counting from 1,
+    /// failed with a failure of kind `kind`.
+    pub(super) fn retries(&self, request: &Request, attempt: u32, kind: RetryOn) -> bool {
+        attempt < self.max_attempts && self.retry_on.contains(&kind) && is_safe_to_retry(request)
+    }
+
+    /// How long to wait after the attempt number `attempt`, counting from 1, failed.
+    pub(super) fn wait(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31);
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff);
+        let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        backoff.mul_f64(1.0 - self.jitter * random)
+    }
+}
+
+/// The kind of failure `err` is, if sending the request again might get past it. `connecting`
+/// says whether it happened while connecting, before the request was sent.
+pub(super) fn failure_kind(err: &KvsError, connecting: bool) -> Option<RetryOn> {
+    match err {
+        KvsError::IoError(_) if connecting => Some(RetryOn::Connect),
+        KvsError::Backpressure => Some(RetryOn::Backpressure),
+        err if is_closed(err) => Some(RetryOn::Closed),
+        err if is_timeout(err) => Some(RetryOn::Timeout),
+        _ => None,
+    }
+}
+
+// Whether a request failed because the server had closed the connection. A request that timed out
+// isn't one of them: the server may still be working on it.
+fn is_closed(err: &KvsError) -> bool {
+    match err {
+        KvsError::UnexpectedEOF => true,
+        KvsError::IoError(err) => matches!(
+            err.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::UnexpectedEof
+        ),
+        _ => false,
+    }
+}
+
+// Whether a request failed because the server didn't respond in time.
+fn is_timeout(err: &KvsError) -> bool {
+    match err {
+        KvsError::IoError(err) => matches!(
+            err.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ),
+        _ => false,
+    }
+}
+
+// Whether a request may be applied twice without harm: anything but a write without an id,
+// which the server would apply again.
+fn is_safe_to_retry(request: &Request) -> bool {
+    match request {
+        Request::Set { id: None, .. } | Request::Remove { id: None, .. } => false,
+        Request::Tagged { request, .. } | Request::Traced { request, .. } => {
+            is_safe_to_retry(request)
+        }
+        _ => true,
+    }
+}
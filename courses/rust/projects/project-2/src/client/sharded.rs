@@ -0,0 +1,78 @@
+use std::net::SocketAddr;
+
+use super::{ClientOptions, KvsClient};
+use crate::error::Result;
+use crate::ring::HashRing;
+
+/// A client of several `KvsServer`s that each hold a share of the keys, picked with consistent
+/// hashing as `ShardedKvStore` picks the shard of a key.
+///
+/// Every client must be given the same addresses, in the same order. Changing them moves the
+/// share of some keys to other servers, without moving the keys themselves.
+pub struct ShardedClient {
+    clients: Vec<KvsClient>,
+    ring: HashRing,
+}
+
+impl ShardedClient {
+    /// Connect to each of the servers listening on `addrs`, with `options`.
+    ///
+    /// Panics if `addrs` is empty.
+    pub fn connect(addrs: &[SocketAddr], options: &ClientOptions) -> Result<ShardedClient> {
+        assert!(
+            !addrs.is_empty(),
+            "a sharded client needs at least one server"
+        );
+        let names: Vec<String> = addrs.iter().map(SocketAddr::to_string).collect();
+        Ok(ShardedClient {
+            clients: addrs
+                .iter()
+                .map(|&addr| options.connect(addr))
+                .collect::<Result<_>>()?,
+            ring: HashRing::new(&names),
+        })
+    }
+
+    /// The index in the addresses of the server that holds `key`.
+    pub fn server(&self, key: &str) -> usize {
+        self.ring.owner(key.as_bytes())
+    }
+
+    /// Retrieve the value of a key from the server that holds it.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let server = self.server(&key);
+        self.clients[server].get(key)
+    }
+
+    /// Retrieve the values of several keys, in the same order as `keys`, with a request to each
+    /// server that holds some of them.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut groups = vec![(Vec::new(), Vec::new()); self.clients.len()];
+        for (index, key) in keys.into_iter().enumerate() {
+            let (indices, keys) = &mut groups[self.server(&key)];
+            indices.push(index);
+            keys.push(key);
+        }
+        let mut values = Vec::new();
+        for (client, (indices, keys)) in self.clients.iter_mut().zip(groups) {
+            if keys.is_empty() {
+                continue;
+            }
+            values.extend(indices.into_iter().zip(client.get_many(keys)?));
+        }
+        values.sort_unstable_by_key(|&(index, _)| index);
+        Ok(values.into_iter().map(|(_, value)| value).collect())
+    }
+
+    /// Set a key on the server that holds it.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let server = self.server(&key);
+        self.clients[server].set(key, value)
+    }
+
+    /// Remove a key from the server that holds it.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let server = self.server(&key);
+        self.clients[server].remove(key)
+    }
+}
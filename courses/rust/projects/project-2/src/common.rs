@@ -0,0 +1,358 @@
+//! The protocol spoken between `KvsClient` and `KvsServer`.
+//!
+//! Every message is a little-endian `u32` length followed by that many bytes, encoded with the
+//! `Codec` of the connection. The value of a `SetStream` request, and of a `Stream` response,
+//! follows the message in chunks, each a little-endian `u32` length followed by that many bytes,
+//! up to an empty chunk.
+//!
+//! Connections start out with JSON. A client sends `Hello` first, with the version of the
+//! protocol it speaks and the codecs it can use, in the order it prefers them. The server answers
+//! with `Hello`, its own version, what it can do, and the first of the codecs it accepts, which
+//! both use from the next message on. A server that doesn't speak the version of the client
+//! answers with `UnsupportedProtocolVersion` instead, and closes the connection. The hellos are
+//! always JSON, and only ever gain fields, so that any version can read them. Servers from before
+//! the hellos close the connection as they can't read the request, and serve clients from before
+//! them as if they had asked for JSON.
+//!
+//! A `Set` or `Remove` may carry a `RequestId`, with which the server applies it once however
+//! many times it is sent, and answers it with `Written`. Writes without one, as older clients
+//! send them, are answered with `Ok`.
+//!
+//! Requests are answered in the order they are sent, but for those sent in `Tagged`, which the
+//! server may work on at the same time as the requests after them, and answers with `Tagged` and
+//! the same tag as soon as it is done with them. A client can then have many requests in flight
+//! on a single connection, and tell which request each response is for by its tag.
+//!
+//! A request sent in `Traced` carries a trace id, which the server logs it with in its access log
+//! and answers with in `Traced`, for clients to tie the logs of the server to their own traces.
+//! A traced request can be tagged, and is then answered with `Tagged` holding `Traced`.
+
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Credentials;
+use crate::engines::{ReplicatedPair, ReplicationEntry};
+use crate::error::{KvsError, RemoteError, Result};
+use crate::raft::Message as RaftMessage;
+use crate::Stats;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    Get {
+        key: String,
+    },
+    GetMany {
+        keys: Vec<String>,
+    },
+    /// Whether a key exists, answered with `Exists`. Since version 2 of the protocol.
+    Exists {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<RequestId>,
+    },
+    Remove {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<RequestId>,
+    },
+    /// Set a key to the `len` bytes that follow in chunks
+    SetStream {
+        key: String,
+        len: u64,
+    },
+    /// Retrieve the value of a key in chunks, after a `Stream` response
+    GetStream {
+        key: String,
+    },
+    Stats,
+    Ping,
+    Auth(Credentials),
+    /// Turn the connection into a stream of the replication log, starting after the entries of
+    /// the log `id` before `offset`, or from scratch if `id` is `None`.
+    Replicate {
+        id: Option<String>,
+        offset: u64,
+    },
+    /// A message from another node of a Raft cluster
+    Raft(RaftMessage),
+    /// Tell the server which version of the protocol the client speaks, and switch to the first
+    /// of `codecs` the server accepts, which it answers with `Hello`. Only sent as the first
+    /// request of a connection.
+    Hello {
+        version: u32,
+        codecs: Vec<Codec>,
+    },
+    /// A request that may be answered out of order, with a `Tagged` response with the same tag.
+    /// Only `Get`, `GetMany`, `Exists`, `Set`, `Remove`, `Stats` and `Ping` can be tagged, traced
+    /// or not. Since version 3 of the protocol.
+    Tagged {
+        tag: u64,
+        request: Box<Request>,
+    },
+    /// A request to log with a trace id, answered with a `Traced` response with the same trace
+    /// id. Since version 4 of the protocol.
+    Traced {
+        trace_id: String,
+        request: Box<Request>,
+    },
+}
+
+/// Identifies a write, which the server applies once however many times it is sent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestId {
+    /// Tells the client apart from the others, for as long as it lives
+    pub client: String,
+    /// The number of the write among those of the client, in the order they are sent
+    pub request: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Ok(Option<String>),
+    /// A write with a `RequestId` was applied, with the sequence number the engine gave it if it
+    /// numbers its writes
+    Written(Option<u64>),
+    Values(Vec<Option<String>>),
+    Exists(bool),
+    /// The value follows in chunks
+    Stream,
+    Stats(Stats),
+    KeyNotFound,
+    AuthRequired,
+    AuthFailed,
+    /// Any other error, with its code and context
+    Err(RemoteError),
+    Replication(ReplicationMessage),
+    Raft(RaftMessage),
+    /// The server isn't the leader of its Raft cluster, which is at the address given, if known
+    NotLeader(Option<String>),
+    /// The answer to `Hello`
+    Hello(ServerInfo),
+    /// The answer to the `Tagged` request with the same tag
+    Tagged {
+        tag: u64,
+        response: Box<Response>,
+    },
+    /// The answer to the `Traced` request with the same trace id
+    Traced {
+        trace_id: String,
+        response: Box<Response>,
+    },
+}
+
+/// The version of the protocol this build speaks, which goes up whenever the messages change in
+/// a way that older versions can't read.
+///
+/// Version 2 added `Exists`, version 3 `Tagged` and version 4 `Traced`.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// The oldest version of the protocol this build still speaks.
+pub(crate) const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// What a server told a client about itself when it connected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// The version of the protocol the server speaks
+    pub version: u32,
+    /// The codec of the connection from then on
+    pub codec: Codec,
+    /// What the server can do
+    pub capabilities: Capabilities,
+}
+
+/// What a server can do, or requires, besides what every server does. What servers from before
+/// a capability was introduced don't say is false.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Capabilities {
+    /// Requests other than `ping` are only served once the connection authenticated
+    pub auth: bool,
+    /// Writes are turned down
+    pub read_only: bool,
+    /// The engine compresses the values it stores
+    pub compression: bool,
+    /// The store can be replicated to a `Replica`
+    pub replication: bool,
+    /// The server is a node of a Raft cluster
+    pub raft: bool,
+}
+
+/// The formats the messages between `KvsClient` and `KvsServer` can be encoded in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    /// JSON, which is easy to read when debugging, and which every connection starts with
+    Json,
+    /// MessagePack, which makes smaller messages that are faster to encode and decode
+    MessagePack,
+}
+
+impl FromStr for Codec {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Codec> {
+        match s {
+            "json" => Ok(Codec::Json),
+            "msgpack" => Ok(Codec::MessagePack),
+            _ => Err(KvsError::UnknownFormat(s.to_owned())),
+        }
+    }
+}
+
+impl Codec {
+    /// The codecs a server accepts by default, all of them.
+    pub(crate) const ALL: [Codec; 2] = [Codec::MessagePack, Codec::Json];
+
+    /// Write a length-prefixed message. It isn't flushed, so that several messages can be sent
+    /// at once.
+    pub(crate) fn write_message<T: Serialize>(
+        self,
+        writer: &mut impl Write,
+        message: &T,
+    ) -> Result<()> {
+        let bytes = match self {
+            Codec::Json => serde_json::to_vec(message)?,
+            // with the names of the fields, so that optional ones can be left out
+            Codec::MessagePack => rmp_serde::to_vec_named(message)?,
+        };
+        writer.write_all(&u32::to_le_bytes(bytes.len() as u32))?;
+        writer.write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// Read a length-prefixed message, or `None` if the peer closed the connection.
+    pub(crate) fn read_message<T: DeserializeOwned>(
+        self,
+        reader: &mut impl Read,
+    ) -> Result<Option<T>> {
+        let mut size_buffer: [u8; 4] = [0; 4];
+        if let Err(err) = reader.read_exact(&mut size_buffer) {
+            if err.kind() == std::io::ErrorKind::UnexpectedEof {
+                return Ok(None);
+            }
+            return Err(err.into());
+        }
+        // The size comes from the peer, so the buffer only grows as the bytes arrive.
+        let size = u32::from_le_bytes(size_buffer) as u64;
+        let mut data_buffer = Vec::new();
+        reader.take(size).read_to_end(&mut data_buffer)?;
+        if (data_buffer.len() as u64) < size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(Some(match self {
+            Codec::Json => serde_json::from_slice(&data_buffer)?,
+            Codec::MessagePack => rmp_serde::from_slice(&data_buffer)?,
+        }))
+    }
+}
+
+/// The messages of a replication stream.
+///
+/// It starts with either `Continue` followed by the entries the follower missed, or with
+/// `FullSync` followed by all the keys in `Pairs` and then `SyncEnd`. Every entry written from
+/// then on follows, with a `Heartbeat` whenever nothing has been written for a while.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ReplicationMessage {
+    Continue,
+    FullSync { id: String, offset: u64 },
+    Pairs(Vec<ReplicatedPair>),
+    SyncEnd,
+    Entry(ReplicationEntry),
+    Heartbeat,
+}
+
+/// Write a length-prefixed JSON message. It isn't flushed, so that several messages can be sent
+/// at once.
+pub fn write_message<T: Serialize>(writer: &mut impl Write, message: &T) -> Result<()> {
+    Codec::Json.write_message(writer, message)
+}
+
+/// Read a length-prefixed JSON message, or `None` if the peer closed the connection.
+pub fn read_message<T: DeserializeOwned>(reader: &mut impl Read) -> Result<Option<T>> {
+    Codec::Json.read_message(reader)
+}
+
+/// The size of the chunks streamed values are sent in.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Write `value` as a chunk of a streamed value, or the end of it if `value` is empty.
+fn write_chunk(writer: &mut impl Write, value: &[u8]) -> io::Result<()> {
+    writer.write_all(&u32::to_le_bytes(value.len() as u32))?;
+    writer.write_all(value)
+}
+
+/// Writes a streamed value as chunks, one per call to `write`. `finish` writes the end of it.
+pub struct ChunkWriter<W: Write>(pub W);
+
+impl<W: Write> ChunkWriter<W> {
+    pub fn finish(mut self) -> io::Result<W> {
+        write_chunk(&mut self.0, &[])?;
+        Ok(self.0)
+    }
+}
+
+impl<W: Write> Write for ChunkWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(u32::MAX as usize);
+        write_chunk(&mut self.0, &buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Reads a streamed value from its chunks, up to the end of it.
+pub struct ChunkReader<R: Read> {
+    reader: R,
+    // what is left of the current chunk
+    remaining: u32,
+    done: bool,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(reader: R) -> ChunkReader<R> {
+        ChunkReader {
+            reader,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    /// Skip the rest of the value, so that the next message can be read.
+    pub fn drain(&mut self) -> io::Result<()> {
+        io::copy(self, &mut io::sink())?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ChunkReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            if self.done {
+                return Ok(0);
+            }
+            let mut len = [0; 4];
+            self.reader.read_exact(&mut len)?;
+            self.remaining = u32::from_le_bytes(len);
+            self.done = self.remaining == 0;
+        }
+        let len = buf.len().min(self.remaining as usize);
+        let n = self.reader.read(&mut buf[..len])?;
+        if n == 0 && len > 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n as u32;
+        Ok(n)
+    }
+}
@@ -0,0 +1,88 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use log::LevelFilter;
+use serde::Deserialize;
+
+use crate::engines::{Engine, KvStore, KvStoreOptions};
+use crate::error::{KvsError, Result};
+
+/// The settings of `kvs-server` read from a TOML file, e.g.
+///
+/// ```toml
+/// addr = "127.0.0.1:4000"
+/// engine = "kvs"
+/// compaction-threshold = 4194304
+/// cache-capacity = 8388608
+/// log-level = "info"
+/// ```
+///
+/// Every setting is optional, and command-line arguments take precedence over them. The
+/// compaction threshold, cache capacity and log level are tunable: reloading the file applies
+/// them to the running server with `tune` and `log_level`. The others only take effect when the
+/// server starts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    /// The address to listen on
+    pub addr: Option<String>,
+    /// The storage engine, `kvs` or `sled`
+    pub engine: Option<Engine>,
+    /// See `KvStoreOptions::compaction_threshold`
+    pub compaction_threshold: Option<u64>,
+    /// See `KvStoreOptions::cache_capacity`
+    pub cache_capacity: Option<usize>,
+    /// The most verbose level logged, one of `off`, `error`, `warn`, `info`, `debug` and `trace`
+    pub log_level: Option<LevelFilter>,
+}
+
+impl ConfigFile {
+    /// Read the settings from a TOML file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<ConfigFile> {
+        let path = path.as_ref();
+        fs::read_to_string(path)?.parse().map_err(|err| match err {
+            KvsError::IoError(err) => io::Error::new(
+                err.kind(),
+                format!("invalid config file {}: {}", path.display(), err),
+            )
+            .into(),
+            err => err,
+        })
+    }
+
+    /// Set the options a `KvStore` is opened with to the settings of the file.
+    pub fn configure(&self, options: &mut KvStoreOptions) {
+        if let Some(bytes) = self.compaction_threshold {
+            options.compaction_threshold(bytes);
+        }
+        if let Some(bytes) = self.cache_capacity {
+            options.cache_capacity(bytes);
+        }
+    }
+
+    /// Apply the tunable settings of the file to an open `KvStore`. Those that aren't in the file
+    /// are left as they are.
+    pub fn tune(&self, store: &KvStore) {
+        if let Some(bytes) = self.compaction_threshold {
+            store.set_compaction_threshold(bytes);
+        }
+        if let Some(bytes) = self.cache_capacity {
+            store.set_cache_capacity(bytes);
+        }
+    }
+
+    /// The log level of the file, `info` if it doesn't set one.
+    pub fn log_level(&self) -> LevelFilter {
+        self.log_level.unwrap_or(LevelFilter::Info)
+    }
+}
+
+impl FromStr for ConfigFile {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<ConfigFile> {
+        toml::from_str(s).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err).into())
+    }
+}
@@ -0,0 +1,69 @@
+//! Deduplication of the writes clients send again.
+//!
+//! A client that doesn't know whether a write was applied, e.g. because the response didn't come
+//! in time, sends it again with the same `RequestId`. The server remembers the last write each
+//! client had applied, and answers it again with the sequence number it got rather than apply
+//! it twice. A write sent again while the first one is still being applied waits for it. Writes
+//! that failed aren't remembered, since they weren't applied and can be tried again.
+//!
+//! A client is expected to number its writes in the order it sends them, one at a time: a write
+//! numbered lower than the last one applied is refused, as its outcome is forgotten. So are the
+//! writes of the clients seen least recently, once there are too many clients.
+
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+
+use lru::LruCache;
+
+use crate::common::RequestId;
+use crate::error::{KvsError, Result};
+
+// The number of clients whose last write is remembered.
+const MAX_CLIENTS: usize = 10_000;
+
+// The number and sequence number of the last write of a client, locked while one is applied.
+type LastWrite = Arc<Mutex<Option<(u64, Option<u64>)>>>;
+
+/// The last write of each client, shared by the connections of a server.
+pub(crate) struct Deduplicator {
+    // the last write of each client, by id
+    clients: Mutex<LruCache<String, LastWrite>>,
+}
+
+impl Default for Deduplicator {
+    fn default() -> Self {
+        let capacity = NonZeroUsize::new(MAX_CLIENTS).expect("capacity is not zero");
+        Deduplicator {
+            clients: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+}
+
+impl Deduplicator {
+    /// Apply the write `id` with `write`, unless it already was, and return the sequence number
+    /// it got.
+    pub(crate) fn apply(
+        &self,
+        id: &RequestId,
+        write: impl FnOnce() -> Result<Option<u64>>,
+    ) -> Result<Option<u64>> {
+        let client = {
+            let mut clients = self.clients.lock().expect("clients lock poisoned");
+            Arc::clone(clients.get_or_insert(id.client.clone(), Default::default))
+        };
+        let mut last = client.lock().expect("client lock poisoned");
+        match *last {
+            Some((request, seq)) if request == id.request => return Ok(seq),
+            Some((request, _)) if request > id.request => {
+                return Err(KvsError::ServerError(format!(
+                    "request {} of client {} is older than its last write",
+                    id.request, id.client
+                )))
+            }
+            _ => {}
+        }
+        let seq = write()?;
+        *last = Some((id.request, seq));
+        Ok(seq)
+    }
+}
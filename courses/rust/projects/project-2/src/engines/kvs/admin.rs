@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::SystemTime;
+
+use log::{error, warn};
+
+use super::crypto::EncryptionKey;
+use super::hint::remove_hint;
+use super::manifest::live_segments;
+use super::segment::{Format, SegmentFile, TornTail, HEADER_SIZE};
+use super::{
+    current, from_millis, live_offset, lock_dir, CompactorMessage, KvStore, KvStoreOptions, Offset,
+};
+use crate::error::KvsError::{
+    self, ChecksumMismatch, InvalidRecord, ReadOnly, SerdeError, UnsupportedFormat,
+};
+use crate::error::Result;
+
+/// A record of a segment, passed to the callback of `KvStore::inspect`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordInfo {
+    /// The segment that holds the record
+    pub segment: u64,
+    /// Where the record starts in the segment
+    pub offset: u64,
+    /// Bytes the record takes up, including its header
+    pub len: u64,
+    /// The key of the record
+    pub key: Vec<u8>,
+    /// The value of the record, or `None` if it removes the key. A value streamed in with
+    /// `set_reader` is left empty, since it is in a blob file.
+    pub value: Option<Vec<u8>>,
+    /// When the key expires, in milliseconds since the Unix epoch
+    pub expires_at: Option<u64>,
+    /// Whether the record holds the current value of its key, or one of the records a merge
+    /// operand holding it is folded onto
+    pub live: bool,
+    /// Whether the record is a range tombstone, which removes every key that starts with `key`
+    pub prefix: bool,
+    /// Whether the value is a merge operand, folded into the value `key` had rather than
+    /// replacing it
+    pub merge: bool,
+}
+
+/// A summary of a segment, returned by `KvStore::inspect`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// The id of the segment
+    pub segment: u64,
+    /// The version of the binary format of the segment, or `None` for the old JSON format
+    pub version: Option<u8>,
+    /// When the segment was created, if it was written in a format that records it
+    pub created: Option<SystemTime>,
+    /// Size of the segment in bytes
+    pub size: u64,
+    /// Number of records, counting each record of a batch
+    pub records: u64,
+    /// Number of records that hold the current value of their key
+    pub live_records: u64,
+    /// Bytes taken up by the live records
+    pub live_bytes: u64,
+    /// Bytes taken up by everything else: overwritten values, tombstones, batch headers and any
+    /// torn record at the end
+    pub dead_bytes: u64,
+}
+
+/// The result of checking a segment, returned by `KvStore::verify`.
+#[derive(Debug)]
+pub struct SegmentCheck {
+    /// The id of the segment
+    pub segment: u64,
+    /// Size of the segment in bytes
+    pub size: u64,
+    /// Where the last intact record ends. Anything after it is damaged.
+    pub valid_len: u64,
+    /// Number of intact records
+    pub records: u64,
+    /// What is wrong with the segment, or `None` if it is intact. A torn record at the end is
+    /// reported as `ChecksumMismatch`.
+    pub error: Option<KvsError>,
+}
+
+impl SegmentCheck {
+    /// Whether the segment is intact.
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// A record that failed its checksum, found by `KvStore::scrub`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptRecord {
+    /// The segment that holds the record
+    pub segment: u64,
+    /// Where the record starts in the segment
+    pub offset: u64,
+    /// Bytes the record takes up according to its header, or the rest of the segment if it
+    /// doesn't fit
+    pub len: u64,
+    /// The keys whose current value was in the record, which were dropped. A batch record holds
+    /// many keys.
+    pub keys: Vec<Vec<u8>>,
+}
+
+impl KvStore {
+    /// Read every record of every segment in order, passing each one to `f`, and return a
+    /// summary of each segment.
+    ///
+    /// Writes wait until it is done, so that the records it reports as live are consistent.
+    pub fn inspect<F>(&self, mut f: F) -> Result<Vec<SegmentInfo>>
+    where
+        F: FnMut(RecordInfo),
+    {
+        let writer = self.writer();
+        let mut infos = Vec::new();
+        for (&segment, file) in &writer.segments {
+            let mut info = SegmentInfo {
+                segment,
+                version: match file.format {
+                    Format::Json => None,
+                    Format::Binary(version) => Some(version),
+                },
+                created: file.created_at.map(from_millis),
+                ..SegmentInfo::default()
+            };
+            file.for_each_record(TornTail::Ignore, |start, len, pair| {
+                // a merge operand is folded onto records that aren't current themselves
+                let live = live_offset(&self.index, &pair.key).is_some_and(|offset| {
+                    offset.points_to(file, start)
+                        || offset.merged.as_ref().is_some_and(|chain| {
+                            chain
+                                .records
+                                .iter()
+                                .any(|record| record.points_to(file, start))
+                        })
+                }) || self.history.contains(&pair.key, file, start);
+                let len = HEADER_SIZE + len as u64;
+                info.records += 1;
+                if live {
+                    info.live_records += 1;
+                    info.live_bytes += len;
+                }
+                f(RecordInfo {
+                    segment,
+                    offset: start - HEADER_SIZE,
+                    len,
+                    key: pair.key,
+                    value: pair.value,
+                    expires_at: pair.expires_at,
+                    live,
+                    prefix: pair.prefix,
+                    merge: pair.merge,
+                });
+                Ok(())
+            })?;
+            info.size = file.file.metadata()?.len();
+            info.dead_bytes = info.size - file.data_start().min(info.size) - info.live_bytes;
+            infos.push(info);
+        }
+        Ok(infos)
+    }
+
+    /// Check every record of every segment against its checksum, and return those that don't
+    /// match, going on past them to the next record as long as their length fits in the segment.
+    /// The keys whose current value is in a corrupt record are dropped, as when `get` finds them.
+    ///
+    /// Unlike `KvStore::verify`, the store stays open. Writes wait until it is done.
+    pub fn scrub(&self) -> Result<Vec<CorruptRecord>> {
+        let mut writer = self.writer();
+        let mut corrupt = Vec::new();
+        let segments: Vec<(u64, Arc<SegmentFile>)> = writer
+            .segments
+            .iter()
+            .map(|(&segment, file)| (segment, Arc::clone(file)))
+            .collect();
+        for (segment, file) in segments {
+            let damaged = file
+                .damaged_records()
+                .map_err(|err| err.in_file(&file.path, None))?;
+            if damaged.is_empty() {
+                continue;
+            }
+            let offsets: Vec<(Vec<u8>, Offset)> = self
+                .index
+                .iter()
+                .map(|entry| (entry.key().to_vec(), current(&entry)))
+                .filter(|(_, offset)| Arc::ptr_eq(&offset.file, &file))
+                .collect();
+            for (offset, len) in damaged {
+                error!(
+                    "Record at offset {} of segment {} is corrupt",
+                    offset, segment
+                );
+                let mut keys = Vec::new();
+                for (key, at) in &offsets {
+                    if (offset..offset + len).contains(&at.start) && writer.drop_corrupt(key, at) {
+                        keys.push(key.clone());
+                    }
+                }
+                corrupt.push(CorruptRecord {
+                    segment,
+                    offset,
+                    len,
+                    keys,
+                });
+            }
+        }
+        Ok(corrupt)
+    }
+
+    /// Compact every segment that holds stale data right away, whatever the compaction
+    /// threshold, and wait for it to finish. The active segment is sealed first, so that its
+    /// stale data is reclaimed too.
+    pub fn compact(&self) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        {
+            let mut writer = self.writer();
+            if writer.options.read_only {
+                return Err(ReadOnly);
+            }
+            let active_segment = writer.active_segment;
+            if writer
+                .stale_bytes
+                .get(&active_segment)
+                .is_some_and(|&stale| stale > 0)
+            {
+                writer.seal()?;
+            }
+            // the compaction thread only stops once the store is dropped
+            let _ = writer.compactor.send(CompactorMessage::CompactAll(tx));
+        }
+        rx.recv()
+            .unwrap_or_else(|_| Err(io::Error::other("the compaction thread stopped").into()))
+    }
+
+    /// Compact the segments the compaction policy picks right away, one after the other until it
+    /// picks none, even if it only compacts when asked, like `ManualOnly`. Waits for it to
+    /// finish, and returns how many segments were compacted. See the `policy` module.
+    pub fn compact_now(&self) -> Result<usize> {
+        let (tx, rx) = mpsc::channel();
+        {
+            let writer = self.writer();
+            if writer.options.read_only {
+                return Err(ReadOnly);
+            }
+            // the compaction thread only stops once the store is dropped
+            let _ = writer.compactor.send(CompactorMessage::CompactNow(tx));
+        }
+        rx.recv()
+            .unwrap_or_else(|_| Err(io::Error::other("the compaction thread stopped").into()))
+    }
+
+    /// Check the checksum of every record of the store in `dir`, without opening it.
+    ///
+    /// Records appended while the store is being checked may show up as torn. Use
+    /// `KvStoreOptions::verify` to check an encrypted store.
+    pub fn verify(dir: impl AsRef<Path>) -> Result<Vec<SegmentCheck>> {
+        KvStore::options().verify(dir)
+    }
+
+    /// Cut every damaged segment of the store in `dir` off after its last intact record, so that
+    /// the store can be opened again, and return the checks of all the segments from before the
+    /// repair. The records after the damage are lost.
+    ///
+    /// Fails with `StoreLocked` if the store is open, and with `UnsupportedFormat` without
+    /// changing anything if a segment was written by a newer version. Use
+    /// `KvStoreOptions::repair` to repair an encrypted store.
+    pub fn repair(dir: impl AsRef<Path>) -> Result<Vec<SegmentCheck>> {
+        KvStore::options().repair(dir)
+    }
+}
+
+impl KvStoreOptions {
+    /// Like `KvStore::verify`, decrypting the records with the encryption key of these options.
+    pub fn verify(&self, dir: impl AsRef<Path>) -> Result<Vec<SegmentCheck>> {
+        let dir = dir.as_ref();
+        live_segments(dir)?
+            .into_iter()
+            .map(|(segment, path)| check_segment(&path, segment, self.encryption_key.as_ref()))
+            .collect()
+    }
+
+    /// Like `KvStore::repair`, decrypting the records with the encryption key of these options.
+    pub fn repair(&self, dir: impl AsRef<Path>) -> Result<Vec<SegmentCheck>> {
+        let dir = dir.as_ref();
+        let _lock = lock_dir(dir)?;
+        let checks = self.verify(dir)?;
+        let paths: HashMap<u64, PathBuf> = live_segments(dir)?.into_iter().collect();
+        for check in &checks {
+            if let Some(UnsupportedFormat(version)) = check.error {
+                return Err(UnsupportedFormat(version));
+            }
+        }
+        let key = self.encryption_key.as_ref();
+        for check in checks.iter().filter(|check| !check.is_ok()) {
+            warn!(
+                "Truncating segment {} from {} to {} bytes",
+                check.segment, check.size, check.valid_len
+            );
+            let path = &paths[&check.segment];
+            remove_hint(dir, check.segment)?;
+            OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(check.valid_len)?;
+            // A batch whose records were cut in the middle is torn now, so cut the rest of it.
+            SegmentFile::open_path(path.clone(), true, key)?
+                .for_each_record(TornTail::Truncate, |_, _, _| Ok(()))?;
+        }
+        Ok(checks)
+    }
+}
+
+/// Read every record of a segment until the first damaged one.
+fn check_segment(path: &Path, segment: u64, key: Option<&EncryptionKey>) -> Result<SegmentCheck> {
+    let size = fs::metadata(path)?.len();
+    let mut check = SegmentCheck {
+        segment,
+        size,
+        valid_len: 0,
+        records: 0,
+        error: None,
+    };
+    let file = match SegmentFile::open_path(path.to_owned(), false, key) {
+        Ok(file) => file,
+        // a torn header, or one from a newer version
+        Err(err @ ChecksumMismatch) | Err(err @ UnsupportedFormat(_)) => {
+            check.error = Some(err);
+            return Ok(check);
+        }
+        Err(err) => return Err(err),
+    };
+
+    let mut valid_len = file.data_start().min(size);
+    let mut records = 0;
+    let result = file.for_each_record(TornTail::Ignore, |start, len, _| {
+        valid_len = start + len as u64;
+        records += 1;
+        Ok(())
+    });
+    check.valid_len = valid_len;
+    check.records = records;
+    match result {
+        Ok(end) if end < size => check.error = Some(ChecksumMismatch),
+        Ok(_) => {}
+        Err(err @ ChecksumMismatch) | Err(err @ InvalidRecord) | Err(err @ SerdeError(_)) => {
+            check.error = Some(err)
+        }
+        Err(err) => return Err(err),
+    }
+    Ok(check)
+}
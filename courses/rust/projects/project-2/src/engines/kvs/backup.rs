@@ -0,0 +1,139 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use super::manifest::persist;
+use super::segment::{
+    segment_path, write_file_header, write_record, KvPair, SegmentFile, TornTail,
+};
+use super::{current, now_millis, KvStore, WriteBatch};
+use crate::error::KvsError::ChecksumMismatch;
+use crate::error::Result;
+
+// A backup holds a single segment with all the live keys.
+const BACKUP_SEGMENT: u64 = 1;
+
+// Holds the CRC32 checksum of the backup segment, in hex.
+const CHECKSUM_FILE: &str = "checksum";
+
+impl KvStore {
+    /// Write a consistent copy of the store to `dir`, which must not hold a store already.
+    ///
+    /// The copy is taken from a snapshot, so writes can go on while it is made. The backup is a
+    /// store of its own with a single segment, along with a file holding the checksum of that
+    /// segment, which `restore` verifies. The backup of an encrypted store is encrypted with the
+    /// same key.
+    pub fn backup(&self, dir: impl Into<PathBuf>) -> Result<()> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        if segment_path(&dir, BACKUP_SEGMENT).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already holds a store", dir.display()),
+            )
+            .into());
+        }
+
+        let options = self.writer().options.clone();
+        let snapshot = self.snapshot();
+        let mut output = tempfile::NamedTempFile::new_in(&dir)?;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut header = Vec::new();
+        let cipher = write_file_header(&mut header, options.encryption_key.as_ref())?;
+        hasher.update(&header);
+        output.write_all(&header)?;
+        for offset in snapshot.offsets.values() {
+            let pair = offset.read_pair()?;
+            let mut record = Vec::new();
+            let data = pair.encode(options.compression_threshold, cipher.as_ref());
+            write_record(&mut record, &data)?;
+            hasher.update(&record);
+            output.write_all(&record)?;
+        }
+        output.as_file().sync_all()?;
+        persist(output, &segment_path(&dir, BACKUP_SEGMENT))?;
+        fs::write(
+            dir.join(CHECKSUM_FILE),
+            format!("{:08x}\n", hasher.finalize()),
+        )?;
+        info!(
+            "Backed up {} keys to {}",
+            snapshot.offsets.len(),
+            dir.display()
+        );
+        Ok(())
+    }
+
+    /// Replace the contents of the store with the backup in `dir`.
+    ///
+    /// The backup is verified against its checksum before anything is written, and fails with
+    /// `ChecksumMismatch` if it doesn't match. The keys are replaced atomically: they are
+    /// written as a single batch, so a crash leaves either the old or the restored contents.
+    pub fn restore(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        verify_backup(dir)?;
+
+        let key = self.writer().options.encryption_key.clone();
+        let segment = SegmentFile::open(dir, BACKUP_SEGMENT, false, key.as_ref())?;
+        let now = now_millis();
+        let mut batch = WriteBatch::default();
+        segment.for_each_record(TornTail::Fail, |_, _, pair| {
+            if pair.value.is_some() && pair.expires_at.is_none_or(|t| t > now) {
+                batch.pairs.push(pair);
+            }
+            Ok(())
+        })?;
+
+        self.write(|writer| {
+            let restored: HashSet<&[u8]> = batch.pairs.iter().map(|p| p.key.as_slice()).collect();
+            let mut removed = WriteBatch::default();
+            for entry in writer.index.iter() {
+                if !restored.contains(&entry.key()[..]) && !current(&entry).is_expired(now) {
+                    removed.pairs.push(KvPair {
+                        key: entry.key().to_vec(),
+                        value: None,
+                        expires_at: None,
+                        modified_at: None,
+                        blob: None,
+                        vlog: None,
+                        seq: None,
+                        prefix: false,
+                        merge: false,
+                    });
+                }
+            }
+            info!(
+                "Restoring {} keys and removing {} from {}",
+                batch.len(),
+                removed.len(),
+                dir.display()
+            );
+            removed.pairs.append(&mut batch.pairs);
+            writer.write_batch(removed)
+        })
+    }
+}
+
+/// Check the segment of the backup in `dir` against the checksum recorded next to it.
+fn verify_backup(dir: &Path) -> Result<()> {
+    let expected = fs::read_to_string(dir.join(CHECKSUM_FILE))?;
+    let expected = u32::from_str_radix(expected.trim(), 16).map_err(|_| ChecksumMismatch)?;
+
+    let mut file = fs::File::open(segment_path(dir, BACKUP_SEGMENT))?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    if hasher.finalize() != expected {
+        return Err(ChecksumMismatch);
+    }
+    Ok(())
+}
@@ -0,0 +1,341 @@
+//! Values streamed in and out of the store, for values too large to hold in memory.
+//!
+//! `KvStore::set_reader` writes a value larger than 64 KiB to a blob file named
+//! `<id>.blob` next to the segments, and then appends a record that refers to it. A blob file
+//! has the file header of a segment, and its records are the chunks of the value, each checked
+//! and, in an encrypted store, encrypted on its own. `KvStore::get_writer` copies the chunks out
+//! one at a time. Every chunk but the last holds 1 MiB of the value, so `KvStore::get_range`
+//! skips to the chunks of a range by their headers alone, and `KvStore::value_len` tells the
+//! length of the value from its record.
+//!
+//! The other reads load the whole value of a blob into memory. So do the followers of the store,
+//! which start over from a copy of it after each value streamed in, since the replication log
+//! keeps its entries in memory.
+//!
+//! A blob belongs to a single record: renaming or copying the key links the blob under a new id.
+//! Compaction removes the blob of each record it drops, once the scans, snapshots and replays
+//! that may still read it are dropped, as described in the `pin` module. A crash between writing
+//! a blob and appending its record leaves the blob behind.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::manifest::persist;
+use super::segment::{write_file_header, write_record, KvPair, SegmentFile};
+use super::{live_offset, KvStore, KvStoreWriter, Metrics, Offset};
+use crate::error::KvsError::{InvalidRecord, ReadOnly};
+use crate::error::Result;
+
+// The values `set_reader` streams in up to this size are stored in the log like any other.
+const INLINE_LIMIT: u64 = 64 * 1024;
+
+// The size of the chunks of a blob.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Where the value of a record is kept, if it is in a blob file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct BlobRef {
+    pub(super) id: u64,
+    // the length of the value
+    pub(super) len: u64,
+}
+
+impl BlobRef {
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(16);
+        data.extend_from_slice(&u64::to_le_bytes(self.id));
+        data.extend_from_slice(&u64::to_le_bytes(self.len));
+        data
+    }
+
+    pub(super) fn decode(data: &[u8]) -> Result<BlobRef> {
+        if data.len() != 16 {
+            return Err(InvalidRecord);
+        }
+        let mut id = [0; 8];
+        id.copy_from_slice(&data[..8]);
+        let mut len = [0; 8];
+        len.copy_from_slice(&data[8..]);
+        Ok(BlobRef {
+            id: u64::from_le_bytes(id),
+            len: u64::from_le_bytes(len),
+        })
+    }
+}
+
+pub(super) fn blob_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.blob", id))
+}
+
+/// The id to give the next blob written in `dir`.
+pub(super) fn next_blob_id(dir: &Path) -> Result<u64> {
+    let mut next = 1;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() != Some(OsStr::new("blob")) {
+            continue;
+        }
+        if let Some(id) = path
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .and_then(|s| s.parse::<u64>().ok())
+        {
+            next = next.max(id + 1);
+        }
+    }
+    Ok(next)
+}
+
+impl KvStore {
+    /// Set a key to the `len` bytes read from `reader`, which are streamed to disk rather than
+    /// held in memory. See the `blob` module.
+    ///
+    /// Fails with an `UnexpectedEof` error, without changing the key, if `reader` ends before
+    /// `len` bytes. Whatever it yields past them is left unread.
+    pub fn set_reader(&self, key: String, reader: impl Read, len: u64) -> Result<()> {
+        let (dir, encryption_key) = {
+            let writer = self.writer();
+            if writer.options.read_only {
+                return Err(ReadOnly);
+            }
+            writer.check_free_space()?;
+            (writer.dir.clone(), writer.options.encryption_key.clone())
+        };
+        let mut reader = reader.take(len);
+        if len <= INLINE_LIMIT {
+            let mut value = Vec::with_capacity(len as usize);
+            reader.read_to_end(&mut value)?;
+            check_len(value.len() as u64, len)?;
+            return self.set_bytes(key.as_bytes(), &value);
+        }
+
+        let mut output = tempfile::NamedTempFile::new_in(&dir)?;
+        let cipher = write_file_header(&mut output, encryption_key.as_ref())?;
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let mut written = 0;
+        loop {
+            let n = read_chunk(&mut reader, &mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            match &cipher {
+                Some(cipher) => write_record(&mut output, &cipher.encrypt(&chunk[..n]))?,
+                None => write_record(&mut output, &chunk[..n])?,
+            }
+            written += n as u64;
+        }
+        check_len(written, len)?;
+        output.as_file().sync_all()?;
+
+        self.write(|writer| {
+            let blob = writer.new_blob_id();
+            let path = blob_path(&dir, blob);
+            persist(output, &path)?;
+            let result = writer.append(KvPair {
+                key: key.into_bytes(),
+                value: Some(Vec::new()),
+                expires_at: None,
+                modified_at: None,
+                blob: Some(BlobRef { id: blob, len }),
+                vlog: None,
+                seq: None,
+                prefix: false,
+                merge: false,
+            });
+            if result.is_err() {
+                let _ = fs::remove_file(&path);
+            }
+            result
+        })
+    }
+
+    /// Write the value of a key to `writer`, a chunk at a time for a value streamed in with
+    /// `set_reader`, and return whether the key exists.
+    pub fn get_writer(&self, key: String, mut writer: impl Write) -> Result<bool> {
+        Metrics::add(&self.metrics.reads, 1);
+        self.sample_read(key.as_bytes());
+        let (offset, pair) = match self.read_live_pair(key.as_bytes())? {
+            Some(read) => read,
+            None => return Ok(false),
+        };
+        self.used(key.as_bytes());
+        match (pair.blob, pair.vlog) {
+            (Some(blob), _) => offset.file.read_blob(blob, &mut writer)?,
+            (None, Some(vlog)) => {
+                writer.write_all(&offset.file.read_vlog_value(&pair.key, vlog)?)?
+            }
+            (None, None) => writer.write_all(&pair.value.unwrap_or_default())?,
+        }
+        writer.flush()?;
+        Ok(true)
+    }
+
+    /// Write the `len` bytes of the value of a key from `start` on to `writer`, or as many of
+    /// them as the value holds, and return whether the key exists. Only the chunks holding the
+    /// range are read of a value streamed in with `set_reader`, and the whole value of others.
+    pub fn get_range(
+        &self,
+        key: String,
+        start: u64,
+        len: u64,
+        mut writer: impl Write,
+    ) -> Result<bool> {
+        Metrics::add(&self.metrics.reads, 1);
+        self.sample_read(key.as_bytes());
+        let (offset, pair) = match self.read_live_pair(key.as_bytes())? {
+            Some(read) => read,
+            None => return Ok(false),
+        };
+        self.used(key.as_bytes());
+        let value = match (pair.blob, pair.vlog) {
+            (Some(blob), _) => {
+                offset.file.read_blob_range(blob, start, len, &mut writer)?;
+                writer.flush()?;
+                return Ok(true);
+            }
+            (None, Some(vlog)) => offset.file.read_vlog_value(&pair.key, vlog)?,
+            (None, None) => pair.value.unwrap_or_default(),
+        };
+        let start = start.min(value.len() as u64) as usize;
+        let end = start + len.min((value.len() - start) as u64) as usize;
+        writer.write_all(&value[start..end])?;
+        writer.flush()?;
+        Ok(true)
+    }
+
+    /// The length of the value of a key, or `None` if it doesn't exist. It is read from the
+    /// record of a value streamed in with `set_reader`, without reading the value.
+    pub fn value_len(&self, key: String) -> Result<Option<u64>> {
+        let (offset, pair) = match self.read_live_pair(key.as_bytes())? {
+            Some(read) => read,
+            None => return Ok(None),
+        };
+        let len = match (pair.blob, pair.vlog) {
+            (Some(blob), _) => blob.len,
+            (None, Some(vlog)) => offset.file.read_vlog_value(&pair.key, vlog)?.len() as u64,
+            (None, None) => pair.value.map_or(0, |value| value.len() as u64),
+        };
+        Ok(Some(len))
+    }
+
+    /// The offset and the record of a key that exists, without the value it may refer to.
+    fn read_live_pair(&self, key: &[u8]) -> Result<Option<(Offset, KvPair)>> {
+        let offset = match live_offset(&self.index, key) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        // a merge operand is folded onto the records before it
+        let stored = match offset.merged {
+            Some(_) => offset.read_pair(),
+            None => offset.file.read_stored_pair(offset.start, offset.len),
+        };
+        let pair = self.check_corruption(key, &offset, stored)?;
+        Ok(Some((offset, pair)))
+    }
+}
+
+impl KvStoreWriter {
+    pub(super) fn new_blob_id(&mut self) -> u64 {
+        let id = self.next_blob;
+        self.next_blob += 1;
+        id
+    }
+
+    /// Give the blob of a record copied to another key an id of its own, so that each blob
+    /// belongs to a single record.
+    pub(super) fn link_blob(&mut self, blob: Option<BlobRef>) -> Result<Option<BlobRef>> {
+        let blob = match blob {
+            Some(blob) => blob,
+            None => return Ok(None),
+        };
+        let id = self.new_blob_id();
+        let (from, to) = (blob_path(&self.dir, blob.id), blob_path(&self.dir, id));
+        if fs::hard_link(&from, &to).is_err() {
+            fs::copy(&from, &to)?;
+        }
+        Ok(Some(BlobRef { id, ..blob }))
+    }
+}
+
+impl SegmentFile {
+    /// Write the value held by `blob` to `writer`, a chunk at a time.
+    pub(super) fn read_blob(&self, blob: BlobRef, writer: &mut impl Write) -> Result<()> {
+        let path = blob_path(&self.store_dir, blob.id);
+        let file = SegmentFile::open_path(path.clone(), false, self.key.as_ref())
+            .map_err(|err| err.in_file(&path, None))?;
+        let mut read = 0;
+        file.for_each_chunk(|_, chunk| {
+            read += chunk.len() as u64;
+            writer.write_all(chunk)?;
+            Ok(())
+        })?;
+        if read != blob.len {
+            return Err(InvalidRecord.in_file(path, None));
+        }
+        Ok(())
+    }
+
+    /// Write the `len` bytes of the value held by `blob` from `start` on to `writer`, or as many
+    /// of them as it holds, reading only the chunks they are in.
+    pub(super) fn read_blob_range(
+        &self,
+        blob: BlobRef,
+        start: u64,
+        len: u64,
+        writer: &mut impl Write,
+    ) -> Result<()> {
+        let path = blob_path(&self.store_dir, blob.id);
+        let file = SegmentFile::open_path(path.clone(), false, self.key.as_ref())
+            .map_err(|err| err.in_file(&path, None))?;
+        let end = start.saturating_add(len).min(blob.len);
+        if start >= end {
+            return Ok(());
+        }
+        let mut offset = file.data_start();
+        // where the chunk at `offset` starts in the value
+        let mut chunk_start = 0;
+        while chunk_start + CHUNK_SIZE as u64 <= start {
+            offset = file.next_record(offset)?;
+            chunk_start += CHUNK_SIZE as u64;
+        }
+        while chunk_start < end {
+            let (chunk, next) = file.read_chunk(offset)?;
+            if chunk.is_empty() {
+                return Err(InvalidRecord.in_file(path, Some(offset)));
+            }
+            let from = start.saturating_sub(chunk_start).min(chunk.len() as u64) as usize;
+            let to = (end - chunk_start).min(chunk.len() as u64) as usize;
+            writer.write_all(&chunk[from..to])?;
+            chunk_start += chunk.len() as u64;
+            offset = next;
+        }
+        Ok(())
+    }
+}
+
+/// Fill as much of `chunk` as `reader` has left, and return how much that is.
+fn read_chunk(reader: &mut impl Read, chunk: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < chunk.len() {
+        match reader.read(&mut chunk[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+fn check_len(read: u64, len: u64) -> Result<()> {
+    if read < len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("the value ended after {} of its {} bytes", read, len),
+        )
+        .into());
+    }
+    Ok(())
+}
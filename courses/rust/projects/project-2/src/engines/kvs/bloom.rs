@@ -0,0 +1,173 @@
+//! Bloom filters, which tell that a segment holds no record of a key without reading it, with
+//! `KvStoreOptions::bloom_false_positive_rate`.
+//!
+//! A filter is written next to the hint of a segment, as `<id>.bloom`, whenever the hint is, and
+//! holds the keys of the records of the segment, tombstones included but range tombstones left
+//! out. It starts with the magic bytes `kvb`, a format version and the size of the segment it was
+//! written for, as a u64 LE, and is only used if the segment still has that size. Then comes a
+//! single record framed like a segment record:
+//!
+//! ```text
+//! [hashes: u8][bits]
+//! ```
+//!
+//! A key is looked up at `hashes` bits, derived from two hashes of it, and may be in the segment
+//! only if they are all set. The hashes are taken from the SHA-256 digest of the key, which
+//! doesn't change from one build to the next as the filters outlive them.
+//!
+//! The index holds every key in memory, so `get` never reads a segment for a missing key to
+//! begin with. The filters spare the reads that look for the records of a key in every segment,
+//! those of `KvStore::purge`.
+
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use sha2::{Digest, Sha256};
+
+use super::manifest::persist;
+use super::segment::{read_record, write_record};
+use crate::error::KvsError::InvalidRecord;
+use crate::error::Result;
+
+const MAGIC: &[u8; 3] = b"kvb";
+
+const VERSION: u8 = 1;
+
+const HEADER_SIZE: usize = 12;
+
+// Bounds of the hashes per key, and of the bits per key, whatever the false-positive rate.
+const MAX_HASHES: u32 = 30;
+const MAX_BITS_PER_KEY: f64 = 64.0;
+
+/// A Bloom filter of the keys of a segment. See the `bloom` module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct BloomFilter {
+    hashes: u32,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    /// An empty filter sized for `keys` keys, that takes a missing key for one about
+    /// `false_positive_rate` of the time once they are inserted.
+    pub(super) fn new(keys: usize, false_positive_rate: f64) -> BloomFilter {
+        let ln2 = std::f64::consts::LN_2;
+        let bits_per_key = (-false_positive_rate.ln() / (ln2 * ln2)).clamp(1.0, MAX_BITS_PER_KEY);
+        let hashes = ((bits_per_key * ln2).round() as u32).clamp(1, MAX_HASHES);
+        let bits = ((keys.max(1) as f64 * bits_per_key).ceil() as usize).max(64);
+        BloomFilter {
+            hashes,
+            bits: vec![0; bits.div_ceil(8)],
+        }
+    }
+
+    pub(super) fn insert(&mut self, key: &[u8]) {
+        for bit in self.bit_indexes(key) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Whether `key` may have been inserted. A key that was is never missed.
+    pub(super) fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_indexes(key)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// The bit of each hash of `key`, derived from two hashes of it rather than hashing it once
+    /// per bit.
+    fn bit_indexes(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let digest = Sha256::digest(key);
+        let h1 = u64::from_le_bytes(digest[..8].try_into().expect("digest is 32 bytes"));
+        let h2 = u64::from_le_bytes(digest[8..16].try_into().expect("digest is 32 bytes"));
+        let len = self.bits.len() as u64 * 8;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+pub(super) fn filter_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{}.bloom", segment))
+}
+
+/// Write `filter` as the filter of `segment`, whose file is `segment_size` bytes long.
+///
+/// The filter is written to a temporary file first, so a crash never leaves a partial one behind.
+pub(super) fn write_filter(
+    dir: &Path,
+    segment: u64,
+    segment_size: u64,
+    filter: &BloomFilter,
+) -> Result<()> {
+    let mut data = Vec::with_capacity(HEADER_SIZE + 9 + filter.bits.len());
+    data.extend_from_slice(MAGIC);
+    data.push(VERSION);
+    data.extend_from_slice(&u64::to_le_bytes(segment_size));
+    let mut record = Vec::with_capacity(1 + filter.bits.len());
+    record.push(filter.hashes as u8);
+    record.extend_from_slice(&filter.bits);
+    write_record(&mut data, &record)?;
+
+    let mut output = tempfile::NamedTempFile::new_in(dir)?;
+    output.write_all(&data)?;
+    persist(output, &filter_path(dir, segment))
+}
+
+/// Remove the filter of `segment` if there is one.
+pub(super) fn remove_filter(dir: &Path, segment: u64) -> Result<()> {
+    match fs::remove_file(filter_path(dir, segment)) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// Read the filter of `segment`, whose file is `segment_size` bytes long. Returns `None` if there
+/// is no filter or it can't be used, in which case the segment has to be read instead.
+pub(super) fn read_filter(dir: &Path, segment: u64, segment_size: u64) -> Option<BloomFilter> {
+    let data = match fs::read(filter_path(dir, segment)) {
+        Ok(data) => data,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            warn!(
+                "Failed to read the filter of segment {}: {:?}",
+                segment, err
+            );
+            return None;
+        }
+    };
+    match parse_filter(&data, segment_size) {
+        Ok(filter) => filter,
+        Err(err) => {
+            warn!("Ignoring the filter of segment {}: {:?}", segment, err);
+            None
+        }
+    }
+}
+
+fn parse_filter(data: &[u8], segment_size: u64) -> Result<Option<BloomFilter>> {
+    if data.len() < HEADER_SIZE || &data[..3] != MAGIC || data[3] != VERSION {
+        return Err(InvalidRecord);
+    }
+    let size = u64::from_le_bytes(data[4..12].try_into().expect("header is 12 bytes"));
+    if size != segment_size {
+        // written for an older version of the segment
+        return Ok(None);
+    }
+    let mut rest = &data[HEADER_SIZE..];
+    let remaining = rest.len() as u64;
+    let record = match read_record(&mut rest, remaining, remaining)? {
+        Some((false, record)) if rest.is_empty() => record,
+        _ => return Err(InvalidRecord),
+    };
+    match record.split_first() {
+        Some((&hashes, bits))
+            if (1..=MAX_HASHES).contains(&u32::from(hashes)) && !bits.is_empty() =>
+        {
+            Ok(Some(BloomFilter {
+                hashes: u32::from(hashes),
+                bits: bits.to_vec(),
+            }))
+        }
+        _ => Err(InvalidRecord),
+    }
+}
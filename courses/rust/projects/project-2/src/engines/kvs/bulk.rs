@@ -0,0 +1,136 @@
+use std::io::{self, BufWriter, Write};
+use std::sync::Arc;
+
+use log::info;
+
+use super::hint::{write_hint, HintEntry};
+use super::manifest::persist;
+use super::segment::{
+    file_header_size, segment_path, write_file_header, write_record, KvPair, SegmentFile,
+    HEADER_SIZE,
+};
+use super::{now_millis, update_index, KvStore, Metrics, Offset};
+use crate::error::KvsError::ReadOnly;
+use crate::error::Result;
+
+impl KvStore {
+    /// Set many keys at once, from `pairs` sorted by key with no key twice, and return how many
+    /// were set.
+    ///
+    /// Rather than being appended one at a time, the pairs are streamed into a new segment that
+    /// is synced once and added to the store along with its hint, as if compaction had written
+    /// it. Other writes go on meanwhile, and those made before the load finishes are overwritten
+    /// by it. Nothing is loaded if `pairs` turns out not to be sorted.
+    ///
+    /// Watchers aren't told about the loaded keys, and followers start over from a full copy of
+    /// the store. The loaded keys aren't given sequence numbers, so `replay_from` leaves them out.
+    pub fn bulk_load<I, K, V>(&self, pairs: I) -> Result<u64>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        let (dir, options) = {
+            let writer = self.writer();
+            (writer.dir.clone(), writer.options.clone())
+        };
+        if options.read_only {
+            return Err(ReadOnly);
+        }
+
+        let mut output = tempfile::NamedTempFile::new_in(&dir)?;
+        let mut file = BufWriter::new(output.as_file_mut());
+        let cipher = write_file_header(&mut file, options.encryption_key.as_ref())?;
+        let mut size = file_header_size(cipher.is_some());
+        let mut hints: Vec<HintEntry> = Vec::new();
+        let modified_at = Some(now_millis());
+        for (key, value) in pairs {
+            let pair = KvPair {
+                key: key.into(),
+                value: Some(value.into()),
+                expires_at: None,
+                modified_at,
+                blob: None,
+                vlog: None,
+                seq: None,
+                prefix: false,
+                merge: false,
+            };
+            if hints.last().is_some_and(|last| last.key >= pair.key) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "bulk-loaded keys must be sorted, with no key twice",
+                )
+                .into());
+            }
+            let data = pair.encode(options.compression_threshold, cipher.as_ref());
+            write_record(&mut file, &data)?;
+            size += HEADER_SIZE + data.len() as u64;
+            hints.push(HintEntry {
+                key: pair.key,
+                has_value: true,
+                start: size - data.len() as u64,
+                len: data.len(),
+                expires_at: None,
+                prefix: false,
+            });
+        }
+        file.flush()?;
+        drop(file);
+        output.as_file().sync_all()?;
+        let loaded = hints.len() as u64;
+        if hints.is_empty() {
+            return Ok(0);
+        }
+
+        // The segment goes after every other, so that its values replace theirs.
+        let mut writer = self.writer();
+        writer.check_index_memory(hints.iter().map(|entry| entry.key.as_slice()))?;
+        let segment = writer.active_segment + 1;
+        persist(output, &segment_path(&dir, segment))?;
+        // a hint would hold the keys of an encrypted segment in plaintext
+        if cipher.is_none() {
+            write_hint(
+                &dir,
+                segment,
+                size,
+                &hints,
+                options.bloom_false_positive_rate,
+            )?;
+        }
+        let file = Arc::new(
+            SegmentFile::open(&dir, segment, false, options.encryption_key.as_ref())?
+                .with_max_record_size(options.max_record_size),
+        );
+        let writer = &mut *writer;
+        for entry in hints {
+            let offset = Offset {
+                segment,
+                file: Arc::clone(&file),
+                start: entry.start,
+                len: entry.len,
+                expires_at: None,
+                merged: None,
+            };
+            writer.cache.invalidate(&entry.key);
+            writer.written(&entry.key, Some(offset.record_len()));
+            update_index(
+                &writer.index,
+                &writer.history,
+                &mut writer.stale_bytes,
+                &mut writer.index_bytes,
+                entry.key,
+                true,
+                offset,
+            );
+        }
+        writer.segments.insert(segment, file);
+        writer.active_segment = segment;
+        writer.seal()?;
+        writer.replication.restart();
+        Metrics::add(&writer.metrics.writes, loaded);
+        info!("Bulk loaded {} keys into segment {}", loaded, segment);
+        writer.after_write()?;
+        Ok(loaded)
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use lru::LruCache;
+
+use super::segment::SegmentFile;
+use super::Offset;
+
+/// A least-recently-used cache of values, bounded by the bytes of the keys and values it holds.
+///
+/// Every value is stored with the offset it was read from, and is only returned for a lookup at
+/// that same offset. A reader that raced with a writer can thus cache an old value without it
+/// ever being returned once the index points at the new one.
+pub(super) struct ReadCache {
+    capacity: AtomicUsize,
+    entries: Mutex<Entries>,
+}
+
+struct Entries {
+    lru: LruCache<Vec<u8>, CachedValue>,
+    // bytes of the keys and values in `lru`
+    size: usize,
+}
+
+struct CachedValue {
+    // A weak reference keeps the address of the file from being reused by another segment, while
+    // letting the file be closed once compaction replaces it.
+    file: Weak<SegmentFile>,
+    start: u64,
+    value: Vec<u8>,
+}
+
+impl ReadCache {
+    /// Create a cache holding up to `capacity` bytes. A capacity of zero disables it.
+    pub(super) fn new(capacity: usize) -> ReadCache {
+        ReadCache {
+            capacity: AtomicUsize::new(capacity),
+            entries: Mutex::new(Entries {
+                lru: LruCache::unbounded(),
+                size: 0,
+            }),
+        }
+    }
+
+    /// The value of `key` if it was cached from `offset`.
+    pub(super) fn get(&self, key: &[u8], offset: &Offset) -> Option<Vec<u8>> {
+        if self.capacity() == 0 {
+            return None;
+        }
+        let mut entries = self.entries();
+        match entries.lru.get(key) {
+            Some(cached)
+                if Weak::as_ptr(&cached.file) == Arc::as_ptr(&offset.file)
+                    && cached.start == offset.start =>
+            {
+                Some(cached.value.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Cache the value of `key` read from `offset`, evicting the least recently used values to
+    /// make room for it.
+    pub(super) fn insert(&self, key: &[u8], offset: &Offset, value: &[u8]) {
+        let size = key.len() + value.len();
+        let mut entries = self.entries();
+        // read under the lock, for `set_capacity` not to be undone by a value inserted meanwhile
+        let capacity = self.capacity();
+        if size > capacity {
+            return;
+        }
+        let cached = CachedValue {
+            file: Arc::downgrade(&offset.file),
+            start: offset.start,
+            value: value.to_vec(),
+        };
+        if let Some(old) = entries.lru.put(key.to_vec(), cached) {
+            entries.size -= key.len() + old.value.len();
+        }
+        entries.size += size;
+        entries.evict(capacity);
+    }
+
+    /// Hold up to `capacity` bytes from now on, evicting the least recently used values that
+    /// no longer fit.
+    pub(super) fn set_capacity(&self, capacity: usize) {
+        let mut entries = self.entries();
+        self.capacity.store(capacity, Ordering::Relaxed);
+        entries.evict(capacity);
+    }
+
+    /// Drop the cached value of `key`.
+    pub(super) fn invalidate(&self, key: &[u8]) {
+        if self.capacity() == 0 {
+            return;
+        }
+        let mut entries = self.entries();
+        if let Some(old) = entries.lru.pop(key) {
+            entries.size -= key.len() + old.value.len();
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, Entries> {
+        self.entries.lock().expect("cache lock poisoned")
+    }
+}
+
+impl Entries {
+    // Evict the least recently used values until the others fit in `capacity` bytes.
+    fn evict(&mut self, capacity: usize) {
+        while self.size > capacity {
+            match self.lru.pop_lru() {
+                Some((key, old)) => self.size -= key.len() + old.value.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ReadCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadCache")
+            .field("capacity", &self.capacity())
+            .field("size", &self.entries().size)
+            .finish()
+    }
+}
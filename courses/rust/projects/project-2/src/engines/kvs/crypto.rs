@@ -0,0 +1,107 @@
+//! Encryption of the records of a segment.
+//!
+//! Each segment of an encrypted store has a key of its own, derived with HKDF-SHA256 from the key
+//! of the store and a random salt kept in the file header of the segment, so compaction
+//! re-encrypts the records it copies under a fresh key. Records are encrypted with
+//! XChaCha20-Poly1305 under a random nonce, whose 24 bytes are enough for random nonces never to
+//! repeat, and which is stored in front of the ciphertext.
+//!
+//! The file header also holds the tag of an empty message encrypted under the all-zero nonce,
+//! which tells a wrong key apart from damaged records.
+
+use std::fmt;
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::error::KvsError::InvalidRecord;
+use crate::error::Result;
+
+pub(super) const SALT_SIZE: usize = 16;
+
+pub(super) const KEY_CHECK_SIZE: usize = 16;
+
+const NONCE_SIZE: usize = 24;
+
+// Binds the keys derived from the key of a store to their use.
+const KDF_INFO: &[u8] = b"kvs segment key";
+
+/// The key a store is encrypted with.
+#[derive(Clone, PartialEq, Eq)]
+pub(super) struct EncryptionKey(pub(super) [u8; 32]);
+
+/// Keeps the key out of logs.
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Encrypts and decrypts the records of a segment.
+pub(super) struct SegmentCipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl fmt::Debug for SegmentCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SegmentCipher(..)")
+    }
+}
+
+impl SegmentCipher {
+    /// The cipher of the segment with `salt` in a store encrypted with `key`.
+    pub(super) fn new(key: &EncryptionKey, salt: &[u8]) -> SegmentCipher {
+        let mut segment_key = [0; 32];
+        Hkdf::<Sha256>::new(Some(salt), &key.0)
+            .expand(KDF_INFO, &mut segment_key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        SegmentCipher {
+            aead: XChaCha20Poly1305::new(&segment_key.into()),
+        }
+    }
+
+    /// A salt for a new segment.
+    pub(super) fn random_salt() -> [u8; SALT_SIZE] {
+        let mut salt = [0; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        salt
+    }
+
+    /// The value stored in the file header to check the key against.
+    pub(super) fn key_check(&self) -> [u8; KEY_CHECK_SIZE] {
+        let tag = self
+            .aead
+            .encrypt(&XNonce::default(), &[][..])
+            .expect("an empty message can be encrypted");
+        let mut check = [0; KEY_CHECK_SIZE];
+        check.copy_from_slice(&tag);
+        check
+    }
+
+    /// Encrypt the data of a record, prefixed with its nonce.
+    pub(super) fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .aead
+            .encrypt(&nonce, data)
+            .expect("records are small enough to be encrypted");
+        let mut sealed = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypt the data of a record. Fails with `InvalidRecord` if it was tampered with.
+    pub(super) fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_SIZE {
+            return Err(InvalidRecord);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| InvalidRecord)
+    }
+}
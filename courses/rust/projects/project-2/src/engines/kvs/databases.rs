@@ -0,0 +1,80 @@
+//! Several stores open in one process, each opened only once.
+//!
+//! A store can only be opened once at a time: the lock file keeps a second `KvStore::open` of the
+//! same directory from succeeding, even in the same process. `Databases` opens each directory
+//! the first time it is asked for, and hands out clones of the same `KvStore` after that. The
+//! directories are told apart by their canonical paths, so that a relative path, a symbolic link
+//! or a path with `..` in it finds the store opened through another path.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::{KvStore, KvStoreOptions};
+use crate::error::Result;
+
+/// The stores open in the process, by directory.
+///
+/// The stores are kept open until they are closed with `close`, or the `Databases` is dropped,
+/// and their other clones are dropped too.
+#[derive(Debug, Default)]
+pub struct Databases {
+    // what the stores are opened with
+    options: KvStoreOptions,
+    // the stores open, by canonical path
+    stores: Mutex<HashMap<PathBuf, KvStore>>,
+}
+
+impl Databases {
+    /// A manager that opens the stores with the default options.
+    pub fn new() -> Databases {
+        Databases::default()
+    }
+
+    /// A manager that opens the stores with `options`.
+    pub fn with_options(options: KvStoreOptions) -> Databases {
+        Databases {
+            options,
+            stores: Mutex::default(),
+        }
+    }
+
+    /// The store in the directory at `path`, opened unless it already is. The directory must
+    /// exist.
+    pub fn open(&self, path: impl AsRef<Path>) -> Result<KvStore> {
+        let path = fs::canonicalize(path)?;
+        // the lock is held while the store is opened, so that it is opened only once
+        let mut stores = self.stores.lock().expect("databases lock poisoned");
+        if let Some(store) = stores.get(&path) {
+            return Ok(store.clone());
+        }
+        let store = self.options.open(&path)?;
+        stores.insert(path, store.clone());
+        Ok(store)
+    }
+
+    /// The store in the directory at `path`, if it is open.
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<KvStore> {
+        let path = fs::canonicalize(path).ok()?;
+        let stores = self.stores.lock().expect("databases lock poisoned");
+        stores.get(&path).cloned()
+    }
+
+    /// Stop keeping the store in the directory at `path` open, and return whether it was. The
+    /// store closes once its other clones are dropped, and the next `open` opens it again.
+    pub fn close(&self, path: impl AsRef<Path>) -> bool {
+        let path = match fs::canonicalize(path) {
+            Ok(path) => path,
+            Err(_) => return false,
+        };
+        let mut stores = self.stores.lock().expect("databases lock poisoned");
+        stores.remove(&path).is_some()
+    }
+
+    /// The canonical paths of the stores open, in no particular order.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let stores = self.stores.lock().expect("databases lock poisoned");
+        stores.keys().cloned().collect()
+    }
+}
@@ -0,0 +1,147 @@
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use super::segment::KvPair;
+use super::{now_millis, KvStore, WriteBatch};
+use crate::error::KvsError::{self, UnknownFormat};
+use crate::error::Result;
+
+// Number of keys `import` writes in each batch.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// The formats `KvStore::export` writes and `KvStore::import` reads.
+///
+/// Every key is written with its value and, if it expires, with when it expires in milliseconds
+/// since the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// One JSON object per line, like `{"key":"k","value":"v","expires_at":1700000000000}`, without
+    /// `expires_at` for keys that don't expire
+    JsonLines,
+    /// CSV with a header row and the columns `key`, `value` and `expires_at`, which is empty for
+    /// keys that don't expire
+    Csv,
+}
+
+impl FromStr for DumpFormat {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<DumpFormat> {
+        match s {
+            "jsonl" => Ok(DumpFormat::JsonLines),
+            "csv" => Ok(DumpFormat::Csv),
+            _ => Err(UnknownFormat(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct DumpRecord {
+    key: String,
+    value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+impl KvStore {
+    /// Write every key of the store with its value to `writer`, in sorted key order, and return
+    /// the number of keys written.
+    ///
+    /// The keys are read from a snapshot, so writes can go on meanwhile. Fails with `Utf8Error`
+    /// if a key or value isn't valid UTF-8, since neither format can hold arbitrary bytes.
+    pub fn export(&self, writer: impl Write, format: DumpFormat) -> Result<u64> {
+        let snapshot = self.snapshot();
+        let records = snapshot
+            .offsets
+            .iter()
+            .map(|(key, offset)| -> Result<DumpRecord> {
+                let pair = offset.read_pair()?;
+                Ok(DumpRecord {
+                    key: String::from_utf8(key.clone())?,
+                    value: String::from_utf8(pair.value.unwrap_or_default())?,
+                    expires_at: offset.expires_at,
+                })
+            });
+
+        let mut count = 0;
+        match format {
+            DumpFormat::JsonLines => {
+                let mut writer = BufWriter::new(writer);
+                for record in records {
+                    serde_json::to_writer(&mut writer, &record?)?;
+                    writer.write_all(b"\n")?;
+                    count += 1;
+                }
+                writer.flush()?;
+            }
+            DumpFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(writer);
+                writer.write_record(["key", "value", "expires_at"])?;
+                for record in records {
+                    let record = record?;
+                    writer.serialize((record.key, record.value, record.expires_at))?;
+                    count += 1;
+                }
+                writer.flush()?;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Set every key read from `reader`, written by `export`, and return the number of keys set.
+    ///
+    /// Keys already in the store are overwritten, and the others are left alone. Keys that
+    /// have expired since they were exported are skipped. The keys are written in batches of a
+    /// thousand, so if reading fails halfway, the batches written before stay.
+    pub fn import(&self, reader: impl Read, format: DumpFormat) -> Result<u64> {
+        let now = now_millis();
+        let mut batch = WriteBatch::default();
+        let mut count = 0;
+        let mut add = |record: DumpRecord| -> Result<()> {
+            if record
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= now)
+            {
+                return Ok(());
+            }
+            batch.pairs.push(KvPair {
+                key: record.key.into_bytes(),
+                value: Some(record.value.into_bytes()),
+                expires_at: record.expires_at,
+                modified_at: None,
+                blob: None,
+                vlog: None,
+                seq: None,
+                prefix: false,
+                merge: false,
+            });
+            count += 1;
+            if batch.len() >= IMPORT_BATCH_SIZE {
+                self.write_batch(std::mem::take(&mut batch))?;
+            }
+            Ok(())
+        };
+
+        match format {
+            DumpFormat::JsonLines => {
+                for line in BufReader::new(reader).lines() {
+                    let line = line?;
+                    if !line.trim().is_empty() {
+                        add(serde_json::from_str(&line)?)?;
+                    }
+                }
+            }
+            DumpFormat::Csv => {
+                for record in csv::Reader::from_reader(reader).deserialize() {
+                    add(record?)?;
+                }
+            }
+        }
+        if !batch.is_empty() {
+            self.write_batch(batch)?;
+        }
+        Ok(count)
+    }
+}
@@ -0,0 +1,140 @@
+//! Capacity limits, for using a store as a bounded cache.
+//!
+//! With `KvStoreOptions::max_keys` or `KvStoreOptions::max_live_bytes` set, the store keeps its
+//! live keys in the order they were last written, or last read or written with
+//! `EvictionPolicy::LeastRecentlyUsed`, along with the size of their records. After each write,
+//! the writer removes the keys at the front with tombstones until the store is back within its
+//! limits. The order isn't persisted: on open, the keys are ordered by where their records are in
+//! the log, i.e. by when they were last written.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use log::debug;
+
+use super::segment::KvPair;
+use super::{KvStoreWriter, Metrics};
+use crate::error::Result;
+
+/// Which keys a `KvStore` with capacity limits removes first once it goes over them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// The keys written the longest ago
+    LeastRecentlyWritten,
+    /// The keys read or written the longest ago. Every read then takes a lock to record it.
+    LeastRecentlyUsed,
+}
+
+/// The live keys of a store with capacity limits, the least recently written or used first.
+#[derive(Debug)]
+pub(super) struct Recency {
+    policy: EvictionPolicy,
+    state: Mutex<RecencyState>,
+}
+
+#[derive(Debug, Default)]
+struct RecencyState {
+    // incremented on every write or use
+    clock: u64,
+    // maps each key to when it was last written or used, and the size of its record
+    keys: HashMap<Vec<u8>, (u64, u64)>,
+    // the keys by when they were last written or used
+    order: BTreeMap<u64, Vec<u8>>,
+    // the sizes of the records of the keys, added up
+    bytes: u64,
+}
+
+impl Recency {
+    pub(super) fn new(policy: EvictionPolicy) -> Recency {
+        Recency {
+            policy,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Record that `key` was written with a record of `bytes` bytes, or removed if it is `None`.
+    pub(super) fn written(&self, key: &[u8], bytes: Option<u64>) {
+        let mut state = self.lock();
+        if let Some((tick, old_bytes)) = state.keys.remove(key) {
+            state.order.remove(&tick);
+            state.bytes -= old_bytes;
+        }
+        if let Some(bytes) = bytes {
+            state.clock += 1;
+            let tick = state.clock;
+            state.keys.insert(key.to_vec(), (tick, bytes));
+            state.order.insert(tick, key.to_vec());
+            state.bytes += bytes;
+        }
+    }
+
+    /// Record that `key` was read, if reads count.
+    pub(super) fn used(&self, key: &[u8]) {
+        if self.policy != EvictionPolicy::LeastRecentlyUsed {
+            return;
+        }
+        let mut state = self.lock();
+        state.clock += 1;
+        let clock = state.clock;
+        let tick = match state.keys.get_mut(key) {
+            Some((tick, _)) => std::mem::replace(tick, clock),
+            None => return,
+        };
+        if let Some(key) = state.order.remove(&tick) {
+            state.order.insert(clock, key);
+        }
+    }
+
+    /// The keys to remove, first to last, to get down to `max_keys` keys and `max_bytes` bytes.
+    fn victims(&self, max_keys: Option<u64>, max_bytes: Option<u64>) -> Vec<Vec<u8>> {
+        let state = self.lock();
+        let mut keys = state.keys.len() as u64;
+        let mut bytes = state.bytes;
+        let mut victims = Vec::new();
+        for key in state.order.values() {
+            if max_keys.is_none_or(|max| keys <= max) && max_bytes.is_none_or(|max| bytes <= max) {
+                break;
+            }
+            keys -= 1;
+            bytes -= state.keys[key].1;
+            victims.push(key.clone());
+        }
+        victims
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RecencyState> {
+        self.state.lock().expect("recency lock poisoned")
+    }
+}
+
+impl KvStoreWriter {
+    /// Remove the least recently written or used keys while the store is over its capacity
+    /// limits.
+    pub(super) fn evict(&mut self) -> Result<()> {
+        let victims = match &self.recency {
+            Some(recency) => recency.victims(self.options.max_keys, self.options.max_live_bytes),
+            None => return Ok(()),
+        };
+        if victims.is_empty() {
+            return Ok(());
+        }
+        debug!("Evicting {} keys", victims.len());
+        Metrics::add(&self.metrics.evictions, victims.len() as u64);
+        self.write_pairs(
+            victims
+                .into_iter()
+                .map(|key| KvPair {
+                    key,
+                    value: None,
+                    expires_at: None,
+                    modified_at: None,
+                    blob: None,
+                    vlog: None,
+                    seq: None,
+                    prefix: false,
+                    merge: false,
+                })
+                .collect(),
+        )
+    }
+}
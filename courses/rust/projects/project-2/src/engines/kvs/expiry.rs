@@ -0,0 +1,164 @@
+//! Expiring keys as soon as their TTL runs out.
+//!
+//! Reads treat an expired key as missing on their own, but a key nobody reads again would only
+//! go when compaction reaches its segment. The writer keeps the keys that expire in a heap
+//! ordered by when they do, and an expiry thread wakes up at the earliest of them, writes a
+//! tombstone for each key that expired and is still set to the same record, and sends an
+//! `Expire` event to the watchers of the key. Keys compaction drops first are reported by
+//! compaction instead.
+
+use std::cmp::Reverse;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use log::error;
+
+use super::segment::KvPair;
+use super::watch::ChangeEvent;
+use super::{current, now_millis, KvStoreWriter};
+use crate::error::Result;
+
+// How long to wait before trying again after expiring keys failed, e.g. on a full disk.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub(super) enum ExpiryMessage {
+    // A key expires before those the thread is waiting for.
+    Wake,
+    Shutdown,
+}
+
+/// The expiry thread, which stops once it is dropped.
+#[derive(Debug)]
+pub(super) struct Expirer {
+    pub(super) tx: Sender<ExpiryMessage>,
+    pub(super) handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for Expirer {
+    fn drop(&mut self) {
+        // the thread may already be gone if it panicked
+        let _ = self.tx.send(ExpiryMessage::Shutdown);
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("The expiry thread panicked");
+            }
+        }
+    }
+}
+
+impl KvStoreWriter {
+    /// Remember that `key` expires at `expires_at`, and wake the expiry thread if it does so
+    /// before every other key.
+    pub(super) fn schedule_expiry(&mut self, key: &[u8], expires_at: u64) {
+        if self.options.read_only {
+            return;
+        }
+        let is_first = self
+            .expiring
+            .peek()
+            .is_none_or(|Reverse((first, _))| expires_at < *first);
+        self.expiring.push(Reverse((expires_at, key.to_vec())));
+        if is_first {
+            // the expiry thread only stops once the store is dropped
+            let _ = self.expirer.send(ExpiryMessage::Wake);
+        }
+    }
+
+    /// Remove the keys that expired by `now` and report them to their watchers. Returns when the
+    /// next key expires, if any does.
+    pub(super) fn expire(&mut self, now: u64) -> Result<Option<u64>> {
+        // A key set twice to expire at the same time is in the heap twice, and they come out
+        // one after the other.
+        let mut due: Vec<(u64, Vec<u8>)> = Vec::new();
+        while let Some(Reverse((expires_at, _))) = self.expiring.peek() {
+            if *expires_at > now {
+                break;
+            }
+            let Reverse(entry) = self.expiring.pop().expect("peeked an entry");
+            // the key may have been set again or removed since, or dropped by compaction
+            let is_current = self
+                .index
+                .get(&entry.1)
+                .is_some_and(|index_entry| current(&index_entry).expires_at == Some(entry.0));
+            if is_current && due.last() != Some(&entry) {
+                due.push(entry);
+            }
+        }
+        if due.is_empty() {
+            return Ok(self.next_expiry());
+        }
+        match self.write_expired(&due) {
+            Ok(()) => Ok(self.next_expiry()),
+            Err(err) => {
+                // the keys are tried again, unless they change meanwhile
+                self.expiring.extend(due.into_iter().map(Reverse));
+                Err(err)
+            }
+        }
+    }
+
+    /// Write tombstones for expired keys, and send `Expire` events instead of the removals.
+    fn write_expired(&mut self, due: &[(u64, Vec<u8>)]) -> Result<()> {
+        let mut events = Vec::new();
+        for (_, key) in due {
+            if let Some(offset) = self.index.get(key).map(|entry| current(&entry)) {
+                if self.watchers.is_watched(key) {
+                    let value = offset.read_pair()?.value;
+                    events.push((key.clone(), ChangeEvent::expired(key, value.as_deref())));
+                }
+            }
+        }
+        let pairs = due
+            .iter()
+            .map(|(_, key)| KvPair {
+                key: key.clone(),
+                value: None,
+                expires_at: None,
+                modified_at: None,
+                blob: None,
+                vlog: None,
+                seq: None,
+                prefix: false,
+                merge: false,
+            })
+            .collect();
+        self.write_pairs_with(pairs, events)
+    }
+
+    fn next_expiry(&self) -> Option<u64> {
+        self.expiring.peek().map(|Reverse((first, _))| *first)
+    }
+}
+
+/// Expire keys whenever the earliest of them is due, until the store is dropped.
+pub(super) fn run_expirer(writer: Weak<Mutex<KvStoreWriter>>, rx: Receiver<ExpiryMessage>) {
+    // keys may have expired while the store was closed
+    let mut next: Option<u64> = Some(0);
+    loop {
+        let message = match next {
+            Some(at) => rx.recv_timeout(Duration::from_millis(at.saturating_sub(now_millis()))),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match message {
+            Ok(ExpiryMessage::Wake) | Err(RecvTimeoutError::Timeout) => {}
+            Ok(ExpiryMessage::Shutdown) | Err(RecvTimeoutError::Disconnected) => return,
+        }
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let mut writer = writer.lock().expect("writer lock poisoned");
+        next = match writer.expire(now_millis()) {
+            Ok(next) => next,
+            Err(err) => {
+                error!("Expiring keys failed: {}", err);
+                Some(now_millis() + RETRY_DELAY.as_millis() as u64)
+            }
+        };
+        // nobody waits for the tombstones to be synced with `SyncPolicy::Group`
+        writer.ticket = None;
+    }
+}
@@ -0,0 +1,31 @@
+//! The parsers of the files of a store, for the fuzz targets. See the `fuzzing` module of the
+//! crate.
+
+use std::convert::TryInto;
+use std::io::Write;
+
+use super::hint::parse_hint;
+use super::segment::{KvPair, SegmentFile, TornTail};
+
+/// Open `data` as a segment, and decode each of its records.
+pub(crate) fn segment(data: &[u8]) {
+    let mut file = tempfile::NamedTempFile::new().expect("unable to create a temporary file");
+    file.write_all(data)
+        .expect("unable to write a temporary file");
+    if let Ok(segment) = SegmentFile::open_path(file.path().to_owned(), false, None) {
+        let _ = segment.for_each_record(TornTail::Ignore, |_, _, _| Ok(()));
+    }
+}
+
+/// Decode `data` as the data of a binary record.
+pub(crate) fn record(data: &[u8]) {
+    let _ = KvPair::decode(data);
+}
+
+/// Parse `data` as a hint file, for a segment of the size it says.
+pub(crate) fn hint(data: &[u8]) {
+    let segment_size = data.get(4..12).map_or(0, |size| {
+        u64::from_le_bytes(size.try_into().expect("8 bytes"))
+    });
+    let _ = parse_hint(data, segment_size);
+}
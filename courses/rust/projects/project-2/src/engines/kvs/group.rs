@@ -0,0 +1,160 @@
+//! Group commit, for `SyncPolicy::Group`.
+//!
+//! Writers don't sync the active segment themselves: each write takes a ticket, numbered in the
+//! order the writes were made, and its writer waits for it once it has let go of the writer
+//! lock. A committer thread syncs the active segment once for all the tickets taken since its
+//! last sync, and wakes their writers. Writes made while a sync is running are synced together
+//! by the next one, so the more writers there are, the fewer syncs each write costs.
+
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+
+use log::error;
+
+use super::KvStoreWriter;
+use crate::error::Result;
+
+#[derive(Debug)]
+pub(super) struct GroupCommit {
+    state: Mutex<CommitState>,
+    // notified when a ticket is taken, when tickets are synced and when the committer stops
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct CommitState {
+    // the last ticket taken
+    written: u64,
+    // the last ticket synced
+    synced: u64,
+    // Why a sync failed. Nothing written since can be known to be durable, so every write that
+    // waits afterwards fails too.
+    failed: Option<(io::ErrorKind, String)>,
+    stopped: bool,
+}
+
+impl GroupCommit {
+    pub(super) fn new() -> GroupCommit {
+        GroupCommit {
+            state: Mutex::default(),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Take a ticket for a write just made to the active segment. Must be called with the
+    /// writer lock held.
+    pub(super) fn ticket(&self) -> u64 {
+        let mut state = self.lock();
+        state.written += 1;
+        self.changed.notify_all();
+        state.written
+    }
+
+    /// Wait until the write that took `ticket` is synced. Must be called without the writer
+    /// lock, which the committer needs.
+    pub(super) fn wait(&self, ticket: u64) -> Result<()> {
+        let mut state = self.lock();
+        loop {
+            if let Some((kind, message)) = &state.failed {
+                return Err(io::Error::new(*kind, message.clone()).into());
+            }
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            state = self
+                .changed
+                .wait(state)
+                .expect("group commit lock poisoned");
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CommitState> {
+        self.state.lock().expect("group commit lock poisoned")
+    }
+}
+
+/// The committer thread, which stops once it is dropped.
+#[derive(Debug)]
+pub(super) struct Committer {
+    group: Arc<GroupCommit>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Committer {
+    pub(super) fn start(
+        group: Arc<GroupCommit>,
+        writer: Weak<Mutex<KvStoreWriter>>,
+    ) -> io::Result<Committer> {
+        let thread_group = Arc::clone(&group);
+        let handle = thread::Builder::new()
+            .name("kvs-commit".to_owned())
+            .spawn(move || run_committer(&thread_group, writer))?;
+        Ok(Committer {
+            group,
+            handle: Some(handle),
+        })
+    }
+
+    /// Wait until the write that took `ticket` is synced.
+    pub(super) fn wait(&self, ticket: u64) -> Result<()> {
+        self.group.wait(ticket)
+    }
+}
+
+impl Drop for Committer {
+    fn drop(&mut self) {
+        self.group.lock().stopped = true;
+        self.group.changed.notify_all();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("The commit thread panicked");
+            }
+        }
+    }
+}
+
+/// Sync the active segment whenever tickets are waiting, until the store is dropped or a sync
+/// fails.
+fn run_committer(group: &GroupCommit, writer: Weak<Mutex<KvStoreWriter>>) {
+    loop {
+        {
+            let mut state = group.lock();
+            while state.written == state.synced && !state.stopped {
+                state = group
+                    .changed
+                    .wait(state)
+                    .expect("group commit lock poisoned");
+            }
+            // writers wait for their tickets while they hold the store, so none is left by now
+            if state.stopped {
+                return;
+            }
+        }
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        // Tickets are taken with the writer lock held, so every write up to `target` is in the
+        // active segment, or in a segment that was synced when it was sealed.
+        let (file, target) = {
+            let writer = writer.lock().expect("writer lock poisoned");
+            (Arc::clone(&writer.active_file), group.lock().written)
+        };
+        drop(writer);
+
+        let result = file.file.sync_data();
+        let mut state = group.lock();
+        match result {
+            Ok(()) => state.synced = target,
+            Err(err) => {
+                error!("Syncing the active segment failed: {}", err);
+                state.failed = Some((err.kind(), err.to_string()));
+            }
+        }
+        group.changed.notify_all();
+        if state.failed.is_some() {
+            return;
+        }
+    }
+}
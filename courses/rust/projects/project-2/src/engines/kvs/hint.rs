@@ -0,0 +1,203 @@
+//! Hint files, which let `open` load the index entries of a compacted segment without reading the
+//! segment itself.
+//!
+//! The hint of segment `<id>.log` is `<id>.hint`. It starts with the magic bytes `kvh`, a format
+//! version and the size of the segment it was written for, as a u64 LE. A hint is only used if
+//! the segment still has that size. Then comes a record framed like a segment record for each
+//! record of the segment, in the same order:
+//!
+//! ```text
+//! [flags: u8][key len: u32 LE][key][start: u64 LE][len: u32 LE][expires at: u64 LE, if flagged]
+//! ```
+//!
+//! Since version 2, an entry may be flagged as a range tombstone. Older versions would take one
+//! for the tombstone of a single key, so they ignore the hint and read the segment instead,
+//! which fails on the range tombstone rather than bring back the keys it removed.
+//!
+//! The Bloom filter of the segment, described in the `bloom` module, is written and removed along
+//! with its hint.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use super::bloom::{remove_filter, write_filter, BloomFilter};
+use super::manifest::persist;
+use super::segment::{read_record, write_record};
+use crate::error::KvsError::InvalidRecord;
+use crate::error::Result;
+
+const MAGIC: &[u8; 3] = b"kvh";
+
+const VERSION: u8 = 2;
+
+const HEADER_SIZE: usize = 12;
+
+// Flags of an entry.
+const HAS_VALUE: u8 = 1;
+const HAS_EXPIRY: u8 = 1 << 1;
+const PREFIX: u8 = 1 << 2;
+
+/// Where a record of a segment is, and what it does to its key.
+#[derive(Debug)]
+pub(super) struct HintEntry {
+    pub(super) key: Vec<u8>,
+    // false if the record is a tombstone
+    pub(super) has_value: bool,
+    pub(super) start: u64,
+    pub(super) len: usize,
+    pub(super) expires_at: Option<u64>,
+    // true if the record is a range tombstone, which removes the keys that start with `key`
+    pub(super) prefix: bool,
+}
+
+pub(super) fn hint_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{}.hint", segment))
+}
+
+/// Write the hint of `segment`, whose file is `segment_size` bytes long, along with a Bloom filter
+/// of its keys with `false_positive_rate` if there is one.
+///
+/// The hint is written to a temporary file first, so a crash never leaves a partial hint behind.
+pub(super) fn write_hint(
+    dir: &Path,
+    segment: u64,
+    segment_size: u64,
+    entries: &[HintEntry],
+    false_positive_rate: Option<f64>,
+) -> Result<()> {
+    if let Some(rate) = false_positive_rate {
+        let mut filter = BloomFilter::new(entries.len(), rate);
+        for entry in entries.iter().filter(|entry| !entry.prefix) {
+            filter.insert(&entry.key);
+        }
+        write_filter(dir, segment, segment_size, &filter)?;
+    }
+    let mut data = Vec::new();
+    data.extend_from_slice(MAGIC);
+    data.push(VERSION);
+    data.extend_from_slice(&u64::to_le_bytes(segment_size));
+    for entry in entries {
+        let mut flags = 0;
+        if entry.has_value {
+            flags |= HAS_VALUE;
+        }
+        if entry.expires_at.is_some() {
+            flags |= HAS_EXPIRY;
+        }
+        if entry.prefix {
+            flags |= PREFIX;
+        }
+        let mut record = Vec::with_capacity(25 + entry.key.len());
+        record.push(flags);
+        record.extend_from_slice(&u32::to_le_bytes(entry.key.len() as u32));
+        record.extend_from_slice(&entry.key);
+        record.extend_from_slice(&u64::to_le_bytes(entry.start));
+        record.extend_from_slice(&u32::to_le_bytes(entry.len as u32));
+        if let Some(expires_at) = entry.expires_at {
+            record.extend_from_slice(&u64::to_le_bytes(expires_at));
+        }
+        write_record(&mut data, &record)?;
+    }
+
+    let mut output = tempfile::NamedTempFile::new_in(dir)?;
+    output.write_all(&data)?;
+    persist(output, &hint_path(dir, segment))
+}
+
+/// Remove the hint of `segment` and its filter if there are.
+pub(super) fn remove_hint(dir: &Path, segment: u64) -> Result<()> {
+    match fs::remove_file(hint_path(dir, segment)) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+        result => result?,
+    }
+    remove_filter(dir, segment)
+}
+
+/// Read the hint of `segment`, whose file is `segment_size` bytes long. Returns `None` if there
+/// is no hint or it can't be used, in which case the segment has to be read instead.
+pub(super) fn read_hint(dir: &Path, segment: u64, segment_size: u64) -> Option<Vec<HintEntry>> {
+    let data = match fs::read(hint_path(dir, segment)) {
+        Ok(data) => data,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            warn!("Failed to read the hint of segment {}: {:?}", segment, err);
+            return None;
+        }
+    };
+    match parse_hint(&data, segment_size) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("Ignoring the hint of segment {}: {:?}", segment, err);
+            None
+        }
+    }
+}
+
+pub(super) fn parse_hint(data: &[u8], segment_size: u64) -> Result<Option<Vec<HintEntry>>> {
+    if data.len() < HEADER_SIZE || &data[..3] != MAGIC || !(1..=VERSION).contains(&data[3]) {
+        return Err(InvalidRecord);
+    }
+    if read_u64(&data[4..HEADER_SIZE]) != segment_size {
+        // written for an older version of the segment
+        return Ok(None);
+    }
+
+    let mut entries = Vec::new();
+    let mut records = &data[HEADER_SIZE..];
+    while !records.is_empty() {
+        let remaining = records.len() as u64;
+        // the hint is already in memory, so its records are only bounded by its size
+        let record = match read_record(&mut records, remaining, remaining)? {
+            Some((false, record)) => record,
+            _ => return Err(InvalidRecord),
+        };
+        let entry = parse_entry(&record).ok_or(InvalidRecord)?;
+        // the segment is read from where the entry says, for as long as it says
+        if entry
+            .start
+            .checked_add(entry.len as u64)
+            .is_none_or(|end| end > segment_size)
+        {
+            return Err(InvalidRecord);
+        }
+        entries.push(entry);
+    }
+    Ok(Some(entries))
+}
+
+fn parse_entry(record: &[u8]) -> Option<HintEntry> {
+    let flags = *record.first()?;
+    let key_len = read_u32(record.get(1..5)?) as usize;
+    let key = record.get(5..5 + key_len)?.to_vec();
+    let rest = &record[5 + key_len..];
+    let start = read_u64(rest.get(..8)?);
+    let len = read_u32(rest.get(8..12)?) as usize;
+    let expires_at = if flags & HAS_EXPIRY != 0 {
+        Some(read_u64(rest.get(12..20)?))
+    } else {
+        None
+    };
+    Some(HintEntry {
+        key,
+        has_value: flags & HAS_VALUE != 0,
+        start,
+        len,
+        expires_at,
+        prefix: flags & PREFIX != 0,
+    })
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(bytes);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_le_bytes(buf)
+}
@@ -0,0 +1,106 @@
+//! The older versions of the keys, kept when `KvStoreOptions::versions` is above 1.
+//!
+//! The index only points to the current record of each key. The history points to the records
+//! of the values each key had before, newest first, which compaction keeps rather than reclaims.
+//! It is rebuilt on open by replaying the records, as the index is: compaction only drops the
+//! records that fell out of the history, so replaying those it keeps gives the same versions.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crossbeam_skiplist::SkipMap;
+
+use super::segment::SegmentFile;
+use super::{KeyRange, Offset};
+
+#[derive(Debug)]
+pub(super) struct History {
+    // older versions kept besides the current one
+    depth: usize,
+    versions: SkipMap<Vec<u8>, Mutex<VecDeque<Offset>>>,
+}
+
+impl History {
+    /// A history keeping `versions` versions of each key, the current one included.
+    pub(super) fn new(versions: usize) -> History {
+        History {
+            depth: versions.saturating_sub(1),
+            versions: SkipMap::new(),
+        }
+    }
+
+    /// Keep `offset`, the record of the value `key` had until now. Returns the record that no
+    /// version points to anymore, which is then stale.
+    pub(super) fn push(&self, key: &[u8], offset: Offset) -> Option<Offset> {
+        if self.depth == 0 {
+            return Some(offset);
+        }
+        let entry = self
+            .versions
+            .get_or_insert_with(key.to_vec(), Mutex::default);
+        let mut versions = entry.value().lock().expect("history lock poisoned");
+        versions.push_front(offset);
+        if versions.len() > self.depth {
+            versions.pop_back()
+        } else {
+            None
+        }
+    }
+
+    /// The records of the older versions of `key`, newest first.
+    pub(super) fn get(&self, key: &[u8]) -> Vec<Offset> {
+        match self.versions.get(key) {
+            Some(entry) => {
+                let versions = entry.value().lock().expect("history lock poisoned");
+                versions.iter().cloned().collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Whether the record at `start` in `file` is one of the older versions of `key`.
+    pub(super) fn contains(&self, key: &[u8], file: &Arc<SegmentFile>, start: u64) -> bool {
+        self.get(key)
+            .iter()
+            .any(|offset| offset.points_to(file, start))
+    }
+
+    /// Forget the older versions of the keys in `range`, and return their records, which are
+    /// then stale.
+    pub(super) fn remove_range(&self, range: KeyRange) -> Vec<Offset> {
+        let mut removed = Vec::new();
+        for entry in self.versions.range(range) {
+            if entry.remove() {
+                let mut versions = entry.value().lock().expect("history lock poisoned");
+                removed.extend(versions.drain(..));
+            }
+        }
+        removed
+    }
+
+    /// Point the version of `key` recorded at `start` in `file` to `offset`, where compaction
+    /// copied it. Returns whether it is one of the versions.
+    pub(super) fn relocate(
+        &self,
+        key: &[u8],
+        file: &Arc<SegmentFile>,
+        start: u64,
+        offset: Offset,
+    ) -> bool {
+        let entry = match self.versions.get(key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let mut versions = entry.value().lock().expect("history lock poisoned");
+        match versions
+            .iter_mut()
+            .find(|version| version.points_to(file, start))
+        {
+            Some(version) => {
+                *version = offset;
+                true
+            }
+            None => false,
+        }
+    }
+}
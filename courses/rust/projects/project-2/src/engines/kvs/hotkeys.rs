@@ -0,0 +1,200 @@
+//! Sampling the reads and writes of the keys, to find those most of the traffic goes to, with
+//! `KvStoreOptions::key_sampling`.
+//!
+//! One in every `n` reads and writes is sampled, and counted in a count-min sketch: a few rows of
+//! counters, each indexed by a different hash of the key, which take up the same memory however
+//! many keys there are. The count of a key is the smallest of its counters, each of which also
+//! counts the keys that share it, so it may be overestimated but never underestimated. The keys
+//! with the highest counts so far are kept as candidates, up to `CANDIDATES` of them, for
+//! `KvStore::hot_keys` to rank. Counts are scaled back up by `n` to estimate every read and write
+//! rather than those sampled, and are only kept in memory, from when the store was opened.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::{KvStore, KvStoreWriter};
+
+// The rows of each sketch, and the counters in each row.
+const DEPTH: usize = 4;
+const WIDTH: usize = 4096;
+
+// The keys kept as candidates for the hottest.
+const CANDIDATES: usize = 256;
+
+/// A key that many reads and writes went to, returned by `KvStore::hot_keys`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HotKey {
+    /// The key
+    pub key: Vec<u8>,
+    /// Estimated reads of the key, `get` and the other reads of its value
+    pub reads: u64,
+    /// Estimated writes of the key, sets, removals and merges, those of batches included
+    pub writes: u64,
+}
+
+/// Whether an access to a key read or wrote it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Access {
+    Read,
+    Write,
+}
+
+/// Counters indexed by hashes of the keys. See the `hotkeys` module.
+#[derive(Debug)]
+struct Sketch {
+    counters: Vec<AtomicU64>,
+}
+
+impl Sketch {
+    fn new() -> Sketch {
+        Sketch {
+            counters: (0..DEPTH * WIDTH).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    fn add(&self, hashes: (u64, u64)) {
+        for index in indexes(hashes) {
+            self.counters[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn estimate(&self, hashes: (u64, u64)) -> u64 {
+        indexes(hashes)
+            .map(|index| self.counters[index].load(Ordering::Relaxed))
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+/// The counter of each row for a key with `hashes`, derived from two hashes of it rather than
+/// hashing it once per row.
+fn indexes((h1, h2): (u64, u64)) -> impl Iterator<Item = usize> {
+    (0..DEPTH).map(move |row| {
+        let hash = h1.wrapping_add((row as u64).wrapping_mul(h2));
+        row * WIDTH + (hash % WIDTH as u64) as usize
+    })
+}
+
+fn hashes(key: &[u8]) -> (u64, u64) {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let hash = hasher.finish();
+    // an odd second hash visits every counter of a row
+    (hash & 0xffff_ffff, (hash >> 32) | 1)
+}
+
+/// The sampled reads and writes of the keys of a store.
+#[derive(Debug)]
+pub(super) struct KeySampler {
+    // one access in this many is sampled
+    every: u64,
+    accesses: AtomicU64,
+    reads: Sketch,
+    writes: Sketch,
+    // the keys with the highest counts, with their count when last sampled
+    candidates: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl KeySampler {
+    /// A sampler of one access in every `every`.
+    pub(super) fn new(every: u32) -> KeySampler {
+        KeySampler {
+            every: u64::from(every.max(1)),
+            accesses: AtomicU64::new(0),
+            reads: Sketch::new(),
+            writes: Sketch::new(),
+            candidates: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count an access to `key`, if it is sampled.
+    pub(super) fn sample(&self, key: &[u8], access: Access) {
+        if !self
+            .accesses
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.every)
+        {
+            return;
+        }
+        let hashes = hashes(key);
+        match access {
+            Access::Read => self.reads.add(hashes),
+            Access::Write => self.writes.add(hashes),
+        }
+        let count = self.reads.estimate(hashes) + self.writes.estimate(hashes);
+
+        let mut candidates = self.candidates.lock().expect("sampler lock poisoned");
+        if let Some(candidate) = candidates.get_mut(key) {
+            *candidate = count;
+            return;
+        }
+        if candidates.len() >= CANDIDATES {
+            let coldest = candidates
+                .iter()
+                .min_by_key(|&(_, &count)| count)
+                .map(|(key, &count)| (key.clone(), count));
+            match coldest {
+                Some((coldest, lowest)) if lowest < count => candidates.remove(&coldest),
+                _ => return,
+            };
+        }
+        candidates.insert(key.to_vec(), count);
+    }
+
+    /// The `n` candidates with the most reads and writes, the hottest first.
+    fn hot_keys(&self, n: usize) -> Vec<HotKey> {
+        let candidates: Vec<Vec<u8>> = {
+            let candidates = self.candidates.lock().expect("sampler lock poisoned");
+            candidates.keys().cloned().collect()
+        };
+        let mut keys: Vec<HotKey> = candidates
+            .into_iter()
+            .map(|key| {
+                let hashes = hashes(&key);
+                HotKey {
+                    reads: self.reads.estimate(hashes) * self.every,
+                    writes: self.writes.estimate(hashes) * self.every,
+                    key,
+                }
+            })
+            .collect();
+        keys.sort_by(|a, b| {
+            (b.reads + b.writes)
+                .cmp(&(a.reads + a.writes))
+                .then_with(|| a.key.cmp(&b.key))
+        });
+        keys.truncate(n);
+        keys
+    }
+}
+
+impl KvStore {
+    /// The `n` keys most reads and writes went to since the store was opened, the hottest first,
+    /// with estimates of their reads and writes. See the `hotkeys` module. At most 256 keys are
+    /// tracked, and none unless the store was opened with `KvStoreOptions::key_sampling`.
+    pub fn hot_keys(&self, n: usize) -> Vec<HotKey> {
+        match &self.sampler {
+            Some(sampler) => sampler.hot_keys(n),
+            None => Vec::new(),
+        }
+    }
+
+    /// Count a read of `key`, if its reads and writes are sampled.
+    pub(super) fn sample_read(&self, key: &[u8]) {
+        if let Some(sampler) = &self.sampler {
+            sampler.sample(key, Access::Read);
+        }
+    }
+}
+
+impl KvStoreWriter {
+    /// Count a write of `key`, if its reads and writes are sampled.
+    pub(super) fn sample_write(&self, key: &[u8]) {
+        if let Some(sampler) = &self.sampler {
+            sampler.sample(key, Access::Write);
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! The index of the keys of a store, which maps each key to the offset of its record.
+//!
+//! The keys are held compactly. Most keys are short, and an owned `Vec<u8>` spends more on one
+//! than its bytes: an allocation of its own, the bookkeeping of the allocator for it, and the
+//! capacity it has to spare. An `IndexKey` takes up as much room as a `Vec<u8>` in the entry of
+//! the index, but holds keys of up to `INLINE_LEN` bytes in that room, and longer keys in an
+//! allocation of exactly their length.
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Bound, Deref};
+use std::sync::RwLock;
+
+use crossbeam_skiplist::{map, SkipMap};
+
+use super::{KeyRange, Offset};
+
+/// Maps keys to their offsets. An existing key is updated in place rather than re-inserted,
+/// since replacing an entry of the skip list briefly hides the key from readers.
+#[derive(Debug, Default)]
+pub(super) struct Index {
+    entries: SkipMap<IndexKey, RwLock<Offset>>,
+}
+
+pub(super) type Entry<'a> = map::Entry<'a, IndexKey, RwLock<Offset>>;
+
+pub(super) type IndexRange = (Bound<IndexKey>, Bound<IndexKey>);
+
+impl Index {
+    pub(super) fn get(&self, key: &[u8]) -> Option<Entry<'_>> {
+        self.entries.get(key)
+    }
+
+    pub(super) fn contains_key(&self, key: &[u8]) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub(super) fn insert(&self, key: Vec<u8>, offset: Offset) -> Entry<'_> {
+        self.entries.insert(key.into(), RwLock::new(offset))
+    }
+
+    pub(super) fn remove(&self, key: &[u8]) -> Option<Entry<'_>> {
+        self.entries.remove(key)
+    }
+
+    pub(super) fn range(
+        &self,
+        (start, end): KeyRange,
+    ) -> map::Range<'_, IndexKey, IndexRange, IndexKey, RwLock<Offset>> {
+        self.entries
+            .range((start.map(IndexKey::from), end.map(IndexKey::from)))
+    }
+
+    pub(super) fn iter(&self) -> map::Iter<'_, IndexKey, RwLock<Offset>> {
+        self.entries.iter()
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// The longest key held in the entry of the index rather than on the heap.
+pub(super) const INLINE_LEN: usize = 22;
+
+/// A key of the index, which compares, hashes and borrows as its bytes.
+#[derive(Clone)]
+pub(super) enum IndexKey {
+    Inline { len: u8, bytes: [u8; INLINE_LEN] },
+    Heap(Box<[u8]>),
+}
+
+impl IndexKey {
+    /// The bytes a key of `len` bytes takes up on the heap.
+    pub(super) fn heap_size(len: usize) -> usize {
+        if len <= INLINE_LEN {
+            0
+        } else {
+            len
+        }
+    }
+}
+
+impl From<&[u8]> for IndexKey {
+    fn from(key: &[u8]) -> IndexKey {
+        if key.len() <= INLINE_LEN {
+            let mut bytes = [0; INLINE_LEN];
+            bytes[..key.len()].copy_from_slice(key);
+            IndexKey::Inline {
+                len: key.len() as u8,
+                bytes,
+            }
+        } else {
+            IndexKey::Heap(key.into())
+        }
+    }
+}
+
+impl From<Vec<u8>> for IndexKey {
+    fn from(key: Vec<u8>) -> IndexKey {
+        if key.len() <= INLINE_LEN {
+            IndexKey::from(&key[..])
+        } else {
+            IndexKey::Heap(key.into_boxed_slice())
+        }
+    }
+}
+
+impl Deref for IndexKey {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            IndexKey::Inline { len, bytes } => &bytes[..*len as usize],
+            IndexKey::Heap(bytes) => bytes,
+        }
+    }
+}
+
+impl Borrow<[u8]> for IndexKey {
+    fn borrow(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &IndexKey) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for IndexKey {}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &IndexKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &IndexKey) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl Hash for IndexKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl fmt::Debug for IndexKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
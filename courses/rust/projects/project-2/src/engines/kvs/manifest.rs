@@ -0,0 +1,261 @@
+//! The manifest of a store, which lists the segments that are part of it.
+//!
+//! The manifest is the JSON file `MANIFEST`, replaced as a whole each time the set of segments
+//! changes, so that a crash leaves either the old one or the new one. A segment file is created
+//! before the manifest lists it and removed after the manifest stops listing it, and nothing is
+//! written to a segment before it is listed. A segment file the manifest doesn't list was thus
+//! left behind by a crash, e.g. in the middle of a compaction, and holds nothing that was ever
+//! part of the store, so `open` deletes it along with the temporary files of the store.
+//!
+//! The manifest also holds the compaction generation, the number of compactions the store went
+//! through, the last sequence number given to a write, and the namespaces dropped whose records
+//! may still be in the segments. Since the manifest is written at least each time a segment is
+//! sealed, its sequence number is at least that of every record of the sealed segments, and
+//! the numbers never go back when compaction removes the last records written. Stores
+//! written before they had a manifest are given one listing every segment file the first time
+//! they are opened.
+//!
+//! With `KvStoreOptions::cold_storage`, compaction moves the segments it rewrites to another
+//! storage, and the manifest maps each segment moved to the location of that storage. The new
+//! segment is put in the storage, the manifest is written, and only then is the file in the
+//! directory of the store removed, so a crash leaves a copy the manifest doesn't point to, which
+//! `open` deletes as well. See the `storage` module.
+//!
+//! The layout of the directory, i.e. which files a store is made of and what they hold, has a
+//! version recorded in the manifest. A store with a layout newer than the one this version knows
+//! is refused rather than misread. Manifests written before the layout was recorded have none,
+//! and are given the current one the next time the store is opened for writing.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+use super::hint::remove_hint;
+use super::namespace::namespace_prefix;
+use super::segment::segment_path;
+use super::segment_ids;
+use super::storage::{cached_path, cached_segments, is_local, object_name, Storages};
+use crate::error::KvsError::UnknownFormat;
+use crate::error::Result;
+
+pub(super) const MANIFEST_FILE: &str = "MANIFEST";
+
+// The prefix of the names of the temporary files written in the directory of the store.
+const TEMP_PREFIX: &str = ".tmp";
+
+/// The version of the layout of the directory of a store.
+pub(super) const LAYOUT_VERSION: u32 = 1;
+
+// How many times a rename refused on Windows is retried, and how long apart.
+const RENAME_RETRIES: u32 = 10;
+const RENAME_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct Manifest {
+    // the version of the layout of the directory, or 0 if it predates the version
+    #[serde(default)]
+    pub(super) layout: u32,
+    // how many compactions rewrote or removed a segment
+    pub(super) generation: u64,
+    // the last sequence number given to a write when the manifest was written
+    #[serde(default)]
+    pub(super) sequence: u64,
+    // the live segments, in ascending order
+    pub(super) segments: Vec<u64>,
+    // the namespaces dropped, until no segment that can hold their records is left
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) dropped: Vec<DroppedNamespace>,
+    // the segments moved out of the directory of the store, with the location of the storage
+    // they are in
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(super) cold: BTreeMap<u64, String>,
+}
+
+/// A namespace dropped by `KvStore::drop_namespace`. Its records in the segments up to
+/// `through` are ignored, and compaction leaves them out.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct DroppedNamespace {
+    pub(super) name: String,
+    pub(super) through: u64,
+}
+
+impl DroppedNamespace {
+    /// Whether the record of `key` in `segment` belongs to the namespace when it was dropped.
+    pub(super) fn covers(&self, segment: u64, key: &[u8]) -> bool {
+        segment <= self.through && key.starts_with(namespace_prefix(&self.name).as_bytes())
+    }
+}
+
+/// Read the manifest of the store in `dir`, or `None` if it doesn't have one.
+pub(super) fn read_manifest(dir: &Path) -> Result<Option<Manifest>> {
+    match fs::read(dir.join(MANIFEST_FILE)) {
+        Ok(bytes) => {
+            let manifest: Manifest = serde_json::from_slice(&bytes)?;
+            if manifest.layout > LAYOUT_VERSION {
+                return Err(UnknownFormat(format!("layout {}", manifest.layout)));
+            }
+            Ok(Some(manifest))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Replace the manifest of the store in `dir`, durably.
+pub(super) fn write_manifest(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let mut output = tempfile::NamedTempFile::new_in(dir)?;
+    serde_json::to_writer(&mut output, manifest)?;
+    output.flush()?;
+    output.as_file().sync_all()?;
+    persist(output, &dir.join(MANIFEST_FILE))?;
+    sync_dir(dir)?;
+    Ok(())
+}
+
+/// The file `segment` of the store in `dir` is read from, given the locations of the segments
+/// moved to other storage: its file in the directory of the store or in a local directory, or
+/// the copy kept of it.
+pub(super) fn segment_file(dir: &Path, cold: &BTreeMap<u64, String>, segment: u64) -> PathBuf {
+    match cold.get(&segment) {
+        None => segment_path(dir, segment),
+        Some(location) if is_local(location) => segment_path(Path::new(location), segment),
+        Some(_) => cached_path(dir, segment),
+    }
+}
+
+/// The live segments of the store in `dir`, with the file each is read from: those its manifest
+/// lists, or every segment file if it has none.
+pub(super) fn live_segments(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let (segments, cold) = match read_manifest(dir)? {
+        Some(manifest) => (manifest.segments, manifest.cold),
+        None => (segment_ids(dir)?, BTreeMap::new()),
+    };
+    Ok(segments
+        .into_iter()
+        .map(|segment| (segment, segment_file(dir, &cold, segment)))
+        .collect())
+}
+
+/// Delete the segments in `dir` that `manifest` doesn't list there, along with the hints of
+/// those it doesn't list at all, the copies kept of segments no longer in other storage, and the
+/// temporary files of the store. So are the segments and temporary files in `storages` that it
+/// doesn't list there. The store must be locked.
+pub(super) fn remove_stale_files(
+    dir: &Path,
+    manifest: &Manifest,
+    storages: &Storages,
+) -> Result<()> {
+    for segment in segment_ids(dir)? {
+        if let Some(location) = manifest.cold.get(&segment) {
+            warn!(
+                "Removing segment {}, which was moved to {}",
+                segment, location
+            );
+            fs::remove_file(segment_path(dir, segment))?;
+        } else if manifest.segments.binary_search(&segment).is_err() {
+            warn!(
+                "Removing segment {}, which isn't part of the store",
+                segment
+            );
+            fs::remove_file(segment_path(dir, segment))?;
+            remove_hint(dir, segment)?;
+        }
+    }
+    for segment in cached_segments(dir)? {
+        if manifest
+            .cold
+            .get(&segment)
+            .is_none_or(|location| is_local(location))
+        {
+            warn!(
+                "Removing the copy of segment {}, which isn't needed",
+                segment
+            );
+            fs::remove_file(cached_path(dir, segment))?;
+        }
+    }
+    remove_temp_files(dir)?;
+    for (location, storage) in storages {
+        for name in storage.list()? {
+            let listed = name
+                .strip_suffix(".log")
+                .and_then(|id| id.parse().ok())
+                .is_some_and(|segment: u64| {
+                    manifest.cold.get(&segment) == Some(location) && name == object_name(segment)
+                });
+            if !listed && (name.ends_with(".log") || name.starts_with(TEMP_PREFIX)) {
+                warn!(
+                    "Removing {} from {}, which isn't part of the store",
+                    name, location
+                );
+                storage.delete(&name)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Delete the temporary files of the store in `dir`.
+fn remove_temp_files(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let is_temp = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(TEMP_PREFIX));
+        if is_temp && entry.file_type()?.is_file() {
+            warn!("Removing temporary file {}", entry.path().display());
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Move the temporary file `output`, written in the directory of the store, to `path`, replacing
+/// the file there.
+///
+/// The temporary file is closed before it is renamed, and the rename is the one of `std`, which
+/// replaces a file that is still open on Windows too, as long as it was opened with the default
+/// sharing. A rename refused there, e.g. because a virus scanner briefly has one of the files
+/// open, is retried a few times. Both files being in the same directory, the rename never
+/// crosses file systems.
+pub(super) fn persist(output: NamedTempFile, path: &Path) -> Result<()> {
+    let temp = output.into_temp_path().keep().map_err(|e| e.error)?;
+    let mut retries = 0;
+    loop {
+        match fs::rename(&temp, path) {
+            Ok(()) => return Ok(()),
+            Err(err)
+                if cfg!(windows)
+                    && err.kind() == io::ErrorKind::PermissionDenied
+                    && retries < RENAME_RETRIES =>
+            {
+                retries += 1;
+                thread::sleep(RENAME_RETRY_DELAY);
+            }
+            Err(err) => {
+                let _ = fs::remove_file(&temp);
+                return Err(err.into());
+            }
+        }
+    }
+}
+
+// Make the files renamed into `dir` survive a crash.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+// Directories can't be opened on other systems, where the rename is left to the file system.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,90 @@
+//! Merge operators, which let a write add to the value of a key without reading it first.
+//!
+//! A store opened with `KvStoreOptions::merge_operator` takes `KvStore::merge`, which appends its
+//! operand to the log as a record of its own. Reading the key folds the operands written since
+//! its last set into the value it had then, oldest first, with the operator. Counters and lists
+//! that are appended to then cost a single append per write, and the folding is left to reads.
+//!
+//! The index entry of a key whose last record is an operand holds the records it is folded onto:
+//! the value the key was set to, if any, and the operands before it. They are all kept in the
+//! active segment: a merge into a key whose last record is in a sealed segment, or keeps its
+//! value out of the record, folds the value and writes it whole, as does a merge that would make
+//! the chain longer than `MAX_OPERANDS`. Compaction writes the folded value in place of the last
+//! operand of each key and leaves out the records it was folded from, so compacted segments, and
+//! their hints, hold no operands. A store that holds operands can't be opened without a merge
+//! operator.
+
+use std::fmt;
+use std::sync::Arc;
+
+use super::Offset;
+use crate::error::Result;
+
+// The operands a key may have before a merge folds them and writes the value whole.
+pub(super) const MAX_OPERANDS: usize = 32;
+
+// Given a key, its value, or `None` if it has none, and an operand, returns the new value.
+type MergeFn = dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync;
+
+/// A function that folds an operand into the value of a key.
+#[derive(Clone)]
+pub(super) struct MergeOperator(Arc<MergeFn>);
+
+impl fmt::Debug for MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MergeOperator(..)")
+    }
+}
+
+impl MergeOperator {
+    pub(super) fn new(
+        operator: impl Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        MergeOperator(Arc::new(operator))
+    }
+
+    pub(super) fn apply(&self, key: &[u8], value: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+        (self.0)(key, value, operand)
+    }
+}
+
+/// The records a merge operand is folded onto, oldest first.
+#[derive(Debug)]
+pub(super) struct MergeChain {
+    operator: MergeOperator,
+    // the value the key was set to, if any, and the operands since
+    pub(super) records: Vec<Offset>,
+}
+
+impl MergeChain {
+    /// The chain of an operand written after `prev`, the record `key` pointed to if any.
+    pub(super) fn after(operator: &MergeOperator, prev: Option<&Offset>) -> Arc<MergeChain> {
+        let mut records = Vec::new();
+        if let Some(prev) = prev {
+            if let Some(chain) = &prev.merged {
+                records.extend(chain.records.iter().cloned());
+            }
+            records.push(Offset {
+                merged: None,
+                ..prev.clone()
+            });
+        }
+        Arc::new(MergeChain {
+            operator: operator.clone(),
+            records,
+        })
+    }
+
+    /// Fold the records of the chain, and then `operand`, into the value of `key`.
+    pub(super) fn fold(&self, key: &[u8], operand: &[u8]) -> Result<Vec<u8>> {
+        let mut value = None;
+        for record in &self.records {
+            let pair = record.file.read_pair(record.start, record.len)?;
+            value = match (pair.merge, pair.value) {
+                (true, Some(operand)) => Some(self.operator.apply(key, value.as_deref(), &operand)),
+                (_, value) => value,
+            };
+        }
+        Ok(self.operator.apply(key, value.as_deref(), operand))
+    }
+}
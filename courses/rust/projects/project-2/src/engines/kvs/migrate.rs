@@ -0,0 +1,137 @@
+use std::fmt;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use log::info;
+
+use super::hint::remove_hint;
+use super::manifest::{live_segments, persist};
+use super::segment::{write_file_header, write_record, Format, SegmentFile, TornTail};
+use super::{lock_dir, KvStore, KvStoreOptions};
+use crate::error::KvsError::UnknownFormat;
+use crate::error::Result;
+
+/// A version of the on-disk format of the segments of a `KvStore`, named `json` for the format
+/// of the segments written before they had a header, and `v1`, `v2`, ... for the binary ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatVersion {
+    /// Records in JSON, without a file header
+    Json,
+    /// The binary format, with its version
+    Binary(u8),
+}
+
+impl FormatVersion {
+    /// The format new segments are written in.
+    pub fn current() -> FormatVersion {
+        Format::CURRENT.into()
+    }
+}
+
+impl From<Format> for FormatVersion {
+    fn from(format: Format) -> FormatVersion {
+        match format {
+            Format::Json => FormatVersion::Json,
+            Format::Binary(version) => FormatVersion::Binary(version),
+        }
+    }
+}
+
+impl FromStr for FormatVersion {
+    type Err = crate::KvsError;
+
+    fn from_str(s: &str) -> Result<FormatVersion> {
+        if s == "json" {
+            return Ok(FormatVersion::Json);
+        }
+        match s.strip_prefix('v').unwrap_or(s).parse() {
+            Ok(version) if version > 0 => Ok(FormatVersion::Binary(version)),
+            _ => Err(UnknownFormat(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for FormatVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatVersion::Json => write!(f, "json"),
+            FormatVersion::Binary(version) => write!(f, "v{}", version),
+        }
+    }
+}
+
+impl KvStore {
+    /// Rewrite every segment of the store in `dir` that isn't in the current format in it, and
+    /// return the ids of the segments rewritten. Only segments in the format `from` are
+    /// expected, if it is given.
+    ///
+    /// Use `KvStoreOptions::migrate` to migrate an encrypted store.
+    pub fn migrate(dir: impl AsRef<Path>, from: Option<FormatVersion>) -> Result<Vec<u64>> {
+        KvStore::options().migrate(dir, from)
+    }
+}
+
+impl KvStoreOptions {
+    /// Like `KvStore::migrate`, decrypting the records with the encryption key of these options,
+    /// and writing them encrypted with it, and compressed as these options say.
+    ///
+    /// Each segment is written to a temporary file that then replaces it, so a migration that is
+    /// interrupted leaves each segment either as it was or migrated, and can be run again. Fails
+    /// with `StoreLocked` if the store is open, with `ChecksumMismatch` if a segment is damaged,
+    /// which `repair` fixes, and without changing anything if a segment isn't in the format
+    /// `from`.
+    pub fn migrate(&self, dir: impl AsRef<Path>, from: Option<FormatVersion>) -> Result<Vec<u64>> {
+        let dir = dir.as_ref();
+        let _lock = lock_dir(dir)?;
+        let key = self.encryption_key.as_ref();
+        let mut outdated = Vec::new();
+        for (segment, path) in live_segments(dir)? {
+            let file = SegmentFile::open_path(path, false, key)?;
+            let format = FormatVersion::from(file.format);
+            let encrypted_as_asked = file.cipher.is_some() == key.is_some();
+            if format == FormatVersion::current() && encrypted_as_asked {
+                continue;
+            }
+            if let Some(from) = from {
+                if format != from && format != FormatVersion::current() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("segment {} is in format {}, not {}", segment, format, from),
+                    )
+                    .into());
+                }
+            }
+            outdated.push((segment, file));
+        }
+
+        for (segment, file) in &outdated {
+            // a segment moved to a cold directory is replaced there
+            let segment_dir = file.path.parent().unwrap_or(dir);
+            let mut output = tempfile::NamedTempFile::new_in(segment_dir)?;
+            let mut writer = BufWriter::new(output.as_file_mut());
+            let cipher = write_file_header(&mut writer, key)?;
+            let mut records = 0;
+            file.for_each_record(TornTail::Fail, |_, _, pair| {
+                let data = pair.encode(self.compression_threshold, cipher.as_ref());
+                write_record(&mut writer, &data)?;
+                records += 1;
+                Ok(())
+            })?;
+            writer.flush()?;
+            drop(writer);
+            output.as_file().sync_all()?;
+            // the offsets of the hint are those of the old records
+            remove_hint(dir, *segment)?;
+            persist(output, &file.path)?;
+            info!(
+                "Migrated segment {} from {} to {}, with {} records",
+                segment,
+                FormatVersion::from(file.format),
+                FormatVersion::current(),
+                records
+            );
+        }
+        Ok(outdated.into_iter().map(|(segment, _)| segment).collect())
+    }
+}
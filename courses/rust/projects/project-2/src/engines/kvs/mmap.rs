@@ -0,0 +1,179 @@
+//! Zero-copy reads through memory-mapped segments, with the `mmap` feature.
+//!
+//! `KvStore::get_ref` maps the segment that holds the value into memory, and returns a
+//! `ValueRef` that borrows the value from the mapping instead of reading it into a new buffer.
+//! Each segment is mapped once, by the first read that needs it, and the active segment again
+//! when a read goes past the end of its mapping. Values that aren't stored as they are, because
+//! they are compressed, encrypted, in a JSON segment, in a blob or in the value log, are read
+//! into a buffer as `get_bytes` does.
+//!
+//! A `ValueRef` keeps the mapping alive, so it stays valid after the key is changed, its segment
+//! is compacted, or the store is dropped.
+
+use std::fmt;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+use memmap2::Mmap;
+
+use super::segment::{
+    checked_data, decompress, Format, RawPair, RawValue, SegmentFile, HEADER_SIZE,
+};
+use super::{current, now_millis, KvStore, Metrics};
+use crate::error::KvsError::{self, InvalidRecord};
+use crate::error::Result;
+
+/// A value returned by `KvStore::get_ref`, which derefs to its bytes.
+#[derive(Clone)]
+pub struct ValueRef(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    // borrowed from a mapped segment
+    Mapped(Arc<Mmap>, Range<usize>),
+    // decoded into a buffer
+    Owned(Vec<u8>),
+}
+
+impl ValueRef {
+    /// Whether the value is borrowed from a mapped segment rather than copied out of it.
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.0, Repr::Mapped(..))
+    }
+
+    /// Copy the value into a `Vec`.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.deref().to_vec()
+    }
+
+    fn owned(value: Vec<u8>) -> ValueRef {
+        ValueRef(Repr::Owned(value))
+    }
+}
+
+impl Deref for ValueRef {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            Repr::Mapped(map, range) => &map[range.clone()],
+            Repr::Owned(value) => value,
+        }
+    }
+}
+
+impl AsRef<[u8]> for ValueRef {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for ValueRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValueRef")
+            .field("len", &self.len())
+            .field("mapped", &self.is_mapped())
+            .finish()
+    }
+}
+
+impl KvStore {
+    /// Retrieve the value of a key without copying it, borrowed from the segment that holds it.
+    /// See the `mmap` module.
+    ///
+    /// The read cache is bypassed: the page cache of the operating system serves the same
+    /// purpose for mapped segments.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(key_len = key.len()),
+        )
+    )]
+    pub fn get_ref(&self, key: &[u8]) -> Result<Option<ValueRef>> {
+        Metrics::add(&self.metrics.reads, 1);
+        self.sample_read(key);
+        let offset = match self.index.get(key) {
+            Some(entry) => current(&entry),
+            None => return Ok(None),
+        };
+        if offset.is_expired(now_millis()) {
+            return Ok(None);
+        }
+        self.used(key);
+        if offset.merged.is_some() {
+            let value = offset
+                .read_pair()
+                .map(|pair| pair.value.map(ValueRef::owned));
+            return self.check_corruption(key, &offset, value);
+        }
+        let value = offset.file.read_value_ref(offset.start, offset.len);
+        self.check_corruption(key, &offset, value)
+    }
+}
+
+impl SegmentFile {
+    /// Read the value of the record whose data is `len` bytes long and starts at `start`,
+    /// borrowing it from the mapped segment if it is stored as it is. Errors tell where the
+    /// record is.
+    fn read_value_ref(&self, start: u64, len: usize) -> Result<Option<ValueRef>> {
+        if self.format == Format::Json || self.cipher.is_some() {
+            return Ok(self.read_pair(start, len)?.value.map(ValueRef::owned));
+        }
+        let in_file = |err: KvsError| err.in_file(&self.path, Some(start));
+        let end = start as usize + len;
+        let map = self.map_through(end as u64).map_err(in_file)?;
+        let record = &map[(start - HEADER_SIZE) as usize..end];
+        let raw = RawPair::parse(checked_data(record).map_err(in_file)?).map_err(in_file)?;
+        let value = match raw.value {
+            RawValue::Missing => return Ok(None),
+            RawValue::Plain(value) => value.len(),
+            RawValue::Compressed(value) => {
+                let value = decompress(value).map_err(in_file)?;
+                return Ok(Some(ValueRef::owned(value)));
+            }
+            RawValue::Blob(blob) => {
+                let mut value = Vec::with_capacity(blob.len as usize);
+                self.read_blob(blob, &mut value)?;
+                return Ok(Some(ValueRef::owned(value)));
+            }
+            RawValue::Vlog(vlog) => {
+                let value = self.read_vlog_value(raw.key, vlog)?;
+                return Ok(Some(ValueRef::owned(value)));
+            }
+        };
+        // the value is the tail of the data
+        Ok(Some(ValueRef(Repr::Mapped(map, end - value..end))))
+    }
+
+    /// The mapping of the segment, which holds at least its first `end` bytes.
+    fn map_through(&self, end: u64) -> Result<Arc<Mmap>> {
+        if let Some(map) = &*self.map.read().expect("map lock poisoned") {
+            if map.len() as u64 >= end {
+                return Ok(Arc::clone(map));
+            }
+        }
+        let mut slot = self.map.write().expect("map lock poisoned");
+        if let Some(map) = &*slot {
+            if map.len() as u64 >= end {
+                return Ok(Arc::clone(map));
+            }
+        }
+        // SAFETY: segments are only ever appended to while the store is open, and the lock file
+        // keeps other processes from writing to them, so the mapped bytes never change. Records
+        // are in the file before the index points to them.
+        let map = Arc::new(unsafe { Mmap::map(&self.file)? });
+        if (map.len() as u64) < end {
+            return Err(InvalidRecord);
+        }
+        *slot = Some(Arc::clone(&map));
+        Ok(map)
+    }
+
+    /// Drop the mapping of the segment, before its file is replaced. Values borrowed from it keep
+    /// it alive, and later reads map the file again.
+    pub(super) fn unmap(&self) {
+        *self.map.write().expect("map lock poisoned") = None;
+    }
+}
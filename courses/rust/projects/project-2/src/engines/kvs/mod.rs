@@ -0,0 +1,3116 @@
+use std::cmp::Reverse;
+use std::collections::{btree_map, BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Write};
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crossbeam_skiplist::map;
+use log::{debug, error, warn};
+
+pub use self::admin::{CorruptRecord, RecordInfo, SegmentCheck, SegmentInfo};
+pub use self::databases::Databases;
+pub use self::dump::DumpFormat;
+pub use self::eviction::EvictionPolicy;
+pub use self::migrate::FormatVersion;
+#[cfg(feature = "mmap")]
+pub use self::mmap::ValueRef;
+pub use self::namespace::Namespace;
+pub use self::options::{Backpressure, KvStoreOptions, SyncPolicy};
+pub use self::policy::{
+    CompactionPolicy, DeadRatio, ManualOnly, SegmentUsage, SizeTiered, StaleBytes, TimeBased,
+    TotalStaleRatio,
+};
+pub use self::progress::OpenProgress;
+use self::progress::REPORT_INTERVAL;
+pub use self::replay::{Operation, Replay};
+#[cfg(feature = "s3")]
+pub use self::s3::S3Storage;
+use self::segment::{
+    file_header_size, segment_path, write_file_header, write_frame, write_record, Format, KvPair,
+    SegmentFile, TornTail, BATCH_FLAG, HEADER_SIZE,
+};
+use self::storage::{cached_path, fetch_segment, is_local, object_name, Storages};
+pub use self::storage::{LocalStorage, SegmentStorage};
+pub use self::transaction::Transaction;
+pub use self::watch::{ChangeEvent, ChangeOp};
+use super::{
+    add_to_value, check_dir, claim_dir, Engine, KvsEngine, Latencies, LatencyRecorder, Stats,
+};
+use crate::error::ErrorCode;
+use crate::error::KvsError::{
+    self, DiskFull, IndexFull, KeyNotFound, NoMergeOperator, ReadOnly, RecordTooLarge, StoreLocked,
+};
+use crate::error::Result;
+
+use self::blob::{blob_path, next_blob_id};
+use self::cache::ReadCache;
+use self::eviction::Recency;
+use self::expiry::{run_expirer, Expirer, ExpiryMessage};
+use self::group::{Committer, GroupCommit};
+use self::hint::{read_hint, remove_hint, write_hint, HintEntry};
+use self::history::History;
+pub use self::hotkeys::HotKey;
+use self::hotkeys::KeySampler;
+use self::index::{Entry, Index, IndexKey, IndexRange};
+use self::manifest::{
+    persist, read_manifest, remove_stale_files, segment_file, write_manifest, DroppedNamespace,
+    Manifest, LAYOUT_VERSION,
+};
+use self::merge::{MergeChain, MAX_OPERANDS};
+use self::namespace::NAMESPACE_MARKER;
+use self::pin::{Pin, Pins};
+use self::purge::compact_purged;
+use self::replication::{ReplicationEntry, ReplicationLog};
+use self::vlog::ValueLog;
+use self::watch::Watchers;
+
+mod admin;
+mod backup;
+mod blob;
+mod bloom;
+mod bulk;
+mod cache;
+mod crypto;
+mod databases;
+mod dump;
+mod eviction;
+mod expiry;
+#[cfg(feature = "fuzzing")]
+pub(crate) mod fuzzing;
+mod group;
+mod hint;
+mod history;
+mod hotkeys;
+mod index;
+mod manifest;
+mod merge;
+mod migrate;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod namespace;
+mod options;
+mod pin;
+mod policy;
+mod progress;
+mod purge;
+mod replay;
+pub(crate) mod replication;
+#[cfg(feature = "s3")]
+mod s3;
+mod segment;
+mod slowlog;
+mod storage;
+mod transaction;
+mod vlog;
+mod watch;
+
+// Record a field of the current span, when built with the `tracing` feature.
+macro_rules! record {
+    ($field:literal, $value:expr) => {
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record($field, $value);
+    };
+}
+
+// Locked by the process that writes to the store.
+const LOCK_FILE: &str = "LOCK";
+
+// Memory an entry of the index takes up besides the bytes of a key too long to be held inline,
+// roughly: the key, the offset and its lock, and the node of the skip list with its links.
+const INDEX_ENTRY_OVERHEAD: usize =
+    mem::size_of::<IndexKey>() + mem::size_of::<RwLock<Offset>>() + 64;
+
+#[derive(Debug, Clone)]
+struct Offset {
+    // The segment that holds the data.
+    segment: u64,
+    // The segment file. Compaction replaces the file of a segment, so every offset keeps the
+    // file it points into open.
+    file: Arc<SegmentFile>,
+    // The offset where "key value" data starts.
+    start: u64,
+    // Length of the data in bytes.
+    len: usize,
+    // When the key expires, copied from the record so that expired keys are skipped without
+    // reading them.
+    expires_at: Option<u64>,
+    // The records the value is folded onto, if the record is a merge operand.
+    merged: Option<Arc<MergeChain>>,
+}
+
+impl Offset {
+    // Number of bytes the record occupies on disk, including its header.
+    fn record_len(&self) -> u64 {
+        HEADER_SIZE + self.len as u64
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    // Whether this is the record that starts at `start` in `file`.
+    fn points_to(&self, file: &Arc<SegmentFile>, start: u64) -> bool {
+        Arc::ptr_eq(&self.file, file) && self.start == start
+    }
+
+    // Read the record, with the value of a merge operand folded onto the records before it.
+    fn read_pair(&self) -> Result<KvPair> {
+        let mut pair = self.file.read_pair(self.start, self.len)?;
+        if let Some(chain) = &self.merged {
+            let operand = pair.value.take().unwrap_or_default();
+            pair.value = Some(chain.fold(&pair.key, &operand)?);
+            pair.merge = false;
+        }
+        Ok(pair)
+    }
+}
+
+/// A database that stores key-value pairs.
+///
+/// The log is split into segments named `<id>.log`. New records are appended to the segment
+/// with the highest id, and older segments are compacted one at a time. The file `MANIFEST`
+/// lists the segments, and files it doesn't list are removed when the store is opened.
+///
+/// `KvStore` is cheap to clone and can be shared between threads. Reads go through a lock-free
+/// index and positional reads on the segment files, so they never wait for each other or for
+/// writers. Writes are serialized, and compaction runs on a background thread.
+#[derive(Debug, Clone)]
+pub struct KvStore {
+    // Stops the compaction thread once the last clone is dropped. It is declared first so that
+    // it is dropped while the writer, which a running compaction needs, is still alive.
+    _compactor: Arc<Compactor>,
+    // stops the expiry thread, which needs the writer too
+    _expirer: Arc<Expirer>,
+    // syncs the writes in groups, with `SyncPolicy::Group`
+    committer: Option<Arc<Committer>>,
+    // maps keys to their offsets in the segments, ordered by key
+    index: Arc<Index>,
+    // the older versions of the keys
+    history: Arc<History>,
+    // recently read values
+    cache: Arc<ReadCache>,
+    // the order keys are evicted in, with capacity limits
+    recency: Option<Arc<Recency>>,
+    // the sampled reads and writes of the keys, with `KvStoreOptions::key_sampling`
+    sampler: Option<Arc<KeySampler>>,
+    metrics: Arc<Metrics>,
+    // the generations pinned by scans, snapshots and replays
+    pins: Arc<Pins>,
+    writer: Arc<Mutex<KvStoreWriter>>,
+    // notified with the writer lock each time compaction makes progress, for held back writes
+    compacted: Arc<Condvar>,
+    // `KvStoreOptions::slow_log_threshold`
+    slow_log: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct KvStoreWriter {
+    // the directory that holds the segments
+    dir: PathBuf,
+    // the lock file that keeps other processes from writing to the directory
+    _lock: Option<File>,
+    index: Arc<Index>,
+    history: Arc<History>,
+    cache: Arc<ReadCache>,
+    // id of the segment that new records are appended to
+    active_segment: u64,
+    // the active segment, opened for appending and reading
+    active_file: Arc<SegmentFile>,
+    // size of the active segment in bytes
+    active_size: u64,
+    // all the segments, including the active one
+    segments: BTreeMap<u64, Arc<SegmentFile>>,
+    // maps segment ids to the number of bytes in them that compaction could reclaim
+    stale_bytes: HashMap<u64, u64>,
+    // estimated memory taken up by the index
+    index_bytes: u64,
+    options: KvStoreOptions,
+    // whether the compaction thread has been asked to compact and hasn't finished yet
+    compaction_pending: bool,
+    compactor: Sender<CompactorMessage>,
+    // the keys that expire and when, the earliest first, for the expiry thread
+    expiring: BinaryHeap<Reverse<(u64, Vec<u8>)>>,
+    expirer: Sender<ExpiryMessage>,
+    watchers: Watchers,
+    replication: ReplicationLog,
+    metrics: Arc<Metrics>,
+    // the writes waiting to be synced, with `SyncPolicy::Group`
+    group: Option<Arc<GroupCommit>>,
+    // the last ticket taken since the writer lock was last taken, for the caller to wait for
+    ticket: Option<u64>,
+    // the compaction generation recorded in the manifest
+    generation: u64,
+    // the last sequence number given to a write
+    sequence: u64,
+    // the namespaces dropped whose records may still be in the segments
+    dropped: Vec<DroppedNamespace>,
+    // the keys being purged, with the last segment whose records of them compaction leaves out
+    purging: Vec<(Vec<u8>, u64)>,
+    recency: Option<Arc<Recency>>,
+    sampler: Option<Arc<KeySampler>>,
+    // the id of the next blob file
+    next_blob: u64,
+    value_log: ValueLog,
+    pins: Arc<Pins>,
+    // the segments moved to other storage, with the location of that storage
+    cold: BTreeMap<u64, String>,
+    // the storages segments were moved to, and the one compaction moves them to
+    storages: Storages,
+    cold_storage: Option<Arc<dyn SegmentStorage>>,
+    compacted: Arc<Condvar>,
+}
+
+// The counters and latencies reported by `KvStore::stats`.
+#[derive(Debug, Default)]
+struct Metrics {
+    compactions: AtomicU64,
+    reads: AtomicU64,
+    writes: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    evictions: AtomicU64,
+    get: LatencyRecorder,
+    set: LatencyRecorder,
+    remove: LatencyRecorder,
+    compaction: LatencyRecorder,
+}
+
+impl Metrics {
+    fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+enum CompactorMessage {
+    Compact,
+    // Compact every sealed segment with stale data, and send back the result.
+    CompactAll(Sender<Result<()>>),
+    // Compact the segments holding records of a key being purged, and send back the result.
+    Purge(Vec<u8>, Sender<Result<()>>),
+    // Compact the segments the compaction policy picks, and send back how many there were.
+    CompactNow(Sender<Result<usize>>),
+    Shutdown,
+}
+
+#[derive(Debug)]
+struct Compactor {
+    tx: Sender<CompactorMessage>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // the thread may already be gone if it panicked
+        let _ = self.tx.send(CompactorMessage::Shutdown);
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("The compaction thread panicked");
+            }
+        }
+    }
+}
+
+impl KvStore {
+    /// Open a directory and return a KvStore object.
+    /// If the database already exists, we expect to find one or more "<id>.log" segments, listed
+    /// in its manifest if it has one.
+    ///
+    /// Fails with `WrongEngine` if the directory holds the store of another engine, and with
+    /// `StoreLocked` if the store is already open, in this process too. `Databases` hands out the
+    /// store already open instead.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::options().open(path)
+    }
+
+    /// Open a store whose records are encrypted with `key`. See
+    /// `KvStoreOptions::encryption_key`.
+    pub fn open_encrypted(path: impl Into<PathBuf>, key: [u8; 32]) -> Result<KvStore> {
+        KvStore::options().encryption_key(key).open(path)
+    }
+
+    /// Options to open a store with, starting from the defaults.
+    pub fn options() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "info",
+            skip_all,
+            fields(
+                dir = %dir.display(),
+                segments = tracing::field::Empty,
+                keys = tracing::field::Empty,
+            ),
+        )
+    )]
+    fn open_with(dir: PathBuf, options: KvStoreOptions) -> Result<KvStore> {
+        // Readers don't need the lock, since segments are only ever appended to or replaced.
+        let lock = if options.read_only {
+            check_dir(&dir, Engine::Kvs)?;
+            None
+        } else {
+            let lock = lock_dir(&dir)?;
+            claim_dir(&dir, Engine::Kvs)?;
+            Some(lock)
+        };
+        let cold_storage: Option<Arc<dyn SegmentStorage>> = match &options.cold_storage {
+            Some(storage) => Some(Arc::clone(storage)),
+            None => match &options.cold_dir {
+                Some(cold_dir) => Some(Arc::new(LocalStorage::new(cold_dir)?)),
+                None => None,
+            },
+        };
+        if let Some(storage) = &cold_storage {
+            let location = storage.location();
+            if is_local(&location) && Path::new(&location) == fs::canonicalize(&dir)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the cold directory is the directory of the store",
+                )
+                .into());
+            }
+        }
+        // A read-only store leaves the files the manifest doesn't list for the next writer to
+        // remove, and reads the segments it lists.
+        let manifest = read_manifest(&dir)?;
+        let (segments, generation, mut sequence, dropped, cold) = match &manifest {
+            Some(manifest) => (
+                manifest.segments.clone(),
+                manifest.generation,
+                manifest.sequence,
+                manifest.dropped.clone(),
+                manifest.cold.clone(),
+            ),
+            None => (segment_ids(&dir)?, 0, 0, Vec::new(), BTreeMap::new()),
+        };
+        let mut storages = Storages::new();
+        if let Some(storage) = &cold_storage {
+            storages.insert(storage.location(), Arc::clone(storage));
+        }
+        for location in cold.values() {
+            if !storages.contains_key(location) {
+                if !is_local(location) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("segments were moved to {}, which isn't given", location),
+                    )
+                    .into());
+                }
+                storages.insert(location.clone(), Arc::new(LocalStorage::at(location)));
+            }
+        }
+        if !options.read_only {
+            remove_stale_files(
+                &dir,
+                &Manifest {
+                    layout: LAYOUT_VERSION,
+                    generation,
+                    sequence,
+                    segments: segments.clone(),
+                    dropped: dropped.clone(),
+                    cold: cold.clone(),
+                },
+                &storages,
+            )?;
+        }
+        // the records of the namespaces dropped are stale, and aren't indexed
+        let is_dropped =
+            |segment: u64, key: &[u8]| dropped.iter().any(|dropped| dropped.covers(segment, key));
+        if options.read_only && segments.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no store found in {}", dir.display()),
+            )
+            .into());
+        }
+        let index = Arc::new(Index::default());
+        let history = Arc::new(History::new(options.versions));
+        let mut files = BTreeMap::new();
+        let mut stale_bytes = HashMap::new();
+        let mut index_bytes = 0;
+        let mut active_size = 0;
+        let mut progress = OpenProgress {
+            segments_loaded: 0,
+            segments: segments.len(),
+            bytes_loaded: 0,
+            bytes: segments
+                .iter()
+                .map(|&segment| {
+                    fs::metadata(segment_file(&dir, &cold, segment)).map_or(0, |m| m.len())
+                })
+                .sum(),
+            keys: 0,
+        };
+        let report = |progress: &mut OpenProgress, keys: usize| {
+            progress.keys = keys as u64;
+            if let Some(callback) = &options.on_open_progress {
+                callback.report(progress);
+            }
+        };
+
+        for &segment in &segments {
+            // Only the last segment can end with a record torn by a crash. A read-only store
+            // skips it, leaving it for the next writer to cut off.
+            let is_last = segments.last() == Some(&segment);
+            let is_cold = cold.contains_key(&segment);
+            let writable = is_last && !is_cold && !options.read_only;
+            let torn_tail = match (is_last, options.read_only) {
+                (false, _) => TornTail::Fail,
+                (true, false) => TornTail::Truncate,
+                (true, true) => TornTail::Ignore,
+            };
+            let path = segment_file(&dir, &cold, segment);
+            let in_segment = |err: KvsError| err.in_file(&path, None);
+            if let Some(location) = cold.get(&segment) {
+                if !is_local(location) && !path.exists() {
+                    debug!("Fetching segment {} from {}", segment, location);
+                    fetch_segment(&dir, storages[location].as_ref(), segment)
+                        .map_err(in_segment)?;
+                }
+            }
+            let key = options.encryption_key.as_ref();
+            let file = if is_cold {
+                SegmentFile::open_cold(path.clone(), &dir, key)
+            } else {
+                SegmentFile::open(&dir, segment, writable, key)
+            };
+            let file = file.map_err(in_segment)?;
+            let file = Arc::new(file.with_max_record_size(options.max_record_size));
+            files.insert(segment, Arc::clone(&file));
+            let hint = if is_last {
+                None
+            } else {
+                read_hint(&dir, segment, file.file.metadata()?.len())
+            };
+            let loaded_before = progress.bytes_loaded;
+            if let Some(entries) = hint {
+                debug!("Loading segment {} from its hint", segment);
+                active_size = file.file.metadata()?.len();
+                for entry in entries {
+                    let offset = Offset {
+                        segment,
+                        file: Arc::clone(&file),
+                        start: entry.start,
+                        len: entry.len,
+                        expires_at: entry.expires_at,
+                        merged: None,
+                    };
+                    if is_dropped(segment, &entry.key) {
+                        *stale_bytes.entry(segment).or_default() += offset.record_len();
+                        continue;
+                    }
+                    if entry.prefix {
+                        remove_range(
+                            &index,
+                            &history,
+                            &mut stale_bytes,
+                            &mut index_bytes,
+                            &entry.key,
+                            offset,
+                            |_, _| {},
+                        );
+                        continue;
+                    }
+                    update_index(
+                        &index,
+                        &history,
+                        &mut stale_bytes,
+                        &mut index_bytes,
+                        entry.key,
+                        entry.has_value,
+                        offset,
+                    );
+                }
+                progress.segments_loaded += 1;
+                progress.bytes_loaded = loaded_before + active_size;
+                report(&mut progress, index.len());
+                continue;
+            }
+            let mut next_report = REPORT_INTERVAL;
+            active_size = file
+                .for_each_record(torn_tail, |start, len, pair| {
+                    let offset = Offset {
+                        segment,
+                        file: Arc::clone(&file),
+                        start,
+                        len,
+                        expires_at: pair.expires_at,
+                        merged: None,
+                    };
+                    // the segments loaded from their hints are older than the manifest
+                    sequence = sequence.max(pair.seq.unwrap_or(0));
+                    if is_dropped(segment, &pair.key) {
+                        *stale_bytes.entry(segment).or_default() += offset.record_len();
+                        return Ok(());
+                    }
+                    if pair.prefix {
+                        remove_range(
+                            &index,
+                            &history,
+                            &mut stale_bytes,
+                            &mut index_bytes,
+                            &pair.key,
+                            offset,
+                            |_, _| {},
+                        );
+                    } else {
+                        let mut offset = offset;
+                        if pair.merge {
+                            let operator =
+                                options.merge_operator.as_ref().ok_or(NoMergeOperator)?;
+                            let prev = index.get(&pair.key).map(|entry| current(&entry));
+                            offset.merged = Some(MergeChain::after(operator, prev.as_ref()));
+                        }
+                        let has_value = pair.value.is_some();
+                        update_index(
+                            &index,
+                            &history,
+                            &mut stale_bytes,
+                            &mut index_bytes,
+                            pair.key,
+                            has_value,
+                            offset,
+                        );
+                    }
+                    if start + len as u64 >= next_report {
+                        next_report += REPORT_INTERVAL;
+                        progress.bytes_loaded = loaded_before + start + len as u64;
+                        report(&mut progress, index.len());
+                    }
+                    Ok(())
+                })
+                .map_err(in_segment)?;
+            progress.segments_loaded += 1;
+            progress.bytes_loaded = loaded_before + file.file.metadata()?.len();
+            report(&mut progress, index.len());
+        }
+
+        // Records are only appended in the current format and encrypted only if the store is, so
+        // a full segment or one in another format is left for compaction to rewrite.
+        let mut active_segment = segments.last().cloned().unwrap_or(1);
+        let (active_file, active_size) = match files.get(&active_segment) {
+            Some(file)
+                if options.read_only
+                    || (active_size < options.segment_size
+                        && !cold.contains_key(&active_segment)
+                        && file.format == Format::CURRENT
+                        && file.cipher.is_some() == options.encryption_key.is_some()) =>
+            {
+                (Arc::clone(file), active_size)
+            }
+            Some(_) => {
+                active_segment += 1;
+                let file = Arc::new(
+                    SegmentFile::open(&dir, active_segment, true, options.encryption_key.as_ref())?
+                        .with_max_record_size(options.max_record_size),
+                );
+                files.insert(active_segment, Arc::clone(&file));
+                let size = file.data_start();
+                (file, size)
+            }
+            None => {
+                let file = Arc::new(
+                    SegmentFile::open(&dir, active_segment, true, options.encryption_key.as_ref())?
+                        .with_max_record_size(options.max_record_size),
+                );
+                files.insert(active_segment, Arc::clone(&file));
+                let size = file.data_start();
+                (file, size)
+            }
+        };
+
+        // the keys that expired while the store was closed are expired once it opens
+        let expiring = if options.read_only {
+            BinaryHeap::new()
+        } else {
+            index
+                .iter()
+                .filter_map(|entry| {
+                    current(&entry)
+                        .expires_at
+                        .map(|expires_at| Reverse((expires_at, entry.key().to_vec())))
+                })
+                .collect()
+        };
+        // the keys are ordered by where their last write is in the log
+        let recency = if options.read_only
+            || (options.max_keys.is_none() && options.max_live_bytes.is_none())
+        {
+            None
+        } else {
+            let recency = Recency::new(options.eviction_policy);
+            let mut offsets: Vec<(Vec<u8>, Offset)> = index
+                .iter()
+                .map(|entry| (entry.key().to_vec(), current(&entry)))
+                .collect();
+            offsets.sort_unstable_by_key(|(_, offset)| (offset.segment, offset.start));
+            for (key, offset) in offsets {
+                recency.written(&key, Some(offset.record_len()));
+            }
+            Some(Arc::new(recency))
+        };
+        let current = Manifest {
+            layout: LAYOUT_VERSION,
+            generation,
+            sequence,
+            segments: files.keys().copied().collect(),
+            dropped: live_drops(&files, dropped),
+            cold: cold.clone(),
+        };
+        if !options.read_only && manifest.as_ref() != Some(&current) {
+            write_manifest(&dir, &current)?;
+        }
+
+        record!("segments", files.len() as u64);
+        record!("keys", index.len() as u64);
+
+        let cache = Arc::new(ReadCache::new(options.cache_capacity));
+        let sampler = options
+            .key_sampling
+            .map(|every| Arc::new(KeySampler::new(every)));
+        let (tx, rx) = mpsc::channel();
+        let (expiry_tx, expiry_rx) = mpsc::channel();
+        let metrics = Arc::new(Metrics::default());
+        let replication = ReplicationLog::new(options.replication_backlog);
+        let group = match options.sync_policy {
+            SyncPolicy::Group => Some(Arc::new(GroupCommit::new())),
+            _ => None,
+        };
+        let next_blob = next_blob_id(&dir)?;
+        let value_log = ValueLog::open(&dir)?;
+        let pins = Arc::new(Pins::default());
+        let compacted = Arc::new(Condvar::new());
+        let slow_log = options.slow_log_threshold;
+        let writer = Arc::new(Mutex::new(KvStoreWriter {
+            dir,
+            _lock: lock,
+            index: Arc::clone(&index),
+            history: Arc::clone(&history),
+            cache: Arc::clone(&cache),
+            active_segment,
+            active_file,
+            active_size,
+            segments: files,
+            stale_bytes,
+            index_bytes,
+            options,
+            compaction_pending: false,
+            compactor: tx.clone(),
+            expiring,
+            expirer: expiry_tx.clone(),
+            watchers: Watchers::default(),
+            replication,
+            metrics: Arc::clone(&metrics),
+            group: group.clone(),
+            ticket: None,
+            generation,
+            sequence,
+            dropped: current.dropped,
+            purging: Vec::new(),
+            recency: recency.clone(),
+            sampler: sampler.clone(),
+            next_blob,
+            value_log,
+            pins: Arc::clone(&pins),
+            cold,
+            storages,
+            cold_storage,
+            compacted: Arc::clone(&compacted),
+        }));
+        let committer = match group {
+            Some(group) => Some(Arc::new(Committer::start(group, Arc::downgrade(&writer))?)),
+            None => None,
+        };
+        let weak_writer = Arc::downgrade(&writer);
+        let handle = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || run_compactor(weak_writer, rx))?;
+        let weak_writer = Arc::downgrade(&writer);
+        let expiry_handle = thread::Builder::new()
+            .name("kvs-expiry".to_owned())
+            .spawn(move || run_expirer(weak_writer, expiry_rx))?;
+
+        let store = KvStore {
+            _compactor: Arc::new(Compactor {
+                tx,
+                handle: Some(handle),
+            }),
+            _expirer: Arc::new(Expirer {
+                tx: expiry_tx,
+                handle: Some(expiry_handle),
+            }),
+            committer,
+            index,
+            history,
+            cache,
+            recency,
+            sampler,
+            metrics,
+            pins,
+            writer,
+            compacted,
+            slow_log,
+        };
+        // the limits may have been lowered since the store was last open
+        store.write_unthrottled(|writer| writer.evict())?;
+        Ok(store)
+    }
+
+    /// Set a key and append it to the end of the file.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    /// Set a key to a value, both of which can be arbitrary bytes.
+    pub fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.set_bytes_numbered(key, value).map(|_| ())
+    }
+
+    /// Like `set_bytes`, returning the sequence number of the write.
+    fn set_bytes_numbered(&self, key: &[u8], value: &[u8]) -> Result<u64> {
+        let started = Instant::now();
+        slowlog::start();
+        let result = self.write(|writer| {
+            writer.append(KvPair {
+                key: key.to_vec(),
+                value: Some(value.to_vec()),
+                expires_at: None,
+                modified_at: None,
+                blob: None,
+                vlog: None,
+                seq: None,
+                prefix: false,
+                merge: false,
+            })?;
+            Ok(writer.sequence)
+        });
+        let elapsed = started.elapsed();
+        self.metrics.set.record(elapsed);
+        slowlog::log_if_slow(self.slow_log, "set", key, Some(value.len()), elapsed);
+        result
+    }
+
+    /// Set a key that expires after `ttl`. Once expired, the key is treated as missing, and a
+    /// background thread removes it and reports it to the watchers of the key.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        // a TTL too long to tell apart from forever expires at the end of time
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let expires_at = now_millis().saturating_add(ttl);
+        self.write(|writer| {
+            writer.append(KvPair {
+                key: key.into_bytes(),
+                value: Some(value.into_bytes()),
+                expires_at: Some(expires_at),
+                modified_at: None,
+                blob: None,
+                vlog: None,
+                seq: None,
+                prefix: false,
+                merge: false,
+            })
+        })
+    }
+
+    /// Retrieve the value of a key
+    ///
+    /// Returns `KvsError::Utf8Error` if the value was set with `set_bytes` and isn't valid UTF-8.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Retrieve the value of a key as bytes.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(
+                key_len = key.len(),
+                bytes = tracing::field::Empty,
+                cache_hit = tracing::field::Empty,
+            ),
+        )
+    )]
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let started = Instant::now();
+        slowlog::start();
+        let value = self.lookup(key);
+        let elapsed = started.elapsed();
+        self.metrics.get.record(elapsed);
+        let bytes = value
+            .as_ref()
+            .ok()
+            .and_then(|value| value.as_ref().map(Vec::len));
+        slowlog::log_if_slow(self.slow_log, "get", key, bytes, elapsed);
+        value
+    }
+
+    fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Metrics::add(&self.metrics.reads, 1);
+        self.sample_read(key);
+        match self.index.get(key) {
+            Some(entry) => {
+                let offset = current(&entry);
+                if offset.is_expired(now_millis()) {
+                    return Ok(None);
+                }
+                self.used(key);
+                if let Some(value) = self.cache.get(key, &offset) {
+                    Metrics::add(&self.metrics.cache_hits, 1);
+                    record!("cache_hit", true);
+                    record!("bytes", value.len() as u64);
+                    return Ok(Some(value));
+                }
+                Metrics::add(&self.metrics.cache_misses, 1);
+                record!("cache_hit", false);
+                record!("bytes", offset.len as u64);
+                let pair = self.check_corruption(key, &offset, offset.read_pair())?;
+                if let Some(value) = &pair.value {
+                    self.cache.insert(key, &offset, value);
+                }
+                Ok(pair.value)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Whether a key exists, which the index tells without reading its value.
+    pub fn contains_key(&self, key: impl AsRef<[u8]>) -> bool {
+        contains_live(&self.index, key.as_ref())
+    }
+
+    /// The number of keys in the store, those of its namespaces included, which the index tells
+    /// without reading them. Keys that expired are counted until the background thread that
+    /// expires them removes them, shortly after.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Whether the store has no keys. See `len`.
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Retrieve the value of a key along with when it was written and the size of its record.
+    ///
+    /// The value is always read from disk, since the cache doesn't keep the record.
+    pub fn get_with_meta(&self, key: String) -> Result<Option<(String, Metadata)>> {
+        Metrics::add(&self.metrics.reads, 1);
+        self.sample_read(key.as_bytes());
+        let offset = match live_offset(&self.index, key.as_bytes()) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        Metrics::add(&self.metrics.cache_misses, 1);
+        let pair = self.check_corruption(key.as_bytes(), &offset, offset.read_pair())?;
+        let metadata = Metadata {
+            modified: pair.modified_at.map(from_millis),
+            expires: offset.expires_at.map(from_millis),
+            size: offset.record_len(),
+        };
+        match pair.value {
+            Some(value) => Ok(Some((String::from_utf8(value)?, metadata))),
+            None => Ok(None),
+        }
+    }
+
+    /// Retrieve the value `key` was set to `n` versions ago: its current value for 0, the value
+    /// before for 1, and so on.
+    ///
+    /// Returns `None` if the key had fewer versions, or if that version is no longer kept: only
+    /// the number of versions set by `KvStoreOptions::versions` are. A removed key has no current
+    /// version, but keeps its older ones.
+    pub fn get_version(&self, key: String, n: usize) -> Result<Option<String>> {
+        if n == 0 {
+            return self.get(key);
+        }
+        Metrics::add(&self.metrics.reads, 1);
+        self.sample_read(key.as_bytes());
+        match self.history.get(key.as_bytes()).get(n - 1) {
+            Some(offset) => read_value(offset),
+            None => Ok(None),
+        }
+    }
+
+    /// Retrieve the kept versions of `key`, newest first, starting with its current value if it
+    /// exists. See `get_version`.
+    pub fn history(&self, key: String) -> Result<Vec<String>> {
+        let mut values: Vec<String> = self.get(key.clone())?.into_iter().collect();
+        for offset in self.history.get(key.as_bytes()) {
+            values.extend(read_value(&offset)?);
+        }
+        Ok(values)
+    }
+
+    /// Retrieve the values of several keys at once, in the same order as `keys`.
+    ///
+    /// The values that aren't cached are read in the order they are laid out on disk, one
+    /// segment after another, rather than in the order of `keys`.
+    pub fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        Metrics::add(&self.metrics.reads, keys.len() as u64);
+        let now = now_millis();
+        let mut values = vec![None; keys.len()];
+        let mut reads = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            self.sample_read(key.as_bytes());
+            let offset = match self.index.get(key.as_bytes()) {
+                Some(entry) => current(&entry),
+                None => continue,
+            };
+            if offset.is_expired(now) {
+                continue;
+            }
+            self.used(key.as_bytes());
+            match self.cache.get(key.as_bytes(), &offset) {
+                Some(value) => {
+                    Metrics::add(&self.metrics.cache_hits, 1);
+                    values[i] = Some(value);
+                }
+                None => reads.push((i, offset)),
+            }
+        }
+
+        Metrics::add(&self.metrics.cache_misses, reads.len() as u64);
+        reads.sort_unstable_by_key(|(_, offset)| (offset.segment, offset.start));
+        for (i, offset) in reads {
+            let pair = self.check_corruption(keys[i].as_bytes(), &offset, offset.read_pair())?;
+            if let Some(value) = &pair.value {
+                self.cache.insert(keys[i].as_bytes(), &offset, value);
+            }
+            values[i] = pair.value;
+        }
+
+        values
+            .into_iter()
+            .map(|value| match value {
+                Some(value) => Ok(Some(String::from_utf8(value)?)),
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    /// Iterate over the key-value pairs whose keys fall in `range`, in sorted key order.
+    ///
+    /// Values are read from disk lazily as the iterator advances.
+    pub fn scan<K, R>(&self, range: R) -> Scan<'_>
+    where
+        K: AsRef<str>,
+        R: RangeBounds<K>,
+    {
+        let start = range.start_bound().map(|k| k.as_ref().as_bytes().to_vec());
+        let end = range.end_bound().map(|k| k.as_ref().as_bytes().to_vec());
+        Scan {
+            entries: Entries::Index(self.index.range((start, end))),
+            now: now_millis(),
+            namespace: None,
+            _pin: Some(self.pins.pin()),
+        }
+    }
+
+    /// Take a snapshot of the store. Reads through the snapshot see the keys as they were at
+    /// this point, no matter what is written afterwards.
+    ///
+    /// Creating a snapshot copies the index, and the snapshot keeps the files it reads from until
+    /// it is dropped, even after compaction replaces or removes them.
+    pub fn snapshot(&self) -> Snapshot {
+        // holding the writer lock keeps batches and compaction from being seen half done
+        let _writer = self.writer();
+        self.snapshot_locked()
+    }
+
+    /// Take a snapshot while holding the writer lock.
+    fn snapshot_locked(&self) -> Snapshot {
+        let pin = self.pins.pin();
+        let now = now_millis();
+        let offsets = self
+            .index
+            .iter()
+            .map(|entry| (entry.key().to_vec(), current(&entry)))
+            .filter(|(_, offset)| !offset.is_expired(now))
+            .collect();
+        Snapshot {
+            offsets,
+            now,
+            _pin: pin,
+        }
+    }
+
+    /// Iterate over the key-value pairs whose keys start with `prefix`, in sorted key order.
+    pub fn scan_prefix(&self, prefix: &str) -> Scan<'_> {
+        Scan {
+            entries: Entries::Index(self.index.range(prefix_range(prefix.as_bytes()))),
+            now: now_millis(),
+            namespace: None,
+            _pin: Some(self.pins.pin()),
+        }
+    }
+
+    /// Iterate over all the keys in sorted order, leaving out those of namespaces.
+    ///
+    /// Keys set with `set_bytes` that aren't valid UTF-8 are converted lossily.
+    pub fn keys(&self) -> impl Iterator<Item = String> + '_ {
+        let now = now_millis();
+        self.index
+            .iter()
+            .filter(|entry| entry.key().first() != Some(&NAMESPACE_MARKER))
+            .filter(move |entry| !current(entry).is_expired(now))
+            .map(|entry| String::from_utf8_lossy(entry.key()).into_owned())
+    }
+
+    /// Iterate over the keys that start with `prefix` in sorted order, without reading their
+    /// values, leaving out those of namespaces. See `keys`.
+    pub fn scan_keys(&self, prefix: &str) -> impl Iterator<Item = String> + '_ {
+        let now = now_millis();
+        self.index
+            .range(prefix_range(prefix.as_bytes()))
+            .filter(|entry| entry.key().first() != Some(&NAMESPACE_MARKER))
+            .filter(move |entry| !current(entry).is_expired(now))
+            .map(|entry| String::from_utf8_lossy(entry.key()).into_owned())
+    }
+
+    /// Remove a key by adding a tombstone value!
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Remove a key given as bytes.
+    pub fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.remove_bytes_numbered(key).map(|_| ())
+    }
+
+    /// Like `remove_bytes`, returning the sequence number of the write.
+    fn remove_bytes_numbered(&self, key: &[u8]) -> Result<u64> {
+        let started = Instant::now();
+        slowlog::start();
+        let result = self.write(|writer| {
+            if !contains_live(&writer.index, key) {
+                return Err(KeyNotFound);
+            }
+            writer.append(KvPair {
+                key: key.to_vec(),
+                value: None,
+                expires_at: None,
+                modified_at: None,
+                blob: None,
+                vlog: None,
+                seq: None,
+                prefix: false,
+                merge: false,
+            })?;
+            Ok(writer.sequence)
+        });
+        let elapsed = started.elapsed();
+        self.metrics.remove.record(elapsed);
+        slowlog::log_if_slow(self.slow_log, "remove", key, None, elapsed);
+        result
+    }
+
+    /// Merge `operand` into the value of `key` with the merge operator the store was opened with,
+    /// set with `KvStoreOptions::merge_operator`. The operand is appended as it is, and folded
+    /// in when the key is read. A key with a TTL keeps it.
+    ///
+    /// Fails with `KvsError::NoMergeOperator` if the store has no merge operator.
+    pub fn merge(&self, key: String, operand: String) -> Result<()> {
+        self.merge_bytes(key.as_bytes(), operand.as_bytes())
+    }
+
+    /// Like `merge`, with the key and the operand given as bytes.
+    pub fn merge_bytes(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        let started = Instant::now();
+        let result = self.write(|writer| writer.merge(key, operand.to_vec()));
+        self.metrics.set.record(started.elapsed());
+        result
+    }
+
+    /// Remove every key that starts with `prefix`, and return how many there were.
+    ///
+    /// However many keys it removes, a single range tombstone is appended to the log, which
+    /// compaction and `open` apply to the keys written before it. Keys set afterwards are kept.
+    pub fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        self.remove_prefix_bytes(prefix.as_bytes())
+    }
+
+    /// Like `remove_prefix`, with the prefix given as bytes.
+    pub fn remove_prefix_bytes(&self, prefix: &[u8]) -> Result<usize> {
+        let started = Instant::now();
+        let result = self.write(|writer| writer.remove_prefix(prefix));
+        self.metrics.remove.record(started.elapsed());
+        result
+    }
+
+    /// Remove every key, those of namespaces included, with a single range tombstone, and return
+    /// how many there were.
+    pub fn clear(&self) -> Result<usize> {
+        self.remove_prefix_bytes(b"")
+    }
+
+    /// Set `key` to `new` if its value is `expected`, atomically, and return whether it did.
+    ///
+    /// `None` stands for a missing key: an `expected` of `None` only sets the key if it doesn't
+    /// exist, and a `new` of `None` removes it.
+    pub fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        // holding the writer lock keeps the key from changing between the read and the write
+        self.write(|writer| {
+            let old = self.get_bytes(key.as_bytes())?;
+            if old.as_deref() != expected.as_ref().map(String::as_bytes) {
+                return Ok(false);
+            }
+            if new.is_some() || old.is_some() {
+                writer.append(KvPair {
+                    key: key.into_bytes(),
+                    value: new.map(String::into_bytes),
+                    expires_at: None,
+                    modified_at: None,
+                    blob: None,
+                    vlog: None,
+                    seq: None,
+                    prefix: false,
+                    merge: false,
+                })?;
+            }
+            Ok(true)
+        })
+    }
+
+    /// Move the value of `old_key` to `new_key`, replacing the value `new_key` had if any, and
+    /// keeping its TTL. Both keys are written in a single batch record, so a crash leaves
+    /// either the old key or the new one.
+    ///
+    /// Returns `KvsError::KeyNotFound` if `old_key` doesn't exist.
+    pub fn rename(&self, old_key: String, new_key: String) -> Result<()> {
+        self.write(|writer| {
+            let pair = self.read_live(old_key.as_bytes())?.ok_or(KeyNotFound)?;
+            if old_key == new_key {
+                return Ok(());
+            }
+            let blob = writer.link_blob(pair.blob)?;
+            writer.write_pairs(vec![
+                KvPair {
+                    key: new_key.into_bytes(),
+                    value: pair.value,
+                    expires_at: pair.expires_at,
+                    modified_at: None,
+                    blob,
+                    vlog: None,
+                    seq: None,
+                    prefix: false,
+                    merge: false,
+                },
+                KvPair {
+                    key: old_key.into_bytes(),
+                    value: None,
+                    expires_at: None,
+                    modified_at: None,
+                    blob: None,
+                    vlog: None,
+                    seq: None,
+                    prefix: false,
+                    merge: false,
+                },
+            ])
+        })
+    }
+
+    /// Set `dst` to the value of `src`, with the same TTL, in a single write.
+    ///
+    /// Returns `KvsError::KeyNotFound` if `src` doesn't exist.
+    pub fn copy(&self, src: String, dst: String) -> Result<()> {
+        self.write(|writer| {
+            let pair = self.read_live(src.as_bytes())?.ok_or(KeyNotFound)?;
+            if src == dst {
+                return Ok(());
+            }
+            let blob = writer.link_blob(pair.blob)?;
+            writer.append(KvPair {
+                key: dst.into_bytes(),
+                value: pair.value,
+                expires_at: pair.expires_at,
+                modified_at: None,
+                blob,
+                vlog: None,
+                seq: None,
+                prefix: false,
+                merge: false,
+            })
+        })
+    }
+
+    /// The record of `key` as it is stored, if the key is live. A value in the value log is read,
+    /// since it belongs to the key it was written for.
+    fn read_live(&self, key: &[u8]) -> Result<Option<KvPair>> {
+        let offset = match live_offset(&self.index, key) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        if offset.merged.is_some() {
+            return offset.read_pair().map(Some);
+        }
+        let mut pair = offset.file.read_stored_pair(offset.start, offset.len)?;
+        if let Some(vlog) = pair.vlog.take() {
+            pair.value = Some(offset.file.read_vlog_value(&pair.key, vlog)?);
+        }
+        Ok(Some(pair))
+    }
+
+    /// Add `delta` to the integer value of `key`, atomically, and return the result. A missing
+    /// key counts as 0, and a key set with a TTL keeps it.
+    ///
+    /// Returns `KvsError::InvalidInteger` if the value isn't an integer or the result would
+    /// overflow an `i64`.
+    pub fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        self.write(|writer| {
+            let old = self.get_bytes(key.as_bytes())?;
+            let value = add_to_value(old.as_deref(), delta)?;
+            let expires_at = match old {
+                Some(_) => self
+                    .index
+                    .get(key.as_bytes())
+                    .and_then(|entry| current(&entry).expires_at),
+                None => None,
+            };
+            writer.append(KvPair {
+                key: key.into_bytes(),
+                value: Some(value.to_string().into_bytes()),
+                expires_at,
+                modified_at: None,
+                blob: None,
+                vlog: None,
+                seq: None,
+                prefix: false,
+                merge: false,
+            })?;
+            Ok(value)
+        })
+    }
+
+    /// Set `key` to what `f` returns given its current value, and return the new value. `None`
+    /// stands for a missing key: `f` gets `None` if the key doesn't exist, and returning `None`
+    /// removes it. A key that keeps a value keeps its TTL.
+    ///
+    /// `f` is called once, with the writer lock held, so no other write gets in between the read
+    /// and the write. It must not use the store, which would deadlock. If it panics, nothing is
+    /// written and the panic is passed on once the lock is released.
+    ///
+    /// ```no_run
+    /// # use kvs::KvStore;
+    /// # let store = KvStore::open("db")?;
+    /// // append to a list of comma-separated names
+    /// store.update("names".to_owned(), |names| match names {
+    ///     Some(names) => Some(format!("{},bob", names)),
+    ///     None => Some("bob".to_owned()),
+    /// })?;
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    pub fn update<F>(&self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(Option<String>) -> Option<String>,
+    {
+        let result = self.write(|writer| {
+            let old = match self.get_bytes(key.as_bytes())? {
+                Some(value) => Some(String::from_utf8(value)?),
+                None => None,
+            };
+            let had_value = old.is_some();
+            let new = match panic::catch_unwind(AssertUnwindSafe(|| f(old))) {
+                Ok(new) => new,
+                Err(payload) => return Ok(Err(payload)),
+            };
+            if new.is_some() || had_value {
+                let expires_at = match (&new, had_value) {
+                    (Some(_), true) => self
+                        .index
+                        .get(key.as_bytes())
+                        .and_then(|entry| current(&entry).expires_at),
+                    _ => None,
+                };
+                writer.append(KvPair {
+                    key: key.into_bytes(),
+                    value: new.clone().map(String::into_bytes),
+                    expires_at,
+                    modified_at: None,
+                    blob: None,
+                    vlog: None,
+                    seq: None,
+                    prefix: false,
+                    merge: false,
+                })?;
+            }
+            Ok(Ok(new))
+        })?;
+        match result {
+            Ok(new) => Ok(new),
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    /// Create an empty batch of writes to be applied with `write_batch`.
+    pub fn batch(&self) -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Apply all the writes in `batch` atomically: they are written to the log as a single
+    /// checksummed block, so either all of them survive a crash or none do.
+    ///
+    /// Fails with `KeyNotFound` without writing anything if the batch removes a key that
+    /// doesn't exist at that point of the batch.
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write(|writer| writer.write_batch(batch))
+    }
+
+    /// Statistics about the store: its keys, how much of the disk space it takes is stale, and
+    /// counters of reads, writes and compactions since it was opened.
+    ///
+    /// Reads count the keys looked up by `get`, `get_bytes` and `get_many`, and writes the keys
+    /// set or removed, including by batches and transactions. The latencies are those of the
+    /// calls to `get`, `set` and `remove` and their `_bytes` versions, and of the compactions.
+    pub fn stats(&self) -> Result<Stats> {
+        let writer = self.writer();
+        let mut bytes = 0;
+        for file in writer.segments.values() {
+            bytes += file
+                .file
+                .metadata()?
+                .len()
+                .saturating_sub(file.data_start());
+        }
+        let dead_bytes = writer.stale_bytes.values().sum();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Ok(Stats {
+            keys: self.index.len() as u64,
+            live_bytes: bytes.saturating_sub(dead_bytes),
+            dead_bytes,
+            segments: writer.segments.len() as u64,
+            compactions: load(&self.metrics.compactions),
+            reads: load(&self.metrics.reads),
+            writes: load(&self.metrics.writes),
+            cache_hits: load(&self.metrics.cache_hits),
+            cache_misses: load(&self.metrics.cache_misses),
+            index_bytes: writer.index_bytes,
+            evictions: load(&self.metrics.evictions),
+            value_log_bytes: writer.value_log.size(),
+            latencies: Latencies {
+                get: self.metrics.get.snapshot(),
+                set: self.metrics.set.snapshot(),
+                remove: self.metrics.remove.snapshot(),
+                compaction: self.metrics.compaction.snapshot(),
+            },
+        })
+    }
+
+    /// Sync every write made so far to disk, whatever the sync policy.
+    pub fn flush(&self) -> Result<()> {
+        let writer = self.writer();
+        if !writer.options.read_only {
+            writer.active_file.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Change the compaction threshold of the open store. See
+    /// `KvStoreOptions::compaction_threshold`.
+    pub fn set_compaction_threshold(&self, bytes: u64) {
+        let mut writer = self.writer();
+        writer.options.compaction_threshold = bytes;
+        // a lower threshold may already be crossed
+        if !writer.options.read_only {
+            writer.check_compaction();
+        }
+    }
+
+    /// Change the capacity of the read cache of the open store, dropping the least recently
+    /// used values that no longer fit. See `KvStoreOptions::cache_capacity`.
+    pub fn set_cache_capacity(&self, bytes: usize) {
+        self.writer().options.cache_capacity = bytes;
+        self.cache.set_capacity(bytes);
+    }
+
+    /// Watch the keys that start with `prefix`. Every set or remove of one of them, including
+    /// those of batches and transactions, is sent to the returned receiver once it is written, in
+    /// the order of the writes. Keys that expire are sent as `ChangeOp::Expire` shortly after
+    /// they do, whether or not anything reads them. Those that expired while the store was closed
+    /// are removed as soon as it is opened, possibly before the watcher is added.
+    ///
+    /// The store stops sending changes once the receiver is dropped.
+    pub fn watch(&self, prefix: &str) -> Receiver<ChangeEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.writer().watchers.add(prefix.as_bytes().to_vec(), tx);
+        rx
+    }
+
+    /// Run `f` in a transaction, and commit its writes atomically if it returns `Ok`. Nothing is
+    /// written if it returns an error, which is passed on.
+    ///
+    /// Transactions are optimistic: they don't block each other, but one fails with
+    /// `KvsError::TransactionConflict` when it commits if a key it read or wrote has been written
+    /// since, e.g. by another transaction. It can then be retried.
+    ///
+    /// ```no_run
+    /// # use kvs::KvStore;
+    /// # let store = KvStore::open("db")?;
+    /// // move the seat from alice to bob, unless someone else took it meanwhile
+    /// store.transaction(|txn| {
+    ///     if txn.get("seat:12".to_owned())? == Some("alice".to_owned()) {
+    ///         txn.set("seat:12".to_owned(), "bob".to_owned());
+    ///         txn.remove("alice:seat".to_owned())?;
+    ///     }
+    ///     Ok::<(), kvs::KvsError>(())
+    /// })?;
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    pub fn transaction<T, E, F>(&self, f: F) -> std::result::Result<T, E>
+    where
+        F: FnOnce(&mut Transaction<'_>) -> std::result::Result<T, E>,
+        E: From<KvsError>,
+    {
+        let mut txn = Transaction::new(self);
+        let result = f(&mut txn)?;
+        txn.commit()?;
+        Ok(result)
+    }
+
+    /// Record that `key` was read, for eviction.
+    /// Turn the result of reading the current record of `key`, at `offset`, into `Corruption` if
+    /// the record failed its checksum, and drop the key so that the record isn't read again.
+    fn check_corruption<T>(&self, key: &[u8], offset: &Offset, result: Result<T>) -> Result<T> {
+        match result {
+            Err(err) if err.code() == ErrorCode::ChecksumMismatch => {
+                error!("Dropping a key whose record is corrupt: {}", err);
+                self.writer().drop_corrupt(key, offset);
+                Err(KvsError::Corruption {
+                    key: String::from_utf8_lossy(key).into_owned(),
+                    offset: offset.start - HEADER_SIZE,
+                }
+                .in_file(&offset.file.path, None))
+            }
+            result => result,
+        }
+    }
+
+    fn used(&self, key: &[u8]) {
+        if let Some(recency) = &self.recency {
+            recency.used(key);
+        }
+    }
+
+    fn writer(&self) -> MutexGuard<'_, KvStoreWriter> {
+        self.writer.lock().expect("writer lock poisoned")
+    }
+
+    /// Whether the values written are compressed when they are long enough.
+    pub(crate) fn compresses(&self) -> bool {
+        self.writer().options.compression_threshold.is_some()
+    }
+
+    /// Run `f` with the writer lock held, then wait until what it wrote is synced if writes are
+    /// synced in groups. Every write goes through here, so that no caller returns before its
+    /// write is as durable as the sync policy says.
+    ///
+    /// The write is held back first while compaction is behind, as
+    /// `KvStoreOptions::backpressure` says.
+    fn write<T>(&self, f: impl FnOnce(&mut KvStoreWriter) -> Result<T>) -> Result<T> {
+        let writer = slowlog::time(|spent| &mut spent.queue, || self.throttled_writer())?;
+        self.write_locked(writer, f)
+    }
+
+    /// Like `write`, for the writes that can't be held back: those a follower applies from its
+    /// leader, and the evictions made when the store is opened.
+    fn write_unthrottled<T>(&self, f: impl FnOnce(&mut KvStoreWriter) -> Result<T>) -> Result<T> {
+        self.write_locked(self.writer(), f)
+    }
+
+    fn write_locked<T>(
+        &self,
+        mut writer: MutexGuard<'_, KvStoreWriter>,
+        f: impl FnOnce(&mut KvStoreWriter) -> Result<T>,
+    ) -> Result<T> {
+        let result = f(&mut writer);
+        let ticket = writer.ticket.take();
+        drop(writer);
+        if let (Some(committer), Some(ticket)) = (&self.committer, ticket) {
+            slowlog::time(|spent| &mut spent.sync, || committer.wait(ticket))?;
+        }
+        result
+    }
+
+    /// Take the writer lock for a write, once compaction caught up with the writes or, with
+    /// `Backpressure::Block`, once the time to wait for it ran out. Fails with
+    /// `KvsError::Backpressure` with `Backpressure::Fail` if it hasn't.
+    fn throttled_writer(&self) -> Result<MutexGuard<'_, KvStoreWriter>> {
+        let mut writer = self.writer();
+        let mut deadline = None;
+        while !writer.options.read_only && writer.stalled() {
+            // the threshold may be above the stale data that stalls the writes
+            writer.request_compaction();
+            let timeout = match writer.options.backpressure {
+                Backpressure::Block(timeout) => timeout,
+                Backpressure::Fail => {
+                    warn!("Turning down a write while compaction is behind");
+                    return Err(KvsError::Backpressure);
+                }
+            };
+            let deadline = *deadline.get_or_insert_with(|| Instant::now() + timeout);
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                debug!(
+                    "Writing while compaction is behind, after waiting {:?}",
+                    timeout
+                );
+                break;
+            }
+            writer = self
+                .compacted
+                .wait_timeout(writer, left)
+                .expect("writer lock poisoned")
+                .0;
+        }
+        Ok(writer)
+    }
+}
+
+impl KvStoreWriter {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(writes = batch.pairs.len(), bytes = tracing::field::Empty),
+        )
+    )]
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if self.options.read_only {
+            return Err(ReadOnly);
+        }
+        if batch.pairs.is_empty() {
+            return Ok(());
+        }
+        // whether a key exists after the writes of the batch seen so far
+        let mut exists: HashMap<&[u8], bool> = HashMap::new();
+        for pair in &batch.pairs {
+            if pair.value.is_none()
+                && !exists
+                    .get(pair.key.as_slice())
+                    .cloned()
+                    .unwrap_or_else(|| contains_live(&self.index, &pair.key))
+            {
+                return Err(KeyNotFound);
+            }
+            exists.insert(&pair.key, pair.value.is_some());
+        }
+        self.write_pairs(batch.pairs)
+    }
+
+    /// Write `pairs` as a batch, without checking that the keys they remove exist.
+    fn write_pairs(&mut self, pairs: Vec<KvPair>) -> Result<()> {
+        let events = self.change_events(&pairs)?;
+        self.write_pairs_with(pairs, events)
+    }
+
+    /// Like `write_pairs`, sending `events` to the watchers rather than the changes the pairs
+    /// make.
+    fn write_pairs_with(
+        &mut self,
+        mut pairs: Vec<KvPair>,
+        events: Vec<(Vec<u8>, ChangeEvent)>,
+    ) -> Result<()> {
+        if self.options.read_only {
+            return Err(ReadOnly);
+        }
+        if pairs.is_empty() {
+            return Ok(());
+        }
+        self.check_index_memory(
+            pairs
+                .iter()
+                .filter(|pair| pair.value.is_some())
+                .map(|pair| pair.key.as_slice()),
+        )?;
+        if pairs.iter().any(|pair| pair.value.is_some()) {
+            self.check_free_space()?;
+        }
+        let now = now_millis();
+        for pair in &mut pairs {
+            pair.modified_at.get_or_insert(now);
+            self.sequence += 1;
+            pair.seq = Some(self.sequence);
+        }
+
+        let mut block = Vec::new();
+        let mut lens = Vec::with_capacity(pairs.len());
+        for pair in &pairs {
+            let bytes = self.encode_pair(pair)?;
+            write_record(&mut block, &bytes)?;
+            lens.push(bytes.len());
+        }
+        record!("bytes", block.len() as u64);
+        self.append_frame(block.len() as u32 | BATCH_FLAG, &block)?;
+        self.replicate(&pairs);
+
+        let writes = lens.len() as u64;
+        let mut start = self.active_size + HEADER_SIZE;
+        for (pair, len) in pairs.into_iter().zip(lens) {
+            let offset = Offset {
+                segment: self.active_segment,
+                file: Arc::clone(&self.active_file),
+                start: start + HEADER_SIZE,
+                len,
+                expires_at: pair.expires_at,
+                merged: None,
+            };
+            start += offset.record_len();
+            self.cache.invalidate(&pair.key);
+            if let Some(expires_at) = pair.expires_at {
+                self.schedule_expiry(&pair.key, expires_at);
+            }
+            let has_value = pair.value.is_some();
+            self.written(&pair.key, has_value.then(|| offset.record_len()));
+            self.sample_write(&pair.key);
+            update_index(
+                &self.index,
+                &self.history,
+                &mut self.stale_bytes,
+                &mut self.index_bytes,
+                pair.key,
+                has_value,
+                offset,
+            );
+        }
+        self.active_size = start;
+        Metrics::add(&self.metrics.writes, writes);
+        self.send_events(events);
+        self.after_write()
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(key_len = pair.key.len(), bytes = tracing::field::Empty),
+        )
+    )]
+    fn append(&mut self, mut pair: KvPair) -> Result<()> {
+        if self.options.read_only {
+            return Err(ReadOnly);
+        }
+        if pair.value.is_some() {
+            self.check_index_memory(std::iter::once(pair.key.as_slice()))?;
+            self.check_free_space()?;
+        }
+        let merged = if pair.merge {
+            let operator = self
+                .options
+                .merge_operator
+                .as_ref()
+                .ok_or(NoMergeOperator)?;
+            let prev = self.index.get(&pair.key).map(|entry| current(&entry));
+            Some(MergeChain::after(operator, prev.as_ref()))
+        } else {
+            None
+        };
+        pair.modified_at.get_or_insert_with(now_millis);
+        self.sequence += 1;
+        pair.seq = Some(self.sequence);
+        let events = self.change_events(std::slice::from_ref(&pair))?;
+        let bytes = self.encode_pair(&pair)?;
+        let size = bytes.len();
+        record!("bytes", size as u64);
+        self.append_frame(size as u32, &bytes)?;
+        self.replicate(std::slice::from_ref(&pair));
+
+        let offset = Offset {
+            segment: self.active_segment,
+            file: Arc::clone(&self.active_file),
+            start: self.active_size + HEADER_SIZE,
+            len: size,
+            expires_at: pair.expires_at,
+            merged,
+        };
+        self.active_size += offset.record_len();
+        self.cache.invalidate(&pair.key);
+        if let Some(expires_at) = pair.expires_at {
+            self.schedule_expiry(&pair.key, expires_at);
+        }
+        let has_value = pair.value.is_some();
+        self.written(&pair.key, has_value.then(|| offset.record_len()));
+        self.sample_write(&pair.key);
+        update_index(
+            &self.index,
+            &self.history,
+            &mut self.stale_bytes,
+            &mut self.index_bytes,
+            pair.key,
+            has_value,
+            offset,
+        );
+        Metrics::add(&self.metrics.writes, 1);
+        self.send_events(events);
+        self.after_write()
+    }
+
+    /// Merge `operand` into the value of `key`. It is appended as an operand if the records it
+    /// would be folded onto are in the active segment, and folded in here otherwise: see the
+    /// `merge` module.
+    fn merge(&mut self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        let operator = self.options.merge_operator.clone().ok_or(NoMergeOperator)?;
+        let now = now_millis();
+        let prev = self.index.get(key).map(|entry| current(&entry));
+        let chained = match &prev {
+            None => true,
+            Some(prev) if prev.segment != self.active_segment || prev.is_expired(now) => false,
+            Some(Offset {
+                merged: Some(chain),
+                ..
+            }) => chain.records.len() < MAX_OPERANDS,
+            // a value kept in a blob or the value log is moved around apart from its record
+            Some(prev) => {
+                let stored = prev.file.read_stored_pair(prev.start, prev.len)?;
+                stored.blob.is_none() && stored.vlog.is_none()
+            }
+        };
+        let live = prev.filter(|prev| !prev.is_expired(now));
+        let expires_at = live.as_ref().and_then(|prev| prev.expires_at);
+        let value = if chained {
+            operand
+        } else {
+            let old = match &live {
+                Some(prev) => prev.read_pair()?.value,
+                None => None,
+            };
+            operator.apply(key, old.as_deref(), &operand)
+        };
+        self.append(KvPair {
+            key: key.to_vec(),
+            value: Some(value),
+            expires_at,
+            modified_at: None,
+            blob: None,
+            vlog: None,
+            seq: None,
+            prefix: false,
+            merge: chained,
+        })
+    }
+
+    /// Remove every key that starts with `prefix` with a single range tombstone, and return how
+    /// many live keys it removed. Nothing is written if no key starts with `prefix`.
+    fn remove_prefix(&mut self, prefix: &[u8]) -> Result<usize> {
+        if self.options.read_only {
+            return Err(ReadOnly);
+        }
+        if self.index.range(prefix_range(prefix)).next().is_none() {
+            return Ok(0);
+        }
+        let mut events = Vec::new();
+        for entry in self.index.range(prefix_range(prefix)) {
+            if !self.watchers.is_watched(entry.key()) {
+                continue;
+            }
+            if let Some(offset) = live_offset(&self.index, entry.key()) {
+                let old_value = offset.read_pair()?.value;
+                let event = ChangeEvent::new(entry.key(), old_value.as_deref(), None);
+                events.push((entry.key().to_vec(), event));
+            }
+        }
+        let mut pair = KvPair {
+            key: prefix.to_vec(),
+            value: None,
+            expires_at: None,
+            modified_at: Some(now_millis()),
+            blob: None,
+            vlog: None,
+            seq: None,
+            prefix: true,
+            merge: false,
+        };
+        self.sequence += 1;
+        pair.seq = Some(self.sequence);
+        let bytes = self.encode_pair(&pair)?;
+        self.append_frame(bytes.len() as u32, &bytes)?;
+        self.replication
+            .push(ReplicationEntry::RemovePrefix(pair.key));
+
+        let offset = Offset {
+            segment: self.active_segment,
+            file: Arc::clone(&self.active_file),
+            start: self.active_size + HEADER_SIZE,
+            len: bytes.len(),
+            expires_at: None,
+            merged: None,
+        };
+        self.active_size += offset.record_len();
+        let now = now_millis();
+        let mut live = 0;
+        let (cache, recency) = (&self.cache, &self.recency);
+        remove_range(
+            &self.index,
+            &self.history,
+            &mut self.stale_bytes,
+            &mut self.index_bytes,
+            prefix,
+            offset,
+            |key, offset| {
+                cache.invalidate(key);
+                if let Some(recency) = recency {
+                    recency.written(key, None);
+                }
+                if !offset.is_expired(now) {
+                    live += 1;
+                }
+            },
+        );
+        Metrics::add(&self.metrics.writes, 1);
+        self.send_events(events);
+        self.after_write()?;
+        Ok(live)
+    }
+
+    /// Fail with `IndexFull` if setting `keys` would add keys to an index that takes up as much
+    /// memory as it may.
+    fn check_index_memory<'a>(&self, keys: impl Iterator<Item = &'a [u8]>) -> Result<()> {
+        let limit = match self.options.index_memory_limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let mut added = HashSet::new();
+        let mut bytes = self.index_bytes;
+        for key in keys {
+            if !self.index.contains_key(key) && added.insert(key) {
+                bytes += index_entry_size(key);
+            }
+        }
+        if !added.is_empty() && bytes > limit {
+            return Err(IndexFull);
+        }
+        Ok(())
+    }
+
+    /// The changes that writing `pairs` in order makes to watched keys.
+    fn change_events(&self, pairs: &[KvPair]) -> Result<Vec<(Vec<u8>, ChangeEvent)>> {
+        let mut events = Vec::new();
+        // the values set by the pairs seen so far
+        let mut written: HashMap<&[u8], Option<&[u8]>> = HashMap::new();
+        for pair in pairs {
+            if self.watchers.is_watched(&pair.key) {
+                let old_value = match written.get(pair.key.as_slice()) {
+                    Some(value) => value.map(<[u8]>::to_vec),
+                    None => match live_offset(&self.index, &pair.key) {
+                        Some(offset) => offset.read_pair()?.value,
+                        None => None,
+                    },
+                };
+                let merged = match (&self.options.merge_operator, &pair.value) {
+                    (Some(operator), Some(operand)) if pair.merge => {
+                        Some(operator.apply(&pair.key, old_value.as_deref(), operand))
+                    }
+                    _ => None,
+                };
+                let event = match pair.blob {
+                    Some(_) => ChangeEvent::streamed(&pair.key, old_value.as_deref()),
+                    None => ChangeEvent::new(
+                        &pair.key,
+                        old_value.as_deref(),
+                        merged.as_deref().or(pair.value.as_deref()),
+                    ),
+                };
+                events.push((pair.key.clone(), event));
+            }
+            written.insert(&pair.key, pair.value.as_deref());
+        }
+        Ok(events)
+    }
+
+    /// Send writes to the followers. The value of a blob isn't sent along: the followers start
+    /// over from a copy of the store instead, which holds it.
+    fn replicate(&mut self, pairs: &[KvPair]) {
+        match pairs {
+            _ if pairs.iter().any(|pair| pair.blob.is_some()) => self.replication.restart(),
+            // merge operands are only ever appended alone
+            [pair] if pair.merge => self.replication.push(ReplicationEntry::Merge {
+                key: pair.key.clone(),
+                operand: pair.value.clone().unwrap_or_default(),
+            }),
+            _ => self.replication.push(ReplicationEntry::write(pairs)),
+        }
+    }
+
+    fn send_events(&mut self, events: Vec<(Vec<u8>, ChangeEvent)>) {
+        for (key, event) in events {
+            self.watchers.send(&key, event);
+        }
+    }
+
+    /// Record that `key` was written with a record of `bytes` bytes, or removed, for eviction.
+    fn written(&self, key: &[u8], bytes: Option<u64>) {
+        if let Some(recency) = &self.recency {
+            recency.written(key, bytes);
+        }
+    }
+
+    /// Append `data` to the active segment, framed with `len` as its length field, and sync it as
+    /// the sync policy asks. If that fails, e.g. because the disk is full, the segment is cut
+    /// back to where it ended, so that the next records don't land after a partial one. The
+    /// index is only updated once the record is written.
+    fn append_frame(&mut self, len: u32, data: &[u8]) -> Result<()> {
+        if data.len() as u64 > self.options.max_record_size {
+            return Err(RecordTooLarge(data.len() as u64));
+        }
+        let result = write_frame(&mut &self.active_file.file, len, data).and_then(|_| self.sync());
+        if result.is_err() {
+            if let Err(err) = self.active_file.file.set_len(self.active_size) {
+                error!(
+                    "Failed to cut a partial record off segment {}: {}",
+                    self.active_segment, err
+                );
+            }
+        }
+        result
+    }
+
+    /// Fail with `DiskFull` if the disk holding the store has less free space left than
+    /// `KvStoreOptions::min_free_space`.
+    fn check_free_space(&self) -> Result<()> {
+        let min = match self.options.min_free_space {
+            Some(min) => min,
+            None => return Ok(()),
+        };
+        match free_space(&self.dir)? {
+            Some(free) if free < min => {
+                error!("Turning down a write with {} bytes free", free);
+                Err(DiskFull)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Drop `key` from the index if its current record is the one at `offset`, which is corrupt.
+    /// Returns whether it was dropped.
+    ///
+    /// The record isn't counted as stale: compaction would fail on it, until
+    /// `KvStoreOptions::repair` cuts the segment off before it.
+    fn drop_corrupt(&mut self, key: &[u8], offset: &Offset) -> bool {
+        let dropped = match self.index.get(key) {
+            Some(entry) => current(&entry).points_to(&offset.file, offset.start) && entry.remove(),
+            None => false,
+        };
+        if dropped {
+            self.index_bytes -= index_entry_size(key);
+            self.written(key, None);
+            self.cache.invalidate(key);
+        }
+        dropped
+    }
+
+    /// Evict keys if the store is over its capacity limits, start a new segment if the active
+    /// one is full, and start compacting if there is enough stale data.
+    fn after_write(&mut self) -> Result<()> {
+        self.evict()?;
+        if self.active_size >= self.options.segment_size {
+            debug!("Segment {} is full", self.active_segment);
+            self.seal()?;
+        }
+        self.check_compaction();
+        Ok(())
+    }
+
+    /// Start compacting if the stale data crossed the threshold.
+    fn check_compaction(&mut self) {
+        if !self.compaction_pending && self.needs_compaction() {
+            // the compaction thread only stops once the store is dropped
+            let _ = self.compactor.send(CompactorMessage::Compact);
+            self.compaction_pending = true;
+        }
+    }
+
+    /// Start compacting if a sealed segment holds stale data, whatever the threshold.
+    fn request_compaction(&mut self) {
+        if !self.compaction_pending && self.compaction_candidate().is_some() {
+            let _ = self.compactor.send(CompactorMessage::Compact);
+            self.compaction_pending = true;
+        }
+    }
+
+    /// Seal the active segment and start appending to a new one.
+    fn seal(&mut self) -> Result<()> {
+        // the committer only syncs the active segment
+        if self.group.is_some() {
+            self.active_file.file.sync_data()?;
+        }
+        self.active_segment += 1;
+        self.active_file = Arc::new(
+            SegmentFile::open(
+                &self.dir,
+                self.active_segment,
+                true,
+                self.options.encryption_key.as_ref(),
+            )?
+            .with_max_record_size(self.options.max_record_size),
+        );
+        self.segments
+            .insert(self.active_segment, Arc::clone(&self.active_file));
+        self.active_size = self.active_file.data_start();
+        // nothing is written to the new segment before the manifest lists it
+        self.save_manifest()
+    }
+
+    /// Record the segments of the store, its compaction generation and the namespaces dropped in
+    /// its manifest.
+    fn save_manifest(&mut self) -> Result<()> {
+        self.dropped = live_drops(&self.segments, mem::take(&mut self.dropped));
+        write_manifest(
+            &self.dir,
+            &Manifest {
+                layout: LAYOUT_VERSION,
+                generation: self.generation,
+                sequence: self.sequence,
+                segments: self.segments.keys().copied().collect(),
+                dropped: self.dropped.clone(),
+                cold: self.cold.clone(),
+            },
+        )
+    }
+
+    /// Whether the compaction policy picks segments to compact in the background.
+    fn needs_compaction(&self) -> bool {
+        self.pick_compaction(true).is_some()
+    }
+
+    /// The run of adjacent segments the compaction policy picks to compact next, if any, which
+    /// is a single segment unless it merges several. With `background`, for the compaction the
+    /// store starts by itself, which a manual-only policy leaves out.
+    fn pick_compaction(&self, background: bool) -> Option<Vec<u64>> {
+        let default = StaleBytes(self.options.compaction_threshold);
+        let policy = match &self.options.compaction_policy {
+            Some(policy) => policy.as_ref(),
+            None => &default,
+        };
+        if background && !policy.background() {
+            return None;
+        }
+        let usage = self.segment_usage();
+        if let Some(run) = policy.merge(&usage) {
+            if self.is_mergeable(&run) {
+                return Some(run);
+            }
+            debug!(
+                "Compaction policy picked segments {:?}, which can't be merged",
+                run
+            );
+        }
+        policy.pick(&usage).map(|segment| vec![segment])
+    }
+
+    /// Whether `run` lists at least two sealed segments, each next to the one before, all in the
+    /// same storage. Records only keep their order if no segment in between is left out.
+    fn is_mergeable(&self, run: &[u64]) -> bool {
+        let (first, last) = match (run.first(), run.last()) {
+            (Some(first), Some(last)) if run.len() > 1 => (first, last),
+            _ => return false,
+        };
+        *last < self.active_segment
+            && self
+                .segments
+                .range(first..=last)
+                .map(|(segment, _)| segment)
+                .eq(run)
+            && run
+                .iter()
+                .all(|segment| self.cold.get(segment) == self.cold.get(first))
+    }
+
+    /// The usage of the sealed segments, oldest first. Stale data in the active segment doesn't
+    /// count, since it can't be compacted until the segment is sealed.
+    fn segment_usage(&self) -> Vec<SegmentUsage> {
+        self.segments
+            .iter()
+            .filter(|&(&segment, _)| segment != self.active_segment)
+            .map(|(&segment, file)| SegmentUsage {
+                segment,
+                size: file.sealed_size().saturating_sub(file.data_start()),
+                stale: self.stale_bytes.get(&segment).copied().unwrap_or(0),
+                created: file.created_at.map(from_millis),
+            })
+            .collect()
+    }
+
+    /// Whether compaction fell behind the writes, past the high-water marks of
+    /// `KvStoreOptions::stall_stale_ratio` or `stall_segments`.
+    fn stalled(&self) -> bool {
+        if let Some(max) = self.options.stall_segments {
+            if self.segments.len() > max {
+                return true;
+            }
+        }
+        match self.options.stall_stale_ratio {
+            Some(max) if self.needs_compaction() => self.stale_ratio() > max,
+            _ => false,
+        }
+    }
+
+    /// The share of the bytes of the sealed segments that is stale, from 0 to 1.
+    fn stale_ratio(&self) -> f64 {
+        let (mut bytes, mut stale) = (0, 0);
+        for (segment, file) in &self.segments {
+            if *segment == self.active_segment {
+                continue;
+            }
+            let len = file.file.metadata().map_or(0, |metadata| metadata.len());
+            bytes += len.saturating_sub(file.data_start());
+            stale += self.stale_bytes.get(segment).copied().unwrap_or(0);
+        }
+        if bytes == 0 {
+            return 0.0;
+        }
+        stale as f64 / bytes as f64
+    }
+
+    /// The sealed segment with the most stale data, if any has some.
+    fn compaction_candidate(&self) -> Option<u64> {
+        self.stale_bytes
+            .iter()
+            .filter(|&(&segment, &stale)| segment != self.active_segment && stale > 0)
+            .max_by_key(|&(_, &stale)| stale)
+            .map(|(&segment, _)| segment)
+    }
+
+    /// Sync the active segment if the sync policy asks for it, or take a ticket for the
+    /// committer to sync it.
+    fn sync(&mut self) -> Result<()> {
+        match self.options.sync_policy {
+            SyncPolicy::Always => self.active_file.file.sync_data()?,
+            SyncPolicy::Group => {
+                if let Some(group) = &self.group {
+                    self.ticket = Some(group.ticket());
+                }
+            }
+            SyncPolicy::Never => {}
+        }
+        Ok(())
+    }
+}
+
+/// Compact segments each time the writer asks for it, until the stale data drops below the
+/// threshold. Runs until the store is dropped.
+fn run_compactor(writer: Weak<Mutex<KvStoreWriter>>, rx: Receiver<CompactorMessage>) {
+    loop {
+        let reply = match rx.recv() {
+            Ok(CompactorMessage::Compact) => None,
+            Ok(CompactorMessage::CompactAll(reply)) => Some((None, reply)),
+            Ok(CompactorMessage::Purge(key, reply)) => Some((Some(key), reply)),
+            Ok(CompactorMessage::CompactNow(reply)) => {
+                if let Some(writer) = writer.upgrade() {
+                    // the caller may have given up waiting
+                    let _ = reply.send(compact_now(&writer));
+                    writer
+                        .lock()
+                        .expect("writer lock poisoned")
+                        .compacted
+                        .notify_all();
+                }
+                continue;
+            }
+            Ok(CompactorMessage::Shutdown) | Err(_) => return,
+        };
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        if let Some((purged, reply)) = reply {
+            let result = match purged {
+                None => compact_all(&writer),
+                Some(key) => compact_purged(&writer, &key),
+            };
+            // the caller may have given up waiting
+            let _ = reply.send(result);
+            writer
+                .lock()
+                .expect("writer lock poisoned")
+                .compacted
+                .notify_all();
+            continue;
+        }
+        loop {
+            // compaction goes on past what the policy picks while writes are held back
+            let candidate = {
+                let writer = writer.lock().expect("writer lock poisoned");
+                writer.pick_compaction(true).or_else(|| {
+                    let segment = writer.stalled().then(|| writer.compaction_candidate())??;
+                    Some(vec![segment])
+                })
+            };
+            let run = match candidate {
+                Some(run) => run,
+                None => break,
+            };
+            let result = compaction(&writer, &run);
+            writer
+                .lock()
+                .expect("writer lock poisoned")
+                .compacted
+                .notify_all();
+            if let Err(err) = result {
+                error!("Compaction failed: {:?}", err);
+                break;
+            }
+        }
+        writer
+            .lock()
+            .expect("writer lock poisoned")
+            .compaction_pending = false;
+    }
+}
+
+/// Compact the segments the compaction policy picks or merges, one run after the other, until it
+/// picks none, and return how many compactions there were.
+fn compact_now(writer: &Mutex<KvStoreWriter>) -> Result<usize> {
+    let mut compacted = 0;
+    loop {
+        let candidate = writer
+            .lock()
+            .expect("writer lock poisoned")
+            .pick_compaction(false);
+        match candidate {
+            Some(run) => compaction(writer, &run)?,
+            None => return Ok(compacted),
+        }
+        compacted += 1;
+    }
+}
+
+/// Compact each sealed segment that holds stale data once, whatever the threshold, along with
+/// the plaintext segments of an encrypted store.
+fn compact_all(writer: &Mutex<KvStoreWriter>) -> Result<()> {
+    let segments: Vec<u64> = {
+        let writer = writer.lock().expect("writer lock poisoned");
+        let encrypted = writer.options.encryption_key.is_some();
+        writer
+            .segments
+            .iter()
+            .filter(|&(&segment, file)| {
+                segment != writer.active_segment
+                    && (writer
+                        .stale_bytes
+                        .get(&segment)
+                        .is_some_and(|&stale| stale > 0)
+                        || (encrypted && file.cipher.is_none()))
+            })
+            .map(|(&segment, _)| segment)
+            .collect()
+    };
+    for segment in segments {
+        compaction(writer, &[segment])?;
+    }
+    Ok(())
+}
+
+/// Compact a run of adjacent sealed segments into one, under the id of the newest. Usually the
+/// run is a single segment, so that the cost of a compaction is bounded by the segment size, and
+/// it only spans several when the compaction policy merges them.
+///
+/// Create a new file, write the live records of the segments to it, oldest first, and move it to
+/// override the newest segment before the others are removed. Tombstones, range tombstones
+/// included, are kept unless the run starts at the oldest segment, as they may still shadow
+/// records in older segments. The records keep their order, so a range tombstone still only
+/// removes the keys written before it. With cold storage, the new segment is put there, and the
+/// old files removed once the manifest lists the new one. The writer lock is only held to pick
+/// the segments and to swap in the new file, so writes carry on while the records are copied.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "info",
+        skip_all,
+        fields(
+            segment = tracing::field::Empty,
+            bytes_in = tracing::field::Empty,
+            bytes_out = tracing::field::Empty,
+        ),
+    )
+)]
+fn compaction(writer: &Mutex<KvStoreWriter>, run: &[u64]) -> Result<()> {
+    let started = Instant::now();
+    let segment = *run.last().expect("a compaction has a segment to rewrite");
+    let (
+        dir,
+        storage,
+        was_cold,
+        index,
+        history,
+        files,
+        is_oldest,
+        compression_threshold,
+        key,
+        dropped,
+        purging,
+    ) = {
+        let writer = writer.lock().expect("writer lock poisoned");
+        // a segment in other storage stays there, even if segments are no longer moved to it
+        let storage = match writer.cold.get(&segment) {
+            Some(location) => Some(Arc::clone(&writer.storages[location])),
+            None => writer.cold_storage.clone(),
+        };
+        let files: Vec<_> = run
+            .iter()
+            .map(|segment| (*segment, Arc::clone(&writer.segments[segment])))
+            .collect();
+        (
+            writer.dir.clone(),
+            storage,
+            writer.cold.contains_key(&segment),
+            Arc::clone(&writer.index),
+            Arc::clone(&writer.history),
+            files,
+            writer.segments.keys().next() == run.first(),
+            writer.options.compression_threshold,
+            writer.options.encryption_key.clone(),
+            writer.dropped.clone(),
+            writer.purging.clone(),
+        )
+    };
+    debug!("Running compaction on segments {:?}", run);
+    record!("segment", segment);
+    record!(
+        "bytes_in",
+        files
+            .iter()
+            .map(|(_, file)| file.file.metadata().map(|metadata| metadata.len()))
+            .sum::<io::Result<u64>>()?
+    );
+
+    // The hint is rewritten once the new segment is in place. Until then, there is none.
+    for &segment in run {
+        remove_hint(&dir, segment)?;
+    }
+    // The new segment is written where it is read from, and put in the storage too if that is
+    // the copy kept of it.
+    let location = storage.as_ref().map(|storage| storage.location());
+    let (path, remote) = match storage {
+        None => (segment_path(&dir, segment), None),
+        Some(storage) => match storage.local_path(&object_name(segment)) {
+            Some(path) => (path, None),
+            None => (cached_path(&dir, segment), Some(storage)),
+        },
+    };
+    let mut output =
+        tempfile::NamedTempFile::new_in(path.parent().expect("segments are in a directory"))?;
+    // the records are re-encrypted under the key of the new segment
+    let cipher = write_file_header(&mut output, key.as_ref())?;
+    let mut hints = Vec::new();
+    let mut moved = Vec::new();
+    let mut expired = Vec::new();
+    // the blobs of the records left out
+    let mut dead_blobs = Vec::new();
+    let mut output_size = file_header_size(cipher.is_some());
+    let now = now_millis();
+    for (input, file) in &files {
+        let input = *input;
+        file.for_each_record(TornTail::Fail, |start, _, mut pair| {
+            // nothing older than the drop of a namespace can bring back its keys, and nothing is
+            // left of a key being purged, not even its tombstone
+            if dropped
+                .iter()
+                .any(|dropped| dropped.covers(input, &pair.key))
+                || purging
+                    .iter()
+                    .any(|(key, through)| input <= *through && !pair.prefix && *key == pair.key)
+            {
+                dead_blobs.extend(pair.blob);
+                return Ok(());
+            }
+            let current_offset = index
+                .get(&pair.key)
+                .map(|entry| current(&entry))
+                .filter(|offset| offset.points_to(file, start));
+            let is_current = pair.value.is_some() && current_offset.is_some();
+            let live = match pair.value {
+                Some(_) => is_current || history.contains(&pair.key, file, start),
+                None => !is_oldest,
+            };
+            if !live {
+                dead_blobs.extend(pair.blob);
+                return Ok(());
+            }
+            // The last operand of a key is written as the value it stands for, and the records
+            // it was folded onto, in these segments too, are left out as they aren't current.
+            if let Some(offset) = current_offset.filter(|_| pair.merge) {
+                pair.value = offset.read_pair()?.value;
+                pair.merge = false;
+            }
+            // older versions are kept as they are, even once expired
+            if is_current && pair.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                // An expired key turns into a tombstone, so that it doesn't bring back a value
+                // from an older segment.
+                let value = pair.value.take();
+                let value = match (pair.blob.take(), pair.vlog.take()) {
+                    (Some(blob), _) => {
+                        dead_blobs.push(blob);
+                        None
+                    }
+                    // left for the garbage collection of the value log
+                    (None, Some(_)) => None,
+                    (None, None) => value,
+                };
+                expired.push((pair.key.clone(), Arc::clone(file), start, value));
+                if is_oldest {
+                    return Ok(());
+                }
+                pair.expires_at = None;
+            }
+            let data = pair.encode(compression_threshold, cipher.as_ref());
+            write_record(&mut output, &data)?;
+            output_size += HEADER_SIZE + data.len() as u64;
+            hints.push(HintEntry {
+                key: pair.key.clone(),
+                has_value: pair.value.is_some(),
+                start: output_size - data.len() as u64,
+                len: data.len(),
+                expires_at: pair.expires_at,
+                prefix: pair.prefix,
+            });
+            if pair.value.is_some() {
+                moved.push((
+                    pair.key,
+                    Arc::clone(file),
+                    start,
+                    output_size - data.len() as u64,
+                    data.len(),
+                    pair.expires_at,
+                ));
+            }
+            Ok(())
+        })?;
+    }
+    output.flush()?;
+    record!("bytes_out", output_size);
+    if let Some(storage) = remote.as_ref().filter(|_| !hints.is_empty()) {
+        storage.put(&object_name(segment), output.path())?;
+    }
+
+    let mut writer = writer.lock().expect("writer lock poisoned");
+    let (older, newest) = files.split_at(files.len() - 1);
+    let file = &newest[0].1;
+    if hints.is_empty() {
+        // the manifest stops listing the segments before they are removed
+        for &segment in run {
+            writer.segments.remove(&segment);
+            writer.cold.remove(&segment);
+            writer.stale_bytes.remove(&segment);
+        }
+        writer.generation += 1;
+        writer.save_manifest()?;
+        writer
+            .pins
+            .remove_files(files.iter().map(|(_, file)| file.path.clone()).collect())?;
+        if let Some(storage) = remote.as_ref().filter(|_| was_cold) {
+            for &segment in run {
+                storage.delete(&object_name(segment))?;
+            }
+        }
+    } else {
+        // Windows won't replace a file mapped into memory. Values borrowed from the old segment
+        // keep their mapping, and the compaction fails until they are dropped.
+        #[cfg(feature = "mmap")]
+        file.unmap();
+        persist(output, &path)?;
+    }
+    // Records overwritten while they were being copied are already stale in the new file.
+    let mut stale = 0;
+    if !hints.is_empty() {
+        // Readers that already looked up an old offset keep reading the replaced files.
+        let new_file = match location {
+            None => SegmentFile::open(&dir, segment, false, key.as_ref())?,
+            Some(_) => SegmentFile::open_cold(path.clone(), &dir, key.as_ref())?,
+        };
+        let new_file = Arc::new(new_file.with_max_record_size(file.max_record_size));
+        for (key, old_file, old_start, start, len, expires_at) in moved {
+            let offset = Offset {
+                segment,
+                file: Arc::clone(&new_file),
+                start,
+                len,
+                expires_at,
+                merged: None,
+            };
+            let record_len = offset.record_len();
+            let kept = match index.get(&key) {
+                Some(entry) if current(&entry).points_to(&old_file, old_start) => {
+                    *entry.value().write().expect("index lock poisoned") = offset;
+                    true
+                }
+                _ => history.relocate(&key, &old_file, old_start, offset),
+            };
+            if !kept {
+                stale += record_len;
+            }
+        }
+        // the segments merged into the newest one are no longer listed
+        for (segment, _) in older {
+            writer.segments.remove(segment);
+            writer.cold.remove(segment);
+            writer.stale_bytes.remove(segment);
+        }
+        writer.segments.insert(segment, new_file);
+        match location {
+            None => writer.cold.remove(&segment),
+            Some(location) => writer.cold.insert(segment, location),
+        };
+        writer.generation += 1;
+        writer.save_manifest()?;
+        // a hint would hold the keys of an encrypted segment in plaintext
+        if cipher.is_none() {
+            let rate = writer.options.bloom_false_positive_rate;
+            write_hint(&dir, segment, output_size, &hints, rate)?;
+        }
+        // the files merged into the new one, and the old one if it moved to other storage
+        let replaced = files
+            .iter()
+            .map(|(_, file)| file.path.clone())
+            .filter(|old| *old != path)
+            .collect();
+        writer.pins.remove_files(replaced)?;
+        if let Some(storage) = remote.as_ref().filter(|_| was_cold) {
+            for (segment, _) in older {
+                storage.delete(&object_name(*segment))?;
+            }
+        }
+    }
+    let dead_blobs = dead_blobs
+        .iter()
+        .map(|blob| blob_path(&dir, blob.id))
+        .collect();
+    writer.pins.remove_files(dead_blobs)?;
+    // the expiry thread skips the keys dropped here, so they are reported here instead
+    for (key, old_file, old_start, value) in expired {
+        if let Some(entry) = index.get(&key) {
+            if current(&entry).points_to(&old_file, old_start) && entry.remove() {
+                writer.index_bytes -= index_entry_size(&key);
+                writer.written(&key, None);
+                if writer.watchers.is_watched(&key) {
+                    let event = ChangeEvent::expired(&key, value.as_deref());
+                    writer.watchers.send(&key, event);
+                }
+            }
+        }
+    }
+    if !hints.is_empty() {
+        writer.stale_bytes.insert(segment, stale);
+    }
+    writer.replication.push(ReplicationEntry::Compacted);
+    Metrics::add(&writer.metrics.compactions, 1);
+    writer.metrics.compaction.record(started.elapsed());
+
+    Ok(())
+}
+
+/// What `KvStore::get_with_meta` returns about a value besides the value itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// When the value was written, or `None` if it was written by a version of the store that
+    /// didn't keep track of it
+    pub modified: Option<SystemTime>,
+    /// When the key expires, if it was set with a TTL
+    pub expires: Option<SystemTime>,
+    /// Bytes the record of the value takes up on disk, including its header
+    pub size: u64,
+}
+
+/// A group of sets and removes applied atomically by `KvStore::write_batch`.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    pairs: Vec<KvPair>,
+}
+
+impl WriteBatch {
+    /// Set a key when the batch is written.
+    pub fn set(&mut self, key: String, value: String) {
+        self.pairs.push(KvPair {
+            key: key.into_bytes(),
+            value: Some(value.into_bytes()),
+            expires_at: None,
+            modified_at: None,
+            blob: None,
+            vlog: None,
+            seq: None,
+            prefix: false,
+            merge: false,
+        });
+    }
+
+    /// Remove a key when the batch is written.
+    pub fn remove(&mut self, key: String) {
+        self.pairs.push(KvPair {
+            key: key.into_bytes(),
+            value: None,
+            expires_at: None,
+            modified_at: None,
+            blob: None,
+            vlog: None,
+            seq: None,
+            prefix: false,
+            merge: false,
+        });
+    }
+
+    /// Number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    /// Whether the batch has no writes.
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+/// A point-in-time view of a `KvStore`, created by `KvStore::snapshot`.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    // the offsets of the live keys when the snapshot was taken
+    offsets: BTreeMap<Vec<u8>, Offset>,
+    // when the snapshot was taken
+    now: u64,
+    // keeps the files the offsets point to
+    _pin: Pin,
+}
+
+impl Snapshot {
+    /// Retrieve the value a key had when the snapshot was taken.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Retrieve the value a key had when the snapshot was taken, as bytes.
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.offsets.get(key) {
+            Some(offset) => Ok(offset.read_pair()?.value),
+            None => Ok(None),
+        }
+    }
+
+    /// Iterate over the key-value pairs whose keys fall in `range` when the snapshot was taken,
+    /// in sorted key order.
+    pub fn scan<K, R>(&self, range: R) -> Scan<'_>
+    where
+        K: AsRef<str>,
+        R: RangeBounds<K>,
+    {
+        let start = range.start_bound().map(|k| k.as_ref().as_bytes().to_vec());
+        let end = range.end_bound().map(|k| k.as_ref().as_bytes().to_vec());
+        // `BTreeMap::range` panics on a range that ends before it starts, which is empty, as it
+        // is for a scan of the store; nothing comes before the empty key
+        let range = if ends_before_start(&start, &end) {
+            (Bound::Unbounded, Bound::Excluded(Vec::new()))
+        } else {
+            (start, end)
+        };
+        Scan {
+            entries: Entries::Snapshot(self.offsets.range(range)),
+            now: self.now,
+            namespace: None,
+            // the snapshot pins the files it reads
+            _pin: None,
+        }
+    }
+}
+
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// Whether a range ends before it starts, or ends where it starts without either end in it.
+fn ends_before_start(start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    match (start, end) {
+        (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        (
+            Bound::Included(start) | Bound::Excluded(start),
+            Bound::Included(end) | Bound::Excluded(end),
+        ) => start > end,
+        _ => false,
+    }
+}
+
+/// An iterator over a range of key-value pairs, created by `KvStore::scan`, `Snapshot::scan` or
+/// `Namespace::scan_prefix`.
+///
+/// Yields `KvsError::Utf8Error` for pairs set with `set_bytes` that aren't valid UTF-8. Scans of
+/// the store leave out the keys of namespaces.
+///
+/// When scanning the store, keys written while the scan is in progress may or may not be
+/// returned.
+pub struct Scan<'a> {
+    entries: Entries<'a>,
+    // keys that expire before the scan started are skipped
+    now: u64,
+    // the length of the prefix stripped from the keys of a namespace, or `None` to skip the
+    // keys of namespaces
+    namespace: Option<usize>,
+    // keeps the files the entries point to, for scans of the store
+    _pin: Option<Pin>,
+}
+
+enum Entries<'a> {
+    Index(map::Range<'a, IndexKey, IndexRange, IndexKey, RwLock<Offset>>),
+    Snapshot(btree_map::Range<'a, Vec<u8>, Offset>),
+}
+
+impl Entries<'_> {
+    fn next(&mut self) -> Option<(Vec<u8>, Offset)> {
+        match self {
+            Entries::Index(range) => range
+                .next()
+                .map(|entry| (entry.key().to_vec(), current(&entry))),
+            Entries::Snapshot(range) => range
+                .next()
+                .map(|(key, offset)| (key.clone(), offset.clone())),
+        }
+    }
+}
+
+impl<'a> Iterator for Scan<'a> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, offset) = loop {
+            let (mut key, offset) = self.entries.next()?;
+            if offset.is_expired(self.now) {
+                continue;
+            }
+            match self.namespace {
+                Some(prefix_len) => {
+                    key.drain(..prefix_len);
+                }
+                None if key.first() == Some(&NAMESPACE_MARKER) => continue,
+                None => {}
+            }
+            break (key, offset);
+        };
+        let pair = match offset.read_pair() {
+            Ok(pair) => pair,
+            Err(err) => return Some(Err(err)),
+        };
+        // the index only holds live keys, so the record always has a value
+        let value = pair.value.unwrap_or_default();
+        match (String::from_utf8(key), String::from_utf8(value)) {
+            (Ok(key), Ok(value)) => Some(Ok((key, value))),
+            (Err(err), _) | (_, Err(err)) => Some(Err(err.into())),
+        }
+    }
+}
+
+/// The namespaces of `dropped` whose records may still be in one of `segments`.
+fn live_drops<T>(
+    segments: &BTreeMap<u64, T>,
+    mut dropped: Vec<DroppedNamespace>,
+) -> Vec<DroppedNamespace> {
+    let first = segments.keys().next().copied().unwrap_or(u64::MAX);
+    dropped.retain(|dropped| first <= dropped.through);
+    dropped
+}
+
+/// The range of the keys that start with `prefix`.
+fn prefix_range(prefix: &[u8]) -> KeyRange {
+    let end = match prefix_end(prefix) {
+        Some(end) => Bound::Excluded(end),
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix.to_vec()), end)
+}
+
+/// The smallest key greater than all the keys that start with `prefix`, or `None` if there is no
+/// such key.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(byte) = end.pop() {
+        if byte < u8::MAX {
+            end.push(byte + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Point `key` at `offset`, or drop it if the record is a tombstone, i.e. it has no value, and
+/// account for the bytes made stale by the write.
+fn update_index(
+    index: &Index,
+    history: &History,
+    stale_bytes: &mut HashMap<u64, u64>,
+    index_bytes: &mut u64,
+    key: Vec<u8>,
+    has_value: bool,
+    offset: Offset,
+) {
+    let prev = if has_value {
+        match index.get(&key) {
+            // the records a merge operand is folded onto are still in use
+            Some(entry) if offset.merged.is_some() => {
+                *entry.value().write().expect("index lock poisoned") = offset;
+                return;
+            }
+            Some(entry) => std::mem::replace(
+                &mut *entry.value().write().expect("index lock poisoned"),
+                offset,
+            ),
+            None => {
+                *index_bytes += index_entry_size(&key);
+                index.insert(key, offset);
+                return;
+            }
+        }
+    } else {
+        // the key is deleted, and the tombstone itself is garbage
+        *stale_bytes.entry(offset.segment).or_insert(0) += offset.record_len();
+        match index.remove(&key) {
+            Some(entry) => {
+                *index_bytes -= index_entry_size(&key);
+                current(&entry)
+            }
+            None => return,
+        }
+    };
+    retire(history, stale_bytes, &key, prev);
+}
+
+/// Account for `prev`, the record `key` no longer points to. It becomes a version of the
+/// history, which may push out an older one, unless it is a merge operand: a value made of
+/// operands isn't kept as a version, and its records turn stale.
+fn retire(history: &History, stale_bytes: &mut HashMap<u64, u64>, key: &[u8], prev: Offset) {
+    if let Some(chain) = &prev.merged {
+        for record in chain.records.iter().chain(Some(&prev)) {
+            *stale_bytes.entry(record.segment).or_insert(0) += record.record_len();
+        }
+        return;
+    }
+    if let Some(stale) = history.push(key, prev) {
+        *stale_bytes.entry(stale.segment).or_insert(0) += stale.record_len();
+    }
+}
+
+/// Drop the keys that start with `prefix` from the index, for the range tombstone at `offset`,
+/// and account for the bytes made stale by the write. `removed` is called with each key dropped
+/// and the offset it pointed to.
+fn remove_range(
+    index: &Index,
+    history: &History,
+    stale_bytes: &mut HashMap<u64, u64>,
+    index_bytes: &mut u64,
+    prefix: &[u8],
+    offset: Offset,
+    mut removed: impl FnMut(&[u8], &Offset),
+) {
+    // the tombstone itself is garbage
+    *stale_bytes.entry(offset.segment).or_insert(0) += offset.record_len();
+    for entry in index.range(prefix_range(prefix)) {
+        if !entry.remove() {
+            continue;
+        }
+        *index_bytes -= index_entry_size(entry.key());
+        let prev = current(&entry);
+        removed(entry.key(), &prev);
+        retire(history, stale_bytes, entry.key(), prev);
+    }
+}
+
+/// The memory the index entry of `key` takes up, as estimated for `index_memory_limit`.
+fn index_entry_size(key: &[u8]) -> u64 {
+    (INDEX_ENTRY_OVERHEAD + IndexKey::heap_size(key.len())) as u64
+}
+
+/// Read the value of the record at `offset`.
+fn read_value(offset: &Offset) -> Result<Option<String>> {
+    match offset.read_pair()?.value {
+        Some(value) => Ok(Some(String::from_utf8(value)?)),
+        None => Ok(None),
+    }
+}
+
+/// The offset of `key` if it is in the index and not expired.
+fn live_offset(index: &Index, key: &[u8]) -> Option<Offset> {
+    index
+        .get(key)
+        .map(|entry| current(&entry))
+        .filter(|offset| !offset.is_expired(now_millis()))
+}
+
+/// Whether `key` is in the index and not expired.
+fn contains_live(index: &Index, key: &[u8]) -> bool {
+    index
+        .get(key)
+        .is_some_and(|entry| !current(&entry).is_expired(now_millis()))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// The offset an index entry points to at the moment.
+fn current(entry: &Entry<'_>) -> Offset {
+    entry.value().read().expect("index lock poisoned").clone()
+}
+
+/// The bytes left on the file system that holds `dir` for unprivileged users.
+#[cfg(unix)]
+fn free_space(dir: &Path) -> io::Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat = mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is NUL-terminated, and `statvfs` fills in `stat` when it succeeds.
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    // the fields are narrower than 64 bits on some systems
+    #[allow(clippy::useless_conversion)]
+    Ok(Some(u64::from(stat.f_bavail) * u64::from(stat.f_frsize)))
+}
+
+// The free space isn't known on other systems, where only a full disk turns writes down.
+#[cfg(not(unix))]
+fn free_space(_dir: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Take the exclusive lock on the store in `dir`, which is held until the returned file is closed.
+/// Fails with `StoreLocked` if another handle holds it.
+fn lock_dir(dir: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(StoreLocked),
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
+
+/// Return the ids of the segments in `dir` in ascending order.
+fn segment_ids(dir: &Path) -> Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() != Some(OsStr::new("log")) {
+            continue;
+        }
+        if let Some(id) = path
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .and_then(|s| s.parse::<u64>().ok())
+        {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+impl KvsEngine for KvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        KvStore::get_many(self, keys)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        KvStore::remove(self, key)
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(KvStore::contains_key(self, key))
+    }
+
+    fn set_numbered(&self, key: String, value: String) -> Result<Option<u64>> {
+        self.set_bytes_numbered(key.as_bytes(), value.as_bytes())
+            .map(Some)
+    }
+
+    fn remove_numbered(&self, key: String) -> Result<Option<u64>> {
+        self.remove_bytes_numbered(key.as_bytes()).map(Some)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        KvStore::compare_and_swap(self, key, expected, new)
+    }
+
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        KvStore::increment(self, key, delta)
+    }
+
+    fn update<F>(&self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnMut(Option<String>) -> Option<String>,
+    {
+        KvStore::update(self, key, f)
+    }
+
+    fn rename(&self, old_key: String, new_key: String) -> Result<()> {
+        KvStore::rename(self, old_key, new_key)
+    }
+
+    fn copy(&self, src: String, dst: String) -> Result<()> {
+        KvStore::copy(self, src, dst)
+    }
+
+    fn set_reader<R: io::Read>(&self, key: String, reader: R, len: u64) -> Result<()> {
+        KvStore::set_reader(self, key, reader, len)
+    }
+
+    fn get_writer<W: io::Write>(&self, key: String, writer: W) -> Result<bool> {
+        KvStore::get_writer(self, key, writer)
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        KvStore::stats(self)
+    }
+
+    fn flush(&self) -> Result<()> {
+        KvStore::flush(self)
+    }
+
+    fn as_kv_store(&self) -> Option<&KvStore> {
+        Some(self)
+    }
+}
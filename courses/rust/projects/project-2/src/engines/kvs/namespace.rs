@@ -0,0 +1,176 @@
+//! Namespaces, sets of keys isolated from each other within a store.
+//!
+//! The keys of a namespace are stored with the prefix `\0<name>\0`, so they sort together and
+//! no two namespaces share a key. Scans of the store leave out the keys that start with `\0`,
+//! which are reserved for namespaces.
+//!
+//! Dropping a namespace doesn't write a tombstone for each of its keys: they are removed from
+//! the index, and the manifest records which segments can hold their records, which `open`
+//! ignores and compaction leaves out.
+
+use std::io;
+use std::time::Duration;
+
+use log::info;
+
+use super::manifest::DroppedNamespace;
+use super::{current, index_entry_size, now_millis, prefix_range, KvStore, KvStoreWriter, Scan};
+use crate::error::KvsError::ReadOnly;
+use crate::error::Result;
+
+// The first byte of the keys of every namespace.
+pub(super) const NAMESPACE_MARKER: u8 = b'\0';
+
+/// The prefix of the keys of the namespace `name`.
+pub(super) fn namespace_prefix(name: &str) -> String {
+    format!("\0{}\0", name)
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains('\0') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid namespace name {:?}", name),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// A set of keys isolated from those of the store and of other namespaces, created by
+/// `KvStore::namespace`.
+///
+/// Like the store, it is cheap to clone and can be shared between threads. It holds a clone of
+/// the store, which stays open until the handle is dropped too.
+#[derive(Debug, Clone)]
+pub struct Namespace {
+    store: KvStore,
+    name: String,
+    // prepended to the keys of the namespace
+    prefix: String,
+}
+
+impl KvStore {
+    /// A handle on the keys of the namespace `name`, which exists as long as it holds keys.
+    ///
+    /// Fails with an `InvalidInput` error if the name is empty or holds a NUL character.
+    pub fn namespace(&self, name: &str) -> Result<Namespace> {
+        check_name(name)?;
+        Ok(Namespace {
+            store: self.clone(),
+            name: name.to_owned(),
+            prefix: namespace_prefix(name),
+        })
+    }
+
+    /// Remove every key of the namespace `name` at once. The disk space they take up is
+    /// reclaimed by compaction. Keys set in the namespace afterwards are kept.
+    ///
+    /// Watchers aren't told about the keys removed, and followers start over from a full copy of
+    /// the store.
+    pub fn drop_namespace(&self, name: &str) -> Result<()> {
+        check_name(name)?;
+        self.write(|writer| writer.drop_namespace(name))
+    }
+}
+
+impl KvStoreWriter {
+    fn drop_namespace(&mut self, name: &str) -> Result<()> {
+        if self.options.read_only {
+            return Err(ReadOnly);
+        }
+        // keys set in the namespace from now on go to a segment the drop doesn't cover
+        if self.active_size > self.active_file.data_start() {
+            self.seal()?;
+        }
+        let through = self.active_segment - 1;
+
+        let range = prefix_range(namespace_prefix(name).as_bytes());
+        let mut removed = 0;
+        for entry in self.index.range(range.clone()) {
+            let offset = current(&entry);
+            if entry.remove() {
+                *self.stale_bytes.entry(offset.segment).or_default() += offset.record_len();
+                self.index_bytes -= index_entry_size(entry.key());
+                self.cache.invalidate(entry.key());
+                self.written(entry.key(), None);
+                removed += 1;
+            }
+        }
+        for offset in self.history.remove_range(range) {
+            *self.stale_bytes.entry(offset.segment).or_default() += offset.record_len();
+        }
+
+        match self.dropped.iter_mut().find(|dropped| dropped.name == name) {
+            Some(dropped) => dropped.through = through,
+            None => self.dropped.push(DroppedNamespace {
+                name: name.to_owned(),
+                through,
+            }),
+        }
+        self.save_manifest()?;
+        // the followers would keep the keys otherwise
+        self.replication.restart();
+        info!("Dropped namespace {} with {} keys", name, removed);
+        self.check_compaction();
+        Ok(())
+    }
+}
+
+impl Namespace {
+    /// The name of the namespace.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Set a key of the namespace.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.store.set(self.key(&key), value)
+    }
+
+    /// Set a key of the namespace that expires after `ttl`.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.store.set_with_ttl(self.key(&key), value, ttl)
+    }
+
+    /// Retrieve the value of a key of the namespace.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.store.get(self.key(&key))
+    }
+
+    /// Remove a key of the namespace. Fails with `KeyNotFound` if it doesn't exist.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.store.remove(self.key(&key))
+    }
+
+    /// Remove every key of the namespace with a single range tombstone, and return how many there
+    /// were. Unlike `KvStore::drop_namespace`, watchers are told about the keys removed and
+    /// followers remove them too.
+    pub fn clear(&self) -> Result<usize> {
+        self.store.remove_prefix(&self.prefix)
+    }
+
+    /// Iterate over the key-value pairs of the namespace whose keys start with `prefix`, in
+    /// sorted key order.
+    pub fn scan_prefix(&self, prefix: &str) -> Scan<'_> {
+        let mut scan = self.store.scan_prefix(&self.key(prefix));
+        scan.namespace = Some(self.prefix.len());
+        scan
+    }
+
+    /// Iterate over all the keys of the namespace in sorted order.
+    pub fn keys(&self) -> impl Iterator<Item = String> + '_ {
+        let now = now_millis();
+        self.store
+            .index
+            .range(prefix_range(self.prefix.as_bytes()))
+            .filter(move |entry| !current(entry).is_expired(now))
+            .map(move |entry| {
+                String::from_utf8_lossy(&entry.key()[self.prefix.len()..]).into_owned()
+            })
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
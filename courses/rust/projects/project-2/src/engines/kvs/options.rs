@@ -0,0 +1,439 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::crypto::EncryptionKey;
+use super::eviction::EvictionPolicy;
+use super::merge::MergeOperator;
+use super::policy::CompactionPolicy;
+use super::progress::{OpenProgress, ProgressCallback};
+use super::segment::{BATCH_FLAG, DEFAULT_MAX_RECORD_SIZE};
+use super::storage::SegmentStorage;
+use super::KvStore;
+use crate::error::KvsError::InvalidEncryptionKey;
+use crate::error::Result;
+
+/// When the writes to a `KvStore` are synced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Leave it to the operating system. Writes survive the process crashing but may be lost if
+    /// the machine does.
+    Never,
+    /// Sync every write before it returns.
+    Always,
+    /// Sync every write before it returns, but sync the writes made at the same time together,
+    /// from a background thread. Concurrent writers wait less than with `Always`, while a single
+    /// writer waits about as long.
+    Group,
+}
+
+/// What a write to a `KvStore` does once compaction fell behind, past the high-water marks of
+/// `KvStoreOptions::stall_stale_ratio` and `stall_segments`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Wait up to the given time for compaction to catch up, and then write anyway. Writers are
+    /// slowed down to the pace of compaction, and a write is never turned down.
+    Block(Duration),
+    /// Turn the write down with `KvsError::Backpressure`, for the caller to retry it later.
+    Fail,
+}
+
+/// Options for opening a `KvStore`, created by `KvStore::options`.
+///
+/// ```no_run
+/// # use kvs::{KvStore, SyncPolicy};
+/// let store = KvStore::options()
+///     .segment_size(16 * 1024 * 1024)
+///     .sync_policy(SyncPolicy::Always)
+///     .open("db")?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(super) segment_size: u64,
+    pub(super) compaction_threshold: u64,
+    pub(super) compaction_policy: Option<Arc<dyn CompactionPolicy>>,
+    pub(super) compression_threshold: Option<usize>,
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) read_only: bool,
+    pub(super) cache_capacity: usize,
+    pub(super) encryption_key: Option<EncryptionKey>,
+    pub(super) replication_backlog: usize,
+    pub(super) versions: usize,
+    pub(super) index_memory_limit: Option<u64>,
+    pub(super) max_keys: Option<u64>,
+    pub(super) max_live_bytes: Option<u64>,
+    pub(super) eviction_policy: EvictionPolicy,
+    pub(super) value_log_threshold: Option<usize>,
+    pub(super) value_log_gc_threshold: f64,
+    pub(super) min_free_space: Option<u64>,
+    pub(super) on_open_progress: Option<ProgressCallback>,
+    pub(super) merge_operator: Option<MergeOperator>,
+    pub(super) cold_dir: Option<PathBuf>,
+    pub(super) cold_storage: Option<Arc<dyn SegmentStorage>>,
+    pub(super) stall_stale_ratio: Option<f64>,
+    pub(super) stall_segments: Option<usize>,
+    pub(super) backpressure: Backpressure,
+    pub(super) slow_log_threshold: Option<Duration>,
+    pub(super) max_record_size: u64,
+    pub(super) key_sampling: Option<u32>,
+    pub(super) bloom_false_positive_rate: Option<f64>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            segment_size: 4 * 1024 * 1024,
+            compaction_threshold: 4 * 1024 * 1024,
+            compaction_policy: None,
+            compression_threshold: Some(1024),
+            sync_policy: SyncPolicy::Never,
+            read_only: false,
+            cache_capacity: 8 * 1024 * 1024,
+            encryption_key: None,
+            replication_backlog: 1024 * 1024,
+            versions: 1,
+            index_memory_limit: None,
+            max_keys: None,
+            max_live_bytes: None,
+            eviction_policy: EvictionPolicy::LeastRecentlyWritten,
+            value_log_threshold: None,
+            value_log_gc_threshold: 0.5,
+            min_free_space: None,
+            on_open_progress: None,
+            merge_operator: None,
+            cold_dir: None,
+            cold_storage: None,
+            stall_stale_ratio: None,
+            stall_segments: None,
+            backpressure: Backpressure::Block(Duration::from_millis(100)),
+            slow_log_threshold: None,
+            max_record_size: DEFAULT_MAX_RECORD_SIZE,
+            key_sampling: None,
+            bloom_false_positive_rate: Some(0.01),
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Start a new segment once the active one grows past `bytes`. Defaults to 4 MiB.
+    pub fn segment_size(&mut self, bytes: u64) -> &mut KvStoreOptions {
+        self.segment_size = bytes;
+        self
+    }
+
+    /// Start compacting once the segments that are no longer written to hold `bytes` bytes of
+    /// stale data, i.e. records that have been overwritten or removed. Defaults to 4 MiB.
+    ///
+    /// The threshold of `StaleBytes`, the compaction policy of a store without one, and ignored
+    /// with a `compaction_policy`.
+    pub fn compaction_threshold(&mut self, bytes: u64) -> &mut KvStoreOptions {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Decide when to compact, and which segment first, with `policy`. Defaults to `StaleBytes`
+    /// with the `compaction_threshold`. See the `policy` module.
+    ///
+    /// ```no_run
+    /// # use kvs::{DeadRatio, KvStore, ManualOnly};
+    /// // compact each segment once half of it is stale, but only when asked
+    /// let store = KvStore::options()
+    ///     .compaction_policy(ManualOnly(DeadRatio(0.5)))
+    ///     .open("db")?;
+    /// store.compact_now()?;
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    pub fn compaction_policy(
+        &mut self,
+        policy: impl CompactionPolicy + 'static,
+    ) -> &mut KvStoreOptions {
+        self.compaction_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Compress the values longer than `bytes`, or none if it is `None`. Defaults to 1 KiB.
+    ///
+    /// Values are compressed with LZ4, and only kept compressed if that makes them shorter.
+    pub fn compression_threshold(&mut self, bytes: Option<usize>) -> &mut KvStoreOptions {
+        self.compression_threshold = bytes;
+        self
+    }
+
+    /// When writes are synced to disk. Defaults to `SyncPolicy::Never`.
+    pub fn sync_policy(&mut self, policy: SyncPolicy) -> &mut KvStoreOptions {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Open the store without ever writing to it. Writes fail with `KvsError::ReadOnly`, and the
+    /// store must already exist.
+    pub fn read_only(&mut self, read_only: bool) -> &mut KvStoreOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Keep up to `bytes` bytes of recently read keys and values in memory, or none if it is
+    /// zero. Defaults to 8 MiB.
+    pub fn cache_capacity(&mut self, bytes: usize) -> &mut KvStoreOptions {
+        self.cache_capacity = bytes;
+        self
+    }
+
+    /// Encrypt the records written to disk with `key`, which must be the key the store was
+    /// encrypted with if it already is. Defaults to no encryption.
+    ///
+    /// Opening an encrypted store without its key fails with `KvsError::InvalidEncryptionKey`.
+    /// Opening a plaintext store with a key encrypts the records written from then on, and
+    /// compaction encrypts the existing ones as it rewrites them; `KvStore::compact` rewrites all
+    /// of them. Hint files aren't written for encrypted segments, since they would hold the keys
+    /// in plaintext.
+    pub fn encryption_key(&mut self, key: [u8; 32]) -> &mut KvStoreOptions {
+        self.encryption_key = Some(EncryptionKey(key));
+        self
+    }
+
+    /// Encrypt the store with the key in a file, which holds either the 32 bytes of the key or
+    /// 64 hex digits. See `encryption_key`.
+    ///
+    /// Fails with `KvsError::InvalidEncryptionKey` if the file holds neither.
+    pub fn encryption_key_file(&mut self, path: impl AsRef<Path>) -> Result<&mut KvStoreOptions> {
+        let contents = fs::read(path)?;
+        let mut key = [0; 32];
+        if contents.len() == key.len() {
+            key.copy_from_slice(&contents);
+        } else {
+            let hex = std::str::from_utf8(&contents).map_err(|_| InvalidEncryptionKey)?;
+            let hex = hex.trim();
+            if hex.len() != 2 * key.len() || !hex.is_ascii() {
+                return Err(InvalidEncryptionKey);
+            }
+            for (i, byte) in key.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                    .map_err(|_| InvalidEncryptionKey)?;
+            }
+        }
+        Ok(self.encryption_key(key))
+    }
+
+    /// Keep up to `bytes` bytes of the latest writes in memory for followers to catch up from
+    /// when they connect again. A follower that missed more copies the whole store again.
+    /// Defaults to 1 MiB.
+    pub fn replication_backlog(&mut self, bytes: usize) -> &mut KvStoreOptions {
+        self.replication_backlog = bytes;
+        self
+    }
+
+    /// Keep the last `versions` values of each key, the current one included, for
+    /// `KvStore::get_version` and `KvStore::history`. Compaction only reclaims the older ones.
+    /// Defaults to 1, which keeps only the current value.
+    ///
+    /// The older versions are found again on open by replaying the segments, so a store must be
+    /// opened with the same number of versions every time to get them all back.
+    pub fn versions(&mut self, versions: usize) -> &mut KvStoreOptions {
+        self.versions = versions.max(1);
+        self
+    }
+
+    /// Turn down writes that would add keys once the index of the keys takes up about `bytes`
+    /// bytes of memory, with `KvsError::IndexFull`, or never if it is `None`. Keys that exist can
+    /// still be overwritten and removed. Defaults to `None`.
+    ///
+    /// Keys of up to 22 bytes are held in the entry of the index itself, and longer ones in an
+    /// allocation of their own. The memory is estimated from a fixed overhead per key and the
+    /// length of the longer keys, and reported by `KvStore::stats`. A store whose index already
+    /// takes up more is still opened.
+    pub fn index_memory_limit(&mut self, bytes: Option<u64>) -> &mut KvStoreOptions {
+        self.index_memory_limit = bytes;
+        self
+    }
+
+    /// Evict keys once the store holds more than `keys` keys, or never if it is `None`. Defaults
+    /// to `None`.
+    ///
+    /// Evicted keys are removed as if with `KvStore::remove`, in the order `eviction_policy`
+    /// says, right after the write that took the store over the limit. This makes the store a
+    /// persistent bounded cache.
+    pub fn max_keys(&mut self, keys: Option<u64>) -> &mut KvStoreOptions {
+        self.max_keys = keys;
+        self
+    }
+
+    /// Evict keys once the records of the live keys take up more than `bytes` bytes on disk, or
+    /// never if it is `None`. Defaults to `None`. See `max_keys`.
+    ///
+    /// A value whose record alone is larger is evicted right after it is written.
+    pub fn max_live_bytes(&mut self, bytes: Option<u64>) -> &mut KvStoreOptions {
+        self.max_live_bytes = bytes;
+        self
+    }
+
+    /// Which keys to evict first once the store goes over `max_keys` or `max_live_bytes`.
+    /// Defaults to `EvictionPolicy::LeastRecentlyWritten`.
+    pub fn eviction_policy(&mut self, policy: EvictionPolicy) -> &mut KvStoreOptions {
+        self.eviction_policy = policy;
+        self
+    }
+
+    /// Keep the values longer than `bytes` in the value log rather than in the segments, or none
+    /// if it is `None`, so that compaction doesn't copy them. Defaults to `None`.
+    ///
+    /// The values in the value log aren't compressed, and are reclaimed by
+    /// `KvStore::collect_value_log` rather than by compaction. A store keeps reading the values
+    /// already in the value log once the threshold is raised or turned off.
+    pub fn value_log_threshold(&mut self, bytes: Option<usize>) -> &mut KvStoreOptions {
+        self.value_log_threshold = bytes;
+        self
+    }
+
+    /// Have `KvStore::collect_value_log` reclaim a file of the value log once at least `ratio`
+    /// of it is overwritten or removed values, from 0 to 1. Defaults to 0.5.
+    pub fn value_log_gc_threshold(&mut self, ratio: f64) -> &mut KvStoreOptions {
+        self.value_log_gc_threshold = ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Turn down the writes that set keys with `KvsError::DiskFull` once the disk holding the
+    /// store has less than `bytes` bytes free, or only once it is full if it is `None`. Defaults
+    /// to `None`.
+    ///
+    /// Removing keys still works, so that compaction can free up space. Compaction needs room for
+    /// a copy of a segment. The free space is only known on Unix.
+    pub fn min_free_space(&mut self, bytes: Option<u64>) -> &mut KvStoreOptions {
+        self.min_free_space = bytes;
+        self
+    }
+
+    /// Call `callback` with how far opening the store got, after each segment is loaded and
+    /// every 64 MiB of a segment read record by record. See the `progress` module.
+    pub fn on_open_progress(
+        &mut self,
+        callback: impl Fn(&OpenProgress) + Send + Sync + 'static,
+    ) -> &mut KvStoreOptions {
+        self.on_open_progress = Some(ProgressCallback::new(callback));
+        self
+    }
+
+    /// Fold the operands of `KvStore::merge` into the values of their keys with `operator`, which
+    /// is given the key, its value, or `None` if it has none, and the operand, and returns the
+    /// new value. See the `merge` module.
+    ///
+    /// A store that holds operands must be opened with the same operator every time.
+    ///
+    /// ```no_run
+    /// # use kvs::KvStore;
+    /// // counters, whose operands are added to them
+    /// let store = KvStore::options()
+    ///     .merge_operator(|_, value, operand| {
+    ///         let parse = |bytes: &[u8]| String::from_utf8_lossy(bytes).parse::<i64>().unwrap_or(0);
+    ///         let sum = value.map_or(0, parse) + parse(operand);
+    ///         sum.to_string().into_bytes()
+    ///     })
+    ///     .open("db")?;
+    /// store.merge("visits".to_owned(), "1".to_owned())?;
+    /// # Ok::<(), kvs::KvsError>(())
+    /// ```
+    pub fn merge_operator(
+        &mut self,
+        operator: impl Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) -> &mut KvStoreOptions {
+        self.merge_operator = Some(MergeOperator::new(operator));
+        self
+    }
+
+    /// Move the segments compaction rewrote to `dir`, e.g. on a slower and cheaper disk, or keep
+    /// them all in the directory of the store if it is `None`. Defaults to `None`.
+    ///
+    /// Like `cold_storage` with a `LocalStorage` in `dir`, which is created if it doesn't exist
+    /// when the store is opened.
+    pub fn cold_dir(&mut self, dir: Option<PathBuf>) -> &mut KvStoreOptions {
+        self.cold_dir = dir;
+        self.cold_storage = None;
+        self
+    }
+
+    /// Move the segments compaction rewrote to `storage`, and read them from there. Defaults to
+    /// keeping them all in the directory of the store. See the `storage` module.
+    ///
+    /// The active segment, the hints, the blobs and the value log stay in the directory of the
+    /// store. The manifest records where each segment is, so a store opened without the option,
+    /// or with another storage, leaves the segments moved so far where they are. It can only
+    /// read them from a storage other than a local directory if it is given that storage.
+    pub fn cold_storage(&mut self, storage: impl SegmentStorage + 'static) -> &mut KvStoreOptions {
+        self.cold_storage = Some(Arc::new(storage));
+        self.cold_dir = None;
+        self
+    }
+
+    /// Hold the writes back once more than `ratio` of the bytes of the sealed segments is stale,
+    /// from 0 to 1, or never if it is `None`, until compaction brings it back down. Defaults to
+    /// `None`. See `backpressure`.
+    ///
+    /// The stale data only counts once there is enough of it to compact, per
+    /// `compaction_threshold`.
+    pub fn stall_stale_ratio(&mut self, ratio: Option<f64>) -> &mut KvStoreOptions {
+        self.stall_stale_ratio = ratio.map(|ratio| ratio.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Hold the writes back once the store has more than `segments` segments, or never if it is
+    /// `None`, until compaction empties some of them. Defaults to `None`. See `backpressure`.
+    pub fn stall_segments(&mut self, segments: Option<usize>) -> &mut KvStoreOptions {
+        self.stall_segments = segments;
+        self
+    }
+
+    /// What the writes do once compaction fell behind, past `stall_stale_ratio` or
+    /// `stall_segments`. Defaults to `Backpressure::Block` for 100 ms.
+    ///
+    /// Only the writes made through the store are held back, not those a follower applies from
+    /// its leader.
+    pub fn backpressure(&mut self, backpressure: Backpressure) -> &mut KvStoreOptions {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Log the calls to `get`, `set` and `remove`, and their `_bytes` versions, that take at
+    /// least `threshold`, or none if it is `None`. Defaults to `None`. See the `slowlog` module.
+    pub fn slow_log_threshold(&mut self, threshold: Option<Duration>) -> &mut KvStoreOptions {
+        self.slow_log_threshold = threshold;
+        self
+    }
+
+    /// Fail writes whose record would be longer than `bytes` with `KvsError::RecordTooLarge`,
+    /// a batch counting as one record, and fail the same way to read a record on disk that
+    /// claims to be longer, rather than allocating for a length that may be corrupt. Defaults to
+    /// 256 MiB, and can't go past 2 GiB, the most the length of a record can hold.
+    pub fn max_record_size(&mut self, bytes: u64) -> &mut KvStoreOptions {
+        self.max_record_size = bytes.min(u64::from(!BATCH_FLAG));
+        self
+    }
+
+    /// Sample one in every `every` reads and writes of the keys, for `KvStore::hot_keys`, or none
+    /// if it is `None`. Defaults to `None`. See the `hotkeys` module.
+    ///
+    /// Sampling takes 256 KiB of memory for the counters, and a little time on the reads and
+    /// writes it samples, which `every` spreads out.
+    pub fn key_sampling(&mut self, every: Option<u32>) -> &mut KvStoreOptions {
+        self.key_sampling = every;
+        self
+    }
+
+    /// Write a Bloom filter of the keys of each segment along with its hint, which takes a key
+    /// the segment doesn't hold for one `rate` of the time, or none if it is `None` or not
+    /// between 0 and 1. Defaults to 1%. See the `bloom` module.
+    ///
+    /// A filter takes up about 1.44 bits per key for every halving of the rate, so 10 bits per
+    /// key at 1%, on disk only. Like hints, filters aren't written for an encrypted store, as
+    /// they would tell which keys it holds.
+    pub fn bloom_false_positive_rate(&mut self, rate: Option<f64>) -> &mut KvStoreOptions {
+        self.bloom_false_positive_rate = rate.filter(|rate| *rate > 0.0 && *rate < 1.0);
+        self
+    }
+
+    /// Open the store in a directory with these options.
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path.into(), self.clone())
+    }
+}
@@ -0,0 +1,125 @@
+//! Keeping the files that scans, snapshots and replays read from until they are dropped.
+//!
+//! Compaction and the garbage collection of the value log remove the files they emptied: the
+//! segments left without a live record, the blobs of the records they dropped, and the value log
+//! files whose values they moved. A scan, snapshot or replay may still hold offsets into them,
+//! and reads blobs and value log files by path, so the files it may read must outlive it.
+//!
+//! Each removal starts a new generation, and each reader pins the generation it started in. A
+//! file removed in a generation no reader pinned is removed at once. Otherwise its removal is
+//! deferred until the last reader pinned before it is dropped. A store closed while readers are
+//! left, or a crash, leaves the deferred files behind: the segments are removed the next time the
+//! store is opened, and the value log files the next time the value log is collected.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+
+use crate::error::Result;
+
+/// The generations pinned by the readers of a store, and the files waiting for them.
+#[derive(Debug, Default)]
+pub(super) struct Pins {
+    state: Mutex<PinState>,
+}
+
+#[derive(Debug, Default)]
+struct PinState {
+    // bumped by each removal
+    generation: u64,
+    // the number of readers that pinned each generation
+    pinned: BTreeMap<u64, usize>,
+    // the files removed while readers were pinned, with the generation they were removed in
+    deferred: Vec<(u64, PathBuf)>,
+}
+
+impl Pins {
+    /// Pin the current generation, until the pin is dropped.
+    pub(super) fn pin(self: &Arc<Self>) -> Pin {
+        let mut state = self.state.lock().expect("pins lock poisoned");
+        let generation = state.generation;
+        *state.pinned.entry(generation).or_default() += 1;
+        Pin {
+            pins: Arc::clone(self),
+            generation,
+        }
+    }
+
+    /// Remove `paths` once no reader that may read them is left.
+    pub(super) fn remove_files(&self, paths: Vec<PathBuf>) -> Result<()> {
+        let mut state = self.state.lock().expect("pins lock poisoned");
+        state.generation += 1;
+        if state.pinned.is_empty() {
+            drop(state);
+            for path in paths {
+                remove(&path)?;
+            }
+        } else {
+            let generation = state.generation;
+            state
+                .deferred
+                .extend(paths.into_iter().map(|path| (generation, path)));
+        }
+        Ok(())
+    }
+}
+
+// Remove a file. One that is already gone is skipped.
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            warn!("{} was already removed", path.display());
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// A generation pinned by a reader, which keeps the files removed since until it is dropped.
+#[derive(Debug)]
+pub(super) struct Pin {
+    pins: Arc<Pins>,
+    generation: u64,
+}
+
+impl Clone for Pin {
+    fn clone(&self) -> Self {
+        let mut state = self.pins.state.lock().expect("pins lock poisoned");
+        *state.pinned.entry(self.generation).or_default() += 1;
+        Pin {
+            pins: Arc::clone(&self.pins),
+            generation: self.generation,
+        }
+    }
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        let removable = {
+            let mut state = self.pins.state.lock().expect("pins lock poisoned");
+            if let Some(count) = state.pinned.get_mut(&self.generation) {
+                *count -= 1;
+                if *count == 0 {
+                    state.pinned.remove(&self.generation);
+                }
+            }
+            // the files removed after the oldest generation still pinned are kept
+            let oldest = state.pinned.keys().next().copied().unwrap_or(u64::MAX);
+            let (removable, kept): (Vec<_>, Vec<_>) = state
+                .deferred
+                .drain(..)
+                .partition(|(removed_in, _)| *removed_in <= oldest);
+            state.deferred = kept;
+            removable
+        };
+        for (_, path) in removable {
+            if let Err(err) = remove(&path) {
+                warn!("Failed to remove {}: {}", path.display(), err);
+            }
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! Compaction policies, which decide when a store compacts its segments and which one first.
+//!
+//! Compaction rewrites one sealed segment at a time, leaving out its stale records, unless the
+//! policy merges a run of adjacent segments into one. After each write, the store hands the
+//! policy set with `KvStoreOptions::compaction_policy` the usage of its sealed segments, and
+//! starts compacting in the background if the policy merges or picks some. The compaction thread
+//! then compacts them, one after the other, until it picks none. Without a policy, the store uses
+//! `StaleBytes` with `KvStoreOptions::compaction_threshold`.
+//!
+//! A policy must only pick segments that compaction changes, i.e. that hold stale data, or the
+//! compaction thread keeps rewriting them. `KvStore::compact_now` compacts the segments the policy
+//! picks right away, even with `ManualOnly`, and `KvStore::compact` every segment holding stale
+//! data, whatever the policy.
+
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+/// What a `CompactionPolicy` knows of a sealed segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentUsage {
+    /// The id of the segment
+    pub segment: u64,
+    /// Bytes of records in the segment, without its file header
+    pub size: u64,
+    /// Bytes of records that compaction would reclaim, i.e. those overwritten or removed
+    pub stale: u64,
+    /// When the segment was written, by compaction for a segment it rewrote, if its format
+    /// records it
+    pub created: Option<SystemTime>,
+}
+
+impl SegmentUsage {
+    /// The share of the segment that is stale, from 0 to 1.
+    pub fn stale_ratio(&self) -> f64 {
+        if self.size == 0 {
+            return 0.0;
+        }
+        self.stale as f64 / self.size as f64
+    }
+}
+
+/// Decides when a store compacts, and which segment first. See the `policy` module.
+pub trait CompactionPolicy: fmt::Debug + Send + Sync {
+    /// The segment to compact next, out of the sealed segments of the store in `segments`,
+    /// oldest first, or `None` to leave them as they are for now. Called after each write, so it
+    /// should be cheap.
+    fn pick(&self, segments: &[SegmentUsage]) -> Option<u64>;
+
+    /// The run of segments to merge into one next, out of `segments` as for `pick`, or `None` to
+    /// pick a single segment instead. Asked before `pick`. The run must list at least two
+    /// segments, each next to the one before in `segments`, or the store ignores it: a segment
+    /// left out in between could hold a record that the merged ones shadow. Defaults to `None`.
+    fn merge(&self, _segments: &[SegmentUsage]) -> Option<Vec<u64>> {
+        None
+    }
+
+    /// Whether the store compacts the segments the policy picks by itself, in the background,
+    /// rather than only when `KvStore::compact_now` asks.
+    fn background(&self) -> bool {
+        true
+    }
+}
+
+/// Compact once the sealed segments hold at least the given number of stale bytes, altogether,
+/// the segment with the most first. The policy of a store without one, with
+/// `KvStoreOptions::compaction_threshold`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaleBytes(pub u64);
+
+impl CompactionPolicy for StaleBytes {
+    fn pick(&self, segments: &[SegmentUsage]) -> Option<u64> {
+        let stale: u64 = segments.iter().map(|usage| usage.stale).sum();
+        if stale == 0 || stale < self.0 {
+            return None;
+        }
+        most_stale(segments)
+    }
+}
+
+/// Compact once the stale bytes of the sealed segments make up at least the given share of their
+/// size, from 0 to 1, the segment with the most first. The stale data the store puts up with
+/// grows along with it, so that a large store compacts as often, for its size, as a small one,
+/// and the disk space it takes up stays within a bound of its live data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TotalStaleRatio(pub f64);
+
+impl CompactionPolicy for TotalStaleRatio {
+    fn pick(&self, segments: &[SegmentUsage]) -> Option<u64> {
+        let size: u64 = segments.iter().map(|usage| usage.size).sum();
+        let stale: u64 = segments.iter().map(|usage| usage.stale).sum();
+        if stale == 0 || (stale as f64) < self.0 * size as f64 {
+            return None;
+        }
+        most_stale(segments)
+    }
+}
+
+/// Compact each sealed segment whose stale bytes make up at least the given share of it, from 0
+/// to 1, the one with the highest share first. Each compaction then reclaims at least that share
+/// of what it reads, whatever the size of the store.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeadRatio(pub f64);
+
+impl CompactionPolicy for DeadRatio {
+    fn pick(&self, segments: &[SegmentUsage]) -> Option<u64> {
+        segments
+            .iter()
+            .filter(|usage| usage.stale > 0 && usage.stale_ratio() >= self.0)
+            .max_by(|a, b| a.stale_ratio().total_cmp(&b.stale_ratio()))
+            .map(|usage| usage.segment)
+    }
+}
+
+/// Compact each sealed segment that holds stale data once it was written longer than the given
+/// time ago, the oldest first, however little stale data it holds. The space of removed keys is
+/// then reclaimed within a bounded time, e.g. to erase them from the disk, at a steady pace. A
+/// segment written in a format that doesn't record when is taken for an old one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeBased(pub Duration);
+
+impl CompactionPolicy for TimeBased {
+    fn pick(&self, segments: &[SegmentUsage]) -> Option<u64> {
+        let now = SystemTime::now();
+        segments
+            .iter()
+            .filter(|usage| usage.stale > 0)
+            .filter(|usage| {
+                usage
+                    .created
+                    .is_none_or(|created| now.duration_since(created).unwrap_or_default() >= self.0)
+            })
+            .min_by_key(|usage| usage.created)
+            .map(|usage| usage.segment)
+    }
+}
+
+/// Merge each run of at least the given number of adjacent sealed segments whose sizes are
+/// within a factor of two of each other, the oldest run first, leaving out their stale records
+/// along the way. Small segments are merged into larger ones, which are in turn merged once
+/// there are enough of them, so that the number of segments grows with the logarithm of the size
+/// of the store rather than with it, and each record is rewritten that many times at most.
+/// Segments are never compacted on their own, so stale data is only reclaimed as they merge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeTiered(pub usize);
+
+impl CompactionPolicy for SizeTiered {
+    fn pick(&self, _segments: &[SegmentUsage]) -> Option<u64> {
+        None
+    }
+
+    fn merge(&self, segments: &[SegmentUsage]) -> Option<Vec<u64>> {
+        let min_run = self.0.max(2);
+        let mut start = 0;
+        while start + min_run <= segments.len() {
+            let (mut smallest, mut largest) = (segments[start].size, segments[start].size);
+            let mut end = start + 1;
+            while let Some(usage) = segments.get(end) {
+                let (low, high) = (smallest.min(usage.size), largest.max(usage.size));
+                if high > low.saturating_mul(2) {
+                    break;
+                }
+                smallest = low;
+                largest = high;
+                end += 1;
+            }
+            if end - start >= min_run {
+                return Some(
+                    segments[start..end]
+                        .iter()
+                        .map(|usage| usage.segment)
+                        .collect(),
+                );
+            }
+            start += 1;
+        }
+        None
+    }
+}
+
+/// Never compact in the background: the segments the given policy picks are only compacted when
+/// `KvStore::compact_now` asks, e.g. at a quiet time of the day.
+///
+/// The high-water mark of `KvStoreOptions::stall_segments` still starts compacting if it is set,
+/// so that the writes it holds back can go on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ManualOnly<P>(pub P);
+
+impl<P: CompactionPolicy> CompactionPolicy for ManualOnly<P> {
+    fn pick(&self, segments: &[SegmentUsage]) -> Option<u64> {
+        self.0.pick(segments)
+    }
+
+    fn merge(&self, segments: &[SegmentUsage]) -> Option<Vec<u64>> {
+        self.0.merge(segments)
+    }
+
+    fn background(&self) -> bool {
+        false
+    }
+}
+
+/// The segment out of `segments` with the most stale bytes, if one has some.
+fn most_stale(segments: &[SegmentUsage]) -> Option<u64> {
+    segments
+        .iter()
+        .filter(|usage| usage.stale > 0)
+        .max_by_key(|usage| usage.stale)
+        .map(|usage| usage.segment)
+}
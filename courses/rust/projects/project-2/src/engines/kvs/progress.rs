@@ -0,0 +1,47 @@
+//! Reporting how far `KvStore::open` got in loading the index.
+//!
+//! Opening a store reads the hint of every segment that has one, and every record of the others,
+//! which takes a while once the log is gigabytes long. A callback set with
+//! `KvStoreOptions::on_open_progress` is called after each segment, and every `REPORT_INTERVAL`
+//! bytes of a segment read record by record, with how much of the log was loaded so far.
+
+use std::fmt;
+use std::sync::Arc;
+
+// How many bytes of a segment are read between two reports.
+pub(super) const REPORT_INTERVAL: u64 = 64 * 1024 * 1024;
+
+/// How far opening a store got, passed to the callback of `KvStoreOptions::on_open_progress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenProgress {
+    /// The segments loaded so far
+    pub segments_loaded: usize,
+    /// The segments of the store
+    pub segments: usize,
+    /// The bytes of the segments loaded so far, counting whole segments loaded from their hints
+    pub bytes_loaded: u64,
+    /// The bytes of all the segments
+    pub bytes: u64,
+    /// The keys in the index so far
+    pub keys: u64,
+}
+
+/// A callback that is told how far opening a store got.
+#[derive(Clone)]
+pub(super) struct ProgressCallback(Arc<dyn Fn(&OpenProgress) + Send + Sync>);
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback(..)")
+    }
+}
+
+impl ProgressCallback {
+    pub(super) fn new(callback: impl Fn(&OpenProgress) + Send + Sync + 'static) -> Self {
+        ProgressCallback(Arc::new(callback))
+    }
+
+    pub(super) fn report(&self, progress: &OpenProgress) {
+        (self.0)(progress)
+    }
+}
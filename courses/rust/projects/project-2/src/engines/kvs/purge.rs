@@ -0,0 +1,215 @@
+//! Purging keys, for when a key must be gone from the files of the store rather than only from
+//! its index, e.g. to erase personal data on request.
+//!
+//! Removing a key appends a tombstone, and leaves the older records of the key in the segments
+//! until compaction rewrites them, which it may never do for a segment with little stale data.
+//! The tombstone itself stays as long as an older segment is left. `KvStore::purge` removes the
+//! key and forgets its older versions, seals the active segment, and has the compaction thread
+//! rewrite every segment up to it that holds a record of the key, leaving all of them out, the
+//! tombstone included. The hints of those segments are rewritten along with them, and the blobs
+//! of the records left out are removed. With a value log, the file values are appended to is
+//! sealed, and every file holding a value of the key is collected, whatever
+//! `KvStoreOptions::value_log_gc_threshold`.
+//!
+//! The purge then reads those segments, their hints and value log files again, and fails with
+//! `KvsError::PurgeIncomplete`, in the file that still holds a record of the key, if one does:
+//! a value log file that also holds older versions of other keys can't be collected, for one.
+//! Writes of the key made while it is purged go to the new segment, and are kept. A segment
+//! whose Bloom filter, written along with its hint, leaves the key out is skipped both times,
+//! without being read.
+//!
+//! A purge only covers the files of the store as they are once it returns. The files it removes
+//! or replaces are unlinked rather than overwritten, and those a scan, snapshot or replay still
+//! reads are only removed once it is dropped, as described in the `pin` module. Backups,
+//! followers and the older versions of objects kept by a storage keep what they hold. A crash
+//! during a purge leaves the key removed, and the purge can be run again.
+
+use std::io;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+
+use log::info;
+
+use super::bloom::read_filter;
+use super::hint::{hint_path, read_hint};
+use super::segment::{KvPair, SegmentFile, TornTail};
+use super::vlog::{find_value, vlog_path};
+use super::{compaction, CompactorMessage, KvStore, KvStoreWriter};
+use crate::error::KvsError::{PurgeIncomplete, ReadOnly};
+use crate::error::Result;
+
+impl KvStore {
+    /// Remove `key`, and every record of it from the files of the store, and wait until they
+    /// are gone. See the `purge` module. A key that doesn't exist is purged all the same, since
+    /// records of it may be left.
+    ///
+    /// Fails with `KvsError::PurgeIncomplete` if a record of the key is still in a file once
+    /// done.
+    pub fn purge(&self, key: String) -> Result<()> {
+        self.purge_bytes(key.as_bytes())
+    }
+
+    /// Like `purge`, with the key given as bytes.
+    pub fn purge_bytes(&self, key: &[u8]) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let (through, vlog_through) = self.write(|writer| writer.start_purge(key, tx))?;
+        let result = rx
+            .recv()
+            .unwrap_or_else(|_| Err(io::Error::other("the compaction thread stopped").into()))
+            .and_then(|_| self.collect_purged_values(key, vlog_through))
+            .and_then(|_| self.check_purged(key, through, vlog_through));
+        let mut writer = self.writer();
+        if let Some(at) = writer.purging.iter().position(|(purged, _)| purged == key) {
+            writer.purging.remove(at);
+        }
+        drop(writer);
+        if result.is_ok() {
+            info!("Purged key {}", String::from_utf8_lossy(key));
+        }
+        result
+    }
+
+    /// Collect the sealed value log files up to `through` that hold a value of `key`.
+    fn collect_purged_values(&self, key: &[u8], through: Option<u64>) -> Result<()> {
+        let (dir, encryption_key, sealed) = {
+            let writer = self.writer();
+            (
+                writer.dir.clone(),
+                writer.options.encryption_key.clone(),
+                writer.value_log.sealed(),
+            )
+        };
+        for id in sealed.into_iter().filter(|&id| Some(id) <= through) {
+            if find_value(&dir, id, encryption_key.as_ref(), key)?.is_some() {
+                self.collect_value_log_file(id, 0.0)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail with `PurgeIncomplete` if a segment up to `through`, its hint, or a value log file up
+    /// to `vlog_through` still holds a record of `key`.
+    fn check_purged(&self, key: &[u8], through: u64, vlog_through: Option<u64>) -> Result<()> {
+        let (dir, encryption_key, segments, sealed) = {
+            let writer = self.writer();
+            let segments: Vec<_> = writer
+                .segments
+                .range(..=through)
+                .map(|(&segment, file)| (segment, file.clone()))
+                .collect();
+            (
+                writer.dir.clone(),
+                writer.options.encryption_key.clone(),
+                segments,
+                writer.value_log.sealed(),
+            )
+        };
+        for (segment, file) in segments {
+            if !may_hold(&dir, segment, &file, key)? {
+                continue;
+            }
+            if let Some(start) = find_record(&file, key)? {
+                return Err(PurgeIncomplete.in_file(&file.path, Some(start)));
+            }
+            let size = file.file.metadata()?.len();
+            let hint = read_hint(&dir, segment, size).unwrap_or_default();
+            if let Some(entry) = hint.iter().find(|entry| !entry.prefix && entry.key == key) {
+                return Err(PurgeIncomplete.in_file(hint_path(&dir, segment), Some(entry.start)));
+            }
+        }
+        for id in sealed.into_iter().filter(|&id| Some(id) <= vlog_through) {
+            if let Some(start) = find_value(&dir, id, encryption_key.as_ref(), key)? {
+                return Err(PurgeIncomplete.in_file(vlog_path(&dir, id), Some(start)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl KvStoreWriter {
+    /// Remove `key` and its older versions, and seal the active segment and the file values are
+    /// appended to, so that the records of the key are all in sealed files. Then ask the
+    /// compaction thread to rewrite the segments holding them, sending the result to `reply`.
+    /// Returns the last segment and the last value log file that can hold them.
+    fn start_purge(&mut self, key: &[u8], reply: Sender<Result<()>>) -> Result<(u64, Option<u64>)> {
+        if self.options.read_only {
+            return Err(ReadOnly);
+        }
+        // the tombstone keeps the key removed if the purge doesn't get to finish
+        if self.index.contains_key(key) {
+            self.append(KvPair {
+                key: key.to_vec(),
+                value: None,
+                expires_at: None,
+                modified_at: None,
+                blob: None,
+                vlog: None,
+                seq: None,
+                prefix: false,
+                merge: false,
+            })?;
+        }
+        let range = (Bound::Included(key.to_vec()), Bound::Included(key.to_vec()));
+        for offset in self.history.remove_range(range) {
+            *self.stale_bytes.entry(offset.segment).or_default() += offset.record_len();
+        }
+        if self.active_size > self.active_file.data_start() {
+            self.seal()?;
+        }
+        let through = self.active_segment - 1;
+        let vlog_through = self.value_log.seal_head()?;
+        self.purging.push((key.to_vec(), through));
+        // the compaction thread only stops once the store is dropped
+        let _ = self
+            .compactor
+            .send(CompactorMessage::Purge(key.to_vec(), reply));
+        Ok((through, vlog_through))
+    }
+}
+
+/// Compact each segment that holds a record of `key`, up to the last one it is purged through.
+/// Runs on the compaction thread, so that no other compaction rewrites them meanwhile.
+pub(super) fn compact_purged(writer: &Mutex<KvStoreWriter>, key: &[u8]) -> Result<()> {
+    let (dir, segments) = {
+        let writer = writer.lock().expect("writer lock poisoned");
+        let through = match writer.purging.iter().find(|(purged, _)| purged == key) {
+            Some(&(_, through)) => through,
+            None => return Ok(()),
+        };
+        let segments: Vec<_> = writer
+            .segments
+            .range(..=through)
+            .map(|(&segment, file)| (segment, file.clone()))
+            .collect();
+        (writer.dir.clone(), segments)
+    };
+    for (segment, file) in segments {
+        if may_hold(&dir, segment, &file, key)? && find_record(&file, key)?.is_some() {
+            compaction(writer, &[segment])?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether `segment` may hold a record of `key`. One whose Bloom filter leaves the key out holds
+/// none, and one without a filter has to be read to tell.
+fn may_hold(dir: &Path, segment: u64, file: &SegmentFile, key: &[u8]) -> Result<bool> {
+    let size = file.file.metadata()?.len();
+    Ok(read_filter(dir, segment, size).is_none_or(|filter| filter.may_contain(key)))
+}
+
+/// Where the data of the first record of `key` starts in `file`, if it holds one. Range
+/// tombstones don't count, as they remove other keys too.
+fn find_record(file: &SegmentFile, key: &[u8]) -> Result<Option<u64>> {
+    let mut found = None;
+    file.for_each_record(TornTail::Fail, |start, _, pair| {
+        if found.is_none() && !pair.prefix && pair.key == key {
+            found = Some(start);
+        }
+        Ok(())
+    })
+    .map_err(|err| err.in_file(&file.path, None))?;
+    Ok(found)
+}
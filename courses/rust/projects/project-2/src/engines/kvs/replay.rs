@@ -0,0 +1,198 @@
+//! Replaying the log of writes of a store, for systems that consume its changes.
+//!
+//! Every set and remove appended to the log is given a sequence number, one more than the
+//! previous write's, which its record holds. `KvStore::replay_from` reads the records of the
+//! segments back in the order of their numbers, from a given one on, so that a consumer can
+//! remember the last number it saw and pick up from there later, even after the store was
+//! reopened. The numbers only go up: the manifest records the last one given, so that the
+//! next write of a reopened store gets a higher one even if compaction removed the records of
+//! the last writes.
+//!
+//! The log is the one the store keeps, not a copy: compaction removes the writes that were
+//! overwritten, the keys that were removed and, once they are in the oldest segment, the
+//! removals themselves. A consumer that falls behind compaction misses them, and only sees the
+//! writes that are left. A key that expired by the time compaction rewrote its record is
+//! turned into a removal with the number it was set with. Records written before sequence
+//! numbers were introduced, and keys loaded with `bulk_load`, have no number and are left out.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use super::pin::Pin;
+use super::segment::{SegmentFile, TornTail};
+use super::KvStore;
+use crate::error::Result;
+
+/// A write read back from the log of a store by `KvStore::replay_from`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    /// A key was set
+    Set {
+        /// The sequence number of the write
+        seq: u64,
+        /// The key set
+        key: Vec<u8>,
+        /// The value it was set to
+        value: Vec<u8>,
+        /// When the key expires, in milliseconds since the Unix epoch
+        expires_at: Option<u64>,
+        /// When the write was made, in milliseconds since the Unix epoch
+        modified_at: Option<u64>,
+    },
+    /// A key was removed, or expired
+    Remove {
+        /// The sequence number of the write
+        seq: u64,
+        /// The key removed
+        key: Vec<u8>,
+        /// When the write was made, in milliseconds since the Unix epoch
+        modified_at: Option<u64>,
+    },
+    /// An operand was merged into the value of a key, by `KvStore::merge`
+    Merge {
+        /// The sequence number of the write
+        seq: u64,
+        /// The key merged into
+        key: Vec<u8>,
+        /// The operand, as it was given rather than folded into the value
+        operand: Vec<u8>,
+        /// When the write was made, in milliseconds since the Unix epoch
+        modified_at: Option<u64>,
+    },
+    /// The keys that start with a prefix were removed, by `KvStore::remove_prefix` or `clear`
+    RemovePrefix {
+        /// The sequence number of the write
+        seq: u64,
+        /// The prefix of the keys removed
+        prefix: Vec<u8>,
+        /// When the write was made, in milliseconds since the Unix epoch
+        modified_at: Option<u64>,
+    },
+}
+
+impl Operation {
+    /// The sequence number of the write.
+    pub fn seq(&self) -> u64 {
+        match self {
+            Operation::Set { seq, .. }
+            | Operation::Remove { seq, .. }
+            | Operation::Merge { seq, .. }
+            | Operation::RemovePrefix { seq, .. } => *seq,
+        }
+    }
+
+    /// The key written, or the prefix of the keys removed.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Operation::Set { key, .. }
+            | Operation::Remove { key, .. }
+            | Operation::Merge { key, .. } => key,
+            Operation::RemovePrefix { prefix, .. } => prefix,
+        }
+    }
+}
+
+/// The writes of a store from a sequence number on, in order, returned by
+/// `KvStore::replay_from`.
+///
+/// Where the records are is read up front, and their keys and values as they are yielded, from
+/// the segments as they were when the replay started. Writes made since aren't yielded.
+pub struct Replay {
+    // the sequence numbers left to yield, with where their records are
+    records: BTreeMap<u64, (Arc<SegmentFile>, u64, usize)>,
+    // keeps the blobs and value log files the records point to
+    _pin: Pin,
+}
+
+impl Iterator for Replay {
+    type Item = Result<Operation>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (seq, (file, start, len)) = self.records.pop_first()?;
+        let pair = match file.read_pair(start, len) {
+            Ok(pair) => pair,
+            Err(err) => {
+                // the rest of the log may be missing as well
+                self.records.clear();
+                return Some(Err(err));
+            }
+        };
+        if pair.prefix {
+            return Some(Ok(Operation::RemovePrefix {
+                seq,
+                prefix: pair.key,
+                modified_at: pair.modified_at,
+            }));
+        }
+        Some(Ok(match pair.value {
+            Some(operand) if pair.merge => Operation::Merge {
+                seq,
+                key: pair.key,
+                operand,
+                modified_at: pair.modified_at,
+            },
+            Some(value) => Operation::Set {
+                seq,
+                key: pair.key,
+                value,
+                expires_at: pair.expires_at,
+                modified_at: pair.modified_at,
+            },
+            None => Operation::Remove {
+                seq,
+                key: pair.key,
+                modified_at: pair.modified_at,
+            },
+        }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.records.len()))
+    }
+}
+
+impl KvStore {
+    /// Read back the writes of the store whose sequence numbers are `seq` or more, in order.
+    ///
+    /// Only the writes the segments still hold are yielded: see the `replay` module. Writes
+    /// batched together have consecutive numbers, and the numbers of failed writes are skipped.
+    pub fn replay_from(&self, seq: u64) -> Result<Replay> {
+        let (segments, dropped, until, pin) = {
+            let writer = self.writer();
+            (
+                writer.segments.clone(),
+                writer.dropped.clone(),
+                writer.sequence,
+                self.pins.pin(),
+            )
+        };
+        let last = segments.keys().next_back().copied();
+        let mut records = BTreeMap::new();
+        for (segment, file) in segments {
+            // the active segment may be appended to while it is read
+            let torn_tail = if Some(segment) == last {
+                TornTail::Ignore
+            } else {
+                TornTail::Fail
+            };
+            file.for_each_record(torn_tail, |start, len, pair| {
+                let in_range = pair.seq.filter(|n| (seq..=until).contains(n));
+                if let Some(n) = in_range {
+                    if !dropped.iter().any(|d| d.covers(segment, &pair.key)) {
+                        // A value moved out of the value log by its garbage collection keeps its
+                        // number, and the copy in the later segment is the one kept.
+                        records.insert(n, (Arc::clone(&file), start, len));
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        Ok(Replay { records, _pin: pin })
+    }
+
+    /// The sequence number of the last write to the store, or 0 if there was none since sequence
+    /// numbers were introduced.
+    pub fn last_seq(&self) -> u64 {
+        self.writer().sequence
+    }
+}
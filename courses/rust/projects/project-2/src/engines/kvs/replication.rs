@@ -0,0 +1,259 @@
+//! The log of writes a store sends to the stores that replicate it.
+//!
+//! Every write, and every batch of writes, is an entry of the log, as is every compaction. Entries
+//! are numbered from 0 each time the store is opened, along with an id that tells the log apart
+//! from the ones of the previous times. The latest entries are kept in a backlog, so that a
+//! follower that lost its connection can catch up from where it was rather than copy the whole
+//! store again.
+
+use std::collections::VecDeque;
+use std::process;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::segment::KvPair;
+use super::{KvStore, Snapshot};
+use crate::error::Result;
+
+// Entries a follower can fall behind by. One further behind is dropped, and catches up from the
+// backlog once it connects again.
+const FOLLOWER_QUEUE: usize = 1024;
+
+// What an entry counts for in the backlog on top of its keys and values.
+const ENTRY_OVERHEAD: usize = 32;
+
+/// A set or remove sent to followers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicatedPair {
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+    pub expires_at: Option<u64>,
+    // when the leader wrote the pair, missing from the pairs of older leaders
+    #[serde(default)]
+    pub modified_at: Option<u64>,
+}
+
+impl From<&KvPair> for ReplicatedPair {
+    fn from(pair: &KvPair) -> ReplicatedPair {
+        ReplicatedPair {
+            key: pair.key.clone(),
+            value: pair.value.clone(),
+            expires_at: pair.expires_at,
+            modified_at: pair.modified_at,
+        }
+    }
+}
+
+impl From<ReplicatedPair> for KvPair {
+    fn from(pair: ReplicatedPair) -> KvPair {
+        KvPair {
+            key: pair.key,
+            value: pair.value,
+            expires_at: pair.expires_at,
+            modified_at: pair.modified_at,
+            blob: None,
+            vlog: None,
+            seq: None,
+            prefix: false,
+            merge: false,
+        }
+    }
+}
+
+/// An entry of the replication log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReplicationEntry {
+    /// Writes applied together: a single one, or a batch
+    Write(Vec<ReplicatedPair>),
+    /// The keys that start with a prefix were removed by a range tombstone
+    RemovePrefix(Vec<u8>),
+    /// An operand was merged into the value of a key
+    Merge {
+        /// The key merged into
+        key: Vec<u8>,
+        /// The operand, which the follower folds in with its own merge operator
+        operand: Vec<u8>,
+    },
+    /// A segment was compacted
+    Compacted,
+}
+
+impl ReplicationEntry {
+    pub(super) fn write(pairs: &[KvPair]) -> ReplicationEntry {
+        ReplicationEntry::Write(pairs.iter().map(ReplicatedPair::from).collect())
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            ReplicationEntry::Write(pairs) => pairs
+                .iter()
+                .map(|pair| {
+                    ENTRY_OVERHEAD + pair.key.len() + pair.value.as_ref().map_or(0, Vec::len)
+                })
+                .sum(),
+            ReplicationEntry::RemovePrefix(prefix) => ENTRY_OVERHEAD + prefix.len(),
+            ReplicationEntry::Merge { key, operand } => ENTRY_OVERHEAD + key.len() + operand.len(),
+            ReplicationEntry::Compacted => ENTRY_OVERHEAD,
+        }
+    }
+}
+
+/// Where a follower starts from, returned by `KvStore::subscribe`.
+pub(crate) enum SyncStart {
+    /// The entries the follower missed
+    Continue(Vec<Arc<ReplicationEntry>>),
+    /// A copy of the store as of entry `offset`, for a follower that has to start over
+    Full { snapshot: Snapshot, offset: u64 },
+}
+
+/// A follower's view of the replication log of a store.
+pub(crate) struct Subscription {
+    /// The id of the log
+    pub(crate) id: String,
+    pub(crate) start: SyncStart,
+    /// The entries written from then on. Disconnected if the follower falls too far behind.
+    pub(crate) entries: Receiver<Arc<ReplicationEntry>>,
+}
+
+#[derive(Debug)]
+pub(super) struct ReplicationLog {
+    id: String,
+    // the number of the next entry
+    offset: u64,
+    // the latest entries, up to `offset`
+    backlog: VecDeque<Arc<ReplicationEntry>>,
+    backlog_bytes: usize,
+    backlog_capacity: usize,
+    followers: Vec<SyncSender<Arc<ReplicationEntry>>>,
+}
+
+impl ReplicationLog {
+    pub(super) fn new(backlog_capacity: usize) -> ReplicationLog {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        ReplicationLog {
+            id: format!("{:x}-{:x}", nanos, process::id()),
+            offset: 0,
+            backlog: VecDeque::new(),
+            backlog_bytes: 0,
+            backlog_capacity,
+            followers: Vec::new(),
+        }
+    }
+
+    /// Start a new log, for writes that aren't entries of it. The followers are dropped, and
+    /// start over from a full copy of the store when they connect again.
+    pub(super) fn restart(&mut self) {
+        *self = ReplicationLog::new(self.backlog_capacity);
+    }
+
+    /// Add an entry, send it to the followers, and forget the followers that were dropped or
+    /// have fallen too far behind.
+    pub(super) fn push(&mut self, entry: ReplicationEntry) {
+        let entry = Arc::new(entry);
+        self.offset += 1;
+        self.backlog_bytes += entry.size();
+        self.backlog.push_back(Arc::clone(&entry));
+        while self.backlog_bytes > self.backlog_capacity {
+            match self.backlog.pop_front() {
+                Some(oldest) => self.backlog_bytes -= oldest.size(),
+                None => break,
+            }
+        }
+        self.followers
+            .retain(|tx| match tx.try_send(Arc::clone(&entry)) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+impl Snapshot {
+    /// The pairs of the snapshot, with when they expire and when they were written.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = Result<ReplicatedPair>> + '_ {
+        self.offsets.iter().map(|(key, offset)| {
+            let pair = offset.read_pair()?;
+            Ok(ReplicatedPair {
+                key: key.clone(),
+                value: Some(pair.value.unwrap_or_default()),
+                expires_at: offset.expires_at,
+                modified_at: pair.modified_at,
+            })
+        })
+    }
+}
+
+impl KvStore {
+    /// Start sending the replication log to a follower that has applied the entries of the log
+    /// `id` before `offset`, or that has nothing yet if `id` is `None`.
+    pub(crate) fn subscribe(&self, id: Option<&str>, offset: u64) -> Subscription {
+        let mut writer = self.writer();
+        let (tx, rx) = mpsc::sync_channel(FOLLOWER_QUEUE);
+        let log = &mut writer.replication;
+        log.followers.push(tx);
+        let backlog_start = log.offset - log.backlog.len() as u64;
+        let start =
+            if id == Some(log.id.as_str()) && backlog_start <= offset && offset <= log.offset {
+                let missed = (offset - backlog_start) as usize;
+                SyncStart::Continue(log.backlog.iter().skip(missed).cloned().collect())
+            } else {
+                SyncStart::Full {
+                    snapshot: self.snapshot_locked(),
+                    offset: log.offset,
+                }
+            };
+        Subscription {
+            id: log.id.clone(),
+            start,
+            entries: rx,
+        }
+    }
+
+    /// Apply writes received from the leader, whether or not the keys they remove exist here.
+    pub(crate) fn apply_replicated(&self, pairs: Vec<ReplicatedPair>) -> Result<()> {
+        self.write_unthrottled(|writer| {
+            writer.write_pairs(pairs.into_iter().map(KvPair::from).collect())
+        })
+    }
+
+    /// Remove the keys that start with `prefix`, since the leader did.
+    pub(crate) fn apply_removed_prefix(&self, prefix: &[u8]) -> Result<()> {
+        self.write_unthrottled(|writer| writer.remove_prefix(prefix))
+            .map(|_| ())
+    }
+
+    /// Merge `operand` into the value of `key`, since the leader did.
+    pub(crate) fn apply_merged(&self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        self.write_unthrottled(|writer| writer.merge(key, operand))
+    }
+
+    /// Remove every key `keep` turns down, e.g. those not copied from the leader.
+    pub(crate) fn retain(&self, keep: impl Fn(&[u8]) -> bool) -> Result<()> {
+        let removed: Vec<KvPair> = self
+            .index
+            .iter()
+            .filter(|entry| !keep(entry.key()))
+            .map(|entry| KvPair {
+                key: entry.key().to_vec(),
+                value: None,
+                expires_at: None,
+                modified_at: None,
+                blob: None,
+                vlog: None,
+                seq: None,
+                prefix: false,
+                merge: false,
+            })
+            .collect();
+        self.write_unthrottled(|writer| writer.write_pairs(removed))
+    }
+
+    /// Compact a segment, if one holds stale data, since the leader did.
+    pub(crate) fn replicate_compaction(&self) {
+        self.writer().request_compaction();
+    }
+}
@@ -0,0 +1,390 @@
+//! A `SegmentStorage` in a bucket of S3, or of a service compatible with it, built with the `s3`
+//! feature.
+//!
+//! Each call is a single request to the endpoint, on a connection of its own, addressing the
+//! bucket in the path, e.g. `/bucket/prefix/12.log`, and signed with AWS Signature Version 4.
+//! The body of a segment is held in memory while it is sent or received, which is fine for
+//! segments of a few MiB.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use sha2::{Digest, Sha256};
+
+use super::storage::SegmentStorage;
+use crate::error::{KvsError, Result};
+use crate::tls::Stream;
+
+// How long a request may wait on the endpoint.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A `SegmentStorage` that keeps the segments as objects in an S3-compatible bucket. See the
+/// `s3` module.
+///
+/// ```no_run
+/// # use kvs::{KvStore, S3Storage};
+/// let storage = S3Storage::new("localhost:9000", "segments", "us-east-1", "key", "secret")
+///     .prefix("db/");
+/// let store = KvStore::options().cold_storage(storage).open("db")?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Clone)]
+pub struct S3Storage {
+    // `host:port`
+    endpoint: String,
+    bucket: String,
+    // prepended to the names of the objects
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    tls: Option<Arc<ClientConfig>>,
+}
+
+impl fmt::Debug for S3Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Storage")
+            .field("endpoint", &self.endpoint)
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("region", &self.region)
+            .field("tls", &self.tls.is_some())
+            .finish()
+    }
+}
+
+impl S3Storage {
+    /// Keep the segments in `bucket`, at the endpoint `host:port` in `region`, signing the
+    /// requests with the given access key. The endpoint is talked to in plain HTTP, unless
+    /// `tls` is set.
+    pub fn new(
+        endpoint: impl Into<String>,
+        bucket: impl Into<String>,
+        region: impl Into<String>,
+        access_key: impl Into<String>,
+        secret_key: impl Into<String>,
+    ) -> S3Storage {
+        S3Storage {
+            endpoint: endpoint.into(),
+            bucket: bucket.into(),
+            prefix: String::new(),
+            region: region.into(),
+            access_key: access_key.into(),
+            secret_key: secret_key.into(),
+            tls: None,
+        }
+    }
+
+    /// Prepend `prefix` to the names of the objects, e.g. `db/`. Defaults to none.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> S3Storage {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Talk to the endpoint over TLS, as configured by `config`, e.g. with the certificates of
+    /// the certificate authorities the system trusts.
+    pub fn tls(mut self, config: Arc<ClientConfig>) -> S3Storage {
+        self.tls = Some(config);
+        self
+    }
+
+    // Send a request for the object `name`, or for the bucket if it is `None`, and return the
+    // status and body of the response.
+    fn request(
+        &self,
+        method: &str,
+        name: Option<&str>,
+        query: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<(u16, Vec<u8>)> {
+        let mut path = format!("/{}", uri_encode(&self.bucket, false));
+        if let Some(name) = name {
+            path.push('/');
+            path.push_str(&uri_encode(&format!("{}{}", self.prefix, name), false));
+        }
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(key, value)| (uri_encode(key, true), uri_encode(value, true)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let payload_hash = hex(&Sha256::digest(body));
+        let amz_date = amz_date(SystemTime::now());
+        let date = &amz_date[..8];
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method,
+            path,
+            query,
+            self.endpoint,
+            payload_hash,
+            amz_date,
+            SIGNED_HEADERS,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut key = hmac(
+            format!("AWS4{}", self.secret_key).as_bytes(),
+            date.as_bytes(),
+        );
+        for part in [self.region.as_bytes(), b"s3", b"aws4_request"] {
+            key = hmac(&key, part);
+        }
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+        let target = if query.is_empty() {
+            path
+        } else {
+            format!("{}?{}", path, query)
+        };
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nx-amz-content-sha256: {}\r\nx-amz-date: {}\r\n\
+             Authorization: AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            method,
+            target,
+            self.endpoint,
+            payload_hash,
+            amz_date,
+            self.access_key,
+            scope,
+            SIGNED_HEADERS,
+            signature,
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(body);
+
+        let socket = TcpStream::connect(&self.endpoint)?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        socket.set_write_timeout(Some(TIMEOUT))?;
+        let host = self
+            .endpoint
+            .rsplit_once(':')
+            .map_or(&*self.endpoint, |(host, _)| host);
+        let server_name = ServerName::try_from(host.to_owned()).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", host, err))
+        })?;
+        let mut stream = Stream::connect(socket, self.tls.as_ref(), server_name)?;
+        stream.write_all(&request)?;
+        stream.flush()?;
+        read_response(BufReader::new(stream))
+    }
+
+    // Fail unless `status` is that of a success.
+    fn check(&self, method: &str, name: &str, status: u16, body: &[u8]) -> Result<()> {
+        if (200..300).contains(&status) {
+            return Ok(());
+        }
+        Err(KvsError::IoError(io::Error::other(format!(
+            "{} of {}{} in {} failed with status {}: {}",
+            method,
+            self.prefix,
+            name,
+            self.bucket,
+            status,
+            String::from_utf8_lossy(body)
+        ))))
+    }
+}
+
+impl SegmentStorage for S3Storage {
+    fn location(&self) -> String {
+        format!("s3://{}/{}/{}", self.endpoint, self.bucket, self.prefix)
+    }
+
+    fn put(&self, name: &str, path: &Path) -> Result<()> {
+        let body = fs::read(path)?;
+        let (status, response) = self.request("PUT", Some(name), &[], &body)?;
+        self.check("PUT", name, status, &response)
+    }
+
+    fn get(&self, name: &str, path: &Path) -> Result<()> {
+        let (status, body) = self.request("GET", Some(name), &[], &[])?;
+        self.check("GET", name, status, &body)?;
+        fs::write(path, body)?;
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        let (status, body) = self.request("DELETE", Some(name), &[], &[])?;
+        if status == 404 {
+            return Ok(());
+        }
+        self.check("DELETE", name, status, &body)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", self.prefix.as_str())];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let (status, body) = self.request("GET", None, &query, &[])?;
+            self.check("LIST", "*", status, &body)?;
+            let body = String::from_utf8_lossy(&body);
+            for key in xml_values(&body, "Key") {
+                names.extend(key.strip_prefix(&self.prefix).map(str::to_owned));
+            }
+            let truncated = xml_values(&body, "IsTruncated") == ["true"];
+            token = xml_values(&body, "NextContinuationToken").pop();
+            if !truncated || token.is_none() {
+                return Ok(names);
+            }
+        }
+    }
+}
+
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+// Read the status and body of an HTTP/1.1 response.
+fn read_response(mut reader: impl BufRead) -> Result<(u16, Vec<u8>)> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_owned());
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid("invalid status line"))?;
+    let mut content_length = None;
+    let mut chunked = false;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("truncated headers").into());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = Some(value.parse().map_err(|_| invalid("invalid length"))?);
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value.eq_ignore_ascii_case("chunked");
+            }
+        }
+    }
+
+    let mut body = Vec::new();
+    if chunked {
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let size = line.trim_end().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk"))?;
+            if size == 0 {
+                break;
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            line.clear();
+            reader.read_line(&mut line)?;
+        }
+    } else if let Some(length) = content_length {
+        body.resize(length, 0);
+        reader.read_exact(&mut body)?;
+    } else {
+        reader.read_to_end(&mut body)?;
+    }
+    Ok((status, body))
+}
+
+// The contents of the elements named `tag` of an XML document, unescaped.
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let end = match rest.find(&close) {
+            Some(end) => end,
+            None => break,
+        };
+        values.push(
+            rest[..end]
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&"),
+        );
+        rest = &rest[end..];
+    }
+    values
+}
+
+// Percent-encode `s` as Signature Version 4 asks, leaving the slashes of a path as they are.
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut encoded = String::new();
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// `time` as `YYYYMMDDTHHMMSSZ`, in UTC.
+fn amz_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // the civil date of a number of days since the epoch, by Howard Hinnant's algorithm
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
@@ -0,0 +1,948 @@
+//! The on-disk format of the segments.
+//!
+//! A segment starts with a file header. Since version 4, it is:
+//!
+//! ```text
+//! [magic: "kvs"][version: u8][flags: u8][created at: u64 LE][crc32 of the above: u32 LE]
+//! ```
+//!
+//! A segment from a newer version, or with flags this version doesn't know, fails to open with
+//! `UnsupportedFormat`, and one whose header doesn't match its checksum with `ChecksumMismatch`.
+//! Compression isn't flagged in the header but on each record, since only the values above the
+//! threshold are compressed. Up to version 3, the header was only the magic bytes and the
+//! version. Segments written before the header was introduced have no header and hold JSON
+//! records.
+//!
+//! Every record is framed as `[len: u32 LE][crc32: u32 LE][data]`. In the binary format, the data
+//! of a record is:
+//!
+//! ```text
+//! [flags: u8][key len: u32 LE][key][expires at: u64 LE, if flagged]
+//! [modified at: u64 LE, if flagged][sequence number: u64 LE, if flagged][value, if flagged]
+//! ```
+//!
+//! The value takes up the rest of the data, so its length is not stored. Since version 2, the
+//! value may be compressed with LZ4, which is also flagged. Since version 3, records hold the
+//! time they were written, which compaction keeps. A value streamed in is kept in a blob file
+//! described in the `blob` module, which is flagged too, and the record holds
+//! `[blob id: u64 LE][value len: u64 LE]` in place of the value. So is a value kept in the value
+//! log described in the `vlog` module, in place of which the record holds where it is. Records
+//! written since sequence numbers were introduced hold theirs, which compaction keeps too.
+//! A range tombstone, written by `remove_prefix`, is a record without a value flagged as
+//! removing every key that starts with its key, rather than its key alone. The same flag on a
+//! record with a value makes it a merge operand, written by `merge`, which is folded into the
+//! value its key had rather than replace it.
+//!
+//! The segments of an encrypted store are flagged as such, with `1` in the flags, or `0x80` in the
+//! format version up to version 3. Their file header goes on with the salt and the key check
+//! described in the `crypto` module. The data of each of their records is the binary data above,
+//! encrypted.
+
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use log::{debug, warn};
+use serde::Deserialize;
+
+use super::blob::BlobRef;
+use super::crypto::{EncryptionKey, SegmentCipher, KEY_CHECK_SIZE, SALT_SIZE};
+use super::now_millis;
+use super::slowlog;
+use super::vlog::VlogRef;
+use crate::error::KvsError::{
+    self, ChecksumMismatch, InvalidEncryptionKey, InvalidRecord, RecordTooLarge, UnsupportedFormat,
+};
+use crate::error::Result;
+
+// Every record starts with the length and the CRC32 checksum of its data.
+pub(super) const HEADER_SIZE: u64 = 8;
+
+// Set in the length of a record whose data is a block of records written by `write_batch`.
+pub(super) const BATCH_FLAG: u32 = 1 << 31;
+
+// The longest record read or written, unless `KvStoreOptions::max_record_size` says otherwise.
+pub(super) const DEFAULT_MAX_RECORD_SIZE: u64 = 256 * 1024 * 1024;
+
+// Segments start with these bytes followed by the format version.
+const MAGIC: &[u8; 3] = b"kvs";
+
+// The file header up to version 3: the magic bytes and the format version.
+const LEGACY_HEADER_SIZE: u64 = 4;
+
+// Set in the format version of an encrypted segment, up to version 3.
+const LEGACY_ENCRYPTED: u8 = 0x80;
+
+// The file header since version 4: the magic bytes, the format version, the flags, the creation
+// time and the checksum.
+const FILE_HEADER_SIZE: u64 = 17;
+
+// Flags of the file header.
+const ENCRYPTED: u8 = 1;
+// The flags this version knows. A segment with others needs a newer version to be read.
+const KNOWN_FLAGS: u8 = ENCRYPTED;
+
+// The salt and the key check that follow the file header of an encrypted segment.
+const KEY_CHECK_BLOCK_SIZE: u64 = (SALT_SIZE + KEY_CHECK_SIZE) as u64;
+const MAX_FILE_HEADER_SIZE: u64 = FILE_HEADER_SIZE + KEY_CHECK_BLOCK_SIZE;
+
+// The version of the binary format. New segments are always written with it.
+const FORMAT_VERSION: u8 = 4;
+
+// Flags of a binary record.
+const HAS_VALUE: u8 = 1;
+const HAS_EXPIRY: u8 = 1 << 1;
+const COMPRESSED: u8 = 1 << 2;
+const HAS_TIMESTAMP: u8 = 1 << 3;
+const BLOB: u8 = 1 << 4;
+const VLOG: u8 = 1 << 5;
+const HAS_SEQUENCE: u8 = 1 << 6;
+// Set on a record that isn't a plain write of its key: a range tombstone if it has no value, a
+// merge operand if it has one.
+const SPECIAL: u8 = 1 << 7;
+
+#[derive(Debug)]
+pub(super) struct KvPair {
+    pub(super) key: Vec<u8>,
+    // None means the key has been deleted!
+    pub(super) value: Option<Vec<u8>>,
+    // Milliseconds since the Unix epoch after which the key is treated as missing.
+    pub(super) expires_at: Option<u64>,
+    // Milliseconds since the Unix epoch when the pair was written, unless it was written before
+    // records held the time. The writer stamps the pairs that don't have one yet.
+    pub(super) modified_at: Option<u64>,
+    // The blob file holding the value, if it was streamed in. The value is then left empty until
+    // the blob is read.
+    pub(super) blob: Option<BlobRef>,
+    // Where the value is in the value log, if it was long enough to be kept there. The value is
+    // then left empty until it is read.
+    pub(super) vlog: Option<VlogRef>,
+    // The number of the write in the log of the store, unless it was written before records held
+    // it or bulk loaded. The writer numbers the pairs it appends.
+    pub(super) seq: Option<u64>,
+    // Whether the pair removes every key that starts with `key` rather than `key` alone. Such a
+    // pair has no value.
+    pub(super) prefix: bool,
+    // Whether the value is a merge operand, to be folded into the value `key` had rather than
+    // replace it.
+    pub(super) merge: bool,
+}
+
+// A record of a segment written before the binary format, when keys and values had to be strings.
+#[derive(Deserialize)]
+struct JsonPair {
+    key: String,
+    value: Option<String>,
+    #[serde(default)]
+    expires_at: Option<u64>,
+}
+
+impl From<JsonPair> for KvPair {
+    fn from(pair: JsonPair) -> KvPair {
+        KvPair {
+            key: pair.key.into_bytes(),
+            value: pair.value.map(String::into_bytes),
+            expires_at: pair.expires_at,
+            modified_at: None,
+            blob: None,
+            vlog: None,
+            seq: None,
+            prefix: false,
+            merge: false,
+        }
+    }
+}
+
+impl KvPair {
+    /// Encode the pair in the current binary format, encrypted with `cipher` if there is one. The
+    /// value is compressed if it's longer than `compression_threshold` and compressing makes it
+    /// shorter.
+    pub(super) fn encode(
+        &self,
+        compression_threshold: Option<usize>,
+        cipher: Option<&SegmentCipher>,
+    ) -> Vec<u8> {
+        let mut value = self.value.as_deref().unwrap_or_default();
+        let mut flags = 0;
+        let compressed;
+        let blob;
+        let vlog;
+        if let (Some(_), Some(blob_ref)) = (&self.value, &self.blob) {
+            blob = blob_ref.encode();
+            value = &blob;
+            flags |= BLOB;
+        } else if let (Some(_), Some(vlog_ref)) = (&self.value, &self.vlog) {
+            vlog = vlog_ref.encode();
+            value = &vlog;
+            flags |= VLOG;
+        } else if compression_threshold.is_some_and(|threshold| value.len() > threshold) {
+            compressed = lz4_flex::compress_prepend_size(value);
+            if compressed.len() < value.len() {
+                value = &compressed;
+                flags |= COMPRESSED;
+            }
+        }
+        let mut data = Vec::with_capacity(29 + self.key.len() + value.len());
+        if self.value.is_some() {
+            flags |= HAS_VALUE;
+        }
+        if self.expires_at.is_some() {
+            flags |= HAS_EXPIRY;
+        }
+        if self.modified_at.is_some() {
+            flags |= HAS_TIMESTAMP;
+        }
+        if self.seq.is_some() {
+            flags |= HAS_SEQUENCE;
+        }
+        if self.prefix || self.merge {
+            flags |= SPECIAL;
+        }
+        data.push(flags);
+        data.extend_from_slice(&u32::to_le_bytes(self.key.len() as u32));
+        data.extend_from_slice(&self.key);
+        if let Some(expires_at) = self.expires_at {
+            data.extend_from_slice(&u64::to_le_bytes(expires_at));
+        }
+        if let Some(modified_at) = self.modified_at {
+            data.extend_from_slice(&u64::to_le_bytes(modified_at));
+        }
+        if let Some(seq) = self.seq {
+            data.extend_from_slice(&u64::to_le_bytes(seq));
+        }
+        data.extend_from_slice(value);
+        match cipher {
+            Some(cipher) => cipher.encrypt(&data),
+            None => data,
+        }
+    }
+
+    pub(super) fn decode(data: &[u8]) -> Result<KvPair> {
+        let raw = RawPair::parse(data)?;
+        let (value, blob, vlog) = match raw.value {
+            RawValue::Missing => (None, None, None),
+            RawValue::Plain(value) => (Some(value.to_vec()), None, None),
+            RawValue::Compressed(value) => (Some(decompress(value)?), None, None),
+            RawValue::Blob(blob) => (Some(Vec::new()), Some(blob), None),
+            RawValue::Vlog(vlog) => (Some(Vec::new()), None, Some(vlog)),
+        };
+        Ok(KvPair {
+            key: raw.key.to_vec(),
+            value,
+            expires_at: raw.expires_at,
+            modified_at: raw.modified_at,
+            blob,
+            vlog,
+            seq: raw.seq,
+            prefix: raw.prefix,
+            merge: raw.merge,
+        })
+    }
+}
+
+/// The fields of a binary record, borrowed from its data.
+pub(super) struct RawPair<'a> {
+    pub(super) key: &'a [u8],
+    pub(super) expires_at: Option<u64>,
+    pub(super) modified_at: Option<u64>,
+    pub(super) seq: Option<u64>,
+    pub(super) value: RawValue<'a>,
+    pub(super) prefix: bool,
+    pub(super) merge: bool,
+}
+
+/// The value of a binary record, as it is stored.
+pub(super) enum RawValue<'a> {
+    Missing,
+    Plain(&'a [u8]),
+    // compressed with LZ4, prefixed with its size
+    Compressed(&'a [u8]),
+    // in a blob file
+    Blob(BlobRef),
+    // in the value log
+    Vlog(VlogRef),
+}
+
+impl<'a> RawPair<'a> {
+    /// Parse the plaintext data of a binary record. The value, if any, is the tail of `data`.
+    pub(super) fn parse(mut data: &'a [u8]) -> Result<RawPair<'a>> {
+        let flags = take(&mut data, 1)?[0];
+        let known = HAS_VALUE
+            | HAS_EXPIRY
+            | COMPRESSED
+            | HAS_TIMESTAMP
+            | BLOB
+            | VLOG
+            | HAS_SEQUENCE
+            | SPECIAL;
+        // merge operands are always kept in the record
+        if flags & !known != 0 || (flags & SPECIAL != 0 && flags & (BLOB | VLOG) != 0) {
+            return Err(InvalidRecord);
+        }
+        let mut key_len = [0; 4];
+        key_len.copy_from_slice(take(&mut data, 4)?);
+        let key = take(&mut data, u32::from_le_bytes(key_len) as usize)?;
+        let expires_at = if flags & HAS_EXPIRY != 0 {
+            Some(take_u64(&mut data)?)
+        } else {
+            None
+        };
+        let modified_at = if flags & HAS_TIMESTAMP != 0 {
+            Some(take_u64(&mut data)?)
+        } else {
+            None
+        };
+        let seq = if flags & HAS_SEQUENCE != 0 {
+            Some(take_u64(&mut data)?)
+        } else {
+            None
+        };
+        let value = if flags & BLOB != 0 {
+            RawValue::Blob(BlobRef::decode(data)?)
+        } else if flags & VLOG != 0 {
+            RawValue::Vlog(VlogRef::decode(data)?)
+        } else if flags & COMPRESSED != 0 {
+            RawValue::Compressed(data)
+        } else if flags & HAS_VALUE != 0 {
+            RawValue::Plain(data)
+        } else if data.is_empty() {
+            RawValue::Missing
+        } else {
+            return Err(InvalidRecord);
+        };
+        Ok(RawPair {
+            key,
+            expires_at,
+            modified_at,
+            seq,
+            value,
+            prefix: flags & SPECIAL != 0 && flags & HAS_VALUE == 0,
+            merge: flags & SPECIAL != 0 && flags & HAS_VALUE != 0,
+        })
+    }
+}
+
+/// Decompress a value compressed by `KvPair::encode`.
+///
+/// The size the value is prefixed with is checked against the most LZ4 can expand its input to,
+/// 255 times, rather than trusted to allocate the value.
+pub(super) fn decompress(value: &[u8]) -> Result<Vec<u8>> {
+    let size = match value.get(..4) {
+        Some(size) => u32::from_le_bytes(size.try_into().expect("4 bytes")) as u64,
+        None => return Err(InvalidRecord),
+    };
+    if size > (value.len() as u64 - 4) * 255 {
+        return Err(InvalidRecord);
+    }
+    lz4_flex::decompress_size_prepended(value).map_err(|_| InvalidRecord)
+}
+
+/// Split the first `n` bytes off `data`.
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if data.len() < n {
+        return Err(InvalidRecord);
+    }
+    let (head, tail) = data.split_at(n);
+    *data = tail;
+    Ok(head)
+}
+
+/// Split a little-endian `u64` off `data`.
+fn take_u64(data: &mut &[u8]) -> Result<u64> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(take(data, 8)?);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// What `SegmentFile::for_each_record` does with a record at the end of the segment that was
+/// only partially written, e.g. because the process was killed in the middle of an append.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum TornTail {
+    /// Fail with `ChecksumMismatch`.
+    Fail,
+    /// Cut the record off the file.
+    Truncate,
+    /// Stop reading before the record.
+    Ignore,
+}
+
+/// How the records of a segment are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Format {
+    /// Segments without a file header, whose records are JSON.
+    Json,
+    /// The binary format described at the top of this module, with its version.
+    Binary(u8),
+}
+
+impl Format {
+    /// The format new segments are written in.
+    pub(super) const CURRENT: Format = Format::Binary(FORMAT_VERSION);
+}
+
+/// A segment file along with the format of its records.
+#[derive(Debug)]
+pub(super) struct SegmentFile {
+    pub(super) file: File,
+    // where the file is, for the errors reading it
+    pub(super) path: PathBuf,
+    // the directory of the store, which holds the blobs and the value log, unless the segment
+    // was moved to a cold directory it is the one the segment is in
+    pub(super) store_dir: PathBuf,
+    pub(super) format: Format,
+    // decrypts the records of an encrypted segment
+    pub(super) cipher: Option<SegmentCipher>,
+    // the size of the file header
+    data_start: u64,
+    // when the segment was created, in milliseconds since the Unix epoch, if its header says
+    pub(super) created_at: Option<u64>,
+    // the key of the store, to read the blobs of the records with
+    pub(super) key: Option<EncryptionKey>,
+    // reading a longer record fails with `RecordTooLarge`
+    pub(super) max_record_size: u64,
+    // the size of the file, read the first time it is asked for once the segment is sealed
+    sealed_size: OnceLock<u64>,
+    // the file mapped into memory, by the first read that borrows a value from it
+    #[cfg(feature = "mmap")]
+    pub(super) map: std::sync::RwLock<Option<std::sync::Arc<memmap2::Mmap>>>,
+}
+
+impl SegmentFile {
+    /// Open a segment for reading, and for appending too if it is `writable`. A writable segment
+    /// that is empty gets a file header for the current format, encrypted if there is a `key`.
+    ///
+    /// Fails with `InvalidEncryptionKey` if the segment is encrypted and `key` is missing or
+    /// isn't the one it was encrypted with.
+    pub(super) fn open(
+        dir: &Path,
+        segment: u64,
+        writable: bool,
+        key: Option<&EncryptionKey>,
+    ) -> Result<SegmentFile> {
+        SegmentFile::open_path(segment_path(dir, segment), writable, key)
+    }
+
+    /// Like `open`, for a segment moved out of the directory of the store, `store_dir`, read from
+    /// the file at `path`.
+    pub(super) fn open_cold(
+        path: PathBuf,
+        store_dir: &Path,
+        key: Option<&EncryptionKey>,
+    ) -> Result<SegmentFile> {
+        let mut file = SegmentFile::open_path(path, false, key)?;
+        file.store_dir = store_dir.to_owned();
+        Ok(file)
+    }
+
+    /// Like `open`, for a file with the format of a segment at `path`.
+    pub(super) fn open_path(
+        path: PathBuf,
+        writable: bool,
+        key: Option<&EncryptionKey>,
+    ) -> Result<SegmentFile> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(writable)
+            .create(writable)
+            .open(&path)?;
+        let store_dir = path.parent().map(Path::to_owned).unwrap_or_default();
+        let size = file.metadata()?.len();
+        let mut bytes = [0; MAX_FILE_HEADER_SIZE as usize];
+        let bytes = &mut bytes[..size.min(MAX_FILE_HEADER_SIZE) as usize];
+        read_exact_at(&file, bytes, 0)?;
+        let header = if size < LEGACY_HEADER_SIZE {
+            None
+        } else {
+            parse_file_header(bytes)?
+        };
+        let header = match header {
+            Some(header) => header,
+            None if writable => {
+                // an empty segment, or one whose header was torn by a crash
+                file.set_len(0)?;
+                let cipher = write_file_header(&mut &file, key)?;
+                return Ok(SegmentFile {
+                    file,
+                    path,
+                    store_dir,
+                    format: Format::CURRENT,
+                    cipher,
+                    data_start: file_header_size(key.is_some()),
+                    created_at: Some(now_millis()),
+                    key: key.cloned(),
+                    max_record_size: DEFAULT_MAX_RECORD_SIZE,
+                    sealed_size: OnceLock::new(),
+                    #[cfg(feature = "mmap")]
+                    map: Default::default(),
+                });
+            }
+            // too short to hold anything but the start of a JSON record
+            None if size < LEGACY_HEADER_SIZE => FileHeader::JSON,
+            None => return Err(ChecksumMismatch),
+        };
+
+        let cipher = match header.key_check_at {
+            Some(at) => {
+                let key = key.ok_or(InvalidEncryptionKey)?;
+                let salt = &bytes[at as usize..][..SALT_SIZE];
+                let cipher = SegmentCipher::new(key, salt);
+                if cipher.key_check()[..] != bytes[at as usize + SALT_SIZE..][..KEY_CHECK_SIZE] {
+                    return Err(InvalidEncryptionKey);
+                }
+                Some(cipher)
+            }
+            None => None,
+        };
+        Ok(SegmentFile {
+            file,
+            path,
+            store_dir,
+            format: header.format,
+            cipher,
+            data_start: header.size,
+            created_at: header.created_at,
+            key: key.cloned(),
+            max_record_size: DEFAULT_MAX_RECORD_SIZE,
+            sealed_size: OnceLock::new(),
+            #[cfg(feature = "mmap")]
+            map: Default::default(),
+        })
+    }
+
+    /// Fail to read records longer than `bytes` with `RecordTooLarge`, rather than allocating
+    /// for them. Defaults to `DEFAULT_MAX_RECORD_SIZE`.
+    pub(super) fn with_max_record_size(mut self, bytes: u64) -> SegmentFile {
+        self.max_record_size = bytes;
+        self
+    }
+
+    /// The offset of the first record.
+    pub(super) fn data_start(&self) -> u64 {
+        self.data_start
+    }
+
+    /// The size of the file, which is only read once, for a segment no longer appended to.
+    pub(super) fn sealed_size(&self) -> u64 {
+        *self.sealed_size.get_or_init(|| {
+            self.file
+                .metadata()
+                .map_or(self.data_start, |metadata| metadata.len())
+        })
+    }
+
+    /// Decode the data of a record of this segment.
+    fn decode(&self, data: &[u8]) -> Result<KvPair> {
+        match (self.format, &self.cipher) {
+            (Format::Json, _) => Ok(serde_json::from_slice::<JsonPair>(data)?.into()),
+            (Format::Binary(_), Some(cipher)) => KvPair::decode(&cipher.decrypt(data)?),
+            (Format::Binary(_), None) => KvPair::decode(data),
+        }
+    }
+
+    /// Read the record whose data is `len` bytes long and starts at `start`, along with its
+    /// value if it is in a blob or the value log. Errors tell where the record is.
+    pub(super) fn read_pair(&self, start: u64, len: usize) -> Result<KvPair> {
+        let mut pair = self.read_stored_pair(start, len)?;
+        if let Some(blob) = pair.blob.take() {
+            // the value grows with the chunks read, whatever length the record says
+            let mut value = Vec::with_capacity(blob.len.min(self.max_record_size) as usize);
+            slowlog::time(|spent| &mut spent.disk, || self.read_blob(blob, &mut value))?;
+            pair.value = Some(value);
+        }
+        if let Some(vlog) = pair.vlog.take() {
+            let value = slowlog::time(
+                |spent| &mut spent.disk,
+                || self.read_vlog_value(&pair.key, vlog),
+            )?;
+            pair.value = Some(value);
+        }
+        Ok(pair)
+    }
+
+    /// Like `read_pair`, leaving the value of a record whose value is in a blob or the value log
+    /// empty. The record is checked against its checksum, and fails with `ChecksumMismatch` if it
+    /// doesn't match.
+    pub(super) fn read_stored_pair(&self, start: u64, len: usize) -> Result<KvPair> {
+        // the length comes from the index, which may have been loaded from a damaged hint
+        if len as u64 > self.max_record_size {
+            return Err(RecordTooLarge(len as u64).in_file(&self.path, Some(start)));
+        }
+        let mut record: Vec<u8> = vec![0; HEADER_SIZE as usize + len];
+        slowlog::time(
+            |spent| &mut spent.disk,
+            || read_exact_at(&self.file, &mut record, start - HEADER_SIZE),
+        )
+        .map_err(KvsError::from)
+        .and_then(|_| {
+            slowlog::time(
+                |spent| &mut spent.decode,
+                || self.decode(checked_data(&record)?),
+            )
+        })
+        .map_err(|err| err.in_file(&self.path, Some(start)))
+    }
+
+    /// Read the data of every record in order, decrypted but not decoded, for the files holding
+    /// the chunks of a blob or values of the value log. Passes the offset of the data to `f`.
+    pub(super) fn for_each_chunk<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(u64, &[u8]) -> Result<()>,
+    {
+        let file = &self.file;
+        let file_size = file.metadata()?.len();
+        let mut offset = self.data_start().min(file_size);
+        let mut reader = BufReader::new(PositionalReader { file, pos: offset });
+        while offset < file_size {
+            let data = match read_record(&mut reader, file_size - offset, self.max_record_size)? {
+                Some((false, data)) => data,
+                _ => return Err(ChecksumMismatch.in_file(&self.path, Some(offset))),
+            };
+            let start = offset + HEADER_SIZE;
+            offset = start + data.len() as u64;
+            match &self.cipher {
+                Some(cipher) => f(start, &cipher.decrypt(&data)?)?,
+                None => f(start, &data)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// The offset of the record after the one at `offset`, told from its header without reading
+    /// its data, to skip the chunks of a blob.
+    pub(super) fn next_record(&self, offset: u64) -> Result<u64> {
+        let mut header = [0; HEADER_SIZE as usize];
+        read_exact_at(&self.file, &mut header, offset)
+            .map_err(|err| KvsError::from(err).in_file(&self.path, Some(offset)))?;
+        let len = u32::from_le_bytes(header[..4].try_into().expect("4 bytes"));
+        Ok(offset + HEADER_SIZE + u64::from(len & !BATCH_FLAG))
+    }
+
+    /// Read the data of the record at `offset`, decrypted but not decoded as `for_each_chunk`
+    /// passes it, along with the offset of the record after it.
+    pub(super) fn read_chunk(&self, offset: u64) -> Result<(Vec<u8>, u64)> {
+        let mut reader = PositionalReader {
+            file: &self.file,
+            pos: offset,
+        };
+        let remaining = self.sealed_size().saturating_sub(offset);
+        let data = match read_record(&mut reader, remaining, self.max_record_size)
+            .map_err(|err| err.in_file(&self.path, Some(offset)))?
+        {
+            Some((false, data)) => data,
+            _ => return Err(ChecksumMismatch.in_file(&self.path, Some(offset))),
+        };
+        let next = offset + HEADER_SIZE + data.len() as u64;
+        match &self.cipher {
+            Some(cipher) => Ok((cipher.decrypt(&data)?, next)),
+            None => Ok((data, next)),
+        }
+    }
+
+    /// Check every record against its checksum, going on past those that don't match as long as
+    /// their length fits in the segment. Returns where each damaged record starts and how many
+    /// bytes it takes up, the rest of the segment for the last one if its length doesn't fit.
+    pub(super) fn damaged_records(&self) -> Result<Vec<(u64, u64)>> {
+        let file = &self.file;
+        let file_size = file.metadata()?.len();
+        let mut offset = self.data_start().min(file_size);
+        let mut reader = BufReader::new(PositionalReader { file, pos: offset });
+        let mut damaged = Vec::new();
+        while offset < file_size {
+            if file_size - offset < HEADER_SIZE {
+                damaged.push((offset, file_size - offset));
+                break;
+            }
+            let mut header = [0; HEADER_SIZE as usize];
+            reader.read_exact(&mut header)?;
+            let len = u32::from_le_bytes(header[..4].try_into().expect("4 bytes")) & !BATCH_FLAG;
+            let record_len = HEADER_SIZE + len as u64;
+            if record_len > file_size - offset {
+                damaged.push((offset, file_size - offset));
+                break;
+            }
+            let mut record = header.to_vec();
+            record.resize(record_len as usize, 0);
+            reader.read_exact(&mut record[HEADER_SIZE as usize..])?;
+            if checked_data(&record).is_err() {
+                damaged.push((offset, record_len));
+            }
+            offset += record_len;
+        }
+        Ok(damaged)
+    }
+
+    /// Read every record in order, passing the offset and length of its data to `f`.
+    /// Returns the size of the segment, up to the torn record if `torn_tail` ignores it.
+    pub(super) fn for_each_record<F>(&self, torn_tail: TornTail, mut f: F) -> Result<u64>
+    where
+        F: FnMut(u64, usize, KvPair) -> Result<()>,
+    {
+        let file = &self.file;
+        let file_size = file.metadata()?.len();
+        debug!("file size: {:?}", file_size);
+        let mut offset = self.data_start().min(file_size);
+        let mut reader = BufReader::new(PositionalReader { file, pos: offset });
+
+        while offset < file_size {
+            match read_record(&mut reader, file_size - offset, self.max_record_size)? {
+                Some((true, block)) => {
+                    debug!("batch_size: {}", block.len());
+                    let mut pos = 0;
+                    let mut records = &block[..];
+                    while pos < block.len() as u64 {
+                        match read_record(
+                            &mut records,
+                            block.len() as u64 - pos,
+                            self.max_record_size,
+                        )? {
+                            Some((false, data)) => {
+                                let pair = self.decode(&data)?;
+                                f(offset + HEADER_SIZE + pos + HEADER_SIZE, data.len(), pair)?;
+                                pos += HEADER_SIZE + data.len() as u64;
+                            }
+                            // the block passed its checksum, so its records must be intact
+                            _ => return Err(ChecksumMismatch),
+                        }
+                    }
+                    offset += HEADER_SIZE + block.len() as u64;
+                }
+                Some((false, data)) => {
+                    debug!("data_size: {}", data.len());
+                    let pair = self.decode(&data)?;
+                    f(offset + HEADER_SIZE, data.len(), pair)?;
+                    offset += HEADER_SIZE + data.len() as u64;
+                }
+                None => match torn_tail {
+                    TornTail::Fail => return Err(ChecksumMismatch),
+                    TornTail::Truncate => {
+                        warn!("Truncating torn record at offset {}", offset);
+                        file.set_len(offset)?;
+                        return Ok(offset);
+                    }
+                    TornTail::Ignore => {
+                        warn!("Ignoring torn record at offset {}", offset);
+                        return Ok(offset);
+                    }
+                },
+            }
+        }
+
+        Ok(file_size)
+    }
+}
+
+pub(super) fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{}.log", segment))
+}
+
+/// The size of the file header of a new segment, encrypted or not.
+pub(super) fn file_header_size(encrypted: bool) -> u64 {
+    if encrypted {
+        MAX_FILE_HEADER_SIZE
+    } else {
+        FILE_HEADER_SIZE
+    }
+}
+
+/// Write the file header of a new segment in the current format, encrypted if there is a `key`.
+/// Returns the cipher to encrypt the records of the segment with.
+pub(super) fn write_file_header(
+    writer: &mut impl Write,
+    key: Option<&EncryptionKey>,
+) -> Result<Option<SegmentCipher>> {
+    let mut header = Vec::with_capacity(MAX_FILE_HEADER_SIZE as usize);
+    header.extend_from_slice(MAGIC);
+    header.push(FORMAT_VERSION);
+    header.push(if key.is_some() { ENCRYPTED } else { 0 });
+    header.extend_from_slice(&u64::to_le_bytes(now_millis()));
+    let checksum = crc32fast::hash(&header);
+    header.extend_from_slice(&u32::to_le_bytes(checksum));
+    let cipher = key.map(|key| {
+        let salt = SegmentCipher::random_salt();
+        let cipher = SegmentCipher::new(key, &salt);
+        header.extend_from_slice(&salt);
+        header.extend_from_slice(&cipher.key_check());
+        cipher
+    });
+    writer.write_all(&header)?;
+    Ok(cipher)
+}
+
+/// What the file header of a segment holds.
+struct FileHeader {
+    format: Format,
+    // where the salt and the key check of an encrypted segment start
+    key_check_at: Option<u64>,
+    // the size of the header, where the records start
+    size: u64,
+    created_at: Option<u64>,
+}
+
+impl FileHeader {
+    /// The header of a segment written before segments had one.
+    const JSON: FileHeader = FileHeader {
+        format: Format::Json,
+        key_check_at: None,
+        size: 0,
+        created_at: None,
+    };
+}
+
+/// Parse the file header at the start of `bytes`, the first bytes of a segment, at least
+/// `LEGACY_HEADER_SIZE` of them. Returns `None` if the header was torn by a crash.
+///
+/// Fails with `UnsupportedFormat` if the segment was written by a newer version, and with
+/// `ChecksumMismatch` if the header is damaged.
+fn parse_file_header(bytes: &[u8]) -> Result<Option<FileHeader>> {
+    if &bytes[..3] != MAGIC {
+        // Written before segments had a header. The length of a JSON record never gets
+        // anywhere near the magic bytes read as a length.
+        return Ok(Some(FileHeader::JSON));
+    }
+    let (version, encrypted, created_at, size) = match bytes[3] {
+        FORMAT_VERSION => {
+            if (bytes.len() as u64) < FILE_HEADER_SIZE {
+                return Ok(None);
+            }
+            let checksum = u32::from_le_bytes(bytes[13..17].try_into().expect("4 bytes"));
+            if crc32fast::hash(&bytes[..13]) != checksum {
+                return Err(ChecksumMismatch);
+            }
+            let flags = bytes[4];
+            if flags & !KNOWN_FLAGS != 0 {
+                return Err(UnsupportedFormat(FORMAT_VERSION));
+            }
+            let created_at = u64::from_le_bytes(bytes[5..13].try_into().expect("8 bytes"));
+            (
+                FORMAT_VERSION,
+                flags & ENCRYPTED != 0,
+                Some(created_at),
+                FILE_HEADER_SIZE,
+            )
+        }
+        version @ 1..=3 => (version, false, None, LEGACY_HEADER_SIZE),
+        version if matches!(version & !LEGACY_ENCRYPTED, 1..=3) => {
+            (version & !LEGACY_ENCRYPTED, true, None, LEGACY_HEADER_SIZE)
+        }
+        version => return Err(UnsupportedFormat(version)),
+    };
+    let header = FileHeader {
+        format: Format::Binary(version),
+        key_check_at: if encrypted { Some(size) } else { None },
+        size: size + if encrypted { KEY_CHECK_BLOCK_SIZE } else { 0 },
+        created_at,
+    };
+    if (bytes.len() as u64) < header.size {
+        return Ok(None);
+    }
+    Ok(Some(header))
+}
+
+/// Read the next record from `reader`, which has `remaining` bytes left, and verify its checksum.
+/// Returns whether the record is a batch along with its data, or `None` if the record is the last
+/// one and it is incomplete or its checksum does not match, which is what a torn write looks like.
+/// A record whose data is longer than `max` bytes, but fits, fails with `RecordTooLarge`.
+pub(super) fn read_record(
+    reader: &mut impl Read,
+    remaining: u64,
+    max: u64,
+) -> Result<Option<(bool, Vec<u8>)>> {
+    if remaining < HEADER_SIZE {
+        return Ok(None);
+    }
+    let mut header: [u8; 8] = [0; 8];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let is_batch = len & BATCH_FLAG != 0;
+    let data_size = (len & !BATCH_FLAG) as u64;
+    let checksum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if HEADER_SIZE + data_size > remaining {
+        return Ok(None);
+    }
+    if data_size > max {
+        return Err(RecordTooLarge(data_size));
+    }
+    // the length is only trusted as far as the bytes that are there
+    let mut data_buffer = Vec::new();
+    reader.take(data_size).read_to_end(&mut data_buffer)?;
+    if (data_buffer.len() as u64) < data_size {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    if crc32fast::hash(&data_buffer) != checksum {
+        if HEADER_SIZE + data_size == remaining {
+            return Ok(None);
+        }
+        return Err(ChecksumMismatch);
+    }
+    Ok(Some((is_batch, data_buffer)))
+}
+
+/// The data of `record`, a header followed by the data, if it matches the checksum in the header.
+pub(super) fn checked_data(record: &[u8]) -> Result<&[u8]> {
+    let (header, data) = record.split_at(HEADER_SIZE as usize);
+    let checksum = u32::from_le_bytes(header[4..].try_into().expect("4 bytes"));
+    if crc32fast::hash(data) != checksum {
+        return Err(ChecksumMismatch);
+    }
+    Ok(data)
+}
+
+/// Write `data` prefixed with its header. The record is written with a single call so that a
+/// crash leaves at most one torn record at the end of the segment.
+pub(super) fn write_record(writer: &mut impl Write, data: &[u8]) -> Result<()> {
+    write_frame(writer, data.len() as u32, data)
+}
+
+/// Write `data` prefixed with the given length field and the checksum of `data`.
+pub(super) fn write_frame(writer: &mut impl Write, len: u32, data: &[u8]) -> Result<()> {
+    let mut record = Vec::with_capacity(HEADER_SIZE as usize + data.len());
+    record.extend_from_slice(&u32::to_le_bytes(len));
+    record.extend_from_slice(&u32::to_le_bytes(crc32fast::hash(data)));
+    record.extend_from_slice(data);
+    writer.write_all(&record)?;
+    Ok(())
+}
+
+/// Fill `buf` from `file` starting at `pos`.
+pub(super) fn read_exact_at(file: &File, mut buf: &mut [u8], mut pos: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match read_at(file, buf, pos) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                pos += n as u64;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Read from `file` starting at `pos`, without moving the cursor it shares with all its handles,
+/// so that many threads can read the same file at once.
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, pos)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, pos)
+}
+
+/// Reads a file sequentially with positional reads.
+struct PositionalReader<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl Read for PositionalReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = read_at(self.file, buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
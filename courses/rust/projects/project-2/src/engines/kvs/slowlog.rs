@@ -0,0 +1,77 @@
+//! Logging the reads and writes that take longer than `KvStoreOptions::slow_log_threshold`.
+//!
+//! A slow `get`, `set` or `remove` is logged as a warning with the `kvs::slow` target, so that it
+//! can be routed apart from the rest of the log, along with its key, the size of its value, and
+//! where the time went: waiting for the writer lock, reading from disk, decoding the record and
+//! waiting for the write to be synced. The time is measured on the thread doing the work, by
+//! adding each part to a tally kept per thread, which the operation clears when it starts.
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+/// Where the time of an operation went.
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct Spent {
+    /// waiting for the writer lock, writes held back by backpressure included
+    pub(super) queue: Duration,
+    /// reading records, blobs and values of the value log
+    pub(super) disk: Duration,
+    /// checking, decrypting and decoding records
+    pub(super) decode: Duration,
+    /// waiting for the write to be synced, with `SyncPolicy::Group`
+    pub(super) sync: Duration,
+}
+
+thread_local! {
+    static SPENT: Cell<Spent> = Cell::new(Spent::default());
+}
+
+/// Clear the tally of the thread, as an operation starts.
+pub(super) fn start() {
+    SPENT.with(|spent| spent.set(Spent::default()));
+}
+
+/// Run `f`, adding the time it took to the part of the tally `part` picks.
+pub(super) fn time<T>(part: fn(&mut Spent) -> &mut Duration, f: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = f();
+    let elapsed = started.elapsed();
+    SPENT.with(|spent| {
+        let mut tally = spent.get();
+        *part(&mut tally) += elapsed;
+        spent.set(tally);
+    });
+    result
+}
+
+/// Log `operation` on `key` if it took longer than `threshold`, with the tally of the thread.
+/// `bytes` is the size of the value read or written, if there is one.
+pub(super) fn log_if_slow(
+    threshold: Option<Duration>,
+    operation: &str,
+    key: &[u8],
+    bytes: Option<usize>,
+    elapsed: Duration,
+) {
+    match threshold {
+        Some(threshold) if elapsed >= threshold => {}
+        _ => return,
+    }
+    let spent = SPENT.with(Cell::get);
+    let bytes = bytes.map_or_else(|| "no value".to_owned(), |bytes| format!("{} bytes", bytes));
+    warn!(
+        target: "kvs::slow",
+        "Slow {} of key '{}' ({}) took {:?}: {:?} waiting for the writer, {:?} reading from disk, \
+         {:?} decoding, {:?} waiting for the sync",
+        operation,
+        String::from_utf8_lossy(key),
+        bytes,
+        elapsed,
+        spent.queue,
+        spent.disk,
+        spent.decode,
+        spent.sync
+    );
+}
@@ -0,0 +1,162 @@
+//! Where the segments moved out of the directory of a store are kept.
+//!
+//! With `KvStoreOptions::cold_storage`, compaction moves the segments it rewrites to a
+//! `SegmentStorage`, under the file name they have in the directory of the store. A
+//! `LocalStorage` keeps them in a directory, e.g. on a slower and cheaper disk, where they are
+//! read in place. Other storages, such as `S3Storage` with the `s3` feature, keep them as
+//! objects: compaction uploads the new segment, and keeps a copy of it in the directory of the
+//! store, `<id>.cached`, to read from. A store opened without the copy, e.g. on another machine
+//! given a copy of the directory without them, downloads it first.
+//!
+//! The manifest records the location of the storage each segment was moved to. A store must be
+//! opened with the storage its segments were moved to, unless it is a local directory, which is
+//! read from whether it is given or not. Objects in a storage that the manifest doesn't list
+//! there were left behind by a crash, and are removed when the store is opened, so a storage
+//! must not be shared with another store.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::manifest::persist;
+use crate::error::Result;
+
+/// The storages segments were moved to, by location.
+pub(super) type Storages = HashMap<String, Arc<dyn SegmentStorage>>;
+
+/// A place to keep the segments of a store in, as objects named like their files. See the
+/// `storage` module.
+pub trait SegmentStorage: fmt::Debug + Send + Sync {
+    /// Where the storage keeps the segments, e.g. a URL, recorded in the manifest to find them
+    /// again. Only a `LocalStorage` may have the absolute path of a directory for location.
+    fn location(&self) -> String;
+
+    /// Store the file at `path` as the object `name`, replacing the object of that name if there
+    /// is one. The object must be complete once this returns, or not be there at all.
+    fn put(&self, name: &str, path: &Path) -> Result<()>;
+
+    /// Copy the object `name` to a file at `path`.
+    fn get(&self, name: &str, path: &Path) -> Result<()>;
+
+    /// Remove the object `name`. An object that is already gone is skipped.
+    fn delete(&self, name: &str) -> Result<()>;
+
+    /// The names of the objects.
+    fn list(&self) -> Result<Vec<String>>;
+
+    /// The file that holds the object `name`, if it can be read in place rather than copied.
+    fn local_path(&self, name: &str) -> Option<PathBuf> {
+        let _ = name;
+        None
+    }
+}
+
+/// A `SegmentStorage` that keeps the segments in a directory, read in place.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    /// Keep the segments in `dir`, which is created if it doesn't exist.
+    pub fn new(dir: impl AsRef<Path>) -> Result<LocalStorage> {
+        fs::create_dir_all(dir.as_ref())?;
+        // the location recorded mustn't depend on the working directory
+        Ok(LocalStorage {
+            dir: fs::canonicalize(dir)?,
+        })
+    }
+
+    /// The storage of the location of a `LocalStorage`, i.e. its directory.
+    pub(super) fn at(location: &str) -> LocalStorage {
+        LocalStorage {
+            dir: PathBuf::from(location),
+        }
+    }
+
+    /// The directory the segments are kept in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl SegmentStorage for LocalStorage {
+    fn location(&self) -> String {
+        self.dir.to_string_lossy().into_owned()
+    }
+
+    fn put(&self, name: &str, path: &Path) -> Result<()> {
+        let mut output = tempfile::NamedTempFile::new_in(&self.dir)?;
+        io::copy(&mut File::open(path)?, &mut output)?;
+        output.as_file().sync_all()?;
+        persist(output, &self.dir.join(name))
+    }
+
+    fn get(&self, name: &str, path: &Path) -> Result<()> {
+        fs::copy(self.dir.join(name), path)?;
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        match fs::remove_file(self.dir.join(name)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                names.extend(entry.file_name().to_str().map(str::to_owned));
+            }
+        }
+        Ok(names)
+    }
+
+    fn local_path(&self, name: &str) -> Option<PathBuf> {
+        Some(self.dir.join(name))
+    }
+}
+
+/// Whether `location` is that of a `LocalStorage`.
+pub(super) fn is_local(location: &str) -> bool {
+    Path::new(location).is_absolute()
+}
+
+/// The name of `segment` in a storage.
+pub(super) fn object_name(segment: u64) -> String {
+    format!("{}.log", segment)
+}
+
+/// The copy of `segment` kept in the directory `dir` of the store while it is in other storage
+/// than a local directory.
+pub(super) fn cached_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{}.cached", segment))
+}
+
+/// The segments a copy is kept of in `dir`, whichever storage they are in.
+pub(super) fn cached_segments(dir: &Path) -> Result<Vec<u64>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let segment = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".cached"))
+            .and_then(|id| id.parse::<u64>().ok());
+        segments.extend(segment);
+    }
+    Ok(segments)
+}
+
+/// Download `segment` from `storage` to its copy in the directory `dir` of the store.
+pub(super) fn fetch_segment(dir: &Path, storage: &dyn SegmentStorage, segment: u64) -> Result<()> {
+    let output = tempfile::NamedTempFile::new_in(dir)?;
+    storage.get(&object_name(segment), output.path())?;
+    output.as_file().sync_all()?;
+    persist(output, &cached_path(dir, segment))
+}
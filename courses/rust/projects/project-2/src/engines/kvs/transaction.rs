@@ -0,0 +1,119 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::segment::KvPair;
+use super::{contains_live, live_offset, KvStore, KvStoreWriter, Offset, WriteBatch};
+use crate::error::KvsError::{KeyNotFound, TransactionConflict};
+use crate::error::Result;
+
+/// Reads and writes applied to a `KvStore` all at once, created by `KvStore::transaction`.
+///
+/// Writes are buffered until the transaction commits, and reads see them. Every key the
+/// transaction reads or writes is checked for other writes when it commits: if there was one, the
+/// transaction fails with `KvsError::TransactionConflict` and none of its writes are applied.
+#[derive(Debug)]
+pub struct Transaction<'a> {
+    store: &'a KvStore,
+    // The record each key pointed to when the transaction first touched it, or `None` if it
+    // didn't exist.
+    versions: HashMap<Vec<u8>, Option<Offset>>,
+    // The buffered writes, `None` removing the key.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(super) fn new(store: &'a KvStore) -> Transaction<'a> {
+        Transaction {
+            store,
+            versions: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Retrieve the value of a key, including the writes of this transaction.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let key = key.into_bytes();
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone().map(String::from_utf8).transpose()?);
+        }
+        let offset = self.observe(&key);
+        let value = match offset {
+            Some(offset) => offset.read_pair()?.value,
+            None => None,
+        };
+        Ok(value.map(String::from_utf8).transpose()?)
+    }
+
+    /// Set a key when the transaction commits.
+    pub fn set(&mut self, key: String, value: String) {
+        let key = key.into_bytes();
+        self.observe(&key);
+        self.writes.insert(key, Some(value.into_bytes()));
+    }
+
+    /// Remove a key when the transaction commits.
+    ///
+    /// Returns `KvsError::KeyNotFound` if the key doesn't exist, including the writes of this
+    /// transaction.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let key = key.into_bytes();
+        let exists = match self.writes.get(&key) {
+            Some(value) => value.is_some(),
+            None => self.observe(&key).is_some(),
+        };
+        if !exists {
+            return Err(KeyNotFound);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// The record `key` points to, remembering it as the version the transaction saw if it is the
+    /// first time the transaction touches the key.
+    fn observe(&mut self, key: &[u8]) -> Option<Offset> {
+        if let Some(version) = self.versions.get(key) {
+            return version.clone();
+        }
+        let offset = live_offset(&self.store.index, key);
+        self.versions.insert(key.to_vec(), offset.clone());
+        offset
+    }
+
+    pub(super) fn commit(self) -> Result<()> {
+        // holding the writer lock keeps other writes out between the check and the batch
+        let store = self.store;
+        store.write(|writer| self.commit_locked(writer))
+    }
+
+    fn commit_locked(self, writer: &mut KvStoreWriter) -> Result<()> {
+        for (key, version) in &self.versions {
+            let unchanged = match (version, live_offset(&self.store.index, key)) {
+                (Some(seen), Some(offset)) => offset.points_to(&seen.file, seen.start),
+                (None, None) => true,
+                _ => false,
+            };
+            if !unchanged {
+                return Err(TransactionConflict);
+            }
+        }
+
+        let mut batch = WriteBatch::default();
+        for (key, value) in self.writes {
+            // a key created and removed by the transaction was never there
+            if value.is_none() && !contains_live(&self.store.index, &key) {
+                continue;
+            }
+            batch.pairs.push(KvPair {
+                key,
+                value,
+                expires_at: None,
+                modified_at: None,
+                blob: None,
+                vlog: None,
+                seq: None,
+                prefix: false,
+                merge: false,
+            });
+        }
+        writer.write_batch(batch)
+    }
+}
@@ -0,0 +1,416 @@
+//! The value log, which keeps large values out of the segments, with
+//! `KvStoreOptions::value_log_threshold`.
+//!
+//! A value longer than the threshold is appended to a value log file named `<id>.vlog` next to
+//! the segments, and the record of the key holds `[file id: u64 LE][start: u64 LE][len: u32 LE]`
+//! in place of the value, flagged as described in the `segment` module. A value log file has the
+//! file header of a segment, and each of its records holds `[key len: u32 LE][key][value]`,
+//! encrypted in an encrypted store. Compaction then only copies the small records of the keys,
+//! and leaves the values where they are.
+//!
+//! Values are appended to the newest file, until it grows past the segment size. Overwriting or
+//! removing a key leaves its old value behind, which `KvStore::collect_value_log` reclaims one
+//! sealed file at a time: it copies the values still live to the newest file, appends new records
+//! pointing to them, and removes the old file. A value is live while the current record of its
+//! key points to it, so renaming or copying a key writes the value again under the new key.
+//!
+//! With `KvStoreOptions::versions` above 1, the older versions may point to any value, so only the
+//! files whose values are all gone are removed. A scan, snapshot or replay still holding the old
+//! record of a key whose value was moved keeps the old file until it is dropped, as described in
+//! the `pin` module, but a plain read that looked the record up before the move may fail.
+//!
+//! Values are synced as they are appended unless the sync policy is `SyncPolicy::Never`, in
+//! which case a crash may leave a record of a key pointing past the end of its value log.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::{debug, info};
+
+use super::crypto::EncryptionKey;
+use super::segment::{checked_data, read_exact_at, write_record, KvPair, SegmentFile, HEADER_SIZE};
+use super::{current, KvStore, KvStoreWriter, Offset, SyncPolicy};
+use crate::error::KvsError::{self, InvalidRecord, ReadOnly, RecordTooLarge};
+use crate::error::Result;
+
+/// Where the value of a record is kept, if it is in the value log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct VlogRef {
+    pub(super) file: u64,
+    // the offset and length of the data of the record holding the value
+    pub(super) start: u64,
+    pub(super) len: u32,
+}
+
+impl VlogRef {
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(20);
+        data.extend_from_slice(&u64::to_le_bytes(self.file));
+        data.extend_from_slice(&u64::to_le_bytes(self.start));
+        data.extend_from_slice(&u32::to_le_bytes(self.len));
+        data
+    }
+
+    pub(super) fn decode(data: &[u8]) -> Result<VlogRef> {
+        if data.len() != 20 {
+            return Err(InvalidRecord);
+        }
+        let mut file = [0; 8];
+        file.copy_from_slice(&data[..8]);
+        let mut start = [0; 8];
+        start.copy_from_slice(&data[8..16]);
+        let mut len = [0; 4];
+        len.copy_from_slice(&data[16..]);
+        Ok(VlogRef {
+            file: u64::from_le_bytes(file),
+            start: u64::from_le_bytes(start),
+            len: u32::from_le_bytes(len),
+        })
+    }
+}
+
+pub(super) fn vlog_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.vlog", id))
+}
+
+/// The value log files of a store, and the one values are appended to.
+#[derive(Debug, Default)]
+pub(super) struct ValueLog {
+    // the file values are appended to, opened by the first value written
+    head: Option<(u64, SegmentFile)>,
+    // maps the ids of the files, the head included, to their sizes
+    files: BTreeMap<u64, u64>,
+}
+
+impl ValueLog {
+    /// The value log files in `dir`. Values are appended to a new one.
+    pub(super) fn open(dir: &Path) -> Result<ValueLog> {
+        let mut files = BTreeMap::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension() != Some(OsStr::new("vlog")) {
+                continue;
+            }
+            if let Some(id) = path
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                files.insert(id, entry.metadata()?.len());
+            }
+        }
+        Ok(ValueLog { head: None, files })
+    }
+
+    /// The total size of the files.
+    pub(super) fn size(&self) -> u64 {
+        self.files.values().sum()
+    }
+
+    /// Stop appending values to the current file, once it is synced, so that the next value
+    /// starts a new one. Returns the id of the newest file, which is now sealed.
+    pub(super) fn seal_head(&mut self) -> Result<Option<u64>> {
+        if let Some((_, head)) = self.head.take() {
+            head.file.sync_data()?;
+        }
+        Ok(self.files.keys().last().copied())
+    }
+
+    /// The files no value is appended to anymore, oldest first.
+    pub(super) fn sealed(&self) -> Vec<u64> {
+        let head = self.head.as_ref().map(|(id, _)| *id);
+        self.files
+            .keys()
+            .copied()
+            .filter(|&id| Some(id) != head)
+            .collect()
+    }
+}
+
+/// Where the data of the first value of `key` starts in the value log file `id` of the store in
+/// `dir`, if the file holds one.
+pub(super) fn find_value(
+    dir: &Path,
+    id: u64,
+    encryption_key: Option<&EncryptionKey>,
+    key: &[u8],
+) -> Result<Option<u64>> {
+    let path = vlog_path(dir, id);
+    let file = SegmentFile::open_path(path.clone(), false, encryption_key)
+        .map_err(|err| err.in_file(&path, None))?;
+    let mut found = None;
+    file.for_each_chunk(|start, data| {
+        if found.is_none() && parse_entry(data)?.0 == key {
+            found = Some(start);
+        }
+        Ok(())
+    })
+    .map_err(|err| err.in_file(&path, None))?;
+    Ok(found)
+}
+
+/// Split a record of a value log into its key and its value.
+fn parse_entry(data: &[u8]) -> Result<(&[u8], &[u8])> {
+    if data.len() < 4 {
+        return Err(InvalidRecord);
+    }
+    let mut key_len = [0; 4];
+    key_len.copy_from_slice(&data[..4]);
+    let key_len = u32::from_le_bytes(key_len) as usize;
+    if data.len() - 4 < key_len {
+        return Err(InvalidRecord);
+    }
+    Ok(data[4..].split_at(key_len))
+}
+
+impl KvStore {
+    /// Reclaim the values overwritten or removed from the value log, from each sealed file in
+    /// which they make up at least `KvStoreOptions::value_log_gc_threshold`. See the `vlog`
+    /// module. Returns how many bytes were reclaimed.
+    ///
+    /// Each file is read without holding the writer lock, which is then held while its live
+    /// values are copied, so writes wait for at most a segment's worth of values.
+    pub fn collect_value_log(&self) -> Result<u64> {
+        let (sealed, threshold) = {
+            let writer = self.writer();
+            if writer.options.read_only {
+                return Err(ReadOnly);
+            }
+            (
+                writer.value_log.sealed(),
+                writer.options.value_log_gc_threshold,
+            )
+        };
+        let mut reclaimed = 0;
+        for id in sealed {
+            reclaimed += self.collect_value_log_file(id, threshold)?;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Reclaim the values overwritten or removed from the sealed value log file `id`, if they
+    /// make up at least `threshold` of it. Returns how many bytes were reclaimed.
+    pub(super) fn collect_value_log_file(&self, id: u64, threshold: f64) -> Result<u64> {
+        let (dir, key, relocate) = {
+            let writer = self.writer();
+            (
+                writer.dir.clone(),
+                writer.options.encryption_key.clone(),
+                writer.options.versions <= 1,
+            )
+        };
+        let path = vlog_path(&dir, id);
+        let file = SegmentFile::open_path(path.clone(), false, key.as_ref())
+            .map_err(|err| err.in_file(&path, None))?;
+        let size = file.file.metadata()?.len();
+
+        // the values still live, with the record of their key
+        let mut live: Vec<(Offset, KvPair, Vec<u8>)> = Vec::new();
+        let mut live_bytes = 0;
+        let mut pinned = false;
+        file.for_each_chunk(|start, data| {
+            let (key, value) = parse_entry(data)?;
+            let points_here = |pair: &KvPair| {
+                pair.vlog
+                    .is_some_and(|at| at.file == id && at.start == start)
+            };
+            // an expired key still points to its value until the expiry thread removes it
+            if let Some(entry) = self.index.get(key) {
+                let offset = current(&entry);
+                let pair = offset.file.read_stored_pair(offset.start, offset.len)?;
+                if points_here(&pair) {
+                    live_bytes += HEADER_SIZE + pair.vlog.map_or(0, |at| at.len as u64);
+                    live.push((offset, pair, value.to_vec()));
+                    return Ok(());
+                }
+            }
+            for version in self.history.get(key) {
+                let pair = version.file.read_stored_pair(version.start, version.len)?;
+                pinned |= points_here(&pair);
+            }
+            Ok(())
+        })?;
+
+        let data_size = size - file.data_start().min(size);
+        let garbage = data_size - live_bytes;
+        if pinned || (!relocate && !live.is_empty()) {
+            debug!("Value log {} holds older versions of keys", id);
+            return Ok(0);
+        }
+        if garbage == 0 || (garbage as f64) < threshold * data_size as f64 {
+            return Ok(0);
+        }
+
+        let mut writer = self.writer();
+        let mut moved = 0;
+        for (old, pair, value) in live {
+            let entry = match self.index.get(&pair.key) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            // the record of the key may have been compacted since, or the key overwritten
+            let offset = current(&entry);
+            if !offset.points_to(&old.file, old.start)
+                && offset.file.read_stored_pair(offset.start, offset.len)?.vlog != pair.vlog
+            {
+                continue;
+            }
+            let vlog = writer.append_value(&pair.key, &value)?;
+            let bytes = KvPair {
+                vlog: Some(vlog),
+                ..pair
+            }
+            .encode(None, writer.active_file.cipher.as_ref());
+            writer.append_frame(bytes.len() as u32, &bytes)?;
+            let new_offset = Offset {
+                segment: writer.active_segment,
+                file: Arc::clone(&writer.active_file),
+                start: writer.active_size + HEADER_SIZE,
+                len: bytes.len(),
+                expires_at: offset.expires_at,
+                merged: None,
+            };
+            writer.active_size += new_offset.record_len();
+            *writer.stale_bytes.entry(offset.segment).or_default() += offset.record_len();
+            *entry.value().write().expect("index lock poisoned") = new_offset;
+            self.cache.invalidate(entry.key());
+            moved += 1;
+        }
+        // the values and the records pointing to them must survive the old file
+        if let Some((_, head)) = &writer.value_log.head {
+            head.file.sync_data()?;
+        }
+        writer.active_file.file.sync_data()?;
+        writer.pins.remove_files(vec![path])?;
+        writer.value_log.files.remove(&id);
+        writer.after_write()?;
+        info!(
+            "Collected value log {}, reclaiming {} bytes and moving {} values",
+            id, garbage, moved
+        );
+        Ok(garbage)
+    }
+}
+
+impl KvStoreWriter {
+    /// Encode `pair` for the active segment, with its value in the value log if it's longer than
+    /// the threshold. The pair itself keeps its value, for the watchers and the followers.
+    pub(super) fn encode_pair(&mut self, pair: &KvPair) -> Result<Vec<u8>> {
+        let threshold = self.options.value_log_threshold;
+        let value = match &pair.value {
+            Some(value)
+                if pair.blob.is_none()
+                    && pair.vlog.is_none()
+                    && !pair.merge
+                    && threshold.is_some_and(|threshold| value.len() > threshold) =>
+            {
+                value
+            }
+            _ => {
+                return Ok(pair.encode(
+                    self.options.compression_threshold,
+                    self.active_file.cipher.as_ref(),
+                ))
+            }
+        };
+        let vlog = self.append_value(&pair.key, value)?;
+        let stored = KvPair {
+            key: pair.key.clone(),
+            value: Some(Vec::new()),
+            expires_at: pair.expires_at,
+            modified_at: pair.modified_at,
+            blob: None,
+            vlog: Some(vlog),
+            seq: pair.seq,
+            prefix: false,
+            merge: false,
+        };
+        Ok(stored.encode(None, self.active_file.cipher.as_ref()))
+    }
+
+    /// Append the value of `key` to the value log, starting a new file if the newest one is full.
+    fn append_value(&mut self, key: &[u8], value: &[u8]) -> Result<VlogRef> {
+        let full = match &self.value_log.head {
+            Some((id, _)) => self.value_log.files[id] >= self.options.segment_size,
+            None => true,
+        };
+        if full {
+            if let Some((_, head)) = &self.value_log.head {
+                head.file.sync_data()?;
+            }
+            let id = self.value_log.files.keys().last().map_or(1, |id| id + 1);
+            let file = SegmentFile::open_path(
+                vlog_path(&self.dir, id),
+                true,
+                self.options.encryption_key.as_ref(),
+            )?;
+            self.value_log.files.insert(id, file.data_start());
+            self.value_log.head = Some((id, file));
+        }
+        let (id, head) = self.value_log.head.as_ref().expect("opened above");
+
+        let mut data = Vec::with_capacity(4 + key.len() + value.len());
+        data.extend_from_slice(&u32::to_le_bytes(key.len() as u32));
+        data.extend_from_slice(key);
+        data.extend_from_slice(value);
+        let data = match &head.cipher {
+            Some(cipher) => cipher.encrypt(&data),
+            None => data,
+        };
+        if data.len() as u64 > self.options.max_record_size {
+            return Err(RecordTooLarge(data.len() as u64));
+        }
+        let size = self
+            .value_log
+            .files
+            .get_mut(id)
+            .expect("the head is listed");
+        let mut result = write_record(&mut &head.file, &data);
+        if result.is_ok() && self.options.sync_policy != SyncPolicy::Never {
+            result = head.file.sync_data().map_err(Into::into);
+        }
+        if let Err(err) = result {
+            // the next values mustn't land after a partial one
+            let _ = head.file.set_len(*size);
+            return Err(err);
+        }
+        let vlog = VlogRef {
+            file: *id,
+            start: *size + HEADER_SIZE,
+            len: data.len() as u32,
+        };
+        *size += HEADER_SIZE + data.len() as u64;
+        Ok(vlog)
+    }
+}
+
+impl SegmentFile {
+    /// Read the value of `key` held by `vlog`, from the value log of the store of this segment.
+    pub(super) fn read_vlog_value(&self, key: &[u8], vlog: VlogRef) -> Result<Vec<u8>> {
+        let path = vlog_path(&self.store_dir, vlog.file);
+        let file = SegmentFile::open_path(path.clone(), false, self.key.as_ref())
+            .map_err(|err| err.in_file(&path, None))?;
+        let in_file = |err: KvsError| err.in_file(&path, Some(vlog.start));
+        if u64::from(vlog.len) > self.max_record_size {
+            return Err(in_file(RecordTooLarge(vlog.len.into())));
+        }
+        let record_len = HEADER_SIZE + vlog.len as u64;
+        let mut record = vec![0; record_len as usize];
+        read_exact_at(&file.file, &mut record, vlog.start - HEADER_SIZE)
+            .map_err(|err| in_file(err.into()))?;
+        let data = checked_data(&record).map_err(in_file)?;
+        let data = match &file.cipher {
+            Some(cipher) => cipher.decrypt(data).map_err(in_file)?,
+            None => data.to_vec(),
+        };
+        let (stored_key, value) = parse_entry(&data).map_err(in_file)?;
+        if stored_key != key {
+            return Err(in_file(InvalidRecord));
+        }
+        Ok(value.to_vec())
+    }
+}
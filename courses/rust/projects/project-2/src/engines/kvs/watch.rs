@@ -0,0 +1,85 @@
+use std::sync::mpsc::Sender;
+
+/// What a write did to a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeOp {
+    /// The key was set
+    Set,
+    /// The key was removed
+    Remove,
+    /// The key expired, and was removed
+    Expire,
+}
+
+/// A change to a watched key, sent by the receivers that `KvStore::watch` returns.
+///
+/// Keys and values that aren't valid UTF-8 are converted lossily.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    /// The key that changed
+    pub key: String,
+    /// What was done to the key
+    pub op: ChangeOp,
+    /// The value before the change, or `None` if the key didn't exist
+    pub old_value: Option<String>,
+    /// The value after the change, or `None` if the key was removed or expired, or set with
+    /// `KvStore::set_reader`
+    pub new_value: Option<String>,
+}
+
+impl ChangeEvent {
+    pub(super) fn new(key: &[u8], old_value: Option<&[u8]>, new_value: Option<&[u8]>) -> Self {
+        let lossy = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+        ChangeEvent {
+            key: lossy(key),
+            op: if new_value.is_some() {
+                ChangeOp::Set
+            } else {
+                ChangeOp::Remove
+            },
+            old_value: old_value.map(lossy),
+            new_value: new_value.map(lossy),
+        }
+    }
+
+    /// The event for `key` set to a value streamed in, which isn't sent along.
+    pub(super) fn streamed(key: &[u8], old_value: Option<&[u8]>) -> Self {
+        ChangeEvent {
+            op: ChangeOp::Set,
+            ..ChangeEvent::new(key, old_value, None)
+        }
+    }
+
+    /// The event for `key` expiring, while it was set to `value`.
+    pub(super) fn expired(key: &[u8], value: Option<&[u8]>) -> Self {
+        ChangeEvent {
+            op: ChangeOp::Expire,
+            ..ChangeEvent::new(key, value, None)
+        }
+    }
+}
+
+/// The watchers of a store, each sent the changes to the keys that start with its prefix.
+#[derive(Debug, Default)]
+pub(super) struct Watchers {
+    watchers: Vec<(Vec<u8>, Sender<ChangeEvent>)>,
+}
+
+impl Watchers {
+    pub(super) fn add(&mut self, prefix: Vec<u8>, tx: Sender<ChangeEvent>) {
+        self.watchers.push((prefix, tx));
+    }
+
+    /// Whether changes to `key` have to be sent to a watcher.
+    pub(super) fn is_watched(&self, key: &[u8]) -> bool {
+        self.watchers
+            .iter()
+            .any(|(prefix, _)| key.starts_with(prefix))
+    }
+
+    /// Send a change to the watchers of its key, and forget the watchers that were dropped.
+    pub(super) fn send(&mut self, key: &[u8], event: ChangeEvent) {
+        self.watchers
+            .retain(|(prefix, tx)| !key.starts_with(prefix) || tx.send(event.clone()).is_ok());
+    }
+}
@@ -0,0 +1,433 @@
+//! This module provides various key value storage engines.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::error::KvsError::{InvalidInteger, UnknownEngine, WrongEngine};
+use crate::error::Result;
+use crate::raft::RaftNode;
+
+mod kvs;
+mod sharded;
+mod sled;
+mod stats;
+
+#[cfg(feature = "fuzzing")]
+pub(crate) use self::kvs::fuzzing;
+pub(crate) use self::kvs::replication::{ReplicatedPair, ReplicationEntry, SyncStart};
+#[cfg(feature = "s3")]
+pub use self::kvs::S3Storage;
+#[cfg(feature = "mmap")]
+pub use self::kvs::ValueRef;
+pub use self::kvs::{
+    Backpressure, ChangeEvent, ChangeOp, CompactionPolicy, CorruptRecord, Databases, DeadRatio,
+    DumpFormat, EvictionPolicy, FormatVersion, HotKey, KvStore, KvStoreOptions, LocalStorage,
+    ManualOnly, Metadata, Namespace, OpenProgress, Operation, RecordInfo, Replay, Scan,
+    SegmentCheck, SegmentInfo, SegmentStorage, SegmentUsage, SizeTiered, Snapshot, StaleBytes,
+    SyncPolicy, TimeBased, TotalStaleRatio, Transaction, WriteBatch,
+};
+pub use self::sharded::ShardedKvStore;
+pub use self::sled::SledKvsEngine;
+pub(crate) use self::stats::LatencyRecorder;
+pub use self::stats::{Latencies, Latency, Stats, LATENCY_BUCKETS};
+
+/// Trait for a key value storage engine.
+///
+/// Engines are cheap to clone, and all the clones share the same data, so that each thread
+/// handling requests can own a handle to the engine.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// Set the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// Retrieve the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// Retrieve the values of several keys at once, in the same order as `keys`.
+    fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        keys.iter().map(|key| self.get(key.clone())).collect()
+    }
+
+    /// Remove a given key.
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given key does not exist.
+    fn remove(&self, key: String) -> Result<()>;
+
+    /// Whether a key exists. This one reads its value, which engines that can tell without
+    /// doing so avoid.
+    fn contains_key(&self, key: String) -> Result<bool> {
+        self.get(key).map(|value| value.is_some())
+    }
+
+    /// Like `set`, and return the sequence number the engine gave the write if it numbers its
+    /// writes, with numbers that go up with every write. This one doesn't.
+    fn set_numbered(&self, key: String, value: String) -> Result<Option<u64>> {
+        self.set(key, value).map(|()| None)
+    }
+
+    /// Like `remove`, and return the sequence number the engine gave the write if it numbers
+    /// its writes. This one doesn't.
+    fn remove_numbered(&self, key: String) -> Result<Option<u64>> {
+        self.remove(key).map(|()| None)
+    }
+
+    /// Set `key` to `new` if its value is `expected`, atomically, and return whether it did.
+    ///
+    /// `None` stands for a missing key: an `expected` of `None` only sets the key if it doesn't
+    /// exist, and a `new` of `None` removes it.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool>;
+
+    /// Add `delta` to the integer value of `key`, atomically, and return the result. A missing
+    /// key counts as 0.
+    ///
+    /// Returns `KvsError::InvalidInteger` if the value isn't an integer or the result would
+    /// overflow an `i64`.
+    fn increment(&self, key: String, delta: i64) -> Result<i64>;
+
+    /// Move the value of `old_key` to `new_key`, atomically, replacing the value `new_key` had
+    /// if any.
+    ///
+    /// Returns `KvsError::KeyNotFound` if `old_key` does not exist.
+    fn rename(&self, old_key: String, new_key: String) -> Result<()>;
+
+    /// Set `dst` to the value of `src`, atomically.
+    ///
+    /// Returns `KvsError::KeyNotFound` if `src` does not exist.
+    fn copy(&self, src: String, dst: String) -> Result<()>;
+
+    /// Set `key` to what `f` returns given its current value, atomically, and return the new
+    /// value. `None` stands for a missing key: `f` gets `None` if the key doesn't exist, and
+    /// returning `None` removes it.
+    ///
+    /// Engines that can hold a lock across the read and the write call `f` once. This one
+    /// retries with `compare_and_swap` until the key didn't change in between, so `f` may be
+    /// called several times.
+    fn update<F>(&self, key: String, mut f: F) -> Result<Option<String>>
+    where
+        F: FnMut(Option<String>) -> Option<String>,
+    {
+        loop {
+            let old = self.get(key.clone())?;
+            let new = f(old.clone());
+            if self.compare_and_swap(key.clone(), old, new.clone())? {
+                return Ok(new);
+            }
+        }
+    }
+
+    /// Set a key to the `len` bytes read from `reader`. Engines that can stream them to disk
+    /// don't hold them all in memory; this one reads them into a string first.
+    ///
+    /// Fails with an `UnexpectedEof` error if `reader` ends before `len` bytes.
+    fn set_reader<R: Read>(&self, key: String, reader: R, len: u64) -> Result<()> {
+        let mut value = Vec::new();
+        reader.take(len).read_to_end(&mut value)?;
+        if (value.len() as u64) < len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        self.set(key, String::from_utf8(value)?)
+    }
+
+    /// Write the value of a key to `writer`, and return whether the key exists. Engines that
+    /// can stream it from disk don't hold it all in memory; this one reads it first.
+    fn get_writer<W: Write>(&self, key: String, mut writer: W) -> Result<bool> {
+        match self.get(key)? {
+            Some(value) => {
+                writer.write_all(value.as_bytes())?;
+                writer.flush()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Statistics about the engine and the data it holds.
+    fn stats(&self) -> Result<Stats>;
+
+    /// Make every write done so far durable, e.g. before the server stops. Engines that sync
+    /// each write before it returns have nothing to do.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// The `KvStore` behind the engine, if there is one. Only a `KvStore` can be replicated.
+    fn as_kv_store(&self) -> Option<&KvStore> {
+        None
+    }
+
+    /// The Raft node the engine is replicated by, if it is a `RaftEngine`. The server hands it
+    /// the messages of the other nodes of the cluster.
+    fn as_raft(&self) -> Option<&RaftNode> {
+        None
+    }
+
+    /// Set the value of a string key to any serializable value, stored as JSON.
+    fn set_typed<T: Serialize>(&self, key: String, value: &T) -> Result<()> {
+        self.set(key, serde_json::to_string(value)?)
+    }
+
+    /// Retrieve a value stored by `set_typed`.
+    ///
+    /// Returns `KvsError::SerdeError` if the stored value can't be deserialized as a `T`.
+    fn get_typed<T: DeserializeOwned>(&self, key: String) -> Result<Option<T>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The storage engines that can be selected by `open_engine`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Engine {
+    /// The log-structured `KvStore`
+    Kvs,
+    /// `SledKvsEngine`, backed by the sled embedded database
+    Sled,
+}
+
+impl FromStr for Engine {
+    type Err = crate::error::KvsError;
+
+    fn from_str(s: &str) -> Result<Engine> {
+        match s {
+            "kvs" => Ok(Engine::Kvs),
+            "sled" => Ok(Engine::Sled),
+            _ => Err(UnknownEngine(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Engine::Kvs => write!(f, "kvs"),
+            Engine::Sled => write!(f, "sled"),
+        }
+    }
+}
+
+// The file that records which engine created the store in a directory.
+const ENGINE_FILE: &str = "engine";
+
+/// The engine that created the store in a directory, or `None` if there is no store in it.
+///
+/// Engines record themselves in a file of the directory when they create a store. Stores
+/// created before they did are recognized by their files.
+pub fn detect_engine(path: impl AsRef<Path>) -> Result<Option<Engine>> {
+    let path = path.as_ref();
+    match fs::read_to_string(path.join(ENGINE_FILE)) {
+        Ok(name) => return name.trim().parse().map(Some),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            return Ok(Some(Engine::Kvs));
+        }
+        if path.file_name().is_some_and(|name| name == "conf") {
+            return Ok(Some(Engine::Sled));
+        }
+    }
+    Ok(None)
+}
+
+/// Record that the store in `dir` is one of `engine`, unless it is one of another engine, which
+/// fails with `WrongEngine` rather than letting `engine` misread it.
+pub(crate) fn claim_dir(dir: &Path, engine: Engine) -> Result<()> {
+    match detect_engine(dir)? {
+        Some(found) if found != engine => Err(WrongEngine(found.to_string())),
+        _ if dir.join(ENGINE_FILE).exists() => Ok(()),
+        _ => {
+            fs::write(dir.join(ENGINE_FILE), format!("{}\n", engine))?;
+            Ok(())
+        }
+    }
+}
+
+/// Fail with `WrongEngine` if the store in `dir` is one of another engine than `engine`.
+pub(crate) fn check_dir(dir: &Path, engine: Engine) -> Result<()> {
+    match detect_engine(dir)? {
+        Some(found) if found != engine => Err(WrongEngine(found.to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// An engine picked at run time, returned by `open_engine`.
+#[derive(Debug, Clone)]
+pub enum AnyEngine {
+    /// A `KvStore`
+    Kvs(KvStore),
+    /// A `SledKvsEngine`
+    Sled(SledKvsEngine),
+}
+
+impl KvsEngine for AnyEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.set(key, value),
+            AnyEngine::Sled(engine) => engine.set(key, value),
+        }
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.get(key),
+            AnyEngine::Sled(engine) => engine.get(key),
+        }
+    }
+
+    fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.get_many(keys),
+            AnyEngine::Sled(engine) => engine.get_many(keys),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.remove(key),
+            AnyEngine::Sled(engine) => engine.remove(key),
+        }
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        match self {
+            AnyEngine::Kvs(engine) => KvsEngine::contains_key(engine, key),
+            AnyEngine::Sled(engine) => engine.contains_key(key),
+        }
+    }
+
+    fn set_numbered(&self, key: String, value: String) -> Result<Option<u64>> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.set_numbered(key, value),
+            AnyEngine::Sled(engine) => engine.set_numbered(key, value),
+        }
+    }
+
+    fn remove_numbered(&self, key: String) -> Result<Option<u64>> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.remove_numbered(key),
+            AnyEngine::Sled(engine) => engine.remove_numbered(key),
+        }
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.compare_and_swap(key, expected, new),
+            AnyEngine::Sled(engine) => engine.compare_and_swap(key, expected, new),
+        }
+    }
+
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.increment(key, delta),
+            AnyEngine::Sled(engine) => engine.increment(key, delta),
+        }
+    }
+
+    fn rename(&self, old_key: String, new_key: String) -> Result<()> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.rename(old_key, new_key),
+            AnyEngine::Sled(engine) => engine.rename(old_key, new_key),
+        }
+    }
+
+    fn copy(&self, src: String, dst: String) -> Result<()> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.copy(src, dst),
+            AnyEngine::Sled(engine) => engine.copy(src, dst),
+        }
+    }
+
+    fn update<F>(&self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnMut(Option<String>) -> Option<String>,
+    {
+        match self {
+            AnyEngine::Kvs(engine) => engine.update(key, f),
+            AnyEngine::Sled(engine) => engine.update(key, f),
+        }
+    }
+
+    fn set_reader<R: Read>(&self, key: String, reader: R, len: u64) -> Result<()> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.set_reader(key, reader, len),
+            AnyEngine::Sled(engine) => engine.set_reader(key, reader, len),
+        }
+    }
+
+    fn get_writer<W: Write>(&self, key: String, writer: W) -> Result<bool> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.get_writer(key, writer),
+            AnyEngine::Sled(engine) => engine.get_writer(key, writer),
+        }
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.stats(),
+            AnyEngine::Sled(engine) => engine.stats(),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        match self {
+            AnyEngine::Kvs(engine) => engine.flush(),
+            AnyEngine::Sled(engine) => engine.flush(),
+        }
+    }
+
+    fn as_kv_store(&self) -> Option<&KvStore> {
+        match self {
+            AnyEngine::Kvs(engine) => Some(engine),
+            AnyEngine::Sled(_) => None,
+        }
+    }
+}
+
+/// Add `delta` to `value`, an integer as a string, or to 0 if there is no value.
+fn add_to_value(value: Option<&[u8]>, delta: i64) -> Result<i64> {
+    let current = match value {
+        Some(value) => std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .ok_or(InvalidInteger)?,
+        None => 0,
+    };
+    current.checked_add(delta).ok_or(InvalidInteger)
+}
+
+/// Open the given engine in a directory.
+pub fn open_engine(engine: Engine, path: impl Into<PathBuf>) -> Result<AnyEngine> {
+    match engine {
+        Engine::Kvs => Ok(AnyEngine::Kvs(KvStore::open(path)?)),
+        Engine::Sled => Ok(AnyEngine::Sled(SledKvsEngine::open(path)?)),
+    }
+}
@@ -0,0 +1,372 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use super::{KvStore, KvStoreOptions, KvsEngine, Stats};
+use crate::error::Result;
+use crate::ring::HashRing;
+
+// The file listing the shards of a store, in its directory.
+const MANIFEST: &str = "shards.json";
+const MANIFEST_TMP: &str = "shards.json.tmp";
+// Shards are kept in directories named with this prefix and a number.
+const SHARD_PREFIX: &str = "shard-";
+// Keys copied to another shard at a time while resharding.
+const COPY_BATCH: usize = 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    // the directories of the shards, in ring order
+    shards: Vec<String>,
+    // the shards a resharding that hasn't finished yet is moving the keys to
+    target: Option<Vec<String>>,
+}
+
+/// A store that spreads its keys over several `KvStore`s, its shards, each in a directory of its
+/// own.
+///
+/// A `KvStore` appends every write to a single active segment, under one lock. Each shard has
+/// its own, so writes to keys in different shards don't wait for each other. Keys are assigned
+/// to shards with consistent hashing, so that changing the number of shards with `reshard` only
+/// moves about the share of the keys that the shards added or removed hold.
+///
+/// Transactions, scans and watches span a single `KvStore`, and are available on each of
+/// `shards`.
+#[derive(Debug, Clone)]
+pub struct ShardedKvStore {
+    shards: Arc<[KvStore]>,
+    ring: Arc<HashRing>,
+}
+
+impl ShardedKvStore {
+    /// Open the sharded store in a directory, creating one of `shards` shards if there is none.
+    pub fn open(path: impl Into<PathBuf>, shards: usize) -> Result<ShardedKvStore> {
+        ShardedKvStore::open_with(path, shards, &KvStore::options())
+    }
+
+    /// Open the sharded store in a directory, opening each shard with `options`, and creating
+    /// one of `shards` shards if there is none.
+    ///
+    /// Fails if the store has another number of shards: `reshard` changes it.
+    pub fn open_with(
+        path: impl Into<PathBuf>,
+        shards: usize,
+        options: &KvStoreOptions,
+    ) -> Result<ShardedKvStore> {
+        let path = path.into();
+        check_count(shards)?;
+        let manifest = match read_manifest(&path)? {
+            Some(manifest) => manifest,
+            None => create(&path, shards)?,
+        };
+        if manifest.shards.len() != shards {
+            return Err(invalid_input(format!(
+                "the store has {} shards, not {}",
+                manifest.shards.len(),
+                shards
+            )));
+        }
+        let stores = open_shards(&path, &manifest.shards, options)?;
+        Ok(ShardedKvStore {
+            shards: stores.into(),
+            ring: Arc::new(HashRing::new(&manifest.shards)),
+        })
+    }
+
+    /// Change the number of shards of the store in a directory to `shards`, opening them with
+    /// `options`, and return the number of keys moved.
+    ///
+    /// The store must not be open meanwhile. The keys that move are copied to their new shards
+    /// before they are removed from the old ones, so a resharding that is interrupted loses
+    /// nothing, and can be finished by running it again.
+    pub fn reshard(
+        path: impl Into<PathBuf>,
+        shards: usize,
+        options: &KvStoreOptions,
+    ) -> Result<u64> {
+        let path = path.into();
+        check_count(shards)?;
+        let manifest = read_manifest(&path)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no sharded store in {}", path.display()),
+            )
+        })?;
+
+        // Drop what an interrupted resharding left behind: the copies it made in the current
+        // shards, and the shards it added.
+        let current = open_shards(&path, &manifest.shards, options)?;
+        let ring = HashRing::new(&manifest.shards);
+        retain_owned(&current, &ring)?;
+        remove_unlisted(&path, &manifest.shards)?;
+
+        let target = shard_names(&manifest.shards, shards);
+        if target == manifest.shards {
+            return Ok(0);
+        }
+        write_manifest(
+            &path,
+            &Manifest {
+                shards: manifest.shards.clone(),
+                target: Some(target.clone()),
+            },
+        )?;
+
+        // The stores of the shards that stay are already open, and can't be opened twice.
+        let stores = target
+            .iter()
+            .map(
+                |name| match manifest.shards.iter().position(|n| n == name) {
+                    Some(index) => Ok(current[index].clone()),
+                    None => open_shard(&path, name, options),
+                },
+            )
+            .collect::<Result<Vec<_>>>()?;
+        let new_ring = HashRing::new(&target);
+        let mut moved = 0;
+        for (name, store) in manifest.shards.iter().zip(&current) {
+            let mut batches = vec![Vec::new(); stores.len()];
+            for pair in store.snapshot().entries() {
+                let pair = pair?;
+                let owner = new_ring.owner(&pair.key);
+                if target[owner] == *name {
+                    continue;
+                }
+                batches[owner].push(pair);
+                moved += 1;
+                if batches[owner].len() == COPY_BATCH {
+                    stores[owner].apply_replicated(std::mem::take(&mut batches[owner]))?;
+                }
+            }
+            for (owner, batch) in batches.into_iter().enumerate() {
+                if !batch.is_empty() {
+                    stores[owner].apply_replicated(batch)?;
+                }
+            }
+        }
+
+        // Every key is now in its new shard, which the store is switched to before the keys
+        // are removed from the old ones.
+        write_manifest(
+            &path,
+            &Manifest {
+                shards: target.clone(),
+                target: None,
+            },
+        )?;
+        retain_owned(&stores, &new_ring)?;
+        drop(current);
+        drop(stores);
+        remove_unlisted(&path, &target)?;
+        Ok(moved)
+    }
+
+    /// The shards of the store.
+    pub fn shards(&self) -> &[KvStore] {
+        &self.shards
+    }
+
+    /// The shard `key` belongs in.
+    pub fn shard(&self, key: &str) -> &KvStore {
+        &self.shards[self.ring.owner(key.as_bytes())]
+    }
+
+    // The shard both keys belong in.
+    fn same_shard(&self, a: &str, b: &str) -> Result<&KvStore> {
+        let shard = self.ring.owner(a.as_bytes());
+        if self.ring.owner(b.as_bytes()) != shard {
+            return Err(invalid_input(format!(
+                "{:?} and {:?} are in different shards",
+                a, b
+            )));
+        }
+        Ok(&self.shards[shard])
+    }
+}
+
+impl KvsEngine for ShardedKvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.shard(&key).set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.shard(&key).remove(key)
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.shard(&key).contains_key(&key))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.shard(&key).compare_and_swap(key, expected, new)
+    }
+
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        self.shard(&key).increment(key, delta)
+    }
+
+    fn update<F>(&self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnMut(Option<String>) -> Option<String>,
+    {
+        self.shard(&key).update(key, f)
+    }
+
+    /// Fails with an `InvalidInput` error if the keys are in different shards, since writes to
+    /// several shards can't be made atomic.
+    fn rename(&self, old_key: String, new_key: String) -> Result<()> {
+        self.same_shard(&old_key, &new_key)?
+            .rename(old_key, new_key)
+    }
+
+    /// Fails with an `InvalidInput` error if the keys are in different shards, like `rename`.
+    fn copy(&self, src: String, dst: String) -> Result<()> {
+        self.same_shard(&src, &dst)?.copy(src, dst)
+    }
+
+    fn set_reader<R: io::Read>(&self, key: String, reader: R, len: u64) -> Result<()> {
+        self.shard(&key).set_reader(key, reader, len)
+    }
+
+    fn get_writer<W: io::Write>(&self, key: String, writer: W) -> Result<bool> {
+        self.shard(&key).get_writer(key, writer)
+    }
+
+    /// The statistics of the shards, added up.
+    fn stats(&self) -> Result<Stats> {
+        let mut total = Stats::default();
+        for shard in self.shards.iter() {
+            let stats = shard.stats()?;
+            total.keys += stats.keys;
+            total.live_bytes += stats.live_bytes;
+            total.dead_bytes += stats.dead_bytes;
+            total.segments += stats.segments;
+            total.compactions += stats.compactions;
+            total.reads += stats.reads;
+            total.writes += stats.writes;
+            total.cache_hits += stats.cache_hits;
+            total.cache_misses += stats.cache_misses;
+            total.index_bytes += stats.index_bytes;
+            total.evictions += stats.evictions;
+            total.value_log_bytes += stats.value_log_bytes;
+            total.latencies.merge(&stats.latencies);
+        }
+        Ok(total)
+    }
+
+    fn flush(&self) -> Result<()> {
+        for shard in self.shards.iter() {
+            shard.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn check_count(shards: usize) -> Result<()> {
+    if shards == 0 {
+        return Err(invalid_input("a store needs at least one shard".to_owned()));
+    }
+    Ok(())
+}
+
+fn invalid_input(message: String) -> crate::KvsError {
+    io::Error::new(io::ErrorKind::InvalidInput, message).into()
+}
+
+// Start a store of `shards` shards in `path`, unless it holds a store that isn't sharded, whose
+// keys would be lost from sight.
+fn create(path: &Path, shards: usize) -> Result<Manifest> {
+    fs::create_dir_all(path)?;
+    for entry in fs::read_dir(path)? {
+        if entry?.path().extension() == Some(OsStr::new("log")) {
+            return Err(invalid_input(format!(
+                "{} holds a store that isn't sharded",
+                path.display()
+            )));
+        }
+    }
+    let manifest = Manifest {
+        shards: shard_names(&[], shards),
+        target: None,
+    };
+    write_manifest(path, &manifest)?;
+    Ok(manifest)
+}
+
+// The names of `count` shards: the first of `current`, then new ones.
+fn shard_names(current: &[String], count: usize) -> Vec<String> {
+    let mut names: Vec<String> = current.iter().take(count).cloned().collect();
+    let mut next = 0;
+    while names.len() < count {
+        let name = format!("{}{}", SHARD_PREFIX, next);
+        if !current.contains(&name) {
+            names.push(name);
+        }
+        next += 1;
+    }
+    names
+}
+
+fn open_shards(path: &Path, names: &[String], options: &KvStoreOptions) -> Result<Vec<KvStore>> {
+    names
+        .iter()
+        .map(|name| open_shard(path, name, options))
+        .collect()
+}
+
+fn open_shard(path: &Path, name: &str, options: &KvStoreOptions) -> Result<KvStore> {
+    let path = path.join(name);
+    fs::create_dir_all(&path)?;
+    options.open(path)
+}
+
+// Remove the keys of each store that another shard owns.
+fn retain_owned(stores: &[KvStore], ring: &HashRing) -> Result<()> {
+    for (index, store) in stores.iter().enumerate() {
+        store.retain(|key| ring.owner(key) == index)?;
+    }
+    Ok(())
+}
+
+// Remove the directories of the shards that aren't in `names`.
+fn remove_unlisted(path: &Path, names: &[String]) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(SHARD_PREFIX) && entry.file_type()?.is_dir() && !names.contains(&name) {
+            fs::remove_dir_all(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn read_manifest(path: &Path) -> Result<Option<Manifest>> {
+    match fs::read(path.join(MANIFEST)) {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Replace the manifest at once, so that a crash leaves either the old one or the new one.
+fn write_manifest(path: &Path, manifest: &Manifest) -> Result<()> {
+    let tmp = path.join(MANIFEST_TMP);
+    let file = fs::File::create(&tmp)?;
+    serde_json::to_writer(&file, manifest)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path.join(MANIFEST))?;
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+
+use super::{add_to_value, claim_dir, Engine, KvsEngine, Stats};
+use crate::error::KvsError::{self, KeyNotFound};
+use crate::error::Result;
+
+/// A storage engine backed by the sled embedded database.
+#[derive(Debug, Clone)]
+pub struct SledKvsEngine {
+    db: sled::Db,
+}
+
+impl SledKvsEngine {
+    /// Open a sled database in a directory.
+    ///
+    /// Fails with `WrongEngine` if the directory holds the store of another engine.
+    pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        claim_dir(&path, Engine::Sled)?;
+        // Every write is flushed right away, so sled's periodic flush thread isn't needed. It
+        // would also keep the directory locked for a while after the engine is dropped.
+        let config = sled::Config::new().path(path).flush_every_ms(None);
+        // sled's IO threads can still hold the lock of a database that was just dropped, so
+        // give them a moment to let go of it.
+        let mut attempts = 0;
+        loop {
+            match config.open() {
+                Err(sled::Error::Io(ref err)) if is_lock_error(err) && attempts < 50 => {
+                    attempts += 1;
+                    thread::sleep(Duration::from_millis(20));
+                }
+                result => return Ok(SledKvsEngine { db: result? }),
+            }
+        }
+    }
+}
+
+// Whether sled failed to lock the database because another handle holds it. sled 0.34 reports
+// that as an `Other` error, whose message is all that tells it apart, so this relies on the
+// wording of sled 0.34: the `reopen_sled_while_dropping` test fails if it changes.
+fn is_lock_error(err: &std::io::Error) -> bool {
+    err.kind() == std::io::ErrorKind::Other && err.to_string().starts_with("could not acquire lock")
+}
+
+// The error a transaction was aborted with, or the error of the database.
+fn finish_transaction(result: std::result::Result<(), TransactionError<KvsError>>) -> Result<()> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(TransactionError::Abort(err)) => Err(err),
+        Err(TransactionError::Storage(err)) => Err(err.into()),
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.db.insert(key, value.into_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.db.get(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.db.contains_key(key)?)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.db.remove(key)?.ok_or(KeyNotFound)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let swapped = self
+            .db
+            .compare_and_swap(key, expected, new.map(String::into_bytes))?
+            .is_ok();
+        if swapped {
+            self.db.flush()?;
+        }
+        Ok(swapped)
+    }
+
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        // retry until no other write gets in between the read and the swap
+        loop {
+            let current = self.db.get(&key)?;
+            let value = add_to_value(current.as_deref(), delta)?;
+            let new = value.to_string().into_bytes();
+            if self.db.compare_and_swap(&key, current, Some(new))?.is_ok() {
+                self.db.flush()?;
+                return Ok(value);
+            }
+        }
+    }
+
+    fn rename(&self, old_key: String, new_key: String) -> Result<()> {
+        let moved = self.db.transaction(|tx| {
+            let value = tx
+                .remove(old_key.as_bytes())?
+                .ok_or(ConflictableTransactionError::Abort(KeyNotFound))?;
+            tx.insert(new_key.as_bytes(), value)?;
+            Ok(())
+        });
+        finish_transaction(moved)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn copy(&self, src: String, dst: String) -> Result<()> {
+        let copied = self.db.transaction(|tx| {
+            let value = tx
+                .get(src.as_bytes())?
+                .ok_or(ConflictableTransactionError::Abort(KeyNotFound))?;
+            tx.insert(dst.as_bytes(), value)?;
+            Ok(())
+        });
+        finish_transaction(copied)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // sled doesn't tell apart live and dead bytes, or keep counters
+    fn stats(&self) -> Result<Stats> {
+        Ok(Stats {
+            keys: self.db.len() as u64,
+            live_bytes: self.db.size_on_disk()?,
+            ..Stats::default()
+        })
+    }
+}
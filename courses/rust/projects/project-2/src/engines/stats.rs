@@ -0,0 +1,358 @@
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// The upper bounds of the buckets of a `Latency` histogram, in microseconds: 1, 2 and 5 times
+/// each power of ten from 1µs to 100s. A last bucket counts the operations that took longer.
+pub const LATENCY_BUCKETS: [u64; 27] = [
+    1,
+    2,
+    5,
+    10,
+    20,
+    50,
+    100,
+    200,
+    500,
+    1_000,
+    2_000,
+    5_000,
+    10_000,
+    20_000,
+    50_000,
+    100_000,
+    200_000,
+    500_000,
+    1_000_000,
+    2_000_000,
+    5_000_000,
+    10_000_000,
+    20_000_000,
+    50_000_000,
+    100_000_000,
+    200_000_000,
+    500_000_000,
+];
+
+/// Statistics about a storage engine, returned by `KvsEngine::stats`.
+///
+/// Engines fill in the statistics they keep track of and leave the others at zero. The counters
+/// count from when the engine was opened.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    /// Number of keys, including expired keys that haven't been dropped yet
+    pub keys: u64,
+    /// Bytes on disk holding the current values of the keys
+    pub live_bytes: u64,
+    /// Bytes on disk holding overwritten or removed values, which compaction will reclaim
+    pub dead_bytes: u64,
+    /// Number of segment files
+    pub segments: u64,
+    /// Number of segments compacted
+    pub compactions: u64,
+    /// Number of keys looked up
+    pub reads: u64,
+    /// Number of keys set or removed
+    pub writes: u64,
+    /// Number of lookups answered by the read cache
+    pub cache_hits: u64,
+    /// Number of lookups that had to read from disk
+    pub cache_misses: u64,
+    /// Estimated bytes of memory taken up by the index of the keys
+    #[serde(default)]
+    pub index_bytes: u64,
+    /// Number of keys evicted to keep the store within its capacity limits
+    #[serde(default)]
+    pub evictions: u64,
+    /// Bytes on disk in the value log, live or not
+    #[serde(default)]
+    pub value_log_bytes: u64,
+    /// How long the operations took
+    #[serde(default)]
+    pub latencies: Latencies,
+}
+
+/// The latency histograms of the operations of an engine.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Latencies {
+    /// Keys looked up with `get` or `get_bytes`
+    pub get: Latency,
+    /// Keys set with `set` or `set_bytes`
+    pub set: Latency,
+    /// Keys removed with `remove` or `remove_bytes`, including those that didn't exist
+    pub remove: Latency,
+    /// Segments compacted
+    pub compaction: Latency,
+}
+
+impl Latencies {
+    /// The histograms along with the names of their operations.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &Latency)> {
+        vec![
+            ("get", &self.get),
+            ("set", &self.set),
+            ("remove", &self.remove),
+            ("compaction", &self.compaction),
+        ]
+        .into_iter()
+    }
+
+    /// Add the operations timed in `other`, e.g. by another shard.
+    pub fn merge(&mut self, other: &Latencies) {
+        self.get.merge(&other.get);
+        self.set.merge(&other.set);
+        self.remove.merge(&other.remove);
+        self.compaction.merge(&other.compaction);
+    }
+}
+
+/// A histogram of how long an operation took, with the buckets of `LATENCY_BUCKETS`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Latency {
+    /// Number of operations timed
+    pub count: u64,
+    /// Their durations added up, in microseconds
+    pub sum_micros: u64,
+    /// The longest of them, in microseconds
+    pub max_micros: u64,
+    /// Number of operations in each bucket, not added up, with the longer ones last. Empty if
+    /// no operation was timed.
+    pub buckets: Vec<u64>,
+}
+
+impl Latency {
+    /// The duration that `p` percent of the operations took at most, e.g. the p99 latency for
+    /// a `p` of 99. It is the upper bound of the bucket the percentile falls in, so it is
+    /// overestimated by up to 2.5 times, but never more than the longest duration.
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((p / 100.0 * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                let bound = LATENCY_BUCKETS.get(i).copied().unwrap_or(u64::MAX);
+                return Duration::from_micros(bound.min(self.max_micros));
+            }
+        }
+        Duration::from_micros(self.max_micros)
+    }
+
+    /// The average duration of the operations.
+    pub fn mean(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.checked_div(self.count).unwrap_or(0))
+    }
+
+    /// Write the series of the histogram `name`, with the labels `labels`, in the text format of
+    /// Prometheus.
+    pub(crate) fn write_prometheus(&self, out: &mut String, name: &str, labels: &str) {
+        let mut seen = 0;
+        for (i, &bound) in LATENCY_BUCKETS.iter().enumerate() {
+            seen += self.buckets.get(i).copied().unwrap_or(0);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name,
+                labels,
+                bound as f64 / 1e6,
+                seen
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let _ = writeln!(
+            out,
+            "{}_sum{{{}}} {}",
+            name,
+            labels,
+            self.sum_micros as f64 / 1e6
+        );
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+
+    /// Add the operations timed in `other`.
+    pub fn merge(&mut self, other: &Latency) {
+        if other.buckets.is_empty() {
+            return;
+        }
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; other.buckets.len()];
+        }
+        for (n, other) in self.buckets.iter_mut().zip(&other.buckets) {
+            *n += other;
+        }
+        self.count += other.count;
+        self.sum_micros += other.sum_micros;
+        self.max_micros = self.max_micros.max(other.max_micros);
+    }
+}
+
+/// Times an operation into a `Latency` histogram, from any thread.
+#[derive(Debug, Default)]
+pub(crate) struct LatencyRecorder {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+impl LatencyRecorder {
+    pub(crate) fn record(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = LATENCY_BUCKETS.partition_point(|&bound| bound < micros);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// The histogram of the operations timed so far. The counts are read one at a time, so it
+    /// may be off by the operations timed meanwhile.
+    pub(crate) fn snapshot(&self) -> Latency {
+        let count = self.count.load(Ordering::Relaxed);
+        Latency {
+            count,
+            sum_micros: self.sum_micros.load(Ordering::Relaxed),
+            max_micros: self.max_micros.load(Ordering::Relaxed),
+            buckets: if count == 0 {
+                Vec::new()
+            } else {
+                self.buckets
+                    .iter()
+                    .map(|n| n.load(Ordering::Relaxed))
+                    .collect()
+            },
+        }
+    }
+}
+
+impl Stats {
+    /// The share of lookups answered by the read cache, between 0 and 1.
+    pub fn cache_hit_rate(&self) -> f64 {
+        let lookups = self.cache_hits + self.cache_misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.cache_hits as f64 / lookups as f64
+        }
+    }
+
+    /// The statistics in the text exposition format of Prometheus, with the latencies as
+    /// histograms in seconds.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP kvs_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE kvs_{} {}", name, kind);
+            let _ = writeln!(out, "kvs_{} {}", name, value);
+        };
+        metric("keys", "gauge", "Number of keys", self.keys);
+        metric(
+            "live_bytes",
+            "gauge",
+            "Bytes on disk holding current values",
+            self.live_bytes,
+        );
+        metric(
+            "dead_bytes",
+            "gauge",
+            "Bytes on disk that compaction will reclaim",
+            self.dead_bytes,
+        );
+        metric(
+            "segments",
+            "gauge",
+            "Number of segment files",
+            self.segments,
+        );
+        metric(
+            "index_bytes",
+            "gauge",
+            "Estimated bytes of memory taken up by the index",
+            self.index_bytes,
+        );
+        metric(
+            "compactions_total",
+            "counter",
+            "Segments compacted",
+            self.compactions,
+        );
+        metric("reads_total", "counter", "Keys looked up", self.reads);
+        metric(
+            "writes_total",
+            "counter",
+            "Keys set or removed",
+            self.writes,
+        );
+        metric(
+            "cache_hits_total",
+            "counter",
+            "Lookups answered by the read cache",
+            self.cache_hits,
+        );
+        metric(
+            "cache_misses_total",
+            "counter",
+            "Lookups that read from disk",
+            self.cache_misses,
+        );
+        metric(
+            "evictions_total",
+            "counter",
+            "Keys evicted to stay within the capacity limits",
+            self.evictions,
+        );
+        metric(
+            "value_log_bytes",
+            "gauge",
+            "Bytes on disk in the value log",
+            self.value_log_bytes,
+        );
+
+        let name = "kvs_operation_duration_seconds";
+        let _ = writeln!(out, "# HELP {} How long the operations took", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (op, latency) in self.latencies.iter() {
+            latency.write_prometheus(&mut out, name, &format!("op=\"{}\"", op));
+        }
+        out
+    }
+}
+
+/// One `name:value` line per statistic, like the `INFO` command of Redis.
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "keys:{}", self.keys)?;
+        writeln!(f, "live_bytes:{}", self.live_bytes)?;
+        writeln!(f, "dead_bytes:{}", self.dead_bytes)?;
+        writeln!(f, "segments:{}", self.segments)?;
+        writeln!(f, "compactions:{}", self.compactions)?;
+        writeln!(f, "reads:{}", self.reads)?;
+        writeln!(f, "writes:{}", self.writes)?;
+        writeln!(f, "cache_hits:{}", self.cache_hits)?;
+        writeln!(f, "cache_misses:{}", self.cache_misses)?;
+        writeln!(f, "index_bytes:{}", self.index_bytes)?;
+        writeln!(f, "evictions:{}", self.evictions)?;
+        writeln!(f, "value_log_bytes:{}", self.value_log_bytes)?;
+        write!(f, "cache_hit_rate:{:.4}", self.cache_hit_rate())?;
+        for (op, latency) in self.latencies.iter() {
+            write!(
+                f,
+                "\n{op}_count:{}\n{op}_p50_us:{}\n{op}_p99_us:{}\n{op}_max_us:{}",
+                latency.count,
+                latency.percentile(50.0).as_micros(),
+                latency.percentile(99.0).as_micros(),
+                latency.max_micros,
+                op = op,
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,10 @@
-use crate::error::KvsError::{IoError, SerdeError};
+use crate::error::KvsError::{CsvError, IoError, SerdeError, SledError, TlsError, Utf8Error};
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::io;
 use std::io::Error;
+use std::path::PathBuf;
+use std::string::FromUtf8Error;
 
 /// Errors that can be thrown by this program.
 #[derive(Debug)]
@@ -16,11 +20,479 @@ pub enum KvsError {
 
     /// Failed to deserialize serde_json data to KvStore
     SerdeError(serde_json::Error),
+
+    /// A message of the network protocol can't be encoded in MessagePack, or decoded from it
+    MessagePackError(String),
+
+    /// A record on disk doesn't match its checksum
+    ChecksumMismatch,
+
+    /// A segment was written in a format version this build doesn't know
+    UnsupportedFormat(u8),
+
+    /// A record passed its checksum but can't be decoded
+    InvalidRecord,
+
+    /// The store is encrypted and the key is missing or isn't the one it was encrypted with, or
+    /// a key file doesn't hold a key
+    InvalidEncryptionKey,
+
+    /// The store was opened read-only
+    ReadOnly,
+
+    /// Another process, or another handle in this one, has the store open for writing
+    StoreLocked,
+
+    /// Errors from the sled engine
+    SledError(sled::Error),
+
+    /// A key read or written by a transaction was written by someone else before it committed
+    TransactionConflict,
+
+    /// A value to increment isn't an integer, or incrementing it would overflow
+    InvalidInteger,
+
+    /// A key was merged into, or the store holds merge operands, but no merge operator was set
+    /// with `KvStoreOptions::merge_operator`
+    NoMergeOperator,
+
+    /// A key or value is not valid UTF-8
+    Utf8Error(FromUtf8Error),
+
+    /// The name doesn't match any storage engine
+    UnknownEngine(String),
+
+    /// The directory holds the store of another storage engine, named here
+    WrongEngine(String),
+
+    /// The name doesn't match any server protocol
+    UnknownProtocol(String),
+
+    /// The other end of a connection speaks a version of the network protocol this one doesn't,
+    /// given here
+    UnsupportedProtocolVersion(u32),
+
+    /// The name doesn't match any dump format, segment format version or codec
+    UnknownFormat(String),
+
+    /// Errors reading or writing CSV
+    CsvError(csv::Error),
+
+    /// The server failed to handle a request
+    ServerError(String),
+
+    /// The server only handles requests on connections that authenticated first
+    AuthRequired,
+
+    /// The server didn't accept the credentials
+    AuthFailed,
+
+    /// The user the connection authenticated as may not read, or may not write, the key given
+    /// here, by the ACL of the server
+    Forbidden(String),
+
+    /// A TLS configuration or connection is invalid
+    TlsError(rustls::Error),
+
+    /// The index of the store takes up as much memory as it may, so no key can be added
+    IndexFull,
+
+    /// The server isn't the leader of its Raft cluster. Holds the address of the leader, if the
+    /// server knows it.
+    NotLeader(Option<String>),
+
+    /// The disk holding the store is full, or has less free space left than
+    /// `KvStoreOptions::min_free_space`
+    DiskFull,
+
+    /// Compaction fell behind the writes, past the high-water marks of
+    /// `KvStoreOptions::stall_stale_ratio` or `stall_segments`, and the store was opened with
+    /// `Backpressure::Fail`
+    Backpressure,
+
+    /// A record on disk claims to be longer than `KvStoreOptions::max_record_size`, given here,
+    /// or a write would be
+    RecordTooLarge(u64),
+
+    /// A key was purged with `KvStore::purge`, but a file of the store still holds a record of it
+    PurgeIncomplete,
+
+    /// The record holding the value of `key`, which starts at `offset` in its segment, failed its
+    /// checksum when it was read. The key was dropped rather than given a damaged value.
+    Corruption {
+        /// The key, converted lossily if it isn't valid UTF-8
+        key: String,
+        /// Where the record starts in its segment
+        offset: u64,
+    },
+
+    /// An error that happened in a file of the store, at `offset` in it if it is known
+    InFile {
+        /// The file
+        path: PathBuf,
+        /// Where in the file
+        offset: Option<u64>,
+        /// What went wrong
+        source: Box<KvsError>,
+    },
+
+    /// An error the server sent that can't be rebuilt as it was, since it wraps an error of
+    /// another crate, e.g. an `IoError`
+    Remote(RemoteError),
+}
+
+/// What kind of error a `KvsError` is, which stays the same when it is sent over the network or
+/// given a context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// `KvsError::UnexpectedEOF`
+    UnexpectedEof,
+    /// `KvsError::IoError`
+    Io,
+    /// `KvsError::KeyNotFound`
+    KeyNotFound,
+    /// `KvsError::SerdeError`
+    Serde,
+    /// `KvsError::ChecksumMismatch`
+    ChecksumMismatch,
+    /// `KvsError::UnsupportedFormat`
+    UnsupportedFormat,
+    /// `KvsError::InvalidRecord`
+    InvalidRecord,
+    /// `KvsError::InvalidEncryptionKey`
+    InvalidEncryptionKey,
+    /// `KvsError::ReadOnly`
+    ReadOnly,
+    /// `KvsError::StoreLocked`
+    StoreLocked,
+    /// `KvsError::SledError`
+    Sled,
+    /// `KvsError::TransactionConflict`
+    TransactionConflict,
+    /// `KvsError::InvalidInteger`
+    InvalidInteger,
+    /// `KvsError::NoMergeOperator`
+    NoMergeOperator,
+    /// `KvsError::Utf8Error`
+    Utf8,
+    /// `KvsError::UnknownEngine`
+    UnknownEngine,
+    /// `KvsError::WrongEngine`
+    WrongEngine,
+    /// `KvsError::UnknownProtocol`
+    UnknownProtocol,
+    /// `KvsError::UnsupportedProtocolVersion`
+    UnsupportedProtocolVersion,
+    /// `KvsError::UnknownFormat`
+    UnknownFormat,
+    /// `KvsError::CsvError`
+    Csv,
+    /// `KvsError::ServerError`
+    Server,
+    /// `KvsError::AuthRequired`
+    AuthRequired,
+    /// `KvsError::AuthFailed`
+    AuthFailed,
+    /// `KvsError::Forbidden`
+    Forbidden,
+    /// `KvsError::TlsError`
+    Tls,
+    /// `KvsError::IndexFull`
+    IndexFull,
+    /// `KvsError::NotLeader`
+    NotLeader,
+    /// `KvsError::Corruption`
+    Corruption,
+    /// `KvsError::DiskFull`
+    DiskFull,
+    /// `KvsError::MessagePackError`
+    MessagePack,
+    /// `KvsError::Backpressure`
+    Backpressure,
+    /// `KvsError::RecordTooLarge`
+    RecordTooLarge,
+    /// `KvsError::PurgeIncomplete`
+    PurgeIncomplete,
+}
+
+/// A `KvsError` as it is sent over the network.
+///
+/// Everything about the error is kept, but the errors of other crates it wraps, of which only
+/// the message is.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteError {
+    /// What kind of error it is
+    pub code: ErrorCode,
+    /// The message of the error, as `Display` shows it
+    pub message: String,
+    /// The value the error holds besides its code, e.g. the address of the leader of
+    /// `NotLeader`
+    pub detail: Option<String>,
+    /// The file the error happened in, if it is known
+    pub path: Option<PathBuf>,
+    /// Where in the file the error happened, if it is known
+    pub offset: Option<u64>,
+}
+
+impl KvsError {
+    /// What kind of error this is, whatever its context and wherever it happened.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvsError::UnexpectedEOF => ErrorCode::UnexpectedEof,
+            IoError(_) => ErrorCode::Io,
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            SerdeError(_) => ErrorCode::Serde,
+            KvsError::ChecksumMismatch => ErrorCode::ChecksumMismatch,
+            KvsError::UnsupportedFormat(_) => ErrorCode::UnsupportedFormat,
+            KvsError::InvalidRecord => ErrorCode::InvalidRecord,
+            KvsError::InvalidEncryptionKey => ErrorCode::InvalidEncryptionKey,
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            KvsError::StoreLocked => ErrorCode::StoreLocked,
+            SledError(_) => ErrorCode::Sled,
+            KvsError::TransactionConflict => ErrorCode::TransactionConflict,
+            KvsError::InvalidInteger => ErrorCode::InvalidInteger,
+            KvsError::NoMergeOperator => ErrorCode::NoMergeOperator,
+            Utf8Error(_) => ErrorCode::Utf8,
+            KvsError::UnknownEngine(_) => ErrorCode::UnknownEngine,
+            KvsError::WrongEngine(_) => ErrorCode::WrongEngine,
+            KvsError::UnknownProtocol(_) => ErrorCode::UnknownProtocol,
+            KvsError::UnsupportedProtocolVersion(_) => ErrorCode::UnsupportedProtocolVersion,
+            KvsError::UnknownFormat(_) => ErrorCode::UnknownFormat,
+            CsvError(_) => ErrorCode::Csv,
+            KvsError::ServerError(_) => ErrorCode::Server,
+            KvsError::AuthRequired => ErrorCode::AuthRequired,
+            KvsError::AuthFailed => ErrorCode::AuthFailed,
+            KvsError::Forbidden(_) => ErrorCode::Forbidden,
+            TlsError(_) => ErrorCode::Tls,
+            KvsError::IndexFull => ErrorCode::IndexFull,
+            KvsError::NotLeader(_) => ErrorCode::NotLeader,
+            KvsError::Corruption { .. } => ErrorCode::Corruption,
+            KvsError::DiskFull => ErrorCode::DiskFull,
+            KvsError::Backpressure => ErrorCode::Backpressure,
+            KvsError::RecordTooLarge(_) => ErrorCode::RecordTooLarge,
+            KvsError::PurgeIncomplete => ErrorCode::PurgeIncomplete,
+            KvsError::MessagePackError(_) => ErrorCode::MessagePack,
+            KvsError::InFile { source, .. } => source.code(),
+            KvsError::Remote(remote) => remote.code,
+        }
+    }
+
+    /// The error without its context.
+    pub fn root(&self) -> &KvsError {
+        match self {
+            KvsError::InFile { source, .. } => source.root(),
+            err => err,
+        }
+    }
+
+    /// Add the file the error happened in, and where in it, to the error.
+    pub(crate) fn in_file(self, path: impl Into<PathBuf>, offset: Option<u64>) -> KvsError {
+        KvsError::InFile {
+            path: path.into(),
+            offset,
+            source: Box::new(self),
+        }
+    }
+
+    /// The error as it is sent over the network.
+    pub fn to_remote(&self) -> RemoteError {
+        let (path, mut offset) = match self {
+            KvsError::InFile { path, offset, .. } => (Some(path.clone()), *offset),
+            KvsError::Remote(remote) => (remote.path.clone(), remote.offset),
+            _ => (None, None),
+        };
+        if let KvsError::Corruption { offset: at, .. } = self.root() {
+            offset = Some(*at);
+        }
+        let detail = match self.root() {
+            KvsError::UnsupportedFormat(version) => Some(version.to_string()),
+            KvsError::UnsupportedProtocolVersion(version) => Some(version.to_string()),
+            KvsError::RecordTooLarge(size) => Some(size.to_string()),
+            KvsError::UnknownEngine(name)
+            | KvsError::WrongEngine(name)
+            | KvsError::UnknownProtocol(name)
+            | KvsError::UnknownFormat(name)
+            | KvsError::MessagePackError(name)
+            | KvsError::Forbidden(name)
+            | KvsError::ServerError(name) => Some(name.clone()),
+            KvsError::NotLeader(leader) => leader.clone(),
+            KvsError::Corruption { key, .. } => Some(key.clone()),
+            KvsError::Remote(remote) => remote.detail.clone(),
+            _ => None,
+        };
+        RemoteError {
+            code: self.code(),
+            message: self.root().to_string(),
+            detail,
+            path,
+            offset,
+        }
+    }
+}
+
+impl From<RemoteError> for KvsError {
+    /// Rebuild the error sent by the server, or keep it as a `Remote` error if it wraps an error
+    /// of another crate.
+    fn from(remote: RemoteError) -> Self {
+        let detail = remote.detail.clone().unwrap_or_default();
+        let err = match remote.code {
+            ErrorCode::UnexpectedEof => KvsError::UnexpectedEOF,
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::ChecksumMismatch => KvsError::ChecksumMismatch,
+            ErrorCode::UnsupportedFormat => match detail.parse() {
+                Ok(version) => KvsError::UnsupportedFormat(version),
+                Err(_) => return KvsError::Remote(remote),
+            },
+            ErrorCode::InvalidRecord => KvsError::InvalidRecord,
+            ErrorCode::InvalidEncryptionKey => KvsError::InvalidEncryptionKey,
+            ErrorCode::ReadOnly => KvsError::ReadOnly,
+            ErrorCode::StoreLocked => KvsError::StoreLocked,
+            ErrorCode::TransactionConflict => KvsError::TransactionConflict,
+            ErrorCode::InvalidInteger => KvsError::InvalidInteger,
+            ErrorCode::NoMergeOperator => KvsError::NoMergeOperator,
+            ErrorCode::UnknownEngine => KvsError::UnknownEngine(detail),
+            ErrorCode::WrongEngine => KvsError::WrongEngine(detail),
+            ErrorCode::UnknownProtocol => KvsError::UnknownProtocol(detail),
+            ErrorCode::UnsupportedProtocolVersion => match detail.parse() {
+                Ok(version) => KvsError::UnsupportedProtocolVersion(version),
+                Err(_) => return KvsError::Remote(remote),
+            },
+            ErrorCode::UnknownFormat => KvsError::UnknownFormat(detail),
+            ErrorCode::Server => KvsError::ServerError(remote.detail.unwrap_or(remote.message)),
+            ErrorCode::AuthRequired => KvsError::AuthRequired,
+            ErrorCode::AuthFailed => KvsError::AuthFailed,
+            ErrorCode::Forbidden => KvsError::Forbidden(detail),
+            ErrorCode::IndexFull => KvsError::IndexFull,
+            ErrorCode::NotLeader => KvsError::NotLeader(remote.detail),
+            ErrorCode::DiskFull => KvsError::DiskFull,
+            ErrorCode::Backpressure => KvsError::Backpressure,
+            ErrorCode::RecordTooLarge => match detail.parse() {
+                Ok(size) => KvsError::RecordTooLarge(size),
+                Err(_) => return KvsError::Remote(remote),
+            },
+            ErrorCode::PurgeIncomplete => KvsError::PurgeIncomplete,
+            ErrorCode::MessagePack => KvsError::MessagePackError(detail),
+            // the offset is that of the record, not another one in the same file
+            ErrorCode::Corruption => {
+                let err = KvsError::Corruption {
+                    key: detail,
+                    offset: remote.offset.unwrap_or_default(),
+                };
+                return match remote.path {
+                    Some(path) => err.in_file(path, None),
+                    None => err,
+                };
+            }
+            ErrorCode::Io
+            | ErrorCode::Serde
+            | ErrorCode::Sled
+            | ErrorCode::Utf8
+            | ErrorCode::Csv
+            | ErrorCode::Tls => return KvsError::Remote(remote),
+        };
+        match remote.path {
+            Some(path) => err.in_file(path, remote.offset),
+            None => err,
+        }
+    }
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvsError::UnexpectedEOF => write!(f, "unexpected end of file"),
+            IoError(err) => write!(f, "I/O error: {}", err),
+            KvsError::KeyNotFound => write!(f, "key not found"),
+            SerdeError(err) => write!(f, "invalid JSON: {}", err),
+            KvsError::MessagePackError(err) => write!(f, "invalid MessagePack: {}", err),
+            KvsError::ChecksumMismatch => write!(f, "checksum mismatch"),
+            KvsError::UnsupportedFormat(version) => {
+                write!(f, "unsupported segment format version {}", version)
+            }
+            KvsError::InvalidRecord => write!(f, "invalid record"),
+            KvsError::InvalidEncryptionKey => write!(f, "missing or wrong encryption key"),
+            KvsError::ReadOnly => write!(f, "the store is read-only"),
+            KvsError::StoreLocked => write!(f, "the store is open for writing elsewhere"),
+            SledError(err) => write!(f, "sled error: {}", err),
+            KvsError::TransactionConflict => write!(f, "transaction conflict"),
+            KvsError::InvalidInteger => write!(f, "the value is not an integer or would overflow"),
+            KvsError::NoMergeOperator => write!(f, "no merge operator is set"),
+            Utf8Error(err) => write!(f, "invalid UTF-8: {}", err),
+            KvsError::UnknownEngine(name) => write!(f, "unknown engine '{}'", name),
+            KvsError::WrongEngine(name) => {
+                write!(f, "the directory holds a store of the {} engine", name)
+            }
+            KvsError::UnknownProtocol(name) => write!(f, "unknown protocol '{}'", name),
+            KvsError::UnsupportedProtocolVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            KvsError::UnknownFormat(name) => write!(f, "unknown format '{}'", name),
+            CsvError(err) => write!(f, "CSV error: {}", err),
+            KvsError::ServerError(message) => write!(f, "server error: {}", message),
+            KvsError::AuthRequired => write!(f, "authentication required"),
+            KvsError::AuthFailed => write!(f, "authentication failed"),
+            KvsError::Forbidden(key) => write!(f, "no access to key '{}'", key),
+            TlsError(err) => write!(f, "TLS error: {}", err),
+            KvsError::IndexFull => write!(f, "the index is full"),
+            KvsError::NotLeader(Some(leader)) => write!(f, "not the leader, which is {}", leader),
+            KvsError::NotLeader(None) => write!(f, "not the leader, which is unknown"),
+            KvsError::DiskFull => write!(f, "the disk is full"),
+            KvsError::Backpressure => write!(f, "compaction fell behind, try again later"),
+            KvsError::RecordTooLarge(size) => {
+                write!(f, "a record of {} bytes is larger than allowed", size)
+            }
+            KvsError::PurgeIncomplete => write!(f, "a record of the purged key is left"),
+            KvsError::Corruption { key, offset } => write!(
+                f,
+                "the record of key '{}' at offset {} is corrupt, and the key was dropped",
+                key, offset
+            ),
+            KvsError::InFile {
+                path,
+                offset: Some(offset),
+                source,
+            } => write!(f, "{} at offset {}: {}", path.display(), offset, source),
+            KvsError::InFile {
+                path,
+                offset: None,
+                source,
+            } => write!(f, "{}: {}", path.display(), source),
+            KvsError::Remote(remote) => match (&remote.path, remote.offset) {
+                (Some(path), Some(offset)) => write!(
+                    f,
+                    "{} at offset {}: {}",
+                    path.display(),
+                    offset,
+                    remote.message
+                ),
+                (Some(path), None) => write!(f, "{}: {}", path.display(), remote.message),
+                (None, _) => write!(f, "{}", remote.message),
+            },
+        }
+    }
+}
+
+impl std::error::Error for KvsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IoError(err) => Some(err),
+            SerdeError(err) => Some(err),
+            SledError(err) => Some(err),
+            Utf8Error(err) => Some(err),
+            CsvError(err) => Some(err),
+            TlsError(err) => Some(err),
+            KvsError::InFile { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for KvsError {
     fn from(err: Error) -> Self {
-        IoError(err)
+        match err.kind() {
+            io::ErrorKind::StorageFull => KvsError::DiskFull,
+            _ => IoError(err),
+        }
     }
 }
 
@@ -30,5 +502,41 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<rmp_serde::encode::Error> for KvsError {
+    fn from(err: rmp_serde::encode::Error) -> Self {
+        KvsError::MessagePackError(err.to_string())
+    }
+}
+
+impl From<rmp_serde::decode::Error> for KvsError {
+    fn from(err: rmp_serde::decode::Error) -> Self {
+        KvsError::MessagePackError(err.to_string())
+    }
+}
+
+impl From<sled::Error> for KvsError {
+    fn from(err: sled::Error) -> Self {
+        SledError(err)
+    }
+}
+
+impl From<csv::Error> for KvsError {
+    fn from(err: csv::Error) -> Self {
+        CsvError(err)
+    }
+}
+
+impl From<rustls::Error> for KvsError {
+    fn from(err: rustls::Error) -> Self {
+        TlsError(err)
+    }
+}
+
+impl From<FromUtf8Error> for KvsError {
+    fn from(err: FromUtf8Error) -> Self {
+        Utf8Error(err)
+    }
+}
+
 /// A type alias for Results.
 pub type Result<T> = std::result::Result<T, KvsError>;
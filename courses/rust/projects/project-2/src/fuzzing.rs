@@ -0,0 +1,67 @@
+//! Entry points for the fuzz targets in `fuzz/`, built with the `fuzzing` feature. Not part of
+//! the API.
+//!
+//! Each one feeds arbitrary bytes to a parser, which must turn them down with an error rather
+//! than panic, and must not allocate more than the bytes it was given call for, whatever the
+//! lengths they hold say. Run a target with `cargo +nightly fuzz run <target>` from the
+//! directory of the crate, with `cargo-fuzz` installed.
+
+use crate::common::{ChunkReader, Codec, Request};
+use crate::engines::fuzzing as kvs;
+use crate::grpc::fuzzing as grpc;
+use crate::http::fuzzing as http;
+use crate::resp::read_command;
+
+/// Open `data` as a segment of a `KvStore`, and decode each of its records.
+pub fn segment(data: &[u8]) {
+    kvs::segment(data);
+}
+
+/// Decode `data` as the data of a record of a `KvStore`.
+pub fn record(data: &[u8]) {
+    kvs::record(data);
+}
+
+/// Parse `data` as the hint file of a segment of a `KvStore`.
+pub fn hint(data: &[u8]) {
+    kvs::hint(data);
+}
+
+/// Read `data` as the messages a client sends to a server of the native protocol, in each
+/// codec, as long as they parse.
+pub fn native_messages(data: &[u8]) {
+    for codec in [Codec::Json, Codec::MessagePack] {
+        let mut reader = data;
+        while let Ok(Some(_)) = codec.read_message::<Request>(&mut reader) {}
+    }
+}
+
+/// Read `data` as the chunks of a value a client streams to a server of the native protocol.
+pub fn chunks(data: &[u8]) {
+    let _ = ChunkReader::new(data).drain();
+}
+
+/// Read `data` as the commands a client sends to a server of the Redis protocol, as long as
+/// they parse.
+pub fn resp_commands(data: &[u8]) {
+    let mut reader = data;
+    while let Ok(Some(_)) = read_command(&mut reader) {}
+}
+
+/// Read `data` as the heads of the requests a client sends to the HTTP front end of a server,
+/// as long as they parse.
+pub fn http_heads(data: &[u8]) {
+    http::heads(data);
+}
+
+/// Read `data` as the HTTP/2 frames a client sends to the gRPC front end of a server, as long
+/// as they parse.
+pub fn h2_frames(data: &[u8]) {
+    grpc::frames(data);
+}
+
+/// Decode `data` as the HPACK header blocks a client sends to the gRPC front end of a server,
+/// each after a byte holding its length, as long as they decode.
+pub fn hpack_blocks(data: &[u8]) {
+    grpc::header_blocks(data);
+}
@@ -0,0 +1,737 @@
+//! Just enough of HTTP/2, as RFC 9113 defines it, to serve gRPC.
+//!
+//! The frames of a connection are read on the thread serving it, which hands each request it
+//! read in full to a thread of its own. That thread answers it with frames written under a lock,
+//! waiting for the client to open the windows of flow control when they run out. Server push and
+//! priorities aren't supported, nor the upgrade from HTTP/1.1, so clients have to know that the
+//! server speaks HTTP/2, as gRPC clients do.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+use log::{debug, error};
+
+use super::hpack::{self, Decoder, Header};
+use crate::error::Result;
+use crate::tls::Stream;
+
+// What a client sends first, for the server to know it speaks HTTP/2.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+// The largest frame the server reads, which is the least HTTP/2 allows, and the largest it
+// writes until the client allows more.
+const MAX_FRAME_SIZE: usize = 16 * 1024;
+const MAX_ALLOWED_FRAME_SIZE: u32 = (1 << 24) - 1;
+// The windows of flow control the connection and each stream start with.
+const DEFAULT_WINDOW: i64 = 65535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+// How many requests a client may have in flight at once.
+const MAX_CONCURRENT_STREAMS: usize = 100;
+
+/// A request read in full.
+#[derive(Debug)]
+pub(super) struct Request {
+    pub(super) headers: Vec<Header>,
+    pub(super) body: Vec<u8>,
+    /// Whether the body was longer than the server takes, and was cut off there.
+    pub(super) truncated: bool,
+}
+
+impl Request {
+    /// The value of the header `name`, if the request has it.
+    pub(super) fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name.as_bytes())
+            .map(|(_, value)| &value[..])
+    }
+}
+
+/// Answers a request: with headers, data, then trailers, the last of which ends the stream.
+///
+/// A stream dropped before it ended is reset.
+pub(super) struct Responder {
+    conn: Arc<Shared>,
+    id: u32,
+}
+
+impl Responder {
+    /// Send headers, or trailers once data was sent.
+    pub(super) fn headers(&self, headers: &[(&str, &str)], end_stream: bool) -> io::Result<()> {
+        if !self.conn.state().streams.contains_key(&self.id) {
+            return Err(reset());
+        }
+        let block = hpack::encode(headers);
+        let max_frame_size = self.conn.state().max_frame_size;
+        {
+            // The frames of a header block can't have others in between.
+            let mut writer = self.conn.writer();
+            let mut chunks = block.chunks(max_frame_size).peekable();
+            let mut kind = HEADERS;
+            let mut flags = if end_stream { END_STREAM } else { 0 };
+            while let Some(chunk) = chunks.next() {
+                if chunks.peek().is_none() {
+                    flags |= END_HEADERS;
+                }
+                write_frame(&mut *writer, kind, flags, self.id, chunk)?;
+                kind = CONTINUATION;
+                flags = 0;
+            }
+            writer.flush()?;
+        }
+        if end_stream {
+            self.conn.close_stream(self.id);
+        }
+        Ok(())
+    }
+
+    /// Send data, in as many frames as the windows of flow control take, waiting for them to
+    /// open as long as the client reads the connection.
+    pub(super) fn data(&self, mut data: &[u8], end_stream: bool) -> io::Result<()> {
+        loop {
+            let len = self.conn.reserve(self.id, data.len())?;
+            let (chunk, rest) = data.split_at(len);
+            let flags = if rest.is_empty() && end_stream {
+                END_STREAM
+            } else {
+                0
+            };
+            self.conn.send(DATA, flags, self.id, chunk)?;
+            data = rest;
+            if data.is_empty() {
+                break;
+            }
+        }
+        if end_stream {
+            self.conn.close_stream(self.id);
+        }
+        Ok(())
+    }
+
+    /// Whether the stream can still be answered: the client didn't reset it, and still reads
+    /// the connection.
+    pub(super) fn is_open(&self) -> bool {
+        let state = self.conn.state();
+        !state.reading_done && state.streams.contains_key(&self.id)
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        if self.conn.close_stream(self.id) {
+            debug!("Resetting stream {}, which wasn't answered", self.id);
+            if let Err(err) = self
+                .conn
+                .send(RST_STREAM, 0, self.id, &INTERNAL_ERROR.to_be_bytes())
+            {
+                debug!("Failed to reset stream {}: {}", self.id, err);
+            }
+        }
+    }
+}
+
+/// What the threads answering the requests of a connection share.
+struct Shared {
+    writer: Mutex<BufWriter<Stream>>,
+    state: Mutex<SendState>,
+    // notified when a window opens, or a stream or the connection closes
+    changed: Condvar,
+}
+
+// What the client lets the server send.
+struct SendState {
+    window: i64,
+    // the windows of the streams being answered
+    streams: HashMap<u32, i64>,
+    initial_window: i64,
+    max_frame_size: usize,
+    // whether the client stopped sending, so no window will open anymore
+    reading_done: bool,
+}
+
+impl Shared {
+    fn writer(&self) -> MutexGuard<'_, BufWriter<Stream>> {
+        self.writer.lock().expect("HTTP/2 writer lock poisoned")
+    }
+
+    fn state(&self) -> MutexGuard<'_, SendState> {
+        self.state.lock().expect("HTTP/2 state lock poisoned")
+    }
+
+    // Write a frame, and flush it.
+    fn send(&self, kind: u8, flags: u8, stream: u32, payload: &[u8]) -> io::Result<()> {
+        let mut writer = self.writer();
+        write_frame(&mut *writer, kind, flags, stream, payload)?;
+        writer.flush()
+    }
+
+    // Wait until `len` bytes of data, or some of them, can be sent on `stream`, and return how
+    // many.
+    fn reserve(&self, stream: u32, len: usize) -> io::Result<usize> {
+        let mut state = self.state();
+        loop {
+            let stream_window = match state.streams.get(&stream) {
+                Some(&window) => window,
+                None => return Err(reset()),
+            };
+            let available = cmp::min(state.window, stream_window);
+            let n = cmp::min(
+                len,
+                cmp::min(available.max(0) as usize, state.max_frame_size),
+            );
+            if n > 0 || len == 0 {
+                state.window -= n as i64;
+                *state
+                    .streams
+                    .get_mut(&stream)
+                    .expect("stream was just found") -= n as i64;
+                return Ok(n);
+            }
+            if state.reading_done {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "the client stopped reading",
+                ));
+            }
+            state = self
+                .changed
+                .wait(state)
+                .expect("HTTP/2 state lock poisoned");
+        }
+    }
+
+    // Stop answering `stream`, returning whether it was being answered.
+    fn close_stream(&self, stream: u32) -> bool {
+        let closed = self.state().streams.remove(&stream).is_some();
+        self.changed.notify_all();
+        closed
+    }
+}
+
+fn reset() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "the stream was reset")
+}
+
+/// A frame read from the client.
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+/// Why a connection can't go on.
+enum ConnError {
+    Io(io::Error),
+    // the error code sent to the client, and what went wrong
+    Protocol(u32, &'static str),
+}
+
+impl From<io::Error> for ConnError {
+    fn from(err: io::Error) -> ConnError {
+        ConnError::Io(err)
+    }
+}
+
+/// The state of a connection that only the thread reading it uses.
+struct Connection<F> {
+    conn: Arc<Shared>,
+    handle: Arc<F>,
+    decoder: Decoder,
+    // the requests still being read
+    receiving: HashMap<u32, Request>,
+    last_stream: u32,
+    max_body: usize,
+    // whether the client said it won't start more requests
+    going_away: bool,
+    handlers: Vec<JoinHandle<()>>,
+}
+
+/// Serve the requests of a connection until the client closes it, calling `handle` for each on a
+/// thread of its own, with the body of a request cut off at `max_body` bytes. Returns once every
+/// request was answered.
+pub(super) fn serve<F>(stream: Stream, max_body: usize, handle: F) -> Result<()>
+where
+    F: Fn(Request, Responder) + Send + Sync + 'static,
+{
+    let mut reader = BufReader::new(&stream);
+    let mut preface = [0; PREFACE.len()];
+    reader.read_exact(&mut preface)?;
+    if preface != PREFACE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not an HTTP/2 connection").into());
+    }
+    let conn = Arc::new(Shared {
+        writer: Mutex::new(BufWriter::new(stream.try_clone()?)),
+        state: Mutex::new(SendState {
+            window: DEFAULT_WINDOW,
+            streams: HashMap::new(),
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: MAX_FRAME_SIZE,
+            reading_done: false,
+        }),
+        changed: Condvar::new(),
+    });
+    let mut settings = Vec::new();
+    for &(id, value) in &[
+        (
+            SETTINGS_MAX_CONCURRENT_STREAMS,
+            MAX_CONCURRENT_STREAMS as u32,
+        ),
+        (
+            SETTINGS_MAX_HEADER_LIST_SIZE,
+            hpack::MAX_HEADER_LIST_SIZE as u32,
+        ),
+    ] {
+        settings.extend_from_slice(&id.to_be_bytes());
+        settings.extend_from_slice(&value.to_be_bytes());
+    }
+    conn.send(SETTINGS, 0, 0, &settings)?;
+
+    let mut connection = Connection {
+        conn: Arc::clone(&conn),
+        handle: Arc::new(handle),
+        decoder: Decoder::new(),
+        receiving: HashMap::new(),
+        last_stream: 0,
+        max_body,
+        going_away: false,
+        handlers: Vec::new(),
+    };
+    let result = connection.read_frames(&mut reader);
+    conn.state().reading_done = true;
+    conn.changed.notify_all();
+    for handler in connection.handlers.drain(..) {
+        if handler.join().is_err() {
+            error!("A gRPC call panicked");
+        }
+    }
+    let (code, result) = match result {
+        Ok(()) => (NO_ERROR, Ok(())),
+        Err(ConnError::Protocol(code, message)) => (
+            code,
+            Err(io::Error::new(io::ErrorKind::InvalidData, message).into()),
+        ),
+        Err(ConnError::Io(err)) => return Err(err.into()),
+    };
+    let mut goaway = connection.last_stream.to_be_bytes().to_vec();
+    goaway.extend_from_slice(&code.to_be_bytes());
+    if let Err(err) = conn.send(GOAWAY, 0, 0, &goaway) {
+        debug!("Failed to send GOAWAY: {}", err);
+    }
+    result
+}
+
+impl<F> Connection<F>
+where
+    F: Fn(Request, Responder) + Send + Sync + 'static,
+{
+    // Handle the frames of the client until it closes the connection.
+    fn read_frames(&mut self, reader: &mut impl Read) -> std::result::Result<(), ConnError> {
+        // the header block being read, with the stream and flags of its HEADERS frame
+        let mut block: Option<(u32, u8, Vec<u8>)> = None;
+        while let Some(frame) = read_frame(reader)? {
+            if let Some((stream, _, _)) = block {
+                if frame.kind != CONTINUATION || frame.stream != stream {
+                    return Err(ConnError::Protocol(
+                        PROTOCOL_ERROR,
+                        "a header block was cut off",
+                    ));
+                }
+            }
+            match frame.kind {
+                DATA => self.data(frame)?,
+                HEADERS => {
+                    let fragment = unpad(&frame)?;
+                    if frame.flags & END_HEADERS != 0 {
+                        self.headers(frame.stream, frame.flags, fragment)?;
+                    } else {
+                        block = Some((frame.stream, frame.flags, fragment.to_vec()));
+                    }
+                }
+                CONTINUATION => {
+                    let (stream, flags, mut bytes) = block.take().ok_or(ConnError::Protocol(
+                        PROTOCOL_ERROR,
+                        "CONTINUATION without HEADERS",
+                    ))?;
+                    bytes.extend_from_slice(&frame.payload);
+                    if bytes.len() > hpack::MAX_HEADER_LIST_SIZE {
+                        return Err(ConnError::Protocol(
+                            ENHANCE_YOUR_CALM,
+                            "the headers are too large",
+                        ));
+                    }
+                    if frame.flags & END_HEADERS != 0 {
+                        self.headers(stream, flags, &bytes)?;
+                    } else {
+                        block = Some((stream, flags, bytes));
+                    }
+                }
+                RST_STREAM => {
+                    if frame.stream == 0 {
+                        return Err(ConnError::Protocol(
+                            PROTOCOL_ERROR,
+                            "RST_STREAM on stream 0",
+                        ));
+                    }
+                    self.receiving.remove(&frame.stream);
+                    self.conn.close_stream(frame.stream);
+                }
+                SETTINGS => self.settings(frame)?,
+                PING if frame.flags & ACK == 0 => {
+                    if frame.payload.len() != 8 {
+                        return Err(ConnError::Protocol(FRAME_SIZE_ERROR, "PING isn't 8 bytes"));
+                    }
+                    self.conn.send(PING, ACK, 0, &frame.payload)?;
+                }
+                GOAWAY => self.going_away = true,
+                WINDOW_UPDATE => self.window_update(frame)?,
+                PUSH_PROMISE => {
+                    return Err(ConnError::Protocol(
+                        PROTOCOL_ERROR,
+                        "clients can't push streams",
+                    ))
+                }
+                // priorities, the acknowledgements of pings and the frames of extensions
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn headers(
+        &mut self,
+        stream: u32,
+        flags: u8,
+        block: &[u8],
+    ) -> std::result::Result<(), ConnError> {
+        // decoded whatever happens to the stream, to keep the table in step with the client
+        let headers = self.decoder.decode(block).ok_or(ConnError::Protocol(
+            COMPRESSION_ERROR,
+            "a header block isn't valid",
+        ))?;
+        if self.receiving.contains_key(&stream) {
+            // the trailers of a request, which end it
+            if flags & END_STREAM == 0 {
+                return Err(ConnError::Protocol(
+                    PROTOCOL_ERROR,
+                    "trailers don't end the stream",
+                ));
+            }
+            self.dispatch(stream);
+            return Ok(());
+        }
+        if stream.is_multiple_of(2) || stream <= self.last_stream {
+            return Err(ConnError::Protocol(
+                PROTOCOL_ERROR,
+                "streams have to be opened with increasing odd numbers",
+            ));
+        }
+        self.last_stream = stream;
+        if self.going_away {
+            return Ok(());
+        }
+        {
+            let mut state = self.conn.state();
+            if state.streams.len() >= MAX_CONCURRENT_STREAMS {
+                drop(state);
+                self.conn
+                    .send(RST_STREAM, 0, stream, &REFUSED_STREAM.to_be_bytes())?;
+                return Ok(());
+            }
+            let window = state.initial_window;
+            state.streams.insert(stream, window);
+        }
+        self.receiving.insert(
+            stream,
+            Request {
+                headers,
+                body: Vec::new(),
+                truncated: false,
+            },
+        );
+        if flags & END_STREAM != 0 {
+            self.dispatch(stream);
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, frame: Frame) -> std::result::Result<(), ConnError> {
+        if frame.stream == 0 {
+            return Err(ConnError::Protocol(PROTOCOL_ERROR, "DATA on stream 0"));
+        }
+        let data = unpad(&frame)?;
+        // The window is given back at once, padding included, as the body is kept whole anyway.
+        let len = frame.payload.len() as u32;
+        if len > 0 {
+            self.conn.send(WINDOW_UPDATE, 0, 0, &len.to_be_bytes())?;
+        }
+        let request = match self.receiving.get_mut(&frame.stream) {
+            Some(request) => request,
+            None => {
+                self.conn
+                    .send(RST_STREAM, 0, frame.stream, &STREAM_CLOSED.to_be_bytes())?;
+                return Ok(());
+            }
+        };
+        if request.body.len() + data.len() > self.max_body {
+            request.truncated = true;
+        } else {
+            request.body.extend_from_slice(data);
+        }
+        if frame.flags & END_STREAM != 0 {
+            self.dispatch(frame.stream);
+        } else if len > 0 {
+            self.conn
+                .send(WINDOW_UPDATE, 0, frame.stream, &len.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn settings(&mut self, frame: Frame) -> std::result::Result<(), ConnError> {
+        if frame.stream != 0 {
+            return Err(ConnError::Protocol(PROTOCOL_ERROR, "SETTINGS on a stream"));
+        }
+        if frame.flags & ACK != 0 {
+            return Ok(());
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err(ConnError::Protocol(
+                FRAME_SIZE_ERROR,
+                "SETTINGS isn't made of 6-byte settings",
+            ));
+        }
+        for setting in frame.payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if i64::from(value) > MAX_WINDOW {
+                        return Err(ConnError::Protocol(
+                            FLOW_CONTROL_ERROR,
+                            "the initial window is too large",
+                        ));
+                    }
+                    let mut state = self.conn.state();
+                    let delta = i64::from(value) - state.initial_window;
+                    state.initial_window = i64::from(value);
+                    for window in state.streams.values_mut() {
+                        *window += delta;
+                    }
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(MAX_FRAME_SIZE as u32..=MAX_ALLOWED_FRAME_SIZE).contains(&value) {
+                        return Err(ConnError::Protocol(
+                            PROTOCOL_ERROR,
+                            "the maximum frame size isn't valid",
+                        ));
+                    }
+                    self.conn.state().max_frame_size = value as usize;
+                }
+                // the encoder doesn't use the dynamic table, whatever its size
+                _ => {}
+            }
+        }
+        self.conn.changed.notify_all();
+        self.conn.send(SETTINGS, ACK, 0, &[])?;
+        Ok(())
+    }
+
+    fn window_update(&mut self, frame: Frame) -> std::result::Result<(), ConnError> {
+        if frame.payload.len() != 4 {
+            return Err(ConnError::Protocol(
+                FRAME_SIZE_ERROR,
+                "WINDOW_UPDATE isn't 4 bytes",
+            ));
+        }
+        let increment = i64::from(
+            u32::from_be_bytes([
+                frame.payload[0],
+                frame.payload[1],
+                frame.payload[2],
+                frame.payload[3],
+            ]) & 0x7fff_ffff,
+        );
+        let mut state = self.conn.state();
+        let window = if frame.stream == 0 {
+            &mut state.window
+        } else {
+            match state.streams.get_mut(&frame.stream) {
+                Some(window) => window,
+                // a stream that was answered already
+                None => return Ok(()),
+            }
+        };
+        *window += increment;
+        if increment == 0 || *window > MAX_WINDOW {
+            if frame.stream == 0 {
+                return Err(ConnError::Protocol(
+                    FLOW_CONTROL_ERROR,
+                    "the window of the connection isn't valid",
+                ));
+            }
+            state.streams.remove(&frame.stream);
+            drop(state);
+            self.conn.changed.notify_all();
+            self.conn.send(
+                RST_STREAM,
+                0,
+                frame.stream,
+                &FLOW_CONTROL_ERROR.to_be_bytes(),
+            )?;
+            return Ok(());
+        }
+        drop(state);
+        self.conn.changed.notify_all();
+        Ok(())
+    }
+
+    // Answer a request that was read in full on a thread of its own.
+    fn dispatch(&mut self, stream: u32) {
+        let request = match self.receiving.remove(&stream) {
+            Some(request) => request,
+            None => return,
+        };
+        let responder = Responder {
+            conn: Arc::clone(&self.conn),
+            id: stream,
+        };
+        let handle = Arc::clone(&self.handle);
+        self.handlers.retain(|handler| !handler.is_finished());
+        let spawned = thread::Builder::new()
+            .name("kvs-grpc-call".to_owned())
+            .spawn(move || handle(request, responder));
+        match spawned {
+            Ok(handler) => self.handlers.push(handler),
+            // the responder was dropped with the closure, which reset the stream
+            Err(err) => error!("Failed to start a thread for a gRPC call: {}", err),
+        }
+    }
+}
+
+/// Read a frame, or `None` if the client closed the connection in between two.
+fn read_frame(reader: &mut impl Read) -> std::result::Result<Option<Frame>, ConnError> {
+    let mut header = [0; 9];
+    loop {
+        match reader.read(&mut header[..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    reader.read_exact(&mut header[1..])?;
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(ConnError::Protocol(
+            FRAME_SIZE_ERROR,
+            "a frame is too large",
+        ));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(Frame {
+        kind: header[3],
+        flags: header[4],
+        stream: u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff,
+        payload,
+    }))
+}
+
+/// The payload of a DATA or HEADERS frame, without its padding, nor the priority of HEADERS.
+fn unpad(frame: &Frame) -> std::result::Result<&[u8], ConnError> {
+    let invalid = || ConnError::Protocol(PROTOCOL_ERROR, "the padding of a frame isn't valid");
+    let mut payload = &frame.payload[..];
+    let mut padding = 0;
+    if frame.flags & PADDED != 0 {
+        let (&len, rest) = payload.split_first().ok_or_else(invalid)?;
+        padding = usize::from(len);
+        payload = rest;
+    }
+    if frame.kind == HEADERS && frame.flags & PRIORITY != 0 {
+        payload = payload.get(5..).ok_or(ConnError::Protocol(
+            FRAME_SIZE_ERROR,
+            "HEADERS is too short for its priority",
+        ))?;
+    }
+    let len = payload.len().checked_sub(padding).ok_or_else(invalid)?;
+    Ok(&payload[..len])
+}
+
+fn write_frame(
+    writer: &mut impl Write,
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: &[u8],
+) -> io::Result<()> {
+    let len = (payload.len() as u32).to_be_bytes();
+    writer.write_all(&len[1..])?;
+    writer.write_all(&[kind, flags])?;
+    writer.write_all(&stream.to_be_bytes())?;
+    writer.write_all(payload)
+}
+
+/// The decoders of what clients send, for the fuzz targets. See the `fuzzing` module of the
+/// crate.
+#[cfg(feature = "fuzzing")]
+pub(crate) mod fuzzing {
+    use super::{read_frame, unpad, Decoder, DATA, HEADERS};
+
+    /// Read `data` as frames, without their padding for those that have some, as long as they
+    /// parse.
+    pub(crate) fn frames(data: &[u8]) {
+        let mut reader = data;
+        while let Ok(Some(frame)) = read_frame(&mut reader) {
+            if frame.kind == DATA || frame.kind == HEADERS {
+                let _ = unpad(&frame);
+            }
+        }
+    }
+
+    /// Decode `data` as header blocks, each after a byte holding its length, with the dynamic
+    /// table the blocks before it left, as long as they decode.
+    pub(crate) fn header_blocks(mut data: &[u8]) {
+        let mut decoder = Decoder::new();
+        while let Some((&len, rest)) = data.split_first() {
+            let (block, rest) = rest.split_at(usize::from(len).min(rest.len()));
+            if decoder.decode(block).is_none() {
+                return;
+            }
+            data = rest;
+        }
+    }
+}
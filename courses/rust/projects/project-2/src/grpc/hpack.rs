@@ -0,0 +1,395 @@
+//! HPACK, the compression of the headers of HTTP/2, as RFC 7541 defines it.
+//!
+//! Decoding keeps the dynamic table the client adds to. Encoding never adds to it, nor uses the
+//! Huffman code, so that the headers of every stream can be encoded on the thread answering it
+//! without state shared with the others.
+
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+/// A header, whose name and value may not be valid UTF-8.
+pub(super) type Header = (Vec<u8>, Vec<u8>);
+
+// The size of the dynamic table, which is the default of HTTP/2.
+pub(super) const TABLE_SIZE: usize = 4096;
+// The most the headers of a request may take, counted as HTTP/2 does.
+pub(super) const MAX_HEADER_LIST_SIZE: usize = 64 * 1024;
+// What each entry of the dynamic table takes on top of its name and value.
+const ENTRY_OVERHEAD: usize = 32;
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// The Huffman code of each byte and of the end of string, as its bits and their number.
+#[rustfmt::skip]
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28),
+    (0xfffffe4, 28), (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28),
+    (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28),
+    (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28),
+    (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28),
+    (0xffffff8, 28), (0xffffff9, 28), (0xffffffa, 28), (0xffffffb, 28),
+    (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11),
+    (0x3fa, 10), (0x3fb, 10), (0xf9, 8), (0x7fb, 11),
+    (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6),
+    (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6),
+    (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10),
+    (0x1ffa, 13), (0x21, 6), (0x5d, 7), (0x5e, 7),
+    (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7),
+    (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7),
+    (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7),
+    (0xfc, 8), (0x73, 7), (0xfd, 8), (0x1ffb, 13),
+    (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5),
+    (0x24, 6), (0x5, 5), (0x25, 6), (0x26, 6),
+    (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5),
+    (0x2b, 6), (0x76, 7), (0x2c, 6), (0x8, 5),
+    (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15),
+    (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28),
+    (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23),
+    (0x3fffd6, 22), (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23),
+    (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23),
+    (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23),
+    (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24),
+    (0x3fffda, 22), (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22),
+    (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24),
+    (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23),
+    (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23),
+    (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22),
+    (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19),
+    (0x3fffe7, 22), (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25),
+    (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25),
+    (0x7fff2, 19), (0x1fffe3, 21), (0x3ffffe6, 26), (0x7ffffe0, 27),
+    (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26),
+    (0xffffffd, 28), (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27),
+    (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23),
+    (0x3fffea, 22), (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25),
+    (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26),
+    (0x7ffffe7, 27), (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27),
+    (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+const EOS: u16 = 256;
+// Marks a child of the Huffman tree that is a symbol rather than a node.
+const LEAF: u16 = 0x8000;
+
+/// Decodes the header blocks of a connection, which share its dynamic table.
+#[derive(Debug)]
+pub(super) struct Decoder {
+    // the newest entry first
+    table: VecDeque<Header>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Decoder {
+    pub(super) fn new() -> Decoder {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: TABLE_SIZE,
+        }
+    }
+
+    /// The headers of a block, or `None` if it isn't valid, which leaves the dynamic table out
+    /// of step with that of the client, so the connection can't go on.
+    pub(super) fn decode(&mut self, mut block: &[u8]) -> Option<Vec<Header>> {
+        let mut headers = Vec::new();
+        let mut list_size = 0;
+        while let Some(&byte) = block.first() {
+            if byte & 0xe0 == 0x20 {
+                // a size update, which only comes before the headers
+                let max_size = integer(&mut block, 5)?;
+                if max_size > TABLE_SIZE || !headers.is_empty() {
+                    return None;
+                }
+                self.max_size = max_size;
+                self.evict(0);
+                continue;
+            }
+            let header = if byte & 0x80 != 0 {
+                let index = integer(&mut block, 7)?;
+                self.entry(index)?
+            } else {
+                // added to the table, or not and maybe never to be by proxies either
+                let (prefix, indexed) = if byte & 0x40 != 0 {
+                    (6, true)
+                } else {
+                    (4, false)
+                };
+                let name = match integer(&mut block, prefix)? {
+                    0 => string(&mut block)?,
+                    index => self.entry(index)?.0,
+                };
+                let value = string(&mut block)?;
+                if indexed {
+                    self.insert(name.clone(), value.clone());
+                }
+                (name, value)
+            };
+            list_size += header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+            if list_size > MAX_HEADER_LIST_SIZE {
+                return None;
+            }
+            headers.push(header);
+        }
+        Some(headers)
+    }
+
+    // The header at `index` of the static table, then of the dynamic one.
+    fn entry(&self, index: usize) -> Option<Header> {
+        match index {
+            0 => None,
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Some((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            }
+            _ => self.table.get(index - STATIC_TABLE.len() - 1).cloned(),
+        }
+    }
+
+    fn insert(&mut self, name: Vec<u8>, value: Vec<u8>) {
+        let size = name.len() + value.len() + ENTRY_OVERHEAD;
+        // an entry larger than the table empties it, and isn't added
+        self.evict(size);
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front((name, value));
+        }
+    }
+
+    // Evict the oldest entries until there is room for `size` more bytes, or none are left.
+    fn evict(&mut self, size: usize) {
+        while self.size + size > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Encode `headers` as literals that aren't added to the dynamic table, naming them by their
+/// index in the static table when they are in it.
+pub(super) fn encode(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for &(name, value) in headers {
+        if let Some(i) = STATIC_TABLE
+            .iter()
+            .position(|&entry| entry == (name, value))
+        {
+            push_integer(&mut block, 0x80, 7, i + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|&(entry, _)| entry == name) {
+            Some(i) => push_integer(&mut block, 0, 4, i + 1),
+            None => {
+                block.push(0);
+                push_string(&mut block, name);
+            }
+        }
+        push_string(&mut block, value);
+    }
+    block
+}
+
+fn push_integer(block: &mut Vec<u8>, flags: u8, prefix: u32, mut value: usize) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        block.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn push_string(block: &mut Vec<u8>, s: &str) {
+    push_integer(block, 0, 7, s.len());
+    block.extend_from_slice(s.as_bytes());
+}
+
+// Read an integer whose first byte keeps its `prefix` low bits for it.
+fn integer(block: &mut &[u8], prefix: u32) -> Option<usize> {
+    let (&first, rest) = block.split_first()?;
+    *block = rest;
+    let max = (1 << prefix) - 1;
+    let mut value = usize::from(first) & max;
+    if value < max {
+        return Some(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = block.split_first()?;
+        *block = rest;
+        // more than fits in 32 bits is more than any length or index can be
+        if shift > 28 {
+            return None;
+        }
+        value += usize::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+}
+
+// Read a string, decoding it if it is in the Huffman code.
+fn string(block: &mut &[u8]) -> Option<Vec<u8>> {
+    let huffman = block.first()? & 0x80 != 0;
+    let len = integer(block, 7)?;
+    if len > block.len() {
+        return None;
+    }
+    let (bytes, rest) = block.split_at(len);
+    *block = rest;
+    if huffman {
+        huffman_decode(bytes)
+    } else {
+        Some(bytes.to_vec())
+    }
+}
+
+// The tree of the Huffman code, with the children of each node, which are other nodes or
+// symbols marked with `LEAF`. The code is complete, so every node has both.
+fn huffman_tree() -> &'static [[u16; 2]] {
+    static TREE: OnceLock<Vec<[u16; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![[0; 2]];
+        for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for i in (0..len).rev() {
+                let bit = ((code >> i) & 1) as usize;
+                if i == 0 {
+                    tree[node][bit] = LEAF | symbol as u16;
+                } else {
+                    if tree[node][bit] == 0 {
+                        tree.push([0; 2]);
+                        tree[node][bit] = (tree.len() - 1) as u16;
+                    }
+                    node = usize::from(tree[node][bit]);
+                }
+            }
+        }
+        tree
+    })
+}
+
+fn huffman_decode(bytes: &[u8]) -> Option<Vec<u8>> {
+    let tree = huffman_tree();
+    let mut decoded = Vec::with_capacity(bytes.len() * 8 / 5);
+    let mut node = 0;
+    // the bits read since the last symbol, which must be padding made of fewer than 8 ones at
+    // the end
+    let mut pending = 0;
+    let mut all_ones = true;
+    for &byte in bytes {
+        for i in (0..8).rev() {
+            let bit = (byte >> i) & 1;
+            pending += 1;
+            all_ones &= bit == 1;
+            let child = tree[node][usize::from(bit)];
+            if child & LEAF == 0 {
+                node = usize::from(child);
+                continue;
+            }
+            let symbol = child & !LEAF;
+            if symbol == EOS {
+                return None;
+            }
+            decoded.push(symbol as u8);
+            node = 0;
+            pending = 0;
+            all_ones = true;
+        }
+    }
+    if pending > 7 || !all_ones {
+        return None;
+    }
+    Some(decoded)
+}
@@ -0,0 +1,535 @@
+//! The gRPC front end of a `KvsServer`: the `kvs.v1.Kvs` service of `proto/kvs.proto`, for
+//! clients generated from that file in any language to call instead of speaking the native
+//! protocol.
+//!
+//! Given a listener with `KvsServer::grpc`, the server serves each connection accepted on it on a
+//! thread of its own, over HTTP/2 in cleartext, or over TLS if the server has a TLS
+//! configuration. Like the rest of the server this runs on threads rather than on an async
+//! runtime, so rather than a gRPC library the `h2` module speaks as much of HTTP/2 as gRPC needs,
+//! and each call is answered on a thread of its own.
+//!
+//! Calls go through the checks of the native protocol: if the server checks credentials, they
+//! are sent in `authorization` metadata as `Bearer TOKEN` or `Bearer USER:PASSWORD`, and a
+//! read-only server turns down `Set` and `Remove`. `Scan` and `Watch` are only served by the kvs
+//! engine. Compressed messages aren't supported, and deadlines are left to clients. A call may
+//! carry a trace id in `x-request-id` metadata, which the access log of the server logs it with.
+
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use self::h2::{Request, Responder};
+use self::proto::{decode_strings, Encoder};
+use crate::access_log::{Access, AccessLog, Outcome};
+use crate::auth::{AuthConfig, Credentials, KeyAccess};
+use crate::error::{ErrorCode, KvsError, Result};
+use crate::metrics::ServerMetrics;
+use crate::tls::Stream;
+use crate::{ChangeOp, KvsEngine};
+
+mod h2;
+mod hpack;
+mod proto;
+
+#[cfg(feature = "fuzzing")]
+pub(crate) use self::h2::fuzzing;
+
+// The largest message a call takes, which is the default of gRPC.
+const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+// Each message is prefixed with whether it is compressed, and its length.
+const MESSAGE_HEADER_SIZE: usize = 5;
+// How many bytes of messages a streaming call gathers before it sends them.
+const STREAM_BATCH_SIZE: usize = 16 * 1024;
+// How often a watch checks whether it was cancelled, while no change comes.
+const WATCH_POLL: Duration = Duration::from_millis(200);
+
+const RESPONSE_HEADERS: [(&str, &str); 2] =
+    [(":status", "200"), ("content-type", "application/grpc")];
+
+/// The status codes of gRPC that calls end with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Code {
+    Ok = 0,
+    Cancelled = 1,
+    InvalidArgument = 3,
+    NotFound = 5,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    FailedPrecondition = 9,
+    Aborted = 10,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    DataLoss = 15,
+    Unauthenticated = 16,
+}
+
+/// How a call ends.
+#[derive(Debug)]
+struct Status {
+    code: Code,
+    message: String,
+}
+
+impl Status {
+    fn new(code: Code, message: impl Into<String>) -> Status {
+        Status {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn ok() -> Status {
+        Status::new(Code::Ok, "")
+    }
+
+    fn invalid_message() -> Status {
+        Status::new(Code::InvalidArgument, "the message isn't valid")
+    }
+
+    // The headers that carry the status, which end the stream.
+    fn trailers(&self) -> Vec<(&'static str, String)> {
+        let mut trailers = vec![("grpc-status", (self.code as u8).to_string())];
+        if !self.message.is_empty() {
+            trailers.push(("grpc-message", percent_encode(&self.message)));
+        }
+        trailers
+    }
+}
+
+impl From<KvsError> for Status {
+    fn from(err: KvsError) -> Status {
+        let code = match err.code() {
+            ErrorCode::KeyNotFound => Code::NotFound,
+            ErrorCode::ReadOnly => Code::FailedPrecondition,
+            ErrorCode::NotLeader | ErrorCode::Backpressure => Code::Unavailable,
+            ErrorCode::IndexFull | ErrorCode::DiskFull => Code::ResourceExhausted,
+            ErrorCode::RecordTooLarge | ErrorCode::InvalidInteger => Code::InvalidArgument,
+            ErrorCode::TransactionConflict => Code::Aborted,
+            ErrorCode::Corruption | ErrorCode::ChecksumMismatch => Code::DataLoss,
+            ErrorCode::AuthRequired | ErrorCode::AuthFailed => Code::Unauthenticated,
+            ErrorCode::Forbidden => Code::PermissionDenied,
+            _ => Code::Internal,
+        };
+        Status::new(code, err.to_string())
+    }
+}
+
+/// The methods of the service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Method {
+    Get,
+    Set,
+    Remove,
+    Scan,
+    Watch,
+}
+
+impl Method {
+    fn from_path(path: &[u8]) -> Option<Method> {
+        match path {
+            b"/kvs.v1.Kvs/Get" => Some(Method::Get),
+            b"/kvs.v1.Kvs/Set" => Some(Method::Set),
+            b"/kvs.v1.Kvs/Remove" => Some(Method::Remove),
+            b"/kvs.v1.Kvs/Scan" => Some(Method::Scan),
+            b"/kvs.v1.Kvs/Watch" => Some(Method::Watch),
+            _ => None,
+        }
+    }
+
+    /// The command the calls are counted as in the metrics of the server.
+    fn command(self) -> &'static str {
+        match self {
+            Method::Get => "get",
+            Method::Set => "set",
+            Method::Remove => "remove",
+            Method::Scan | Method::Watch => "other",
+        }
+    }
+}
+
+/// What the calls of every connection share.
+pub(crate) struct Service<E: KvsEngine> {
+    // engines can be sent to other threads but not shared, so each call clones its own
+    engine: Mutex<E>,
+    auth: Option<Arc<AuthConfig>>,
+    read_only: bool,
+    metrics: Arc<ServerMetrics>,
+    access_log: AccessLog,
+}
+
+impl<E: KvsEngine> Service<E> {
+    pub(crate) fn new(
+        engine: E,
+        auth: Option<Arc<AuthConfig>>,
+        read_only: bool,
+        metrics: Arc<ServerMetrics>,
+        access_log: AccessLog,
+    ) -> Service<E> {
+        Service {
+            engine: Mutex::new(engine),
+            auth,
+            read_only,
+            metrics,
+            access_log,
+        }
+    }
+
+    fn call(&self, request: Request, responder: Responder, peer_addr: SocketAddr) {
+        let engine = self
+            .engine
+            .lock()
+            .expect("gRPC engine lock poisoned")
+            .clone();
+        let started = Instant::now();
+        let method = request.header(":path").and_then(Method::from_path);
+        let command = method.map_or("other", Method::command);
+        let code = match self.answer(&engine, method, &request, &responder) {
+            Ok(code) => code,
+            // the client cancelled the call, e.g. to stop watching
+            Err(err) if err.kind() == io::ErrorKind::ConnectionReset => Code::Cancelled,
+            Err(err) => {
+                debug!("Failed to answer a gRPC call: {}", err);
+                Code::Internal
+            }
+        };
+        let failed = !matches!(code, Code::Ok | Code::NotFound | Code::Cancelled);
+        self.metrics.record(command, started.elapsed(), failed);
+        if self.access_log.is_on() {
+            let key = match method {
+                Some(Method::Get) | Some(Method::Set) | Some(Method::Remove) => request
+                    .body
+                    .get(MESSAGE_HEADER_SIZE..)
+                    .and_then(decode_strings::<1>)
+                    .map(|[key]| key),
+                _ => None,
+            };
+            let status = format!("{:?}", code);
+            let outcome = match code {
+                Code::Ok => Outcome::Ok,
+                Code::NotFound => Outcome::NotFound,
+                _ => Outcome::FailedWith(&status),
+            };
+            self.access_log.log(&Access {
+                peer_addr,
+                protocol: "grpc",
+                trace_id: request
+                    .header("x-request-id")
+                    .and_then(|id| std::str::from_utf8(id).ok()),
+                command,
+                key: key.as_deref().map(str::as_bytes),
+                outcome,
+                latency: started.elapsed(),
+            });
+        }
+    }
+
+    // Answer a call, and return the code it ended with.
+    fn answer(
+        &self,
+        engine: &E,
+        method: Option<Method>,
+        request: &Request,
+        responder: &Responder,
+    ) -> io::Result<Code> {
+        // not gRPC calls at all
+        if request.header(":method") != Some(b"POST") {
+            responder.headers(&[(":status", "405")], true)?;
+            return Ok(Code::Unimplemented);
+        }
+        if !request
+            .header("content-type")
+            .is_some_and(|content_type| content_type.starts_with(b"application/grpc"))
+        {
+            responder.headers(&[(":status", "415")], true)?;
+            return Ok(Code::Unimplemented);
+        }
+
+        let (method, message, user) = match self.check(method, request) {
+            Ok(call) => call,
+            Err(status) => return fail(responder, status),
+        };
+        let user = user.as_deref();
+        let readable = |key: &str| self.allows(user, key, KeyAccess::Read);
+        let reply = match method {
+            Method::Scan => return scan(engine, message, responder, readable),
+            Method::Watch => return watch(engine, message, responder, readable),
+            Method::Get => decode_strings(message)
+                .ok_or_else(Status::invalid_message)
+                .and_then(|[key]| {
+                    self.authorize(user, &key, KeyAccess::Read)?;
+                    Ok(engine.get(key)?)
+                })
+                .map(|value| Encoder::new().optional_string(1, value.as_deref())),
+            Method::Set => decode_strings(message)
+                .ok_or_else(Status::invalid_message)
+                .and_then(|[key, value]| {
+                    self.authorize(user, &key, KeyAccess::ReadWrite)?;
+                    Ok(engine.set(key, value)?)
+                })
+                .map(|()| Encoder::new()),
+            Method::Remove => decode_strings(message)
+                .ok_or_else(Status::invalid_message)
+                .and_then(|[key]| {
+                    self.authorize(user, &key, KeyAccess::ReadWrite)?;
+                    Ok(engine.remove(key)?)
+                })
+                .map(|()| Encoder::new()),
+        };
+        match reply {
+            Ok(reply) => {
+                responder.headers(&RESPONSE_HEADERS, false)?;
+                responder.data(&frame(&reply.finish()), false)?;
+                end(responder, &Status::ok())
+            }
+            Err(status) => fail(responder, status),
+        }
+    }
+
+    // Whether `user` may access `key` as `access` says.
+    fn allows(&self, user: Option<&str>, key: &str, access: KeyAccess) -> bool {
+        match self.auth {
+            Some(ref auth) => auth.allows(user, key, access),
+            None => true,
+        }
+    }
+
+    // Turn down a call about a key `user` may not access as `access` says.
+    fn authorize(
+        &self,
+        user: Option<&str>,
+        key: &str,
+        access: KeyAccess,
+    ) -> std::result::Result<(), Status> {
+        match self.auth {
+            Some(ref auth) => Ok(auth.authorize(user, key, access)?),
+            None => Ok(()),
+        }
+    }
+
+    // The method of a call, the message it was sent and the user it authenticated as, or `None`
+    // for a shared token or a server without credentials, or the status it is turned down with.
+    fn check<'a>(
+        &self,
+        method: Option<Method>,
+        request: &'a Request,
+    ) -> std::result::Result<(Method, &'a [u8], Option<String>), Status> {
+        let method = method.ok_or_else(|| {
+            let path = String::from_utf8_lossy(request.header(":path").unwrap_or_default());
+            Status::new(Code::Unimplemented, format!("unknown method {}", path))
+        })?;
+        if request
+            .header("grpc-encoding")
+            .is_some_and(|encoding| encoding != b"identity")
+        {
+            return Err(Status::new(
+                Code::Unimplemented,
+                "compressed messages aren't supported",
+            ));
+        }
+        let mut user = None;
+        if let Some(ref auth) = self.auth {
+            let credentials = request
+                .header("authorization")
+                .and_then(|value| std::str::from_utf8(value).ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .and_then(|credentials| credentials.parse::<Credentials>().ok());
+            match credentials {
+                Some(ref credentials) if auth.check(credentials) => {
+                    user = credentials.username().map(str::to_owned);
+                }
+                Some(_) => {
+                    warn!("Authentication of a gRPC call failed");
+                    return Err(KvsError::AuthFailed.into());
+                }
+                None => return Err(KvsError::AuthRequired.into()),
+            }
+        }
+        if self.read_only && matches!(method, Method::Set | Method::Remove) {
+            return Err(KvsError::ReadOnly.into());
+        }
+
+        if request.truncated {
+            return Err(Status::new(
+                Code::ResourceExhausted,
+                format!("messages are {} bytes at most", MAX_MESSAGE_SIZE),
+            ));
+        }
+        let body = &request.body[..];
+        let one_message = Status::new(Code::InvalidArgument, "the request has to hold one message");
+        if body.len() < MESSAGE_HEADER_SIZE {
+            return Err(one_message);
+        }
+        if body[0] != 0 {
+            return Err(Status::new(
+                Code::Unimplemented,
+                "compressed messages aren't supported",
+            ));
+        }
+        let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+        if body.len() - MESSAGE_HEADER_SIZE != len {
+            return Err(one_message);
+        }
+        Ok((method, &body[MESSAGE_HEADER_SIZE..], user))
+    }
+}
+
+/// Serve the calls of a connection until the client closes it.
+pub(crate) fn serve_connection<E: KvsEngine>(
+    stream: Stream,
+    service: Arc<Service<E>>,
+) -> Result<()> {
+    let peer_addr = stream.socket().peer_addr()?;
+    debug!("gRPC connection from {}", peer_addr);
+    h2::serve(
+        stream,
+        MESSAGE_HEADER_SIZE + MAX_MESSAGE_SIZE,
+        move |request, responder| service.call(request, responder, peer_addr),
+    )
+}
+
+// Stream the keys that start with a prefix and their values, of the keys `readable` lets through.
+fn scan<E: KvsEngine>(
+    engine: &E,
+    message: &[u8],
+    responder: &Responder,
+    readable: impl Fn(&str) -> bool,
+) -> io::Result<Code> {
+    let [prefix] = match decode_strings(message) {
+        Some(fields) => fields,
+        None => return fail(responder, Status::invalid_message()),
+    };
+    let store = match engine.as_kv_store() {
+        Some(store) => store,
+        None => return fail(responder, unsupported("Scan")),
+    };
+    responder.headers(&RESPONSE_HEADERS, false)?;
+    let mut batch = Vec::new();
+    let mut status = Status::ok();
+    for pair in store.scan_prefix(&prefix) {
+        match pair {
+            Ok((key, _)) if !readable(&key) => {}
+            Ok((key, value)) => {
+                let message = Encoder::new().string(1, &key).string(2, &value).finish();
+                batch.extend_from_slice(&frame(&message));
+                if batch.len() >= STREAM_BATCH_SIZE {
+                    responder.data(&batch, false)?;
+                    batch.clear();
+                }
+            }
+            Err(err) => {
+                status = err.into();
+                break;
+            }
+        }
+    }
+    if !batch.is_empty() {
+        responder.data(&batch, false)?;
+    }
+    end(responder, &status)
+}
+
+// Stream the changes to the keys that start with a prefix, of the keys `readable` lets through,
+// until the call is cancelled.
+fn watch<E: KvsEngine>(
+    engine: &E,
+    message: &[u8],
+    responder: &Responder,
+    readable: impl Fn(&str) -> bool,
+) -> io::Result<Code> {
+    let [prefix] = match decode_strings(message) {
+        Some(fields) => fields,
+        None => return fail(responder, Status::invalid_message()),
+    };
+    let store = match engine.as_kv_store() {
+        Some(store) => store,
+        None => return fail(responder, unsupported("Watch")),
+    };
+    let changes = store.watch(&prefix);
+    responder.headers(&RESPONSE_HEADERS, false)?;
+    let status = loop {
+        match changes.recv_timeout(WATCH_POLL) {
+            Ok(event) if !readable(&event.key) => {}
+            Ok(event) => {
+                let op = match event.op {
+                    ChangeOp::Set => 0,
+                    ChangeOp::Remove => 1,
+                    ChangeOp::Expire => 2,
+                };
+                let message = Encoder::new()
+                    .string(1, &event.key)
+                    .enumeration(2, op)
+                    .optional_string(3, event.old_value.as_deref())
+                    .optional_string(4, event.new_value.as_deref())
+                    .finish();
+                responder.data(&frame(&message), false)?;
+            }
+            Err(RecvTimeoutError::Timeout) if responder.is_open() => {}
+            Err(RecvTimeoutError::Timeout) => {
+                break Status::new(Code::Unavailable, "the connection is closing")
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                break Status::new(Code::Unavailable, "the store was closed")
+            }
+        }
+    };
+    end(responder, &status)
+}
+
+fn unsupported(method: &str) -> Status {
+    Status::new(
+        Code::Unimplemented,
+        format!("{} is only served by the kvs engine", method),
+    )
+}
+
+// End a call that was answered with headers with the trailers of `status`.
+fn end(responder: &Responder, status: &Status) -> io::Result<Code> {
+    let trailers = status.trailers();
+    let trailers: Vec<_> = trailers
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .collect();
+    responder.headers(&trailers, true)?;
+    Ok(status.code)
+}
+
+// Answer a call with nothing but its status, which goes with the headers.
+fn fail(responder: &Responder, status: Status) -> io::Result<Code> {
+    debug!("gRPC call failed: {:?}", status);
+    let trailers = status.trailers();
+    let headers: Vec<_> = RESPONSE_HEADERS
+        .iter()
+        .copied()
+        .chain(trailers.iter().map(|(name, value)| (*name, value.as_str())))
+        .collect();
+    responder.headers(&headers, true)?;
+    Ok(status.code)
+}
+
+// Prefix a message with its length, as it is sent in the body of a call.
+fn frame(message: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(MESSAGE_HEADER_SIZE + message.len());
+    framed.push(0);
+    framed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    framed.extend_from_slice(message);
+    framed
+}
+
+// Percent-encode what isn't printable ASCII in a status message, as gRPC wants.
+fn percent_encode(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for &byte in message.as_bytes() {
+        if (b' '..=b'~').contains(&byte) && byte != b'%' {
+            encoded.push(char::from(byte));
+        } else {
+            let _ = write!(encoded, "%{:02X}", byte);
+        }
+    }
+    encoded
+}
@@ -0,0 +1,115 @@
+//! The messages of `proto/kvs.proto`, in the wire format of Protocol Buffers.
+//!
+//! Every request holds strings numbered from 1, so they are read as an array of them. Fields
+//! that aren't known are skipped, and fields left to their default aren't written, but for the
+//! `optional` ones that are set.
+
+use std::convert::TryFrom;
+
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LEN: u64 = 2;
+const FIXED32: u64 = 5;
+
+/// The string fields numbered 1 to `N` of a message, empty if it doesn't have them, or `None` if
+/// it isn't valid or one of them isn't UTF-8.
+pub(super) fn decode_strings<const N: usize>(mut message: &[u8]) -> Option<[String; N]> {
+    let mut strings: [String; N] = std::array::from_fn(|_| String::new());
+    while !message.is_empty() {
+        let tag = varint(&mut message)?;
+        let (field, wire_type) = (tag >> 3, tag & 7);
+        if field == 0 {
+            return None;
+        }
+        let known = field <= N as u64;
+        match wire_type {
+            LEN => {
+                let len = usize::try_from(varint(&mut message)?).ok()?;
+                let bytes = message.get(..len)?;
+                skip(&mut message, len)?;
+                // the last one wins if a field is repeated
+                if known {
+                    strings[field as usize - 1] = String::from_utf8(bytes.to_vec()).ok()?;
+                }
+            }
+            _ if known => return None,
+            VARINT => {
+                varint(&mut message)?;
+            }
+            FIXED64 => skip(&mut message, 8)?,
+            FIXED32 => skip(&mut message, 4)?,
+            // groups are long gone, and the others don't exist
+            _ => return None,
+        }
+    }
+    Some(strings)
+}
+
+fn varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn skip(bytes: &mut &[u8], len: usize) -> Option<()> {
+    *bytes = bytes.get(len..)?;
+    Some(())
+}
+
+/// Writes a message, field by field.
+#[derive(Debug, Default)]
+pub(super) struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    pub(super) fn new() -> Encoder {
+        Encoder::default()
+    }
+
+    /// Write a string field, unless it is empty.
+    pub(super) fn string(mut self, field: u64, value: &str) -> Encoder {
+        if !value.is_empty() {
+            self = self.optional_string(field, Some(value));
+        }
+        self
+    }
+
+    /// Write an `optional` string field if it is set, even if it is empty.
+    pub(super) fn optional_string(mut self, field: u64, value: Option<&str>) -> Encoder {
+        if let Some(value) = value {
+            self.push_varint((field << 3) | LEN);
+            self.push_varint(value.len() as u64);
+            self.bytes.extend_from_slice(value.as_bytes());
+        }
+        self
+    }
+
+    /// Write an enum field, unless it is 0.
+    pub(super) fn enumeration(mut self, field: u64, value: u64) -> Encoder {
+        if value != 0 {
+            self.push_varint((field << 3) | VARINT);
+            self.push_varint(value);
+        }
+        self
+    }
+
+    pub(super) fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    fn push_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.bytes.push(0x80 | (value & 0x7f) as u8);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+}
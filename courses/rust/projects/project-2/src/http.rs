@@ -0,0 +1,548 @@
+//! The HTTP front end of a `KvsServer`: a small REST gateway to its engine, for integrations
+//! that would rather use `curl` than a client of the native protocol.
+//!
+//! Given a listener with `KvsServer::http`, the server answers these requests on it, over
+//! HTTP/1.1 in cleartext or over TLS if the server has a TLS configuration:
+//!
+//! * `GET /keys/KEY` with the value of the key as plain text,
+//! * `PUT /keys/KEY` by setting the key to the body of the request,
+//! * `DELETE /keys/KEY` by removing the key,
+//! * `GET /keys?prefix=PREFIX` with a JSON array of the keys that start with the prefix, in
+//!   sorted order, or of every key without one. Only the kvs engine lists keys.
+//!
+//! Keys and prefixes are percent-encoded. Requests go through the checks of the native protocol:
+//! if the server checks credentials, they are sent in an `Authorization` header as
+//! `Bearer TOKEN` or `Bearer USER:PASSWORD`, and a read-only server turns down `PUT` and
+//! `DELETE`. Failed requests are answered with a status matching their error, and a JSON body
+//! holding the error as it is sent over the network, e.g.
+//! `{"error":{"code":"KeyNotFound","message":"key not found",...}}`.
+//!
+//! Responses carry the trace id of their request in an `X-Request-Id` header: the one the request
+//! was sent with in that header, or one the server made up, which its access log logs the request
+//! with too.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use log::{debug, warn};
+use serde::Serialize;
+
+use crate::access_log::{new_trace_id, Access, AccessLog, Outcome};
+use crate::auth::{AuthConfig, Credentials, KeyAccess};
+use crate::error::{ErrorCode, KvsError, RemoteError, Result};
+use crate::metrics::ServerMetrics;
+use crate::tls::Stream;
+use crate::KvsEngine;
+
+// The longest request line and headers a request may have.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+// The largest value a request may set.
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// What the requests of every connection share.
+pub(crate) struct Gateway<E: KvsEngine> {
+    // engines can be sent to other threads but not shared, so each connection clones its own
+    engine: Mutex<E>,
+    auth: Option<Arc<AuthConfig>>,
+    read_only: bool,
+    metrics: Arc<ServerMetrics>,
+    access_log: AccessLog,
+}
+
+/// A request, as far as the gateway cares.
+#[derive(Debug, Default)]
+struct Request {
+    method: String,
+    target: String,
+    // whether the client speaks HTTP/1.1 rather than HTTP/1.0
+    http11: bool,
+    content_length: Option<usize>,
+    chunked: bool,
+    expect_continue: bool,
+    close: bool,
+    authorization: Option<String>,
+    request_id: Option<String>,
+}
+
+/// A response.
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    // the methods the path allows, for `405 Method Not Allowed`
+    allow: Option<&'static str>,
+    // the trace id of the request, once it is known
+    request_id: Option<String>,
+}
+
+impl Response {
+    fn ok(content_type: &'static str, body: Vec<u8>) -> Response {
+        Response {
+            status: 200,
+            content_type,
+            body,
+            allow: None,
+            request_id: None,
+        }
+    }
+
+    fn no_content() -> Response {
+        Response::ok("text/plain", Vec::new()).with_status(204)
+    }
+
+    fn with_status(mut self, status: u16) -> Response {
+        self.status = status;
+        self
+    }
+}
+
+/// Why a request failed: the status it is answered with, and the error its body holds.
+#[derive(Debug)]
+struct Failure {
+    status: u16,
+    error: RemoteError,
+    // the methods the path allows, for `405 Method Not Allowed`
+    allow: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a RemoteError,
+}
+
+impl Failure {
+    // A failure of the request itself rather than of the engine.
+    fn new(status: u16, message: impl Into<String>) -> Failure {
+        Failure {
+            status,
+            error: KvsError::ServerError(message.into()).to_remote(),
+            allow: None,
+        }
+    }
+
+    fn into_response(self) -> Response {
+        let body = serde_json::to_vec(&ErrorBody { error: &self.error })
+            .expect("errors serialize to JSON");
+        let mut response = Response::ok("application/json", body).with_status(self.status);
+        response.allow = self.allow;
+        response
+    }
+}
+
+impl From<KvsError> for Failure {
+    fn from(err: KvsError) -> Failure {
+        let status = match err.code() {
+            ErrorCode::KeyNotFound => 404,
+            ErrorCode::AuthRequired | ErrorCode::AuthFailed => 401,
+            ErrorCode::ReadOnly | ErrorCode::Forbidden => 403,
+            ErrorCode::TransactionConflict => 409,
+            ErrorCode::RecordTooLarge => 413,
+            ErrorCode::InvalidInteger | ErrorCode::Utf8 => 400,
+            ErrorCode::NotLeader | ErrorCode::Backpressure => 503,
+            ErrorCode::IndexFull | ErrorCode::DiskFull => 507,
+            _ => 500,
+        };
+        Failure {
+            status,
+            error: err.to_remote(),
+            allow: None,
+        }
+    }
+}
+
+type Answer = std::result::Result<Response, Failure>;
+
+impl<E: KvsEngine> Gateway<E> {
+    pub(crate) fn new(
+        engine: E,
+        auth: Option<Arc<AuthConfig>>,
+        read_only: bool,
+        metrics: Arc<ServerMetrics>,
+        access_log: AccessLog,
+    ) -> Gateway<E> {
+        Gateway {
+            engine: Mutex::new(engine),
+            auth,
+            read_only,
+            metrics,
+            access_log,
+        }
+    }
+
+    // Answer a request, and return the command it is counted as in the metrics of the server and
+    // the key it is about, if any.
+    fn answer(
+        &self,
+        engine: &E,
+        request: &Request,
+        body: Vec<u8>,
+    ) -> (&'static str, Option<String>, Answer) {
+        let (path, query) = match request.target.split_once('?') {
+            Some((path, query)) => (path, query),
+            None => (request.target.as_str(), ""),
+        };
+        let key = match path.strip_prefix("/keys") {
+            Some("") | Some("/") => None,
+            Some(key) if key.starts_with('/') => match percent_decode(&key[1..], false) {
+                Some(key) => Some(key),
+                None => return ("other", None, Err(Failure::new(400, "the key isn't valid"))),
+            },
+            _ => {
+                let failure = Failure::new(404, "only /keys and /keys/KEY are served");
+                return ("other", None, Err(failure));
+            }
+        };
+        let command = match (request.method.as_str(), &key) {
+            ("GET", None) => "other",
+            ("GET", Some(_)) => "get",
+            ("PUT", Some(_)) => "set",
+            ("DELETE", Some(_)) => "remove",
+            (_, key) => {
+                let mut failure = Failure::new(405, format!("{} isn't allowed", request.method));
+                failure.allow = Some(if key.is_some() {
+                    "GET, PUT, DELETE"
+                } else {
+                    "GET"
+                });
+                return ("other", key.clone(), Err(failure));
+            }
+        };
+        let user = match self.check(request, key.as_deref()) {
+            Ok(user) => user,
+            Err(failure) => return (command, key, Err(failure)),
+        };
+        let logged_key = key.clone();
+
+        let answer = match key {
+            None => list(engine, query, |key| match self.auth {
+                Some(ref auth) => auth.allows(user.as_deref(), key, KeyAccess::Read),
+                None => true,
+            }),
+            Some(key) => match command {
+                "get" => match engine.get(key) {
+                    Ok(Some(value)) => Ok(Response::ok(
+                        "text/plain; charset=utf-8",
+                        value.into_bytes(),
+                    )),
+                    Ok(None) => Err(KvsError::KeyNotFound.into()),
+                    Err(err) => Err(err.into()),
+                },
+                "set" => String::from_utf8(body)
+                    .map_err(KvsError::from)
+                    .and_then(|value| engine.set(key, value))
+                    .map(|()| Response::no_content())
+                    .map_err(Failure::from),
+                _ => engine
+                    .remove(key)
+                    .map(|()| Response::no_content())
+                    .map_err(Failure::from),
+            },
+        };
+        (command, logged_key, answer)
+    }
+
+    // Turn down a request the client isn't allowed to send, about `key` if it is about one, and
+    // return the user it authenticated as, or `None` for a shared token or a server without
+    // credentials.
+    fn check(
+        &self,
+        request: &Request,
+        key: Option<&str>,
+    ) -> std::result::Result<Option<String>, Failure> {
+        let mut user = None;
+        if let Some(ref auth) = self.auth {
+            let credentials = request
+                .authorization
+                .as_deref()
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .and_then(|credentials| credentials.parse::<Credentials>().ok());
+            match credentials {
+                Some(ref credentials) if auth.check(credentials) => {
+                    user = credentials.username().map(str::to_owned);
+                }
+                Some(_) => {
+                    warn!("Authentication of an HTTP request failed");
+                    return Err(KvsError::AuthFailed.into());
+                }
+                None => return Err(KvsError::AuthRequired.into()),
+            }
+            if let Some(key) = key {
+                let access = match request.method.as_str() {
+                    "GET" => KeyAccess::Read,
+                    _ => KeyAccess::ReadWrite,
+                };
+                auth.authorize(user.as_deref(), key, access)?;
+            }
+        }
+        if self.read_only && matches!(request.method.as_str(), "PUT" | "DELETE") {
+            return Err(KvsError::ReadOnly.into());
+        }
+        Ok(user)
+    }
+}
+
+/// Serve the requests of a connection until the client closes it, or `next_request` says it was
+/// idle for too long.
+pub(crate) fn serve_connection<E: KvsEngine>(
+    stream: Stream,
+    gateway: Arc<Gateway<E>>,
+    next_request: impl Fn(&mut BufReader<&Stream>) -> Result<bool>,
+) -> Result<()> {
+    let peer_addr = stream.socket().peer_addr()?;
+    debug!("HTTP connection from {}", peer_addr);
+    let engine = gateway
+        .engine
+        .lock()
+        .expect("HTTP engine lock poisoned")
+        .clone();
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    while next_request(&mut reader)? {
+        let started = Instant::now();
+        let request = match read_head(&mut reader)? {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(failure) => {
+                // the request can't be read past, so the connection ends with it
+                write_response(&mut writer, failure.into_response(), true)?;
+                break;
+            }
+        };
+        debug!("HTTP request: {} {}", request.method, request.target);
+        let body = match read_body(&request, &mut reader, &mut writer)? {
+            Ok(body) => body,
+            Err(failure) => {
+                // the body is left unread, so the connection can't go on past it
+                write_response(&mut writer, failure.into_response(), true)?;
+                break;
+            }
+        };
+        let (command, key, answer) = gateway.answer(&engine, &request, body);
+        let (failed, outcome) = match answer {
+            Ok(_) => (false, Outcome::Ok),
+            Err(ref failure) => (
+                failure.error.code != ErrorCode::KeyNotFound,
+                Outcome::failed(failure.error.code),
+            ),
+        };
+        let close = request.close;
+        let mut response = answer.unwrap_or_else(Failure::into_response);
+        let request_id = request.request_id.unwrap_or_else(new_trace_id);
+        response.request_id = Some(request_id.clone());
+        write_response(&mut writer, response, close)?;
+        gateway.metrics.record(command, started.elapsed(), failed);
+        gateway.access_log.log(&Access {
+            peer_addr,
+            protocol: "http",
+            trace_id: Some(&request_id),
+            command,
+            key: key.as_deref().map(str::as_bytes),
+            outcome,
+            latency: started.elapsed(),
+        });
+        if close {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// List the keys that start with the prefix the query holds, of those `readable` lets through.
+fn list<E: KvsEngine>(engine: &E, query: &str, readable: impl Fn(&str) -> bool) -> Answer {
+    let mut prefix = String::new();
+    for pair in query.split('&') {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if name == "prefix" {
+            prefix = percent_decode(value, true)
+                .ok_or_else(|| Failure::new(400, "the prefix isn't valid"))?;
+        }
+    }
+    let store = engine
+        .as_kv_store()
+        .ok_or_else(|| Failure::new(501, "keys are only listed by the kvs engine"))?;
+    let keys: Vec<String> = store
+        .scan_keys(&prefix)
+        .filter(|key| readable(key))
+        .collect();
+    let body = serde_json::to_vec(&keys).map_err(KvsError::from)?;
+    Ok(Response::ok("application/json", body))
+}
+
+// Read the request line and the headers of the next request, or `None` if the client closed
+// the connection before sending one.
+fn read_head(
+    reader: &mut impl BufRead,
+) -> io::Result<std::result::Result<Option<Request>, Failure>> {
+    let mut limited = reader.take(MAX_HEAD_SIZE as u64);
+    let mut request = Request::default();
+    let mut line = String::new();
+    let mut first = true;
+    loop {
+        line.clear();
+        match limited.read_line(&mut line) {
+            Ok(0) if first => return Ok(Ok(None)),
+            Ok(_) if !line.ends_with('\n') => {
+                return Ok(Err(Failure::new(431, "the request head is too large")))
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                return Ok(Err(Failure::new(400, "the request head isn't UTF-8")))
+            }
+            Err(err) => return Err(err),
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if first {
+            first = false;
+            let mut parts = line.split(' ');
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(method), Some(target), Some(version), None)
+                    if version.starts_with("HTTP/1.") =>
+                {
+                    request.method = method.to_owned();
+                    request.target = target.to_owned();
+                    request.http11 = version != "HTTP/1.0";
+                    request.close = !request.http11;
+                }
+                _ => return Ok(Err(Failure::new(400, "the request line isn't valid"))),
+            }
+            continue;
+        }
+        if line.is_empty() {
+            return Ok(Ok(Some(request)));
+        }
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.to_ascii_lowercase(), value.trim()),
+            None => return Ok(Err(Failure::new(400, "a header isn't valid"))),
+        };
+        match name.as_str() {
+            "content-length" => match value.parse() {
+                Ok(len) => request.content_length = Some(len),
+                Err(_) => return Ok(Err(Failure::new(400, "Content-Length isn't valid"))),
+            },
+            "transfer-encoding" => request.chunked = true,
+            "expect" => request.expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            "connection" => {
+                for option in value.split(',').map(str::trim) {
+                    if option.eq_ignore_ascii_case("close") {
+                        request.close = true;
+                    } else if option.eq_ignore_ascii_case("keep-alive") {
+                        request.close = false;
+                    }
+                }
+            }
+            "authorization" => request.authorization = Some(value.to_owned()),
+            "x-request-id" => request.request_id = Some(value.to_owned()),
+            _ => {}
+        }
+    }
+}
+
+// Read the body of a request, telling the client to go on sending it first if it waits for
+// that.
+fn read_body(
+    request: &Request,
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+) -> io::Result<std::result::Result<Vec<u8>, Failure>> {
+    if request.chunked {
+        return Ok(Err(Failure::new(411, "the request needs a Content-Length")));
+    }
+    let len = request.content_length.unwrap_or(0);
+    if len > MAX_BODY_SIZE {
+        let message = format!("request bodies are {} bytes at most", MAX_BODY_SIZE);
+        return Ok(Err(Failure::new(413, message)));
+    }
+    if len > 0 && request.expect_continue && request.http11 {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        writer.flush()?;
+    }
+    // the length comes from the client, so the memory only grows as the body arrives
+    let mut body = Vec::new();
+    reader.take(len as u64).read_to_end(&mut body)?;
+    if body.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Ok(body))
+}
+
+fn write_response(writer: &mut impl Write, response: Response, close: bool) -> io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason(response.status)
+    )?;
+    if response.status != 204 {
+        write!(
+            writer,
+            "Content-Type: {}\r\nContent-Length: {}\r\n",
+            response.content_type,
+            response.body.len()
+        )?;
+    }
+    if let Some(allow) = response.allow {
+        write!(writer, "Allow: {}\r\n", allow)?;
+    }
+    if let Some(ref request_id) = response.request_id {
+        write!(writer, "X-Request-Id: {}\r\n", request_id)?;
+    }
+    if response.status == 401 {
+        writer.write_all(b"WWW-Authenticate: Bearer\r\n")?;
+    }
+    if close {
+        writer.write_all(b"Connection: close\r\n")?;
+    }
+    writer.write_all(b"\r\n")?;
+    writer.write_all(&response.body)?;
+    writer.flush()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        507 => "Insufficient Storage",
+        _ => "Internal Server Error",
+    }
+}
+
+// Decode `%XX` escapes, and `+` as a space in queries, or `None` if they don't make UTF-8.
+fn percent_decode(s: &str, query: bool) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'%' => {
+                let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &rest[2..];
+            }
+            b'+' if query => bytes.push(b' '),
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// The parser of request heads, for the fuzz targets. See the `fuzzing` module of the crate.
+#[cfg(feature = "fuzzing")]
+pub(crate) mod fuzzing {
+    /// Read `data` as the heads of requests one after the other, as long as they parse.
+    pub(crate) fn heads(data: &[u8]) {
+        let mut reader = data;
+        while let Ok(Ok(Some(_))) = super::read_head(&mut reader) {}
+    }
+}
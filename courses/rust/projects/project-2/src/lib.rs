@@ -1,8 +1,55 @@
 #![deny(missing_docs)]
 //! A simple key/value store.
 
-pub use error::{KvsError, Result};
-pub use kv::KvStore;
+pub use access_log::AccessLog;
+pub use auth::{Acl, AuthConfig, Credentials, KeyAccess};
+pub use client::{
+    ClientOptions, KvsClient, KvsClientPool, MultiplexedClient, Pipeline, PooledClient, RetryOn,
+    RetryPolicy, ShardedClient,
+};
+pub use common::{Capabilities, Codec, ServerInfo, PROTOCOL_VERSION};
+pub use config::ConfigFile;
+#[cfg(feature = "s3")]
+pub use engines::S3Storage;
+#[cfg(feature = "mmap")]
+pub use engines::ValueRef;
+pub use engines::{
+    detect_engine, open_engine, AnyEngine, Backpressure, ChangeEvent, ChangeOp, CompactionPolicy,
+    CorruptRecord, Databases, DeadRatio, DumpFormat, Engine, EvictionPolicy, FormatVersion, HotKey,
+    KvStore, KvStoreOptions, KvsEngine, Latencies, Latency, LocalStorage, ManualOnly, Metadata,
+    Namespace, OpenProgress, Operation, RecordInfo, Replay, Scan, SegmentCheck, SegmentInfo,
+    SegmentStorage, SegmentUsage, ShardedKvStore, SizeTiered, SledKvsEngine, Snapshot, StaleBytes,
+    Stats, SyncPolicy, TimeBased, TotalStaleRatio, Transaction, WriteBatch, LATENCY_BUCKETS,
+};
+pub use error::{ErrorCode, KvsError, RemoteError, Result};
+pub use replication::Replica;
+pub use server::{KvsServer, Protocol, ShutdownHandle};
 
+/// The TLS library `KvsServer::tls` and `ClientOptions::tls` take configurations of.
+pub use rustls;
+
+mod access_log;
+pub mod asynch;
+mod auth;
+mod client;
+mod common;
+mod config;
+mod dedup;
+mod engines;
 mod error;
-mod kv;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+mod grpc;
+mod http;
+mod metrics;
+pub mod raft;
+pub mod repl;
+mod replication;
+mod resp;
+mod ring;
+mod server;
+#[cfg(unix)]
+pub mod signals;
+pub mod thread_pool;
+mod tls;
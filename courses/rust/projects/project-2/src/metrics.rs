@@ -0,0 +1,251 @@
+//! The metrics of a `KvsServer`, and the HTTP endpoint that exposes them to Prometheus.
+//!
+//! A server counts the requests it answers by command, along with those that failed and how long
+//! they took, and the connections it accepted. Given a listener with `KvsServer::metrics`, it
+//! answers `GET /metrics` on it with those metrics followed by the statistics of its engine, in
+//! the text exposition format of Prometheus. Scrapes are answered one at a time on a thread of
+//! their own, so they don't wait for the thread pool when it is busy.
+
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use log::{debug, error};
+
+use crate::common::Request;
+use crate::engines::LatencyRecorder;
+use crate::error::Result;
+
+// The commands counted apart, named after the requests of the native protocol. RESP commands
+// count as the requests they match, and any other command as `other`.
+const COMMANDS: [&str; 12] = [
+    "get",
+    "get_many",
+    "set",
+    "remove",
+    "exists",
+    "set_stream",
+    "get_stream",
+    "stats",
+    "ping",
+    "auth",
+    "raft",
+    "other",
+];
+
+// How long a scrape may take to send its request, and then to take the response.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The most a scrape may send as its request line and headers, as for the HTTP front end.
+const MAX_HEAD_SIZE: u64 = 16 * 1024;
+
+/// The counters of a server, shared by its connections.
+#[derive(Debug, Default)]
+pub(crate) struct ServerMetrics {
+    requests: [AtomicU64; COMMANDS.len()],
+    errors: [AtomicU64; COMMANDS.len()],
+    latencies: [LatencyRecorder; COMMANDS.len()],
+    connections: AtomicU64,
+}
+
+impl ServerMetrics {
+    /// Record that a request for `command` was answered after `elapsed`, with an error if
+    /// `failed`.
+    pub(crate) fn record(&self, command: &str, elapsed: Duration, failed: bool) {
+        let i = COMMANDS
+            .iter()
+            .position(|&name| name == command)
+            .unwrap_or(COMMANDS.len() - 1);
+        self.requests[i].fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.errors[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.latencies[i].record(elapsed);
+    }
+
+    /// Record that a connection was accepted.
+    pub(crate) fn connected(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The metrics in the text format of Prometheus, with `open` connections being served.
+    pub(crate) fn to_prometheus(&self, open: usize) -> String {
+        let mut out = String::new();
+        let mut counters = |name: &str, help: &str, counters: &[AtomicU64]| {
+            let _ = writeln!(out, "# HELP kvs_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE kvs_{} counter", name);
+            for (command, counter) in COMMANDS.iter().zip(counters) {
+                let _ = writeln!(
+                    out,
+                    "kvs_{}{{command=\"{}\"}} {}",
+                    name,
+                    command,
+                    counter.load(Ordering::Relaxed)
+                );
+            }
+        };
+        counters(
+            "requests_total",
+            "Requests answered, by command",
+            &self.requests,
+        );
+        counters(
+            "request_errors_total",
+            "Requests answered with an error, by command",
+            &self.errors,
+        );
+
+        let name = "kvs_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {} How long the requests took to serve", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (command, latency) in COMMANDS.iter().zip(&self.latencies) {
+            latency.snapshot().write_prometheus(
+                &mut out,
+                name,
+                &format!("command=\"{}\"", command),
+            );
+        }
+
+        let _ = writeln!(out, "# HELP kvs_connections_total Connections accepted");
+        let _ = writeln!(out, "# TYPE kvs_connections_total counter");
+        let _ = writeln!(
+            out,
+            "kvs_connections_total {}",
+            self.connections.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# HELP kvs_connections_open Connections being served");
+        let _ = writeln!(out, "# TYPE kvs_connections_open gauge");
+        let _ = writeln!(out, "kvs_connections_open {}", open);
+        out
+    }
+}
+
+/// The command a request of the native protocol is counted as.
+pub(crate) fn request_command(request: &Request) -> &'static str {
+    match request {
+        Request::Get { .. } => "get",
+        Request::GetMany { .. } => "get_many",
+        Request::Exists { .. } => "exists",
+        Request::Set { .. } => "set",
+        Request::Remove { .. } => "remove",
+        Request::SetStream { .. } => "set_stream",
+        Request::GetStream { .. } => "get_stream",
+        Request::Stats => "stats",
+        Request::Ping => "ping",
+        Request::Auth(_) => "auth",
+        Request::Raft(_) => "raft",
+        Request::Tagged { request, .. } | Request::Traced { request, .. } => {
+            request_command(request)
+        }
+        Request::Replicate { .. } | Request::Hello { .. } => "other",
+    }
+}
+
+/// The command a RESP command, lowercased, is counted as.
+pub(crate) fn resp_command(name: &[u8]) -> &'static str {
+    match name {
+        b"get" => "get",
+        b"mget" => "get_many",
+        b"set" => "set",
+        b"del" => "remove",
+        b"exists" => "exists",
+        b"stats" | b"metrics" => "stats",
+        b"ping" => "ping",
+        b"auth" => "auth",
+        _ => "other",
+    }
+}
+
+/// Answer the scrapes accepted by `listener` with the metrics `render` returns, until `stop`
+/// says otherwise after a connection wakes it up.
+pub(crate) fn serve_metrics(
+    listener: TcpListener,
+    render: impl Fn() -> Result<String>,
+    stop: impl Fn() -> bool,
+) {
+    for stream in listener.incoming() {
+        if stop() {
+            break;
+        }
+        match stream {
+            Ok(stream) => {
+                if let Err(err) = answer_scrape(stream, &render) {
+                    debug!("Failed to answer a scrape: {}", err);
+                }
+            }
+            Err(err) => error!("Metrics connection failed: {}", err),
+        }
+    }
+}
+
+/// Read an HTTP request, and answer it with the metrics if it asks for them.
+fn answer_scrape(mut stream: TcpStream, render: impl Fn() -> Result<String>) -> io::Result<()> {
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+    let stream_reader = DeadlineReader {
+        stream: &stream,
+        deadline: Instant::now() + SCRAPE_TIMEOUT,
+    };
+    let mut reader = BufReader::new(stream_reader).take(MAX_HEAD_SIZE);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers don't matter, but for where they end
+    let mut ended = false;
+    let mut line = String::new();
+    while request_line.ends_with('\n') {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+            break;
+        }
+        if line.trim_end().is_empty() {
+            ended = true;
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next();
+    let path = parts
+        .next()
+        .map(|target| target.split('?').next().unwrap_or(""));
+    let too_large = !ended && reader.limit() == 0;
+    let (status, body) = match (method, path) {
+        _ if too_large => (
+            "431 Request Header Fields Too Large",
+            "The request head is too large\n".to_owned(),
+        ),
+        (Some("GET"), Some("/metrics")) => match render() {
+            Ok(body) => ("200 OK", body),
+            Err(err) => ("500 Internal Server Error", format!("{}\n", err)),
+        },
+        (Some("GET"), _) => ("404 Not Found", "Only /metrics is served\n".to_owned()),
+        _ => ("405 Method Not Allowed", "Only GET is allowed\n".to_owned()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Reads a stream until a deadline, however little the peer sends at a time.
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        (&*self.stream).read(buf)
+    }
+}
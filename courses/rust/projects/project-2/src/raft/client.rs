@@ -0,0 +1,122 @@
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::KvsError::{IoError, NotLeader, UnexpectedEOF};
+use crate::error::Result;
+use crate::{ClientOptions, KvsClient};
+
+// How long to wait before trying another node, when the last one didn't know the leader.
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// A client of a Raft cluster, which sends its requests to the leader.
+///
+/// A request turned down by a node that isn't the leader is sent again to the leader that node
+/// knows of, or to the next node if it knows of none, as is one that couldn't reach a node at
+/// all, until one succeeds or the timeout runs out. As with `KvsClient`, a `remove` sent again
+/// after the connection broke may report `KeyNotFound` if the first one went through.
+pub struct RaftClient {
+    addrs: Vec<SocketAddr>,
+    options: ClientOptions,
+    timeout: Duration,
+    current: usize,
+    client: Option<KvsClient>,
+}
+
+impl RaftClient {
+    /// A client of the cluster of the servers listening on `addrs`, connecting with `options`.
+    /// Nothing is connected to until the first request.
+    ///
+    /// Panics if `addrs` is empty.
+    pub fn new(addrs: Vec<SocketAddr>, options: &ClientOptions) -> RaftClient {
+        assert!(!addrs.is_empty(), "a cluster needs at least one node");
+        RaftClient {
+            addrs,
+            options: options.clone(),
+            timeout: Duration::from_secs(10),
+            current: 0,
+            client: None,
+        }
+    }
+
+    /// Give up on a request after trying for `timeout`. Defaults to 10s.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut RaftClient {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The node requests are sent to: the leader, as far as the client knows.
+    pub fn current(&self) -> SocketAddr {
+        self.addrs[self.current]
+    }
+
+    /// Retrieve the value of a key from the cluster.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.retry(|client| client.get(key.clone()))
+    }
+
+    /// Set a key on the cluster.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.retry(|client| client.set(key.clone(), value.clone()))
+    }
+
+    /// Remove a key on the cluster.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.retry(|client| client.remove(key.clone()))
+    }
+
+    fn retry<T>(&mut self, mut request: impl FnMut(&mut KvsClient) -> Result<T>) -> Result<T> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let result = match self.client {
+                Some(ref mut client) => request(client),
+                None => match self.options.connect(self.addrs[self.current]) {
+                    Ok(client) => request(self.client.insert(client)),
+                    Err(err) => Err(err),
+                },
+            };
+            let (err, wait) = match result {
+                Err(NotLeader(leader)) => {
+                    self.client = None;
+                    match leader.as_deref().and_then(|leader| leader.parse().ok()) {
+                        Some(leader) => {
+                            self.follow(leader);
+                            (NotLeader(Some(leader.to_string())), false)
+                        }
+                        None => {
+                            self.next();
+                            (NotLeader(None), true)
+                        }
+                    }
+                }
+                Err(err @ IoError(_)) | Err(err @ UnexpectedEOF) => {
+                    self.client = None;
+                    self.next();
+                    (err, true)
+                }
+                result => return result,
+            };
+            if Instant::now() >= deadline {
+                return Err(err);
+            }
+            if wait {
+                thread::sleep(RETRY_DELAY);
+            }
+        }
+    }
+
+    // Send the next requests to `leader`, which may not be one of the addresses given.
+    fn follow(&mut self, leader: SocketAddr) {
+        self.current = match self.addrs.iter().position(|&addr| addr == leader) {
+            Some(index) => index,
+            None => {
+                self.addrs.push(leader);
+                self.addrs.len() - 1
+            }
+        };
+    }
+
+    fn next(&mut self) {
+        self.current = (self.current + 1) % self.addrs.len();
+    }
+}
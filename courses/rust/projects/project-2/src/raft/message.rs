@@ -0,0 +1,83 @@
+use serde::{Deserialize, Serialize};
+
+/// A write to apply to the engine, once a majority of the cluster has it in its log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Command {
+    /// Written by each new leader, so that it can tell which entries are committed
+    Noop,
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    CompareAndSwap {
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    },
+    Increment {
+        key: String,
+        delta: i64,
+    },
+    Rename {
+        old_key: String,
+        new_key: String,
+    },
+    Copy {
+        src: String,
+        dst: String,
+    },
+}
+
+/// An entry of the log, along with the term of the leader that added it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub term: u64,
+    pub command: Command,
+}
+
+/// The messages nodes send each other, carried by `Request::Raft` and `Response::Raft`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Message {
+    /// Sent by a candidate to ask for votes
+    RequestVote {
+        term: u64,
+        candidate: usize,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    VoteReply {
+        term: u64,
+        granted: bool,
+    },
+    /// Sent by the leader with the entries a follower doesn't have yet, if any, following the
+    /// entry at `prev_index`
+    AppendEntries {
+        term: u64,
+        leader: usize,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    },
+    /// `last_index` is the last entry the follower has in common with the leader when it
+    /// succeeded, and where the leader should look for one when it didn't
+    AppendReply {
+        term: u64,
+        success: bool,
+        last_index: u64,
+    },
+}
+
+impl Message {
+    pub fn term(&self) -> u64 {
+        match *self {
+            Message::RequestVote { term, .. }
+            | Message::VoteReply { term, .. }
+            | Message::AppendEntries { term, .. }
+            | Message::AppendReply { term, .. } => term,
+        }
+    }
+}
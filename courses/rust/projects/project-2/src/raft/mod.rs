@@ -0,0 +1,168 @@
+//! Replicated consensus over a storage engine with Raft.
+//!
+//! Each server of a cluster serves a `RaftEngine`, which wraps its own engine. Writes are added to
+//! a log that the leader of the cluster copies to the other nodes, and are applied to the engines
+//! once a majority of the nodes have them. Reads are served by the leader once a majority
+//! confirmed it still leads. When the leader fails, the other nodes elect a new one, so a cluster
+//! of 3 nodes keeps working when any one of them fails, and one of 5 when two do. Nodes that
+//! aren't the leader turn requests down with `KvsError::NotLeader`, which `RaftClient` follows.
+//!
+//! Nodes talk to each other over the connections their `KvsServer`s serve clients on, and keep
+//! one open to each other node, so the thread pool of a server needs a thread for each other
+//! node on top of those for its clients.
+//!
+//! The log is kept whole rather than compacted into snapshots, so a node that lost its data
+//! catches up by replaying it from the start.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use crate::error::Result;
+use crate::{KvsEngine, Stats};
+
+pub use self::client::RaftClient;
+pub use self::node::RaftNode;
+pub use self::options::RaftOptions;
+
+pub(crate) use self::message::Message;
+
+use self::message::Command;
+use self::node::Outcome;
+
+mod client;
+mod message;
+mod node;
+mod options;
+mod storage;
+
+/// A storage engine replicated across a cluster with Raft, started by `RaftEngine::start`.
+///
+/// The node stops once every clone is dropped, or when `shutdown` is called.
+#[derive(Clone)]
+pub struct RaftEngine<E: KvsEngine> {
+    engine: E,
+    handle: Arc<NodeHandle>,
+}
+
+// Stops the node when the last clone of its engine is dropped.
+struct NodeHandle {
+    node: Arc<RaftNode>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl NodeHandle {
+    fn shutdown(&self) {
+        self.node.stop();
+        let threads: Vec<_> = self
+            .threads
+            .lock()
+            .expect("thread list poisoned")
+            .drain(..)
+            .collect();
+        for thread in threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for NodeHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl<E: KvsEngine> RaftEngine<E> {
+    /// Start the node `me` of the cluster of the servers listening on `peers`, with the default
+    /// options. See `RaftOptions::start`.
+    pub fn start(
+        engine: E,
+        path: impl Into<PathBuf>,
+        peers: Vec<SocketAddr>,
+        me: usize,
+    ) -> Result<RaftEngine<E>> {
+        RaftOptions::default().start(engine, path, peers, me)
+    }
+
+    /// The Raft node of this server.
+    pub fn node(&self) -> &RaftNode {
+        &self.handle.node
+    }
+
+    /// Stop taking part in the cluster, as if the server had failed. Requests are turned down
+    /// from then on.
+    pub fn shutdown(&self) {
+        self.handle.shutdown();
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for RaftEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.node().propose(Command::Set { key, value }).map(|_| ())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.node().read_barrier()?;
+        self.engine.get(key)
+    }
+
+    fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        self.node().read_barrier()?;
+        self.engine.get_many(keys)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.node().propose(Command::Remove { key }).map(|_| ())
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        self.node().read_barrier()?;
+        self.engine.contains_key(key)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        match self
+            .node()
+            .propose(Command::CompareAndSwap { key, expected, new })?
+        {
+            Outcome::Swapped(swapped) => Ok(swapped),
+            _ => unreachable!("a compare-and-swap reports whether it swapped"),
+        }
+    }
+
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        match self.node().propose(Command::Increment { key, delta })? {
+            Outcome::Value(value) => Ok(value),
+            _ => unreachable!("an increment reports the new value"),
+        }
+    }
+
+    fn rename(&self, old_key: String, new_key: String) -> Result<()> {
+        self.node()
+            .propose(Command::Rename { old_key, new_key })
+            .map(|_| ())
+    }
+
+    fn copy(&self, src: String, dst: String) -> Result<()> {
+        self.node().propose(Command::Copy { src, dst }).map(|_| ())
+    }
+
+    /// The statistics of the engine of this node, which may be behind the leader's.
+    fn stats(&self) -> Result<Stats> {
+        self.engine.stats()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn as_raft(&self) -> Option<&RaftNode> {
+        Some(self.node())
+    }
+}
@@ -0,0 +1,679 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info};
+
+use super::message::{Command, Entry, Message};
+use super::options::RaftOptions;
+use super::storage::{HardState, RaftLog};
+use crate::error::{KvsError, Result};
+use crate::{KvsClient, KvsEngine};
+
+// Entries sent to a follower in a single message.
+const MAX_ENTRIES: usize = 256;
+
+/// A member of a Raft cluster, which votes for leaders and keeps a copy of the log.
+///
+/// Each `RaftEngine` runs one. It is handed the messages of the other nodes by the `KvsServer`
+/// serving the engine.
+pub struct RaftNode {
+    me: usize,
+    peers: Vec<SocketAddr>,
+    options: RaftOptions,
+    core: Mutex<Core>,
+    // Notified whenever the state changes: a new term or role, new entries, a commit, an
+    // answer from a peer, or the node stopping.
+    changed: Condvar,
+}
+
+/// What applying a command gave, for the node waiting on it.
+pub(super) enum Outcome {
+    Done,
+    Swapped(bool),
+    Value(i64),
+}
+
+enum Role {
+    Follower,
+    Candidate {
+        votes: usize,
+    },
+    /// `term_start` is the index of the entry the leader added when it was elected. Once that one
+    /// is committed, so are all those before it.
+    Leader {
+        term_start: u64,
+    },
+}
+
+// What a node knows of another one.
+#[derive(Default)]
+struct Peer {
+    // the last term a vote was asked for
+    vote_requested: u64,
+    // as the leader, the next entry to send and the last one known to be replicated
+    next_index: u64,
+    match_index: u64,
+    last_sent: Option<Instant>,
+    // when the latest message it acknowledged in this term was sent
+    acked: Option<Instant>,
+    // don't send anything before then, after failing to reach it
+    retry_at: Option<Instant>,
+}
+
+struct Core {
+    term: u64,
+    voted_for: Option<usize>,
+    log: RaftLog,
+    commit: u64,
+    applied: u64,
+    role: Role,
+    leader: Option<usize>,
+    election_deadline: Instant,
+    peers: Vec<Peer>,
+    // the outcomes of entries proposed by this node, filled once they are applied
+    waiting: HashMap<u64, Option<Result<Outcome>>>,
+    stopped: bool,
+}
+
+impl Core {
+    fn save(&self, sync: bool) -> Result<()> {
+        let state = HardState {
+            term: self.term,
+            voted_for: self.voted_for,
+            applied: self.applied,
+        };
+        self.log.save_state(&state, sync)
+    }
+
+    // Follow whoever leads `term`, which is at least the current one.
+    fn step_down(&mut self, term: u64) -> Result<()> {
+        self.role = Role::Follower;
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.leader = None;
+            self.save(true)?;
+        }
+        Ok(())
+    }
+}
+
+impl RaftNode {
+    pub(super) fn open(
+        path: &Path,
+        peers: Vec<SocketAddr>,
+        me: usize,
+        options: RaftOptions,
+    ) -> Result<RaftNode> {
+        let (log, state) = RaftLog::open(path)?;
+        // Entries up to the last one applied were committed before the restart.
+        let applied = state.applied.min(log.last_index());
+        let core = Core {
+            term: state.term,
+            voted_for: state.voted_for,
+            log,
+            commit: applied,
+            applied,
+            role: Role::Follower,
+            leader: None,
+            election_deadline: Instant::now(),
+            peers: peers.iter().map(|_| Peer::default()).collect(),
+            waiting: HashMap::new(),
+            stopped: false,
+        };
+        let node = RaftNode {
+            me,
+            peers,
+            options,
+            core: Mutex::new(core),
+            changed: Condvar::new(),
+        };
+        node.lock().election_deadline = Instant::now() + node.election_timeout();
+        Ok(node)
+    }
+
+    /// Whether this node is the leader of the cluster.
+    pub fn is_leader(&self) -> bool {
+        matches!(self.lock().role, Role::Leader { .. })
+    }
+
+    /// The address of the leader of the cluster, as far as this node knows.
+    pub fn leader(&self) -> Option<SocketAddr> {
+        self.lock().leader.map(|id| self.peers[id])
+    }
+
+    /// The current term, which goes up with each election.
+    pub fn term(&self) -> u64 {
+        self.lock().term
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Core> {
+        self.core.lock().expect("Raft state poisoned")
+    }
+
+    fn wait<'a>(&self, core: MutexGuard<'a, Core>, timeout: Duration) -> MutexGuard<'a, Core> {
+        self.changed
+            .wait_timeout(core, timeout)
+            .expect("Raft state poisoned")
+            .0
+    }
+
+    fn majority(&self) -> usize {
+        self.peers.len() / 2 + 1
+    }
+
+    // A random timeout between one and two times the configured one, so that nodes rarely
+    // start elections at the same time.
+    fn election_timeout(&self) -> Duration {
+        let base = self.options.election_timeout;
+        let random = RandomState::new().build_hasher().finish();
+        base + Duration::from_micros(random % (base.as_micros() as u64).max(1))
+    }
+
+    fn not_leader(&self, core: &Core) -> KvsError {
+        let leader = core.leader.filter(|&id| id != self.me);
+        KvsError::NotLeader(leader.map(|id| self.peers[id].to_string()))
+    }
+
+    /// Answer a message from another node.
+    pub(crate) fn handle(&self, message: Message) -> Result<Message> {
+        let mut core = self.lock();
+        if core.stopped {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "Raft node stopped").into());
+        }
+        let reply = match message {
+            Message::RequestVote {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => {
+                if term > core.term {
+                    core.step_down(term)?;
+                }
+                let up_to_date = (last_log_term, last_log_index)
+                    >= (core.log.last_term(), core.log.last_index());
+                let granted = term == core.term
+                    && up_to_date
+                    && core.voted_for.is_none_or(|id| id == candidate);
+                if granted {
+                    core.voted_for = Some(candidate);
+                    core.save(true)?;
+                    core.election_deadline = Instant::now() + self.election_timeout();
+                }
+                Message::VoteReply {
+                    term: core.term,
+                    granted,
+                }
+            }
+            Message::AppendEntries {
+                term,
+                leader,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => {
+                if term < core.term {
+                    return Ok(Message::AppendReply {
+                        term: core.term,
+                        success: false,
+                        last_index: core.log.last_index(),
+                    });
+                }
+                if term > core.term || !matches!(core.role, Role::Follower) {
+                    core.step_down(term)?;
+                }
+                core.leader = Some(leader);
+                core.election_deadline = Instant::now() + self.election_timeout();
+                self.append_entries(&mut core, prev_index, prev_term, entries, commit)?
+            }
+            message => {
+                return Err(KvsError::ServerError(format!(
+                    "Unexpected Raft message: {:?}",
+                    message
+                )))
+            }
+        };
+        self.changed.notify_all();
+        Ok(reply)
+    }
+
+    // Add the entries of the leader that follow the entry at `prev_index`, if the log has it.
+    fn append_entries(
+        &self,
+        core: &mut Core,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    ) -> Result<Message> {
+        let term = core.term;
+        match core.log.term_at(prev_index) {
+            Some(found) if found == prev_term => {}
+            found => {
+                // Point the leader before the entries of the conflicting term, or at the end of
+                // the log if it is too short, rather than let it go back one entry at a time.
+                let mut last_index = core.log.last_index();
+                if let Some(found) = found {
+                    last_index = prev_index - 1;
+                    while last_index > core.commit && core.log.term_at(last_index) == Some(found) {
+                        last_index -= 1;
+                    }
+                }
+                return Ok(Message::AppendReply {
+                    term,
+                    success: false,
+                    last_index,
+                });
+            }
+        }
+
+        // Skip the entries the log already has, and drop the ones after them that differ.
+        let mut index = prev_index + 1;
+        let mut rest = &entries[..];
+        while let Some((entry, tail)) = rest.split_first() {
+            match core.log.term_at(index) {
+                Some(found) if found == entry.term => {
+                    index += 1;
+                    rest = tail;
+                }
+                Some(_) => {
+                    debug_assert!(index > core.commit, "committed entry overwritten");
+                    core.log.truncate(index)?;
+                    break;
+                }
+                None => break,
+            }
+        }
+        if !rest.is_empty() {
+            core.log.append(rest)?;
+        }
+
+        let last_index = prev_index + entries.len() as u64;
+        let commit = commit.min(last_index);
+        if commit > core.commit {
+            core.commit = commit;
+        }
+        Ok(Message::AppendReply {
+            term,
+            success: true,
+            last_index,
+        })
+    }
+
+    /// Start an election whenever the leader hasn't been heard from for an election timeout.
+    pub(super) fn run_timer(&self) {
+        let mut core = self.lock();
+        while !core.stopped {
+            let is_leader = matches!(core.role, Role::Leader { .. });
+            if !is_leader && Instant::now() >= core.election_deadline {
+                if let Err(err) = self.start_election(&mut core) {
+                    error!("Failed to start an election: {:?}", err);
+                }
+                self.changed.notify_all();
+            }
+            let timeout = match core.role {
+                Role::Leader { .. } => self.options.election_timeout,
+                _ => core
+                    .election_deadline
+                    .saturating_duration_since(Instant::now()),
+            };
+            core = self.wait(core, timeout);
+        }
+    }
+
+    fn start_election(&self, core: &mut Core) -> Result<()> {
+        core.term += 1;
+        core.voted_for = Some(self.me);
+        core.role = Role::Candidate { votes: 1 };
+        core.leader = None;
+        core.election_deadline = Instant::now() + self.election_timeout();
+        core.save(true)?;
+        info!("Node {} starts an election for term {}", self.me, core.term);
+        if self.majority() == 1 {
+            self.become_leader(core)?;
+        }
+        Ok(())
+    }
+
+    fn become_leader(&self, core: &mut Core) -> Result<()> {
+        info!("Node {} leads term {}", self.me, core.term);
+        let next_index = core.log.last_index() + 1;
+        core.role = Role::Leader {
+            term_start: next_index,
+        };
+        core.leader = Some(self.me);
+        for peer in &mut core.peers {
+            *peer = Peer {
+                next_index,
+                ..Peer::default()
+            };
+        }
+        let entry = Entry {
+            term: core.term,
+            command: Command::Noop,
+        };
+        core.log.append(&[entry])?;
+        self.advance_commit(core);
+        Ok(())
+    }
+
+    // As the leader, commit the latest entry of this term a majority has.
+    fn advance_commit(&self, core: &mut Core) {
+        if !matches!(core.role, Role::Leader { .. }) {
+            return;
+        }
+        let mut index = core.log.last_index();
+        // Entries of earlier terms are only committed along with one of this term.
+        while index > core.commit && core.log.term_at(index) == Some(core.term) {
+            let replicas = 1
+                + (0..self.peers.len())
+                    .filter(|&id| id != self.me && core.peers[id].match_index >= index)
+                    .count();
+            if replicas >= self.majority() {
+                core.commit = index;
+                return;
+            }
+            index -= 1;
+        }
+    }
+
+    /// Send `peer` what it needs to hear from this node: requests for votes as a candidate,
+    /// entries and heartbeats as the leader.
+    pub(super) fn run_peer(&self, peer: usize) {
+        let mut client = None;
+        let mut core = self.lock();
+        while !core.stopped {
+            let now = Instant::now();
+            let message = match core.peers[peer].retry_at {
+                Some(retry_at) if now < retry_at => None,
+                _ => self.next_message(&mut core, peer, now),
+            };
+            let message = match message {
+                Some(message) => message,
+                None => {
+                    core = self.wait(core, self.options.heartbeat_interval / 2);
+                    continue;
+                }
+            };
+            let term = core.term;
+            drop(core);
+            let reply = self.send(&mut client, peer, message);
+            core = self.lock();
+            match reply {
+                Ok(reply) => {
+                    core.peers[peer].retry_at = None;
+                    if let Err(err) = self.handle_reply(&mut core, peer, term, now, reply) {
+                        error!("Failed to handle the reply of node {}: {:?}", peer, err);
+                    }
+                }
+                Err(err) => {
+                    debug!("Node {} can't reach node {}: {:?}", self.me, peer, err);
+                    client = None;
+                    core.peers[peer].retry_at =
+                        Some(Instant::now() + self.options.heartbeat_interval);
+                }
+            }
+            self.changed.notify_all();
+        }
+    }
+
+    fn next_message(&self, core: &mut Core, peer: usize, now: Instant) -> Option<Message> {
+        let term = core.term;
+        match core.role {
+            Role::Candidate { .. } if core.peers[peer].vote_requested < term => {
+                core.peers[peer].vote_requested = term;
+                Some(Message::RequestVote {
+                    term,
+                    candidate: self.me,
+                    last_log_index: core.log.last_index(),
+                    last_log_term: core.log.last_term(),
+                })
+            }
+            Role::Leader { .. } => {
+                let next_index = core.peers[peer].next_index;
+                let heartbeat_due = core.peers[peer]
+                    .last_sent
+                    .is_none_or(|sent| now >= sent + self.options.heartbeat_interval);
+                if next_index > core.log.last_index() && !heartbeat_due {
+                    return None;
+                }
+                core.peers[peer].last_sent = Some(now);
+                let prev_index = next_index - 1;
+                Some(Message::AppendEntries {
+                    term,
+                    leader: self.me,
+                    prev_index,
+                    prev_term: core.log.term_at(prev_index).unwrap_or(0),
+                    entries: core.log.entries_from(next_index, MAX_ENTRIES).to_vec(),
+                    commit: core.commit,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn send(
+        &self,
+        client: &mut Option<KvsClient>,
+        peer: usize,
+        message: Message,
+    ) -> Result<Message> {
+        let mut connected = match client.take() {
+            Some(connected) => connected,
+            None => self.options.peer_options.connect(self.peers[peer])?,
+        };
+        let reply = connected.raft(message);
+        *client = Some(connected);
+        reply
+    }
+
+    fn handle_reply(
+        &self,
+        core: &mut Core,
+        peer: usize,
+        term: u64,
+        sent_at: Instant,
+        reply: Message,
+    ) -> Result<()> {
+        if reply.term() > core.term {
+            return core.step_down(reply.term());
+        }
+        if core.term != term {
+            // an answer to a message of an earlier term
+            return Ok(());
+        }
+        match reply {
+            Message::VoteReply { granted, .. } => {
+                let won = match core.role {
+                    Role::Candidate { ref mut votes } if granted => {
+                        *votes += 1;
+                        *votes >= self.majority()
+                    }
+                    _ => false,
+                };
+                if won {
+                    self.become_leader(core)?;
+                }
+            }
+            Message::AppendReply {
+                success,
+                last_index,
+                ..
+            } => {
+                if !matches!(core.role, Role::Leader { .. }) {
+                    return Ok(());
+                }
+                let state = &mut core.peers[peer];
+                if success {
+                    state.match_index = state.match_index.max(last_index);
+                    state.next_index = state.match_index + 1;
+                    state.acked = Some(state.acked.map_or(sent_at, |acked| acked.max(sent_at)));
+                    self.advance_commit(core);
+                } else {
+                    state.next_index = (last_index + 1).min(state.next_index - 1).max(1);
+                }
+            }
+            reply => {
+                return Err(KvsError::ServerError(format!(
+                    "Unexpected Raft reply: {:?}",
+                    reply
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the committed entries to `engine`, in order.
+    pub(super) fn run_apply<E: KvsEngine>(&self, engine: &E) {
+        let mut core = self.lock();
+        loop {
+            while !core.stopped && core.applied >= core.commit {
+                core = self.changed.wait(core).expect("Raft state poisoned");
+            }
+            if core.stopped {
+                return;
+            }
+            let first = core.applied + 1;
+            let entries = core
+                .log
+                .entries_from(first, (core.commit - core.applied) as usize)
+                .to_vec();
+            drop(core);
+            let outcomes: Vec<_> = entries
+                .into_iter()
+                .map(|entry| apply(engine, entry.command))
+                .collect();
+            core = self.lock();
+            for (index, outcome) in (first..).zip(outcomes) {
+                match core.waiting.get_mut(&index) {
+                    Some(slot) => *slot = Some(outcome),
+                    None => match outcome {
+                        Ok(_) | Err(KvsError::KeyNotFound) => {}
+                        Err(err) => error!("Failed to apply entry {}: {:?}", index, err),
+                    },
+                }
+                core.applied = index;
+            }
+            if let Err(err) = core.save(false) {
+                error!("Failed to save the Raft state: {:?}", err);
+            }
+            self.changed.notify_all();
+        }
+    }
+
+    /// Add `command` to the log as the leader, and return what applying it gave once it is
+    /// committed.
+    pub(super) fn propose(&self, command: Command) -> Result<Outcome> {
+        let mut core = self.lock();
+        let term = match core.role {
+            Role::Leader { .. } if !core.stopped => core.term,
+            _ => return Err(self.not_leader(&core)),
+        };
+        core.log.append(&[Entry { term, command }])?;
+        let index = core.log.last_index();
+        core.waiting.insert(index, None);
+        self.advance_commit(&mut core);
+        self.changed.notify_all();
+
+        let deadline = Instant::now() + self.options.request_timeout;
+        let result = loop {
+            if let Some(outcome) = core.waiting.get_mut(&index).and_then(Option::take) {
+                // The entry applied may be one a later leader put there instead.
+                break match core.log.term_at(index) {
+                    Some(found) if found == term => outcome,
+                    _ => Err(self.not_leader(&core)),
+                };
+            }
+            if core.stopped || (core.term != term && core.log.term_at(index) != Some(term)) {
+                break Err(self.not_leader(&core));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break Err(no_majority());
+            }
+            core = self.wait(core, deadline - now);
+        };
+        core.waiting.remove(&index);
+        result
+    }
+
+    /// Wait until reading the engine of this node sees every write committed before, which is
+    /// the case if it is still the leader and has applied them.
+    pub(super) fn read_barrier(&self) -> Result<()> {
+        let mut core = self.lock();
+        let (term, term_start) = match core.role {
+            Role::Leader { term_start } if !core.stopped => (core.term, term_start),
+            _ => return Err(self.not_leader(&core)),
+        };
+        let read_index = core.commit.max(term_start);
+        let started = Instant::now();
+        // Have a majority confirm the node still leads, right away rather than at the next
+        // heartbeat.
+        for peer in &mut core.peers {
+            peer.last_sent = None;
+        }
+        self.changed.notify_all();
+
+        let deadline = started + self.options.request_timeout;
+        loop {
+            if core.stopped || core.term != term {
+                return Err(self.not_leader(&core));
+            }
+            let confirmed = 1
+                + (0..self.peers.len())
+                    .filter(|&id| {
+                        id != self.me && core.peers[id].acked.is_some_and(|at| at >= started)
+                    })
+                    .count();
+            if confirmed >= self.majority() && core.applied >= read_index {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(no_majority());
+            }
+            core = self.wait(core, deadline - now);
+        }
+    }
+
+    /// Stop taking part in the cluster, and wake up the threads of the node so that they exit.
+    pub(super) fn stop(&self) {
+        let mut core = self.lock();
+        core.stopped = true;
+        core.role = Role::Follower;
+        core.leader = None;
+        self.changed.notify_all();
+    }
+}
+
+fn apply<E: KvsEngine>(engine: &E, command: Command) -> Result<Outcome> {
+    match command {
+        Command::Noop => Ok(Outcome::Done),
+        Command::Set { key, value } => engine.set(key, value).map(|()| Outcome::Done),
+        Command::Remove { key } => engine.remove(key).map(|()| Outcome::Done),
+        Command::CompareAndSwap { key, expected, new } => engine
+            .compare_and_swap(key, expected, new)
+            .map(Outcome::Swapped),
+        Command::Increment { key, delta } => engine.increment(key, delta).map(Outcome::Value),
+        Command::Rename { old_key, new_key } => {
+            engine.rename(old_key, new_key).map(|()| Outcome::Done)
+        }
+        Command::Copy { src, dst } => engine.copy(src, dst).map(|()| Outcome::Done),
+    }
+}
+
+fn no_majority() -> KvsError {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        "a majority of the Raft cluster didn't answer in time",
+    )
+    .into()
+}
@@ -0,0 +1,136 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::node::RaftNode;
+use super::{NodeHandle, RaftEngine};
+use crate::error::Result;
+use crate::{ClientOptions, KvsEngine};
+
+/// Options for starting a `RaftEngine`.
+///
+/// ```no_run
+/// # use std::net::SocketAddr;
+/// # use std::time::Duration;
+/// # use kvs::raft::RaftOptions;
+/// # use kvs::KvStore;
+/// let peers: Vec<SocketAddr> = vec![
+///     ([127, 0, 0, 1], 4001).into(),
+///     ([127, 0, 0, 1], 4002).into(),
+///     ([127, 0, 0, 1], 4003).into(),
+/// ];
+/// let engine = RaftOptions::default()
+///     .election_timeout(Duration::from_millis(500))
+///     .start(KvStore::open("db")?, "db/raft", peers, 0)?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct RaftOptions {
+    pub(super) election_timeout: Duration,
+    pub(super) heartbeat_interval: Duration,
+    pub(super) request_timeout: Duration,
+    pub(super) peer_options: ClientOptions,
+}
+
+impl Default for RaftOptions {
+    fn default() -> RaftOptions {
+        let mut peer_options = ClientOptions::default();
+        peer_options
+            .connect_timeout(Some(Duration::from_millis(200)))
+            .read_timeout(Some(Duration::from_secs(1)))
+            .write_timeout(Some(Duration::from_secs(1)));
+        RaftOptions {
+            election_timeout: Duration::from_millis(300),
+            heartbeat_interval: Duration::from_millis(50),
+            request_timeout: Duration::from_secs(5),
+            peer_options,
+        }
+    }
+}
+
+impl RaftOptions {
+    /// Start an election after hearing nothing from the leader for between one and two times
+    /// `timeout`, picked at random. Defaults to 300ms.
+    pub fn election_timeout(&mut self, timeout: Duration) -> &mut RaftOptions {
+        self.election_timeout = timeout;
+        self
+    }
+
+    /// As the leader, send a heartbeat to each node that nothing was sent to for `interval`.
+    /// It must be well below the election timeout. Defaults to 50ms.
+    pub fn heartbeat_interval(&mut self, interval: Duration) -> &mut RaftOptions {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// Fail a read or write if a majority of the cluster doesn't confirm it within `timeout`.
+    /// Defaults to 5s.
+    pub fn request_timeout(&mut self, timeout: Duration) -> &mut RaftOptions {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Talk to the other nodes with `options`, e.g. to authenticate with them or to use TLS.
+    /// Defaults to giving up on connecting after 200ms and on an answer after 1s.
+    pub fn peer_options(&mut self, options: ClientOptions) -> &mut RaftOptions {
+        self.peer_options = options;
+        self
+    }
+
+    /// Start the node `me` of the cluster of the servers listening on `peers`, replicating
+    /// `engine` and keeping its log in the directory `path`.
+    ///
+    /// Every node must be given the same `peers`, in the same order.
+    pub fn start<E: KvsEngine>(
+        &self,
+        engine: E,
+        path: impl Into<PathBuf>,
+        peers: Vec<SocketAddr>,
+        me: usize,
+    ) -> Result<RaftEngine<E>> {
+        if me >= peers.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the node isn't one of the peers",
+            )
+            .into());
+        }
+        let count = peers.len();
+        let node = Arc::new(RaftNode::open(&path.into(), peers, me, self.clone())?);
+        // Stops the threads started so far if starting one fails.
+        let handle = Arc::new(NodeHandle {
+            node: Arc::clone(&node),
+            threads: Mutex::new(Vec::new()),
+        });
+        let spawn = |name: String, run: Box<dyn FnOnce() + Send>| -> Result<()> {
+            let thread = thread::Builder::new().name(name).spawn(run)?;
+            handle
+                .threads
+                .lock()
+                .expect("thread list poisoned")
+                .push(thread);
+            Ok(())
+        };
+
+        let timer = Arc::clone(&node);
+        spawn("raft-timer".to_owned(), Box::new(move || timer.run_timer()))?;
+        for peer in (0..count).filter(|&peer| peer != me) {
+            let node = Arc::clone(&node);
+            spawn(
+                format!("raft-peer-{}", peer),
+                Box::new(move || node.run_peer(peer)),
+            )?;
+        }
+        let applier = Arc::clone(&node);
+        let applied = engine.clone();
+        spawn(
+            "raft-apply".to_owned(),
+            Box::new(move || applier.run_apply(&applied)),
+        )?;
+
+        Ok(RaftEngine { engine, handle })
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::message::Entry;
+use crate::common::{read_message, write_message};
+use crate::error::Result;
+
+const LOG_FILE: &str = "log";
+const STATE_FILE: &str = "state";
+const STATE_TMP_FILE: &str = "state.tmp";
+
+/// What a node keeps across restarts besides its log.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(super) struct HardState {
+    pub(super) term: u64,
+    pub(super) voted_for: Option<usize>,
+    /// The last entry applied to the engine
+    pub(super) applied: u64,
+}
+
+/// The log of a node, in memory and in a file of length-prefixed entries.
+///
+/// Entries are numbered from 1. Index 0 stands for the empty log, with a term of 0.
+pub(super) struct RaftLog {
+    dir: PathBuf,
+    file: File,
+    entries: Vec<Entry>,
+    // where each entry starts in the file
+    offsets: Vec<u64>,
+    len: u64,
+}
+
+impl RaftLog {
+    /// Open the log and the state kept in `dir`, creating them if there are none.
+    pub(super) fn open(dir: &Path) -> Result<(RaftLog, HardState)> {
+        fs::create_dir_all(dir)?;
+        let state = match fs::read(dir.join(STATE_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => HardState::default(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOG_FILE))?;
+        let mut entries = Vec::new();
+        let mut offsets = Vec::new();
+        let mut len = 0;
+        {
+            let mut reader = BufReader::new(&mut file);
+            loop {
+                match read_message::<Entry>(&mut reader) {
+                    Ok(Some(entry)) => {
+                        offsets.push(len);
+                        entries.push(entry);
+                        len = reader.stream_position()?;
+                    }
+                    Ok(None) => break,
+                    Err(err) => {
+                        // an entry cut short by a crash, which no other node relied on
+                        warn!("Dropping the end of the Raft log: {:?}", err);
+                        break;
+                    }
+                }
+            }
+        }
+        file.set_len(len)?;
+        file.seek(SeekFrom::Start(len))?;
+        let log = RaftLog {
+            dir: dir.to_owned(),
+            file,
+            entries,
+            offsets,
+            len,
+        };
+        Ok((log, state))
+    }
+
+    pub(super) fn last_index(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    pub(super) fn last_term(&self) -> u64 {
+        self.entries.last().map_or(0, |entry| entry.term)
+    }
+
+    /// The term of the entry at `index`, or `None` if there is none.
+    pub(super) fn term_at(&self, index: u64) -> Option<u64> {
+        match index {
+            0 => Some(0),
+            _ => self.entries.get(index as usize - 1).map(|entry| entry.term),
+        }
+    }
+
+    /// Up to `max` entries, starting at `index`.
+    pub(super) fn entries_from(&self, index: u64, max: usize) -> &[Entry] {
+        let start = (index.max(1) as usize - 1).min(self.entries.len());
+        let end = (start + max).min(self.entries.len());
+        &self.entries[start..end]
+    }
+
+    /// Append entries, and wait for them to be on disk.
+    pub(super) fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        let mut bytes = Vec::new();
+        let mut offsets = Vec::with_capacity(entries.len());
+        for entry in entries {
+            offsets.push(self.len + bytes.len() as u64);
+            write_message(&mut bytes, entry)?;
+        }
+        self.file.write_all(&bytes)?;
+        self.file.sync_data()?;
+        self.len += bytes.len() as u64;
+        self.offsets.extend(offsets);
+        self.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    /// Remove the entries from `index` on, which a new leader doesn't have.
+    pub(super) fn truncate(&mut self, index: u64) -> Result<()> {
+        let start = index.max(1) as usize - 1;
+        if start >= self.entries.len() {
+            return Ok(());
+        }
+        self.len = self.offsets[start];
+        self.entries.truncate(start);
+        self.offsets.truncate(start);
+        self.file.set_len(self.len)?;
+        self.file.seek(SeekFrom::Start(self.len))?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Replace the state kept on disk, waiting for it to be there if `sync` is set.
+    pub(super) fn save_state(&self, state: &HardState, sync: bool) -> Result<()> {
+        let tmp_path = self.dir.join(STATE_TMP_FILE);
+        let mut file = File::create(&tmp_path)?;
+        serde_json::to_writer(&mut file, state)?;
+        if sync {
+            file.sync_data()?;
+        }
+        fs::rename(tmp_path, self.dir.join(STATE_FILE))?;
+        Ok(())
+    }
+}
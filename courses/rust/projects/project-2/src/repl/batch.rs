@@ -0,0 +1,127 @@
+//! Running the commands of the shell in a batch, with `kvs exec`, one result per line of JSON.
+//!
+//! Each line of the input is a command, as in the shell, and each gives a line of output, like
+//! `{"line":1,"command":"get","key":"k","ok":true,"found":true,"value":"v"}`. A command that
+//! fails gives `"ok":false` with its `error` and `code`, and the batch goes on. Empty lines and
+//! lines starting with `#` are skipped, and `exit` ends the batch.
+
+use std::io::{BufRead, Write};
+
+use serde::Serialize;
+
+use super::{Command, Shell, SCAN_UNSUPPORTED};
+use crate::engines::Stats;
+use crate::error::{ErrorCode, KvsError, Result};
+
+/// The result of a command, as a line of output.
+#[derive(Debug, Default, Serialize)]
+struct Outcome {
+    // the line of the input the command is on, from 1
+    line: usize,
+    // none for a line that isn't a command
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prefix: Option<String>,
+    ok: bool,
+    // whether the key of `get` or `rm` exists
+    #[serde(skip_serializing_if = "Option::is_none")]
+    found: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pairs: Option<Vec<Pair>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<Stats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<ErrorCode>,
+}
+
+/// A key and its value found by `scan`.
+#[derive(Debug, Serialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+/// Run the commands of `input` on `shell`, one per line, writing the result of each to `output`
+/// as a line of JSON. Returns whether every command succeeded, which a key that isn't found
+/// doesn't count against. See the `batch` module.
+pub fn exec(shell: &mut dyn Shell, input: impl BufRead, mut output: impl Write) -> Result<bool> {
+    let mut succeeded = true;
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        let mut outcome = Outcome {
+            line: i + 1,
+            ..Outcome::default()
+        };
+        match Command::parse(&line) {
+            Ok(None) => continue,
+            Ok(Some(Command::Exit)) => break,
+            Ok(Some(command)) => {
+                outcome.command = Some(command.name());
+                if let Err(err) = execute(shell, command, &mut outcome) {
+                    outcome.error = Some(err.to_string());
+                    outcome.code = Some(err.code());
+                }
+            }
+            Err(message) => outcome.error = Some(message),
+        }
+        outcome.ok = outcome.error.is_none();
+        succeeded &= outcome.ok;
+        serde_json::to_writer(&mut output, &outcome)?;
+        writeln!(output)?;
+    }
+    output.flush()?;
+    Ok(succeeded)
+}
+
+fn execute(shell: &mut dyn Shell, command: Command, outcome: &mut Outcome) -> Result<()> {
+    match command {
+        Command::Get(key) => {
+            outcome.key = Some(key.clone());
+            let value = shell.get(key)?;
+            outcome.found = Some(value.is_some());
+            outcome.value = value;
+        }
+        Command::Set(key, value) => {
+            outcome.key = Some(key.clone());
+            shell.set(key, value)?;
+        }
+        Command::Remove(key) => {
+            outcome.key = Some(key.clone());
+            match shell.remove(key) {
+                Ok(()) => outcome.found = Some(true),
+                Err(KvsError::KeyNotFound) => outcome.found = Some(false),
+                Err(err) => return Err(err),
+            }
+        }
+        Command::Scan(prefix) => {
+            outcome.prefix = Some(prefix.clone());
+            let pairs = match shell.scan(&prefix) {
+                Some(pairs) => pairs?,
+                None => {
+                    outcome.error = Some(SCAN_UNSUPPORTED.to_owned());
+                    return Ok(());
+                }
+            };
+            outcome.pairs = Some(
+                pairs
+                    .into_iter()
+                    .map(|(key, value)| Pair { key, value })
+                    .collect(),
+            );
+        }
+        Command::Stats => outcome.stats = Some(shell.stats()?),
+        Command::Help => outcome.error = Some("Help is only available in the shell".to_owned()),
+        Command::Exit => {}
+    }
+    Ok(())
+}
@@ -0,0 +1,408 @@
+//! Reading the lines of the shell, edited on a terminal.
+//!
+//! On a Unix terminal, the line is read a key at a time with the terminal in raw mode, and drawn
+//! again after each key: the prompt, the line, then the cursor moved back to where it is in the
+//! line. Elsewhere, the line is read as the terminal or the pipe hands it over.
+
+use std::fs::{self, OpenOptions};
+#[cfg(unix)]
+use std::io::Read;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+
+// The lines of the history kept, the latest ones.
+const MAX_HISTORY: usize = 1000;
+
+/// Reads the lines of the shell, keeping those entered on a terminal as its history.
+pub(super) struct LineEditor {
+    // what Tab completes the first word of the line to
+    completions: &'static [&'static str],
+    history: Vec<String>,
+    // where the history is kept across sessions
+    history_file: Option<PathBuf>,
+    terminal: bool,
+}
+
+/// A key pressed while editing a line.
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    // Ctrl-C
+    Interrupt,
+    // Ctrl-D
+    EndOfFile,
+    // Ctrl-K
+    KillToEnd,
+    // Ctrl-U
+    KillToStart,
+    Other,
+}
+
+impl LineEditor {
+    /// A line editor that completes the commands in `completions`, with the history in
+    /// `history_file` if there is one.
+    pub(super) fn new(
+        completions: &'static [&'static str],
+        history_file: Option<PathBuf>,
+    ) -> LineEditor {
+        let terminal = io::stdin().is_terminal() && io::stdout().is_terminal();
+        // the commands piped in aren't worth recalling
+        let history_file = history_file.filter(|_| terminal);
+        let mut history: Vec<String> = history_file
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|history| history.lines().map(str::to_owned).collect())
+            .unwrap_or_default();
+        let skip = history.len().saturating_sub(MAX_HISTORY);
+        history.drain(..skip);
+        LineEditor {
+            completions,
+            history,
+            history_file,
+            terminal,
+        }
+    }
+
+    /// Read the next line, without its line ending, or `None` at the end of the input.
+    pub(super) fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        let line = if self.terminal {
+            self.edit(prompt)?
+        } else {
+            let mut line = String::new();
+            if io::stdin().lock().read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            Some(line.trim_end_matches(['\n', '\r']).to_owned())
+        };
+        if let Some(line) = &line {
+            self.remember(line);
+        }
+        Ok(line)
+    }
+
+    fn remember(&mut self, line: &str) {
+        if !self.terminal
+            || line.trim().is_empty()
+            || self.history.last().map(String::as_str) == Some(line)
+        {
+            return;
+        }
+        self.history.push(line.to_owned());
+        if self.history.len() > MAX_HISTORY {
+            self.history.remove(0);
+        }
+        if let Some(path) = &self.history_file {
+            // a history that can't be written to is only lost for later sessions
+            let _ = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", line));
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn edit(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        print!("{}", prompt);
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line.trim_end_matches(['\n', '\r']).to_owned()))
+    }
+
+    #[cfg(unix)]
+    fn edit(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        let _raw = raw::RawMode::enable()?;
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        let mut out = io::stdout();
+        let mut line: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        // the entry of the history shown, or its length for the line being edited, which is kept
+        // in `editing` meanwhile
+        let mut shown = self.history.len();
+        let mut editing = Vec::new();
+        refresh(&mut out, prompt, &line, cursor)?;
+        loop {
+            let key = match read_key(&mut input)? {
+                Some(key) => key,
+                None => {
+                    write!(out, "\r\n")?;
+                    return Ok(None);
+                }
+            };
+            match key {
+                Key::Char(c) => {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                }
+                Key::Enter => {
+                    write!(out, "\r\n")?;
+                    out.flush()?;
+                    return Ok(Some(line.into_iter().collect()));
+                }
+                Key::Tab => self.complete(&mut out, prompt, &mut line, &mut cursor)?,
+                Key::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                Key::Delete if cursor < line.len() => {
+                    line.remove(cursor);
+                }
+                Key::Left if cursor > 0 => cursor -= 1,
+                Key::Right if cursor < line.len() => cursor += 1,
+                Key::Home => cursor = 0,
+                Key::End => cursor = line.len(),
+                Key::Up if shown > 0 => {
+                    if shown == self.history.len() {
+                        editing = line;
+                    }
+                    shown -= 1;
+                    line = self.history[shown].chars().collect();
+                    cursor = line.len();
+                }
+                Key::Down if shown < self.history.len() => {
+                    shown += 1;
+                    line = match self.history.get(shown) {
+                        Some(entry) => entry.chars().collect(),
+                        None => std::mem::take(&mut editing),
+                    };
+                    cursor = line.len();
+                }
+                Key::Interrupt => {
+                    write!(out, "^C\r\n")?;
+                    line.clear();
+                    cursor = 0;
+                    shown = self.history.len();
+                }
+                Key::EndOfFile if line.is_empty() => {
+                    write!(out, "\r\n")?;
+                    out.flush()?;
+                    return Ok(None);
+                }
+                Key::EndOfFile if cursor < line.len() => {
+                    line.remove(cursor);
+                }
+                Key::KillToEnd => line.truncate(cursor),
+                Key::KillToStart => {
+                    line.drain(..cursor);
+                    cursor = 0;
+                }
+                _ => {}
+            }
+            refresh(&mut out, prompt, &line, cursor)?;
+        }
+    }
+
+    /// Complete the first word of the line, if the cursor is at its end: to the only command it
+    /// starts, or as far as the commands it starts agree, listing them if that adds nothing.
+    #[cfg(unix)]
+    fn complete(
+        &self,
+        out: &mut impl Write,
+        prompt: &str,
+        line: &mut Vec<char>,
+        cursor: &mut usize,
+    ) -> io::Result<()> {
+        let in_first_word = !line[..*cursor].iter().any(|c| c.is_whitespace())
+            && line.get(*cursor).is_none_or(|c| c.is_whitespace());
+        if !in_first_word {
+            return Ok(());
+        }
+        let word: String = line[..*cursor].iter().collect();
+        let matches: Vec<&str> = self
+            .completions
+            .iter()
+            .copied()
+            .filter(|command| command.starts_with(&word))
+            .collect();
+        let common = match matches.split_first() {
+            Some((first, rest)) => rest.iter().fold(*first, |common, command| {
+                let len = common
+                    .bytes()
+                    .zip(command.bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                &common[..len]
+            }),
+            None => return Ok(()),
+        };
+        let mut completion: Vec<char> = common[word.len()..].chars().collect();
+        if matches.len() == 1 && *cursor == line.len() {
+            completion.push(' ');
+        }
+        if completion.is_empty() {
+            write!(out, "\r\n{}\r\n", matches.join("  "))?;
+            return refresh(out, prompt, line, *cursor);
+        }
+        let len = completion.len();
+        line.splice(*cursor..*cursor, completion);
+        *cursor += len;
+        Ok(())
+    }
+}
+
+/// Draw the prompt and the line over the current line of the terminal, with the cursor at
+/// `cursor`.
+#[cfg(unix)]
+fn refresh(out: &mut impl Write, prompt: &str, line: &[char], cursor: usize) -> io::Result<()> {
+    let text: String = line.iter().collect();
+    write!(out, "\r{}{}\x1b[K", prompt, text)?;
+    let back = line.len() - cursor;
+    if back > 0 {
+        write!(out, "\x1b[{}D", back)?;
+    }
+    out.flush()
+}
+
+#[cfg(unix)]
+fn read_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Read the next key from a terminal in raw mode, or `None` at the end of the input.
+#[cfg(unix)]
+fn read_key(input: &mut impl Read) -> io::Result<Option<Key>> {
+    let byte = match read_byte(input)? {
+        Some(byte) => byte,
+        None => return Ok(None),
+    };
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        0x7f | 0x08 => Key::Backspace,
+        0x01 => Key::Home,
+        0x02 => Key::Left,
+        0x03 => Key::Interrupt,
+        0x04 => Key::EndOfFile,
+        0x05 => Key::End,
+        0x06 => Key::Right,
+        0x0b => Key::KillToEnd,
+        0x0e => Key::Down,
+        0x10 => Key::Up,
+        0x15 => Key::KillToStart,
+        0x1b => read_escape(input)?,
+        byte if byte < 0x20 => Key::Other,
+        byte => {
+            let len = match byte {
+                0xf0.. => 4,
+                0xe0.. => 3,
+                0xc0.. => 2,
+                _ => 1,
+            };
+            let mut bytes = vec![byte];
+            for _ in 1..len {
+                match read_byte(input)? {
+                    Some(byte) => bytes.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            match std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(|s| s.chars().next())
+            {
+                Some(c) => Key::Char(c),
+                None => Key::Other,
+            }
+        }
+    };
+    Ok(Some(key))
+}
+
+/// Read the rest of an escape sequence, those of the arrows, Home, End and Delete.
+#[cfg(unix)]
+fn read_escape(input: &mut impl Read) -> io::Result<Key> {
+    match read_byte(input)? {
+        Some(b'[') | Some(b'O') => {}
+        _ => return Ok(Key::Other),
+    }
+    let key = match read_byte(input)? {
+        Some(b'A') => Key::Up,
+        Some(b'B') => Key::Down,
+        Some(b'C') => Key::Right,
+        Some(b'D') => Key::Left,
+        Some(b'H') => Key::Home,
+        Some(b'F') => Key::End,
+        Some(digit @ b'0'..=b'9') => {
+            // e.g. `3~` for Delete, up to the final byte of the sequence
+            let mut sequence = vec![digit];
+            while let Some(byte) = read_byte(input)? {
+                sequence.push(byte);
+                if (0x40..=0x7e).contains(&byte) {
+                    break;
+                }
+            }
+            match sequence.as_slice() {
+                b"3~" => Key::Delete,
+                b"1~" | b"7~" => Key::Home,
+                b"4~" | b"8~" => Key::End,
+                _ => Key::Other,
+            }
+        }
+        _ => Key::Other,
+    };
+    Ok(key)
+}
+
+// Switching the terminal to raw mode, to read a key at a time without echoing it.
+#[cfg(unix)]
+mod raw {
+    use std::io;
+    use std::mem::MaybeUninit;
+
+    /// Raw mode of the terminal on stdin, until dropped.
+    pub(super) struct RawMode {
+        original: libc::termios,
+    }
+
+    impl RawMode {
+        pub(super) fn enable() -> io::Result<RawMode> {
+            let mut termios = MaybeUninit::uninit();
+            // SAFETY: `tcgetattr` initializes the termios if it succeeds.
+            let original = unsafe {
+                if libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                termios.assume_init()
+            };
+            let mut raw = original;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+            raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            // SAFETY: the termios is initialized.
+            if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &raw) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawMode { original })
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            // SAFETY: the termios is the one read from the terminal.
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &self.original) };
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! An interactive shell to get, set and remove keys, for `kvs repl` on a store and
+//! `kvs-client --interactive` on a server, whose commands `kvs exec` also runs in a batch: see
+//! the `batch` module.
+//!
+//! Each line is a command, such as `set key value`: `help` lists them. Keys and values with
+//! spaces go in double quotes, in which a backslash escapes the next character. On a terminal,
+//! the line can be edited, the command completed with Tab, and the lines entered before recalled
+//! with the arrow keys, those of earlier sessions included, which are kept in
+//! `~/.kvs_history`. Otherwise the commands are read from stdin without a prompt.
+
+use std::env;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::client::KvsClient;
+use crate::engines::{KvStore, Stats};
+use crate::error::{KvsError, Result};
+
+pub use self::batch::exec;
+use self::line::LineEditor;
+
+mod batch;
+mod line;
+
+// The commands, in the order `help` lists them, which Tab completes.
+const COMMANDS: &[&str] = &["get", "set", "rm", "scan", "stats", "help", "exit"];
+
+const SCAN_UNSUPPORTED: &str = "Scanning isn't supported over the network";
+
+const HELP: &str = "\
+get KEY          Print the value of KEY
+set KEY VALUE    Set KEY to VALUE
+rm KEY           Remove KEY
+scan [PREFIX]    Print the keys that start with PREFIX, and their values
+stats            Print the statistics of the storage engine
+help             Print this help
+exit             Leave the shell, as does Ctrl-D
+";
+
+/// What the commands of the shell run against: a store or a client of a server.
+pub trait Shell {
+    /// Retrieve the value of `key`.
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+
+    /// Set `key` to `value`.
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+
+    /// Remove `key`, failing with `KvsError::KeyNotFound` if it doesn't exist.
+    fn remove(&mut self, key: String) -> Result<()>;
+
+    /// The keys that start with `prefix` and their values, in sorted key order, or `None` if
+    /// they can't be listed.
+    fn scan(&mut self, prefix: &str) -> Option<Result<Vec<(String, String)>>>;
+
+    /// The statistics of the storage engine.
+    fn stats(&mut self) -> Result<Stats>;
+}
+
+impl Shell for KvStore {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvStore::remove(self, key)
+    }
+
+    fn scan(&mut self, prefix: &str) -> Option<Result<Vec<(String, String)>>> {
+        Some(self.scan_prefix(prefix).collect())
+    }
+
+    fn stats(&mut self) -> Result<Stats> {
+        KvStore::stats(self)
+    }
+}
+
+impl Shell for KvsClient {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvsClient::get(self, key)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvsClient::set(self, key, value)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvsClient::remove(self, key)
+    }
+
+    // the protocol has no request to list keys
+    fn scan(&mut self, _prefix: &str) -> Option<Result<Vec<(String, String)>>> {
+        None
+    }
+
+    fn stats(&mut self) -> Result<Stats> {
+        KvsClient::stats(self)
+    }
+}
+
+/// A command of the shell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Command {
+    Get(String),
+    Set(String, String),
+    Remove(String),
+    Scan(String),
+    Stats,
+    Help,
+    Exit,
+}
+
+impl Command {
+    /// The name of the command, as it is typed.
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Get(_) => "get",
+            Command::Set(..) => "set",
+            Command::Remove(_) => "rm",
+            Command::Scan(_) => "scan",
+            Command::Stats => "stats",
+            Command::Help => "help",
+            Command::Exit => "exit",
+        }
+    }
+
+    /// Parse a line into a command, or `None` for an empty line. Fails with a message for the
+    /// user if it isn't one.
+    fn parse(line: &str) -> std::result::Result<Option<Command>, String> {
+        let words = split(line)?;
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (name.as_str(), args),
+            None => return Ok(None),
+        };
+        let command = match (name, args) {
+            ("get", [key]) => Command::Get(key.clone()),
+            ("set", [key, value]) => Command::Set(key.clone(), value.clone()),
+            ("rm", [key]) => Command::Remove(key.clone()),
+            ("scan", []) => Command::Scan(String::new()),
+            ("scan", [prefix]) => Command::Scan(prefix.clone()),
+            ("stats", []) => Command::Stats,
+            ("help", []) => Command::Help,
+            ("exit", []) | ("quit", []) => Command::Exit,
+            ("get", _) => return Err("Usage: get KEY".to_owned()),
+            ("set", _) => return Err("Usage: set KEY VALUE".to_owned()),
+            ("rm", _) => return Err("Usage: rm KEY".to_owned()),
+            ("scan", _) => return Err("Usage: scan [PREFIX]".to_owned()),
+            ("stats", _) | ("help", _) | ("exit", _) | ("quit", _) => {
+                return Err(format!("Usage: {}", name))
+            }
+            _ => return Err(format!("Unknown command {}, see help", name)),
+        };
+        Ok(Some(command))
+    }
+}
+
+/// Split a line into words at whitespace, taking the text in double quotes as part of a word and
+/// the character after a backslash as it is.
+fn split(line: &str) -> std::result::Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err("Nothing to escape at the end of the line".to_owned()),
+            },
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted {
+        return Err("Unterminated quote".to_owned());
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Run the shell on `shell` until `exit` or the end of stdin, prompting with `prompt` on a
+/// terminal. A command that fails prints its error and the shell goes on.
+pub fn run(shell: &mut dyn Shell, prompt: &str) -> Result<()> {
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(".kvs_history"));
+    let mut editor = LineEditor::new(COMMANDS, history);
+    let stdout = io::stdout();
+    while let Some(line) = editor.read_line(prompt)? {
+        let command = match Command::parse(&line) {
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(message) => {
+                eprintln!("{}", message);
+                continue;
+            }
+        };
+        if command == Command::Exit {
+            break;
+        }
+        let mut out = stdout.lock();
+        if let Err(err) = execute(shell, command, &mut out) {
+            out.flush()?;
+            eprintln!("Error: {}", err);
+        }
+    }
+    Ok(())
+}
+
+fn execute(shell: &mut dyn Shell, command: Command, out: &mut impl Write) -> Result<()> {
+    match command {
+        Command::Get(key) => match shell.get(key)? {
+            Some(value) => writeln!(out, "{}", value)?,
+            None => writeln!(out, "Key not found")?,
+        },
+        Command::Set(key, value) => shell.set(key, value)?,
+        Command::Remove(key) => match shell.remove(key) {
+            Err(KvsError::KeyNotFound) => writeln!(out, "Key not found")?,
+            result => result?,
+        },
+        Command::Scan(prefix) => match shell.scan(&prefix) {
+            Some(pairs) => {
+                for (key, value) in pairs? {
+                    writeln!(out, "{}\t{}", key, value)?;
+                }
+            }
+            None => writeln!(out, "{}", SCAN_UNSUPPORTED)?,
+        },
+        Command::Stats => writeln!(out, "{}", shell.stats()?)?,
+        Command::Help => write!(out, "{}", HELP)?,
+        Command::Exit => {}
+    }
+    Ok(())
+}
@@ -0,0 +1,272 @@
+//! Replication of a `KvStore` from the server that writes to it, the leader, to servers that
+//! serve reads from copies of it, the followers.
+//!
+//! A follower asks the leader for its replication log with the id of the log and the number of
+//! entries it has applied. If the leader still has the entries after those in its backlog, it
+//! sends them, and otherwise sends a copy of the whole store, after which the follower removes
+//! the keys it has that the leader doesn't. Either way, the leader then sends every write as it
+//! happens, and a heartbeat whenever there is none for a while, so that the follower can tell a
+//! quiet leader from a dead one. When the connection breaks, the follower connects again and
+//! catches up from where it was.
+
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::client::ReplicationStream;
+use crate::common::{Codec, ReplicationMessage, Response};
+use crate::engines::{ReplicationEntry, SyncStart};
+use crate::error::Result;
+use crate::{ClientOptions, KvStore};
+
+// How long the leader waits for a write before sending a heartbeat.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long a follower waits for a message before it gives up on the connection.
+const LEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long a follower waits before connecting again.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Keys copied in each message of a full sync.
+const SYNC_CHUNK: usize = 1000;
+
+/// Send the replication log of `store` to a follower, encoded with `codec`, until it disconnects
+/// or falls too far behind.
+pub(crate) fn serve_follower(
+    store: &KvStore,
+    writer: &mut impl Write,
+    codec: Codec,
+    id: Option<String>,
+    offset: u64,
+) -> Result<()> {
+    let subscription = store.subscribe(id.as_deref(), offset);
+    let send =
+        |writer: &mut _, message| codec.write_message(writer, &Response::Replication(message));
+    match subscription.start {
+        SyncStart::Continue(entries) => {
+            send(writer, ReplicationMessage::Continue)?;
+            for entry in entries {
+                send(writer, ReplicationMessage::Entry((*entry).clone()))?;
+            }
+        }
+        SyncStart::Full { snapshot, offset } => {
+            send(
+                writer,
+                ReplicationMessage::FullSync {
+                    id: subscription.id,
+                    offset,
+                },
+            )?;
+            let mut pairs = Vec::with_capacity(SYNC_CHUNK);
+            for pair in snapshot.entries() {
+                pairs.push(pair?);
+                if pairs.len() == SYNC_CHUNK {
+                    send(
+                        writer,
+                        ReplicationMessage::Pairs(std::mem::take(&mut pairs)),
+                    )?;
+                }
+            }
+            if !pairs.is_empty() {
+                send(writer, ReplicationMessage::Pairs(pairs))?;
+            }
+            send(writer, ReplicationMessage::SyncEnd)?;
+        }
+    }
+    writer.flush()?;
+
+    loop {
+        match subscription.entries.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(entry) => {
+                send(writer, ReplicationMessage::Entry((*entry).clone()))?;
+                // send the entries already waiting at once
+                while let Ok(entry) = subscription.entries.try_recv() {
+                    send(writer, ReplicationMessage::Entry((*entry).clone()))?;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => send(writer, ReplicationMessage::Heartbeat)?,
+            Err(RecvTimeoutError::Disconnected) => {
+                warn!("Dropping a follower that fell too far behind");
+                return Ok(());
+            }
+        }
+        writer.flush()?;
+    }
+}
+
+/// Keeps a `KvStore` a copy of the store of a leader server, created by `Replica::start`.
+///
+/// Writes from the leader are applied as they come, on a background thread, which connects again
+/// whenever the connection breaks. Nothing else should write to the store meanwhile: serve it
+/// with a read-only `KvsServer`. Replication stops once the `Replica` is dropped.
+pub struct Replica {
+    state: Arc<ReplicaState>,
+    handle: Option<JoinHandle<()>>,
+}
+
+struct ReplicaState {
+    stop: AtomicBool,
+    connected: AtomicBool,
+    offset: AtomicU64,
+    // the socket of the current connection, to interrupt it when stopping
+    socket: Mutex<Option<TcpStream>>,
+}
+
+impl Replica {
+    /// Start copying the store of the server listening on `leader` into `store`, connecting with
+    /// `options`.
+    ///
+    /// The store is first made a copy of the leader's, unless the leader still has the writes
+    /// it missed, which is only the case if it was replicating from the same leader before,
+    /// without either having restarted since.
+    pub fn start(
+        store: KvStore,
+        leader: impl ToSocketAddrs,
+        options: &ClientOptions,
+    ) -> Result<Replica> {
+        let addrs: Vec<SocketAddr> = leader.to_socket_addrs()?.collect();
+        let mut options = options.clone();
+        options.read_timeout(Some(LEADER_TIMEOUT)).reconnect(false);
+        let state = Arc::new(ReplicaState {
+            stop: AtomicBool::new(false),
+            connected: AtomicBool::new(false),
+            offset: AtomicU64::new(0),
+            socket: Mutex::new(None),
+        });
+        let thread_state = Arc::clone(&state);
+        let handle = thread::Builder::new()
+            .name("kvs-replica".to_owned())
+            .spawn(move || run_replica(store, &addrs, &options, &thread_state))?;
+        Ok(Replica {
+            state,
+            handle: Some(handle),
+        })
+    }
+
+    /// Whether the store is connected to the leader and done copying it.
+    pub fn is_connected(&self) -> bool {
+        self.state.connected.load(Ordering::SeqCst)
+    }
+
+    /// The number of entries of the leader's replication log applied so far.
+    pub fn offset(&self) -> u64 {
+        self.state.offset.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Replica {
+    fn drop(&mut self) {
+        self.state.stop.store(true, Ordering::SeqCst);
+        if let Some(ref socket) = *self.state.socket.lock().expect("replica lock poisoned") {
+            // the connection may be broken already
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// Replicate from the leader, connecting again whenever the connection breaks.
+fn run_replica(
+    store: KvStore,
+    addrs: &[SocketAddr],
+    options: &ClientOptions,
+    state: &ReplicaState,
+) {
+    // the id of the leader's log and the number of its entries applied
+    let mut position = None;
+    while !state.stop.load(Ordering::SeqCst) {
+        if let Err(err) = follow(&store, addrs, options, state, &mut position) {
+            state.connected.store(false, Ordering::SeqCst);
+            if state.stop.load(Ordering::SeqCst) {
+                break;
+            }
+            warn!("Replication from {:?} failed: {:?}", addrs, err);
+            let retry_at = Instant::now() + RETRY_DELAY;
+            while Instant::now() < retry_at && !state.stop.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+}
+
+// Follow the leader over a single connection, until it breaks.
+fn follow(
+    store: &KvStore,
+    addrs: &[SocketAddr],
+    options: &ClientOptions,
+    state: &ReplicaState,
+    position: &mut Option<(String, u64)>,
+) -> Result<()> {
+    let (id, offset) = match position {
+        Some((id, offset)) => (Some(id.clone()), *offset),
+        None => (None, 0),
+    };
+    let mut stream = options.connect(addrs)?.replicate(id, offset)?;
+    *state.socket.lock().expect("replica lock poisoned") = Some(stream.socket()?);
+    if state.stop.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    loop {
+        match stream.next()? {
+            ReplicationMessage::Continue if position.is_some() => {
+                info!("Catching up with the leader from entry {}", offset);
+                state.connected.store(true, Ordering::SeqCst);
+            }
+            ReplicationMessage::FullSync { id, offset } => {
+                info!("Copying the store of the leader");
+                full_sync(store, &mut stream)?;
+                *position = Some((id, offset));
+                state.offset.store(offset, Ordering::SeqCst);
+                state.connected.store(true, Ordering::SeqCst);
+            }
+            ReplicationMessage::Entry(entry) => {
+                let (_, offset) = position.as_mut().ok_or_else(protocol_error)?;
+                match entry {
+                    ReplicationEntry::Write(pairs) => store.apply_replicated(pairs)?,
+                    ReplicationEntry::RemovePrefix(prefix) => {
+                        store.apply_removed_prefix(&prefix)?
+                    }
+                    ReplicationEntry::Merge { key, operand } => {
+                        store.apply_merged(&key, operand)?
+                    }
+                    ReplicationEntry::Compacted => store.replicate_compaction(),
+                }
+                *offset += 1;
+                state.offset.store(*offset, Ordering::SeqCst);
+            }
+            ReplicationMessage::Heartbeat => {}
+            _ => return Err(protocol_error()),
+        }
+    }
+}
+
+// Copy the keys sent after `FullSync`, and remove the others.
+fn full_sync(store: &KvStore, stream: &mut ReplicationStream) -> Result<()> {
+    let mut keys = HashSet::new();
+    loop {
+        match stream.next()? {
+            ReplicationMessage::Pairs(pairs) => {
+                keys.extend(pairs.iter().map(|pair| pair.key.clone()));
+                store.apply_replicated(pairs)?;
+            }
+            ReplicationMessage::SyncEnd => break,
+            _ => return Err(protocol_error()),
+        }
+    }
+    store.retain(|key| keys.contains(key))
+}
+
+fn protocol_error() -> crate::KvsError {
+    io::Error::new(io::ErrorKind::InvalidData, "unexpected replication message").into()
+}
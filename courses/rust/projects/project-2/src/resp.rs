@@ -0,0 +1,147 @@
+//! The subset of the Redis serialization protocol (RESP) that `KvsServer` speaks with
+//! `Protocol::Resp`.
+//!
+//! Commands are read either as an array of bulk strings, which is what Redis clients send, or as
+//! an inline command of space-separated words, which is what a person typing into telnet sends.
+
+use std::io::{self, BufRead, Read, Write};
+
+use crate::error::Result;
+
+// Redis refuses bulk strings longer than this too.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+const MAX_ARRAY_LEN: usize = 1024 * 1024;
+
+// Redis refuses inline commands, and the lines of lengths, longer than this too.
+const MAX_INLINE_LEN: u64 = 64 * 1024;
+
+/// A reply to a command.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// `+OK`
+    Simple(&'static str),
+    /// `-ERR ...`
+    Error(String),
+    /// `:1`
+    Integer(i64),
+    /// `$5\r\nvalue`, or `$-1` for none
+    Bulk(Option<Vec<u8>>),
+    /// `*2` followed by the replies
+    Array(Vec<Reply>),
+}
+
+/// Read a command and its arguments, or `None` if the peer closed the connection.
+pub fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.first() == Some(&b'*') {
+            let len = parse_len(&line[1..])?;
+            if len > MAX_ARRAY_LEN {
+                return Err(protocol_error("invalid multibulk length"));
+            }
+            // Redis ignores empty multibulk commands too.
+            if len == 0 {
+                continue;
+            }
+            // the lengths come from the peer, so the memory only grows as the data arrives
+            let mut args = Vec::new();
+            for _ in 0..len {
+                args.push(read_bulk(reader)?);
+            }
+            return Ok(Some(args));
+        }
+        let args: Vec<Vec<u8>> = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_vec())
+            .collect();
+        // Redis ignores empty inline commands.
+        if !args.is_empty() {
+            return Ok(Some(args));
+        }
+    }
+}
+
+/// Write a reply. It isn't flushed, so that the replies to pipelined commands can be sent at once.
+pub fn write_reply(writer: &mut impl Write, reply: &Reply) -> Result<()> {
+    match reply {
+        Reply::Simple(s) => write!(writer, "+{}\r\n", s)?,
+        Reply::Error(message) => {
+            // A newline would end the error early.
+            let message = message.replace(['\r', '\n'], " ");
+            write!(writer, "-{}\r\n", message)?
+        }
+        Reply::Integer(n) => write!(writer, ":{}\r\n", n)?,
+        Reply::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+        Reply::Bulk(Some(value)) => {
+            write!(writer, "${}\r\n", value.len())?;
+            writer.write_all(value)?;
+            writer.write_all(b"\r\n")?;
+        }
+        Reply::Array(replies) => {
+            write!(writer, "*{}\r\n", replies.len())?;
+            for reply in replies {
+                write_reply(writer, reply)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_bulk(reader: &mut impl BufRead) -> Result<Vec<u8>> {
+    let line = read_line(reader)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    if line.first() != Some(&b'$') {
+        return Err(protocol_error("expected '$'"));
+    }
+    let len = parse_len(&line[1..])?;
+    if len > MAX_BULK_LEN {
+        return Err(protocol_error("invalid bulk length"));
+    }
+    let mut data = Vec::new();
+    reader.take(len as u64 + 2).read_to_end(&mut data)?;
+    if data.len() < len + 2 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    if !data.ends_with(b"\r\n") {
+        return Err(protocol_error("bulk string not terminated by CRLF"));
+    }
+    data.truncate(len);
+    Ok(data)
+}
+
+// A line without its line ending, or `None` at the end of the stream.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.take(MAX_INLINE_LEN).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.len() as u64 == MAX_INLINE_LEN && line.last() != Some(&b'\n') {
+        return Err(protocol_error("too big inline request"));
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8]) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(message: &str) -> crate::KvsError {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Protocol error: {}", message),
+    )
+    .into()
+}
@@ -0,0 +1,56 @@
+//! Consistent hashing, which spreads keys over shards so that adding or removing a shard only
+//! moves the keys it gains or loses.
+//!
+//! Each shard is placed at many points of a ring of 64-bit hashes, derived from its name alone,
+//! and owns the keys that hash between the point before and each of its own. A shard keeps its
+//! points whatever the other shards are, so the keys that change owner when shards come and go
+//! are only those of the points of the shards that came or went.
+
+use std::convert::TryInto;
+
+use sha2::{Digest, Sha256};
+
+// Points each shard has on the ring. The more there are, the more evenly keys are spread.
+const POINTS_PER_SHARD: usize = 128;
+
+/// A ring of shards, each identified by its index in the names it was built from.
+#[derive(Debug)]
+pub(crate) struct HashRing {
+    // the points of the shards, sorted
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    /// The ring of the shards named `names`, which must be distinct. Everyone sharing keys must
+    /// use the same names, since they are what places the shards.
+    ///
+    /// Panics if `names` is empty.
+    pub(crate) fn new<S: AsRef<str>>(names: &[S]) -> HashRing {
+        assert!(!names.is_empty(), "a ring needs at least one shard");
+        let mut points: Vec<(u64, usize)> = names
+            .iter()
+            .enumerate()
+            .flat_map(|(shard, name)| {
+                (0..POINTS_PER_SHARD).map(move |point| {
+                    let id = format!("{}#{}", name.as_ref(), point);
+                    (hash(id.as_bytes()), shard)
+                })
+            })
+            .collect();
+        points.sort_unstable();
+        HashRing { points }
+    }
+
+    /// The index of the shard that owns `key`.
+    pub(crate) fn owner(&self, key: &[u8]) -> usize {
+        let hash = hash(key);
+        let next = self.points.partition_point(|&(point, _)| point < hash);
+        self.points[next % self.points.len()].1
+    }
+}
+
+// A hash that is the same on every machine and across versions, unlike those of `std`.
+fn hash(bytes: &[u8]) -> u64 {
+    let digest = Sha256::digest(bytes);
+    u64::from_be_bytes(digest[..8].try_into().expect("digest is 32 bytes"))
+}
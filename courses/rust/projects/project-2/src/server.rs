@@ -0,0 +1,1489 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+use rustls::ServerConfig;
+
+use crate::access_log::{Access, AccessLog, Outcome};
+use crate::auth::{AuthConfig, Credentials, KeyAccess};
+use crate::common::{
+    write_message, Capabilities, ChunkReader, ChunkWriter, Codec, Request, RequestId, Response,
+    ServerInfo, CHUNK_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::dedup::Deduplicator;
+use crate::error::{KvsError, Result};
+use crate::grpc::{serve_connection as serve_grpc_connection, Service};
+use crate::http::{serve_connection as serve_http_connection, Gateway};
+use crate::metrics::{request_command, resp_command, serve_metrics, ServerMetrics};
+use crate::replication::serve_follower;
+use crate::resp::{read_command, write_reply, Reply};
+use crate::thread_pool::ThreadPool;
+use crate::tls::{read_certs, read_key, Stream};
+use crate::{KvStore, KvsEngine};
+
+/// The protocols a `KvsServer` can speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// The protocol of `KvsClient`
+    Native,
+    /// The Redis protocol, for `redis-cli` and Redis client libraries. Only the GET, MGET, SET,
+    /// DEL, EXISTS, PING and AUTH commands are supported, along with STATS, which replies with
+    /// the statistics of the engine as a bulk string of `name:value` lines, and METRICS, which
+    /// replies with them in the text format of Prometheus.
+    Resp,
+}
+
+impl FromStr for Protocol {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Protocol> {
+        match s {
+            "native" => Ok(Protocol::Native),
+            "resp" => Ok(Protocol::Resp),
+            _ => Err(KvsError::UnknownProtocol(s.to_owned())),
+        }
+    }
+}
+
+// How many tagged requests of a connection are answered at the same time, at most.
+const MAX_TAGGED_WORKERS: usize = 8;
+// How many tagged requests of a connection may wait for a worker before no more are read.
+const MAX_QUEUED_TAGGED: usize = 64;
+
+// How long a server that is shutting down waits for its connections to finish the requests
+// they are serving, by default.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A server that serves requests from `KvsClient`s with a storage engine.
+///
+/// Connections are handled concurrently on a thread pool, each with its own clone of the engine.
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: P,
+    protocol: Protocol,
+    tls: Option<Arc<ServerConfig>>,
+    auth: Option<Arc<AuthConfig>>,
+    read_only: bool,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    max_connections: Option<usize>,
+    timeouts: Timeouts,
+    access_log: AccessLog,
+    metrics: Arc<ServerMetrics>,
+    metrics_listener: Option<TcpListener>,
+    grpc_listener: Option<TcpListener>,
+    http_listener: Option<TcpListener>,
+    // the codecs clients may switch to, in the order they are preferred
+    codecs: Vec<Codec>,
+}
+
+// What the connections of the native protocol share.
+struct Native {
+    // the codecs clients may switch to, in the order they are preferred
+    codecs: Vec<Codec>,
+    // the last write of each client, to apply the writes sent again once
+    dedup: Deduplicator,
+    access_log: AccessLog,
+}
+
+// How long a connection may take to do its part, `None` meaning as long as it likes.
+#[derive(Debug, Clone, Copy, Default)]
+struct Timeouts {
+    // to start sending the next request
+    idle: Option<Duration>,
+    // to send the rest of a request once it started, and each read of the TLS handshake
+    read: Option<Duration>,
+    // to take each write of a response
+    write: Option<Duration>,
+    // to read, handle and answer a request before it is logged as slow
+    slow: Option<Duration>,
+}
+
+/// Stops a `KvsServer` from another thread, e.g. one that handles signals.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle(Arc<ShutdownState>);
+
+#[derive(Debug, Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    // the address the server listens on, once it serves
+    addr: Mutex<Option<SocketAddr>>,
+}
+
+impl ShutdownHandle {
+    /// Ask the server to shut down, and return at once.
+    ///
+    /// The server stops accepting connections, lets each one finish the request it is serving
+    /// and closes it, syncs the engine, then returns from `serve` or `run` after dropping the
+    /// engine, which releases the store.
+    pub fn shutdown(&self) {
+        // The server records its address before it checks whether to stop, so either it stops
+        // before it waits for a connection or the connection below wakes it up.
+        self.0.requested.store(true, Ordering::SeqCst);
+        let addr = *self.0.addr.lock().expect("shutdown lock poisoned");
+        if let Some(addr) = addr {
+            if let Err(err) = TcpStream::connect(local_addr(addr)) {
+                warn!("Failed to wake up the server to shut it down: {}", err);
+            }
+        }
+    }
+
+    /// Whether the server was asked to shut down.
+    pub fn is_shutdown(&self) -> bool {
+        self.0.requested.load(Ordering::SeqCst)
+    }
+}
+
+// An address to connect to a listener on `addr` at, which may be that of every interface.
+fn local_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
+
+// The connections a server is serving, to close when it shuts down.
+#[derive(Default)]
+struct Connections {
+    open: Mutex<(u64, HashMap<u64, TcpStream>)>,
+    closed: Condvar,
+}
+
+// Removes a connection from `Connections` once it is served, or its handler panicked.
+struct ConnectionGuard {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Connections {
+    fn add(self: &Arc<Connections>, stream: &TcpStream) -> io::Result<ConnectionGuard> {
+        let stream = stream.try_clone()?;
+        let mut open = self.open.lock().expect("connections lock poisoned");
+        let id = open.0;
+        open.0 += 1;
+        open.1.insert(id, stream);
+        Ok(ConnectionGuard {
+            connections: Arc::clone(self),
+            id,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.open.lock().expect("connections lock poisoned").1.len()
+    }
+
+    // Shut down the connections still open, logging the failures.
+    fn shutdown(&self, how: Shutdown) {
+        for stream in self
+            .open
+            .lock()
+            .expect("connections lock poisoned")
+            .1
+            .values()
+        {
+            if let Err(err) = stream.shutdown(how) {
+                debug!("Failed to shut down a connection: {}", err);
+            }
+        }
+    }
+
+    // Wait until every connection is closed, or `timeout` has passed, and return the number of
+    // connections still open.
+    fn wait(&self, timeout: Option<Duration>) -> usize {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut open = self.open.lock().expect("connections lock poisoned");
+        while !open.1.is_empty() {
+            let left = match deadline {
+                None => None,
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) => Some(left),
+                    None => break,
+                },
+            };
+            open = match left {
+                None => self.closed.wait(open).expect("connections lock poisoned"),
+                Some(left) => {
+                    let (open, _) = self
+                        .closed
+                        .wait_timeout(open, left)
+                        .expect("connections lock poisoned");
+                    open
+                }
+            };
+        }
+        open.1.len()
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut open = self
+            .connections
+            .open
+            .lock()
+            .expect("connections lock poisoned");
+        open.1.remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
+
+impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    /// Create a server with the given storage engine, handling connections on `pool`.
+    pub fn new(engine: E, pool: P) -> Self {
+        KvsServer {
+            engine,
+            pool,
+            protocol: Protocol::Native,
+            tls: None,
+            auth: None,
+            read_only: false,
+            shutdown: ShutdownHandle::default(),
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            access_log: AccessLog::Off,
+            metrics: Arc::default(),
+            metrics_listener: None,
+            grpc_listener: None,
+            http_listener: None,
+            codecs: Codec::ALL.to_vec(),
+        }
+    }
+
+    /// Speak `protocol` instead of the native protocol.
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Encrypt every connection with TLS, as configured by `config`. Clients that don't speak
+    /// TLS are turned away.
+    pub fn tls(mut self, config: Arc<ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Encrypt every connection with TLS, with the certificate chain in the PEM file `cert` and
+    /// the private key in the PEM file `key`.
+    pub fn tls_files(self, cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self> {
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(read_certs(cert.as_ref())?, read_key(key.as_ref())?)?;
+        Ok(self.tls(Arc::new(config)))
+    }
+
+    /// Only serve connections that authenticated with credentials `config` accepts. Until then,
+    /// a connection can only ping the server. If `config` has an ACL, each connection may only
+    /// access the keys it grants the user it authenticated as, whatever the protocol.
+    pub fn auth(mut self, config: AuthConfig) -> Self {
+        self.auth = Some(Arc::new(config));
+        self
+    }
+
+    /// Turn down requests that write, e.g. to serve a store kept up to date by a `Replica`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Serve `max` connections at most at once, or any number if `None`, the default. Further
+    /// connections are answered with an error and closed, or just closed over TLS.
+    pub fn max_connections(mut self, max: Option<usize>) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Close the connections that don't start sending a request within `timeout` of the last
+    /// response, if it is set. Connections can be idle for as long as they like by default.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.idle = timeout;
+        self
+    }
+
+    /// Close the connections that take longer than `timeout`, if it is set, between two reads of
+    /// a request they started sending, or of the TLS handshake.
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.read = timeout;
+        self
+    }
+
+    /// Close the connections that take longer than `timeout`, if it is set, to take a write of
+    /// a response, e.g. because the client doesn't read them.
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.write = timeout;
+        self
+    }
+
+    /// Log the requests that take at least `threshold` from the moment they start arriving to the
+    /// moment they are answered, or none if it is `None`, the default. They are logged as
+    /// warnings with the `kvs::slow` target, along with their key and where the time went.
+    ///
+    /// `KvStoreOptions::slow_log_threshold` breaks the time the store takes down further.
+    pub fn slow_log_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.timeouts.slow = threshold;
+        self
+    }
+
+    /// Log a line for each request, whatever protocol it came in, as `log` says: with its client,
+    /// trace id, command, key or the digest of its key, outcome and latency. Nothing is logged by
+    /// default. See the `access_log` module.
+    pub fn access_log(mut self, log: AccessLog) -> Self {
+        self.access_log = log;
+        self
+    }
+
+    /// Once asked to shut down, wait `timeout` at most for the connections to finish the requests
+    /// they are serving, 30 seconds by default, then close them even if they haven't.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Serve the metrics of the server and the statistics of its engine over HTTP on `listener`,
+    /// for Prometheus to scrape at `/metrics`. See the `metrics` module.
+    pub fn metrics(mut self, listener: TcpListener) -> Self {
+        self.metrics_listener = Some(listener);
+        self
+    }
+
+    /// Serve the gRPC service of `proto/kvs.proto` on `listener` too, to clients calling it over
+    /// HTTP/2, or over TLS if the server has a TLS configuration. Each of its connections is served
+    /// on a thread of its own rather than on the thread pool. See the `grpc` module.
+    pub fn grpc(mut self, listener: TcpListener) -> Self {
+        self.grpc_listener = Some(listener);
+        self
+    }
+
+    /// Serve a REST gateway to the engine over HTTP on `listener` too, for `curl` and the like, or
+    /// over TLS if the server has a TLS configuration. Like those of gRPC, its connections are
+    /// served on threads of their own. See the `http` module.
+    pub fn http(mut self, listener: TcpListener) -> Self {
+        self.http_listener = Some(listener);
+        self
+    }
+
+    /// Let clients of the native protocol switch to one of `codecs`, in the order they are
+    /// preferred when a client can use several. They can all be switched to by default.
+    /// Connections start out with JSON whatever they may switch to.
+    pub fn codecs(mut self, codecs: &[Codec]) -> Self {
+        self.codecs = codecs.to_vec();
+        self
+    }
+
+    /// A handle that stops the server once it is serving.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Listen on `addr` and serve the incoming connections, until asked to shut down.
+    pub fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serve the connections accepted by `listener`, until asked to shut down.
+    pub fn serve(mut self, listener: TcpListener) -> Result<()> {
+        *self.shutdown.0.addr.lock().expect("shutdown lock poisoned") =
+            Some(listener.local_addr()?);
+        let connections = Arc::new(Connections::default());
+        let native = Arc::new(Native {
+            codecs: self.codecs.clone(),
+            dedup: Deduplicator::default(),
+            access_log: self.access_log,
+        });
+        let metrics_thread = match self.metrics_listener.take() {
+            Some(listener) => Some(self.spawn_metrics(listener, &connections)?),
+            None => None,
+        };
+        let grpc_thread = match self.grpc_listener.take() {
+            Some(listener) => Some(self.spawn_grpc(listener, &connections)?),
+            None => None,
+        };
+        let http_thread = match self.http_listener.take() {
+            Some(listener) => Some(self.spawn_http(listener, &connections)?),
+            None => None,
+        };
+        while !self.shutdown.is_shutdown() {
+            let stream = listener.accept().map(|(stream, _)| stream);
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("Connection failed: {}", err);
+                    continue;
+                }
+            };
+            if let Some(max) = self.max_connections {
+                let open = connections.len();
+                if open >= max {
+                    warn!("Turning down a connection, {} are open already", open);
+                    self.turn_down(stream);
+                    continue;
+                }
+            }
+            match connections.add(&stream) {
+                Ok(connection) => {
+                    self.metrics.connected();
+                    let engine = self.engine.clone();
+                    let protocol = self.protocol;
+                    let tls = self.tls.clone();
+                    let auth = self.auth.clone();
+                    let read_only = self.read_only;
+                    let timeouts = self.timeouts;
+                    let access_log = self.access_log;
+                    let metrics = Arc::clone(&self.metrics);
+                    let native = Arc::clone(&native);
+                    self.pool.spawn(move || {
+                        let _connection = connection;
+                        let result = timeouts
+                            .apply(&stream)
+                            .and_then(|_| Stream::accept(stream, tls.as_ref()))
+                            .and_then(|stream| match protocol {
+                                Protocol::Native => handle_connection(
+                                    engine, stream, auth, read_only, timeouts, metrics, native,
+                                ),
+                                Protocol::Resp => handle_resp_connection(
+                                    engine, stream, auth, read_only, timeouts, access_log, metrics,
+                                ),
+                            });
+                        if let Err(err) = result {
+                            error!("Error serving client: {:?}", err);
+                        }
+                    });
+                }
+                Err(err) => error!("Connection failed: {}", err),
+            }
+        }
+        drop(listener);
+        let threads = [
+            ("metrics", metrics_thread),
+            ("gRPC", grpc_thread),
+            ("HTTP", http_thread),
+        ];
+        for (name, thread) in threads {
+            if let Some((addr, handle)) = thread {
+                // wake the thread up, now that it will see the server is shutting down
+                if let Err(err) = TcpStream::connect(local_addr(addr)) {
+                    warn!("Failed to wake up the {} thread to stop it: {}", name, err);
+                } else if handle.join().is_err() {
+                    error!("The {} thread panicked", name);
+                }
+            }
+        }
+        self.stop(&connections)
+    }
+
+    // Answer the scrapes of the metrics on a thread of their own, until the server shuts down.
+    fn spawn_metrics(
+        &self,
+        listener: TcpListener,
+        connections: &Arc<Connections>,
+    ) -> Result<(SocketAddr, JoinHandle<()>)> {
+        let addr = listener.local_addr()?;
+        info!("Serving metrics on http://{}/metrics", addr);
+        let engine = self.engine.clone();
+        let metrics = Arc::clone(&self.metrics);
+        let connections = Arc::clone(connections);
+        let shutdown = self.shutdown.clone();
+        let handle = thread::Builder::new()
+            .name("kvs-metrics".to_owned())
+            .spawn(move || {
+                serve_metrics(
+                    listener,
+                    || {
+                        let mut out = metrics.to_prometheus(connections.len());
+                        out.push_str(&engine.stats()?.to_prometheus());
+                        Ok(out)
+                    },
+                    || shutdown.is_shutdown(),
+                )
+            })?;
+        Ok((addr, handle))
+    }
+
+    // Serve the connections of gRPC clients, until the server shuts down.
+    fn spawn_grpc(
+        &self,
+        listener: TcpListener,
+        connections: &Arc<Connections>,
+    ) -> Result<(SocketAddr, JoinHandle<()>)> {
+        info!("Serving gRPC on {}", listener.local_addr()?);
+        let service = Arc::new(Service::new(
+            self.engine.clone(),
+            self.auth.clone(),
+            self.read_only,
+            Arc::clone(&self.metrics),
+            self.access_log,
+        ));
+        // gRPC clients ask for HTTP/2 in the TLS handshake
+        let tls = self.tls_with_alpn(b"h2");
+        let timeouts = self.timeouts;
+        self.spawn_front_end("gRPC", listener, connections, move |stream| {
+            // Calls may wait for changes for as long as they like, so only writes time out.
+            stream.set_write_timeout(timeouts.write)?;
+            let stream = Stream::accept(stream, tls.as_ref())?;
+            serve_grpc_connection(stream, Arc::clone(&service))
+        })
+    }
+
+    // Serve the connections of the REST gateway, until the server shuts down.
+    fn spawn_http(
+        &self,
+        listener: TcpListener,
+        connections: &Arc<Connections>,
+    ) -> Result<(SocketAddr, JoinHandle<()>)> {
+        info!("Serving HTTP on http://{}/keys", listener.local_addr()?);
+        let gateway = Arc::new(Gateway::new(
+            self.engine.clone(),
+            self.auth.clone(),
+            self.read_only,
+            Arc::clone(&self.metrics),
+            self.access_log,
+        ));
+        let tls = self.tls_with_alpn(b"http/1.1");
+        let timeouts = self.timeouts;
+        self.spawn_front_end("HTTP", listener, connections, move |stream| {
+            let peer_addr = stream.peer_addr()?;
+            timeouts.apply(&stream)?;
+            let stream = Stream::accept(stream, tls.as_ref())?;
+            serve_http_connection(stream, Arc::clone(&gateway), |reader| {
+                timeouts.next_request(reader, peer_addr)
+            })
+        })
+    }
+
+    // The TLS configuration of the server, if it has one, for clients asking for `protocol` in
+    // the TLS handshake.
+    fn tls_with_alpn(&self, protocol: &[u8]) -> Option<Arc<ServerConfig>> {
+        self.tls.as_ref().map(|config| {
+            let mut config = ServerConfig::clone(config);
+            config.alpn_protocols = vec![protocol.to_vec()];
+            Arc::new(config)
+        })
+    }
+
+    // Accept the connections of a front end other than the native protocol on a thread of its
+    // own, until the server shuts down, and serve each with `serve` on a thread of its own rather
+    // than on the thread pool.
+    fn spawn_front_end(
+        &self,
+        name: &'static str,
+        listener: TcpListener,
+        connections: &Arc<Connections>,
+        serve: impl Fn(TcpStream) -> Result<()> + Send + Sync + 'static,
+    ) -> Result<(SocketAddr, JoinHandle<()>)> {
+        let addr = listener.local_addr()?;
+        let serve = Arc::new(serve);
+        let connections = Arc::clone(connections);
+        let shutdown = self.shutdown.clone();
+        let max_connections = self.max_connections;
+        let metrics = Arc::clone(&self.metrics);
+        let thread_name = format!("kvs-{}", name.to_lowercase());
+        let handle = thread::Builder::new()
+            .name(thread_name.clone())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if shutdown.is_shutdown() {
+                        break;
+                    }
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            error!("{} connection failed: {}", name, err);
+                            continue;
+                        }
+                    };
+                    if let Some(max) = max_connections {
+                        let open = connections.len();
+                        if open >= max {
+                            warn!(
+                                "Turning down a {} connection, {} are open already",
+                                name, open
+                            );
+                            continue;
+                        }
+                    }
+                    let connection = match connections.add(&stream) {
+                        Ok(connection) => connection,
+                        Err(err) => {
+                            error!("{} connection failed: {}", name, err);
+                            continue;
+                        }
+                    };
+                    metrics.connected();
+                    let serve = Arc::clone(&serve);
+                    let spawned = thread::Builder::new()
+                        .name(format!("{}-connection", thread_name))
+                        .spawn(move || {
+                            let _connection = connection;
+                            if let Err(err) = serve(stream) {
+                                error!("Error serving {} client: {:?}", name, err);
+                            }
+                        });
+                    if let Err(err) = spawned {
+                        error!(
+                            "Failed to start a thread for a {} connection: {}",
+                            name, err
+                        );
+                    }
+                }
+            })?;
+        Ok((addr, handle))
+    }
+
+    // Tell a client there are too many connections, in its protocol, unless it speaks TLS.
+    fn turn_down(&self, mut stream: TcpStream) {
+        if self.tls.is_some() {
+            return;
+        }
+        let err = KvsError::ServerError("too many connections".to_owned());
+        // The client hasn't sent anything yet, so the error answers its first request.
+        let result = match self.protocol {
+            Protocol::Native => write_message(&mut stream, &Response::Err(err.to_remote())),
+            Protocol::Resp => write_reply(&mut stream, &Reply::Error(format!("ERR {}", err))),
+        };
+        if let Err(err) = result {
+            debug!("Failed to turn down a connection: {}", err);
+        }
+    }
+
+    // Close the connections once they have served the requests they were reading, and sync the
+    // engine.
+    fn stop(self, connections: &Connections) -> Result<()> {
+        info!("Shutting down");
+        // Reading from a connection now ends as if the client had closed it.
+        connections.shutdown(Shutdown::Read);
+        let busy = connections.wait(Some(self.shutdown_timeout));
+        if busy > 0 {
+            warn!("Closing {} connections that are still busy", busy);
+            connections.shutdown(Shutdown::Both);
+            connections.wait(None);
+        }
+        self.engine.flush()?;
+        info!("Server stopped");
+        Ok(())
+    }
+}
+
+impl Timeouts {
+    // Set the timeouts of a connection that was just accepted.
+    fn apply(&self, socket: &TcpStream) -> Result<()> {
+        socket.set_read_timeout(self.read)?;
+        socket.set_write_timeout(self.write)?;
+        Ok(())
+    }
+
+    // Wait for the client to start sending its next request, unless it is already buffered.
+    // Returns false if the client closed the connection or was idle for too long.
+    fn next_request(&self, reader: &mut BufReader<&Stream>, peer_addr: SocketAddr) -> Result<bool> {
+        if !reader.buffer().is_empty() {
+            return Ok(true);
+        }
+        let socket = reader.get_ref().socket();
+        socket.set_read_timeout(self.idle)?;
+        let started = match reader.fill_buf() {
+            Ok(buf) => !buf.is_empty(),
+            Err(err) if is_timeout(&err) => {
+                info!("Closing the connection of {}, idle for too long", peer_addr);
+                false
+            }
+            Err(err) => return Err(err.into()),
+        };
+        socket.set_read_timeout(self.read)?;
+        Ok(started)
+    }
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Serve the requests sent on a connection until the client closes it.
+///
+/// Tagged requests are handed to workers started as they are needed, which answer them as soon
+/// as they are done, while the requests after them are read.
+fn handle_connection<E: KvsEngine>(
+    engine: E,
+    stream: Stream,
+    auth: Option<Arc<AuthConfig>>,
+    read_only: bool,
+    timeouts: Timeouts,
+    metrics: Arc<ServerMetrics>,
+    native: Arc<Native>,
+) -> Result<()> {
+    let peer_addr = stream.socket().peer_addr()?;
+    let mut reader = BufReader::new(&stream);
+    let writer = Mutex::new(BufWriter::new(&stream));
+    let mut authenticated = auth.is_none();
+    // the user the connection authenticated as, or `None` for a shared token
+    let mut user = None;
+    let mut codec = Codec::Json;
+    // whether a request was served, after which the codec can't be switched
+    let mut started_serving = false;
+    let (tagged_sender, tagged_receiver) = mpsc::sync_channel(MAX_QUEUED_TAGGED);
+    let workers = TaggedWorkers {
+        requests: Mutex::new(tagged_receiver),
+        idle: AtomicUsize::new(0),
+        writer: &writer,
+        read_only,
+        timeouts,
+        metrics: &metrics,
+        native: &native,
+        peer_addr,
+    };
+    let mut worker_count = 0;
+
+    thread::scope(|scope| {
+        while timeouts.next_request(&mut reader, peer_addr)? {
+            let received = Instant::now();
+            let request = match codec.read_message::<Request>(&mut reader)? {
+                Some(request) => request,
+                None => break,
+            };
+            debug!("Request from {}: {:?}", peer_addr, request);
+            let (trace_id, request) = untrace(request);
+            let command = request_command(&request);
+            let key = request_key(&request)
+                .filter(|_| timeouts.slow.is_some() || native.access_log.is_on())
+                .map(str::to_owned);
+            let log_access = |outcome| {
+                native.access_log.log(&Access {
+                    peer_addr,
+                    protocol: "native",
+                    trace_id: trace_id.as_deref(),
+                    command,
+                    key: key.as_deref().map(str::as_bytes),
+                    outcome,
+                    latency: received.elapsed(),
+                })
+            };
+            let authorized = authorize(auth.as_deref(), user.as_deref(), &request);
+            let started = Instant::now();
+            let first = !std::mem::replace(&mut started_serving, true);
+            let mut next_codec = codec;
+            let mut writer = writer.lock().expect("connection writer lock poisoned");
+            let result = match request {
+                Request::Hello { version, .. }
+                    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) =>
+                {
+                    warn!("{} speaks protocol version {}", peer_addr, version);
+                    let err = KvsError::UnsupportedProtocolVersion(version);
+                    metrics.record(command, started.elapsed(), true);
+                    // the client may not understand anything after this
+                    write_message(&mut *writer, &Response::Err(err.to_remote()))?;
+                    writer.flush()?;
+                    log_access(Outcome::Failed(err.code()));
+                    break;
+                }
+                Request::Hello { codecs, .. } if first => {
+                    match native.codecs.iter().find(|codec| codecs.contains(codec)) {
+                        Some(&chosen) => {
+                            next_codec = chosen;
+                            Ok(Response::Hello(ServerInfo {
+                                version: PROTOCOL_VERSION,
+                                codec: chosen,
+                                capabilities: capabilities(&engine, auth.is_some(), read_only),
+                            }))
+                        }
+                        None => Err(KvsError::UnknownFormat(format!(
+                            "none of the codecs {:?} is accepted",
+                            codecs
+                        ))),
+                    }
+                }
+                Request::Hello { .. } => Err(KvsError::ServerError(
+                    "Hello is only sent as the first request".to_owned(),
+                )),
+                Request::Tagged { tag, request } => {
+                    drop(writer);
+                    if workers.idle.load(Ordering::SeqCst) == 0 && worker_count < MAX_TAGGED_WORKERS
+                    {
+                        worker_count += 1;
+                        workers.idle.fetch_add(1, Ordering::SeqCst);
+                        let engine = engine.clone();
+                        let workers = &workers;
+                        scope.spawn(move || workers.work(engine));
+                    }
+                    let tagged = TaggedRequest {
+                        tag,
+                        request: *request,
+                        trace_id,
+                        authenticated,
+                        authorized,
+                        codec,
+                        received,
+                    };
+                    // waits for a worker once too many are queued, which stops reading requests
+                    tagged_sender
+                        .send(tagged)
+                        .expect("tagged request workers stopped");
+                    continue;
+                }
+                Request::Ping => Ok(Response::Ok(None)),
+                Request::Auth(credentials) => Ok(match auth {
+                    Some(ref auth) if auth.check(&credentials) => {
+                        authenticated = true;
+                        user = credentials.username().map(str::to_owned);
+                        Response::Ok(None)
+                    }
+                    Some(_) => {
+                        warn!("Authentication of {} failed", peer_addr);
+                        Response::AuthFailed
+                    }
+                    None => Response::Err(
+                        KvsError::ServerError("The server has no credentials to check".to_owned())
+                            .to_remote(),
+                    ),
+                }),
+                // the value has to be read whatever happens to it, to get to the next request
+                Request::SetStream { key, len } => {
+                    let mut chunks = ChunkReader::new(&mut reader);
+                    let result = if !authenticated {
+                        Ok(Response::AuthRequired)
+                    } else if let Err(err) = authorized {
+                        Err(err)
+                    } else if read_only {
+                        Err(KvsError::ReadOnly)
+                    } else {
+                        engine
+                            .set_reader(key, &mut chunks, len)
+                            .map(|_| Response::Ok(None))
+                    };
+                    chunks.drain()?;
+                    result
+                }
+                _ if !authenticated => Ok(Response::AuthRequired),
+                _ if authorized.is_err() => authorized.map(|()| Response::Ok(None)),
+                Request::Replicate { id, offset } => match engine.as_kv_store() {
+                    Some(store) => {
+                        info!("Replicating to {}", peer_addr);
+                        // the connection only carries the replication log from now on
+                        return serve_follower(store, &mut *writer, codec, id, offset);
+                    }
+                    None => Err(KvsError::ServerError(
+                        "Only the kvs engine can be replicated".to_owned(),
+                    )),
+                },
+                Request::Raft(message) => match engine.as_raft() {
+                    Some(node) => node.handle(message).map(Response::Raft),
+                    None => Err(KvsError::ServerError(
+                        "The server isn't part of a Raft cluster".to_owned(),
+                    )),
+                },
+                Request::GetStream { key } => {
+                    match stream_value(&engine, key, codec, &mut *writer)? {
+                        Some(result) => result,
+                        None => {
+                            metrics.record(command, started.elapsed(), false);
+                            if reader.buffer().is_empty() {
+                                writer.flush()?;
+                            }
+                            log_access(Outcome::Ok);
+                            continue;
+                        }
+                    }
+                }
+                request => answer(&engine, request, read_only, &native.dedup),
+            };
+            let response = into_response(result);
+            let handled = Instant::now();
+            let failed = matches!(response, Response::Err(_) | Response::AuthFailed);
+            metrics.record(command, handled - started, failed);
+            let outcome = Outcome::of(&response);
+            let response = traced(trace_id.clone(), response);
+            debug!("Response to {}: {:?}", peer_addr, response);
+            codec.write_message(&mut *writer, &response)?;
+            codec = next_codec;
+            // Clients may send several requests without waiting for the responses. Answer them
+            // all at once.
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+            drop(writer);
+            log_access(outcome);
+            let key = key.as_deref().map(str::as_bytes);
+            log_slow_request(
+                timeouts.slow,
+                peer_addr,
+                command,
+                key,
+                [received, started, handled],
+            );
+        }
+        // the workers stop once they answered the requests left
+        drop(tagged_sender);
+        Ok(())
+    })
+}
+
+// Answer a request that doesn't depend on the state of the connection it was sent on, as all
+// those that can be tagged don't.
+fn answer<E: KvsEngine>(
+    engine: &E,
+    request: Request,
+    read_only: bool,
+    dedup: &Deduplicator,
+) -> Result<Response> {
+    match request {
+        Request::Set { .. } | Request::Remove { .. } if read_only => Err(KvsError::ReadOnly),
+        Request::Get { key } => engine.get(key).map(Response::Ok),
+        Request::GetMany { keys } => engine.get_many(&keys).map(Response::Values),
+        Request::Exists { key } => engine.contains_key(key).map(Response::Exists),
+        Request::Set { key, value, id } => {
+            write_once(dedup, id, || engine.set_numbered(key, value))
+        }
+        Request::Remove { key, id } => write_once(dedup, id, || engine.remove_numbered(key)),
+        Request::Stats => engine.stats().map(Response::Stats),
+        Request::Ping => Ok(Response::Ok(None)),
+        _ => Err(KvsError::ServerError(
+            "Only get, get_many, exists, set, remove, stats and ping requests can be tagged"
+                .to_owned(),
+        )),
+    }
+}
+
+// Turn down a request for keys the user the connection authenticated as, or a shared token if
+// it is `None`, may not access as the request would. Replicating the store and taking part in its
+// Raft cluster take in every key.
+fn authorize(auth: Option<&AuthConfig>, user: Option<&str>, request: &Request) -> Result<()> {
+    let auth = match auth {
+        Some(auth) => auth,
+        None => return Ok(()),
+    };
+    match request {
+        Request::Get { key } | Request::Exists { key } | Request::GetStream { key } => {
+            auth.authorize(user, key, KeyAccess::Read)
+        }
+        Request::GetMany { keys } => keys
+            .iter()
+            .try_for_each(|key| auth.authorize(user, key, KeyAccess::Read)),
+        Request::Set { key, .. } | Request::Remove { key, .. } | Request::SetStream { key, .. } => {
+            auth.authorize(user, key, KeyAccess::ReadWrite)
+        }
+        Request::Replicate { .. } => auth.authorize(user, "", KeyAccess::Read),
+        Request::Raft(_) => auth.authorize(user, "", KeyAccess::ReadWrite),
+        Request::Tagged { request, .. } | Request::Traced { request, .. } => {
+            authorize(Some(auth), user, request)
+        }
+        _ => Ok(()),
+    }
+}
+
+// Take a request out of `Traced`, along with its trace id.
+fn untrace(request: Request) -> (Option<String>, Request) {
+    match request {
+        Request::Traced { trace_id, request } => (Some(trace_id), *request),
+        request => (None, request),
+    }
+}
+
+// Answer a request that was sent in `Traced` with `Traced`.
+fn traced(trace_id: Option<String>, response: Response) -> Response {
+    match trace_id {
+        Some(trace_id) => Response::Traced {
+            trace_id,
+            response: Box::new(response),
+        },
+        None => response,
+    }
+}
+
+// The response that reports the outcome of a request.
+fn into_response(result: Result<Response>) -> Response {
+    match result {
+        Ok(response) => response,
+        Err(KvsError::KeyNotFound) => Response::KeyNotFound,
+        Err(KvsError::NotLeader(leader)) => Response::NotLeader(leader),
+        Err(err) => Response::Err(err.to_remote()),
+    }
+}
+
+// A tagged request, with what it is answered with that depends on when it was read.
+struct TaggedRequest {
+    tag: u64,
+    request: Request,
+    // the trace id of the request, if it was sent in `Traced` around the tag
+    trace_id: Option<String>,
+    authenticated: bool,
+    // whether the user the connection authenticated as may access the keys of the request
+    authorized: Result<()>,
+    codec: Codec,
+    received: Instant,
+}
+
+// What the workers answering the tagged requests of a connection share.
+struct TaggedWorkers<'a, 's> {
+    requests: Mutex<Receiver<TaggedRequest>>,
+    // the workers waiting for a request
+    idle: AtomicUsize,
+    writer: &'a Mutex<BufWriter<&'s Stream>>,
+    read_only: bool,
+    timeouts: Timeouts,
+    metrics: &'a ServerMetrics,
+    native: &'a Native,
+    peer_addr: SocketAddr,
+}
+
+impl TaggedWorkers<'_, '_> {
+    // Answer tagged requests as they come, until the connection stops reading them.
+    fn work<E: KvsEngine>(&self, engine: E) {
+        loop {
+            let next = self
+                .requests
+                .lock()
+                .expect("tagged requests lock poisoned")
+                .recv();
+            let tagged = match next {
+                Ok(tagged) => tagged,
+                Err(_) => break,
+            };
+            self.idle.fetch_sub(1, Ordering::SeqCst);
+            let result = self.answer(&engine, tagged);
+            self.idle.fetch_add(1, Ordering::SeqCst);
+            if let Err(err) = result {
+                debug!(
+                    "Failed to answer a tagged request of {}: {}",
+                    self.peer_addr, err
+                );
+                // the response may have been cut short, so nothing can be written after it
+                let writer = self.writer.lock().expect("connection writer lock poisoned");
+                let _ = writer.get_ref().socket().shutdown(Shutdown::Both);
+                break;
+            }
+        }
+    }
+
+    fn answer<E: KvsEngine>(&self, engine: &E, tagged: TaggedRequest) -> Result<()> {
+        let TaggedRequest {
+            tag,
+            request,
+            trace_id,
+            authenticated,
+            authorized,
+            codec,
+            received,
+        } = tagged;
+        let (inner_trace_id, request) = untrace(request);
+        let trace_id = inner_trace_id.or(trace_id);
+        let command = request_command(&request);
+        let access_log = self.native.access_log;
+        let key = request_key(&request)
+            .filter(|_| self.timeouts.slow.is_some() || access_log.is_on())
+            .map(str::to_owned);
+        let started = Instant::now();
+        let result = match request {
+            Request::Ping => Ok(Response::Ok(None)),
+            _ if !authenticated => Ok(Response::AuthRequired),
+            _ if authorized.is_err() => authorized.map(|()| Response::Ok(None)),
+            request => answer(engine, request, self.read_only, &self.native.dedup),
+        };
+        let response = into_response(result);
+        let handled = Instant::now();
+        let failed = matches!(response, Response::Err(_));
+        self.metrics.record(command, handled - started, failed);
+        let outcome = Outcome::of(&response);
+        let response = traced(trace_id.clone(), response);
+        debug!(
+            "Response to {}: {:?} (tag {})",
+            self.peer_addr, response, tag
+        );
+        {
+            let mut writer = self.writer.lock().expect("connection writer lock poisoned");
+            let response = Response::Tagged {
+                tag,
+                response: Box::new(response),
+            };
+            codec.write_message(&mut *writer, &response)?;
+            writer.flush()?;
+        }
+        let key = key.as_deref().map(str::as_bytes);
+        access_log.log(&Access {
+            peer_addr: self.peer_addr,
+            protocol: "native",
+            trace_id: trace_id.as_deref(),
+            command,
+            key,
+            outcome,
+            latency: received.elapsed(),
+        });
+        log_slow_request(
+            self.timeouts.slow,
+            self.peer_addr,
+            command,
+            key,
+            [received, started, handled],
+        );
+        Ok(())
+    }
+}
+
+/// Write the value of `key` in chunks after a `Stream` response. Returns what to respond
+/// instead if nothing was written, because the key doesn't exist or reading it failed right
+/// away. Failing once the response is written leaves the connection unusable, so that is an
+/// error.
+fn stream_value<E: KvsEngine>(
+    engine: &E,
+    key: String,
+    codec: Codec,
+    writer: &mut impl Write,
+) -> Result<Option<Result<Response>>> {
+    let mut stream = ValueStream {
+        writer,
+        codec,
+        started: false,
+    };
+    match engine.get_writer(key, BufWriter::with_capacity(CHUNK_SIZE, &mut stream)) {
+        Ok(true) => {
+            stream.start()?;
+            ChunkWriter(&mut stream.writer).finish()?;
+            Ok(None)
+        }
+        Ok(false) => Ok(Some(Ok(Response::Ok(None)))),
+        Err(err) if !stream.started => Ok(Some(Err(err))),
+        Err(err) => Err(err),
+    }
+}
+
+/// What the server tells clients it can do, or requires.
+fn capabilities<E: KvsEngine>(engine: &E, auth: bool, read_only: bool) -> Capabilities {
+    let store = engine.as_kv_store();
+    Capabilities {
+        auth,
+        read_only,
+        compression: store.is_some_and(KvStore::compresses),
+        replication: store.is_some(),
+        raft: engine.as_raft().is_some(),
+    }
+}
+
+/// Apply a write with `write`, once however many times it is sent if it has an `id`.
+fn write_once(
+    dedup: &Deduplicator,
+    id: Option<RequestId>,
+    write: impl FnOnce() -> Result<Option<u64>>,
+) -> Result<Response> {
+    match id {
+        Some(id) => dedup.apply(&id, write).map(Response::Written),
+        None => write().map(|_| Response::Ok(None)),
+    }
+}
+
+/// Writes a value as chunks, after the `Stream` response that announces it.
+struct ValueStream<W: Write> {
+    writer: W,
+    codec: Codec,
+    // whether the response was written
+    started: bool,
+}
+
+impl<W: Write> ValueStream<W> {
+    fn start(&mut self) -> io::Result<()> {
+        if !self.started {
+            self.codec
+                .write_message(&mut self.writer, &Response::Stream)
+                .map_err(|err| io::Error::other(err.to_string()))?;
+            self.started = true;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ValueStream<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.start()?;
+        ChunkWriter(&mut self.writer).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Serve the commands sent on a RESP connection until the client closes it.
+fn handle_resp_connection<E: KvsEngine>(
+    engine: E,
+    stream: Stream,
+    auth: Option<Arc<AuthConfig>>,
+    read_only: bool,
+    timeouts: Timeouts,
+    access_log: AccessLog,
+    metrics: Arc<ServerMetrics>,
+) -> Result<()> {
+    let peer_addr = stream.socket().peer_addr()?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let mut authenticated = auth.is_none();
+    // the user the connection authenticated as, or `None` for a shared token
+    let mut user = None;
+    let log_keys = timeouts.slow.is_some() || access_log.is_on();
+
+    loop {
+        if !timeouts.next_request(&mut reader, peer_addr)? {
+            return Ok(());
+        }
+        let received = Instant::now();
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            // Like Redis, tell the client what was wrong with its input before hanging up.
+            Err(KvsError::IoError(err)) if err.kind() == std::io::ErrorKind::InvalidData => {
+                write_reply(&mut writer, &Reply::Error(format!("ERR {}", err)))?;
+                writer.flush()?;
+                return Err(err.into());
+            }
+            Err(err) => return Err(err),
+        };
+        let name = args
+            .first()
+            .map(|name| name.to_ascii_lowercase())
+            .unwrap_or_default();
+        // the first key, and never the credentials of AUTH
+        let key = match &name[..] {
+            b"get" | b"set" | b"del" | b"exists" if log_keys => args.get(1).cloned(),
+            _ => None,
+        };
+        let started = Instant::now();
+        if name == b"auth" {
+            // without the credentials
+            debug!("Command from {}: AUTH", peer_addr);
+        } else {
+            debug!(
+                "Command from {}: {:?}",
+                peer_addr,
+                args.iter()
+                    .map(|arg| String::from_utf8_lossy(arg))
+                    .collect::<Vec<_>>()
+            );
+        }
+        let reply = if name == b"auth" {
+            let reply = authenticate(auth.as_deref(), &args, &mut authenticated, &mut user);
+            if let Reply::Error(_) = reply {
+                warn!("Authentication of {} failed", peer_addr);
+            }
+            reply
+        } else if !authenticated && name != b"ping" {
+            Reply::Error("NOAUTH Authentication required.".to_owned())
+        } else if !resp_authorized(auth.as_deref(), user.as_deref(), &name, &args) {
+            Reply::Error(
+                "NOPERM this user has no permissions to access one of the keys used as arguments"
+                    .to_owned(),
+            )
+        } else if read_only && (name == b"set" || name == b"del") {
+            Reply::Error("READONLY You can't write against a read only replica.".to_owned())
+        } else {
+            match execute(&engine, &args) {
+                Ok(reply) => reply,
+                Err(err) => Reply::Error(format!("ERR {}", err)),
+            }
+        };
+        let handled = Instant::now();
+        let outcome = match reply {
+            Reply::Error(ref err) => Outcome::FailedWith(err.split(' ').next().unwrap_or_default()),
+            _ => Outcome::Ok,
+        };
+        let failed = matches!(reply, Reply::Error(_));
+        metrics.record(resp_command(&name), handled - started, failed);
+        debug!("Reply to {}: {:?}", peer_addr, reply);
+        write_reply(&mut writer, &reply)?;
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        access_log.log(&Access {
+            peer_addr,
+            protocol: "resp",
+            trace_id: None,
+            command: resp_command(&name),
+            key: key.as_deref(),
+            outcome,
+            latency: received.elapsed(),
+        });
+        let times = [received, started, handled];
+        let command = String::from_utf8_lossy(&name);
+        log_slow_request(timeouts.slow, peer_addr, &command, key.as_deref(), times);
+    }
+}
+
+// The key a request is about, if it is about a single one.
+fn request_key(request: &Request) -> Option<&str> {
+    match request {
+        Request::Get { key }
+        | Request::Exists { key }
+        | Request::Set { key, .. }
+        | Request::Remove { key, .. }
+        | Request::SetStream { key, .. }
+        | Request::GetStream { key } => Some(key),
+        Request::Tagged { request, .. } | Request::Traced { request, .. } => request_key(request),
+        _ => None,
+    }
+}
+
+// Log a request that took at least `threshold`, with where the time went: `times` are when it
+// started arriving, when it was read and when it was handled, and it was answered just now.
+fn log_slow_request(
+    threshold: Option<Duration>,
+    peer_addr: SocketAddr,
+    command: &str,
+    key: Option<&[u8]>,
+    times: [Instant; 3],
+) {
+    let [received, started, handled] = times;
+    let answered = Instant::now();
+    match threshold {
+        Some(threshold) if answered - received >= threshold => {}
+        _ => return,
+    }
+    let key = key.map_or_else(String::new, |key| {
+        format!(" of key '{}'", String::from_utf8_lossy(key))
+    });
+    warn!(
+        target: "kvs::slow",
+        "Slow {}{} from {} took {:?}: {:?} reading the request, {:?} handling it, {:?} writing \
+         the response",
+        command,
+        key,
+        peer_addr,
+        answered - received,
+        started - received,
+        handled - started,
+        answered - handled
+    );
+}
+
+/// Run an AUTH command, which takes either a shared token or a username and a password.
+fn authenticate(
+    auth: Option<&AuthConfig>,
+    args: &[Vec<u8>],
+    authenticated: &mut bool,
+    user: &mut Option<String>,
+) -> Reply {
+    let credentials = match args {
+        [_, token] => Credentials::token(String::from_utf8_lossy(token)),
+        [_, username, password] => Credentials::user(
+            String::from_utf8_lossy(username),
+            String::from_utf8_lossy(password),
+        ),
+        _ => return Reply::Error("ERR wrong number of arguments for 'auth' command".to_owned()),
+    };
+    match auth {
+        Some(auth) if auth.check(&credentials) => {
+            *authenticated = true;
+            *user = credentials.username().map(str::to_owned);
+            Reply::Simple("OK")
+        }
+        Some(_) => Reply::Error("WRONGPASS invalid username-password pair".to_owned()),
+        None => Reply::Error("ERR AUTH called without any credentials configured".to_owned()),
+    }
+}
+
+/// Whether `user` may access the keys of a RESP command as the command would.
+fn resp_authorized(
+    auth: Option<&AuthConfig>,
+    user: Option<&str>,
+    name: &[u8],
+    args: &[Vec<u8>],
+) -> bool {
+    let auth = match auth {
+        Some(auth) => auth,
+        None => return true,
+    };
+    let keys = args.get(1..).unwrap_or_default();
+    let (keys, access) = match name {
+        b"get" | b"mget" | b"exists" => (keys, KeyAccess::Read),
+        b"set" => (&keys[..keys.len().min(1)], KeyAccess::ReadWrite),
+        b"del" => (keys, KeyAccess::ReadWrite),
+        _ => return true,
+    };
+    keys.iter()
+        .all(|key| auth.allows(user, &String::from_utf8_lossy(key), access))
+}
+
+/// Run a RESP command. `args` holds the name of the command followed by its arguments.
+fn execute<E: KvsEngine>(engine: &E, args: &[Vec<u8>]) -> Result<Reply> {
+    let (name, args) = match args.split_first() {
+        Some((name, args)) => (String::from_utf8_lossy(name).to_ascii_lowercase(), args),
+        None => return Ok(Reply::Error("ERR empty command".to_owned())),
+    };
+    let wrong_arity = || {
+        Ok(Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        )))
+    };
+    let mut args = args.iter().cloned().map(String::from_utf8);
+    match name.as_str() {
+        "get" => match (args.next(), args.next()) {
+            (Some(key), None) => Ok(Reply::Bulk(engine.get(key?)?.map(String::into_bytes))),
+            _ => wrong_arity(),
+        },
+        "mget" if args.len() > 0 => {
+            let keys = args.collect::<std::result::Result<Vec<_>, _>>()?;
+            let values = engine.get_many(&keys)?;
+            Ok(Reply::Array(
+                values
+                    .into_iter()
+                    .map(|value| Reply::Bulk(value.map(String::into_bytes)))
+                    .collect(),
+            ))
+        }
+        "set" => match (args.next(), args.next(), args.next()) {
+            (Some(key), Some(value), None) => {
+                engine.set(key?, value?)?;
+                Ok(Reply::Simple("OK"))
+            }
+            (Some(_), Some(_), Some(_)) => Ok(Reply::Error("ERR syntax error".to_owned())),
+            _ => wrong_arity(),
+        },
+        "del" if args.len() > 0 => {
+            let mut removed = 0;
+            for key in args {
+                match engine.remove(key?) {
+                    Ok(()) => removed += 1,
+                    Err(KvsError::KeyNotFound) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(Reply::Integer(removed))
+        }
+        "exists" if args.len() > 0 => {
+            let mut found = 0;
+            for key in args {
+                if engine.contains_key(key?)? {
+                    found += 1;
+                }
+            }
+            Ok(Reply::Integer(found))
+        }
+        "del" | "exists" | "mget" => wrong_arity(),
+        "stats" => match args.next() {
+            None => Ok(Reply::Bulk(Some(engine.stats()?.to_string().into_bytes()))),
+            Some(_) => wrong_arity(),
+        },
+        "metrics" => match args.next() {
+            None => Ok(Reply::Bulk(Some(
+                engine.stats()?.to_prometheus().into_bytes(),
+            ))),
+            Some(_) => wrong_arity(),
+        },
+        "ping" => match (args.next(), args.next()) {
+            (None, _) => Ok(Reply::Simple("PONG")),
+            (Some(message), None) => Ok(Reply::Bulk(Some(message?.into_bytes()))),
+            _ => wrong_arity(),
+        },
+        _ => Ok(Reply::Error(format!("ERR unknown command '{}'", name))),
+    }
+}
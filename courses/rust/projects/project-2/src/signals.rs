@@ -0,0 +1,42 @@
+//! Taking signals in a thread of their own, for `kvs-server` and `kvs-fuse`.
+//!
+//! The signals are blocked with `block` before any other thread starts, so that every thread
+//! blocks them too and none is killed or interrupted by them. A thread then takes them one at a
+//! time with `wait`.
+
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::raw::c_int;
+use std::ptr;
+
+fn signal_set(signals: &[c_int]) -> libc::sigset_t {
+    let mut set = MaybeUninit::uninit();
+    // SAFETY: `sigemptyset` initializes the set, which `sigaddset` then adds the signals to, and
+    // which fails without changing it for those that aren't valid.
+    unsafe {
+        libc::sigemptyset(set.as_mut_ptr());
+        let mut set = set.assume_init();
+        for &signal in signals {
+            libc::sigaddset(&mut set, signal);
+        }
+        set
+    }
+}
+
+/// Block `signals` in this thread and the threads it starts from then on.
+pub fn block(signals: &[c_int]) {
+    let set = signal_set(signals);
+    // SAFETY: the set is initialized, and the old mask isn't asked for.
+    unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+}
+
+/// Wait for one of `signals`, which must be blocked, and return it.
+pub fn wait(signals: &[c_int]) -> io::Result<c_int> {
+    let set = signal_set(signals);
+    let mut signal = 0;
+    // SAFETY: the set is initialized, and `signal` is valid to write to.
+    match unsafe { libc::sigwait(&set, &mut signal) } {
+        0 => Ok(signal),
+        err => Err(io::Error::from_raw_os_error(err)),
+    }
+}
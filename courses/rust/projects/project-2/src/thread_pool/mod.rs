@@ -0,0 +1,30 @@
+//! This module provides various thread pools. All thread pools should implement
+//! the `ThreadPool` trait.
+
+use crate::error::Result;
+
+mod naive;
+mod rayon;
+mod shared_queue;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
+
+/// The trait that all thread pools should implement.
+pub trait ThreadPool {
+    /// Create a new thread pool, immediately spawning the specified number of threads.
+    ///
+    /// Returns an error if any thread fails to spawn.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Spawn a function into the thread pool.
+    ///
+    /// Spawning always succeeds, and if the function panics the thread pool keeps
+    /// running with the same number of threads.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
@@ -0,0 +1,33 @@
+use std::io;
+
+use log::error;
+
+use super::ThreadPool;
+use crate::error::Result;
+
+/// A thread pool backed by rayon, whose workers steal jobs from each other's queues rather than
+/// sharing a single one.
+///
+/// If a job panics, the panic is logged and the worker goes on with the next job.
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+}
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            // without a handler, a panicking job would abort the process
+            .panic_handler(|_| error!("A job of the thread pool panicked"))
+            .build()
+            .map_err(io::Error::other)?;
+        Ok(RayonThreadPool { pool })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool.spawn(job);
+    }
+}
@@ -0,0 +1,69 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{debug, error};
+
+use super::ThreadPool;
+use crate::error::Result;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A thread pool whose workers take jobs from a shared queue.
+///
+/// If a job panics, the worker thread dies and a new one is spawned in its place.
+pub struct SharedQueueThreadPool {
+    tx: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..threads {
+            let worker = Worker(Arc::clone(&rx));
+            thread::Builder::new().spawn(move || worker.run())?;
+        }
+        Ok(SharedQueueThreadPool { tx })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.tx
+            .send(Box::new(job))
+            .expect("The thread pool has no thread.");
+    }
+}
+
+// Owned by a worker thread. If the thread unwinds because a job panicked, dropping the worker
+// spawns a replacement thread.
+struct Worker(Arc<Mutex<Receiver<Job>>>);
+
+impl Worker {
+    fn run(self) {
+        loop {
+            // the lock is released before running the job
+            let job = self.0.lock().expect("job queue poisoned").recv();
+            match job {
+                Ok(job) => job(),
+                Err(_) => {
+                    debug!("Thread exits because the thread pool is dropped.");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let worker = Worker(Arc::clone(&self.0));
+            if let Err(err) = thread::Builder::new().spawn(move || worker.run()) {
+                error!("Failed to spawn a thread: {}", err);
+            }
+        }
+    }
+}
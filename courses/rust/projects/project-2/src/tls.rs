@@ -0,0 +1,261 @@
+//! TLS between `KvsClient` and `KvsServer`, with rustls.
+//!
+//! Connections are read and written through separate buffered halves, and a pipeline writes its
+//! requests on another thread than the one reading the responses. A `TcpStream` allows that
+//! since reads and writes don't share anything, but a TLS connection keeps state that both go
+//! through. `TlsStream` keeps it behind a lock that isn't held while waiting on the socket, so
+//! that a read waiting for the peer doesn't hold up writes.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, Connection, ServerConfig, ServerConnection};
+
+use crate::error::Result;
+
+// Bytes read from the socket at a time.
+const READ_SIZE: usize = 16 * 1024;
+
+/// A connection, encrypted or not.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Tls(Arc<TlsStream>),
+}
+
+impl Stream {
+    /// Accept a connection, first completing the TLS handshake if `config` is set.
+    pub(crate) fn accept(socket: TcpStream, config: Option<&Arc<ServerConfig>>) -> Result<Stream> {
+        match config {
+            None => Ok(Stream::Tcp(socket)),
+            Some(config) => {
+                let conn = ServerConnection::new(Arc::clone(config))?;
+                Ok(Stream::Tls(Arc::new(TlsStream::new(conn.into(), socket)?)))
+            }
+        }
+    }
+
+    /// Open a connection on `socket`, first completing the TLS handshake with the server named
+    /// `server_name` if `config` is set.
+    pub(crate) fn connect(
+        socket: TcpStream,
+        config: Option<&Arc<ClientConfig>>,
+        server_name: ServerName<'static>,
+    ) -> Result<Stream> {
+        match config {
+            None => Ok(Stream::Tcp(socket)),
+            Some(config) => {
+                let conn = ClientConnection::new(Arc::clone(config), server_name)?;
+                Ok(Stream::Tls(Arc::new(TlsStream::new(conn.into(), socket)?)))
+            }
+        }
+    }
+
+    /// Another handle to the same connection.
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(socket) => Ok(Stream::Tcp(socket.try_clone()?)),
+            Stream::Tls(stream) => Ok(Stream::Tls(Arc::clone(stream))),
+        }
+    }
+
+    /// The socket underneath.
+    pub(crate) fn socket(&self) -> &TcpStream {
+        match self {
+            Stream::Tcp(socket) => socket,
+            Stream::Tls(stream) => &stream.socket,
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(socket) => (&*socket).read(buf),
+            Stream::Tls(stream) => (&**stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(socket) => (&*socket).write(buf),
+            Stream::Tls(stream) => (&**stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(socket) => (&*socket).flush(),
+            Stream::Tls(stream) => (&**stream).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+/// A TLS connection that can be read and written at the same time from different threads.
+pub(crate) struct TlsStream {
+    state: Mutex<TlsState>,
+    socket: TcpStream,
+    // Held while records are encrypted and sent, so that they go out in the order they were
+    // encrypted in. Always taken before `state`.
+    send_lock: Mutex<()>,
+}
+
+struct TlsState {
+    conn: Connection,
+    // Bytes read from the socket that rustls hasn't taken yet
+    incoming: Vec<u8>,
+}
+
+impl TlsStream {
+    fn new(mut conn: Connection, mut socket: TcpStream) -> io::Result<TlsStream> {
+        while conn.is_handshaking() {
+            conn.complete_io(&mut socket)?;
+        }
+        Ok(TlsStream {
+            state: Mutex::new(TlsState {
+                conn,
+                incoming: Vec::new(),
+            }),
+            socket,
+            send_lock: Mutex::new(()),
+        })
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, TlsState> {
+        self.state.lock().expect("TLS state poisoned")
+    }
+
+    // Send the records rustls has queued, like alerts or replies to key updates.
+    fn send_pending(&self) -> io::Result<()> {
+        let _send = self.send_lock.lock().expect("TLS send lock poisoned");
+        let records = self.state().take_records()?;
+        (&self.socket).write_all(&records)
+    }
+}
+
+impl TlsState {
+    // Hand rustls the bytes read from the socket, as far as it takes them.
+    fn process_incoming(&mut self) -> io::Result<()> {
+        while !self.incoming.is_empty() && self.conn.wants_read() {
+            let n = self.conn.read_tls(&mut &self.incoming[..])?;
+            self.incoming.drain(..n);
+            self.conn
+                .process_new_packets()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            if n == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // The records rustls has queued to send.
+    fn take_records(&mut self) -> io::Result<Vec<u8>> {
+        let mut records = Vec::new();
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut records)?;
+        }
+        Ok(records)
+    }
+}
+
+impl Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let pending = {
+                let mut state = self.state();
+                state.process_incoming()?;
+                match state.conn.reader().read(buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+                state.conn.wants_write()
+            };
+            if pending {
+                self.send_pending()?;
+            }
+
+            let mut chunk = [0; READ_SIZE];
+            let n = (&self.socket).read(&mut chunk)?;
+            let mut state = self.state();
+            if n == 0 {
+                // Tells rustls the peer is gone, so that the next read fails unless it said
+                // goodbye first.
+                state.conn.read_tls(&mut io::empty())?;
+            } else {
+                state.incoming.extend_from_slice(&chunk[..n]);
+            }
+        }
+    }
+}
+
+impl Write for &TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _send = self.send_lock.lock().expect("TLS send lock poisoned");
+        let (n, records) = {
+            let mut state = self.state();
+            let n = state.conn.writer().write(buf)?;
+            (n, state.take_records()?)
+        };
+        (&self.socket).write_all(&records)?;
+        Ok(n)
+    }
+
+    // Every write is sent right away.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        self.state().conn.send_close_notify();
+        // the peer may be gone already
+        let _ = self.send_pending();
+    }
+}
+
+/// Read the certificates of a PEM file.
+pub(crate) fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid_pem(path, "certificate"));
+    }
+    Ok(certs)
+}
+
+/// Read the first private key of a PEM file.
+pub(crate) fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or_else(|| invalid_pem(path, "private key"))
+}
+
+fn invalid_pem(path: &Path, what: &str) -> crate::error::KvsError {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("no {} in {}", what, path.display()),
+    )
+    .into()
+}
@@ -0,0 +1,67 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use kvs::{asynch, KvsError, Result};
+use tempfile::TempDir;
+
+// Wakes the thread blocked in `block_on`.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+// A minimal executor, which polls the future on the current thread until it is ready.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+// Should set, get and remove keys through futures.
+#[test]
+fn async_get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    block_on(async {
+        let store = asynch::KvStore::open(temp_dir.path()).await?;
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        store.remove("key1".to_owned()).await?;
+        assert_eq!(store.get("key1".to_owned()).await?, None);
+        match store.remove("key1".to_owned()).await {
+            Err(KvsError::KeyNotFound) => {}
+            other => panic!("expected KeyNotFound, got {:?}", other),
+        }
+        Ok::<(), KvsError>(())
+    })?;
+
+    // The clones share the store, from any thread.
+    let store = asynch::KvStore::with_threads(kvs::KvStore::open(temp_dir.path())?, 2)?;
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || block_on(store.set(format!("key{}", i), i.to_string())))
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    for i in 0..4 {
+        assert_eq!(store.store().get(format!("key{}", i))?, Some(i.to_string()));
+    }
+
+    Ok(())
+}
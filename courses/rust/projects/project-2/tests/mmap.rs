@@ -0,0 +1,65 @@
+#![cfg(feature = "mmap")]
+
+use kvs::{KvStore, Result};
+use tempfile::TempDir;
+
+// Should borrow the values stored as they are from the mapped segments, and decode the others.
+#[test]
+fn get_ref() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .compression_threshold(Some(1024))
+        .open(temp_dir.path())?;
+
+    store.set("small".to_owned(), "value".to_owned())?;
+    let value = store.get_ref(b"small")?.unwrap();
+    assert!(value.is_mapped());
+    assert_eq!(&*value, b"value");
+
+    // compressed, so decoded into a buffer
+    let large = "x".repeat(64 * 1024);
+    store.set("large".to_owned(), large.clone())?;
+    let value = store.get_ref(b"large")?.unwrap();
+    assert!(!value.is_mapped());
+    assert_eq!(&*value, large.as_bytes());
+
+    assert!(store.get_ref(b"missing")?.is_none());
+    store.remove("small".to_owned())?;
+    assert!(store.get_ref(b"small")?.is_none());
+
+    // the active segment is mapped again once it grows past its mapping
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        assert_eq!(
+            &*store.get_ref(format!("key{}", i).as_bytes())?.unwrap(),
+            format!("value{}", i).as_bytes()
+        );
+    }
+
+    // values stay valid after the key is overwritten, compacted away and the store dropped
+    let value = store.get_ref(b"key1")?.unwrap();
+    store.set("key1".to_owned(), "new".to_owned())?;
+    store.compact()?;
+    drop(store);
+    assert_eq!(&*value, b"value1");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(&*store.get_ref(b"key1")?.unwrap(), b"new");
+    assert_eq!(&*store.get_ref(b"large")?.unwrap(), large.as_bytes());
+
+    Ok(())
+}
+
+// Should decode the values of an encrypted store.
+#[test]
+fn get_ref_encrypted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .encryption_key([7; 32])
+        .open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let value = store.get_ref(b"key")?.unwrap();
+    assert!(!value.is_mapped());
+    assert_eq!(&*value, b"value");
+    Ok(())
+}
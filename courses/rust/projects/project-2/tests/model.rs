@@ -0,0 +1,312 @@
+//! Randomized tests that check `KvStore` against a `HashMap` model.
+//!
+//! Each test runs a few fixed seeds. Set `KVS_MODEL_SEED` to run a single seed instead, e.g. to
+//! reproduce a failure, whose message names the seed.
+
+use kvs::{KvStore, KvsError, Result, SyncPolicy};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use tempfile::TempDir;
+
+const SEEDS: &[u64] = &[1, 2, 3, 42, 1234];
+
+// A small xorshift generator, so that the sequences only depend on the seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Set(String, String),
+    Remove(String),
+    Get(String),
+    Batch(Vec<(String, Option<String>)>),
+    Reopen,
+}
+
+fn random_key(rng: &mut Rng) -> String {
+    format!("key{}", rng.below(50))
+}
+
+fn random_value(rng: &mut Rng) -> String {
+    // mostly short values, and some long enough to be compressed
+    let len = if rng.below(10) == 0 {
+        2000 + rng.below(2000)
+    } else {
+        rng.below(40)
+    };
+    let c = (b'a' + rng.below(26) as u8) as char;
+    format!("{}{}", rng.next(), c.to_string().repeat(len as usize))
+}
+
+fn random_op(rng: &mut Rng, reopen: bool) -> Op {
+    match rng.below(100) {
+        0..=44 => Op::Set(random_key(rng), random_value(rng)),
+        45..=64 => Op::Remove(random_key(rng)),
+        65..=89 => Op::Get(random_key(rng)),
+        90..=97 => Op::Batch(
+            (0..1 + rng.below(5))
+                .map(|_| {
+                    let value = if rng.below(3) == 0 {
+                        None
+                    } else {
+                        Some(random_value(rng))
+                    };
+                    (random_key(rng), value)
+                })
+                .collect(),
+        ),
+        _ if reopen => Op::Reopen,
+        _ => Op::Get(random_key(rng)),
+    }
+}
+
+// Apply an operation to the store and the model, and check that they agree on its result.
+fn apply(
+    store: &KvStore,
+    model: &mut HashMap<String, String>,
+    op: &Op,
+    context: &str,
+) -> Result<()> {
+    match op {
+        Op::Set(key, value) => {
+            store.set(key.clone(), value.clone())?;
+            model.insert(key.clone(), value.clone());
+        }
+        Op::Remove(key) => match store.remove(key.clone()) {
+            Ok(()) => assert!(
+                model.remove(key).is_some(),
+                "{}: removed a missing key",
+                context
+            ),
+            Err(KvsError::KeyNotFound) => {
+                assert!(!model.contains_key(key), "{}: key not found", context)
+            }
+            Err(err) => return Err(err),
+        },
+        Op::Get(key) => assert_eq!(
+            store.get(key.clone())?,
+            model.get(key).cloned(),
+            "{}",
+            context
+        ),
+        Op::Batch(writes) => {
+            let mut batch = store.batch();
+            let mut after = model.clone();
+            let mut valid = true;
+            for (key, value) in writes {
+                match value {
+                    Some(value) => {
+                        batch.set(key.clone(), value.clone());
+                        after.insert(key.clone(), value.clone());
+                    }
+                    None => {
+                        batch.remove(key.clone());
+                        valid &= after.remove(key).is_some();
+                    }
+                }
+            }
+            match store.write_batch(batch) {
+                Ok(()) => {
+                    assert!(valid, "{}: applied a batch removing a missing key", context);
+                    *model = after;
+                }
+                Err(KvsError::KeyNotFound) => assert!(!valid, "{}: batch failed", context),
+                Err(err) => return Err(err),
+            }
+        }
+        Op::Reopen => {}
+    }
+    Ok(())
+}
+
+fn check(store: &KvStore, model: &HashMap<String, String>, context: &str) -> Result<()> {
+    let mut keys: Vec<String> = store.keys().collect();
+    keys.sort();
+    let mut expected: Vec<String> = model.keys().cloned().collect();
+    expected.sort();
+    assert_eq!(keys, expected, "{}: keys differ", context);
+    for (key, value) in model {
+        assert_eq!(
+            store.get(key.clone())?.as_ref(),
+            Some(value),
+            "{}: {}",
+            context,
+            key
+        );
+    }
+    Ok(())
+}
+
+fn seeds() -> Vec<u64> {
+    match std::env::var("KVS_MODEL_SEED") {
+        Ok(seed) => vec![seed.parse().expect("KVS_MODEL_SEED must be a number")],
+        Err(_) => SEEDS.to_vec(),
+    }
+}
+
+fn open(path: &Path) -> Result<KvStore> {
+    // small segments, so that segments roll over and get compacted
+    KvStore::options()
+        .segment_size(16 * 1024)
+        .compaction_threshold(16 * 1024)
+        .open(path)
+}
+
+// Should agree with the model on every operation, across reopens and compactions.
+#[test]
+fn random_operations() -> Result<()> {
+    for seed in seeds() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut rng = Rng::new(seed);
+        let mut store = open(temp_dir.path())?;
+        let mut model = HashMap::new();
+        for i in 0..3000 {
+            let op = random_op(&mut rng, true);
+            let context = format!("seed {}, op {} ({:?})", seed, i, op);
+            if let Op::Reopen = op {
+                drop(store);
+                store = open(temp_dir.path())?;
+                check(&store, &model, &context)?;
+            }
+            apply(&store, &mut model, &op, &context)?;
+        }
+        check(&store, &model, &format!("seed {}, end", seed))?;
+        drop(store);
+        check(
+            &open(temp_dir.path())?,
+            &model,
+            &format!("seed {}, reopened", seed),
+        )?;
+    }
+    Ok(())
+}
+
+fn truncate(path: &Path, len: u64) -> Result<()> {
+    OpenOptions::new().write(true).open(path)?.set_len(len)?;
+    Ok(())
+}
+
+// Should recover exactly the operations that made it to disk when the log is cut at any byte,
+// as a crash in the middle of writing would leave it: every operation written before the cut
+// survives and the one the cut goes through, even a batch, is either fully there or not at all.
+#[test]
+fn crash_at_any_byte() -> Result<()> {
+    for seed in seeds() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let segment = temp_dir.path().join("1.log");
+        let mut rng = Rng::new(seed);
+        // a single segment that is never compacted, so that each operation only appends to it
+        let options = || {
+            let mut options = KvStore::options();
+            options
+                .segment_size(u64::MAX)
+                .compaction_threshold(u64::MAX)
+                .sync_policy(SyncPolicy::Always);
+            options
+        };
+
+        // the model and the size of the log after each operation
+        let mut states = vec![(HashMap::new(), 0)];
+        {
+            let store = options().open(temp_dir.path())?;
+            states[0].1 = fs::metadata(&segment)?.len();
+            let mut model = HashMap::new();
+            for i in 0..300 {
+                let op = random_op(&mut rng, false);
+                apply(&store, &mut model, &op, &format!("seed {}, op {}", seed, i))?;
+                states.push((model.clone(), fs::metadata(&segment)?.len()));
+            }
+        }
+        let log = fs::read(&segment)?;
+
+        for _ in 0..20 {
+            let cut = rng.below(log.len() as u64 + 1);
+            fs::write(&segment, &log)?;
+            truncate(&segment, cut)?;
+            let context = format!("seed {}, cut at byte {} of {}", seed, cut, log.len());
+
+            let store = options().open(temp_dir.path())?;
+            let (model, _) = states
+                .iter()
+                .rev()
+                .find(|(_, size)| *size <= cut)
+                .expect("the first state has an empty log");
+            check(&store, model, &context)?;
+
+            // the store keeps working after recovering
+            store.set("after".to_owned(), "crash".to_owned())?;
+            drop(store);
+            let store = options().open(temp_dir.path())?;
+            let mut model = model.clone();
+            model.insert("after".to_owned(), "crash".to_owned());
+            check(&store, &model, &context)?;
+        }
+    }
+    Ok(())
+}
+
+// Should never lose a write that was acknowledged before a crash tore the write after it, even
+// across segments and compactions.
+#[test]
+fn crash_during_write() -> Result<()> {
+    for seed in seeds() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut rng = Rng::new(seed);
+        let mut model = HashMap::new();
+        for round in 0..10 {
+            let context = format!("seed {}, round {}", seed, round);
+            let store = open(temp_dir.path())?;
+            check(&store, &model, &context)?;
+            for i in 0..200 {
+                let op = random_op(&mut rng, false);
+                apply(&store, &mut model, &op, &format!("{}, op {}", context, i))?;
+            }
+            drop(store);
+
+            // Write one more key, then tear it as a crash would.
+            let store = open(temp_dir.path())?;
+            let segment = last_segment(temp_dir.path())?;
+            let size = fs::metadata(&segment)?.len();
+            store.set("torn".to_owned(), random_value(&mut rng))?;
+            drop(store);
+            let new_size = fs::metadata(&segment)?.len();
+            if new_size > size {
+                truncate(&segment, size + rng.below(new_size - size))?;
+                let store = open(temp_dir.path())?;
+                model.remove("torn");
+                check(&store, &model, &format!("{}, torn write", context))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn last_segment(dir: &Path) -> Result<std::path::PathBuf> {
+    let mut segments: Vec<(u64, std::path::PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .filter_map(|path| {
+            let id = path.file_stem()?.to_str()?.parse().ok()?;
+            Some((id, path))
+        })
+        .collect();
+    segments.sort();
+    Ok(segments.pop().expect("no segment").1)
+}
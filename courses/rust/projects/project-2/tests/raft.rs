@@ -0,0 +1,147 @@
+use kvs::raft::{RaftClient, RaftEngine};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsEngine, KvsError, KvsServer, Result};
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// The servers of a cluster, with the stores behind their engines.
+struct Cluster {
+    addrs: Vec<SocketAddr>,
+    engines: Vec<RaftEngine<KvStore>>,
+    stores: Vec<KvStore>,
+}
+
+// Start a cluster of a server in each directory.
+fn spawn_cluster(dirs: &[TempDir]) -> Result<Cluster> {
+    let listeners = dirs
+        .iter()
+        .map(|_| TcpListener::bind("127.0.0.1:0"))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let addrs = listeners
+        .iter()
+        .map(TcpListener::local_addr)
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let mut engines = Vec::new();
+    let mut stores = Vec::new();
+    for (me, (dir, listener)) in dirs.iter().zip(listeners).enumerate() {
+        let store = KvStore::open(dir.path())?;
+        let engine = RaftEngine::start(store.clone(), dir.path().join("raft"), addrs.clone(), me)?;
+        let server = KvsServer::new(engine.clone(), SharedQueueThreadPool::new(8)?);
+        thread::spawn(move || server.serve(listener));
+        engines.push(engine);
+        stores.push(store);
+    }
+    Ok(Cluster {
+        addrs,
+        engines,
+        stores,
+    })
+}
+
+// Wait until `condition` holds, for up to 10 seconds.
+fn wait_for(condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// Should elect a leader, replicate writes to every node, and keep serving after the leader fails.
+#[test]
+fn cluster_survives_leader_failure() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let Cluster {
+        addrs,
+        engines,
+        stores,
+    } = spawn_cluster(&dirs)?;
+
+    let mut client = RaftClient::new(addrs.clone(), &KvsClient::options());
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    for store in &stores {
+        wait_for(|| store.get("key1".to_owned()).unwrap().is_some());
+    }
+
+    // The other nodes turn writes down, pointing at the leader.
+    let leader = engines
+        .iter()
+        .position(|engine| engine.node().is_leader())
+        .expect("no leader");
+    assert_eq!(client.current(), addrs[leader]);
+    let follower = (leader + 1) % 3;
+    wait_for(|| engines[follower].node().leader() == Some(addrs[leader]));
+    match engines[follower].set("key2".to_owned(), "value2".to_owned()) {
+        Err(KvsError::NotLeader(Some(addr))) => assert_eq!(addr, addrs[leader].to_string()),
+        other => panic!("expected NotLeader, got {:?}", other),
+    }
+    let mut direct = KvsClient::connect(addrs[follower])?;
+    match direct.get("key1".to_owned()) {
+        Err(KvsError::NotLeader(Some(addr))) => assert_eq!(addr, addrs[leader].to_string()),
+        other => panic!("expected NotLeader, got {:?}", other),
+    }
+
+    let term = engines[leader].node().term();
+    engines[leader].shutdown();
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_ne!(client.current(), addrs[leader]);
+
+    let new_leader = engines
+        .iter()
+        .position(|engine| engine.node().is_leader())
+        .expect("no leader");
+    assert_ne!(new_leader, leader);
+    assert!(engines[new_leader].node().term() > term);
+    assert_eq!(engines[new_leader].increment("counter".to_owned(), 5)?, 5);
+    assert!(engines[new_leader].compare_and_swap(
+        "counter".to_owned(),
+        Some("5".to_owned()),
+        Some("6".to_owned())
+    )?);
+    for (id, store) in stores.iter().enumerate().filter(|&(id, _)| id != leader) {
+        wait_for(|| store.get("counter".to_owned()).unwrap() == Some("6".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, None, "node {}", id);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should keep its log and what it applied across restarts, so that writes aren't applied twice.
+#[test]
+fn restart_keeps_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let raft_dir = temp_dir.path().join("raft");
+    let peers: Vec<SocketAddr> = vec![([127, 0, 0, 1], 0).into()];
+
+    let engine = RaftEngine::start(KvStore::open(temp_dir.path())?, &raft_dir, peers.clone(), 0)?;
+    wait_for(|| engine.node().is_leader());
+    engine.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(engine.increment("counter".to_owned(), 1)?, 1);
+    assert_eq!(engine.increment("counter".to_owned(), 1)?, 2);
+    let term = engine.node().term();
+    drop(engine);
+
+    let engine = RaftEngine::start(KvStore::open(temp_dir.path())?, &raft_dir, peers, 0)?;
+    wait_for(|| engine.node().is_leader());
+    assert!(engine.node().term() > term);
+    assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(engine.get("counter".to_owned())?, Some("2".to_owned()));
+    assert_eq!(engine.increment("counter".to_owned(), 1)?, 3);
+
+    engine.shutdown();
+    match engine.set("key".to_owned(), "other".to_owned()) {
+        Err(KvsError::NotLeader(None)) => {}
+        other => panic!("expected NotLeader, got {:?}", other),
+    }
+
+    Ok(())
+}
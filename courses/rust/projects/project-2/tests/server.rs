@@ -0,0 +1,1846 @@
+use assert_cmd::prelude::*;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    Acl, AuthConfig, Capabilities, Codec, Credentials, ErrorCode, KvStore, KvsClient,
+    KvsClientPool, KvsError, KvsServer, MultiplexedClient, Protocol, RemoteError, Replica, Result,
+    RetryOn, RetryPolicy, ShardedClient,
+};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Start a server on a free port in the background and return its address.
+fn spawn_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    spawn_server_with(temp_dir, Protocol::Native)
+}
+
+fn spawn_server_with(temp_dir: &TempDir, protocol: Protocol) -> Result<SocketAddr> {
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    thread::spawn(move || {
+        KvsServer::new(store, pool)
+            .protocol(protocol)
+            .serve(listener)
+    });
+    Ok(addr)
+}
+
+// Should serve get/set/remove requests over the network.
+#[test]
+fn client_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    match client.remove("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+    drop(client);
+
+    // A new connection sees the same data.
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        client.get_many(vec![
+            "key2".to_owned(),
+            "key1".to_owned(),
+            "key2".to_owned()
+        ])?,
+        vec![Some("value2".to_owned()), None, Some("value2".to_owned())]
+    );
+    let stats = client.stats()?;
+    assert_eq!(stats.keys, 1);
+    assert_eq!(stats.writes, 3);
+
+    Ok(())
+}
+
+// Should send many requests at once and return the responses in order.
+#[test]
+fn client_pipeline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let mut client = KvsClient::connect(addr)?;
+
+    // Enough requests and responses to fill the socket buffers both ways.
+    let value = "x".repeat(1000);
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline.set(format!("key{}", i), value.clone());
+        pipeline.get(format!("key{}", i));
+    }
+    assert_eq!(pipeline.len(), 2000);
+    let responses = pipeline.send()?;
+    assert_eq!(responses.len(), 2000);
+    for pair in responses.chunks(2) {
+        assert_eq!(pair[0].as_ref().ok(), Some(&None));
+        assert_eq!(pair[1].as_ref().ok(), Some(&Some(value.clone())));
+    }
+
+    let responses = client
+        .pipeline()
+        .remove("key1".to_owned())
+        .remove("key1".to_owned())
+        .get("key1".to_owned())
+        .send()?;
+    assert!(responses[0].is_ok());
+    match responses[1] {
+        Err(KvsError::KeyNotFound) => {}
+        ref other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+    assert_eq!(responses[2].as_ref().ok(), Some(&None));
+
+    assert!(client.pipeline().send()?.is_empty());
+    assert_eq!(client.get("key2".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Should stream values to and from the server in chunks.
+#[test]
+fn client_stream_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let mut client = KvsClient::connect(addr)?;
+
+    let value: Vec<u8> = (0..200 * 1024).map(|i| b'a' + (i % 26) as u8).collect();
+    client.set_reader("big".to_owned(), &value[..], value.len() as u64)?;
+    let mut out = Vec::new();
+    assert!(client.get_writer("big".to_owned(), &mut out)?);
+    assert_eq!(out, value);
+    assert_eq!(
+        client.get("big".to_owned())?.map(String::into_bytes),
+        Some(value.clone())
+    );
+    assert!(!client.get_writer("missing".to_owned(), &mut out)?);
+
+    // The connection is still usable after a value that ends early.
+    assert!(client
+        .set_reader("short".to_owned(), &b"short"[..], 1024)
+        .is_err());
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(client.get("short".to_owned())?, None);
+
+    Ok(())
+}
+
+// Clients borrowed from a pool should be reused once returned.
+#[test]
+fn client_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let pool = KvsClientPool::new(addr)?;
+
+    // The server only has two threads, each serving one connection at a time.
+    let handles: Vec<_> = (0..2)
+        .map(|thread_id| {
+            let pool = pool.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..10 {
+                    let key = format!("key{}-{}", thread_id, i);
+                    pool.get()?.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(pool.get()?.get(key)?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert!(pool.idle_count() >= 1 && pool.idle_count() <= 2);
+
+    let mut client = pool.get()?;
+    assert_eq!(client.get("key1-9".to_owned())?, Some("value9".to_owned()));
+    drop(client);
+    let idle = pool.idle_count();
+    let client1 = pool.get()?;
+    let client2 = pool.get()?;
+    drop(client1);
+    drop(client2);
+    assert_eq!(pool.idle_count(), idle.max(2));
+
+    Ok(())
+}
+
+// Many threads should share a single connection, each getting the responses to its own
+// requests.
+#[test]
+fn client_multiplexing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let client = KvsClient::options()
+        .codec(Codec::MessagePack)
+        .multiplexed(addr)?;
+
+    // More threads than the server has, which would wait for each other with a connection each.
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let client = client.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..20 {
+                    let key = format!("key{}-{}", thread_id, i);
+                    client.set(key.clone(), format!("value{}-{}", thread_id, i))?;
+                    assert_eq!(
+                        client.get(key.clone())?,
+                        Some(format!("value{}-{}", thread_id, i))
+                    );
+                    assert!(client.exists(key)?);
+                }
+                client.remove(format!("key{}-0", thread_id))?;
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(
+        client.get_many(vec!["key3-0".to_owned(), "key3-19".to_owned()])?,
+        vec![None, Some("value3-19".to_owned())]
+    );
+    assert!(matches!(
+        client.remove("key3-0".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    client.ping()?;
+
+    // A server that doesn't know tagged requests is turned down.
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        answer_hello(&mut stream)
+    });
+    assert!(matches!(
+        MultiplexedClient::connect(addr),
+        Err(KvsError::UnsupportedProtocolVersion(1))
+    ));
+    server.join().unwrap()?;
+
+    Ok(())
+}
+
+// A sharded client should keep each key on a single server, and find it there again.
+#[test]
+fn sharded_client() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let addrs = dirs.iter().map(spawn_server).collect::<Result<Vec<_>>>()?;
+    let mut client = ShardedClient::connect(&addrs, &KvsClient::options())?;
+    for i in 0..60 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.remove("key0".to_owned())?;
+
+    let keys: Vec<String> = (0..60).map(|i| format!("key{}", i)).collect();
+    let values = client.get_many(keys.clone())?;
+    assert_eq!(values[0], None);
+    assert_eq!(values[59], Some("value59".to_owned()));
+
+    let mut direct = addrs
+        .iter()
+        .map(KvsClient::connect)
+        .collect::<Result<Vec<_>>>()?;
+    for key in &keys[1..] {
+        let server = client.server(key);
+        for (index, direct) in direct.iter_mut().enumerate() {
+            assert_eq!(direct.get(key.clone())?.is_some(), index == server);
+        }
+    }
+    let another = ShardedClient::connect(&addrs, &KvsClient::options())?;
+    assert!(keys
+        .iter()
+        .all(|key| another.server(key) == client.server(key)));
+
+    Ok(())
+}
+
+// Should send a request again on a new connection if the server closed the old one.
+#[test]
+fn client_reconnect() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        // Close the first connection after the client sent its first request.
+        let (mut stream, _) = listener.accept()?;
+        answer_hello(&mut stream)?;
+        read_request(&mut stream)?;
+        drop(stream);
+        // Answer every request on the second one.
+        let (mut stream, _) = listener.accept()?;
+        answer_hello(&mut stream)?;
+        while read_request(&mut stream)?.is_some() {
+            let response = br#"{"Ok":"value"}"#;
+            stream.write_all(&u32::to_le_bytes(response.len() as u32))?;
+            stream.write_all(response)?;
+        }
+        Ok(())
+    });
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    drop(client);
+    server.join().unwrap()?;
+
+    // Without reconnecting, the request fails.
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        answer_hello(&mut stream)?;
+        read_request(&mut stream)?;
+        Ok(())
+    });
+    let mut client = KvsClient::options().reconnect(false).connect(addr)?;
+    assert!(client.get("key".to_owned()).is_err());
+    server.join().unwrap()?;
+    assert!(client.get("key".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Should fail a request the server doesn't answer in time.
+#[test]
+fn client_read_timeout() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        while read_request(&mut stream)?.is_some() {}
+        Ok(())
+    });
+
+    let mut client = KvsClient::options()
+        .read_timeout(Some(Duration::from_millis(100)))
+        .connect(addr)?;
+    let start = Instant::now();
+    match client.get("key".to_owned()) {
+        Err(KvsError::IoError(_)) => {}
+        other => panic!("expected IoError, got {:?}", other),
+    }
+    assert!(start.elapsed() < Duration::from_secs(5));
+    drop(client);
+    server.join().unwrap()?;
+
+    Ok(())
+}
+
+// Should send requests again as the retry policy says, with the same id for writes.
+#[test]
+fn client_retry_policy() -> Result<()> {
+    // A write turned down for backpressure is sent again, with the same id.
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<Vec<Vec<u8>>> {
+        let (mut stream, _) = listener.accept()?;
+        answer_hello(&mut stream)?;
+        let mut requests = Vec::new();
+        let responses: [&[u8]; 2] = [
+            br#"{"Err":{"code":"Backpressure","message":"busy","detail":null,"path":null,"offset":null}}"#,
+            br#"{"Ok":null}"#,
+        ];
+        for response in responses {
+            requests.extend(read_request(&mut stream)?);
+            stream.write_all(&u32::to_le_bytes(response.len() as u32))?;
+            stream.write_all(response)?;
+        }
+        Ok(requests)
+    });
+    let mut client = KvsClient::options()
+        .retry(
+            RetryPolicy::default()
+                .max_attempts(3)
+                .backoff(Duration::from_millis(10), Duration::from_millis(50))
+                .retry_on(&[RetryOn::Backpressure]),
+        )
+        .connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    let requests = server.join().unwrap()?;
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0], requests[1]);
+
+    // A server that is down for a while is waited for.
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        answer_hello(&mut stream)?;
+        read_request(&mut stream)?;
+        drop(stream);
+        drop(listener);
+        thread::sleep(Duration::from_millis(200));
+        let listener = TcpListener::bind(addr)?;
+        let (mut stream, _) = listener.accept()?;
+        answer_hello(&mut stream)?;
+        read_request(&mut stream)?;
+        let response = br#"{"Ok":"value"}"#;
+        stream.write_all(&u32::to_le_bytes(response.len() as u32))?;
+        stream.write_all(response)?;
+        Ok(())
+    });
+    let mut client = KvsClient::options()
+        .retry(
+            RetryPolicy::default()
+                .max_attempts(20)
+                .backoff(Duration::from_millis(20), Duration::from_millis(100))
+                .retry_on(&[RetryOn::Connect, RetryOn::Closed]),
+        )
+        .connect(addr)?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    server.join().unwrap()?;
+
+    // Without retrying, the first failure is returned.
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        answer_hello(&mut stream)?;
+        read_request(&mut stream)?;
+        Ok(())
+    });
+    let mut client = KvsClient::options()
+        .retry(&RetryPolicy::never())
+        .connect(addr)?;
+    assert!(client.get("key".to_owned()).is_err());
+    server.join().unwrap()?;
+
+    Ok(())
+}
+
+// Should number the writes, and apply a write sent again with the same id only once.
+#[test]
+fn idempotent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    let first = client.set_numbered("k".to_owned(), "v".to_owned())?;
+    let second = client.remove_numbered("k".to_owned())?;
+    assert!(first.is_some());
+    assert!(second > first);
+    client.set("k".to_owned(), "v".to_owned())?;
+
+    // A removal sent twice with the same id is answered the same way both times.
+    let mut stream = TcpStream::connect(addr)?;
+    let request = br#"{"Remove":{"key":"k","id":{"client":"c","request":1}}}"#;
+    let mut responses = Vec::new();
+    for _ in 0..2 {
+        stream.write_all(&u32::to_le_bytes(request.len() as u32))?;
+        stream.write_all(request)?;
+        let response = read_request(&mut stream)?.expect("no response");
+        responses.push(String::from_utf8(response).unwrap());
+    }
+    assert!(responses[0].starts_with(r#"{"Written":"#));
+    assert_eq!(responses[0], responses[1]);
+
+    // An older one is refused.
+    let request = br#"{"Remove":{"key":"k","id":{"client":"c","request":0}}}"#;
+    stream.write_all(&u32::to_le_bytes(request.len() as u32))?;
+    stream.write_all(request)?;
+    let response = read_request(&mut stream)?.expect("no response");
+    assert!(String::from_utf8(response).unwrap().contains("Err"));
+    assert_eq!(client.get("k".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should switch to the codec the client asks for, if the server accepts it.
+#[test]
+fn codecs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut client = KvsClient::options()
+        .codec(Codec::MessagePack)
+        .connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        client.get_many(vec!["key1".to_owned(), "key2".to_owned()])?,
+        vec![Some("value1".to_owned()), None]
+    );
+    match client.remove("key2".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+    client.set_reader("big".to_owned(), &[7u8; 100_000][..], 100_000)?;
+    let mut value = Vec::new();
+    assert!(client.get_writer("big".to_owned(), &mut value)?);
+    assert_eq!(value, vec![7u8; 100_000]);
+    let responses = client
+        .pipeline()
+        .set("key2".to_owned(), "value2".to_owned())
+        .get("key2".to_owned())
+        .send()?;
+    assert_eq!(responses[1].as_ref().ok(), Some(&Some("value2".to_owned())));
+    assert_eq!(client.stats()?.keys, 3);
+
+    // The hello is answered in JSON, and the next response in MessagePack.
+    let mut stream = TcpStream::connect(addr)?;
+    for request in [
+        &br#"{"Hello":{"version":1,"codecs":["MessagePack","Json"]}}"#[..],
+        &b"\xa4Ping"[..],
+    ] {
+        stream.write_all(&u32::to_le_bytes(request.len() as u32))?;
+        stream.write_all(request)?;
+    }
+    let response = read_request(&mut stream)?.expect("no response");
+    assert!(response.starts_with(br#"{"Hello":{"version":4,"codec":"MessagePack","#));
+    let response = read_request(&mut stream)?.expect("no response");
+    assert_eq!(response, b"\x81\xa2Ok\xc0");
+
+    // A server that only accepts JSON keeps to it.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    )
+    .codecs(&[Codec::Json]);
+    thread::spawn(move || server.serve(listener));
+    let mut client = KvsClient::options()
+        .codec(Codec::MessagePack)
+        .connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should tell whether keys exist, over both protocols.
+#[test]
+fn client_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(client.exists("key1".to_owned())?);
+    assert!(!client.exists("key2".to_owned())?);
+    client.remove("key1".to_owned())?;
+    assert!(!client.exists("key1".to_owned())?);
+    drop(client);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server_with(&temp_dir, Protocol::Resp)?;
+    let mut stream = BufReader::new(TcpStream::connect(addr)?);
+    resp_command(&mut stream, b"*3\r\n$3\r\nSET\r\n$2\r\nk1\r\n$1\r\nv\r\n")?;
+    assert_eq!(
+        resp_command(
+            &mut stream,
+            b"*4\r\n$6\r\nEXISTS\r\n$2\r\nk1\r\n$2\r\nk2\r\n$2\r\nk1\r\n"
+        )?,
+        ":2\r\n"
+    );
+
+    // A server from before version 2 of the protocol isn't asked.
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        answer_hello(&mut stream)?;
+        assert!(read_request(&mut stream)?.is_none());
+        Ok(())
+    });
+    let mut client = KvsClient::connect(addr)?;
+    match client.exists("key1".to_owned()) {
+        Err(KvsError::UnsupportedProtocolVersion(1)) => {}
+        other => panic!("expected UnsupportedProtocolVersion, got {:?}", other),
+    }
+    drop(client);
+    server.join().unwrap()?;
+
+    Ok(())
+}
+
+// Should tell clients what it can do, and turn down those that speak another version.
+#[test]
+fn protocol_handshake() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .read_only(true);
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::options()
+        .codec(Codec::MessagePack)
+        .connect(addr)?;
+    let info = client.server_info()?;
+    assert_eq!(info.version, kvs::PROTOCOL_VERSION);
+    assert_eq!(info.codec, Codec::MessagePack);
+    assert_eq!(
+        info.capabilities,
+        Capabilities {
+            auth: false,
+            read_only: true,
+            compression: true,
+            replication: true,
+            raft: false,
+        }
+    );
+    assert_eq!(client.get("key".to_owned())?, None);
+
+    // A client of a later version is told so in JSON, and the connection closed.
+    let mut stream = TcpStream::connect(addr)?;
+    let request = br#"{"Hello":{"version":99,"codecs":["Json"],"later":true}}"#;
+    stream.write_all(&u32::to_le_bytes(request.len() as u32))?;
+    stream.write_all(request)?;
+    let response = read_request(&mut stream)?.expect("no response");
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.contains(r#""code":"UnsupportedProtocolVersion""#));
+    assert!(response.contains(r#""detail":"99""#));
+    assert!(read_request(&mut stream)?.is_none());
+
+    // Clients from before the hellos are still served.
+    let request = br#"{"Get":{"key":"key"}}"#;
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&u32::to_le_bytes(request.len() as u32))?;
+    stream.write_all(request)?;
+    assert_eq!(
+        read_request(&mut stream)?.expect("no response"),
+        br#"{"Ok":null}"#
+    );
+
+    Ok(())
+}
+
+// Should serve clients over TLS, and only those that trust the server's certificate.
+#[test]
+fn client_server_tls() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()]).unwrap();
+    let cert_path = temp_dir.path().join("cert.pem");
+    let key_path = temp_dir.path().join("key.pem");
+    fs::write(&cert_path, certified.cert.pem())?;
+    fs::write(&key_path, certified.key_pair.serialize_pem())?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server =
+        KvsServer::new(store, SharedQueueThreadPool::new(2)?).tls_files(&cert_path, &key_path)?;
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::options()
+        .tls_ca_file(&cert_path)?
+        .connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Enough requests and responses to fill the socket buffers both ways.
+    let value = "x".repeat(1000);
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline.set(format!("key{}", i), value.clone());
+        pipeline.get(format!("key{}", i));
+    }
+    let responses = pipeline.send()?;
+    assert_eq!(responses.len(), 2000);
+    for pair in responses.chunks(2) {
+        assert_eq!(pair[1].as_ref().ok(), Some(&Some(value.clone())));
+    }
+    drop(client);
+
+    // The certificate isn't issued to this name.
+    assert!(KvsClient::options()
+        .tls_ca_file(&cert_path)?
+        .tls_server_name("kvs.example.com")
+        .connect(addr)
+        .is_err());
+    // Nor does a plaintext client get anywhere.
+    let mut client = KvsClient::options()
+        .read_timeout(Some(Duration::from_secs(5)))
+        .connect(addr)?;
+    assert!(client.get("key1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Should only serve connections that authenticated, over both protocols.
+#[test]
+fn client_server_auth() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let auth_path = temp_dir.path().join("auth");
+    fs::write(&auth_path, "# clients\nshared-token\nalice:secret\n")?;
+    let auth = AuthConfig::from_file(&auth_path)?;
+    let store = KvStore::open(temp_dir.path())?;
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store.clone(), SharedQueueThreadPool::new(2)?).auth(auth.clone());
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.ping()?;
+    match client.get("key".to_owned()) {
+        Err(KvsError::AuthRequired) => {}
+        other => panic!("expected AuthRequired, got {:?}", other),
+    }
+    match client.auth(Credentials::user("alice", "wrong")) {
+        Err(KvsError::AuthFailed) => {}
+        other => panic!("expected AuthFailed, got {:?}", other),
+    }
+    match client.auth(Credentials::token("secret")) {
+        Err(KvsError::AuthFailed) => {}
+        other => panic!("expected AuthFailed, got {:?}", other),
+    }
+    client.auth(Credentials::token("shared-token"))?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    drop(client);
+
+    // Each connection authenticates on its own.
+    let mut client = KvsClient::options()
+        .auth(Credentials::user("alice", "secret"))
+        .connect(addr)?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    drop(client);
+    assert!(KvsClient::options()
+        .auth("alice:wrong".parse()?)
+        .connect(addr)
+        .is_err());
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store, SharedQueueThreadPool::new(2)?)
+        .protocol(Protocol::Resp)
+        .auth(auth);
+    thread::spawn(move || server.serve(listener));
+    let mut stream = BufReader::new(TcpStream::connect(addr)?);
+    assert_eq!(resp_command(&mut stream, b"PING\r\n")?, "+PONG\r\n");
+    assert!(resp_command(&mut stream, b"GET key\r\n")?.starts_with("-NOAUTH"));
+    assert!(resp_command(&mut stream, b"AUTH alice wrong\r\n")?.starts_with("-WRONGPASS"));
+    assert_eq!(
+        resp_command(&mut stream, b"AUTH alice secret\r\n")?,
+        "+OK\r\n"
+    );
+    assert_eq!(
+        resp_command(&mut stream, b"GET key\r\n")?,
+        "$5\r\nvalue\r\n"
+    );
+
+    Ok(())
+}
+
+// Should only let each user access the key prefixes the ACL grants them, over every protocol,
+// and follow the ACL as it is reloaded.
+#[test]
+fn client_server_acl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let acl_path = temp_dir.path().join("acl.toml");
+    fs::write(
+        &acl_path,
+        "[users.alice]\nread-write = [\"team-a/\"]\nread = [\"shared/\"]\n\n\
+         [users.admin]\nread-write = [\"\"]\n",
+    )?;
+    let acl = Acl::from_file(&acl_path)?;
+    let mut auth = AuthConfig::new();
+    auth.add(Credentials::user("alice", "secret"))
+        .add(Credentials::user("admin", "secret"))
+        .add(Credentials::token("shared-token"))
+        .acl(acl.clone());
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("shared/motd".to_owned(), "hello".to_owned())?;
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let http_listener = TcpListener::bind("127.0.0.1:0")?;
+    let http_addr = http_listener.local_addr()?;
+    let server = KvsServer::new(store.clone(), SharedQueueThreadPool::new(4)?)
+        .auth(auth.clone())
+        .http(http_listener);
+    thread::spawn(move || server.serve(listener));
+
+    let mut alice = KvsClient::options()
+        .auth(Credentials::user("alice", "secret"))
+        .connect(addr)?;
+    alice.set("team-a/x".to_owned(), "1".to_owned())?;
+    assert_eq!(
+        alice.get("shared/motd".to_owned())?,
+        Some("hello".to_owned())
+    );
+    match alice.set("shared/motd".to_owned(), "bye".to_owned()) {
+        Err(KvsError::Forbidden(key)) => assert_eq!(key, "shared/motd"),
+        other => panic!("expected Forbidden, got {:?}", other),
+    }
+    assert!(matches!(
+        alice.get("team-b/y".to_owned()),
+        Err(KvsError::Forbidden(_))
+    ));
+    assert!(matches!(
+        alice.get_many(vec!["team-a/x".to_owned(), "team-b/y".to_owned()]),
+        Err(KvsError::Forbidden(_))
+    ));
+    let multiplexed = KvsClient::options()
+        .auth(Credentials::user("alice", "secret"))
+        .multiplexed(addr)?;
+    assert_eq!(
+        multiplexed.get("team-a/x".to_owned())?,
+        Some("1".to_owned())
+    );
+    assert!(matches!(
+        multiplexed.remove("team-b/y".to_owned()),
+        Err(KvsError::Forbidden(_))
+    ));
+    // A shared token has no user to be granted anything.
+    let mut client = KvsClient::options()
+        .auth(Credentials::token("shared-token"))
+        .connect(addr)?;
+    assert!(matches!(
+        client.get("shared/motd".to_owned()),
+        Err(KvsError::Forbidden(_))
+    ));
+    let mut admin = KvsClient::options()
+        .auth(Credentials::user("admin", "secret"))
+        .connect(addr)?;
+    admin.set("team-b/y".to_owned(), "2".to_owned())?;
+
+    // The REST gateway turns down the same requests, and only lists the keys the user may read.
+    let mut stream = BufReader::new(TcpStream::connect(http_addr)?);
+    let header = "Authorization: Bearer alice:secret\r\n";
+    let (status, body) = http_request(&mut stream, "PUT /keys/team-b%2Fy", header, "3")?;
+    assert_eq!(status, 403);
+    let error: serde_json::Value = serde_json::from_str(&body)?;
+    assert_eq!(error["error"]["code"], "Forbidden");
+    let (status, body) = http_request(&mut stream, "GET /keys", header, "")?;
+    assert_eq!(
+        (status, body.as_str()),
+        (200, r#"["shared/motd","team-a/x"]"#)
+    );
+
+    // So does RESP.
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let resp_addr = listener.local_addr()?;
+    let server = KvsServer::new(store, SharedQueueThreadPool::new(2)?)
+        .protocol(Protocol::Resp)
+        .auth(auth);
+    thread::spawn(move || server.serve(listener));
+    let mut stream = BufReader::new(TcpStream::connect(resp_addr)?);
+    resp_command(&mut stream, b"AUTH alice secret\r\n")?;
+    assert_eq!(
+        resp_command(&mut stream, b"GET team-a/x\r\n")?,
+        "$1\r\n1\r\n"
+    );
+    assert!(resp_command(&mut stream, b"DEL team-a/x shared/motd\r\n")?.starts_with("-NOPERM"));
+    assert!(resp_command(&mut stream, b"SET team-b/y 3\r\n")?.starts_with("-NOPERM"));
+
+    // A reloaded ACL applies to the connections already open.
+    fs::write(&acl_path, "[users.alice]\nread = [\"team-b/\"]\n")?;
+    acl.reload(&acl_path)?;
+    assert_eq!(alice.get("team-b/y".to_owned())?, Some("2".to_owned()));
+    assert!(matches!(
+        alice.get("team-a/x".to_owned()),
+        Err(KvsError::Forbidden(_))
+    ));
+    // A file that can't be read leaves the grants as they are.
+    fs::write(&acl_path, "[users.alice]\nwrite = [\"\"]\n")?;
+    assert!(acl.reload(&acl_path).is_err());
+    assert_eq!(alice.get("team-b/y".to_owned())?, Some("2".to_owned()));
+
+    Ok(())
+}
+
+// Should copy the store of the leader, follow its writes, and catch up after a broken connection.
+#[test]
+fn replication() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader = KvStore::open(leader_dir.path())?;
+    leader.set("key1".to_owned(), "value1".to_owned())?;
+    leader.set("key2".to_owned(), "value2".to_owned())?;
+    let follower = KvStore::open(follower_dir.path())?;
+    follower.set("stale".to_owned(), "value".to_owned())?;
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let leader_addr = listener.local_addr()?;
+    let server = KvsServer::new(leader.clone(), SharedQueueThreadPool::new(4)?);
+    thread::spawn(move || server.serve(listener));
+    let (proxy_addr, connections) = spawn_proxy(leader_addr)?;
+
+    let replica = Replica::start(follower.clone(), proxy_addr, &KvsClient::options())?;
+    wait_for(|| replica.is_connected());
+    assert_eq!(follower.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(follower.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(follower.get("stale".to_owned())?, None);
+
+    let mut client = KvsClient::connect(leader_addr)?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    client.remove("key1".to_owned())?;
+    wait_for(|| follower.get("key1".to_owned()).unwrap().is_none());
+    assert_eq!(follower.get("key3".to_owned())?, Some("value3".to_owned()));
+    let offset = replica.offset();
+    assert!(offset >= 2);
+
+    // Writes made while the follower is away are sent once it connects again.
+    for connection in connections.lock().unwrap().drain(..) {
+        connection.shutdown(Shutdown::Both)?;
+    }
+    wait_for(|| !replica.is_connected());
+    client.set("key4".to_owned(), "value4".to_owned())?;
+    wait_for(|| replica.is_connected() && replica.offset() > offset);
+    wait_for(|| follower.get("key4".to_owned()).unwrap().is_some());
+    drop(replica);
+
+    // A read-only follower refuses writes.
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(follower, SharedQueueThreadPool::new(2)?).read_only(true);
+    thread::spawn(move || server.serve(listener));
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert!(matches!(
+        client.set("key5".to_owned(), "value5".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+
+    Ok(())
+}
+
+// Should close a connection whose message claims more bytes than it sends, without allocating
+// them up front, and keep serving the others.
+#[test]
+fn truncated_message() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&u32::MAX.to_le_bytes())?;
+    stream.write_all(b"{\"Get\"")?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert!(response.is_empty());
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should send errors over the network with their code and context.
+#[test]
+fn remote_errors() -> Result<()> {
+    let err = KvsError::InFile {
+        path: "db/3.log".into(),
+        offset: Some(42),
+        source: Box::new(KvsError::ChecksumMismatch),
+    };
+    let remote: RemoteError = serde_json::from_str(&serde_json::to_string(&err.to_remote())?)?;
+    let rebuilt = KvsError::from(remote);
+    assert_eq!(rebuilt.code(), ErrorCode::ChecksumMismatch);
+    assert_eq!(
+        rebuilt.to_string(),
+        "db/3.log at offset 42: checksum mismatch"
+    );
+    assert!(std::error::Error::source(&rebuilt).is_some());
+
+    let corrupt = KvsError::InFile {
+        path: "db/1.log".into(),
+        offset: None,
+        source: Box::new(KvsError::Corruption {
+            key: "key".to_owned(),
+            offset: 17,
+        }),
+    };
+    let rebuilt = KvsError::from(corrupt.to_remote());
+    assert!(matches!(
+        rebuilt.root(),
+        KvsError::Corruption { key, offset: 17 } if key == "key"
+    ));
+    assert_eq!(rebuilt.to_string(), corrupt.to_string());
+
+    let leader = KvsError::from(KvsError::NotLeader(Some("127.0.0.1:4000".to_owned())).to_remote());
+    assert!(matches!(leader, KvsError::NotLeader(Some(ref addr)) if addr == "127.0.0.1:4000"));
+
+    // Errors wrapping those of other crates keep their code and message.
+    let io = KvsError::from(std::io::Error::other("disk on fire"));
+    let remote = KvsError::from(io.to_remote());
+    assert_eq!(remote.code(), ErrorCode::Io);
+    assert_eq!(remote.to_string(), io.to_string());
+
+    Ok(())
+}
+
+// Should stop accepting connections, close the idle ones and release the store on shutdown.
+#[test]
+fn graceful_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store, SharedQueueThreadPool::new(2)?);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::options().reconnect(false).connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    // connected, but with no request on the way
+    let mut idle = TcpStream::connect(addr)?;
+
+    assert!(!shutdown.is_shutdown());
+    shutdown.shutdown();
+    assert!(shutdown.is_shutdown());
+    handle.join().unwrap()?;
+
+    // The idle connection was closed, and no new one is accepted.
+    idle.set_read_timeout(Some(Duration::from_secs(5)))?;
+    assert_eq!(idle.read(&mut [0; 1])?, 0);
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(TcpStream::connect(addr).is_err());
+
+    // The store was released, with the write in it.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // A server asked to shut down before it serves stops at once.
+    let server = KvsServer::new(store, SharedQueueThreadPool::new(1)?);
+    server.shutdown_handle().shutdown();
+    server.serve(TcpListener::bind("127.0.0.1:0")?)?;
+
+    Ok(())
+}
+
+// Send an HTTP GET request for `path`, and return the response.
+fn http_get(addr: SocketAddr, path: &str) -> Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+// Should serve the metrics of the server and its engine for Prometheus, until it shuts down.
+#[test]
+fn metrics_endpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let metrics_listener = TcpListener::bind("127.0.0.1:0")?;
+    let metrics_addr = metrics_listener.local_addr()?;
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .metrics(metrics_listener);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let response = http_get(metrics_addr, "/metrics?name=kvs")?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+    for line in &[
+        "kvs_requests_total{command=\"set\"} 2",
+        "kvs_requests_total{command=\"get\"} 1",
+        "kvs_request_errors_total{command=\"set\"} 0",
+        "kvs_request_duration_seconds_count{command=\"set\"} 2",
+        "kvs_connections_total 1",
+        "kvs_connections_open 1",
+        "kvs_keys 2",
+        "kvs_compactions_total 0",
+        "kvs_operation_duration_seconds_count{op=\"get\"} 1",
+    ] {
+        assert!(response.contains(&format!("\n{}\n", line)), "{}", line);
+    }
+    assert!(http_get(metrics_addr, "/")?.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    let mut stream = TcpStream::connect(metrics_addr)?;
+    stream.write_all(b"POST /metrics HTTP/1.1\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    // A request that never ends its head is cut off.
+    let mut stream = TcpStream::connect(metrics_addr)?;
+    let mut head = b"GET /metrics HTTP/1.1\r\nX-Padding: ".to_vec();
+    head.resize(16 * 1024, b'a');
+    stream.write_all(&head)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(
+        response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+        "{}",
+        response
+    );
+
+    // The endpoint goes away with the server.
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    assert!(TcpStream::connect(metrics_addr).is_err());
+
+    Ok(())
+}
+
+// Encode a message of Protocol Buffers made of string fields.
+fn proto(fields: &[(u8, &str)]) -> Vec<u8> {
+    let mut message = Vec::new();
+    for &(field, value) in fields {
+        message.push(field << 3 | 2);
+        let mut len = value.len();
+        while len >= 0x80 {
+            message.push(0x80 | (len & 0x7f) as u8);
+            len >>= 7;
+        }
+        message.push(len as u8);
+        message.extend_from_slice(value.as_bytes());
+    }
+    message
+}
+
+// Read an HTTP/2 frame: its type, flags, stream and payload.
+fn read_frame(stream: &mut TcpStream) -> Result<(u8, u8, u32, Vec<u8>)> {
+    let mut header = [0; 9];
+    stream.read_exact(&mut header)?;
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    let id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    Ok((header[3], header[4], id, payload))
+}
+
+fn write_frame(stream: &mut TcpStream, kind: u8, flags: u8, id: u32, payload: &[u8]) -> Result<()> {
+    stream.write_all(&(payload.len() as u32).to_be_bytes()[1..])?;
+    stream.write_all(&[kind, flags])?;
+    stream.write_all(&id.to_be_bytes())?;
+    stream.write_all(payload)?;
+    Ok(())
+}
+
+// Connect to a gRPC server over HTTP/2 in cleartext.
+fn grpc_connect(addr: SocketAddr) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    stream.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n")?;
+    write_frame(&mut stream, 4, 0, 0, &[])?;
+    Ok(stream)
+}
+
+// Call a method of the service on stream `id`, with headers that are literals not added to the
+// table of HPACK.
+fn grpc_send(stream: &mut TcpStream, id: u32, method: &str, message: &[u8]) -> Result<()> {
+    let path = format!("/kvs.v1.Kvs/{}", method);
+    let mut block = Vec::new();
+    for (name, value) in &[
+        (":method", "POST"),
+        (":scheme", "http"),
+        (":path", path.as_str()),
+        (":authority", "localhost"),
+        ("content-type", "application/grpc"),
+        ("te", "trailers"),
+    ] {
+        block.push(0);
+        block.push(name.len() as u8);
+        block.extend_from_slice(name.as_bytes());
+        block.push(value.len() as u8);
+        block.extend_from_slice(value.as_bytes());
+    }
+    write_frame(stream, 1, 0x4, id, &block)?;
+    let mut body = vec![0];
+    body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    body.extend_from_slice(message);
+    write_frame(stream, 0, 0x1, id, &body)
+}
+
+// Decode the headers the server sends, which only name those of the static table of HPACK by
+// their index, and never use the Huffman code.
+fn grpc_headers(mut block: &[u8], headers: &mut Headers) {
+    let string = |block: &mut &[u8]| {
+        let len = block[0] as usize;
+        let s = String::from_utf8(block[1..1 + len].to_vec()).unwrap();
+        *block = &block[1 + len..];
+        s
+    };
+    while !block.is_empty() {
+        if block[0] == 0x88 {
+            headers.push((":status".to_owned(), "200".to_owned()));
+            block = &block[1..];
+            continue;
+        }
+        let name = match block[0] {
+            0x00 => {
+                block = &block[1..];
+                string(&mut block)
+            }
+            0x0f if block[1] == 0x10 => {
+                block = &block[2..];
+                "content-type".to_owned()
+            }
+            _ => panic!("unexpected header representation {:#x}", block[0]),
+        };
+        let value = string(&mut block);
+        headers.push((name, value));
+    }
+}
+
+type Headers = Vec<(String, String)>;
+
+// Read the response to the call on stream `id`: its headers and trailers, and its messages.
+fn grpc_response(stream: &mut TcpStream, id: u32) -> Result<(Headers, Vec<Vec<u8>>)> {
+    let mut headers = Vec::new();
+    let mut body = Vec::new();
+    loop {
+        let (kind, flags, stream_id, payload) = read_frame(stream)?;
+        if stream_id != id {
+            continue;
+        }
+        match kind {
+            0 => body.extend_from_slice(&payload),
+            1 => grpc_headers(&payload, &mut headers),
+            kind => panic!("unexpected frame type {}", kind),
+        }
+        if flags & 0x1 != 0 {
+            break;
+        }
+    }
+    let mut messages = Vec::new();
+    let mut rest = &body[..];
+    while !rest.is_empty() {
+        let len = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+        messages.push(rest[5..5 + len].to_vec());
+        rest = &rest[5 + len..];
+    }
+    Ok((headers, messages))
+}
+
+fn grpc_status(headers: &[(String, String)]) -> &str {
+    headers
+        .iter()
+        .find(|(name, _)| name == "grpc-status")
+        .map(|(_, value)| value.as_str())
+        .expect("no grpc-status")
+}
+
+// Should serve the gRPC service of proto/kvs.proto over HTTP/2, next to the native protocol.
+#[test]
+fn grpc_service() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let grpc_listener = TcpListener::bind("127.0.0.1:0")?;
+    let grpc_addr = grpc_listener.local_addr()?;
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .grpc(grpc_listener);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.serve(listener));
+
+    let mut stream = grpc_connect(grpc_addr)?;
+    grpc_send(
+        &mut stream,
+        1,
+        "Set",
+        &proto(&[(1, "user:1"), (2, "alice")]),
+    )?;
+    let (headers, messages) = grpc_response(&mut stream, 1)?;
+    assert_eq!(headers[0], (":status".to_owned(), "200".to_owned()));
+    assert_eq!(grpc_status(&headers), "0");
+    assert_eq!(messages, vec![Vec::<u8>::new()]);
+    grpc_send(&mut stream, 3, "Get", &proto(&[(1, "user:1")]))?;
+    let (headers, messages) = grpc_response(&mut stream, 3)?;
+    assert_eq!(grpc_status(&headers), "0");
+    assert_eq!(messages, vec![proto(&[(1, "alice")])]);
+    // The value of a missing key is left unset.
+    grpc_send(&mut stream, 5, "Get", &proto(&[(1, "user:9")]))?;
+    let (headers, messages) = grpc_response(&mut stream, 5)?;
+    assert_eq!(grpc_status(&headers), "0");
+    assert_eq!(messages, vec![Vec::<u8>::new()]);
+    grpc_send(&mut stream, 7, "Remove", &proto(&[(1, "user:9")]))?;
+    let (headers, messages) = grpc_response(&mut stream, 7)?;
+    assert_eq!(grpc_status(&headers), "5");
+    assert!(messages.is_empty());
+
+    // Writes through the native protocol are seen too.
+    let mut client = KvsClient::connect(addr)?;
+    client.set("user:2".to_owned(), "bob".to_owned())?;
+    client.set("other".to_owned(), "value".to_owned())?;
+    grpc_send(&mut stream, 9, "Scan", &proto(&[(1, "user:")]))?;
+    let (headers, messages) = grpc_response(&mut stream, 9)?;
+    assert_eq!(grpc_status(&headers), "0");
+    assert_eq!(
+        messages,
+        vec![
+            proto(&[(1, "user:1"), (2, "alice")]),
+            proto(&[(1, "user:2"), (2, "bob")])
+        ]
+    );
+    grpc_send(&mut stream, 11, "Nope", &[])?;
+    let (headers, _) = grpc_response(&mut stream, 11)?;
+    assert_eq!(grpc_status(&headers), "12");
+
+    // A watch streams the changes until it is cancelled.
+    grpc_send(&mut stream, 13, "Watch", &proto(&[(1, "user:")]))?;
+    loop {
+        let (kind, _, id, _) = read_frame(&mut stream)?;
+        if kind == 1 && id == 13 {
+            break;
+        }
+    }
+    client.set("other".to_owned(), "ignored".to_owned())?;
+    client.set("user:3".to_owned(), "carol".to_owned())?;
+    let change = loop {
+        let (kind, _, id, payload) = read_frame(&mut stream)?;
+        if kind == 0 && id == 13 {
+            break payload;
+        }
+    };
+    assert_eq!(&change[5..], &proto(&[(1, "user:3"), (4, "carol")])[..]);
+    write_frame(&mut stream, 3, 0, 13, &8u32.to_be_bytes())?;
+    grpc_send(&mut stream, 15, "Get", &proto(&[(1, "user:3")]))?;
+    let (_, messages) = grpc_response(&mut stream, 15)?;
+    assert_eq!(messages, vec![proto(&[(1, "carol")])]);
+
+    // The service goes away with the server, which closes its connections.
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    assert!(TcpStream::connect(grpc_addr).is_err());
+
+    Ok(())
+}
+
+// Send an HTTP request on a connection kept alive, and return the status and body of the response.
+fn http_request(
+    stream: &mut BufReader<TcpStream>,
+    request_line: &str,
+    headers: &str,
+    body: &str,
+) -> Result<(u16, String)> {
+    write!(
+        stream.get_mut(),
+        "{} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}",
+        request_line,
+        headers,
+        body.len(),
+        body
+    )?;
+    let mut status_line = String::new();
+    stream.read_line(&mut status_line)?;
+    let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+    let mut len = 0;
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line)?;
+        if line == "\r\n" {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length: ") {
+            len = value.trim().parse().unwrap();
+        }
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body)?;
+    Ok((status, String::from_utf8(body).unwrap()))
+}
+
+// Should serve the REST gateway, with errors in JSON, until the server shuts down.
+#[test]
+fn http_gateway() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let auth_path = temp_dir.path().join("auth");
+    fs::write(&auth_path, "alice:secret\n")?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let http_listener = TcpListener::bind("127.0.0.1:0")?;
+    let http_addr = http_listener.local_addr()?;
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .auth(AuthConfig::from_file(&auth_path)?)
+    .http(http_listener);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.serve(listener));
+
+    let mut stream = BufReader::new(TcpStream::connect(http_addr)?);
+    let (status, body) = http_request(&mut stream, "GET /keys/user%3A1", "", "")?;
+    assert_eq!(status, 401);
+    let error: serde_json::Value = serde_json::from_str(&body)?;
+    assert_eq!(error["error"]["code"], "AuthRequired");
+    let (status, _) = http_request(
+        &mut stream,
+        "GET /keys/user%3A1",
+        "Authorization: Bearer alice:wrong\r\n",
+        "",
+    )?;
+    assert_eq!(status, 401);
+
+    let auth = "Authorization: Bearer alice:secret\r\n";
+    let (status, body) = http_request(&mut stream, "PUT /keys/user%3A1", auth, "alice")?;
+    assert_eq!((status, body.as_str()), (204, ""));
+    let (status, body) = http_request(&mut stream, "GET /keys/user:1", auth, "")?;
+    assert_eq!((status, body.as_str()), (200, "alice"));
+    let (status, body) = http_request(&mut stream, "GET /keys/user%3A9", auth, "")?;
+    assert_eq!(status, 404);
+    let error: serde_json::Value = serde_json::from_str(&body)?;
+    assert_eq!(error["error"]["code"], "KeyNotFound");
+    assert_eq!(error["error"]["message"], "key not found");
+    let (status, _) = http_request(&mut stream, "DELETE /keys/user%3A9", auth, "")?;
+    assert_eq!(status, 404);
+
+    // Writes through the native protocol are listed too.
+    let mut client = KvsClient::options()
+        .auth(Credentials::user("alice", "secret"))
+        .connect(addr)?;
+    client.set("user:2".to_owned(), "bob".to_owned())?;
+    client.set("other".to_owned(), "value".to_owned())?;
+    let (status, body) = http_request(&mut stream, "GET /keys?prefix=user%3A", auth, "")?;
+    assert_eq!((status, body.as_str()), (200, r#"["user:1","user:2"]"#));
+    let (status, body) = http_request(&mut stream, "GET /keys", auth, "")?;
+    assert_eq!(
+        (status, body.as_str()),
+        (200, r#"["other","user:1","user:2"]"#)
+    );
+    let (status, _) = http_request(&mut stream, "DELETE /keys/user%3A1", auth, "")?;
+    assert_eq!(status, 204);
+    assert_eq!(client.get("user:1".to_owned())?, None);
+
+    let (status, _) = http_request(&mut stream, "POST /keys/user%3A1", auth, "")?;
+    assert_eq!(status, 405);
+    let (status, _) = http_request(&mut stream, "GET /values", auth, "")?;
+    assert_eq!(status, 404);
+
+    // The gateway goes away with the server, which closes its connections.
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    assert_eq!(stream.read(&mut [0; 1])?, 0);
+    assert!(TcpStream::connect(http_addr).is_err());
+
+    Ok(())
+}
+
+// Should turn down connections over the limit, and close those that stall.
+#[test]
+fn connection_limits_and_timeouts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .max_connections(Some(1))
+    .idle_timeout(Some(Duration::from_millis(200)));
+    thread::spawn(move || server.serve(listener));
+
+    let mut idle = TcpStream::connect(addr)?;
+    idle.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut client = KvsClient::options().reconnect(false).connect(addr)?;
+    match client.get("key1".to_owned()) {
+        Err(KvsError::ServerError(message)) => assert_eq!(message, "too many connections"),
+        other => panic!("expected ServerError, got {:?}", other),
+    }
+    // The idle connection is closed, which makes room for another.
+    assert_eq!(idle.read(&mut [0; 1])?, 0);
+    wait_for(|| {
+        KvsClient::connect(addr)
+            .and_then(|mut client| client.set("key1".to_owned(), "value1".to_owned()))
+            .is_ok()
+    });
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .read_timeout(Some(Duration::from_millis(200)));
+    thread::spawn(move || server.serve(listener));
+
+    // A request that stops halfway is given up on.
+    let mut stalled = TcpStream::connect(addr)?;
+    stalled.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stalled.write_all(&u32::to_le_bytes(100))?;
+    stalled.write_all(b"{\"Get\"")?;
+    assert_eq!(stalled.read(&mut [0; 1])?, 0);
+    // Idle connections are left alone.
+    let mut client = KvsClient::connect(addr)?;
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// `kvs-server` should shut down and exit successfully on SIGTERM.
+#[cfg(unix)]
+#[test]
+fn cli_server_sigterm() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr.to_string()])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::null())
+        .spawn()?;
+    wait_for(|| TcpStream::connect(addr).is_ok());
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value1".to_owned())?;
+
+    let killed = Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()?;
+    assert!(killed.success());
+    assert!(server.wait()?.success());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `kvs-server` should take its settings from a config file, and reload it on SIGHUP.
+#[cfg(unix)]
+#[test]
+fn cli_server_config_reload() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config,
+        format!("addr = \"{}\"\ncompaction-threshold = 1048576\n", addr),
+    )?;
+    let server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let signal = |name: &str| -> Result<()> {
+        let status = Command::new("kill")
+            .args([name, &server.id().to_string()])
+            .status()?;
+        assert!(status.success());
+        Ok(())
+    };
+    wait_for(|| TcpStream::connect(addr).is_ok());
+
+    fs::write(
+        &config,
+        format!("addr = \"{}\"\nlog-level = \"warn\"\n", addr),
+    )?;
+    signal("-HUP")?;
+    thread::sleep(Duration::from_millis(200));
+    // still serving
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value1".to_owned())?;
+    fs::write(&config, "compaction-threshold = \"lots\"\n")?;
+    signal("-HUP")?;
+    thread::sleep(Duration::from_millis(200));
+    signal("-TERM")?;
+
+    let output = server.wait_with_output()?;
+    assert!(output.status.success());
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("Reloaded the configuration"), "{}", log);
+    assert!(log.contains("Keeping the current configuration"), "{}", log);
+    // Info messages are no longer logged once the level is warn.
+    assert!(!log.contains("Shutting down"), "{}", log);
+
+    Ok(())
+}
+
+// `kvs-server --slow-log` should log the requests, and the reads and writes of the store, that
+// take longer than the threshold, with their key and where the time went.
+#[cfg(unix)]
+#[test]
+fn cli_server_slow_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr.to_string(), "--slow-log", "0.000000001"])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()?;
+    wait_for(|| TcpStream::connect(addr).is_ok());
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+    let status = Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()?;
+    assert!(status.success());
+
+    let output = server.wait_with_output()?;
+    assert!(output.status.success());
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("Slow set of key 'key1' from"), "{}", log);
+    assert!(log.contains("reading the request"), "{}", log);
+    assert!(log.contains("Slow set of key 'key1' (6 bytes)"), "{}", log);
+    assert!(log.contains("waiting for the writer"), "{}", log);
+    assert!(log.contains("Slow get of key 'key1' (6 bytes)"), "{}", log);
+
+    Ok(())
+}
+
+// Should log every request with its trace id, and answer traced requests with their trace id.
+#[test]
+fn cli_server_access_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let http_addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr.to_string(), "--access-log", "hashed-keys"])
+        .args(["--http-addr", &http_addr.to_string()])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()?;
+    wait_for(|| TcpStream::connect(addr).is_ok() && TcpStream::connect(http_addr).is_ok());
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.last_trace_id(), None);
+    let value = client.trace("checkout-1").get("key1".to_owned())?;
+    assert_eq!(value, Some("value1".to_owned()));
+    assert_eq!(client.last_trace_id(), Some("checkout-1"));
+    assert!(matches!(
+        client.trace("checkout-2").remove("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(client.last_trace_id(), Some("checkout-2"));
+    client.ping()?;
+    assert_eq!(client.last_trace_id(), None);
+    drop(client);
+
+    let mut stream = TcpStream::connect(http_addr)?;
+    stream.write_all(
+        b"GET /keys/key1 HTTP/1.1\r\nHost: localhost\r\nX-Request-Id: checkout-3\r\n\
+          Connection: close\r\n\r\n",
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(
+        response.contains("X-Request-Id: checkout-3\r\n"),
+        "{}",
+        response
+    );
+
+    let status = Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()?;
+    assert!(status.success());
+    let output = server.wait_with_output()?;
+    assert!(output.status.success());
+    let log = String::from_utf8_lossy(&output.stderr);
+    // the lines aren't in order, as each is logged once its request is answered
+    let lines: Vec<_> = log
+        .lines()
+        .filter(|line| line.contains("kvs::access"))
+        .collect();
+    assert_eq!(lines.len(), 6, "{}", log);
+    let logged = |expected: &str| lines.iter().any(|line| line.contains(expected));
+    // the first 8 bytes of the SHA-256 digest of "key1"
+    let key1 = "key=sha256:8174099687a26621";
+    assert!(logged("command=other outcome=ok"), "{}", log);
+    assert!(
+        logged(&format!("command=set {} outcome=ok latency_us=", key1)),
+        "{}",
+        log
+    );
+    assert!(
+        logged(&format!(
+            "protocol=native trace_id=\"checkout-1\" command=get {} outcome=ok",
+            key1
+        )),
+        "{}",
+        log
+    );
+    assert!(
+        logged("trace_id=\"checkout-2\" command=remove key=sha256:"),
+        "{}",
+        log
+    );
+    assert!(logged("outcome=not_found"), "{}", log);
+    assert!(logged("command=ping outcome=ok"), "{}", log);
+    assert!(
+        logged(&format!(
+            "protocol=http trace_id=\"checkout-3\" command=get {} outcome=ok",
+            key1
+        )),
+        "{}",
+        log
+    );
+    assert!(!log.contains("key1\""), "{}", log);
+
+    Ok(())
+}
+
+// Forward connections to `target`, keeping the sockets so that the test can break them.
+fn spawn_proxy(target: SocketAddr) -> Result<(SocketAddr, Arc<Mutex<Vec<TcpStream>>>)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let connections = Arc::new(Mutex::new(Vec::new()));
+    let proxy_connections = Arc::clone(&connections);
+    thread::spawn(move || -> Result<()> {
+        for downstream in listener.incoming() {
+            let mut downstream = downstream?;
+            let mut upstream = TcpStream::connect(target)?;
+            let (mut down, mut up) = (downstream.try_clone()?, upstream.try_clone()?);
+            proxy_connections
+                .lock()
+                .unwrap()
+                .push(downstream.try_clone()?);
+            thread::spawn(move || std::io::copy(&mut down, &mut up));
+            thread::spawn(move || std::io::copy(&mut upstream, &mut downstream));
+        }
+        Ok(())
+    });
+    Ok((addr, connections))
+}
+
+// Wait until `condition` holds, for up to 10 seconds.
+fn wait_for(condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// Answer the hello `KvsClient` sends before its first request.
+fn answer_hello(stream: &mut TcpStream) -> Result<()> {
+    read_request(stream)?;
+    let response = br#"{"Hello":{"version":1,"codec":"Json","capabilities":{}}}"#;
+    stream.write_all(&u32::to_le_bytes(response.len() as u32))?;
+    stream.write_all(response)?;
+    Ok(())
+}
+
+// Read a request sent by `KvsClient`, or `None` if it closed the connection.
+fn read_request(stream: &mut TcpStream) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    if stream.read_exact(&mut len).is_err() {
+        return Ok(None);
+    }
+    let mut request = vec![0; u32::from_le_bytes(len) as usize];
+    stream.read_exact(&mut request)?;
+    Ok(Some(request))
+}
+
+// Send a RESP command and return the raw reply.
+fn resp_command(stream: &mut BufReader<TcpStream>, command: &[u8]) -> Result<String> {
+    stream.get_mut().write_all(command)?;
+    let mut reply = String::new();
+    stream.read_line(&mut reply)?;
+    if reply.starts_with('$') && reply != "$-1\r\n" {
+        let len: usize = reply[1..reply.len() - 2].parse().unwrap();
+        let mut data = vec![0; len + 2];
+        stream.read_exact(&mut data)?;
+        reply.push_str(&String::from_utf8(data).unwrap());
+    }
+    Ok(reply)
+}
+
+// Should serve GET/SET/DEL/EXISTS/PING over the Redis protocol.
+#[test]
+fn resp_protocol() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server_with(&temp_dir, Protocol::Resp)?;
+    let mut stream = BufReader::new(TcpStream::connect(addr)?);
+
+    assert_eq!(
+        resp_command(&mut stream, b"*1\r\n$4\r\nPING\r\n")?,
+        "+PONG\r\n"
+    );
+    // An empty multibulk command is skipped, as Redis does.
+    assert_eq!(
+        resp_command(&mut stream, b"*0\r\n*1\r\n$4\r\nPING\r\n")?,
+        "+PONG\r\n"
+    );
+    assert_eq!(
+        resp_command(
+            &mut stream,
+            b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nval\r\n1\r\n"
+        )?,
+        "+OK\r\n"
+    );
+    assert_eq!(
+        resp_command(&mut stream, b"*2\r\n$3\r\nget\r\n$4\r\nkey1\r\n")?,
+        "$6\r\nval\r\n1\r\n"
+    );
+    assert_eq!(
+        resp_command(&mut stream, b"*2\r\n$3\r\nGET\r\n$4\r\nkey2\r\n")?,
+        "$-1\r\n"
+    );
+    // Inline commands, as typed into telnet.
+    assert_eq!(
+        resp_command(&mut stream, b"SET key2 value2\r\n")?,
+        "+OK\r\n"
+    );
+    assert_eq!(
+        resp_command(&mut stream, b"EXISTS key1 key2 key3\r\n")?,
+        ":2\r\n"
+    );
+    assert_eq!(resp_command(&mut stream, b"DEL key1 key3\r\n")?, ":1\r\n");
+    assert_eq!(resp_command(&mut stream, b"EXISTS key1\r\n")?, ":0\r\n");
+    assert_eq!(
+        resp_command(&mut stream, b"PING hello\r\n")?,
+        "$5\r\nhello\r\n"
+    );
+    let stats = resp_command(&mut stream, b"STATS\r\n")?;
+    assert!(stats.contains("\r\nkeys:1\nlive_bytes:"), "{}", stats);
+    assert!(stats.contains("\nget_p99_us:"), "{}", stats);
+    let metrics = resp_command(&mut stream, b"METRICS\r\n")?;
+    assert!(metrics.contains("\nkvs_keys 1\n"), "{}", metrics);
+    assert!(
+        metrics.contains("\nkvs_operation_duration_seconds_count{op=\"set\"} 2\n"),
+        "{}",
+        metrics
+    );
+    assert!(resp_command(&mut stream, b"GET\r\n")?.starts_with("-ERR wrong number"));
+    assert!(resp_command(&mut stream, b"FLUSHALL\r\n")?.starts_with("-ERR unknown command"));
+
+    Ok(())
+}
+
+// Should turn down lines without an end, and not take the lengths a client announces for the
+// memory to set aside.
+#[test]
+fn resp_protocol_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server_with(&temp_dir, Protocol::Resp)?;
+
+    let mut stream = BufReader::new(TcpStream::connect(addr)?);
+    assert_eq!(
+        resp_command(&mut stream, &vec![b'a'; 64 * 1024 + 1])?,
+        "-ERR Protocol error: too big inline request\r\n"
+    );
+
+    // A connection that announces the largest bulk string, and sends little of it.
+    let mut stalled = TcpStream::connect(addr)?;
+    stalled.write_all(b"*1048576\r\n$536870912\r\nabc")?;
+    let mut stream = BufReader::new(TcpStream::connect(addr)?);
+    assert_eq!(
+        resp_command(&mut stream, b"*1\r\n$4\r\nPING\r\n")?,
+        "+PONG\r\n"
+    );
+    drop(stalled);
+
+    Ok(())
+}
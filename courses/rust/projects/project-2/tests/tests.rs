@@ -1,8 +1,21 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{
+    detect_engine, open_engine, Backpressure, ChangeEvent, ChangeOp, ConfigFile, Databases,
+    DeadRatio, DumpFormat, Engine, ErrorCode, EvictionPolicy, FormatVersion, KvStore, KvsEngine,
+    KvsError, Latency, ManualOnly, Operation, Result, SegmentStorage, SegmentUsage, ShardedKvStore,
+    SizeTiered, SledKvsEngine, SyncPolicy, TimeBased, TotalStaleRatio, LATENCY_BUCKETS,
+};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io;
+use std::ops::Bound;
+use std::path::Path;
 use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -17,7 +30,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,7 +41,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -41,7 +54,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -54,7 +67,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -65,14 +78,14 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +93,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -94,13 +107,13 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -108,7 +121,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -117,17 +130,218 @@ fn cli_rm_stored() -> Result<()> {
     Ok(())
 }
 
+// `kvs backup <DIR>` followed by `kvs restore <DIR>` should bring back the backed up keys.
+#[test]
+fn cli_backup_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["backup", backup_dir.path().to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["restore", backup_dir.path().to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Ok(())
+}
+
+// `kvs repl` runs the commands read from stdin, going on after those that fail.
+#[test]
+fn cli_repl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repl"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer(
+            "set key1 value1\n\
+             set \"key 2\" \"value \\\"2\\\"\"\n\
+             \n\
+             get key1\n\
+             get missing\n\
+             set key1\n\
+             rm key1\n\
+             rm key1\n\
+             scan key\n\
+             exit\n\
+             get key1\n",
+        )
+        .assert()
+        .success()
+        .stdout(eq(
+            "value1\nKey not found\nKey not found\nkey 2\tvalue \"2\"\n",
+        ))
+        .stderr(eq("Usage: set KEY VALUE\n"));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(
+        store.get("key 2".to_owned())?,
+        Some("value \"2\"".to_owned())
+    );
+
+    Ok(())
+}
+
+// `kvs exec` runs the commands of a file or stdin, printing the result of each as a line of JSON,
+// and fails if one of them did.
+#[test]
+fn cli_exec() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let commands = temp_dir.path().join("commands");
+    std::fs::write(
+        &commands,
+        "# load the keys\nset key1 value1\nset \"key 2\" value2\n\nget key1\n",
+    )?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["exec", commands.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(concat!(
+            r#"{"line":2,"command":"set","key":"key1","ok":true}"#,
+            "\n",
+            r#"{"line":3,"command":"set","key":"key 2","ok":true}"#,
+            "\n",
+            r#"{"line":5,"command":"get","key":"key1","ok":true,"found":true,"value":"value1"}"#,
+            "\n",
+        )));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["exec", "-"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("rm key1\nrm key1\nget key1\nset key3\nscan key\nexit\nset key4 value4\n")
+        .assert()
+        .failure()
+        .stdout(eq(concat!(
+            r#"{"line":1,"command":"rm","key":"key1","ok":true,"found":true}"#,
+            "\n",
+            r#"{"line":2,"command":"rm","key":"key1","ok":true,"found":false}"#,
+            "\n",
+            r#"{"line":3,"command":"get","key":"key1","ok":true,"found":false}"#,
+            "\n",
+            r#"{"line":4,"ok":false,"error":"Usage: set KEY VALUE"}"#,
+            "\n",
+            r#"{"line":5,"command":"scan","prefix":"key","ok":true,"pairs":[{"key":"key 2","value":"value2"}]}"#,
+            "\n",
+        )));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    Ok(())
+}
+
+// `kvs get --output` prints the value in the format asked for, exiting with 1 if the key isn't
+// found.
+#[test]
+fn cli_get_output() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value\t1\n".to_owned())?;
+    drop(store);
+
+    let get = |output: &str| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["get", "key1", "--output", output])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+    };
+    get("raw").stdout(eq("value\t1\n"));
+    get("json").stdout(eq("{\"key\":\"key1\",\"value\":\"value\\t1\\n\"}\n"));
+    get("tsv").stdout(eq("key1\tvalue\\t1\\n\n"));
+
+    for output in ["raw", "json", "tsv"] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["get", "key2", "--output", output])
+            .current_dir(&temp_dir)
+            .assert()
+            .code(1)
+            .stdout(is_empty());
+    }
+
+    Ok(())
+}
+
+// `kvs scan` prints the keys that start with a prefix and their values, exiting with 1 if there
+// are none.
+#[test]
+fn cli_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("other".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let scan = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("scan")
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    scan(&[])
+        .success()
+        .stdout(eq("key1\tvalue1\nkey2\tvalue2\nother\tvalue3\n"));
+    scan(&["key", "--output", "raw"])
+        .success()
+        .stdout(eq("value1\nvalue2"));
+    scan(&["key2", "--output", "json"])
+        .success()
+        .stdout(eq("{\"key\":\"key2\",\"value\":\"value2\"}\n"));
+    scan(&["missing"]).code(1).stdout(is_empty());
+
+    Ok(())
+}
+
 #[test]
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +350,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +371,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,7 +386,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -181,7 +395,7 @@ fn cli_invalid_subcommand() {
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -202,7 +416,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -211,7 +425,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -223,7 +437,7 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
@@ -239,7 +453,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -247,68 +461,3801 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// Should store keys and values that aren't valid UTF-8.
 #[test]
-fn compaction() -> Result<()> {
+fn binary_keys_and_values() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .filter(|res| match res {
-                Ok(entry) => entry.file_type().is_file(),
-                Err(_err) => false,
-            })
-            .map(|res| {
-                res.and_then(|entry| {
-                    println!("entry: {:?}", entry.path());
-                    entry.metadata()
-                })
-                .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
+    store.set_bytes(b"\xff\x00key", b"\x00\x01\xfe")?;
+    store.set_bytes(b"text", b"")?;
+    assert_eq!(
+        store.get_bytes(b"\xff\x00key")?,
+        Some(b"\x00\x01\xfe".to_vec())
+    );
+    assert_eq!(store.get("text".to_owned())?, Some("".to_owned()));
+    assert!(store.get_bytes(b"\xff")?.is_none());
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_bytes(b"\xff\x00key")?,
+        Some(b"\x00\x01\xfe".to_vec())
+    );
+    store.set_bytes(b"key", b"\xff")?;
+    assert!(store.get("key".to_owned()).is_err());
+    store.remove_bytes(b"\xff\x00key")?;
+    assert!(store.remove_bytes(b"\xff\x00key").is_err());
+    assert_eq!(store.get_bytes(b"\xff\x00key")?, None);
+
+    Ok(())
+}
+
+// Should refuse to open a store that another handle or process writes to.
+#[test]
+fn store_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::StoreLocked)
+    ));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("StoreLocked"));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should replay the writes in order from a sequence number, and keep numbering them after
+// compaction removed the last ones and the store was reopened.
+#[test]
+fn replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .compaction_threshold(u64::MAX)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.last_seq(), 3);
+
+    let ops = store.replay_from(2)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(ops.len(), 2);
+    assert!(matches!(
+        &ops[0],
+        Operation::Set { seq: 2, key, value, expires_at: None, modified_at: Some(_) }
+            if key == b"key2" && value == b"value2"
+    ));
+    assert!(matches!(&ops[1], Operation::Remove { seq: 3, key, .. } if key == b"key1"));
+    let seqs = |from| -> Result<Vec<u64>> {
+        store
+            .replay_from(from)?
+            .map(|op| op.map(|op| op.seq()))
+            .collect()
     };
+    assert_eq!(seqs(0)?, vec![1, 2, 3]);
+    assert_eq!(seqs(4)?, Vec::<u64>::new());
 
-    let mut current_size = dir_size();
-    let iterations = 1000;
-    for iter in 0..iterations {
-        for key_id in 0..iterations {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
+    // overwritten and removed keys are gone once compacted
+    store.remove("key2".to_owned())?;
+    store.compact()?;
+    assert_eq!(seqs(0)?, Vec::<u64>::new());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_seq(), 4);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let ops = store.replay_from(0)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(ops.len(), 1);
+    assert_eq!((ops[0].seq(), ops[0].key()), (5, &b"key3"[..]));
+
+    Ok(())
+}
+
+// Should hand out the same store for every path to a directory, and open several directories.
+#[test]
+fn databases() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir1 = temp_dir.path().join("db1");
+    let dir2 = temp_dir.path().join("db2");
+    std::fs::create_dir(&dir1)?;
+    std::fs::create_dir(&dir2)?;
+    let databases = Databases::new();
+
+    let store1 = databases.open(&dir1)?;
+    store1.set("key1".to_owned(), "value1".to_owned())?;
+    let again = databases.open(temp_dir.path().join("db2/../db1"))?;
+    assert_eq!(again.get("key1".to_owned())?, Some("value1".to_owned()));
+    let store2 = databases.open(&dir2)?;
+    assert_eq!(store2.get("key1".to_owned())?, None);
+    let mut paths = databases.paths();
+    paths.sort();
+    assert_eq!(
+        paths,
+        vec![std::fs::canonicalize(&dir1)?, std::fs::canonicalize(&dir2)?]
+    );
+    assert!(databases.get(&dir1).is_some());
+    assert!(matches!(KvStore::open(&dir1), Err(KvsError::StoreLocked)));
+    assert!(databases.open(temp_dir.path().join("missing")).is_err());
+
+    // the store closes once every handle is dropped
+    assert!(databases.close(&dir1));
+    assert!(!databases.close(&dir1));
+    assert!(databases.get(&dir1).is_none());
+    drop((store1, again));
+    let store1 = databases.open(&dir1)?;
+    assert_eq!(store1.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should load compacted segments from their hint files, and fall back to reading the segments if
+// a hint is damaged.
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::options()
+            .segment_size(1024)
+            .compaction_threshold(1)
+            .compression_threshold(None)
+            .open(temp_dir.path())
+    };
+    let hints = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("hint".as_ref()))
+            .map(|entry| entry.into_path())
+            .collect::<Vec<_>>()
+    };
+
+    let store = open()?;
+    for iter in 0..10 {
+        for key_id in 0..50 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
         }
+    }
+    store.remove("key7".to_owned())?;
+    store.set_with_ttl(
+        "key8".to_owned(),
+        "value8".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    // Compaction runs in the background.
+    let mut attempts = 0;
+    while hints().is_empty() {
+        attempts += 1;
+        assert!(attempts < 100, "no hint file was written");
+        thread::sleep(Duration::from_millis(50));
+    }
+    drop(store);
 
-        let new_size = dir_size();
-        println!(
-            "iteration: {}, current_size: {}, new_size: {}",
-            iter, current_size, new_size
-        );
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in (0..50).filter(|&key_id| key_id != 7 && key_id != 8) {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}-9", key_id))
+            );
         }
-        // Compaction triggered.
+        assert_eq!(store.get("key7".to_owned())?, None);
+        assert_eq!(store.get("key8".to_owned())?, Some("value8".to_owned()));
+        Ok(())
+    };
+    check(&open()?)?;
 
-        drop(store);
-        // reopen and check content.
-        println!("Compaction triggered. Going to check the compacted data.");
-        let store = KvStore::open(temp_dir.path())?;
-        for key_id in 0..iterations {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+    for hint in hints() {
+        let mut bytes = std::fs::read(&hint)?;
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        std::fs::write(&hint, bytes)?;
+    }
+    check(&open()?)?;
+
+    Ok(())
+}
+
+// Should write a Bloom filter next to each hint, sized by the false-positive rate, and skip the
+// segments whose filter leaves a purged key out without reading them.
+#[test]
+fn bloom_filters() -> Result<()> {
+    let files = |dir: &std::path::Path, extension: &str| {
+        WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some(extension.as_ref()))
+            .map(|entry| entry.into_path())
+            .collect::<Vec<_>>()
+    };
+    // every sealed segment holds stale data, and the first one the only record of "secret"
+    let fill = |store: &KvStore| -> Result<()> {
+        store.set("secret".to_owned(), "private".to_owned())?;
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
         }
-        return Ok(());
+        for key_id in (0..200).step_by(10) {
+            store.remove(format!("key{}", key_id))?;
+        }
+        store.compact()
+    };
+    let open = |dir: &std::path::Path, rate| {
+        KvStore::options()
+            .segment_size(1024)
+            .compaction_threshold(u64::MAX)
+            .compression_threshold(None)
+            .bloom_false_positive_rate(rate)
+            .open(dir)
+    };
+
+    let filter_bytes = |rate| -> Result<u64> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        fill(&open(temp_dir.path(), rate)?)?;
+        let hints = files(temp_dir.path(), "hint");
+        let filters = files(temp_dir.path(), "bloom");
+        assert!(!hints.is_empty());
+        assert_eq!(filters.len(), if rate.is_some() { hints.len() } else { 0 });
+        for filter in &filters {
+            assert!(hints.contains(&filter.with_extension("hint")));
+        }
+        Ok(filters
+            .iter()
+            .map(|filter| filter.metadata().unwrap().len())
+            .sum())
+    };
+    assert!(filter_bytes(Some(0.0001))? > 2 * filter_bytes(Some(0.1))?);
+    filter_bytes(None)?;
+
+    // Damage every segment that doesn't hold "secret" but the last one, which is always read on
+    // open. The others are loaded from their hints, and the purge only gets past them unread.
+    let damaged_store = |rate| -> Result<TempDir> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        fill(&open(temp_dir.path(), rate)?)?;
+        let mut segments = files(temp_dir.path(), "log");
+        segments.sort_by_key(|path| {
+            let stem = path.file_stem().unwrap().to_str().unwrap();
+            stem.parse::<u64>().unwrap()
+        });
+        segments.pop();
+        for segment in segments {
+            let mut bytes = std::fs::read(&segment)?;
+            if bytes.windows(6).any(|window| window == b"secret") {
+                continue;
+            }
+            let last = bytes.len() - 1;
+            bytes[last] ^= 1;
+            std::fs::write(&segment, bytes)?;
+        }
+        Ok(temp_dir)
+    };
+    let temp_dir = damaged_store(Some(1e-6))?;
+    let store = open(temp_dir.path(), Some(1e-6))?;
+    store.purge("secret".to_owned())?;
+    assert_eq!(store.get("secret".to_owned())?, None);
+    assert!(!files_contain(temp_dir.path(), b"private"));
+    drop(store);
+
+    let temp_dir = damaged_store(None)?;
+    let store = open(temp_dir.path(), None)?;
+    assert!(store.purge("secret".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Should tell whether keys exist, and how many there are, from the index.
+#[test]
+fn contains_key_and_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set_bytes(b"\xff", b"value")?;
+    assert!(store.contains_key("key1"));
+    assert!(store.contains_key(b"\xff"));
+    assert!(!store.contains_key("key3"));
+    assert_eq!(store.len(), 3);
+
+    store.remove("key1".to_owned())?;
+    assert!(!store.contains_key("key1"));
+    assert!(KvsEngine::contains_key(&store, "key2".to_owned())?);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 2);
+    assert!(store.contains_key("key2"));
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+    assert!(!store.contains_key("key3"));
+    assert!(!store.is_empty());
+
+    Ok(())
+}
+
+// Should remove the keys with a prefix with a single record, which survives reopening and
+// compaction.
+#[test]
+fn remove_prefix_and_clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .segment_size(1024)
+        .compaction_threshold(u64::MAX)
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("user:{}", key_id), format!("value{}", key_id))?;
     }
+    store.set("other".to_owned(), "value".to_owned())?;
+    assert_eq!(store.remove_prefix("user:")?, 100);
+    assert_eq!(store.remove_prefix("user:")?, 0);
+    store.set("user:1".to_owned(), "again".to_owned())?;
+    let mut tombstones = 0;
+    store.inspect(|record| tombstones += record.prefix as usize)?;
+    assert_eq!(tombstones, 1);
+    assert_eq!(store.get("user:0".to_owned())?, None);
+    assert_eq!(store.len(), 2);
+    store.set("padding".to_owned(), "x".repeat(1024))?;
+    drop(store);
 
-    panic!("No compaction detected");
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("user:1".to_owned())?, Some("again".to_owned()));
+        assert_eq!(store.get("user:2".to_owned())?, None);
+        assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.len(), 3);
+        Ok(())
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+
+    // the compacted segments are loaded from their hints
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    let ns = store.namespace("ns")?;
+    ns.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.clear()?, 4);
+    assert!(store.is_empty());
+    assert_eq!(ns.get("key".to_owned())?, None);
+    drop(ns);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    Ok(())
+}
+
+// Should append merge operands, fold them into the value on reads, and write the folded value
+// on compaction.
+#[test]
+fn merge_operator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::options()
+            .segment_size(64 * 1024)
+            .compaction_threshold(u64::MAX)
+            .compression_threshold(None)
+            .merge_operator(|_, value, operand| match value {
+                Some(value) => [value, b",", operand].concat(),
+                None => operand.to_vec(),
+            })
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    store.set("list".to_owned(), "a".to_owned())?;
+    store.merge("list".to_owned(), "b".to_owned())?;
+    store.merge("list".to_owned(), "c".to_owned())?;
+    store.merge("new".to_owned(), "x".to_owned())?;
+    assert_eq!(store.get("list".to_owned())?, Some("a,b,c".to_owned()));
+    assert_eq!(store.get("new".to_owned())?, Some("x".to_owned()));
+    let ops = store.replay_from(0)?.collect::<Result<Vec<Operation>>>()?;
+    assert!(
+        matches!(&ops[1], Operation::Merge { key, operand, .. } if key == b"list" && operand == b"b")
+    );
+    let mut operands = 0;
+    store.inspect(|record| operands += record.merge as usize)?;
+    assert_eq!(operands, 3);
+
+    let expected: Vec<String> = (0..100).map(|n| n.to_string()).collect();
+    for n in &expected {
+        store.merge("numbers".to_owned(), n.clone())?;
+    }
+    assert_eq!(store.get("numbers".to_owned())?, Some(expected.join(",")));
+    drop(store);
+
+    // the store can't be read without the operator
+    assert_eq!(
+        KvStore::open(temp_dir.path())
+            .map(drop)
+            .map_err(|err| err.code()),
+        Err(ErrorCode::NoMergeOperator)
+    );
+    let store = open()?;
+    assert_eq!(store.get("list".to_owned())?, Some("a,b,c".to_owned()));
+    assert_eq!(store.get("numbers".to_owned())?, Some(expected.join(",")));
+    store.merge("list".to_owned(), "d".to_owned())?;
+    // the segment of the operands is sealed, and holds the values folded along the way
+    store.set("padding".to_owned(), "x".repeat(64 * 1024))?;
+    store.compact()?;
+    let mut operands = 0;
+    store.inspect(|record| operands += (record.merge && record.live) as usize)?;
+    assert_eq!(operands, 0);
+    assert_eq!(store.get("list".to_owned())?, Some("a,b,c,d".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("list".to_owned())?, Some("a,b,c,d".to_owned()));
+    assert_eq!(store.get("numbers".to_owned())?, Some(expected.join(",")));
+    assert!(matches!(
+        store.merge("list".to_owned(), "e".to_owned()),
+        Err(KvsError::NoMergeOperator)
+    ));
+    Ok(())
+}
+
+// Should report how far opening the store got after each segment.
+#[test]
+fn open_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .segment_size(1024)
+        .compaction_threshold(u64::MAX)
+        .open(temp_dir.path())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let reports = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = std::sync::Arc::clone(&reports);
+    let store = KvStore::options()
+        .on_open_progress(move |progress| sink.lock().unwrap().push(*progress))
+        .open(temp_dir.path())?;
+    let reports = reports.lock().unwrap();
+    assert!(reports.len() > 1);
+    for pair in reports.windows(2) {
+        assert!(pair[0].segments_loaded < pair[1].segments_loaded);
+        assert!(pair[0].bytes_loaded < pair[1].bytes_loaded);
+        assert!(pair[0].keys <= pair[1].keys);
+    }
+    let last = reports.last().unwrap();
+    assert_eq!(last.segments_loaded, last.segments);
+    assert_eq!(last.bytes_loaded, last.bytes);
+    assert_eq!(last.keys, 200);
+    assert_eq!(store.get("key199".to_owned())?, Some("value199".to_owned()));
+
+    Ok(())
+}
+
+// Should honor the options the store is opened with.
+#[test]
+fn open_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::options()
+        .read_only(true)
+        .open(temp_dir.path())
+        .is_err());
+
+    let store = KvStore::options()
+        .segment_size(1024)
+        .sync_policy(SyncPolicy::Always)
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "x".repeat(100))?;
+    }
+    drop(store);
+    let segments = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+        .count();
+    assert!(segments >= 10, "expected many segments, found {}", segments);
+
+    let store = KvStore::options().read_only(true).open(temp_dir.path())?;
+    assert_eq!(store.get("key42".to_owned())?, Some("x".repeat(100)));
+    assert!(store.set("key1".to_owned(), "value1".to_owned()).is_err());
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("x".repeat(100)));
+
+    // A writer can open the store while a reader has it open.
+    let writer = KvStore::open(temp_dir.path())?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+
+    Ok(())
+}
+
+// Should sync the writes of concurrent writers in groups, with every write done once it returns.
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .segment_size(4096)
+        .sync_policy(SyncPolicy::Group)
+        .open(temp_dir.path())?;
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..50 {
+                    let key = format!("key{}-{}", thread_id, key_id);
+                    store.set(key.clone(), "x".repeat(100))?;
+                    assert_eq!(store.get(key)?, Some("x".repeat(100)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    store.remove("key0-0".to_owned())?;
+    let mut batch = store.batch();
+    batch.set("batched".to_owned(), "value".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for key_id in 0..50 {
+            let value = store.get(format!("key{}-{}", thread_id, key_id))?;
+            if thread_id == 0 && key_id == 0 {
+                assert_eq!(value, None);
+            } else {
+                assert_eq!(value, Some("x".repeat(100)));
+            }
+        }
+    }
+    assert_eq!(store.get("batched".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should serve repeated reads from the cache, and read the new value once a key is overwritten.
+#[test]
+fn read_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .cache_capacity(1024)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Wipe the records on disk. Only the cached value can still be read.
+    let segment = temp_dir.path().join("1.log");
+    let len = std::fs::metadata(&segment)?.len() as usize;
+    let mut bytes = std::fs::read(&segment)?;
+    bytes[4..].copy_from_slice(&vec![0; len - 4]);
+    std::fs::write(&segment, bytes)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.get("key2".to_owned()).is_err());
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    // Values that don't fit in the cache are still read.
+    let value = "x".repeat(2048);
+    store.set("key3".to_owned(), value.clone())?;
+    assert_eq!(store.get("key3".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Should compress large values, and only those over the threshold.
+#[test]
+fn compress_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let segment = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "{\"name\": \"value\"}".repeat(10_000);
+
+    store.set("key1".to_owned(), value.clone())?;
+    let compressed_size = std::fs::metadata(&segment)?.len();
+    assert!(compressed_size < value.len() as u64 / 10);
+
+    drop(store);
+    let store = KvStore::options()
+        .compression_threshold(None)
+        .open(temp_dir.path())?;
+    store.set("key2".to_owned(), value.clone())?;
+    assert!(std::fs::metadata(&segment)?.len() > compressed_size + value.len() as u64);
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key2".to_owned())?, Some(value.clone()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key2".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Should iterate over the keys in a range in sorted order.
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key in &["b2", "a1", "c3", "b1", "a2"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove("b2".to_owned())?;
+
+    let pairs = store.scan("a2".."c3").collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("a2".to_owned(), "value-a2".to_owned()),
+            ("b1".to_owned(), "value-b1".to_owned()),
+        ]
+    );
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let pairs = store.scan("b".to_owned()..).collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("b1".to_owned(), "value-b1".to_owned()),
+            ("c3".to_owned(), "value-c3".to_owned()),
+        ]
+    );
+    assert_eq!(
+        store.keys().collect::<Vec<_>>(),
+        vec!["a1", "a2", "b1", "c3"]
+    );
+
+    // Ranges that end before they start are empty, for the store as for a snapshot of it.
+    let snapshot = store.snapshot();
+    let ranges = [
+        (Bound::Included("b"), Bound::Excluded("a")),
+        (Bound::Included("b1"), Bound::Included("a1")),
+        (Bound::Excluded("b1"), Bound::Excluded("b1")),
+        (Bound::Included("b1"), Bound::Excluded("b1")),
+        (Bound::Excluded("b1"), Bound::Included("b1")),
+        (Bound::Included("b1"), Bound::Included("b1")),
+    ];
+    for range in ranges {
+        let from_store = store.scan::<&str, _>(range).collect::<Result<Vec<_>>>()?;
+        let from_snapshot = snapshot
+            .scan::<&str, _>(range)
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(from_store, from_snapshot, "{:?}", range);
+    }
+    assert!(snapshot
+        .scan::<&str, _>((Bound::Excluded("b1"), Bound::Excluded("b1")))
+        .next()
+        .is_none());
+    assert_eq!(snapshot.scan("b1"..="b1").count(), 1);
+
+    Ok(())
+}
+
+// Should iterate over the keys that start with a prefix in sorted order.
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key in &[
+        "user:2:name",
+        "user:1:name",
+        "user",
+        "user;",
+        "user:1:age",
+        "users",
+    ] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove("user:1:age".to_owned())?;
+
+    let keys = store
+        .scan_prefix("user:")
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec!["user:1:name", "user:2:name"]);
+    let pairs = store.scan_prefix("user:2").collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![("user:2:name".to_owned(), "value-user:2:name".to_owned())]
+    );
+    assert_eq!(store.scan_prefix("").count(), 5);
+    assert_eq!(store.scan_prefix("group:").count(), 0);
+    assert_eq!(
+        store.scan_keys("user").collect::<Vec<_>>(),
+        vec!["user", "user:1:name", "user:2:name", "user;", "users"]
+    );
+
+    Ok(())
+}
+
+// Should read the keys as they were when the snapshot was taken.
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let snapshot = store.snapshot();
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3".to_owned())?, None);
+    let pairs = snapshot.scan::<String, _>(..).collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+// Should replace the contents of the store with a backup, and refuse a corrupted backup.
+#[test]
+fn backup_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.backup(backup_dir.path())?;
+    assert!(store.backup(backup_dir.path()).is_err());
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.restore(backup_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // The backup is a store of its own.
+    let backup = KvStore::open(backup_dir.path())?;
+    assert_eq!(backup.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(backup);
+
+    // Flip a byte of the backup.
+    let segment = backup_dir.path().join("1.log");
+    let mut bytes = std::fs::read(&segment)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 1;
+    std::fs::write(&segment, bytes)?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    assert!(store.restore(backup_dir.path()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Should split the log into several segments once it grows large.
+#[test]
+fn multiple_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // keep the values from compressing into a single segment
+    let store = KvStore::options()
+        .compression_threshold(None)
+        .open(temp_dir.path())?;
+
+    let value = "x".repeat(10_000);
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("{}{}", value, key_id))?;
+    }
+    for key_id in (0..1000).step_by(2) {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    let segments = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+        .count();
+    assert!(
+        segments > 1,
+        "expected several segments, found {}",
+        segments
+    );
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        let expected = if key_id % 2 == 0 {
+            None
+        } else {
+            Some(format!("{}{}", value, key_id))
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+
+    Ok(())
+}
+
+// Should list the live segments in the manifest, and remove the files a crash left behind.
+#[test]
+fn manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let read_manifest = || -> Result<serde_json::Value> {
+        Ok(serde_json::from_slice(&std::fs::read(
+            temp_dir.path().join("MANIFEST"),
+        )?)?)
+    };
+    let store = KvStore::options()
+        .segment_size(4096)
+        .compaction_threshold(u64::MAX)
+        .compression_threshold(None)
+        .open(temp_dir.path())?;
+    for value in &["x", "y"] {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), value.repeat(100))?;
+        }
+    }
+    let manifest = read_manifest()?;
+    let segments = manifest["segments"].as_array().unwrap();
+    assert_eq!(segments.len() as u64, store.stats()?.segments);
+    assert_eq!(manifest["generation"], 0);
+    store.compact()?;
+    assert!(read_manifest()?["generation"].as_u64().unwrap() > 0);
+    let last = read_manifest()?["segments"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|segment| segment.as_u64())
+        .max()
+        .unwrap();
+    drop(store);
+
+    // Leave behind the files of a compaction that didn't finish: a segment the manifest
+    // doesn't list yet, with its hint, and a temporary file.
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other = KvStore::open(other_dir.path())?;
+    other.set("key0".to_owned(), "stale".to_owned())?;
+    drop(other);
+    let stale = temp_dir.path().join(format!("{}.log", last + 100));
+    let stale_hint = temp_dir.path().join(format!("{}.hint", last + 100));
+    let temp = temp_dir.path().join(".tmpAbC123");
+    std::fs::copy(other_dir.path().join("1.log"), &stale)?;
+    std::fs::write(&stale_hint, b"kvh")?;
+    std::fs::write(&temp, b"half a segment")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("y".repeat(100)));
+    assert!(!stale.exists() && !stale_hint.exists() && !temp.exists());
+    drop(store);
+
+    // A store written before it had a manifest is given one.
+    std::fs::remove_file(temp_dir.path().join("MANIFEST"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, Some("y".repeat(100)));
+    assert!(temp_dir.path().join("MANIFEST").exists());
+    drop(store);
+
+    // A manifest without a layout is given the current one, and a newer layout is refused.
+    let mut manifest = read_manifest()?;
+    assert_eq!(manifest["layout"], 1);
+    manifest.as_object_mut().unwrap().remove("layout");
+    std::fs::write(
+        temp_dir.path().join("MANIFEST"),
+        serde_json::to_vec(&manifest)?,
+    )?;
+    drop(KvStore::open(temp_dir.path())?);
+    assert_eq!(read_manifest()?["layout"], 1);
+    manifest["layout"] = 2.into();
+    std::fs::write(
+        temp_dir.path().join("MANIFEST"),
+        serde_json::to_vec(&manifest)?,
+    )?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::UnknownFormat(_))
+    ));
+
+    Ok(())
+}
+
+// Should keep the keys of namespaces apart, and drop a namespace as a whole.
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .segment_size(4096)
+        .compaction_threshold(u64::MAX)
+        .open(temp_dir.path())?;
+    let users = store.namespace("users")?;
+    let orders = store.namespace("orders")?;
+    assert!(store.namespace("").is_err());
+    assert!(store.namespace("a\0b").is_err());
+
+    store.set("1".to_owned(), "plain".to_owned())?;
+    for key_id in 0..100 {
+        users.set(format!("{:03}", key_id), "x".repeat(100))?;
+    }
+    users.set("001".to_owned(), "alice".to_owned())?;
+    orders.set("001".to_owned(), "book".to_owned())?;
+    assert_eq!(store.get("1".to_owned())?, Some("plain".to_owned()));
+    assert_eq!(users.get("001".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(orders.get("001".to_owned())?, Some("book".to_owned()));
+    assert_eq!(orders.get("002".to_owned())?, None);
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec!["1".to_owned()]);
+    assert_eq!(store.scan_prefix("").count(), 1);
+    assert_eq!(users.keys().count(), 100);
+    assert_eq!(
+        orders.scan_prefix("0").collect::<Result<Vec<_>>>()?,
+        vec![("001".to_owned(), "book".to_owned())]
+    );
+
+    store.drop_namespace("users")?;
+    assert_eq!(users.get("001".to_owned())?, None);
+    assert_eq!(users.keys().count(), 0);
+    assert!(store.stats()?.dead_bytes > 100 * 100);
+    // keys set after the drop are kept
+    users.set("101".to_owned(), "bob".to_owned())?;
+    assert_eq!(orders.get("001".to_owned())?, Some("book".to_owned()));
+    // the handles keep the store open
+    drop((store, users, orders));
+
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.namespace("users")?;
+    assert_eq!(users.keys().collect::<Vec<_>>(), vec!["101".to_owned()]);
+    assert_eq!(users.get("001".to_owned())?, None);
+    assert_eq!(
+        store.namespace("orders")?.get("001".to_owned())?,
+        Some("book".to_owned())
+    );
+    // compaction reclaims the records of the dropped keys
+    store.compact()?;
+    assert!(store.stats()?.dead_bytes < 100 * 100);
+    drop((store, users));
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.namespace("users")?;
+    assert_eq!(users.keys().collect::<Vec<_>>(), vec!["101".to_owned()]);
+    assert_eq!(store.get("1".to_owned())?, Some("plain".to_owned()));
+
+    Ok(())
+}
+
+// `kvs export` piped into `kvs import` should copy the keys to another store.
+#[test]
+fn cli_export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value, 2".to_owned())?;
+    drop(store);
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv"])
+        .current_dir(&temp_dir)
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "key,value,expires_at\nkey1,value1,\nkey2,\"value, 2\",\n"
+    );
+    let dump = target_dir.path().join("dump.csv");
+    std::fs::write(&dump, &output.stdout)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", dump.to_str().unwrap()])
+        .current_dir(&target_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&target_dir)
+        .assert()
+        .success()
+        .stdout(eq("value, 2").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(r#"{"key":"key1","value":"value1"}"#));
+
+    Ok(())
+}
+
+// Should drop a record torn by a crash and keep the store usable.
+#[test]
+fn recover_torn_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Chop off the tail of the last record, as if the process died while writing it.
+    let segment = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.path().extension() == Some("log".as_ref()))
+        .expect("no segment found");
+    let file = OpenOptions::new().write(true).open(segment.path())?;
+    let len = file.metadata()?.len();
+    file.set_len(len - 3)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Segments written with JSON records before the binary format should still be readable.
+#[test]
+fn read_legacy_json_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut segment = Vec::new();
+    for data in &[
+        r#"{"key":"key1","value":"value1"}"#,
+        r#"{"key":"key2","value":"value2"}"#,
+        r#"{"key":"key1","value":null}"#,
+    ] {
+        segment.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        segment.extend_from_slice(&crc32fast::hash(data.as_bytes()).to_le_bytes());
+        segment.extend_from_slice(data.as_bytes());
+    }
+    std::fs::write(temp_dir.path().join("1.log"), &segment)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // new records go to a new segment, leaving the legacy one untouched
+    assert_eq!(std::fs::read(temp_dir.path().join("1.log"))?, segment);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should rewrite legacy segments in the current format, from the library and from `kvs migrate`.
+#[test]
+fn migrate_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut segment = Vec::new();
+    for data in &[
+        r#"{"key":"key1","value":"value1"}"#,
+        r#"{"key":"key2","value":"value2"}"#,
+        r#"{"key":"key1","value":null}"#,
+    ] {
+        segment.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        segment.extend_from_slice(&crc32fast::hash(data.as_bytes()).to_le_bytes());
+        segment.extend_from_slice(data.as_bytes());
+    }
+    std::fs::write(temp_dir.path().join("1.log"), &segment)?;
+    std::fs::write(temp_dir.path().join("2.log"), &segment)?;
+
+    // Nothing changes if a segment isn't in the expected format.
+    assert!(KvStore::migrate(temp_dir.path(), Some("v2".parse()?)).is_err());
+    assert_eq!(std::fs::read(temp_dir.path().join("1.log"))?, segment);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        KvStore::migrate(temp_dir.path(), None),
+        Err(KvsError::StoreLocked)
+    ));
+    drop(store);
+    assert_eq!(
+        KvStore::migrate(temp_dir.path(), Some(FormatVersion::Json))?,
+        [1, 2]
+    );
+    assert!(KvStore::migrate(temp_dir.path(), None)?.is_empty());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store
+        .inspect(|_| {})?
+        .iter()
+        .all(|info| info.version == Some(4)));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("1.log"), &segment)?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "json", "--to", "v3"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "json", "--to", "v4"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(eq("Migrated 1 segments to v4").trim());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should apply all the writes of a batch, or none of them.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = store.batch();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Removing a missing key fails the whole batch.
+    let mut batch = store.batch();
+    batch.set("key4".to_owned(), "value4".to_owned());
+    batch.remove("key1".to_owned());
+    assert!(store.write_batch(batch).is_err());
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // A batch torn by a crash is dropped entirely.
+    let mut batch = store.batch();
+    batch.set("key5".to_owned(), "value5".to_owned());
+    batch.set("key6".to_owned(), "value6".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+    let segment = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.path().extension() == Some("log".as_ref()))
+        .expect("no segment found");
+    let file = OpenOptions::new().write(true).open(segment.path())?;
+    let len = file.metadata()?.len();
+    file.set_len(len - 3)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, None);
+    assert_eq!(store.get("key6".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should behave the same through the `KvsEngine` trait, whichever engine is opened.
+#[test]
+fn open_engines() -> Result<()> {
+    for &engine in &[Engine::Kvs, Engine::Sled] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(engine, temp_dir.path())?;
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key2".to_owned())?;
+        assert!(store.remove("key2".to_owned()).is_err());
+
+        // Open from disk again and check persistent data.
+        drop(store);
+        let store = open_engine(engine, temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// Should open a sled store while the handle that held it is still letting go of it, which fails
+// at once instead if sled no longer words its lock error as `SledKvsEngine` expects.
+#[test]
+fn reopen_sled_while_dropping() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    SledKvsEngine::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    let held = SledKvsEngine::open(temp_dir.path())?;
+    let dropping = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        drop(held);
+    });
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    dropping.join().unwrap();
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should record the engine of a store, and refuse to open it with another one.
+#[test]
+fn engine_mismatch() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(detect_engine(kvs_dir.path())?, None);
+
+    open_engine(Engine::Kvs, kvs_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    open_engine(Engine::Sled, sled_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(detect_engine(kvs_dir.path())?, Some(Engine::Kvs));
+    assert_eq!(detect_engine(sled_dir.path())?, Some(Engine::Sled));
+
+    assert!(matches!(
+        open_engine(Engine::Sled, kvs_dir.path()),
+        Err(KvsError::WrongEngine(ref name)) if name == "kvs"
+    ));
+    assert!(matches!(
+        open_engine(Engine::Kvs, sled_dir.path()),
+        Err(KvsError::WrongEngine(ref name)) if name == "sled"
+    ));
+    assert!(matches!(
+        KvStore::options().read_only(true).open(sled_dir.path()),
+        Err(KvsError::WrongEngine(_))
+    ));
+
+    // A store from before engines recorded themselves is recognized by its files.
+    std::fs::remove_file(kvs_dir.path().join("engine"))?;
+    std::fs::remove_file(sled_dir.path().join("engine"))?;
+    assert_eq!(detect_engine(kvs_dir.path())?, Some(Engine::Kvs));
+    assert_eq!(detect_engine(sled_dir.path())?, Some(Engine::Sled));
+    let store = open_engine(Engine::Kvs, kvs_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct User {
+    name: String,
+    age: u32,
+}
+
+// Should store and retrieve serializable values with every engine.
+#[test]
+fn typed_values() -> Result<()> {
+    for &engine in &[Engine::Kvs, Engine::Sled] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(engine, temp_dir.path())?;
+        let user = User {
+            name: "alice".to_owned(),
+            age: 30,
+        };
+
+        store.set_typed("user:1".to_owned(), &user)?;
+        store.set_typed("count".to_owned(), &42u64)?;
+        assert_eq!(store.get_typed("user:1".to_owned())?, Some(user));
+        assert_eq!(store.get_typed::<u64>("count".to_owned())?, Some(42));
+        assert_eq!(store.get_typed::<User>("user:2".to_owned())?, None);
+        assert!(store.get_typed::<User>("count".to_owned()).is_err());
+    }
+    Ok(())
+}
+
+// Should count keys, bytes, reads, writes and compactions.
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .segment_size(4096)
+        .compaction_threshold(u64::MAX)
+        .compression_threshold(None)
+        .open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!((stats.keys, stats.segments, stats.live_bytes), (0, 1, 0));
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "x".repeat(100))?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 100);
+    assert_eq!(stats.writes, 100);
+    assert_eq!(stats.dead_bytes, 0);
+    assert!(stats.live_bytes >= 100 * 100);
+    assert!(stats.segments > 1);
+    let live_bytes = stats.live_bytes;
+
+    // overwriting keys makes as many bytes dead as it adds
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "y".repeat(100))?;
+    }
+    store.get("key0".to_owned())?;
+    store.get("key0".to_owned())?;
+    store.get_many(&["key1".to_owned(), "missing".to_owned()])?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 100);
+    assert_eq!(stats.writes, 150);
+    assert_eq!(stats.live_bytes, live_bytes);
+    assert!(stats.dead_bytes > live_bytes / 3 && stats.dead_bytes < live_bytes / 2);
+    assert_eq!(stats.reads, 4);
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 2));
+    assert!((stats.cache_hit_rate() - 1.0 / 3.0).abs() < 1e-9);
+    assert_eq!(stats.compactions, 0);
+
+    let stats = open_engine(Engine::Sled, temp_dir.path().join("sled"))?.stats()?;
+    assert_eq!(stats.keys, 0);
+
+    Ok(())
+}
+
+// Should time gets, sets, removes and compactions into histograms, and export them for
+// Prometheus.
+#[test]
+fn latency_histograms() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .segment_size(4096)
+        .compaction_threshold(u64::MAX)
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "x".repeat(100))?;
+    }
+    for key_id in 0..50 {
+        store.get(format!("key{}", key_id))?;
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(store.remove("missing".to_owned()).is_err());
+    store.compact()?;
+
+    let latencies = store.stats()?.latencies;
+    assert_eq!(latencies.set.count, 100);
+    assert_eq!(latencies.get.count, 50);
+    assert_eq!(latencies.remove.count, 51);
+    assert!(latencies.compaction.count > 0);
+    for (_, latency) in latencies.iter() {
+        assert_eq!(latency.buckets.len(), LATENCY_BUCKETS.len() + 1);
+        assert_eq!(latency.buckets.iter().sum::<u64>(), latency.count);
+        assert!(latency.percentile(50.0) <= latency.percentile(99.0));
+        assert!(latency.percentile(99.0).as_micros() as u64 <= latency.max_micros);
+    }
+
+    // The percentiles are the upper bounds of the buckets they fall in.
+    let mut buckets = vec![0; LATENCY_BUCKETS.len() + 1];
+    buckets[2] = 98;
+    buckets[9] = 2;
+    let latency = Latency {
+        count: 100,
+        sum_micros: 98 * 4 + 2 * 800,
+        max_micros: 900,
+        buckets,
+    };
+    assert_eq!(latency.percentile(50.0), Duration::from_micros(5));
+    assert_eq!(latency.percentile(98.0), Duration::from_micros(5));
+    assert_eq!(latency.percentile(99.0), Duration::from_micros(900));
+    assert_eq!(latency.mean(), Duration::from_micros(19));
+    let mut merged = Latency::default();
+    merged.merge(&latency);
+    merged.merge(&Latency::default());
+    assert_eq!(merged, latency);
+
+    let metrics = store.stats()?.to_prometheus();
+    assert!(metrics.contains("# TYPE kvs_operation_duration_seconds histogram\n"));
+    assert!(metrics.contains("kvs_operation_duration_seconds_count{op=\"set\"} 100\n"));
+    assert!(metrics.contains("kvs_operation_duration_seconds_bucket{op=\"get\",le=\"+Inf\"} 50\n"));
+    assert!(metrics.contains("\nkvs_keys 50\n"));
+
+    Ok(())
+}
+
+// Should read the settings of a config file, and tune an open store with them.
+#[test]
+fn config_file() -> Result<()> {
+    let config: ConfigFile = r#"
+        addr = "127.0.0.1:4001"
+        engine = "sled"
+        compaction-threshold = 1
+        cache-capacity = 0
+        log-level = "debug"
+    "#
+    .parse()?;
+    assert_eq!(config.addr.as_deref(), Some("127.0.0.1:4001"));
+    assert_eq!(config.engine, Some(Engine::Sled));
+    assert_eq!(config.log_level(), log::LevelFilter::Debug);
+    assert_eq!("".parse::<ConfigFile>()?, ConfigFile::default());
+    assert!("cache-size = 1".parse::<ConfigFile>().is_err());
+    assert!("engine = \"rocks\"".parse::<ConfigFile>().is_err());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.toml");
+    std::fs::write(&path, "cache-capacity = 4096\n")?;
+    let mut options = KvStore::options();
+    ConfigFile::from_file(&path)?.configure(&mut options);
+    let store = options
+        .segment_size(4096)
+        .compaction_threshold(u64::MAX)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("key1".to_owned())?;
+    assert_eq!(store.stats()?.cache_hits, 1);
+
+    // Disabling the cache drops what it holds, and a lower threshold starts compacting.
+    for _ in 0..100 {
+        store.set("key2".to_owned(), "x".repeat(100))?;
+    }
+    config.tune(&store);
+    store.get("key1".to_owned())?;
+    assert_eq!(store.stats()?.cache_hits, 1);
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    while store.stats()?.compactions == 0 {
+        assert!(std::time::Instant::now() < deadline, "timed out waiting");
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(store.get("key2".to_owned())?, Some("x".repeat(100)));
+
+    Ok(())
+}
+
+// Should send the changes to the keys under a prefix to its watchers, in order.
+#[test]
+fn watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    let users = store.watch("user:");
+    let everything = store.watch("");
+
+    store.set("user:1".to_owned(), "bob".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    let mut batch = store.batch();
+    batch.set("user:2".to_owned(), "carol".to_owned());
+    batch.set("user:2".to_owned(), "dave".to_owned());
+    batch.remove("user:1".to_owned());
+    store.write_batch(batch)?;
+    store.increment("user:count".to_owned(), 2)?;
+
+    let event = |key: &str, old: Option<&str>, new: Option<&str>| ChangeEvent {
+        key: key.to_owned(),
+        op: if new.is_some() {
+            ChangeOp::Set
+        } else {
+            ChangeOp::Remove
+        },
+        old_value: old.map(str::to_owned),
+        new_value: new.map(str::to_owned),
+    };
+    let expected = vec![
+        event("user:1", Some("alice"), Some("bob")),
+        event("user:2", None, Some("carol")),
+        event("user:2", Some("carol"), Some("dave")),
+        event("user:1", Some("bob"), None),
+        event("user:count", None, Some("2")),
+    ];
+    assert_eq!(users.try_iter().collect::<Vec<_>>(), expected);
+    assert_eq!(everything.try_iter().count(), expected.len() + 1);
+
+    // Dropped watchers are forgotten.
+    drop(users);
+    store.set("user:3".to_owned(), "erin".to_owned())?;
+    assert_eq!(
+        everything.try_iter().collect::<Vec<_>>(),
+        vec![event("user:3", None, Some("erin"))]
+    );
+
+    Ok(())
+}
+
+// Should remove keys once they expire and tell their watchers, without anything reading them.
+#[test]
+fn watch_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let sessions = store.watch("session:");
+    store.set_with_ttl(
+        "session:1".to_owned(),
+        "alice".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.set_with_ttl(
+        "session:2".to_owned(),
+        "bob".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    // set again before it expires, so only the last value is reported
+    store.set_with_ttl(
+        "session:3".to_owned(),
+        "carol".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    store.set_with_ttl(
+        "session:3".to_owned(),
+        "dave".to_owned(),
+        Duration::from_millis(150),
+    )?;
+    assert_eq!(sessions.try_iter().count(), 4);
+
+    let expired = |key: &str, value: &str| ChangeEvent {
+        key: key.to_owned(),
+        op: ChangeOp::Expire,
+        old_value: Some(value.to_owned()),
+        new_value: None,
+    };
+    let timeout = Duration::from_secs(5);
+    assert_eq!(
+        sessions.recv_timeout(timeout).ok(),
+        Some(expired("session:1", "alice"))
+    );
+    assert_eq!(
+        sessions.recv_timeout(timeout).ok(),
+        Some(expired("session:3", "dave"))
+    );
+    assert!(sessions.try_recv().is_err());
+    drop(store);
+
+    // the tombstones were written
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("session:1".to_owned())?, None);
+    assert_eq!(store.get("session:3".to_owned())?, None);
+    assert_eq!(store.get("session:2".to_owned())?, Some("bob".to_owned()));
+    assert_eq!(store.stats()?.keys, 1);
+
+    Ok(())
+}
+
+// Should report the live and dead records of each segment, and reclaim the dead ones when asked.
+#[test]
+fn inspect_and_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .compaction_threshold(u64::MAX)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let mut records = Vec::new();
+    let segments = store.inspect(|record| records.push(record))?;
+    let summary: Vec<_> = records
+        .iter()
+        .map(|record| (record.key.as_slice(), record.value.is_some(), record.live))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (&b"key1"[..], true, false),
+            (&b"key1"[..], true, true),
+            (&b"key2"[..], true, false),
+            (&b"key2"[..], false, false),
+        ]
+    );
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0].records, 4);
+    assert_eq!(segments[0].live_records, 1);
+    assert_eq!(segments[0].live_bytes, records[1].len);
+    // the rest is the file header
+    assert_eq!(
+        segments[0].live_bytes + segments[0].dead_bytes + 17,
+        segments[0].size
+    );
+
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    let segments = store.inspect(|_| {})?;
+    assert_eq!(segments.iter().map(|s| s.dead_bytes).sum::<u64>(), 0);
+    assert_eq!(store.stats()?.compactions, 1);
+
+    let read_only = KvStore::options().read_only(true).open(temp_dir.path())?;
+    assert!(matches!(read_only.compact(), Err(KvsError::ReadOnly)));
+
+    Ok(())
+}
+
+// Should write a checksummed file header, and refuse segments with a newer or damaged one.
+#[test]
+fn file_header() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let segments = store.inspect(|_| {})?;
+    assert_eq!(segments[0].version, Some(4));
+    assert!(segments[0].created.is_some());
+    drop(store);
+
+    let first = temp_dir.path().join("1.log");
+    let bytes = std::fs::read(&first)?;
+    assert_eq!(&bytes[..4], b"kvs\x04");
+
+    // A segment from a newer version is refused rather than misread.
+    let mut newer = bytes.clone();
+    newer[3] = 9;
+    std::fs::write(&first, &newer)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()).map_err(|err| err.code()),
+        Err(ErrorCode::UnsupportedFormat)
+    ));
+
+    // So is a damaged header.
+    let mut damaged = bytes.clone();
+    damaged[6] ^= 0xff;
+    std::fs::write(&first, &damaged)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()).map_err(|err| err.code()),
+        Err(ErrorCode::ChecksumMismatch)
+    ));
+
+    std::fs::write(&first, &bytes)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should find a damaged record in any segment, and cut the segment off before it.
+#[test]
+fn verify_and_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        let mut options = KvStore::options();
+        options.segment_size(1024).compaction_threshold(u64::MAX);
+        options
+    };
+    let store = options().open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "x".repeat(50))?;
+    }
+    drop(store);
+
+    let checks = KvStore::verify(temp_dir.path())?;
+    assert!(checks.len() > 2);
+    assert!(checks.iter().all(|check| check.is_ok()));
+    let first_records = checks[0].records;
+
+    // Flip a byte in the middle of the first segment.
+    let first = temp_dir.path().join("1.log");
+    let mut bytes = std::fs::read(&first)?;
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0xff;
+    std::fs::write(&first, &bytes)?;
+    // The error tells which segment is corrupt.
+    match options().open(temp_dir.path()) {
+        Err(err) => {
+            assert_eq!(err.code(), ErrorCode::ChecksumMismatch);
+            assert!(matches!(err.root(), KvsError::ChecksumMismatch));
+            assert!(err.to_string().contains("1.log"), "{}", err);
+        }
+        Ok(_) => panic!("expected a checksum mismatch"),
+    }
+
+    let checks = KvStore::verify(temp_dir.path())?;
+    assert!(matches!(checks[0].error, Some(KvsError::ChecksumMismatch)));
+    assert!(checks[0].valid_len <= middle as u64);
+    assert!(checks[1..].iter().all(|check| check.is_ok()));
+    let intact = checks[0].records;
+
+    let repaired = KvStore::repair(temp_dir.path())?;
+    assert_eq!(repaired.iter().filter(|check| !check.is_ok()).count(), 1);
+    assert!(KvStore::verify(temp_dir.path())?
+        .iter()
+        .all(|check| check.is_ok()));
+
+    // The records before the damage and the later segments survive.
+    let store = options().open(temp_dir.path())?;
+    for key_id in 0..100 {
+        let lost = (intact..first_records).contains(&key_id);
+        let value = store.get(format!("key{}", key_id))?;
+        assert_eq!(value.is_none(), lost, "key{}", key_id);
+    }
+
+    Ok(())
+}
+
+// Should check records against their checksum when reading them, drop the keys whose records
+// are corrupt, and find every corrupt record when scrubbing.
+#[test]
+fn corrupt_reads_and_scrub() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let flip = |needle: &str| -> Result<()> {
+        let path = temp_dir.path().join("1.log");
+        let mut bytes = std::fs::read(&path)?;
+        let at = bytes
+            .windows(needle.len())
+            .position(|window| window == needle.as_bytes())
+            .expect("the value is in the segment");
+        bytes[at] ^= 0xff;
+        std::fs::write(&path, &bytes)?;
+        Ok(())
+    };
+
+    flip("value3")?;
+    match store.get("key3".to_owned()) {
+        Err(err) => {
+            assert_eq!(err.code(), ErrorCode::Corruption);
+            assert!(matches!(
+                err.root(),
+                KvsError::Corruption { key, .. } if key == "key3"
+            ));
+            assert!(err.to_string().contains("1.log"), "{}", err);
+        }
+        Ok(value) => panic!("expected a corrupt record, got {:?}", value),
+    }
+    // The key is dropped rather than read again.
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    flip("value5")?;
+    let corrupt = store.scrub()?;
+    assert_eq!(corrupt.len(), 2);
+    assert!(corrupt[0].keys.is_empty());
+    assert_eq!(corrupt[1].keys, vec![b"key5".to_vec()]);
+    assert!(corrupt[0].offset < corrupt[1].offset);
+    assert_eq!(store.get("key5".to_owned())?, None);
+    for key_id in (0..10).filter(|&key_id| key_id != 3 && key_id != 5) {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    assert!(store.scrub()?.iter().all(|record| record.keys.is_empty()));
+
+    Ok(())
+}
+
+// Should export every key in both formats and import them back, along with their expiry.
+#[test]
+fn export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), "value".to_owned())?;
+    store.set("quoted".to_owned(), "a \"b\", c\nd".to_owned())?;
+    store.set("empty".to_owned(), String::new())?;
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "soon".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.set("removed".to_owned(), "gone".to_owned())?;
+    store.remove("removed".to_owned())?;
+
+    for &format in &[DumpFormat::JsonLines, DumpFormat::Csv] {
+        let mut dump = Vec::new();
+        assert_eq!(store.export(&mut dump, format)?, 4);
+
+        let target_dir = TempDir::new().expect("unable to create temporary working directory");
+        let target = KvStore::open(target_dir.path())?;
+        target.set("plain".to_owned(), "old".to_owned())?;
+        target.set("other".to_owned(), "kept".to_owned())?;
+        assert_eq!(target.import(&dump[..], format)?, 4);
+        assert_eq!(target.get("plain".to_owned())?, Some("value".to_owned()));
+        assert_eq!(
+            target.get("quoted".to_owned())?,
+            Some("a \"b\", c\nd".to_owned())
+        );
+        assert_eq!(target.get("empty".to_owned())?, Some(String::new()));
+        assert_eq!(target.get("expiring".to_owned())?, Some("soon".to_owned()));
+        assert_eq!(target.get("removed".to_owned())?, None);
+        assert_eq!(target.get("other".to_owned())?, Some("kept".to_owned()));
+        let mut expected_dump = Vec::new();
+        store.export(&mut expected_dump, format)?;
+        target.remove("other".to_owned())?;
+        let mut actual_dump = Vec::new();
+        target.export(&mut actual_dump, format)?;
+        assert_eq!(actual_dump, expected_dump);
+    }
+
+    let dump = b"{\"key\":\"expired\",\"value\":\"v\",\"expires_at\":1}\n\n";
+    assert_eq!(store.import(&dump[..], DumpFormat::JsonLines)?, 0);
+    assert!(matches!(
+        store.import(&b"not json\n"[..], DumpFormat::JsonLines),
+        Err(KvsError::SerdeError(_))
+    ));
+    assert!(matches!(
+        "xml".parse::<DumpFormat>(),
+        Err(KvsError::UnknownFormat(_))
+    ));
+
+    store.set_bytes(b"binary", &[0xff])?;
+    assert!(matches!(
+        store.export(Vec::new(), DumpFormat::JsonLines),
+        Err(KvsError::Utf8Error(_))
+    ));
+
+    Ok(())
+}
+
+// Whether any file under `dir` holds `needle`.
+fn files_contain(dir: &std::path::Path, needle: &[u8]) -> bool {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .any(|entry| {
+            let bytes = std::fs::read(entry.path()).unwrap();
+            bytes.windows(needle.len()).any(|window| window == needle)
+        })
+}
+
+// Should never write keys or values to disk in plaintext once the store is encrypted, and refuse
+// to open it without its key.
+#[test]
+fn encryption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = [7; 32];
+    let options = || {
+        let mut options = KvStore::options();
+        options
+            .segment_size(1024)
+            .compaction_threshold(1024)
+            .encryption_key(key);
+        options
+    };
+
+    // A plaintext store that gets encrypted.
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("plain-key".to_owned(), "plain-value".to_owned())?;
+    drop(store);
+    let store = options().open(temp_dir.path())?;
+    for i in 0..50 {
+        store.set("secret-key".to_owned(), format!("secret-value-{}", i))?;
+    }
+    store.set("large".to_owned(), "secret-large".repeat(200))?;
+    store.remove("plain-key".to_owned())?;
+    store.compact()?;
+    assert!(store.stats()?.compactions > 0);
+    assert_eq!(
+        store.get("secret-key".to_owned())?,
+        Some("secret-value-49".to_owned())
+    );
+    drop(store);
+    assert!(!files_contain(temp_dir.path(), b"secret"));
+    assert!(!files_contain(temp_dir.path(), b"plain"));
+
+    let store = options().open(temp_dir.path())?;
+    assert_eq!(
+        store.get("secret-key".to_owned())?,
+        Some("secret-value-49".to_owned())
+    );
+    assert_eq!(
+        store.get("large".to_owned())?,
+        Some("secret-large".repeat(200))
+    );
+    assert_eq!(store.get("plain-key".to_owned())?, None);
+
+    // Backups are encrypted too.
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.backup(backup_dir.path())?;
+    assert!(!files_contain(backup_dir.path(), b"secret"));
+    store.set("secret-key".to_owned(), "changed".to_owned())?;
+    store.restore(backup_dir.path())?;
+    assert_eq!(
+        store.get("secret-key".to_owned())?,
+        Some("secret-value-49".to_owned())
+    );
+    drop(store);
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()).map_err(|err| err.code()),
+        Err(ErrorCode::InvalidEncryptionKey)
+    ));
+    assert!(matches!(
+        KvStore::open_encrypted(temp_dir.path(), [8; 32]).map_err(|err| err.code()),
+        Err(ErrorCode::InvalidEncryptionKey)
+    ));
+    assert!(matches!(
+        KvStore::verify(temp_dir.path()),
+        Err(KvsError::InvalidEncryptionKey)
+    ));
+    assert!(options()
+        .verify(temp_dir.path())?
+        .iter()
+        .all(|check| check.is_ok()));
+
+    // The key can be read from a file of hex digits.
+    let key_file = backup_dir.path().join("key");
+    std::fs::write(&key_file, format!("{}\n", "07".repeat(32)))?;
+    let store = KvStore::options()
+        .encryption_key_file(&key_file)?
+        .open(temp_dir.path())?;
+    assert_eq!(
+        store.get("secret-key".to_owned())?,
+        Some("secret-value-49".to_owned())
+    );
+    std::fs::write(&key_file, "not a key")?;
+    assert!(matches!(
+        KvStore::options().encryption_key_file(&key_file),
+        Err(KvsError::InvalidEncryptionKey)
+    ));
+
+    Ok(())
+}
+
+// `kvs-admin` should inspect, verify, compact and repair the store in a directory.
+#[test]
+fn cli_admin() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["inspect"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"key1\""))
+        .stdout(contains("1 segments"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("1.log: ok, 2 records"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["compact", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("0 dead bytes left"));
+
+    // Tear the last record.
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let segment = temp_dir.path().join("2.log");
+    let file = OpenOptions::new().write(true).open(&segment)?;
+    file.set_len(file.metadata()?.len() - 3)?;
+    drop(file);
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stdout(contains("damaged"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["repair", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("Repaired 1 of"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+
+    Ok(())
+}
+
+// Should apply the writes of a transaction together, and none of them if it fails.
+#[test]
+fn transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let value = store.transaction(|txn| {
+        txn.set("key2".to_owned(), "value2".to_owned());
+        txn.remove("key1".to_owned())?;
+        // reads see the writes of the transaction
+        assert_eq!(txn.get("key1".to_owned())?, None);
+        assert_eq!(txn.get("key2".to_owned())?, Some("value2".to_owned()));
+        txn.set("key3".to_owned(), "value3".to_owned());
+        txn.remove("key3".to_owned())?;
+        // but nothing is written before it commits
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        txn.get("key2".to_owned())
+    })?;
+    assert_eq!(value, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // An error rolls the transaction back.
+    let result: Result<()> = store.transaction(|txn| {
+        txn.set("key2".to_owned(), "changed".to_owned());
+        txn.remove("key1".to_owned())
+    });
+    match result {
+        Err(KvsError::KeyNotFound) => {}
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // A write to a key the transaction read makes it fail.
+    let result: Result<()> = store.transaction(|txn| {
+        txn.get("key2".to_owned())?;
+        store.set("key2".to_owned(), "concurrent".to_owned())?;
+        txn.set("key4".to_owned(), "value4".to_owned());
+        Ok(())
+    });
+    match result {
+        Err(KvsError::TransactionConflict) => {}
+        other => panic!("expected TransactionConflict, got {:?}", other),
+    }
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    // So does creating a key the transaction saw missing.
+    let result: Result<()> = store.transaction(|txn| {
+        txn.set("key5".to_owned(), "txn".to_owned());
+        store.set("key5".to_owned(), "concurrent".to_owned())?;
+        Ok(())
+    });
+    assert!(matches!(result, Err(KvsError::TransactionConflict)));
+    assert_eq!(store.get("key5".to_owned())?, Some("concurrent".to_owned()));
+
+    Ok(())
+}
+
+// Should only commit one of the transactions that read and write the same key at the same time.
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<u32> {
+                let mut conflicts = 0;
+                for _ in 0..50 {
+                    loop {
+                        let result = store.transaction(|txn| {
+                            let count: u32 = txn
+                                .get("counter".to_owned())?
+                                .map_or(0, |count| count.parse().unwrap());
+                            txn.set("counter".to_owned(), (count + 1).to_string());
+                            Ok(())
+                        });
+                        match result {
+                            Ok(()) => break,
+                            Err(KvsError::TransactionConflict) => conflicts += 1,
+                            Err(err) => return Err(err),
+                        }
+                    }
+                }
+                Ok(conflicts)
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+
+    Ok(())
+}
+
+// Should read many keys at once, spread over several segments, in the order they were asked for.
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .segment_size(1024)
+        .open(temp_dir.path())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key7".to_owned())?;
+    store.set_with_ttl(
+        "key8".to_owned(),
+        "gone".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(5));
+    // warm the cache for some of the keys
+    store.get("key150".to_owned())?;
+
+    let keys: Vec<String> = vec![
+        "key199", "key7", "key0", "key150", "missing", "key8", "key0",
+    ]
+    .into_iter()
+    .map(str::to_owned)
+    .collect();
+    let expected = vec![
+        Some("value199".to_owned()),
+        None,
+        Some("value0".to_owned()),
+        Some("value150".to_owned()),
+        None,
+        None,
+        Some("value0".to_owned()),
+    ];
+    assert_eq!(store.get_many(&keys)?, expected);
+    assert_eq!(store.get_many(&[])?, vec![]);
+
+    let store = open_engine(Engine::Sled, temp_dir.path().join("sled"))?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    assert_eq!(
+        store.get_many(&["key0".to_owned(), "key1".to_owned()])?,
+        vec![Some("value0".to_owned()), None]
+    );
+
+    Ok(())
+}
+
+// Should only swap a value that matches the expected one, with every engine.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    for &engine in &[Engine::Kvs, Engine::Sled] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(engine, temp_dir.path())?;
+        let key = || "lock".to_owned();
+
+        assert!(store.compare_and_swap(key(), None, Some("owner1".to_owned()))?);
+        assert!(!store.compare_and_swap(key(), None, Some("owner2".to_owned()))?);
+        assert!(!store.compare_and_swap(
+            key(),
+            Some("owner2".to_owned()),
+            Some("owner3".to_owned())
+        )?);
+        assert_eq!(store.get(key())?, Some("owner1".to_owned()));
+
+        assert!(store.compare_and_swap(
+            key(),
+            Some("owner1".to_owned()),
+            Some("owner2".to_owned())
+        )?);
+        assert_eq!(store.get(key())?, Some("owner2".to_owned()));
+        assert!(store.compare_and_swap(key(), Some("owner2".to_owned()), None)?);
+        assert_eq!(store.get(key())?, None);
+        assert!(store.compare_and_swap(key(), None, None)?);
+        assert_eq!(store.get(key())?, None);
+    }
+    Ok(())
+}
+
+// Should add to counters atomically from many threads, with every engine.
+#[test]
+fn increment() -> Result<()> {
+    for &engine in &[Engine::Kvs, Engine::Sled] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(engine, temp_dir.path())?;
+
+        assert_eq!(store.increment("counter".to_owned(), 5)?, 5);
+        assert_eq!(store.increment("counter".to_owned(), -7)?, -2);
+        assert_eq!(store.get("counter".to_owned())?, Some("-2".to_owned()));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                thread::spawn(move || -> Result<()> {
+                    for _ in 0..50 {
+                        store.increment("counter".to_owned(), 1)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        assert_eq!(store.get("counter".to_owned())?, Some("198".to_owned()));
+
+        store.set("name".to_owned(), "alice".to_owned())?;
+        match store.increment("name".to_owned(), 1) {
+            Err(KvsError::InvalidInteger) => {}
+            other => panic!("expected InvalidInteger, got {:?}", other),
+        }
+        store.set("max".to_owned(), i64::MAX.to_string())?;
+        match store.increment("max".to_owned(), 1) {
+            Err(KvsError::InvalidInteger) => {}
+            other => panic!("expected InvalidInteger, got {:?}", other),
+        }
+        assert_eq!(store.get("max".to_owned())?, Some(i64::MAX.to_string()));
+    }
+    Ok(())
+}
+
+// Should read, change and write back a value without losing concurrent updates, with every
+// engine.
+#[test]
+fn update() -> Result<()> {
+    let append = |names: Option<String>| match names {
+        Some(names) => Some(format!("{},x", names)),
+        None => Some("x".to_owned()),
+    };
+    for &engine in &[Engine::Kvs, Engine::Sled] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(engine, temp_dir.path())?;
+
+        assert_eq!(
+            store.update("names".to_owned(), append)?,
+            Some("x".to_owned())
+        );
+        assert_eq!(
+            store.update("names".to_owned(), append)?,
+            Some("x,x".to_owned())
+        );
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                thread::spawn(move || -> Result<()> {
+                    for _ in 0..25 {
+                        store.update("names".to_owned(), append)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        let names = store.get("names".to_owned())?.unwrap();
+        assert_eq!(names.split(',').count(), 102);
+
+        // `None` removes the key, or leaves it missing.
+        assert_eq!(store.update("names".to_owned(), |_| None)?, None);
+        assert_eq!(store.get("names".to_owned())?, None);
+        assert_eq!(store.update("missing".to_owned(), |_| None)?, None);
+        assert_eq!(store.get("missing".to_owned())?, None);
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    // A key that keeps a value keeps its TTL.
+    store.set_with_ttl(
+        "session".to_owned(),
+        "1".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.update("session".to_owned(), |_| Some("2".to_owned()))?;
+    let (value, meta) = store.get_with_meta("session".to_owned())?.unwrap();
+    assert_eq!(value, "2");
+    assert!(meta.expires.is_some());
+
+    // A panic in the closure writes nothing and leaves the store usable.
+    let store2 = store.clone();
+    let result =
+        thread::spawn(move || store2.update("session".to_owned(), |_| panic!("update failed")))
+            .join();
+    assert!(result.is_err());
+    assert_eq!(store.get("session".to_owned())?, Some("2".to_owned()));
+    store.set("after".to_owned(), "panic".to_owned())?;
+
+    Ok(())
+}
+
+// Should move and copy values in one write, with every engine.
+#[test]
+fn rename_and_copy() -> Result<()> {
+    for &engine in &[Engine::Kvs, Engine::Sled] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_engine(engine, temp_dir.path())?;
+        store.set("draft".to_owned(), "text".to_owned())?;
+        store.set("post".to_owned(), "old".to_owned())?;
+
+        store.copy("draft".to_owned(), "backup".to_owned())?;
+        store.rename("draft".to_owned(), "post".to_owned())?;
+        assert_eq!(store.get("draft".to_owned())?, None);
+        assert_eq!(store.get("post".to_owned())?, Some("text".to_owned()));
+        assert_eq!(store.get("backup".to_owned())?, Some("text".to_owned()));
+        store.rename("post".to_owned(), "post".to_owned())?;
+        assert_eq!(store.get("post".to_owned())?, Some("text".to_owned()));
+
+        for result in &[
+            store.rename("draft".to_owned(), "other".to_owned()),
+            store.copy("draft".to_owned(), "other".to_owned()),
+        ] {
+            match result {
+                Err(KvsError::KeyNotFound) => {}
+                other => panic!("expected KeyNotFound, got {:?}", other),
+            }
+        }
+        assert_eq!(store.get("other".to_owned())?, None);
+    }
+
+    // The rename survives a reopen, and the TTL moves with the value.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "session".to_owned(),
+        "alice".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    let writes = store.stats()?.writes;
+    store.rename("session".to_owned(), "session:old".to_owned())?;
+    assert_eq!(store.stats()?.writes, writes + 2);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("session".to_owned())?, None);
+    let (value, meta) = store
+        .get_with_meta("session:old".to_owned())?
+        .expect("renamed key missing");
+    assert_eq!(value, "alice");
+    assert!(meta.expires.is_some());
+
+    Ok(())
+}
+
+// Should evict the least recently written or used keys to stay within the capacity limits.
+#[test]
+fn capacity_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = |key_id: u32| format!("key{}", key_id);
+    let store = KvStore::options()
+        .max_keys(Some(10))
+        .open(temp_dir.path())?;
+    for key_id in 0..15 {
+        store.set(key(key_id), "value".to_owned())?;
+    }
+    assert_eq!(store.stats()?.keys, 10);
+    assert_eq!(store.stats()?.evictions, 5);
+    assert_eq!(store.get(key(4))?, None);
+    // reading doesn't count, writing does
+    store.get(key(5))?;
+    store.set(key(6), "again".to_owned())?;
+    store.set(key(15), "value".to_owned())?;
+    store.set(key(16), "value".to_owned())?;
+    assert_eq!(store.get(key(5))?, None);
+    assert_eq!(store.get(key(6))?, Some("again".to_owned()));
+    assert_eq!(store.get(key(7))?, None);
+    drop(store);
+
+    // Lowering the limit evicts the keys written the longest ago when the store is opened.
+    let store = KvStore::options().max_keys(Some(3)).open(temp_dir.path())?;
+    let mut keys: Vec<String> = store.keys().collect();
+    keys.sort();
+    assert_eq!(keys, vec![key(15), key(16), key(6)]);
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .max_keys(Some(3))
+        .eviction_policy(EvictionPolicy::LeastRecentlyUsed)
+        .open(temp_dir.path())?;
+    for key_id in 0..3 {
+        store.set(key(key_id), "value".to_owned())?;
+    }
+    store.get(key(0))?;
+    store.set(key(3), "value".to_owned())?;
+    assert_eq!(store.get(key(1))?, None);
+    assert_eq!(store.get(key(0))?, Some("value".to_owned()));
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .max_live_bytes(Some(5000))
+        .compression_threshold(None)
+        .open(temp_dir.path())?;
+    for key_id in 0..20 {
+        store.set(key(key_id), "x".repeat(1000))?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 4);
+    assert!(stats.live_bytes <= 5000);
+    assert_eq!(store.get(key(19))?, Some("x".repeat(1000)));
+
+    Ok(())
+}
+
+// The names of the blob files in a store directory, sorted.
+fn blob_files(dir: &std::path::Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(".blob"))
+        .collect();
+    names.sort();
+    names
+}
+
+// Should stream large values in and out of blob files, and remove the blobs of dropped records
+// when compacting.
+#[test]
+fn stream_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value: String = (0..3 * 1024 * 1024 + 17)
+        .map(|i| (b'a' + (i % 26) as u8) as char)
+        .collect();
+    store.set_reader("big".to_owned(), value.as_bytes(), value.len() as u64)?;
+    assert_eq!(blob_files(temp_dir.path()).len(), 1);
+    let mut out = Vec::new();
+    assert!(store.get_writer("big".to_owned(), &mut out)?);
+    assert_eq!(out, value.as_bytes());
+    assert_eq!(store.get("big".to_owned())?, Some(value.clone()));
+    assert!(!store.get_writer("missing".to_owned(), &mut out)?);
+
+    // A reader that ends early leaves the key as it was.
+    match store.set_reader("big".to_owned(), &b"short"[..], 1024 * 1024) {
+        Err(KvsError::IoError(ref err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {}
+        other => panic!("expected UnexpectedEof, got {:?}", other),
+    }
+    assert_eq!(store.get("big".to_owned())?, Some(value.clone()));
+
+    // Small values are stored in the log.
+    store.set_reader("small".to_owned(), &b"small value and more"[..], 11)?;
+    assert_eq!(
+        store.get("small".to_owned())?,
+        Some("small value".to_owned())
+    );
+    assert_eq!(blob_files(temp_dir.path()).len(), 1);
+
+    // Copies and renames get blobs of their own.
+    store.copy("big".to_owned(), "copy".to_owned())?;
+    store.rename("big".to_owned(), "renamed".to_owned())?;
+    assert_eq!(store.get("copy".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("renamed".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("big".to_owned())?, None);
+    store.compact()?;
+    assert_eq!(blob_files(temp_dir.path()).len(), 2);
+
+    store.set("copy".to_owned(), "inline".to_owned())?;
+    store.compact()?;
+    assert_eq!(blob_files(temp_dir.path()).len(), 1);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("renamed".to_owned())?, Some(value.clone()));
+    store.remove("renamed".to_owned())?;
+    store.compact()?;
+    assert!(blob_files(temp_dir.path()).is_empty());
+    drop(store);
+
+    // The chunks of the blobs of an encrypted store are encrypted too.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .encryption_key([7; 32])
+        .open(temp_dir.path())?;
+    store.set_reader("big".to_owned(), value.as_bytes(), value.len() as u64)?;
+    let mut out = Vec::new();
+    assert!(store.get_writer("big".to_owned(), &mut out)?);
+    assert_eq!(out, value.as_bytes());
+    let blob = temp_dir.path().join(&blob_files(temp_dir.path())[0]);
+    let bytes = std::fs::read(blob)?;
+    assert!(!bytes
+        .windows(26)
+        .any(|window| window == &value.as_bytes()[..26]));
+
+    Ok(())
+}
+
+// Should read ranges of values and tell their lengths, for values in blobs, plain or encrypted,
+// as for those in the log.
+#[test]
+fn value_ranges() -> Result<()> {
+    let value: Vec<u8> = (0..3 * 1024 * 1024 + 17).map(|i| (i % 251) as u8).collect();
+    let range = |store: &KvStore, key: &str, start: u64, len: u64| -> Result<Option<Vec<u8>>> {
+        let mut out = Vec::new();
+        Ok(store
+            .get_range(key.to_owned(), start, len, &mut out)?
+            .then_some(out))
+    };
+    for encrypted in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut options = KvStore::options();
+        if encrypted {
+            options.encryption_key([7; 32]);
+        }
+        let store = options.open(temp_dir.path())?;
+        store.set_reader("big".to_owned(), &value[..], value.len() as u64)?;
+        store.set("small".to_owned(), "small value".to_owned())?;
+
+        assert_eq!(store.value_len("big".to_owned())?, Some(value.len() as u64));
+        assert_eq!(store.value_len("small".to_owned())?, Some(11));
+        assert_eq!(store.value_len("missing".to_owned())?, None);
+
+        let mib = 1024 * 1024;
+        for (start, len) in [
+            (0, 10),
+            (mib - 5, 10),
+            (2 * mib, mib),
+            (3 * mib, 100),
+            (value.len() as u64 - 1, 10),
+        ] {
+            let end = (start + len).min(value.len() as u64);
+            assert_eq!(
+                range(&store, "big", start, len)?,
+                Some(value[start as usize..end as usize].to_vec())
+            );
+        }
+        assert_eq!(
+            range(&store, "big", value.len() as u64 + 5, 10)?,
+            Some(Vec::new())
+        );
+        assert_eq!(range(&store, "small", 6, 100)?, Some(b"value".to_vec()));
+        assert_eq!(range(&store, "small", 20, 1)?, Some(Vec::new()));
+        assert_eq!(range(&store, "missing", 0, 1)?, None);
+    }
+
+    Ok(())
+}
+
+// Should keep the blobs a snapshot or replay may read until it is dropped, even after compaction
+// dropped their records.
+#[test]
+fn pinned_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(100 * 1024);
+    store.set_reader("big".to_owned(), value.as_bytes(), value.len() as u64)?;
+    store.set_reader("other".to_owned(), value.as_bytes(), value.len() as u64)?;
+    assert_eq!(blob_files(temp_dir.path()).len(), 2);
+
+    let snapshot = store.snapshot();
+    let mut replay = store.replay_from(0)?;
+    store.set("big".to_owned(), "inline".to_owned())?;
+    store.remove("other".to_owned())?;
+    store.compact()?;
+    assert_eq!(blob_files(temp_dir.path()).len(), 2);
+    assert_eq!(snapshot.get("big".to_owned())?, Some(value.clone()));
+    replay.next();
+    assert!(matches!(
+        replay.next().transpose()?,
+        Some(Operation::Set { key, value: v, .. }) if key == b"other" && v == value.as_bytes()
+    ));
+
+    // A snapshot taken after compaction doesn't keep the blobs.
+    let later = store.snapshot();
+    drop(snapshot);
+    assert_eq!(blob_files(temp_dir.path()).len(), 2);
+    drop(replay);
+    assert!(blob_files(temp_dir.path()).is_empty());
+    assert_eq!(later.get("big".to_owned())?, Some("inline".to_owned()));
+
+    Ok(())
+}
+
+// The names of the segment files in a directory, sorted.
+fn segment_files(dir: &std::path::Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(".log"))
+        .collect();
+    names.sort();
+    names
+}
+
+// Should move the segments compaction rewrote to the cold directory, and keep reading them there
+// once the store is reopened, with or without it.
+#[test]
+fn cold_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cold_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = {
+        let mut options = KvStore::options();
+        options
+            .segment_size(1024)
+            .compaction_threshold(u64::MAX)
+            .compression_threshold(None)
+            .cold_dir(Some(cold_dir.path().join("segments")));
+        options
+    };
+    let key = |i: u32| format!("key{}", i);
+    let store = options.open(temp_dir.path())?;
+    for round in 0..3 {
+        for i in 0..20 {
+            store.set(key(i), format!("{}-{}", round, "v".repeat(100)))?;
+        }
+    }
+    // the segments holding the last values have stale records too
+    store.remove(key(0))?;
+    store.remove(key(19))?;
+    store.compact()?;
+    let cold = segment_files(&cold_dir.path().join("segments"));
+    assert!(!cold.is_empty());
+    for name in &cold {
+        assert!(!temp_dir.path().join(name).exists());
+    }
+    for i in 1..19 {
+        assert_eq!(store.get(key(i))?, Some(format!("2-{}", "v".repeat(100))));
+    }
+    drop(store);
+
+    // A copy left behind by a crash while moving a segment is removed.
+    std::fs::copy(
+        cold_dir.path().join("segments").join(&cold[0]),
+        temp_dir.path().join(&cold[0]),
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!temp_dir.path().join(&cold[0]).exists());
+    for i in 1..19 {
+        assert_eq!(store.get(key(i))?, Some(format!("2-{}", "v".repeat(100))));
+    }
+    assert!(KvStore::verify(temp_dir.path())?
+        .iter()
+        .all(|check| check.is_ok()));
+
+    Ok(())
+}
+
+// A `SegmentStorage` that keeps its objects in memory, like a remote one.
+#[derive(Debug, Clone, Default)]
+struct MemoryStorage(Arc<Mutex<BTreeMap<String, Vec<u8>>>>);
+
+impl SegmentStorage for MemoryStorage {
+    fn location(&self) -> String {
+        "memory://segments".to_owned()
+    }
+
+    fn put(&self, name: &str, path: &Path) -> Result<()> {
+        let bytes = std::fs::read(path)?;
+        self.0.lock().unwrap().insert(name.to_owned(), bytes);
+        Ok(())
+    }
+
+    fn get(&self, name: &str, path: &Path) -> Result<()> {
+        let bytes = self.0.lock().unwrap().get(name).cloned();
+        let bytes = bytes.ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        self.0.lock().unwrap().remove(name);
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(self.0.lock().unwrap().keys().cloned().collect())
+    }
+}
+
+// Should put compacted segments in a storage that can't be read in place, read them from a copy
+// kept in the store, and download the copy again if it's missing.
+#[test]
+fn remote_cold_storage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let storage = MemoryStorage::default();
+    let options = {
+        let mut options = KvStore::options();
+        options
+            .segment_size(1024)
+            .compaction_threshold(u64::MAX)
+            .compression_threshold(None)
+            .cold_storage(storage.clone());
+        options
+    };
+    let key = |i: u32| format!("key{}", i);
+    let value = format!("2-{}", "v".repeat(100));
+    let store = options.open(temp_dir.path())?;
+    for round in 0..3 {
+        for i in 0..20 {
+            store.set(key(i), format!("{}-{}", round, "v".repeat(100)))?;
+        }
+    }
+    store.remove(key(0))?;
+    store.remove(key(19))?;
+    store.compact()?;
+    let objects = storage.list()?;
+    assert!(!objects.is_empty());
+    let cached: Vec<_> = objects
+        .iter()
+        .map(|name| {
+            assert!(!temp_dir.path().join(name).exists());
+            temp_dir.path().join(name.replace(".log", ".cached"))
+        })
+        .collect();
+    for path in &cached {
+        assert!(path.exists());
+    }
+    for i in 1..19 {
+        assert_eq!(store.get(key(i))?, Some(value.clone()));
+    }
+    drop(store);
+
+    // The store can't be opened without the storage its segments are in.
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    // Missing copies are downloaded, and objects left behind by a crash removed.
+    for path in &cached {
+        std::fs::remove_file(path)?;
+    }
+    storage
+        .0
+        .lock()
+        .unwrap()
+        .insert("999.log".to_owned(), b"left behind".to_vec());
+    let store = options.open(temp_dir.path())?;
+    for path in &cached {
+        assert!(path.exists());
+    }
+    assert_eq!(storage.list()?, objects);
+    for i in 1..19 {
+        assert_eq!(store.get(key(i))?, Some(value.clone()));
+    }
+    assert_eq!(store.get(key(0))?, None);
+
+    Ok(())
+}
+
+// The names of the value log files in a store directory, sorted.
+fn vlog_files(dir: &std::path::Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(".vlog"))
+        .collect();
+    names.sort();
+    names
+}
+
+// Should keep large values in the value log, leave them there when compacting, and reclaim the
+// overwritten ones when collecting the value log.
+#[test]
+fn value_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = {
+        let mut options = KvStore::options();
+        options
+            .value_log_threshold(Some(100))
+            .segment_size(16 * 1024);
+        options
+    };
+    let value = |i: usize, round: usize| format!("{:04}-{:02}-{}", i, round, "v".repeat(1000));
+    let store = options.open(temp_dir.path())?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), value(i, 0))?;
+    }
+    store.set("small".to_owned(), "inline".to_owned())?;
+    let stats = store.stats()?;
+    assert!(stats.value_log_bytes > 50 * 1000);
+    assert!(stats.live_bytes < 50 * 100);
+    assert!(vlog_files(temp_dir.path()).len() > 1);
+    for i in 0..50 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i, 0)));
+    }
+    let mut out = Vec::new();
+    assert!(store.get_writer("key7".to_owned(), &mut out)?);
+    assert_eq!(out, value(7, 0).as_bytes());
+    assert_eq!(store.get("small".to_owned())?, Some("inline".to_owned()));
+
+    // Copies and renames write the value again under their own key.
+    store.copy("key1".to_owned(), "copy".to_owned())?;
+    store.rename("key2".to_owned(), "renamed".to_owned())?;
+
+    // Compaction only rewrites the records of the keys.
+    for i in 3..50 {
+        store.set(format!("key{}", i), value(i, 1))?;
+    }
+    let before = store.stats()?.value_log_bytes;
+    store.compact()?;
+    assert_eq!(store.stats()?.value_log_bytes, before);
+
+    let reclaimed = store.collect_value_log()?;
+    assert!(reclaimed > 40 * 1000);
+    assert!(store.stats()?.value_log_bytes < before - 40 * 1000);
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, Some(value(0, 0)));
+        assert_eq!(store.get("key1".to_owned())?, Some(value(1, 0)));
+        assert_eq!(store.get("copy".to_owned())?, Some(value(1, 0)));
+        assert_eq!(store.get("renamed".to_owned())?, Some(value(2, 0)));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        for i in 3..50 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(value(i, 1)));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+
+    let store = options.open(temp_dir.path())?;
+    check(&store)?;
+    drop(store);
+
+    // The values of an encrypted store are encrypted in the value log too.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .value_log_threshold(Some(100))
+        .encryption_key([7; 32])
+        .open(temp_dir.path())?;
+    store.set("key".to_owned(), value(0, 0))?;
+    assert_eq!(store.get("key".to_owned())?, Some(value(0, 0)));
+    let vlog = temp_dir.path().join(&vlog_files(temp_dir.path())[0]);
+    let bytes = std::fs::read(vlog)?;
+    assert!(!bytes.windows(20).any(|window| window == [b'v'; 20]));
+    drop(store);
+
+    // The files holding older versions are kept.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .value_log_threshold(Some(100))
+        .segment_size(4 * 1024)
+        .versions(2)
+        .open(temp_dir.path())?;
+    for round in 0..3 {
+        for i in 0..10 {
+            store.set(format!("key{}", i), value(i, round))?;
+        }
+    }
+    store.collect_value_log()?;
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i, 2)));
+        assert_eq!(
+            store.get_version(format!("key{}", i), 1)?,
+            Some(value(i, 1))
+        );
+    }
+
+    Ok(())
+}
+
+// Should allow clones of the store to read from many threads while another one writes.
+#[test]
+fn concurrent_reads_and_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for iter in 1..=100 {
+                for key_id in 0..100 {
+                    store.set(format!("key{}", key_id), format!("{}", iter))?;
+                }
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..20 {
+                    for key_id in 0..100 {
+                        let value = store.get(format!("key{}", key_id))?;
+                        assert!(value.is_some());
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    writer.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("100".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should treat keys as missing once their TTL has passed, also after reopening.
+#[test]
+fn expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec!["key2"]);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // A TTL past the end of time never expires, and leaves the store usable.
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(u64::MAX),
+    )?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert!(store
+        .get_with_meta("key3".to_owned())?
+        .unwrap()
+        .1
+        .expires
+        .is_some());
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Should report when a value was written, and keep it across restarts.
+#[test]
+fn get_with_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_with_meta("key1".to_owned())?, None);
+
+    let before = SystemTime::now() - Duration::from_millis(1);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    let after = SystemTime::now() + Duration::from_millis(1);
+    let (value, meta) = store
+        .get_with_meta("key1".to_owned())?
+        .expect("key1 missing");
+    assert_eq!(value, "value1");
+    let modified = meta.modified.expect("no write time");
+    assert!(before <= modified && modified <= after);
+    assert_eq!(meta.expires, None);
+    assert!(meta.size > "key1value1".len() as u64);
+    let (_, meta2) = store
+        .get_with_meta("key2".to_owned())?
+        .expect("key2 missing");
+    assert!(meta2.expires.expect("no expiry") > after + Duration::from_secs(3000));
+
+    thread::sleep(Duration::from_millis(20));
+    store.set("key2".to_owned(), "other".to_owned())?;
+    let (_, newer) = store
+        .get_with_meta("key2".to_owned())?
+        .expect("key2 missing");
+    assert!(newer.modified > meta2.modified);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_with_meta("key1".to_owned())?,
+        Some(("value1".to_owned(), meta))
+    );
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get_with_meta("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should keep the configured number of versions of each key, through compaction and restarts.
+#[test]
+fn versioned_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = KvStore::options();
+    options.versions(3).segment_size(64);
+    let store = options.open(temp_dir.path())?;
+    for i in 1..=5 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+        store.set("other".to_owned(), format!("other{}", i))?;
+    }
+    assert_eq!(
+        store.history("key".to_owned())?,
+        vec!["value5", "value4", "value3"]
+    );
+    assert_eq!(
+        store.get_version("key".to_owned(), 0)?,
+        Some("value5".to_owned())
+    );
+    assert_eq!(
+        store.get_version("key".to_owned(), 2)?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(store.get_version("key".to_owned(), 3)?, None);
+    assert_eq!(store.get_version("missing".to_owned(), 1)?, None);
+
+    store.remove("key".to_owned())?;
+    assert_eq!(store.get_version("key".to_owned(), 0)?, None);
+    assert_eq!(
+        store.get_version("key".to_owned(), 1)?,
+        Some("value5".to_owned())
+    );
+    store.set("key".to_owned(), "value6".to_owned())?;
+    assert_eq!(
+        store.history("key".to_owned())?,
+        vec!["value6", "value5", "value4"]
+    );
+
+    store.compact()?;
+    assert_eq!(
+        store.history("key".to_owned())?,
+        vec!["value6", "value5", "value4"]
+    );
+    assert_eq!(
+        store.history("other".to_owned())?,
+        vec!["other5", "other4", "other3"]
+    );
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(
+        store.history("key".to_owned())?,
+        vec!["value6", "value5", "value4"]
+    );
+    assert_eq!(
+        store.history("other".to_owned())?,
+        vec!["other5", "other4", "other3"]
+    );
+    drop(store);
+
+    // Without history, only the current value is kept.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.history("key".to_owned())?, vec!["value6"]);
+    assert_eq!(store.get_version("key".to_owned(), 1)?, None);
+
+    Ok(())
+}
+
+// Should load sorted pairs into a segment of their own, overwriting the keys that exist.
+#[test]
+fn bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key00010".to_owned(), "old".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+
+    let pairs = (0..1000).map(|i| (format!("key{:05}", i), format!("value{}", i)));
+    assert_eq!(store.bulk_load(pairs)?, 1000);
+    assert_eq!(
+        store.get("key00010".to_owned())?,
+        Some("value10".to_owned())
+    );
+    assert_eq!(
+        store.get("key00999".to_owned())?,
+        Some("value999".to_owned())
+    );
+    assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.stats()?.keys, 1001);
+    assert!(store
+        .get_with_meta("key00001".to_owned())?
+        .unwrap()
+        .1
+        .modified
+        .is_some());
+
+    // Later writes go after the loaded segment.
+    store.set("key00020".to_owned(), "new".to_owned())?;
+    assert_eq!(store.bulk_load(Vec::<(String, String)>::new())?, 0);
+
+    let unsorted = vec![("b", "1"), ("a", "2")];
+    assert!(store.bulk_load(unsorted).is_err());
+    let duplicate = vec![("c", "1"), ("c", "2")];
+    assert!(store.bulk_load(duplicate).is_err());
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert_eq!(store.get("c".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get("key00010".to_owned())?,
+        Some("value10".to_owned())
+    );
+    assert_eq!(store.get("key00020".to_owned())?, Some("new".to_owned()));
+    assert_eq!(
+        store.get("key00500".to_owned())?,
+        Some("value500".to_owned())
+    );
+    assert_eq!(store.stats()?.keys, 1001);
+
+    Ok(())
+}
+
+// Should report the memory of the index, and turn down new keys once it reaches the limit.
+#[test]
+fn index_memory_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value".to_owned())?;
+    let entry = store.stats()?.index_bytes;
+    assert!(entry > "key0".len() as u64);
+    drop(store);
+
+    let mut options = KvStore::options();
+    // room for ten short keys
+    options.index_memory_limit(Some(entry * 10 + 1));
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.stats()?.index_bytes, entry);
+    for i in 1..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert_eq!(store.stats()?.index_bytes, entry * 10);
+    assert!(matches!(
+        store.set("key10".to_owned(), "value".to_owned()),
+        Err(KvsError::IndexFull)
+    ));
+    let mut batch = store.batch();
+    batch.set("key5".to_owned(), "other".to_owned());
+    batch.set("key11".to_owned(), "value".to_owned());
+    assert!(matches!(store.write_batch(batch), Err(KvsError::IndexFull)));
+    assert_eq!(store.get("key5".to_owned())?, Some("value".to_owned()));
+
+    // Existing keys can still be overwritten, and removing one makes room for another.
+    store.set("key5".to_owned(), "other".to_owned())?;
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.stats()?.index_bytes, entry * 9);
+    store.set("key10".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key10".to_owned())?, Some("value".to_owned()));
+    drop(store);
+
+    // Short keys cost the same whatever their length, and long ones their length on top.
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("k".to_owned(), "value".to_owned())?;
+    assert_eq!(store.stats()?.index_bytes, entry * 11);
+    let long = "k".repeat(100);
+    store.set(long.clone(), "value".to_owned())?;
+    assert_eq!(store.stats()?.index_bytes, entry * 12 + 100);
+    assert_eq!(store.get(long)?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should turn down the writes that set keys once the disk has less free space than the minimum,
+// leaving the log as it was.
+#[test]
+fn min_free_space() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .min_free_space(Some(1))
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::options()
+        .min_free_space(Some(u64::MAX))
+        .open(temp_dir.path())?;
+    assert!(matches!(
+        store.set("key3".to_owned(), "value3".to_owned()),
+        Err(KvsError::DiskFull)
+    ));
+    let mut batch = store.batch();
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.remove("key1".to_owned());
+    let err = store.write_batch(batch).unwrap_err();
+    assert_eq!(err.code(), ErrorCode::DiskFull);
+    assert!(matches!(
+        store.set_reader("key3".to_owned(), &[0; 100][..], 100),
+        Err(KvsError::DiskFull)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // Removing keys still works, so that compaction can make room.
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should leave no record of a purged key in any file of the store, its older versions, its
+// tombstone and its values in the value log included.
+#[test]
+fn purge() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::options()
+            .segment_size(512)
+            .compaction_threshold(u64::MAX)
+            .compression_threshold(None)
+            .versions(3)
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    for i in 0..20 {
+        store.set("secret".to_owned(), format!("private{:03}", i))?;
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("secret".to_owned())?;
+    assert!(files_contain(temp_dir.path(), b"private"));
+
+    store.purge("secret".to_owned())?;
+    assert_eq!(store.get("secret".to_owned())?, None);
+    assert!(store.history("secret".to_owned())?.is_empty());
+    assert!(!files_contain(temp_dir.path(), b"private"));
+    assert!(!files_contain(temp_dir.path(), b"secret"));
+    // A key that is gone already is purged all the same.
+    store.purge("secret".to_owned())?;
+
+    // The key can be set again, and the other keys are kept.
+    store.set("secret".to_owned(), "new".to_owned())?;
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("secret".to_owned())?, Some("new".to_owned()));
+    for i in 0..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    drop(store);
+
+    // The values in the value log are collected, and those of other keys moved.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .compression_threshold(None)
+        .value_log_threshold(Some(8))
+        .open(temp_dir.path())?;
+    store.set("secret".to_owned(), "private value".to_owned())?;
+    store.set("large".to_owned(), "public value".to_owned())?;
+    store.purge("secret".to_owned())?;
+    assert!(!files_contain(temp_dir.path(), b"private"));
+    assert_eq!(
+        store.get("large".to_owned())?,
+        Some("public value".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should turn down records longer than the max record size, whether written or read.
+#[test]
+fn max_record_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "v".repeat(2000);
+    let store = KvStore::options()
+        .compression_threshold(None)
+        .max_record_size(1024)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let err = store.set("key2".to_owned(), value.clone()).unwrap_err();
+    assert!(matches!(err, KvsError::RecordTooLarge(_)));
+    assert_eq!(err.code(), ErrorCode::RecordTooLarge);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    // A store holding a longer record can't be opened with a smaller max.
+    let store = KvStore::options()
+        .compression_threshold(None)
+        .open(temp_dir.path())?;
+    store.set("key2".to_owned(), value.clone())?;
+    drop(store);
+    let err = KvStore::options()
+        .max_record_size(1024)
+        .open(temp_dir.path())
+        .unwrap_err();
+    assert_eq!(err.code(), ErrorCode::RecordTooLarge);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some(value));
+    drop(store);
+
+    // A length past the end of the segment is taken for a torn record, and nothing is
+    // allocated for it.
+    let first = temp_dir.path().join("1.log");
+    let mut bytes = std::fs::read(&first)?;
+    bytes[17..21].copy_from_slice(&u32::MAX.to_le_bytes());
+    std::fs::write(&first, &bytes)?;
+    let store = KvStore::options().read_only(true).open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should hold the writes back once compaction falls behind, failing them or making them wait.
+#[test]
+fn backpressure() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "v".repeat(200);
+    let store = KvStore::options()
+        .segment_size(1024)
+        .stall_segments(Some(2))
+        .backpressure(Backpressure::Fail)
+        .open(temp_dir.path())?;
+    // Distinct keys leave nothing to compact, so the segments pile up.
+    let mut written = 0;
+    let err = loop {
+        match store.set(format!("key{}", written), value.clone()) {
+            Ok(()) => written += 1,
+            Err(err) => break err,
+        }
+        assert!(written < 100, "writes were never turned down");
+    };
+    assert!(matches!(err, KvsError::Backpressure));
+    assert_eq!(err.code(), ErrorCode::Backpressure);
+    assert_eq!(store.stats()?.segments, 3);
+    assert_eq!(store.get(format!("key{}", written))?, None);
+    drop(store);
+
+    // Blocked writes go through once the time to wait runs out.
+    let store = KvStore::options()
+        .stall_segments(Some(2))
+        .backpressure(Backpressure::Block(Duration::from_millis(50)))
+        .open(temp_dir.path())?;
+    let started = std::time::Instant::now();
+    store.set("blocked".to_owned(), value.clone())?;
+    assert!(started.elapsed() >= Duration::from_millis(50));
+    assert_eq!(store.get("blocked".to_owned())?, Some(value.clone()));
+    drop(store);
+
+    // Writes wait for compaction to bring the stale data back down.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .segment_size(1024)
+        .compaction_threshold(1)
+        .stall_stale_ratio(Some(0.5))
+        .backpressure(Backpressure::Block(Duration::from_secs(10)))
+        .open(temp_dir.path())?;
+    for i in 0..200 {
+        store.set("key".to_owned(), format!("{}-{}", i, value))?;
+    }
+    assert_eq!(store.get("key".to_owned())?, Some(format!("199-{}", value)));
+
+    Ok(())
+}
+
+// Should spread keys over its shards, each key in a single one, and keep them across restarts.
+#[test]
+fn sharded_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    for i in 0..400 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert_eq!(store.increment("counter".to_owned(), 3)?, 3);
+    store.remove("key0".to_owned())?;
+    assert!(store.remove("key0".to_owned()).is_err());
+
+    for shard in store.shards() {
+        let keys = shard.keys().count();
+        assert!(keys > 50 && keys < 150, "uneven shard of {} keys", keys);
+    }
+    assert_eq!(store.stats()?.keys, 400);
+    let owner = store.shard("key1");
+    assert_eq!(owner.get("key1".to_owned())?, Some("value1".to_owned()));
+    let holders = store
+        .shards()
+        .iter()
+        .filter(|shard| shard.get("key1".to_owned()).unwrap().is_some());
+    assert_eq!(holders.count(), 1);
+
+    drop(store);
+    assert!(ShardedKvStore::open(temp_dir.path(), 2).is_err());
+    let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key399".to_owned())?, Some("value399".to_owned()));
+    assert_eq!(store.get("counter".to_owned())?, Some("3".to_owned()));
+
+    // A directory holding a store that isn't sharded is left alone.
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(plain_dir.path())?.set("key".to_owned(), "value".to_owned())?;
+    assert!(ShardedKvStore::open(plain_dir.path(), 4).is_err());
+
+    Ok(())
+}
+
+// Should move only the keys whose shard changes, keeping their values and expiry.
+#[test]
+fn reshard() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = ShardedKvStore::open(temp_dir.path(), 2)?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.shard("expiring").set_with_ttl(
+        "expiring".to_owned(),
+        "soon".to_owned(),
+        Duration::from_secs(1),
+    )?;
+    drop(store);
+
+    // Going from 2 shards to 4 moves about half of the keys.
+    let moved = ShardedKvStore::reshard(temp_dir.path(), 4, &KvStore::options())?;
+    assert!(moved > 300 && moved < 700, "moved {} keys", moved);
+    assert_eq!(
+        ShardedKvStore::reshard(temp_dir.path(), 4, &KvStore::options())?,
+        0
+    );
+    let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    assert_eq!(store.stats()?.keys, 1001);
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("expiring".to_owned())?, Some("soon".to_owned()));
+    drop(store);
+
+    ShardedKvStore::reshard(temp_dir.path(), 1, &KvStore::options())?;
+    assert!(!temp_dir.path().join("shard-1").exists());
+    let store = ShardedKvStore::open(temp_dir.path(), 1)?;
+    assert_eq!(store.shards()[0].keys().count(), 1001);
+    assert_eq!(store.get("key500".to_owned())?, Some("value500".to_owned()));
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(store.get("expiring".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should compact once overwritten values add up to enough stale bytes, even if there were only a
+// few writes.
+#[test]
+fn compaction_by_stale_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .compression_threshold(None)
+        .compaction_threshold(1024 * 1024)
+        .open(temp_dir.path())?;
+
+    let dir_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .filter_map(|entry| entry.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    };
+
+    let value = "x".repeat(1024 * 1024);
+    for iter in 0..20 {
+        store.set("key".to_owned(), format!("{}{}", value, iter))?;
+    }
+
+    // Compaction runs in the background.
+    let mut attempts = 0;
+    while dir_size() > 10 * 1024 * 1024 {
+        attempts += 1;
+        assert!(attempts < 100, "stale values were not compacted");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(store.get("key".to_owned())?, Some(format!("{}19", value)));
+
+    Ok(())
+}
+
+// Should compact the segments the compaction policy picks, by itself or only when asked.
+#[test]
+fn compaction_policies() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .segment_size(1024)
+        .compaction_policy(ManualOnly(DeadRatio(0.5)))
+        .open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    thread::sleep(Duration::from_millis(100));
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 0);
+    assert!(stats.dead_bytes > 0);
+
+    let compacted = store.compact_now()?;
+    assert!(compacted > 0);
+    let after = store.stats()?;
+    assert_eq!(after.compactions, compacted as u64);
+    assert!(after.dead_bytes < stats.dead_bytes);
+    // Nothing is left for the policy to pick.
+    assert_eq!(store.compact_now()?, 0);
+    for i in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("value{}", 90 + i))
+        );
+    }
+    drop(store);
+
+    // Other policies compact in the background.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::options()
+        .segment_size(1024)
+        .compaction_policy(TimeBased(Duration::ZERO))
+        .open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    let mut attempts = 0;
+    while store.stats()?.compactions == 0 {
+        attempts += 1;
+        assert!(attempts < 100, "the segments were not compacted");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(store.get("key9".to_owned())?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+// Should compact once the stale bytes make up the share of all the sealed segments, whatever
+// share of each one they make up, the segment with the most first.
+#[test]
+fn total_stale_ratio() {
+    use kvs::CompactionPolicy;
+
+    let usage = |segment, size, stale| SegmentUsage {
+        segment,
+        size,
+        stale,
+        created: None,
+    };
+    let policy = TotalStaleRatio(0.25);
+    assert_eq!(policy.pick(&[]), None);
+    assert_eq!(policy.pick(&[usage(1, 1000, 0), usage(2, 1000, 0)]), None);
+    assert_eq!(
+        policy.pick(&[usage(1, 1000, 200), usage(2, 1000, 200), usage(3, 1000, 0)]),
+        None
+    );
+    assert_eq!(
+        policy.pick(&[
+            usage(1, 1000, 200),
+            usage(2, 1000, 400),
+            usage(3, 1000, 300)
+        ]),
+        Some(2)
+    );
+}
+
+// Should merge the oldest run of enough adjacent segments within a factor of two of each other's
+// size, all of it, and nothing else.
+#[test]
+fn size_tiered() {
+    use kvs::CompactionPolicy;
+
+    let usage = |segment, size| SegmentUsage {
+        segment,
+        size,
+        stale: 0,
+        created: None,
+    };
+    let policy = SizeTiered(3);
+    assert_eq!(policy.merge(&[]), None);
+    assert_eq!(policy.merge(&[usage(1, 1000), usage(2, 1000)]), None);
+    assert_eq!(
+        policy.merge(&[usage(1, 1000), usage(2, 1500), usage(3, 900)]),
+        Some(vec![1, 2, 3])
+    );
+    // 4000 is more than twice 1000, and the run after it is too short
+    assert_eq!(
+        policy.merge(&[
+            usage(1, 1000),
+            usage(2, 4000),
+            usage(3, 1000),
+            usage(4, 1000)
+        ]),
+        None
+    );
+    assert_eq!(
+        policy.merge(&[
+            usage(1, 8000),
+            usage(2, 1000),
+            usage(3, 1900),
+            usage(4, 1200),
+            usage(5, 1000),
+            usage(6, 3000),
+            usage(7, 1000),
+        ]),
+        Some(vec![2, 3, 4, 5])
+    );
+    // single segments are never compacted on their own
+    assert_eq!(policy.pick(&[usage(1, 1000)]), None);
+}
+
+// Should merge runs of small segments into larger ones in the background, keeping the latest
+// value of each key and its removal, across a reopen too.
+#[test]
+fn size_tiered_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::options()
+            .segment_size(1024)
+            .compaction_policy(SizeTiered(4))
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    for i in 0..300 {
+        store.set(format!("key{}", i % 100), format!("value{}", i))?;
+    }
+    for i in (0..100).step_by(3) {
+        store.remove(format!("key{}", i))?;
+    }
+    for i in 0..100 {
+        store.set(format!("other{}", i), format!("value{}", i))?;
+    }
+    let mut attempts = 0;
+    while store.stats()?.compactions < 2 {
+        attempts += 1;
+        assert!(attempts < 100, "the segments were not merged");
+        thread::sleep(Duration::from_millis(50));
+    }
+    // Let the compaction thread merge all it picks.
+    thread::sleep(Duration::from_millis(200));
+    let stats = store.stats()?;
+    assert!(stats.segments < 10, "{} segments are left", stats.segments);
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..100 {
+            let expected = (i % 3 != 0).then(|| format!("value{}", 200 + i));
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+            assert_eq!(
+                store.get(format!("other{}", i))?,
+                Some(format!("value{}", i))
+            );
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&open()?)?;
+
+    Ok(())
+}
+
+// The keys read and written most are ranked first, with estimates of their reads and writes.
+#[test]
+fn hot_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.get("key".to_owned())?;
+    assert!(store.hot_keys(10).is_empty());
+    drop(store);
+
+    let store = KvStore::options()
+        .key_sampling(Some(1))
+        .open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+        store.get(format!("key{}", i))?;
+    }
+    for _ in 0..100 {
+        store.get("key7".to_owned())?;
+    }
+    for i in 0..50 {
+        store.set("key3".to_owned(), format!("value{}", i))?;
+    }
+    store.get("missing".to_owned())?;
+
+    let hot = store.hot_keys(2);
+    assert_eq!(hot.len(), 2);
+    assert_eq!(hot[0].key, b"key7");
+    assert!(hot[0].reads >= 101);
+    assert!(hot[0].writes >= 1);
+    assert_eq!(hot[1].key, b"key3");
+    assert!(hot[1].writes >= 51);
+    assert!(store.hot_keys(100).len() >= 21);
+
+    Ok(())
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        entries
+            .filter(|res| match res {
+                Ok(entry) => entry.file_type().is_file(),
+                Err(_err) => false,
+            })
+            .map(|res| {
+                res.and_then(|entry| {
+                    println!("entry: {:?}", entry.path());
+                    entry.metadata()
+                })
+                .map(|metadata| metadata.len())
+            })
+            // Compaction runs in the background, so a file may be gone by the time it's read.
+            .filter_map(|len| len.ok())
+            .sum::<u64>()
+    };
+
+    let mut current_size = dir_size();
+    let iterations = 1000;
+    for iter in 0..iterations {
+        for key_id in 0..iterations {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+
+        let new_size = dir_size();
+        println!(
+            "iteration: {}, current_size: {}, new_size: {}",
+            iter, current_size, new_size
+        );
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered.
+
+        drop(store);
+        // reopen and check content.
+        println!("Compaction triggered. Going to check the compacted data.");
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..iterations {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// Should replace a segment that readers still have open, leaving no temporary file behind.
+#[test]
+fn compaction_with_open_readers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    let snapshot = store.snapshot();
+    let mut scan = store.scan::<String, _>(..);
+    assert_eq!(
+        scan.next().transpose()?,
+        Some(("key0".to_owned(), "value2".to_owned()))
+    );
+    store.set("key1".to_owned(), "value3".to_owned())?;
+
+    store.compact()?;
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(scan.count(), 99);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    drop(snapshot);
+    let temp_files = std::fs::read_dir(temp_dir.path())?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(".tmp"))
+        .count();
+    assert_eq!(temp_files, 0);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        let value = if key_id == 1 { "value3" } else { "value2" };
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.to_owned()));
+    }
+
+    Ok(())
 }
@@ -0,0 +1,65 @@
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+
+// Run a number of jobs on the pool and check that all of them finish.
+fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
+    const TASK_NUM: usize = 20;
+    const ADD_COUNT: usize = 1000;
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        let tx = tx.clone();
+        pool.spawn(move || {
+            for _ in 0..ADD_COUNT {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            tx.send(()).unwrap();
+        })
+    }
+    for _ in 0..TASK_NUM {
+        rx.recv().unwrap();
+    }
+
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM * ADD_COUNT);
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(NaiveThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(SharedQueueThreadPool::new(4)?)
+}
+
+// Workers killed by panicking jobs should be replaced.
+#[test]
+fn shared_queue_thread_pool_panic_task() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    for _ in 0..100 {
+        pool.spawn(|| panic!("job panicked on purpose"));
+    }
+    spawn_counter(pool)
+}
+
+#[test]
+fn rayon_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(RayonThreadPool::new(4)?)
+}
+
+// Panicking jobs should neither take the process down nor stop the pool.
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    let pool = RayonThreadPool::new(4)?;
+    for _ in 0..100 {
+        pool.spawn(|| panic!("job panicked on purpose"));
+    }
+    spawn_counter(pool)
+}
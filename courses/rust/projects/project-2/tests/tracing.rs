@@ -0,0 +1,94 @@
+#![cfg(feature = "tracing")]
+
+use kvs::{KvStore, Result};
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+use tracing_core::span::Current;
+
+// A span and its fields, as `name=value` strings.
+type Span = (&'static Metadata<'static>, Vec<String>);
+
+// Collects every span with its fields.
+#[derive(Clone, Default)]
+struct Collector {
+    spans: Arc<Mutex<Vec<Span>>>,
+    // the spans entered and not exited yet
+    entered: Arc<Mutex<Vec<Id>>>,
+}
+
+struct Fields<'a>(&'a mut Vec<String>);
+
+impl Visit for Fields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.push(format!("{}={:?}", field.name(), value));
+    }
+}
+
+impl Subscriber for Collector {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut fields = Vec::new();
+        span.record(&mut Fields(&mut fields));
+        let mut spans = self.spans.lock().unwrap();
+        spans.push((span.metadata(), fields));
+        Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut spans = self.spans.lock().unwrap();
+        values.record(&mut Fields(&mut spans[span.into_u64() as usize - 1].1));
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, _: &Event<'_>) {}
+
+    fn enter(&self, span: &Id) {
+        self.entered.lock().unwrap().push(span.clone());
+    }
+
+    fn exit(&self, _: &Id) {
+        self.entered.lock().unwrap().pop();
+    }
+
+    fn current_span(&self) -> Current {
+        match self.entered.lock().unwrap().last() {
+            Some(id) => {
+                let metadata = self.spans.lock().unwrap()[id.into_u64() as usize - 1].0;
+                Current::new(id.clone(), metadata)
+            }
+            None => Current::none(),
+        }
+    }
+}
+
+// Should emit spans with byte counts for opening the store, writes and reads.
+#[test]
+fn spans() -> Result<()> {
+    let collector = Collector::default();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    tracing::subscriber::with_default(collector.clone(), || -> Result<()> {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.get("key1".to_owned())?;
+        store.get("key1".to_owned())?;
+        Ok(())
+    })?;
+
+    let spans = collector.spans.lock().unwrap();
+    let names: Vec<_> = spans.iter().map(|(metadata, _)| metadata.name()).collect();
+    assert_eq!(names, ["open_with", "append", "get_bytes", "get_bytes"]);
+    assert!(spans[0].1.contains(&"keys=0".to_owned()));
+    assert!(spans[1].1.contains(&"key_len=4".to_owned()));
+    assert!(spans[2].1.contains(&"cache_hit=false".to_owned()));
+    assert!(spans[3].1.contains(&"cache_hit=true".to_owned()));
+    assert!(spans[3].1.contains(&"bytes=6".to_owned()));
+
+    Ok(())
+}